@@ -1,4 +1,53 @@
+use std::process::Command;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     tonic_build::compile_protos("proto/transport.proto")?;
+    tonic_build::compile_protos("proto/admin.proto")?;
+    tonic_build::compile_protos("proto/events.proto")?;
+    tonic_build::compile_protos("proto/geosite.proto")?;
+    emit_build_info();
     Ok(())
 }
+
+/// Embed the git commit, build date and compiler version, which are reported by --version and the admin API.
+fn emit_build_info() {
+    let commit = command_output("git", &["rev-parse", "--short=12", "HEAD"])
+        .unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=TROJAN_GIT_COMMIT={}", commit);
+
+    // Reproducible builds pin the date with SOURCE_DATE_EPOCH
+    let build_time = match std::env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|s| s.parse().ok())
+    {
+        Some(epoch) => UNIX_EPOCH + Duration::from_secs(epoch),
+        None => SystemTime::now(),
+    };
+    println!(
+        "cargo:rustc-env=TROJAN_BUILD_DATE={}",
+        humantime::format_rfc3339_seconds(build_time)
+    );
+
+    let rustc = std::env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+    let rustc_version =
+        command_output(&rustc, &["--version"]).unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=TROJAN_RUSTC_VERSION={}", rustc_version);
+
+    // Refresh the commit when HEAD moves, either to another branch or to a new commit on the branch
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    if let Ok(head) = std::fs::read_to_string(".git/HEAD") {
+        if let Some(reference) = head.trim().strip_prefix("ref: ") {
+            println!("cargo:rerun-if-changed=.git/{}", reference);
+        }
+    }
+}
+
+fn command_output(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program).args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(String::from_utf8(output.stdout).ok()?.trim().to_string())
+}
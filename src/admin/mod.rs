@@ -0,0 +1,5 @@
+pub mod server;
+
+pub mod admin_api {
+    tonic::include_proto!("trojan_rust.admin");
+}
@@ -0,0 +1,533 @@
+use crate::admin::admin_api::admin_service_server::{AdminService, AdminServiceServer};
+use crate::admin::admin_api::list_session_profiles_request::Order;
+#[cfg(feature = "sqlite")]
+use crate::admin::admin_api::DailyTraffic;
+use crate::admin::admin_api::{
+    AddUserRequest, AddUserResponse, BuildInfo, CloseConnectionsRequest, CloseConnectionsResponse,
+    Connection, DrainOutboundRequest, DrainOutboundResponse, GetBuildInfoRequest,
+    GetTrafficStatsRequest, GetTrafficStatsResponse, ListConnectionsRequest,
+    ListConnectionsResponse, ListOutboundGroupsRequest, ListOutboundGroupsResponse,
+    ListSessionProfilesRequest, ListSessionProfilesResponse, ListUsersRequest, ListUsersResponse,
+    OutboundGroup, RemoveUserRequest, RemoveUserResponse, SelectOutboundRequest,
+    SelectOutboundResponse, SessionProfile, UpdateRoutingDatabasesRequest,
+    UpdateRoutingDatabasesResponse, User,
+};
+use crate::auth::secret::{parse_expires_at, StaticAuthenticator};
+use crate::build_info;
+use crate::config::base::{AdminConfig, GroupType};
+#[cfg(feature = "sqlite")]
+use crate::metrics::usage::TrafficStats;
+use crate::profiling::{self, SessionOrder};
+use crate::proxy::connections::{active_connections, close_connections};
+use crate::router::Router;
+
+use hyper::server::conn::Http;
+use log::{info, warn};
+use once_cell::sync::OnceCell;
+use std::fmt;
+use std::io::{self, Error, ErrorKind};
+use std::net::{IpAddr, ToSocketAddrs};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite};
+use tonic::service::Interceptor;
+use tonic::transport::Server;
+use tonic::{Request, Response, Status};
+
+/// Number of sessions listed by ListSessionProfiles unless asked for another number
+const DEFAULT_SESSION_LIMIT: usize = 10;
+
+/// Bytes HTTP/2 clients, gRPC ones included, open their connections with
+const HTTP2_PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+/// Start running the admin GRPC server. The server doesn't authenticate the callers, it should only listen on the
+/// loopback interface or other addresses that are trusted. The outbound groups can only be managed if the inbound
+/// routes the requests through the router.
+pub async fn start(
+    admin_config: &'static AdminConfig,
+    users: &'static StaticAuthenticator,
+    router: Option<&'static Router>,
+) -> io::Result<()> {
+    let address = match (admin_config.address.as_ref(), admin_config.port)
+        .to_socket_addrs()?
+        .next()
+    {
+        Some(addr) => addr,
+        None => {
+            return Err(Error::new(
+                ErrorKind::AddrNotAvailable,
+                "incorrect admin address in configuration",
+            ))
+        }
+    };
+
+    info!("Admin API listening on {}", address);
+
+    match Server::builder()
+        .add_service(AdminServiceServer::new(AdminApi::new(users, router)))
+        .serve(address)
+        .await
+    {
+        Ok(_) => Ok(()),
+        Err(e) => Err(Error::new(
+            ErrorKind::Interrupted,
+            format!("Failed to start admin server: {}", e),
+        )),
+    }
+}
+
+/// Static cell for storing the admin API served on the proxy port, if it is enabled.
+static PROXY_PORT_ADMIN: OnceCell<ProxyPortAdmin> = OnceCell::new();
+
+/// Admin API multiplexed on the port of the TCP trojan inbound, for hosts behind firewalls that only let that port
+/// in. The acceptor hands it the connections that fail the trojan handshake and open with the HTTP/2 preface rather
+/// than the fallback, including the ones of browsers that negotiated h2 in ALPN, so the fallbacks only get HTTP/1.1.
+/// Every call has to carry the pre-shared key as authorization: Bearer <psk>, the others are refused as
+/// unauthenticated.
+pub struct ProxyPortAdmin {
+    psk: String,
+    users: &'static StaticAuthenticator,
+    router: Option<&'static Router>,
+}
+
+impl ProxyPortAdmin {
+    /// Enable the admin API on the proxy port if the configuration asks for it with a pre-shared key, which the
+    /// parser requires along with proxy_port.
+    pub fn init(
+        admin_config: &AdminConfig,
+        users: &'static StaticAuthenticator,
+        router: Option<&'static Router>,
+    ) -> Option<&'static Self> {
+        if !admin_config.proxy_port.unwrap_or(false) {
+            return None;
+        }
+
+        let psk = admin_config.psk.clone().filter(|psk| !psk.is_empty())?;
+        Some(PROXY_PORT_ADMIN.get_or_init(|| Self { psk, users, router }))
+    }
+
+    /// The admin API served on the proxy port, None if it isn't enabled.
+    pub fn get() -> Option<&'static Self> {
+        PROXY_PORT_ADMIN.get()
+    }
+
+    /// Serve the admin API over the HTTP/2 connection until the client closes it.
+    pub async fn serve<T: AsyncRead + AsyncWrite + Send + Unpin + 'static>(
+        &'static self,
+        stream: T,
+    ) -> io::Result<()> {
+        let service = AdminServiceServer::with_interceptor(
+            AdminApi::new(self.users, self.router),
+            BearerToken(&self.psk),
+        );
+
+        match Http::new()
+            .http2_only(true)
+            .serve_connection(stream, service)
+            .await
+        {
+            Ok(()) => Ok(()),
+            Err(e) => Err(Error::new(ErrorKind::ConnectionAborted, e)),
+        }
+    }
+}
+
+/// Error the acceptor ends the admin API connections with once they are closed, as they don't carry proxy requests.
+/// These connections haven't failed and shouldn't be reported as such.
+#[derive(Debug)]
+pub struct AdminHandoff;
+
+impl AdminHandoff {
+    /// Whether the error ends a connection handed over to the admin API.
+    pub fn is(e: &Error) -> bool {
+        e.get_ref().is_some_and(|e| e.is::<Self>())
+    }
+}
+
+impl fmt::Display for AdminHandoff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "admin API connection closed")
+    }
+}
+
+impl std::error::Error for AdminHandoff {}
+
+/// Refuses the calls that don't carry the pre-shared key as their bearer token.
+#[derive(Clone)]
+struct BearerToken(&'static str);
+
+impl Interceptor for BearerToken {
+    fn call(&mut self, request: Request<()>) -> Result<Request<()>, Status> {
+        let expected = format!("Bearer {}", self.0);
+        let token = request
+            .metadata()
+            .get("authorization")
+            .map(|token| token.as_bytes())
+            .unwrap_or_default();
+
+        // Compare every byte so that the time taken doesn't tell how much of the key is right
+        let matches = token.len() == expected.len()
+            && token
+                .iter()
+                .zip(expected.as_bytes())
+                .fold(0, |diff, (a, b)| diff | (a ^ b))
+                == 0;
+        match matches {
+            true => Ok(request),
+            false => {
+                warn!("Refused admin call on the proxy port without a valid token");
+                Err(Status::unauthenticated("invalid admin token"))
+            }
+        }
+    }
+}
+
+/// Whether the stream starts with the HTTP/2 connection preface, reading more of it into buf if what was read so far
+/// is too short to tell.
+pub async fn is_http2<T: AsyncRead + Unpin>(stream: &mut T, buf: &mut Vec<u8>) -> io::Result<bool> {
+    while buf.len() < HTTP2_PREFACE.len() {
+        if !HTTP2_PREFACE.starts_with(buf) {
+            return Ok(false);
+        }
+        let mut chunk = [0u8; 24];
+        let size = stream
+            .read(&mut chunk[..HTTP2_PREFACE.len() - buf.len()])
+            .await?;
+        if size == 0 {
+            return Ok(false);
+        }
+        buf.extend_from_slice(&chunk[..size]);
+    }
+    Ok(buf.starts_with(HTTP2_PREFACE))
+}
+
+pub struct AdminApi {
+    users: &'static StaticAuthenticator,
+    router: Option<&'static Router>,
+}
+
+impl AdminApi {
+    pub fn new(users: &'static StaticAuthenticator, router: Option<&'static Router>) -> Self {
+        Self { users, router }
+    }
+}
+
+#[tonic::async_trait]
+impl AdminService for AdminApi {
+    async fn add_user(
+        &self,
+        request: Request<AddUserRequest>,
+    ) -> Result<Response<AddUserResponse>, Status> {
+        let request = request.into_inner();
+
+        if request.name.is_empty() || request.secret.is_empty() {
+            return Err(Status::invalid_argument("name and secret are required"));
+        }
+
+        let expires_at = match request.expires_at.as_str() {
+            "" => None,
+            timestamp => match parse_expires_at(timestamp) {
+                Ok(time) => Some(time),
+                Err(e) => return Err(Status::invalid_argument(e.to_string())),
+            },
+        };
+
+        if let Err(e) = self
+            .users
+            .add_user(&request.name, &request.secret, expires_at)
+        {
+            return Err(Status::already_exists(e.to_string()));
+        }
+
+        Ok(Response::new(AddUserResponse {}))
+    }
+
+    async fn remove_user(
+        &self,
+        request: Request<RemoveUserRequest>,
+    ) -> Result<Response<RemoveUserResponse>, Status> {
+        let removed = self.users.remove_user(&request.into_inner().name);
+        Ok(Response::new(RemoveUserResponse { removed }))
+    }
+
+    async fn list_users(
+        &self,
+        _request: Request<ListUsersRequest>,
+    ) -> Result<Response<ListUsersResponse>, Status> {
+        let users = self
+            .users
+            .users()
+            .into_iter()
+            .map(|user| User {
+                expires_at: user
+                    .expires_at
+                    .map(|time| humantime::format_rfc3339_seconds(time).to_string())
+                    .unwrap_or_default(),
+                name: user.name,
+            })
+            .collect();
+
+        Ok(Response::new(ListUsersResponse { users }))
+    }
+
+    async fn get_build_info(
+        &self,
+        _request: Request<GetBuildInfoRequest>,
+    ) -> Result<Response<BuildInfo>, Status> {
+        Ok(Response::new(BuildInfo {
+            version: build_info::VERSION.to_string(),
+            git_commit: build_info::GIT_COMMIT.to_string(),
+            build_date: build_info::BUILD_DATE.to_string(),
+            rustc_version: build_info::RUSTC_VERSION.to_string(),
+            features: build_info::features()
+                .into_iter()
+                .map(|feature| feature.to_string())
+                .collect(),
+        }))
+    }
+
+    async fn list_outbound_groups(
+        &self,
+        _request: Request<ListOutboundGroupsRequest>,
+    ) -> Result<Response<ListOutboundGroupsResponse>, Status> {
+        let groups = self
+            .router
+            .map(|router| router.groups())
+            .unwrap_or_default()
+            .into_iter()
+            .map(|group| OutboundGroup {
+                tag: group.tag().to_string(),
+                r#type: format!("{:?}", group.group_type()),
+                members: group.members().map(|member| member.to_string()).collect(),
+                selected: group.selected().to_string(),
+                draining: group.draining().map(|member| member.to_string()).collect(),
+            })
+            .collect();
+
+        Ok(Response::new(ListOutboundGroupsResponse { groups }))
+    }
+
+    async fn select_outbound(
+        &self,
+        request: Request<SelectOutboundRequest>,
+    ) -> Result<Response<SelectOutboundResponse>, Status> {
+        let request = request.into_inner();
+
+        let group = match self.router.and_then(|router| router.group(&request.group)) {
+            Some(group) => group,
+            None => {
+                return Err(Status::not_found(format!(
+                    "no outbound group {}",
+                    request.group
+                )))
+            }
+        };
+
+        if group.group_type() != GroupType::SELECT {
+            return Err(Status::failed_precondition(format!(
+                "outbound group {} is not a SELECT group",
+                request.group
+            )));
+        }
+
+        if !group.select(&request.outbound) {
+            return Err(Status::not_found(format!(
+                "no outbound {} in group {}",
+                request.outbound, request.group
+            )));
+        }
+
+        Ok(Response::new(SelectOutboundResponse {}))
+    }
+
+    async fn drain_outbound(
+        &self,
+        request: Request<DrainOutboundRequest>,
+    ) -> Result<Response<DrainOutboundResponse>, Status> {
+        let request = request.into_inner();
+
+        let router = match self.router {
+            Some(router) => router,
+            None => {
+                return Err(Status::failed_precondition(
+                    "the inbound doesn't use the router",
+                ))
+            }
+        };
+
+        match router.drain(&request.outbound, request.draining) {
+            Some(sessions) => Ok(Response::new(DrainOutboundResponse {
+                sessions: sessions as u64,
+            })),
+            None => Err(Status::not_found(format!(
+                "no outbound {}",
+                request.outbound
+            ))),
+        }
+    }
+
+    async fn update_routing_databases(
+        &self,
+        _request: Request<UpdateRoutingDatabasesRequest>,
+    ) -> Result<Response<UpdateRoutingDatabasesResponse>, Status> {
+        let router = match self.router {
+            Some(router) => router,
+            None => {
+                return Err(Status::failed_precondition(
+                    "the inbound doesn't use the router",
+                ))
+            }
+        };
+
+        match router.update_databases().await {
+            Ok(()) => Ok(Response::new(UpdateRoutingDatabasesResponse {})),
+            Err(e) => Err(Status::unavailable(e.to_string())),
+        }
+    }
+
+    async fn list_session_profiles(
+        &self,
+        request: Request<ListSessionProfilesRequest>,
+    ) -> Result<Response<ListSessionProfilesResponse>, Status> {
+        if !profiling::enabled() {
+            return Err(Status::unimplemented(
+                "the binary is built without the profiling feature",
+            ));
+        }
+
+        let request = request.into_inner();
+        let order = match request.order_by() {
+            Order::CpuTime => SessionOrder::CpuTime,
+            Order::AllocatedBytes => SessionOrder::AllocatedBytes,
+        };
+        let limit = match request.limit {
+            0 => DEFAULT_SESSION_LIMIT,
+            limit => limit as usize,
+        };
+
+        let sessions = profiling::top_sessions(order, limit)
+            .into_iter()
+            .map(|session| SessionProfile {
+                id: session.id,
+                source: session.source.to_string(),
+                destination: session.destination.unwrap_or_default(),
+                allocated_bytes: session.allocated_bytes,
+                allocations: session.allocations,
+                cpu_time_micros: session.cpu_time.as_micros() as u64,
+                active: session.active,
+                inbound: session.context.inbound.unwrap_or_default(),
+                outbound: session.context.outbound.unwrap_or_default(),
+                user: session.context.user.unwrap_or_default(),
+            })
+            .collect();
+
+        Ok(Response::new(ListSessionProfilesResponse { sessions }))
+    }
+
+    async fn list_connections(
+        &self,
+        request: Request<ListConnectionsRequest>,
+    ) -> Result<Response<ListConnectionsResponse>, Status> {
+        let user = request.into_inner().user;
+        let connections = active_connections()
+            .into_iter()
+            .filter(|connection| user.is_empty() || connection.context.user.as_ref() == Some(&user))
+            .map(|connection| Connection {
+                id: connection.id,
+                source: connection.source.to_string(),
+                destination: connection.context.destination.unwrap_or_default(),
+                inbound: connection.context.inbound.unwrap_or_default(),
+                outbound: connection.context.outbound.unwrap_or_default(),
+                user: connection.context.user.unwrap_or_default(),
+                transport: connection.transport.to_string(),
+                mode: connection.mode.to_string(),
+                uploaded_bytes: connection.transfer.uploaded(),
+                downloaded_bytes: connection.transfer.downloaded(),
+                age_millis: connection.transfer.duration().as_millis() as u64,
+            })
+            .collect();
+
+        Ok(Response::new(ListConnectionsResponse { connections }))
+    }
+
+    async fn close_connections(
+        &self,
+        request: Request<CloseConnectionsRequest>,
+    ) -> Result<Response<CloseConnectionsResponse>, Status> {
+        let request = request.into_inner();
+
+        let source_ip: Option<IpAddr> = match request.source_ip.as_str() {
+            "" => None,
+            ip => match ip.parse() {
+                Ok(ip) => Some(ip),
+                Err(e) => return Err(Status::invalid_argument(format!("{}: {}", ip, e))),
+            },
+        };
+        let user = Some(request.user).filter(|user| !user.is_empty());
+        if request.id == 0 && user.is_none() && source_ip.is_none() {
+            return Err(Status::invalid_argument(
+                "id, user or source_ip is required",
+            ));
+        }
+
+        let closed = close_connections(|connection| {
+            (request.id == 0 || connection.id == request.id)
+                && (user.is_none() || connection.context.user == user)
+                && (source_ip.is_none() || source_ip == Some(connection.source.ip()))
+        });
+        if closed > 0 {
+            info!("Closed {} connections through the admin API", closed);
+        }
+
+        Ok(Response::new(CloseConnectionsResponse {
+            closed: closed as u64,
+        }))
+    }
+
+    #[cfg(feature = "sqlite")]
+    async fn get_traffic_stats(
+        &self,
+        request: Request<GetTrafficStatsRequest>,
+    ) -> Result<Response<GetTrafficStatsResponse>, Status> {
+        let stats = match TrafficStats::get() {
+            Some(stats) => stats,
+            None => {
+                return Err(Status::failed_precondition(
+                    "traffic_stats isn't configured",
+                ))
+            }
+        };
+
+        let request = request.into_inner();
+        let days = match stats
+            .query(&request.user, &request.since, &request.until)
+            .await
+        {
+            Ok(days) => days,
+            Err(e) if e.kind() == ErrorKind::InvalidInput => {
+                return Err(Status::invalid_argument(e.to_string()))
+            }
+            Err(e) => return Err(Status::internal(e.to_string())),
+        };
+
+        Ok(Response::new(GetTrafficStatsResponse {
+            days: days
+                .into_iter()
+                .map(|day| DailyTraffic {
+                    user: day.user,
+                    day: day.day,
+                    uploaded_bytes: day.uploaded,
+                    downloaded_bytes: day.downloaded,
+                })
+                .collect(),
+        }))
+    }
+
+    #[cfg(not(feature = "sqlite"))]
+    async fn get_traffic_stats(
+        &self,
+        _request: Request<GetTrafficStatsRequest>,
+    ) -> Result<Response<GetTrafficStatsResponse>, Status> {
+        Err(Status::unimplemented(
+            "the binary is built without the sqlite feature",
+        ))
+    }
+}
@@ -0,0 +1,63 @@
+use crate::auth::{Authenticator, User};
+
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::io::Result;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Upper bound of the cached credentials. The cache is cleared once it is full, as unknown credentials are cached as
+/// well and could otherwise be used to grow the cache without bound.
+const MAX_CACHE_ENTRIES: usize = 4096;
+
+/// Cache the results of a slow authenticator, typically an external database, for a fixed amount of time. Both
+/// found and unknown credentials are cached so that repeated probes don't reach the database either.
+pub struct CachedAuthenticator {
+    inner: Box<dyn Authenticator>,
+    ttl: Duration,
+    entries: Mutex<HashMap<Vec<u8>, CacheEntry>>,
+}
+
+/// Result of the authentication cached for a credential
+struct CacheEntry {
+    user: Option<User>,
+    cached_at: Instant,
+}
+
+impl CachedAuthenticator {
+    pub fn new(inner: Box<dyn Authenticator>, ttl: Duration) -> Self {
+        Self {
+            inner,
+            ttl,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+#[async_trait]
+impl Authenticator for CachedAuthenticator {
+    async fn authenticate(&self, credential: &[u8]) -> Result<Option<User>> {
+        if let Some(entry) = self.entries.lock().unwrap().get(credential) {
+            if entry.cached_at.elapsed() < self.ttl {
+                return Ok(entry.user.clone());
+            }
+        }
+
+        // Errors are not cached, the backend is consulted again by the next handshake
+        let user = self.inner.authenticate(credential).await?;
+
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= MAX_CACHE_ENTRIES {
+            entries.clear();
+        }
+        entries.insert(
+            credential.to_vec(),
+            CacheEntry {
+                user: user.clone(),
+                cached_at: Instant::now(),
+            },
+        );
+
+        Ok(user)
+    }
+}
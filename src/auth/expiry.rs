@@ -0,0 +1,21 @@
+use crate::auth::{AuthContext, Authorizer};
+
+use async_trait::async_trait;
+use std::io::{Error, ErrorKind, Result};
+use std::time::SystemTime;
+
+/// Refuse the users whose access has expired.
+pub struct ExpiryAuthorizer;
+
+#[async_trait]
+impl Authorizer for ExpiryAuthorizer {
+    async fn authorize(&self, context: &AuthContext<'_>) -> Result<()> {
+        match context.user.expires_at {
+            Some(expires_at) if expires_at <= SystemTime::now() => Err(Error::new(
+                ErrorKind::PermissionDenied,
+                format!("User {} has expired", context.user),
+            )),
+            _ => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,51 @@
+use crate::auth::{AuthContext, Authorizer};
+use crate::config::base::InboundConfig;
+
+use async_trait::async_trait;
+use ipnet::IpNet;
+use std::io::{Error, ErrorKind, Result};
+use std::net::IpAddr;
+
+/// Only allow the clients connecting from the configured address ranges.
+pub struct IpAuthorizer {
+    allowed: Vec<IpNet>,
+}
+
+impl IpAuthorizer {
+    /// Build the authorizer from allowed_ips of the inbound configuration, returns None if no restriction is set.
+    /// Both CIDR notation and plain IP addresses are accepted, anything else fails with InvalidInput.
+    pub fn new(inbound: &InboundConfig) -> Result<Option<Self>> {
+        let allowed_ips = match &inbound.allowed_ips {
+            Some(allowed_ips) => allowed_ips,
+            None => return Ok(None),
+        };
+        let allowed = allowed_ips
+            .iter()
+            .map(|ip| match ip.parse::<IpNet>() {
+                Ok(net) => Ok(net),
+                Err(_) => match ip.parse::<IpAddr>() {
+                    Ok(addr) => Ok(IpNet::from(addr)),
+                    Err(e) => Err(Error::new(
+                        ErrorKind::InvalidInput,
+                        format!("invalid allowed ip {}: {}", ip, e),
+                    )),
+                },
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Some(Self { allowed }))
+    }
+}
+
+#[async_trait]
+impl Authorizer for IpAuthorizer {
+    async fn authorize(&self, context: &AuthContext<'_>) -> Result<()> {
+        match context.source {
+            Some(source) if self.allowed.iter().any(|net| net.contains(&source.ip())) => Ok(()),
+            _ => Err(Error::new(
+                ErrorKind::PermissionDenied,
+                format!("User {} is not allowed from this address", context.user),
+            )),
+        }
+    }
+}
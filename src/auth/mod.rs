@@ -0,0 +1,219 @@
+#[cfg(feature = "server")]
+pub mod cache;
+pub mod expiry;
+pub mod ip;
+#[cfg(feature = "mysql")]
+pub mod mysql;
+pub mod port;
+#[cfg(feature = "redis")]
+pub mod redis;
+pub mod secret;
+
+#[cfg(any(feature = "redis", feature = "mysql"))]
+use crate::config::base::AuthBackend;
+#[cfg(feature = "server")]
+use crate::config::base::AuthBackendConfig;
+use crate::config::base::InboundConfig;
+use crate::protocol::common::request::InboundRequest;
+
+use async_trait::async_trait;
+use log::info;
+use once_cell::sync::OnceCell;
+use std::fmt;
+use std::io::{Error, ErrorKind, Result};
+use std::net::SocketAddr;
+#[cfg(feature = "server")]
+use std::time::Duration;
+use std::time::SystemTime;
+
+#[cfg(feature = "server")]
+use self::cache::CachedAuthenticator;
+use self::expiry::ExpiryAuthorizer;
+use self::ip::IpAuthorizer;
+use self::port::PortAuthorizer;
+use self::secret::StaticAuthenticator;
+
+/// Static lifetime authentication chain shared by all the inbound acceptors
+static AUTH_CHAIN: OnceCell<AuthChain> = OnceCell::new();
+
+/// Default time in seconds the results of the external authentication backend are cached for
+#[cfg(feature = "server")]
+const DEFAULT_CACHE_TTL: u64 = 60;
+
+/// Identity of an authenticated user
+#[derive(Clone, Debug)]
+pub struct User {
+    pub name: String,
+    pub expires_at: Option<SystemTime>,
+}
+
+impl fmt::Display for User {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(fmt, "{}", self.name)
+    }
+}
+
+/// Information about the proxy request available to the authorizers
+pub struct AuthContext<'a> {
+    pub user: &'a User,
+    pub source: Option<SocketAddr>,
+    pub request: &'a InboundRequest,
+}
+
+/// Authenticator identifies the user from the credential in the proxy request, which is the hex value for Trojan.
+#[async_trait]
+pub trait Authenticator: Send + Sync {
+    /// Returns the user owning the credential, or None if the credential isn't known to the authenticator, in which
+    /// case the next authenticator in the chain is consulted.
+    async fn authenticate(&self, credential: &[u8]) -> Result<Option<User>>;
+}
+
+#[async_trait]
+impl<A: Authenticator + 'static> Authenticator for &'static A {
+    async fn authenticate(&self, credential: &[u8]) -> Result<Option<User>> {
+        (**self).authenticate(credential).await
+    }
+}
+
+/// Authorizer decides whether an authenticated user is allowed to proceed with the proxy request.
+#[async_trait]
+pub trait Authorizer: Send + Sync {
+    /// Returns an error if the request should be rejected.
+    async fn authorize(&self, context: &AuthContext<'_>) -> Result<()>;
+}
+
+/// AuthChain is executed after the proxy handshake. The authenticators are consulted in order until one of them
+/// identifies the user, then all the authorizers have to approve the request. Deployments compose their policies by
+/// adding authenticators and authorizers to the chain rather than patching the handlers.
+pub struct AuthChain {
+    authenticators: Vec<Box<dyn Authenticator>>,
+    authorizers: Vec<Box<dyn Authorizer>>,
+}
+
+impl AuthChain {
+    /// Build the chain from the inbound configuration.
+    pub fn init(inbound: &InboundConfig) -> Result<&'static Self> {
+        AUTH_CHAIN.get_or_try_init(|| {
+            let mut chain = Self::new();
+
+            chain.add_authenticator(Box::new(StaticAuthenticator::init(inbound)?));
+            // The user databases are only built into the servers, the parser rejects the backend in the others
+            #[cfg(feature = "server")]
+            if let Some(backend) = &inbound.auth_backend {
+                chain.add_authenticator(external_authenticator(backend)?);
+            }
+            chain.add_authorizer(Box::new(ExpiryAuthorizer));
+
+            if let Some(authorizer) = IpAuthorizer::new(inbound)? {
+                chain.add_authorizer(Box::new(authorizer));
+            }
+            if let Some(authorizer) = PortAuthorizer::new(inbound)? {
+                chain.add_authorizer(Box::new(authorizer));
+            }
+
+            Ok(chain)
+        })
+    }
+
+    pub fn new() -> Self {
+        Self {
+            authenticators: Vec::new(),
+            authorizers: Vec::new(),
+        }
+    }
+
+    pub fn add_authenticator(&mut self, authenticator: Box<dyn Authenticator>) {
+        self.authenticators.push(authenticator);
+    }
+
+    pub fn add_authorizer(&mut self, authorizer: Box<dyn Authorizer>) {
+        self.authorizers.push(authorizer);
+    }
+
+    /// Run the request through the chain, returns the authenticated user if the request is allowed.
+    pub async fn authenticate(
+        &self,
+        credential: &[u8],
+        source: Option<SocketAddr>,
+        request: &InboundRequest,
+    ) -> Result<User> {
+        let mut user = None;
+        for authenticator in self.authenticators.iter() {
+            if let Some(u) = authenticator.authenticate(credential).await? {
+                user = Some(u);
+                break;
+            }
+        }
+
+        let user = match user {
+            Some(user) => user,
+            None => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "Received invalid hex value",
+                ))
+            }
+        };
+
+        let context = AuthContext {
+            user: &user,
+            source,
+            request,
+        };
+
+        for authorizer in self.authorizers.iter() {
+            authorizer.authorize(&context).await?;
+        }
+
+        info!("Authenticated user {}", user);
+
+        Ok(user)
+    }
+}
+
+/// Build the authenticator for the external backend, wrapped with the in memory cache.
+#[cfg(feature = "server")]
+fn external_authenticator(config: &AuthBackendConfig) -> Result<Box<dyn Authenticator>> {
+    let backend = backend_authenticator(config)?;
+    let ttl = Duration::from_secs(config.cache_ttl.unwrap_or(DEFAULT_CACHE_TTL));
+    Ok(Box::new(CachedAuthenticator::new(backend, ttl)))
+}
+
+/// Fails with InvalidData if the backend isn't compiled into this build, which the parser rejects beforehand.
+#[cfg(feature = "server")]
+fn backend_authenticator(config: &AuthBackendConfig) -> Result<Box<dyn Authenticator>> {
+    match config.backend {
+        #[cfg(feature = "redis")]
+        AuthBackend::REDIS => Ok(Box::new(self::redis::RedisAuthenticator::new(config)?)),
+        #[cfg(feature = "mysql")]
+        AuthBackend::MYSQL => Ok(Box::new(self::mysql::MysqlAuthenticator::new(config)?)),
+        #[allow(unreachable_patterns)]
+        _ => Err(Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "Authentication backend {:?} is not supported by this build, enable it with --features",
+                config.backend
+            ),
+        )),
+    }
+}
+
+/// Check the backend can be connected to as configured, without connecting to it. Fails with InvalidInput if its url
+/// can't be parsed.
+#[cfg(feature = "server")]
+pub fn check_backend(config: &AuthBackendConfig) -> Result<()> {
+    match config.backend {
+        #[cfg(feature = "redis")]
+        AuthBackend::REDIS => self::redis::RedisAuthenticator::new(config).map(drop),
+        #[cfg(feature = "mysql")]
+        AuthBackend::MYSQL => self::mysql::MysqlAuthenticator::options(config).map(drop),
+        #[allow(unreachable_patterns)]
+        _ => Ok(()),
+    }
+}
+
+impl Default for AuthChain {
+    fn default() -> Self {
+        Self::new()
+    }
+}
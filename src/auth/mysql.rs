@@ -0,0 +1,63 @@
+use crate::auth::{Authenticator, User};
+use crate::config::base::AuthBackendConfig;
+
+use async_trait::async_trait;
+use sqlx::mysql::{MySqlConnectOptions, MySqlPool};
+use std::io::{Error, ErrorKind, Result};
+
+/// Default query compatible with the users table of trojan-gfw
+const DEFAULT_QUERY: &str = "SELECT username FROM users WHERE password = ?";
+
+/// Authenticate the users against a MySQL table, the query takes the hex value and returns the name of the user.
+pub struct MysqlAuthenticator {
+    pool: MySqlPool,
+    query: String,
+}
+
+impl MysqlAuthenticator {
+    /// Fails with InvalidInput if the url can't be parsed. Must be called within the runtime, which runs the
+    /// maintenance of the pool.
+    pub fn new(config: &AuthBackendConfig) -> Result<Self> {
+        // Connections are opened on demand, so the server can start while the database is unavailable
+        let pool = MySqlPool::connect_lazy_with(Self::options(config)?);
+
+        Ok(Self {
+            pool,
+            query: config
+                .query
+                .clone()
+                .unwrap_or_else(|| DEFAULT_QUERY.to_string()),
+        })
+    }
+
+    /// Connection options of the url, fails with InvalidInput if it can't be parsed.
+    pub fn options(config: &AuthBackendConfig) -> Result<MySqlConnectOptions> {
+        config.url.parse().map_err(|e| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!("invalid mysql url {}: {}", config.url, e),
+            )
+        })
+    }
+}
+
+#[async_trait]
+impl Authenticator for MysqlAuthenticator {
+    async fn authenticate(&self, credential: &[u8]) -> Result<Option<User>> {
+        let hex = match std::str::from_utf8(credential) {
+            Ok(hex) => hex,
+            Err(_) => return Ok(None),
+        };
+
+        let name: Option<String> = sqlx::query_scalar(&self.query)
+            .bind(hex)
+            .fetch_optional(&self.pool)
+            .await
+            .map_err(Error::other)?;
+
+        Ok(name.map(|name| User {
+            name,
+            expires_at: None,
+        }))
+    }
+}
@@ -0,0 +1,133 @@
+use crate::auth::{AuthContext, Authorizer};
+use crate::config::base::{InboundConfig, PortConfig};
+use crate::metrics;
+use crate::protocol::common::command::Command;
+use crate::protocol::common::request::{InboundRequest, TransportProtocol};
+use crate::router::matcher::parse_port_range;
+
+use async_trait::async_trait;
+use log::debug;
+use once_cell::sync::OnceCell;
+use std::io::{Error, ErrorKind, Result};
+use std::ops::RangeInclusive;
+
+/// Static port policy of the inbound shared by the handlers and the UDP sessions
+static PORT_POLICY: OnceCell<Option<PortAuthorizer>> = OnceCell::new();
+
+/// Only allow the requests to the destination ports of the inbound configuration, the ports in the allow list if
+/// there is one and not in the deny list. The trojan requests are checked by the authorization chain, and the others
+/// where the inbounds dispatch them, while the destinations of the UDP datagrams are checked by the guards of their
+/// sessions. Refused requests and datagrams are counted in destination_ports_blocked_total.
+pub struct PortAuthorizer {
+    allow: Option<Vec<RangeInclusive<u16>>>,
+    deny: Vec<RangeInclusive<u16>>,
+}
+
+impl PortAuthorizer {
+    /// Build the authorizer from destination_ports of the inbound configuration, returns None if no restriction is
+    /// set. Fails with InvalidInput on invalid port ranges.
+    pub fn new(inbound: &InboundConfig) -> Result<Option<Self>> {
+        let config = match &inbound.destination_ports {
+            Some(config) => config,
+            None => return Ok(None),
+        };
+        Ok(Some(Self {
+            allow: config.allow.as_deref().map(ranges).transpose()?,
+            deny: config
+                .deny
+                .as_deref()
+                .map(ranges)
+                .transpose()?
+                .unwrap_or_default(),
+        }))
+    }
+
+    /// Build the policy shared by the whole process from the inbound configuration, None if no restriction is set.
+    pub fn init(inbound: &InboundConfig) -> Result<Option<&'static Self>> {
+        Ok(PORT_POLICY.get_or_try_init(|| Self::new(inbound))?.as_ref())
+    }
+
+    /// Policy shared by the whole process, None if no restriction is set or it wasn't initialized.
+    pub fn get() -> Option<&'static Self> {
+        PORT_POLICY.get_or_init(|| None).as_ref()
+    }
+
+    /// Fails with PermissionDenied if requests to the port aren't allowed, counting it for the transport.
+    pub fn check(&self, port: u16, transport: &str) -> Result<()> {
+        if self.allows(port) {
+            return Ok(());
+        }
+
+        debug!("Refusing {} request to port {}", transport, port);
+        metrics::increment(
+            &format!(
+                "destination_ports_blocked_total{{transport=\"{}\"}}",
+                transport
+            ),
+            1,
+        );
+        Err(Error::new(
+            ErrorKind::PermissionDenied,
+            format!("port {} is not allowed", port),
+        ))
+    }
+
+    /// Fails with PermissionDenied if the request connects to a port that isn't allowed. The UDP requests pass, the
+    /// destinations of their datagrams are checked one by one instead.
+    pub fn check_request(&self, request: &InboundRequest, transport: &str) -> Result<()> {
+        match request.transport_protocol {
+            TransportProtocol::TCP => self.check(request.addr_port.port, transport),
+            TransportProtocol::UDP => Ok(()),
+        }
+    }
+
+    /// Whether requests to the port are allowed.
+    pub fn allows(&self, port: u16) -> bool {
+        !self.deny.iter().any(|range| range.contains(&port))
+            && match &self.allow {
+                Some(allow) => allow.iter().any(|range| range.contains(&port)),
+                None => true,
+            }
+    }
+}
+
+#[async_trait]
+impl Authorizer for PortAuthorizer {
+    async fn authorize(&self, context: &AuthContext<'_>) -> Result<()> {
+        let port = context.request.addr_port.port;
+        match context.request.command {
+            Command::Connect if !self.allows(port) => Err(Error::new(
+                ErrorKind::PermissionDenied,
+                format!(
+                    "User {} is not allowed to connect to port {}",
+                    context.user, port
+                ),
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Fails with PermissionDenied if the request connects to a port the inbound doesn't allow, see
+/// PortAuthorizer::check_request.
+pub fn check_request(request: &InboundRequest, transport: &str) -> Result<()> {
+    match PortAuthorizer::get() {
+        Some(policy) => policy.check_request(request, transport),
+        None => Ok(()),
+    }
+}
+
+fn ranges(ports: &[PortConfig]) -> Result<Vec<RangeInclusive<u16>>> {
+    ports
+        .iter()
+        .map(|port| match port {
+            PortConfig::Single(port) => Ok(*port..=*port),
+            PortConfig::Range(range) => parse_port_range(range).ok_or_else(|| {
+                Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("invalid destination port range {}", range),
+                )
+            }),
+        })
+        .collect()
+}
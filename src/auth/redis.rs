@@ -0,0 +1,61 @@
+use crate::auth::{Authenticator, User};
+use crate::config::base::AuthBackendConfig;
+
+use async_trait::async_trait;
+use redis::aio::ConnectionManager;
+use redis::{AsyncCommands, Client};
+use std::io::{Error, ErrorKind, Result};
+use tokio::sync::OnceCell;
+
+/// Default prefix of the keys storing the users
+const DEFAULT_KEY_PREFIX: &str = "trojan:user:";
+
+/// Authenticate the users against a Redis server, where the name of the user is stored under the key prefix + hex.
+pub struct RedisAuthenticator {
+    client: Client,
+    key_prefix: String,
+    connection: OnceCell<ConnectionManager>,
+}
+
+impl RedisAuthenticator {
+    /// Fails with InvalidInput if the url can't be parsed.
+    pub fn new(config: &AuthBackendConfig) -> Result<Self> {
+        let client = Client::open(config.url.as_str()).map_err(|e| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!("invalid redis url {}: {}", config.url, e),
+            )
+        })?;
+
+        Ok(Self {
+            client,
+            key_prefix: config
+                .key_prefix
+                .clone()
+                .unwrap_or_else(|| DEFAULT_KEY_PREFIX.to_string()),
+            connection: OnceCell::new(),
+        })
+    }
+}
+
+#[async_trait]
+impl Authenticator for RedisAuthenticator {
+    async fn authenticate(&self, credential: &[u8]) -> Result<Option<User>> {
+        // The connection is established on first use and reconnects by itself afterwards
+        let connection = self
+            .connection
+            .get_or_try_init(|| ConnectionManager::new(self.client.clone()))
+            .await
+            .map_err(|e| Error::new(ErrorKind::ConnectionRefused, e))?;
+
+        let mut key = self.key_prefix.as_bytes().to_vec();
+        key.extend_from_slice(credential);
+
+        let name: Option<String> = connection.clone().get(key).await.map_err(Error::other)?;
+
+        Ok(name.map(|name| User {
+            name,
+            expires_at: None,
+        }))
+    }
+}
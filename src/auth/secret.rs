@@ -0,0 +1,181 @@
+use crate::auth::{Authenticator, User};
+use crate::config::base::InboundConfig;
+
+use async_trait::async_trait;
+use log::info;
+use once_cell::sync::OnceCell;
+use sha2::{Digest, Sha224};
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Result};
+use std::sync::RwLock;
+use std::time::{Duration, SystemTime};
+
+/// Name of the user authenticated with the secret field of the inbound configuration
+const DEFAULT_USER: &str = "default";
+
+/// Static lifetime user table shared by the authentication chain and the admin API
+static STATIC_AUTHENTICATOR: OnceCell<StaticAuthenticator> = OnceCell::new();
+
+/// Convert the plaintext secret into the hex value used as the credential in Trojan requests.
+pub fn secret_hex(secret: &str) -> Vec<u8> {
+    Sha224::digest(secret.as_bytes())
+        .iter()
+        .map(|x| format!("{:02x}", x))
+        .collect::<String>()
+        .into_bytes()
+}
+
+/// Parse the expiration time of a user, which is an RFC 3339 timestamp like 2023-01-01T00:00:00Z, or with an offset
+/// from UTC like 2023-01-01T08:00:00+08:00.
+pub fn parse_expires_at(timestamp: &str) -> Result<SystemTime> {
+    let (local, offset) = match split_offset(timestamp) {
+        Some((local, offset)) => (local, offset),
+        None => (timestamp, 0),
+    };
+
+    let time = match humantime::parse_rfc3339_weak(local) {
+        Ok(time) => time,
+        Err(e) => return Err(Error::new(ErrorKind::InvalidInput, e)),
+    };
+
+    // The local time is ahead of UTC by the offset
+    let utc = match offset {
+        0.. => time.checked_sub(Duration::from_secs(offset as u64)),
+        _ => time.checked_add(Duration::from_secs(offset.unsigned_abs())),
+    };
+    match utc {
+        Some(utc) => Ok(utc),
+        None => Err(Error::new(
+            ErrorKind::InvalidInput,
+            "timestamp is out of range",
+        )),
+    }
+}
+
+/// Split the offset from UTC like +08:00 off the end of the timestamp, returning the local time and the offset in
+/// seconds. None if the timestamp doesn't end with an offset.
+fn split_offset(timestamp: &str) -> Option<(&str, i64)> {
+    // The offset follows the date and the time, so that the dashes of the date aren't taken for a negative offset
+    let start = timestamp.len().checked_sub(6).filter(|start| *start > 10)?;
+    let (local, offset) = (timestamp.get(..start)?, timestamp.get(start..)?);
+
+    let sign = match offset.as_bytes()[0] {
+        b'+' => 1,
+        b'-' => -1,
+        _ => return None,
+    };
+    if offset.as_bytes()[3] != b':' {
+        return None;
+    }
+    let hours: i64 = offset.get(1..3)?.parse().ok()?;
+    let minutes: i64 = offset.get(4..6)?.parse().ok()?;
+    if hours > 23 || minutes > 59 {
+        return None;
+    }
+
+    Some((local, sign * (hours * 3600 + minutes * 60)))
+}
+
+/// Authenticate the users against the secrets listed in the configuration file. Users can also be added and removed
+/// at runtime, which only affects the handshakes afterwards, the connections already established keep running.
+pub struct StaticAuthenticator {
+    users: RwLock<HashMap<Vec<u8>, User>>,
+}
+
+impl StaticAuthenticator {
+    /// Get the user table shared by the whole process, built from the inbound configuration on first use.
+    pub fn init(inbound: &InboundConfig) -> Result<&'static Self> {
+        STATIC_AUTHENTICATOR.get_or_try_init(|| Self::new(inbound))
+    }
+
+    /// Fails with InvalidInput if the expiration time of a user can't be parsed.
+    pub fn new(inbound: &InboundConfig) -> Result<Self> {
+        let mut users = HashMap::new();
+
+        if let Some(secret) = &inbound.secret {
+            users.insert(
+                secret_hex(secret),
+                User {
+                    name: DEFAULT_USER.to_string(),
+                    expires_at: None,
+                },
+            );
+        }
+
+        for user in inbound.users.iter().flatten() {
+            let expires_at = match &user.expires_at {
+                Some(timestamp) => Some(parse_expires_at(timestamp).map_err(|e| {
+                    Error::new(
+                        ErrorKind::InvalidInput,
+                        format!("invalid expires_at of user {}: {}", user.name, e),
+                    )
+                })?),
+                None => None,
+            };
+
+            users.insert(
+                secret_hex(&user.secret),
+                User {
+                    name: user.name.clone(),
+                    expires_at,
+                },
+            );
+        }
+
+        Ok(Self {
+            users: RwLock::new(users),
+        })
+    }
+
+    /// Add the user with the secret, replacing the user with the same name if there is one. Fails with AlreadyExists
+    /// if another user has the secret.
+    pub fn add_user(&self, name: &str, secret: &str, expires_at: Option<SystemTime>) -> Result<()> {
+        let hex = secret_hex(secret);
+        let mut users = self.users.write().unwrap();
+        if let Some(user) = users.get(&hex).filter(|user| user.name != name) {
+            return Err(Error::new(
+                ErrorKind::AlreadyExists,
+                format!("secret is already used by user {}", user.name),
+            ));
+        }
+
+        users.retain(|_, user| user.name != name);
+        users.insert(
+            hex,
+            User {
+                name: name.to_string(),
+                expires_at,
+            },
+        );
+
+        info!("Added user {}", name);
+        Ok(())
+    }
+
+    /// Remove the user with the name, returns false if there is no such user.
+    pub fn remove_user(&self, name: &str) -> bool {
+        let mut users = self.users.write().unwrap();
+        let count = users.len();
+        users.retain(|_, user| user.name != name);
+
+        let removed = users.len() != count;
+        if removed {
+            info!("Removed user {}", name);
+        }
+        removed
+    }
+
+    /// List the users currently accepted, sorted by name.
+    pub fn users(&self) -> Vec<User> {
+        let mut users: Vec<User> = self.users.read().unwrap().values().cloned().collect();
+        users.sort_by(|a, b| a.name.cmp(&b.name));
+        users
+    }
+}
+
+#[async_trait]
+impl Authenticator for StaticAuthenticator {
+    async fn authenticate(&self, credential: &[u8]) -> Result<Option<User>> {
+        Ok(self.users.read().unwrap().get(credential).cloned())
+    }
+}
@@ -0,0 +1,49 @@
+/// Version of the crate
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Abbreviated hash of the git commit the binary is built from, unknown if built outside of a git checkout
+pub const GIT_COMMIT: &str = env!("TROJAN_GIT_COMMIT");
+
+/// RFC 3339 timestamp of the build
+pub const BUILD_DATE: &str = env!("TROJAN_BUILD_DATE");
+
+/// Output of rustc --version of the compiler building the binary
+pub const RUSTC_VERSION: &str = env!("TROJAN_RUSTC_VERSION");
+
+/// Transports built into every binary, followed by the Cargo features the binary is built with.
+pub fn features() -> Vec<&'static str> {
+    let optional = [
+        ("client", cfg!(feature = "client")),
+        ("server", cfg!(feature = "server")),
+        ("redis", cfg!(feature = "redis")),
+        ("mysql", cfg!(feature = "mysql")),
+        ("sqlite", cfg!(feature = "sqlite")),
+        ("profiling", cfg!(feature = "profiling")),
+        // io_uring is only used on Linux, the feature does nothing elsewhere
+        (
+            "io-uring",
+            cfg!(all(target_os = "linux", feature = "io-uring")),
+        ),
+    ];
+
+    let mut features = vec!["tcp", "tls", "grpc", "quic"];
+    features.extend(
+        optional
+            .iter()
+            .filter(|(_, enabled)| *enabled)
+            .map(|(name, _)| *name),
+    );
+    features
+}
+
+/// Multi line description of the build printed by --version.
+pub fn long_version() -> String {
+    format!(
+        "{}\ncommit: {}\nbuilt: {}\nrustc: {}\nfeatures: {}",
+        VERSION,
+        GIT_COMMIT,
+        BUILD_DATE,
+        RUSTC_VERSION,
+        features().join(", ")
+    )
+}
@@ -1,20 +1,240 @@
+use crate::protocol::common::request::TransportProtocol;
 use crate::proxy::base::SupportedProtocols;
 
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 
 #[derive(Serialize, Deserialize)]
 pub struct Config {
     pub inbound: InboundConfig,
     pub outbound: OutboundConfig,
+    pub outbounds: Option<Vec<OutboundConfig>>,
+    pub outbound_groups: Option<Vec<OutboundGroupConfig>>,
+    pub router: Option<RouterConfig>,
+    pub metrics: Option<MetricsConfig>,
+    pub admin: Option<AdminConfig>,
+    pub events: Option<EventsConfig>,
+    pub transports: Option<TransportsConfig>,
+    pub dns: Option<DnsConfig>,
+    pub fake_dns: Option<FakeDnsConfig>,
+    pub dns_inbound: Option<DnsInboundConfig>,
+    pub policy: Option<PolicyConfig>,
+    pub policies: Option<HashMap<String, PolicyConfig>>,
+    pub reaper: Option<ReaperConfig>,
+    pub relay_buffer_size: Option<usize>,
+    pub io_uring: Option<IoUringConfig>,
+    pub runtime: Option<RuntimeConfig>,
+    pub bandwidth: Option<GlobalBandwidthConfig>,
+    pub log: Option<LogConfig>,
+    pub tracing: Option<TracingConfig>,
+    pub traffic_stats: Option<TrafficStatsConfig>,
+    pub health: Option<HealthConfig>,
 }
 
-/// Inbound traffic supports the following 3 modes: 
-/// 
+/// HTTP endpoint of the liveness and readiness probes of load balancers and Kubernetes, listening on address and port.
+/// /livez answers as long as the process runs, and /readyz once the inbound listens, the outbound is reachable and the
+/// certificate of the inbound hasn't expired. The outbound is probed by connecting to probe, a host:port, every
+/// probe_interval seconds, 10 by default, or to the remote server of TCP, GRPC and RACE outbounds if probe isn't set.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct HealthConfig {
+    pub address: String,
+    pub port: u16,
+    pub probe: Option<String>,
+    pub probe_interval: Option<u64>,
+}
+
+/// Bytes relayed by each user and day, stored in the SQLite database at path, which is created if missing, for the
+/// builds with the sqlite feature. The counts are written to the database every flush_interval seconds, 60 by default,
+/// and queried through the GetTrafficStats call of the admin API.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct TrafficStatsConfig {
+    pub path: String,
+    pub flush_interval: Option<u64>,
+}
+
+/// Export the spans of the connections to an OpenTelemetry collector at endpoint, the http or https url of its
+/// OTLP/HTTP JSON traces receiver like http://127.0.0.1:4318/v1/traces. service_name names the process in the traces,
+/// trojan-rust by default, and sample_rate is the fraction of the connections traced, between 0 and 1 with all of
+/// them traced by default.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct TracingConfig {
+    pub endpoint: String,
+    pub service_name: Option<String>,
+    pub sample_rate: Option<f64>,
+}
+
+/// Output of the logs, which are written to stderr at the levels set by RUST_LOG. format is TEXT by default.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct LogConfig {
+    pub format: Option<LogFormat>,
+}
+
+/// TEXT: One line of text per record, as env_logger writes them
+/// JSON: One JSON object per record with the timestamp, level, target and message, along with the connection id,
+/// inbound, outbound, user and destination of the connection the record is about, for log collectors like Loki or ELK
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogFormat {
+    TEXT,
+    JSON,
+}
+
+/// Bandwidth shared by all the TCP connections and QUIC streams relayed by the process, mbps the megabits per second
+/// they send out together, to the clients and to the destinations alike. The relays may go over it by a second worth of
+/// data at once. The TCP connections are relayed in userspace while the bandwidth is limited.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct GlobalBandwidthConfig {
+    pub mbps: u64,
+}
+
+/// Sizing of the async runtime. worker_threads is the number of threads running the connections, one per CPU by
+/// default, and max_blocking_threads caps the threads running blocking work like the file reads, 512 by default.
+/// cpu_affinity lists the CPUs the worker threads are pinned to in turn on Linux, they can run on any CPU by default.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct RuntimeConfig {
+    pub worker_threads: Option<usize>,
+    pub max_blocking_threads: Option<usize>,
+    pub cpu_affinity: Option<Vec<usize>>,
+}
+
+/// io_uring backend of the plain TCP relays of the direct outbounds, only available on Linux in the builds with the
+/// io-uring feature. The relays run on worker threads, one per CPU by default, each with its own ring of entries
+/// submission queue entries, 256 by default. Without the backend the relays splice the data between the sockets.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct IoUringConfig {
+    pub workers: Option<usize>,
+    pub entries: Option<u32>,
+}
+
+/// Timeouts and retries of the connections. policy applies to every inbound and outbound, and the named policies in
+/// policies override parts of it for the inbounds and outbounds that refer to them in their policy field, the values a
+/// named policy leaves out are taken from policy. Times are in seconds, except retry_backoff in milliseconds:
+///
+/// handshake_timeout: TLS and proxy handshakes of the inbound connections together, counted from the connection being
+/// accepted, only bounded by request_deadline by default
+/// connect_timeout: Each attempt of the outbound to connect to the destination or its remote server, only bounded by
+/// request_deadline by default
+/// retries: Times the outbound retries a failed connection attempt within request_deadline, 0 by default
+/// retry_backoff: Wait before the first retry, doubled before each next one, 100 by default
+/// idle_timeout: Connections relayed by the outbound are closed after no data is sent either way for this long, they
+/// are never closed by default
+/// max_connection_lifetime: Connections relayed by the outbound are closed this long after the relay started, however
+/// busy they are, they are never closed by default
+/// half_close_timeout: Connections relayed by the outbound are closed this long after one side stopped sending, if the
+/// other side hasn't stopped by then, 30 by default
+/// udp_session_ttl: UDP sessions of the direct outbound are closed after no datagram is sent either way for this
+/// long, they are never closed by default
+/// max_upload: Bytes a single TCP connection relayed by the outbound sends to the destination before it is closed,
+/// unlimited by default
+/// max_download: Bytes a single TCP connection relayed by the outbound receives from the destination before it is
+/// closed, unlimited by default
+/// destinations: Addresses the direct outbound may connect and send datagrams to once resolved, see IpFilterConfig,
+/// all of them by default
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct PolicyConfig {
+    pub handshake_timeout: Option<u64>,
+    pub connect_timeout: Option<u64>,
+    pub retries: Option<u32>,
+    pub retry_backoff: Option<u64>,
+    pub idle_timeout: Option<u64>,
+    pub max_connection_lifetime: Option<u64>,
+    pub half_close_timeout: Option<u64>,
+    pub udp_session_ttl: Option<u64>,
+    pub max_upload: Option<u64>,
+    pub max_download: Option<u64>,
+    pub destinations: Option<IpFilterConfig>,
+}
+
+/// Reaper of the idle sessions, which sweeps the relayed connections every interval seconds, 60 by default, and closes
+/// the ones idle for longer than the idle_timeout of their policy. idle_timeout is the time in seconds the connections
+/// whose policy has no idle_timeout are closed after, they are never reaped by default.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ReaperConfig {
+    pub idle_timeout: Option<u64>,
+    pub interval: Option<u64>,
+}
+
+/// Resolver of the domain names in the proxy requests and the outbound addresses. Names are looked up from the servers
+/// listed, each an IP address with an optional port like 1.1.1.1 or 8.8.8.8:53, a DNS-over-TLS url like
+/// tls://1.1.1.1 or tls://dns.google:853, or a DNS-over-HTTPS url like https://1.1.1.1/dns-query, or from the name
+/// servers of the system if there are none. Answers are cached for their TTL, clamped to min_ttl and max_ttl seconds if
+/// present, and the cache holds up to cache_size names, 1024 by default. The names in hosts are answered before asking
+/// the servers, each with the fixed IP address it maps to, or with the answer for the other domain name it maps to.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct DnsConfig {
+    pub servers: Option<Vec<DnsServerConfig>>,
+    pub hosts: Option<HashMap<String, String>>,
+    pub cache_size: Option<usize>,
+    pub min_ttl: Option<u64>,
+    pub max_ttl: Option<u64>,
+}
+
+/// DNS server for client deployments listening on address and port, which answers the A queries of the domains matching
+/// domains, or of all the domains if there are none, with fake addresses from ip_range, 198.18.0.0/15 by default. The
+/// connections to the fake addresses arriving at the TCP inbound are mapped back to the domains, so the domain rules of
+/// the router apply even if the applications look up the domains before connecting. The AAAA queries of those domains
+/// get empty answers, other queries are answered by the resolver. Fake answers have a TTL of ttl seconds, 1 by default.
+/// The addresses are reused once all of them are taken, starting from the one given out first.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct FakeDnsConfig {
+    pub address: String,
+    pub port: u16,
+    pub ip_range: Option<String>,
+    pub domains: Option<Vec<String>>,
+    pub ttl: Option<u32>,
+}
+
+/// DNS server capturing the queries of port 53, for routers and TUN setups redirecting the DNS traffic to the client.
+/// It listens on address and port, 53 by default, over both UDP and TCP. When fake_dns is configured, the proxied
+/// domains get fake addresses, unless fake_dns is false here. Other queries are answered by the resolver, or sent to
+/// the upstream DNS server over TCP through the outbound the router selects for it, with the inbound tag dns, if
+/// upstream is set as ip:port. Queries neither of them answers get SERVFAIL.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct DnsInboundConfig {
+    pub address: String,
+    pub port: Option<u16>,
+    pub fake_dns: Option<bool>,
+    pub upstream: Option<String>,
+}
+
+/// DNS server in the servers of the resolver, either its address, or its address with the TLS settings of a
+/// DNS-over-TLS or DNS-over-HTTPS server.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(untagged)]
+pub enum DnsServerConfig {
+    Address(String),
+    Upstream(DnsUpstreamConfig),
+}
+
+/// TLS settings of a DNS-over-TLS or DNS-over-HTTPS server. server_name is sent in SNI and verified against the
+/// certificate of the server, it's the host of the address by default. allow_insecure skips verifying the certificate.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct DnsUpstreamConfig {
+    pub address: String,
+    pub server_name: Option<String>,
+    pub allow_insecure: Option<bool>,
+}
+
+/// Transports that can be turned off in deployments not using them, all are enabled by default. The configuration is
+/// rejected if the inbound or any of the outbounds uses a disabled transport, and the disabled transports aren't set
+/// up, so the code of the transport never runs.
+///
+/// grpc: GRPC inbound and outbounds
+/// quic: QUIC inbound, QUIC and RACE outbounds, and the QUIC failover of the TCP outbounds
+/// websocket: WebSocket upgrades of the TCP inbound
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct TransportsConfig {
+    pub grpc: Option<bool>,
+    pub quic: Option<bool>,
+    pub websocket: Option<bool>,
+}
+
+/// Inbound traffic supports the following 3 modes:
+///
 /// TCP - Raw TCP byte stream traffic
 /// GRPC - GRPC packet stream that contains payload data in the body for proxy purposes
 /// QUIC - Application level byte stream that is built on top of QUIC protocol
-/// 
-/// TCP and QUIC are both byte streams from the abstractions of the low level implementation. GRPC on the other hand is 
+///
+/// TCP and QUIC are both byte streams from the abstractions of the low level implementation. GRPC on the other hand is
 /// packet stream.
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub enum InboundMode {
@@ -23,48 +243,559 @@ pub enum InboundMode {
     QUIC,
 }
 
-/// Outbound traffic supports 4 types of proxy modes:
-/// 
+/// Outbound traffic supports 6 types of proxy modes:
+///
 /// DIRECT: Directly send the data in the proxy request to the requested destination, either via raw TCP or UDP
 /// TCP: Forward the proxy traffic to a remote proxy server via raw TCP stream and have it take care of the traffic handling
 /// GRPC: Forward the proxy traffic to a remote proxy server via GRPC packet stream
 /// QUIC: Forward the proxy traffic to a remote proxy server via QUIC stream
+/// RACE: Dial the remote proxy server with both TCP and QUIC at the same time, and use whichever connects first
+/// BLOCK: Close the connection right away without forwarding anything, UDP packets are dropped along with it
 #[derive(Serialize, Deserialize, Clone)]
 pub enum OutboundMode {
     DIRECT,
     TCP,
     GRPC,
     QUIC,
+    RACE,
+    BLOCK,
 }
 
+/// resolve picks where the domain names in the requests of the inbound are resolved when no routing rule picks it,
+/// like a SOCKS5 client asking for socks5h, which keeps them as domains for the remote server by default.
+///
+/// intercept_dns answers the UDP datagrams a SOCKS client sends to port 53 with the DNS inbound, which has to be
+/// configured, instead of relaying them, so that the domains looked up by devices with hardcoded resolvers get fake
+/// addresses and are routed by domain. Disabled by default.
+///
+/// sniffing reads the domain of the TCP requests to IP addresses from the first bytes the client sends, see
+/// SniffingConfig. Disabled by default.
+///
+/// sources filters the clients by address right after they connect, see IpFilterConfig. All the clients are accepted
+/// by default.
+///
+/// destination_ports restricts the ports the clients of a trojan inbound may connect to, see PortFilterConfig. All
+/// the ports are allowed by default.
+///
+/// block_private refuses the requests the direct outbounds would send to private, loopback and link local addresses
+/// or to the addresses of the host itself, except to the addresses and CIDR ranges in allowed_destinations. Disabled
+/// by default.
+///
+/// fallbacks are the servers the streams failing the trojan handshake are handed to, tried in order before fallback,
+/// see FallbackConfig.
 #[derive(Serialize, Deserialize, Clone)]
 pub struct InboundConfig {
+    pub tag: Option<String>,
     pub mode: InboundMode,
     pub protocol: SupportedProtocols,
     pub address: String,
     pub port: u16,
+    pub v6only: Option<bool>,
     pub secret: Option<String>,
     pub tls: Option<InboundTlsConfig>,
+    pub fallback: Option<String>,
+    pub paranoid: Option<bool>,
+    pub users: Option<Vec<UserConfig>>,
+    pub allowed_ips: Option<Vec<String>>,
+    pub sources: Option<IpFilterConfig>,
+    pub destination_ports: Option<PortFilterConfig>,
+    pub block_private: Option<bool>,
+    pub allowed_destinations: Option<Vec<String>>,
+    pub auth_backend: Option<AuthBackendConfig>,
+    pub request_deadline: Option<u64>,
+    pub quic: Option<InboundQuicConfig>,
+    pub dial_failure: Option<DialFailureMode>,
+    pub connection_limit: Option<ConnectionLimitConfig>,
+    pub bandwidth: Option<BandwidthConfig>,
+    pub bandwidth_classes: Option<HashMap<String, BandwidthClassConfig>>,
+    pub websocket: Option<InboundWebSocketConfig>,
+    pub policy: Option<String>,
+    pub resolve: Option<DomainResolution>,
+    pub intercept_dns: Option<bool>,
+    pub sniffing: Option<SniffingConfig>,
+    pub fallbacks: Option<Vec<FallbackConfig>>,
+    pub socket: Option<SocketConfig>,
 }
 
+/// Options of the TCP sockets accepted by an inbound or dialed by an outbound. nodelay disables Nagle's algorithm so
+/// that small writes of interactive traffic go out right away, enabled by default. keepalive is the seconds a
+/// connection stays idle before the kernel starts probing the peer, which is disabled by default, and then
+/// keepalive_interval the seconds between the probes and keepalive_probes the unanswered probes the connection is
+/// closed after, both the kernel defaults otherwise. fast_open enables TCP Fast Open on the listener of an inbound and
+/// the connections of an outbound on Linux, disabled by default.
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct SocketConfig {
+    pub nodelay: Option<bool>,
+    pub keepalive: Option<u64>,
+    pub keepalive_interval: Option<u64>,
+    pub keepalive_probes: Option<u32>,
+    pub fast_open: Option<bool>,
+}
+
+/// Server taking over the streams that fail the trojan handshake. A stream goes to the first fallback whose
+/// conditions it meets and that passes its health checks: alpn is the protocol negotiated in the TLS handshake, which
+/// needs to be offered with alpn in the TLS config of the inbound, and path the prefix of the path of the HTTP
+/// request. Fallbacks without conditions take any stream. The fallbacks are probed by connecting to them every 30
+/// seconds, and one failing to accept a stream is skipped until it passes a probe again.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct FallbackConfig {
+    pub address: String,
+    pub alpn: Option<String>,
+    pub path: Option<String>,
+}
+
+/// Ports of the destinations sniffed and the protocol expected on each of them, the ports not listed are not sniffed.
+/// TLS reads the server name of the ClientHello and HTTP the Host header of the request. Clients get timeout
+/// milliseconds, 300 by default, to send enough of it, the request goes on unsniffed otherwise.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SniffingConfig {
+    pub ports: Vec<SniffPortConfig>,
+    pub timeout: Option<u64>,
+}
+
+/// With override_destination, true by default, the outbound connects to the sniffed domain rather than the address
+/// the client asked for. Otherwise the domain is only matched by the routing rules.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SniffPortConfig {
+    pub port: PortConfig,
+    pub protocol: SniffProtocol,
+    pub override_destination: Option<bool>,
+}
+
+/// NONE turns sniffing off for the port, so that it can be carved out of a range listed after it.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SniffProtocol {
+    TLS,
+    HTTP,
+    NONE,
+}
+
+/// Accept trojan carried over WebSocket on the TCP inbound along with plain trojan. Only the upgrade requests for the
+/// path, and for the host if it is present, are accepted, every other request goes to the fallback like any other
+/// non trojan traffic.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct InboundWebSocketConfig {
+    pub path: String,
+    pub host: Option<String>,
+}
+
+/// Limit of the new connections per second accepted by the inbound, rate in total and per_ip_rate from each source
+/// address. Bursts of up to burst and per_ip_burst connections are allowed, which default to the rates. Connections
+/// over the limits are closed right after being accepted, unless per_ip_delay is set, in which case connections over
+/// the rate of their source are delayed until the rate allows them, if that takes no longer than per_ip_delay seconds.
+///
+/// max_connections caps the connections open at the same time through all the listeners of the inbound, and
+/// max_connections_per_listener through each of them, both unlimited by default. Connections over the caps are closed
+/// as well, unless queue is set, in which case they wait for a slot and the inbound stops accepting until then.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ConnectionLimitConfig {
+    pub rate: Option<u64>,
+    pub burst: Option<u64>,
+    pub per_ip_rate: Option<u64>,
+    pub per_ip_burst: Option<u64>,
+    pub per_ip_delay: Option<u64>,
+    pub max_connections: Option<usize>,
+    pub max_connections_per_listener: Option<usize>,
+    pub queue: Option<bool>,
+}
+
+/// Bandwidth of each connection accepted by the inbound, upload the bytes per second read from the client and download
+/// the bytes per second written to it, both unlimited by default. Either way may go over its rate by burst bytes at
+/// once, which defaults to the rate. The TCP connections limited either way are relayed in userspace.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct BandwidthConfig {
+    pub upload: Option<u64>,
+    pub download: Option<u64>,
+    pub burst: Option<u64>,
+}
+
+/// Allow and deny lists of addresses and CIDR ranges, given inline with allow and deny, and in allow_file and
+/// deny_file, which hold one entry per line and are reloaded when they change. An address in the deny lists is refused,
+/// and so is an address missing from the allow lists if there are any.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct IpFilterConfig {
+    pub allow: Option<Vec<String>>,
+    pub deny: Option<Vec<String>>,
+    pub allow_file: Option<String>,
+    pub deny_file: Option<String>,
+}
+
+/// Destination ports or port ranges the TCP requests of the inbound are allowed to, the ones in allow if it is given
+/// and not in deny. The requests to other ports are refused once parsed, before connecting anywhere.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PortFilterConfig {
+    pub allow: Option<Vec<PortConfig>>,
+    pub deny: Option<Vec<PortConfig>>,
+}
+
+/// Speed class the users of the inbound are put in with their bandwidth_class, mbps the megabits per second each user
+/// of the class gets both ways across all of their connections, unlimited if it is left out. The users may go over it
+/// by a second worth of data at once.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct BandwidthClassConfig {
+    pub mbps: Option<u64>,
+}
+
+/// What the client of the inbound sees when the outbound connection can't be established:
+///
+/// CLOSE: The connection is accepted right away and closed if the outbound fails, which is the default
+/// RESPOND: The reply is delayed until the outbound is connected, so that a failure is reported with the matching
+/// reply code of the inbound protocol, like host unreachable or connection refused for SOCKS
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DialFailureMode {
+    CLOSE,
+    RESPOND,
+}
+
+/// Additional users accepted by the inbound on top of the secret field, each of them authenticates with their own
+/// secret and is identified by name in logs and statistics. Handshakes of the user are refused after expires_at,
+/// which is an RFC 3339 timestamp like 2023-01-01T00:00:00Z. bandwidth_class is the name of the class in the
+/// bandwidth_classes of the inbound whose bandwidth the user gets, unlimited by default.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct UserConfig {
+    pub name: String,
+    pub secret: String,
+    pub expires_at: Option<String>,
+    pub bandwidth_class: Option<String>,
+}
+
+/// External storage of the user secrets consulted when the hex value isn't known from the configuration file:
+///
+/// REDIS: Look up the key prefix + hex value, the value stored under the key is the name of the user
+/// MYSQL: Run query with the hex value bound to its only parameter, the first column returned is the name of the user
+///
+/// Results are cached in memory for cache_ttl seconds, 60 by default.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct AuthBackendConfig {
+    pub backend: AuthBackend,
+    pub url: String,
+    pub key_prefix: Option<String>,
+    pub query: Option<String>,
+    pub cache_ttl: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub enum AuthBackend {
+    REDIS,
+    MYSQL,
+}
+
+/// Outbound the proxy requests are forwarded through. The connections of a TCP outbound to its remote server can be
+/// tunneled through another outbound by setting dialer to the tag of that outbound, which has to be a DIRECT or TCP
+/// outbound itself, so that the proxy requests hop through both servers. The timeouts and retries of the outbound come
+/// from the named policy in policy if it is set, see PolicyConfig.
+///
+/// servers lists more addresses of the same remote server, like the IPs of an anycast or CDN deployment, each a host
+/// or host:port where the port defaults to port. They are tried after address when connecting to the server, in the
+/// order of server_order, and the addresses that failed within the last minute are tried last. The domain names of the
+/// servers, and of the destinations of a DIRECT outbound, are resolved according to domain_strategy.
+///
+/// discovery publishes more servers in DNS, either srv: followed by the name of SRV records like
+/// srv:_trojan._tcp.example.com, or https: followed by the name of HTTPS records like https:proxy.example.com, whose
+/// ALPN protocols are offered to the servers over TLS. The records are looked up before the first connection and again
+/// whenever none of the servers accepts the connection, and the servers found are tried after the other ones.
+///
+/// reset_failover makes a TCP outbound connect to its server over QUIC for five minutes after a connection to the
+/// server was reset during the TLS handshake or within its first seconds, which is how censors usually block it.
+/// Disabled by default.
 #[derive(Serialize, Deserialize, Clone)]
 pub struct OutboundConfig {
+    pub tag: Option<String>,
     pub mode: OutboundMode,
     pub protocol: SupportedProtocols,
     pub address: Option<String>,
     pub port: Option<u16>,
     pub secret: Option<String>,
     pub tls: Option<OutboundTlsConfig>,
+    pub udp: Option<UdpConfig>,
+    pub dialer: Option<String>,
+    pub policy: Option<String>,
+    pub servers: Option<Vec<String>>,
+    pub server_order: Option<ServerOrder>,
+    pub discovery: Option<String>,
+    pub domain_strategy: Option<DomainStrategy>,
+    pub reset_failover: Option<bool>,
+    pub pool: Option<PoolConfig>,
+    pub socket: Option<SocketConfig>,
 }
 
+/// Connections to the remote server of a TCP or RACE outbound kept established ahead of the requests, so that a new
+/// request skips the TCP and TLS handshakes. size is the number of idle connections kept ready, and max_idle the
+/// seconds an idle connection is kept before being replaced, 20 by default, which has to stay below the time the server
+/// waits for the request on a new connection.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PoolConfig {
+    pub size: usize,
+    pub max_idle: Option<u64>,
+}
+
+/// Addresses used when resolving a domain name:
+///
+/// AS_IS: The first address in the answer of the resolver, which is the default
+/// PREFER_IPV4: IPv4 address, or IPv6 address if the domain has no IPv4 address
+/// PREFER_IPV6: IPv6 address, or IPv4 address if the domain has no IPv6 address
+/// IPV4_ONLY: Only IPv4 address, the domains without one fail to resolve
+/// IPV6_ONLY: Only IPv6 address, the domains without one fail to resolve
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[allow(non_camel_case_types)]
+pub enum DomainStrategy {
+    AS_IS,
+    PREFER_IPV4,
+    PREFER_IPV6,
+    IPV4_ONLY,
+    IPV6_ONLY,
+}
+
+/// Order of trying the addresses of the remote server of an outbound:
+///
+/// ORDER: In the order of the configuration, which is the default
+/// LATENCY: Fastest to connect first, the addresses not connected to yet are tried before the others
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ServerOrder {
+    ORDER,
+    LATENCY,
+}
+
+/// Group of outbounds that can be used in the routing rules by its tag like a single outbound, each proxy request
+/// routed to the group goes to one of the members selected by the type of the group. Members are probed every interval
+/// seconds, 30 by default, and the probes have to finish within timeout seconds, 5 by default. A member is considered
+/// down after fall consecutive failed probes, 3 by default, and up again after rise consecutive successful probes, 2
+/// by default.
+///
+/// FAILOVER: The first healthy member in the order of members, probed by connecting to their servers
+/// URL_TEST: The healthy member with the lowest latency of fetching the test url through it, which is an http url and
+/// defaults to http://www.gstatic.com/generate_204. The group only switches to a faster member if it is faster than
+/// the current one by more than tolerance milliseconds, 50 by default.
+/// SELECT: The member chosen through the admin API, the first member until then. The members are not probed.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct OutboundGroupConfig {
+    pub tag: String,
+    #[serde(rename = "type")]
+    pub group_type: GroupType,
+    pub members: Vec<String>,
+    pub interval: Option<u64>,
+    pub timeout: Option<u64>,
+    pub fall: Option<u32>,
+    pub rise: Option<u32>,
+    pub url: Option<String>,
+    pub tolerance: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[allow(non_camel_case_types)]
+pub enum GroupType {
+    FAILOVER,
+    URL_TEST,
+    SELECT,
+}
+
+/// session_ticket_rotation is the interval in seconds of replacing the key encrypting the TLS session tickets,
+/// defaults to 6 hours. Tickets stay valid for one more interval after their key is replaced.
+///
+/// The certificate is checked at startup and then daily, with a warning when it expires within expiry_warning_days,
+/// 14 by default.
+///
+/// alpn lists the protocols offered to the clients in ALPN, in order of preference, none by default.
+///
+/// ktls hands the sessions relayed as plain TCP over to kernel TLS once the request is read, off by default and only
+/// available on Linux.
 #[derive(Serialize, Deserialize, Clone)]
 pub struct InboundTlsConfig {
     pub cert_path: String,
     pub key_path: String,
+    pub server_names: Option<Vec<String>>,
+    pub sni_routes: Option<Vec<SniRouteConfig>>,
+    pub session_ticket_rotation: Option<u64>,
+    pub expiry_warning_days: Option<u64>,
+    pub alpn: Option<Vec<String>>,
+    pub ktls: Option<bool>,
+}
+
+/// Route TLS connections whose ClientHello carries a matching server name to another local backend without
+/// terminating TLS. The server name can be an exact host name, a wildcard like *.example.com, or * to match
+/// every server name that is not listed in server_names of the inbound TLS config.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SniRouteConfig {
+    pub server_name: String,
+    pub backend: String,
+}
+
+/// Settings of the QUIC inbound. migration allows the clients to keep their connections when their addresses change,
+/// which is enabled by default, and idle_timeout is the time in seconds a connection is kept without hearing from the
+/// client, 60 by default.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct InboundQuicConfig {
+    pub migration: Option<bool>,
+    pub idle_timeout: Option<u64>,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct OutboundTlsConfig {
     pub host_name: String,
     pub allow_insecure: bool,
+    pub fingerprint: Option<FingerprintConfig>,
+}
+
+/// Vary the ClientHello of the TCP connections to the remote server between the profiles, so that a long running
+/// client doesn't always present the same fingerprint. A profile picked at random from profiles, all of them by
+/// default, is used for rotation_interval seconds, one hour by default. Each profile offers the cipher suites in the
+/// order of the browser it is named after, along with its ALPN protocols. The order of the extensions and the GREASE
+/// values are fixed by the TLS library and are not varied.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct FingerprintConfig {
+    pub profiles: Option<Vec<FingerprintProfile>>,
+    pub rotation_interval: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum FingerprintProfile {
+    CHROME,
+    FIREFOX,
+    SAFARI,
+}
+
+/// Settings of the UDP relay. reply_rate_limit caps the bytes per second relayed back to a single UDP session, with
+/// reply_burst bytes allowed in a burst, which defaults to one second worth of traffic. nat picks which hosts can send
+/// datagrams back to the session, PORT_RESTRICTED_CONE by default. max_sessions caps the UDP sessions open at the same
+/// time through the outbound, unlimited by default, the sessions are closed once idle for the udp_session_ttl of the
+/// policy. over_tcp makes a QUIC or RACE outbound carry the UDP requests in trojan UDP frames over its TCP and TLS
+/// connection to the server rather than over QUIC, for networks throttling or blocking UDP, disabled by default.
+/// bind_address is the IP the UDP sessions of a DIRECT outbound send their datagrams from, and port_range like
+/// "20000-30000" the ports their sockets are bound within, any address and port by default. max_packet_size caps the
+/// bytes of a trojan UDP packet, header included, relayed by a session, unlimited by default, the larger replies are
+/// split and the larger requests dropped. recv_buffer_size and send_buffer_size set SO_RCVBUF and SO_SNDBUF in bytes on
+/// the sockets of the sessions, the kernel defaults otherwise.
+/// broadcast lets the sessions of a DIRECT outbound send datagrams to broadcast and multicast addresses for LAN
+/// discovery, and accept the replies from any host of the local network, disabled by default.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct UdpConfig {
+    pub reply_rate_limit: Option<u64>,
+    pub reply_burst: Option<u64>,
+    pub nat: Option<NatBehavior>,
+    pub max_sessions: Option<usize>,
+    pub over_tcp: Option<bool>,
+    pub bind_address: Option<String>,
+    pub port_range: Option<String>,
+    pub max_packet_size: Option<usize>,
+    pub recv_buffer_size: Option<usize>,
+    pub send_buffer_size: Option<usize>,
+    pub broadcast: Option<bool>,
+}
+
+/// Every UDP session sends all its datagrams from the same port whatever their destinations are, the behaviors only
+/// differ in the datagrams accepted on that port. FULL_CONE accepts them from any host once the client has sent a
+/// datagram, as needed by games and peer to peer applications relying on STUN. RESTRICTED_CONE only accepts them from
+/// the hosts the client has sent to, on any port, and PORT_RESTRICTED_CONE only from the exact addresses and ports.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[allow(non_camel_case_types)]
+pub enum NatBehavior {
+    FULL_CONE,
+    RESTRICTED_CONE,
+    PORT_RESTRICTED_CONE,
+}
+
+/// Rules deciding which outbound handles the proxy requests. The rules are evaluated in order and the first rule
+/// matching the request wins, requests not matching any rule go to the outbound in the outbound field.
+/// geoip_database is the path of the MaxMind country database in mmdb format, which is needed by the geoip values in
+/// ip_cidr other than geoip:private. geosite_database is the path of the v2ray geosite.dat file, which is needed by
+/// the geosite values in domain. geoip_url and geosite_url are http or https urls the databases are downloaded from
+/// every update_interval seconds, one day by default, or when asked through the admin API. The downloaded databases
+/// replace the files at geoip_database and geosite_database, which are required along with the urls.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct RouterConfig {
+    pub rules: Vec<RuleConfig>,
+    pub geoip_database: Option<String>,
+    pub geosite_database: Option<String>,
+    pub geoip_url: Option<String>,
+    pub geosite_url: Option<String>,
+    pub update_interval: Option<u64>,
+}
+
+/// A rule matches the request if all the conditions present in the rule are met, each of the conditions is met if
+/// any of its values matches. Domain values match the domain itself and all of its subdomains unless prefixed with
+/// full:, keyword: or regexp:, and can also be geosite categories like geosite:google. ip_cidr values only match the
+/// requests whose destination is an IP address, and can also be country codes like geoip:cn. port values are single
+/// ports or ranges. resolve picks where the domain names of the matching requests are resolved, by default direct
+/// outbounds resolve them locally and the others leave them to the remote server.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct RuleConfig {
+    pub domain: Option<Vec<String>>,
+    pub ip_cidr: Option<Vec<String>>,
+    pub port: Option<Vec<PortConfig>>,
+    pub inbound_tag: Option<Vec<String>>,
+    pub transport: Option<TransportProtocol>,
+    pub resolve: Option<DomainResolution>,
+    pub outbound: String,
+}
+
+/// LOCAL resolves the domain name before handing the request to the outbound, so the remote server receives the
+/// address. REMOTE sends the domain name to the remote server unresolved, which has no effect on direct outbounds as
+/// they always resolve locally.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DomainResolution {
+    LOCAL,
+    REMOTE,
+}
+
+/// Destination port in the routing rules, either a single port like 443 or an inclusive range like "1000-2000".
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(untagged)]
+pub enum PortConfig {
+    Single(u16),
+    Range(String),
+}
+
+/// Address of the admin GRPC API used to manage the server at runtime. The API is not authenticated, so it should
+/// only listen on the loopback interface. With proxy_port, the API is also served on the port of the TCP trojan
+/// inbound to the HTTP/2 connections that fail the trojan handshake, for hosts that can only expose that port. Those
+/// calls have to carry psk, the pre-shared key, as a bearer token, which is required along with proxy_port.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct AdminConfig {
+    pub address: String,
+    pub port: u16,
+    pub psk: Option<String>,
+    pub proxy_port: Option<bool>,
+}
+
+/// Address of the gRPC API streaming the live events, like connections opening and closing, outbound groups
+/// switching, errors and traffic rate samples, for GUI wrappers of the client to show without polling. The traffic
+/// rate is sampled every rate_interval seconds, 1 by default. Like the admin API it is not authenticated, so it should
+/// only listen on the loopback interface.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct EventsConfig {
+    pub address: String,
+    pub port: u16,
+    pub rate_interval: Option<u64>,
+}
+
+/// Periodically export the metrics snapshot to snapshot_path as a JSON file, every snapshot_interval seconds which
+/// defaults to 60. The file is replaced atomically so that readers never observe a partially written snapshot.
+///
+/// access_log_sample_rate is the fraction of the connections written to the access log, between 0 and 1 with all of
+/// them logged by default. Requests are counted per destination host up to max_destinations hosts, 1024 by default,
+/// and the hosts beyond are counted in a few buckets picked by the hash of the host.
+///
+/// access_log writes the lines of the requests to a file of their own rather than to the application log, see
+/// AccessLogConfig.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct MetricsConfig {
+    pub snapshot_path: Option<String>,
+    pub snapshot_interval: Option<u64>,
+    pub access_log_sample_rate: Option<f64>,
+    pub max_destinations: Option<usize>,
+    pub access_log: Option<AccessLogConfig>,
+}
+
+/// Access log with one line per request, written to path if it is set and to the application log otherwise. enabled
+/// turns the access log off entirely when false. The file is rotated once it grows past max_size bytes, 100 MiB by
+/// default, and every rotate_interval seconds if set. The newest max_files rotated files are kept, 7 by default, and
+/// they are compressed with gzip if compress is set.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct AccessLogConfig {
+    pub enabled: Option<bool>,
+    pub path: Option<String>,
+    pub max_size: Option<u64>,
+    pub rotate_interval: Option<u64>,
+    pub max_files: Option<usize>,
+    pub compress: Option<bool>,
 }
@@ -0,0 +1,235 @@
+use crate::config::base::InboundTlsConfig;
+use crate::config::tls::{load_certs, load_private_key};
+use crate::metrics;
+
+use log::{info, warn};
+use rustls::sign::any_supported_type;
+use rustls::{Certificate, PrivateKey, SignatureScheme};
+use std::io::{Error, ErrorKind, Result};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use webpki::{EndEntityCert, SignatureAlgorithm, Time};
+use x509_parser::parse_x509_certificate;
+
+/// Default number of days before the expiry of the certificate to start warning about it
+const DEFAULT_EXPIRY_WARNING_DAYS: u64 = 14;
+
+/// Interval between the checks of the certificate after the one at startup
+const CHECK_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// Expiry of the certificate of the inbound found by the last check
+static NOT_AFTER: Mutex<Option<SystemTime>> = Mutex::new(None);
+
+/// Message signed with the private key to check that it belongs to the certificate
+const KEY_CHECK_MESSAGE: &[u8] = b"trojan-rust certificate check";
+
+/// Signature schemes the private key is checked with, along with the matching algorithm of webpki
+const KEY_CHECK_SCHEMES: [(SignatureScheme, &SignatureAlgorithm); 5] = [
+    (SignatureScheme::ED25519, &webpki::ED25519),
+    (
+        SignatureScheme::ECDSA_NISTP256_SHA256,
+        &webpki::ECDSA_P256_SHA256,
+    ),
+    (
+        SignatureScheme::ECDSA_NISTP384_SHA384,
+        &webpki::ECDSA_P384_SHA384,
+    ),
+    (
+        SignatureScheme::RSA_PSS_SHA256,
+        &webpki::RSA_PSS_2048_8192_SHA256_LEGACY_KEY,
+    ),
+    (
+        SignatureScheme::RSA_PKCS1_SHA256,
+        &webpki::RSA_PKCS1_2048_8192_SHA256,
+    ),
+];
+
+/// Signature algorithms accepted in the certificate chain
+const CHAIN_ALGORITHMS: &[&SignatureAlgorithm] = &[
+    &webpki::ECDSA_P256_SHA256,
+    &webpki::ECDSA_P256_SHA384,
+    &webpki::ECDSA_P384_SHA256,
+    &webpki::ECDSA_P384_SHA384,
+    &webpki::ED25519,
+    &webpki::RSA_PKCS1_2048_8192_SHA256,
+    &webpki::RSA_PKCS1_2048_8192_SHA384,
+    &webpki::RSA_PKCS1_2048_8192_SHA512,
+    &webpki::RSA_PKCS1_3072_8192_SHA384,
+    &webpki::RSA_PSS_2048_8192_SHA256_LEGACY_KEY,
+    &webpki::RSA_PSS_2048_8192_SHA384_LEGACY_KEY,
+    &webpki::RSA_PSS_2048_8192_SHA512_LEGACY_KEY,
+];
+
+/// Outcome of checking the certificate of the inbound, the problems found are described in warnings.
+pub struct CertificateCheck {
+    pub not_after: SystemTime,
+    pub expiry_days: u64,
+    pub warnings: Vec<String>,
+}
+
+/// Check the certificate chain and the private key at the time. The chain should lead to one of the public roots
+/// unless the certificate is self-signed, the key should match the certificate, and the certificate should not
+/// expire within the warning period. Fails if the certificate can't be parsed.
+pub fn check_certificate(
+    certs: &[Certificate],
+    key: &PrivateKey,
+    expiry_warning: Duration,
+    now: SystemTime,
+) -> Result<CertificateCheck> {
+    let leaf = match certs.first() {
+        Some(leaf) => leaf,
+        None => return Err(Error::new(ErrorKind::InvalidData, "no certificate found")),
+    };
+    let (_, parsed) = match parse_x509_certificate(&leaf.0) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("failed to parse the certificate: {}", e),
+            ))
+        }
+    };
+    let not_after =
+        UNIX_EPOCH + Duration::from_secs(parsed.validity().not_after.timestamp().max(0) as u64);
+    let end_entity = match EndEntityCert::try_from(leaf.0.as_slice()) {
+        Ok(end_entity) => end_entity,
+        Err(e) => return Err(Error::new(ErrorKind::InvalidData, e.to_string())),
+    };
+
+    let mut warnings = Vec::new();
+
+    let remaining = not_after.duration_since(now).unwrap_or_default();
+    if remaining.is_zero() {
+        warnings.push("certificate has expired".to_string());
+    } else if remaining <= expiry_warning {
+        warnings.push(format!(
+            "certificate expires in {} days",
+            remaining.as_secs() / SECONDS_PER_DAY
+        ));
+    }
+
+    if !key_matches(&end_entity, key) {
+        warnings.push("private key doesn't match the certificate".to_string());
+    }
+
+    // Self-signed certificates are trusted by the clients explicitly, there is no chain to check
+    if !(certs.len() == 1 && parsed.issuer().as_raw() == parsed.subject().as_raw()) {
+        let intermediates: Vec<&[u8]> = certs[1..].iter().map(|cert| cert.0.as_slice()).collect();
+        let time = Time::from_seconds_since_unix_epoch(
+            now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs(),
+        );
+        match end_entity.verify_is_valid_tls_server_cert(
+            CHAIN_ALGORITHMS,
+            &webpki_roots::TLS_SERVER_ROOTS,
+            &intermediates,
+            time,
+        ) {
+            // Already reported above
+            Ok(_) | Err(webpki::Error::CertExpired) => (),
+            Err(webpki::Error::UnknownIssuer) => warnings.push(
+                "certificate chain is incomplete, intermediate certificates may be missing"
+                    .to_string(),
+            ),
+            Err(e) => warnings.push(format!("certificate chain is invalid: {}", e)),
+        }
+    }
+
+    Ok(CertificateCheck {
+        not_after,
+        expiry_days: remaining.as_secs() / SECONDS_PER_DAY,
+        warnings,
+    })
+}
+
+/// Check the certificate in the files of the inbound, logging the problems found and exporting the days left before
+/// it expires as the tls_certificate_expiry_days metric. Reads the files and verifies the signatures in blocking calls,
+/// so it shouldn't be run on the async runtime.
+pub fn check_files(config: &InboundTlsConfig) {
+    let expiry_warning = Duration::from_secs(
+        config
+            .expiry_warning_days
+            .unwrap_or(DEFAULT_EXPIRY_WARNING_DAYS)
+            * SECONDS_PER_DAY,
+    );
+
+    let result = load_certs(&config.cert_path).and_then(|certs| {
+        let key = load_private_key(&config.key_path)?;
+        check_certificate(&certs, &key, expiry_warning, SystemTime::now())
+    });
+    let check = match result {
+        Ok(check) => check,
+        Err(e) => {
+            warn!(
+                "Failed to check the TLS certificate {}: {}",
+                config.cert_path, e
+            );
+            return;
+        }
+    };
+
+    metrics::set("tls_certificate_expiry_days", check.expiry_days);
+    *NOT_AFTER.lock().unwrap() = Some(check.not_after);
+    if check.warnings.is_empty() {
+        info!(
+            "TLS certificate {} is valid for {} more days",
+            config.cert_path, check.expiry_days
+        );
+    }
+    for warning in check.warnings {
+        warn!("TLS certificate {}: {}", config.cert_path, warning);
+    }
+}
+
+/// Check the certificate of the inbound now and then daily in the background, so that a renewal that didn't happen
+/// is noticed before the clients start failing.
+pub fn start_checks(config: &'static InboundTlsConfig) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(CHECK_INTERVAL);
+        loop {
+            interval.tick().await;
+            if let Err(e) = tokio::task::spawn_blocking(move || check_files(config)).await {
+                warn!(
+                    "Failed to check the TLS certificate {}: {}",
+                    config.cert_path, e
+                );
+            }
+        }
+    });
+}
+
+/// Expiry of the certificate of the inbound found by the last check, None if it wasn't checked.
+pub fn not_after() -> Option<SystemTime> {
+    *NOT_AFTER.lock().unwrap()
+}
+
+/// Whether the private key signs a message that the public key of the certificate verifies.
+fn key_matches(end_entity: &EndEntityCert, key: &PrivateKey) -> bool {
+    let signing_key = match any_supported_type(key) {
+        Ok(signing_key) => signing_key,
+        Err(_) => return false,
+    };
+    let schemes: Vec<SignatureScheme> = KEY_CHECK_SCHEMES
+        .iter()
+        .map(|(scheme, _)| *scheme)
+        .collect();
+    let signer = match signing_key.choose_scheme(&schemes) {
+        Some(signer) => signer,
+        None => return false,
+    };
+    let algorithm = match KEY_CHECK_SCHEMES
+        .iter()
+        .find(|(scheme, _)| *scheme == signer.scheme())
+    {
+        Some((_, algorithm)) => *algorithm,
+        None => return false,
+    };
+
+    match signer.sign(KEY_CHECK_MESSAGE) {
+        Ok(signature) => end_entity
+            .verify_signature(algorithm, KEY_CHECK_MESSAGE, &signature)
+            .is_ok(),
+        Err(_) => false,
+    }
+}
@@ -0,0 +1,157 @@
+use crate::config::base::{FingerprintProfile, OutboundTlsConfig};
+use crate::config::tls::make_client_config_with;
+
+use ring::rand::{SecureRandom, SystemRandom};
+use rustls::cipher_suite::*;
+use rustls::kx_group::{SECP256R1, SECP384R1, X25519};
+use rustls::{ClientConfig, SupportedCipherSuite};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::io::{Error, ErrorKind, Result};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Default time in seconds a profile is used before another one is picked
+const DEFAULT_ROTATION_INTERVAL: u64 = 60 * 60;
+
+/// ALPN protocols offered by all the browsers
+const BROWSER_ALPN: [&[u8]; 2] = [b"h2", b"http/1.1"];
+
+const ALL_PROFILES: [FingerprintProfile; 3] = [
+    FingerprintProfile::CHROME,
+    FingerprintProfile::FIREFOX,
+    FingerprintProfile::SAFARI,
+];
+
+/// Cipher suites in the order the browser offers them, limited to the ones supported by rustls.
+fn cipher_suites(profile: FingerprintProfile) -> [SupportedCipherSuite; 9] {
+    match profile {
+        FingerprintProfile::CHROME => [
+            TLS13_AES_128_GCM_SHA256,
+            TLS13_AES_256_GCM_SHA384,
+            TLS13_CHACHA20_POLY1305_SHA256,
+            TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256,
+            TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256,
+            TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384,
+            TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384,
+            TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256,
+            TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256,
+        ],
+        FingerprintProfile::FIREFOX => [
+            TLS13_AES_128_GCM_SHA256,
+            TLS13_CHACHA20_POLY1305_SHA256,
+            TLS13_AES_256_GCM_SHA384,
+            TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256,
+            TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256,
+            TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256,
+            TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256,
+            TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384,
+            TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384,
+        ],
+        FingerprintProfile::SAFARI => [
+            TLS13_AES_128_GCM_SHA256,
+            TLS13_AES_256_GCM_SHA384,
+            TLS13_CHACHA20_POLY1305_SHA256,
+            TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384,
+            TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256,
+            TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256,
+            TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384,
+            TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256,
+            TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256,
+        ],
+    }
+}
+
+/// Client configurations of the fingerprint profiles, one of which is used at a time. The profile of each period is
+/// picked by a keyed hash of the period, so it changes at the same time for all the connections without any task
+/// running in the background, while the sequence of the profiles can't be predicted from outside. The profiles only
+/// differ in the order of the cipher suites, rustls sends neither GREASE values nor its extensions in another order.
+pub struct Fingerprints {
+    configs: Vec<(FingerprintProfile, Arc<ClientConfig>)>,
+    interval: Duration,
+    key: u64,
+}
+
+impl Fingerprints {
+    /// Build the client configurations of the profiles in the fingerprint settings of the TLS configuration, None if
+    /// the fingerprint isn't varied. Fails with InvalidInput if the profiles are empty.
+    pub fn new(config: &OutboundTlsConfig) -> Result<Option<Self>> {
+        let fingerprint = match &config.fingerprint {
+            Some(fingerprint) => fingerprint,
+            None => return Ok(None),
+        };
+
+        let mut profiles = fingerprint
+            .profiles
+            .clone()
+            .unwrap_or_else(|| ALL_PROFILES.to_vec());
+        let mut seen = Vec::new();
+        profiles.retain(|profile| {
+            let first = !seen.contains(profile);
+            seen.push(*profile);
+            first
+        });
+        if profiles.is_empty() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "missing fingerprint profiles of the outbound TLS",
+            ));
+        }
+
+        let configs = profiles
+            .into_iter()
+            .map(|profile| {
+                let mut client_config = make_client_config_with(
+                    config,
+                    &cipher_suites(profile),
+                    &[&X25519, &SECP256R1, &SECP384R1],
+                );
+                client_config.alpn_protocols = BROWSER_ALPN.iter().map(|p| p.to_vec()).collect();
+                (profile, Arc::new(client_config))
+            })
+            .collect();
+
+        let mut key = [0u8; 8];
+        if SystemRandom::new().fill(&mut key).is_err() {
+            return Err(Error::other(
+                "failed to generate the key of the fingerprint rotation",
+            ));
+        }
+
+        Ok(Some(Self {
+            configs,
+            interval: Duration::from_secs(
+                fingerprint
+                    .rotation_interval
+                    .unwrap_or(DEFAULT_ROTATION_INTERVAL)
+                    .max(1),
+            ),
+            key: u64::from_ne_bytes(key),
+        }))
+    }
+
+    /// Client configuration of the profile in use now.
+    #[inline]
+    pub fn current(&self) -> Arc<ClientConfig> {
+        self.config_at(SystemTime::now())
+    }
+
+    /// Client configuration of the profile in use at the time.
+    pub fn config_at(&self, time: SystemTime) -> Arc<ClientConfig> {
+        self.configs[self.index_at(time)].1.clone()
+    }
+
+    /// Profile in use at the time.
+    pub fn profile_at(&self, time: SystemTime) -> FingerprintProfile {
+        self.configs[self.index_at(time)].0
+    }
+
+    fn index_at(&self, time: SystemTime) -> usize {
+        let elapsed = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let period = elapsed.as_secs() / self.interval.as_secs();
+
+        let mut hasher = DefaultHasher::new();
+        (self.key, period).hash(&mut hasher);
+        (hasher.finish() % self.configs.len() as u64) as usize
+    }
+}
@@ -1,3 +1,11 @@
 pub mod base;
+#[cfg(feature = "server")]
+pub mod certificate;
+#[cfg(feature = "client")]
+pub mod fingerprint;
 pub mod parser;
+pub mod runtime;
+#[cfg(feature = "server")]
+pub mod ticketer;
 pub mod tls;
+pub mod transports;
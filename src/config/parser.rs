@@ -1,7 +1,37 @@
-use crate::config::base::Config;
+#[cfg(feature = "server")]
+use crate::auth;
+use crate::auth::ip::IpAuthorizer;
+use crate::auth::port::PortAuthorizer;
+use crate::auth::secret::StaticAuthenticator;
+use crate::config::base::{AuthBackend, Config, InboundMode, OutboundMode};
+#[cfg(feature = "client")]
+use crate::config::fingerprint::Fingerprints;
+use crate::config::transports::Transports;
+#[cfg(feature = "client")]
+use crate::dns::fake::FakeDns;
+#[cfg(feature = "client")]
+use crate::dns::hijack::DnsHijack;
+use crate::dns::Resolver;
+use crate::protocol::trojan::packet::MAX_REPLY_HEADER_SIZE;
+use crate::proxy::buffer::MIN_BUFFER_SIZE;
+use crate::proxy::destination::DestinationFilter;
+use crate::proxy::filter::IpFilter;
+use crate::proxy::limiter::ConcurrencyLimit;
+use crate::proxy::policy::Policies;
+#[cfg(feature = "server")]
+use crate::proxy::quic;
+use crate::proxy::reaper::Reaper;
+use crate::proxy::servers::ServerList;
+use crate::proxy::socket::SocketOptions;
+use crate::proxy::tcp::pool::ConnectionPool;
+use crate::proxy::tcp::sniff::Sniffer;
+use crate::proxy::throttle::{GlobalBandwidth, UserBandwidth};
+use crate::proxy::udp::bind::UdpBinder;
+use crate::router::{Router, DEFAULT_OUTBOUND_TAG};
 
 use std::fs::File;
 use std::io::{BufReader, Error, ErrorKind, Result};
+use std::net::{Ipv4Addr, SocketAddr};
 
 /// Read and parse the json file located at path, will attempt to deserialize and throw error if the
 /// format is invalid.
@@ -11,8 +41,369 @@ pub fn read_config(path: &'static str) -> Result<Config> {
         Err(e) => return Err(Error::new(ErrorKind::InvalidData, e)),
     };
 
-    return match serde_json::from_reader(reader) {
-        Ok(config) => Ok(config),
+    let config = match serde_json::from_reader(reader) {
+        Ok(config) => config,
         Err(e) => return Err(Error::new(ErrorKind::InvalidData, e)),
     };
+
+    check_transports(&config)?;
+    check_features(&config)?;
+    check_tls(&config)?;
+    check_inbound(&config)?;
+    check_relay(&config)?;
+    check_runtime(&config)?;
+    check_bandwidth(&config)?;
+    check_tracing(&config)?;
+    check_metrics(&config)?;
+    check_admin(&config)?;
+    check_dns(&config)?;
+    check_outbounds(&config)?;
+    check_router(&config)?;
+    Ok(config)
+}
+
+/// Check that the inbound and the outbounds only use the transports enabled in the configuration.
+pub fn check_transports(config: &Config) -> Result<()> {
+    let Transports {
+        grpc,
+        quic,
+        websocket,
+    } = Transports::new(config.transports.as_ref());
+
+    let disabled = |name: &str, user: &str| {
+        Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("{} uses the {} transport, which is disabled", user, name),
+        ))
+    };
+
+    match config.inbound.mode {
+        InboundMode::GRPC if !grpc => return disabled("GRPC", "Inbound"),
+        InboundMode::QUIC if !quic => return disabled("QUIC", "Inbound"),
+        _ => (),
+    }
+    if config.inbound.websocket.is_some() && !websocket {
+        return disabled("WebSocket", "Inbound");
+    }
+
+    for outbound in std::iter::once(&config.outbound).chain(config.outbounds.iter().flatten()) {
+        let user = format!(
+            "Outbound {}",
+            outbound.tag.as_deref().unwrap_or(DEFAULT_OUTBOUND_TAG)
+        );
+        match outbound.mode {
+            OutboundMode::GRPC if !grpc => return disabled("GRPC", &user),
+            OutboundMode::QUIC | OutboundMode::RACE if !quic => return disabled("QUIC", &user),
+            _ => (),
+        }
+    }
+
+    Ok(())
+}
+
+/// Check the TLS settings of the inbound which can't be used as they are.
+pub fn check_tls(config: &Config) -> Result<()> {
+    // A key rotated on every ticket would never decrypt the tickets issued with it
+    if let Some(0) = config
+        .inbound
+        .tls
+        .as_ref()
+        .and_then(|tls| tls.session_ticket_rotation)
+    {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "session_ticket_rotation of the inbound must be positive",
+        ));
+    }
+
+    Ok(())
+}
+
+/// Check the settings of the inbound which can't be used as they are.
+pub fn check_inbound(config: &Config) -> Result<()> {
+    // The DNS inbound answers the queries the inbound intercepts
+    if config.inbound.intercept_dns.unwrap_or(false) && config.dns_inbound.is_none() {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "intercept_dns of the inbound requires dns_inbound",
+        ));
+    }
+    SocketOptions::new(config.inbound.socket.as_ref())?;
+    if let Some(sources) = &config.inbound.sources {
+        IpFilter::new(sources, "source")?;
+    }
+    DestinationFilter::new(&config.inbound)?;
+    Sniffer::new(config.inbound.sniffing.as_ref())?;
+    StaticAuthenticator::new(&config.inbound)?;
+    #[cfg(feature = "server")]
+    if let Some(backend) = &config.inbound.auth_backend {
+        auth::check_backend(backend)?;
+    }
+    #[cfg(feature = "server")]
+    quic::server::idle_timeout(config.inbound.quic.as_ref())?;
+    IpAuthorizer::new(&config.inbound)?;
+    PortAuthorizer::new(&config.inbound)?;
+    if let Some(limit) = &config.inbound.connection_limit {
+        // The listeners are only known once bound, their caps are the same anyway
+        ConcurrencyLimit::for_inbound(limit)?;
+        ConcurrencyLimit::for_listener(limit, SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)))?;
+    }
+
+    Ok(())
+}
+
+/// Check the relay settings which can't be used as they are.
+pub fn check_relay(config: &Config) -> Result<()> {
+    // The buffers hold the headers of the trojan packets
+    if let Some(size) = config.relay_buffer_size {
+        if size < MIN_BUFFER_SIZE {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("relay_buffer_size must be at least {}", MIN_BUFFER_SIZE),
+            ));
+        }
+    }
+    // The policies name each other and hold the destination filters
+    Policies::from_config(config)?;
+    Reaper::new(config.reaper.as_ref())?;
+
+    Ok(())
+}
+
+/// Check the runtime and the io_uring backend have threads to run on.
+pub fn check_runtime(config: &Config) -> Result<()> {
+    if config
+        .io_uring
+        .as_ref()
+        .and_then(|io_uring| io_uring.workers)
+        == Some(0)
+    {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "workers of io_uring must be positive",
+        ));
+    }
+
+    let runtime = match &config.runtime {
+        Some(runtime) => runtime,
+        None => return Ok(()),
+    };
+
+    if runtime.worker_threads == Some(0) || runtime.max_blocking_threads == Some(0) {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "worker_threads and max_blocking_threads of the runtime must be positive",
+        ));
+    }
+    if runtime.cpu_affinity.as_ref().is_some_and(Vec::is_empty) {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "cpu_affinity of the runtime lists no CPU",
+        ));
+    }
+
+    Ok(())
+}
+
+/// Check the bandwidth of the process, of the inbound and of its users can be relayed at.
+pub fn check_bandwidth(config: &Config) -> Result<()> {
+    if let Some(bandwidth) = &config.bandwidth {
+        GlobalBandwidth::new(bandwidth)?;
+    }
+    UserBandwidth::new(&config.inbound)?;
+
+    if let Some(bandwidth) = &config.inbound.bandwidth {
+        for (rate, direction) in [
+            (bandwidth.upload, "upload"),
+            (bandwidth.download, "download"),
+        ] {
+            if rate == Some(0) {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "The {} bandwidth of the inbound has to be at least 1",
+                        direction
+                    ),
+                ));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Check the sample rate of the traces is a fraction.
+pub fn check_tracing(config: &Config) -> Result<()> {
+    if let Some(sample_rate) = config
+        .tracing
+        .as_ref()
+        .and_then(|tracing| tracing.sample_rate)
+    {
+        if !(0.0..=1.0).contains(&sample_rate) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("Invalid tracing sample rate {}", sample_rate),
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Check the sample rate of the access log is a fraction.
+pub fn check_metrics(config: &Config) -> Result<()> {
+    if let Some(sample_rate) = config
+        .metrics
+        .as_ref()
+        .and_then(|metrics| metrics.access_log_sample_rate)
+    {
+        if !(0.0..=1.0).contains(&sample_rate) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("Invalid access log sample rate {}", sample_rate),
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Check the admin API can be served on the proxy port, which takes a pre-shared key to authenticate the calls.
+pub fn check_admin(config: &Config) -> Result<()> {
+    if let Some(admin) = &config.admin {
+        if admin.proxy_port.unwrap_or(false) && admin.psk.as_deref().unwrap_or_default().is_empty()
+        {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "proxy_port of the admin config requires psk",
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Check the resolver, the FakeDNS and the DNS inbound. The servers of the resolver given by name are only looked up
+/// when it is built.
+pub fn check_dns(config: &Config) -> Result<()> {
+    if let Some(dns) = &config.dns {
+        Resolver::check(dns)?;
+    }
+    #[cfg(feature = "client")]
+    if let Some(fake_dns) = &config.fake_dns {
+        FakeDns::new(fake_dns)?;
+    }
+    #[cfg(feature = "client")]
+    if let Some(dns_inbound) = &config.dns_inbound {
+        DnsHijack::check(dns_inbound)?;
+    }
+
+    Ok(())
+}
+
+/// Check the settings of the outbounds which can't be used as they are.
+pub fn check_outbounds(config: &Config) -> Result<()> {
+    for outbound in std::iter::once(&config.outbound).chain(config.outbounds.iter().flatten()) {
+        ServerList::new(outbound)?;
+        #[cfg(feature = "client")]
+        if let Some(tls) = &outbound.tls {
+            Fingerprints::new(tls)?;
+        }
+        SocketOptions::new(outbound.socket.as_ref())?;
+        UdpBinder::new(outbound.udp.as_ref())?;
+        if let Some(pool) = &outbound.pool {
+            let tag = outbound.tag.as_deref().unwrap_or(DEFAULT_OUTBOUND_TAG);
+            ConnectionPool::<()>::new(tag, &outbound.mode, pool)?;
+        }
+        // The replies too large for a packet are split, which needs room for the payload besides the header
+        if let Some(max_packet_size) = outbound.udp.as_ref().and_then(|udp| udp.max_packet_size) {
+            if max_packet_size <= MAX_REPLY_HEADER_SIZE {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "max_packet_size of the UDP sessions must be larger than {}",
+                        MAX_REPLY_HEADER_SIZE
+                    ),
+                ));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Check the outbounds, the groups and the rules of the router refer to each other as they should.
+pub fn check_router(config: &Config) -> Result<()> {
+    Router::check(config)
+}
+
+/// Check that the configuration only uses the components compiled into this build. Client deployments can be built
+/// without the server feature and servers without the client feature.
+pub fn check_features(config: &Config) -> Result<()> {
+    let missing = |name: &str, feature: &str| {
+        Err(Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "{} requires the {} feature, which is not enabled in this build",
+                name, feature
+            ),
+        ))
+    };
+
+    if !cfg!(feature = "client") {
+        let outbounds = std::iter::once(&config.outbound).chain(config.outbounds.iter().flatten());
+        if config.fake_dns.is_some() {
+            return missing("FakeDNS", "client");
+        }
+        if config.dns_inbound.is_some() {
+            return missing("DNS inbound", "client");
+        }
+        if config.inbound.intercept_dns.unwrap_or(false) {
+            return missing("DNS interception", "client");
+        }
+        for outbound in outbounds {
+            if outbound
+                .tls
+                .as_ref()
+                .and_then(|tls| tls.fingerprint.as_ref())
+                .is_some()
+            {
+                return missing("TLS fingerprint", "client");
+            }
+        }
+    }
+
+    if !cfg!(feature = "server") {
+        if config.admin.is_some() {
+            return missing("Admin API", "server");
+        }
+        if config.inbound.auth_backend.is_some() {
+            return missing("Authentication backend", "server");
+        }
+        if config.inbound.tls.is_some() {
+            return missing("Inbound TLS", "server");
+        }
+        if matches!(config.inbound.mode, InboundMode::QUIC) {
+            return missing("QUIC inbound", "server");
+        }
+    }
+    match config
+        .inbound
+        .auth_backend
+        .as_ref()
+        .map(|backend| backend.backend)
+    {
+        Some(AuthBackend::REDIS) if !cfg!(feature = "redis") => {
+            return missing("Redis authentication backend", "redis")
+        }
+        Some(AuthBackend::MYSQL) if !cfg!(feature = "mysql") => {
+            return missing("MySQL authentication backend", "mysql")
+        }
+        _ => (),
+    }
+    if !cfg!(feature = "otel") && config.tracing.is_some() {
+        return missing("Tracing", "otel");
+    }
+
+    Ok(())
 }
@@ -0,0 +1,89 @@
+use crate::config::base::RuntimeConfig;
+
+use log::warn;
+use std::io::{Error, ErrorKind, Result};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::runtime::{Builder, Runtime};
+
+/// Build the multi-threaded runtime sized by the configuration, the defaults of tokio otherwise. Fails with
+/// InvalidInput if the configuration asks for no threads or no CPU.
+pub fn build_runtime(config: Option<&RuntimeConfig>) -> Result<Runtime> {
+    let mut builder = Builder::new_multi_thread();
+    builder.enable_all();
+
+    let config = match config {
+        Some(config) => config,
+        None => return builder.build(),
+    };
+
+    let worker_threads = config.worker_threads.unwrap_or_else(|| {
+        std::thread::available_parallelism()
+            .map(|threads| threads.get())
+            .unwrap_or(1)
+    });
+    if worker_threads == 0 || config.max_blocking_threads == Some(0) {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "worker_threads and max_blocking_threads of the runtime must be positive",
+        ));
+    }
+    builder.worker_threads(worker_threads);
+    if let Some(max_blocking_threads) = config.max_blocking_threads {
+        builder.max_blocking_threads(max_blocking_threads);
+    }
+
+    if let Some(cpus) = config.cpu_affinity.clone() {
+        if cpus.is_empty() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "cpu_affinity of the runtime lists no CPU",
+            ));
+        }
+
+        // The workers are the first threads the runtime starts, the blocking threads come later and aren't pinned
+        let started = Arc::new(AtomicUsize::new(0));
+        builder.on_thread_start(move || {
+            let index = started.fetch_add(1, Ordering::Relaxed);
+            if index < worker_threads {
+                pin_thread(cpus[index % cpus.len()]);
+            }
+        });
+    }
+
+    builder.build()
+}
+
+/// Pin the current thread to the CPU.
+#[cfg(target_os = "linux")]
+fn pin_thread(cpu: usize) {
+    if cpu >= libc::CPU_SETSIZE as usize {
+        warn!(
+            "Can't pin worker thread to CPU {}, there aren't that many",
+            cpu
+        );
+        return;
+    }
+
+    // Safety: the set is zeroed before use and the CPU is within its size
+    let result = unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        libc::CPU_SET(cpu, &mut set);
+        libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set)
+    };
+    if result != 0 {
+        warn!(
+            "Failed to pin worker thread to CPU {}: {}",
+            cpu,
+            std::io::Error::last_os_error()
+        );
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn pin_thread(cpu: usize) {
+    warn!(
+        "Can't pin worker thread to CPU {}, not supported on this platform",
+        cpu
+    );
+}
@@ -0,0 +1,158 @@
+use crate::config::tls::aes_hardware_available;
+use crate::metrics;
+
+use log::info;
+use ring::aead::{self, Aad, LessSafeKey, Nonce, UnboundKey, NONCE_LEN};
+use ring::rand::{SecureRandom, SystemRandom};
+use rustls::server::ProducesTickets;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Length of the random name identifying the key which encrypted a ticket
+const KEY_NAME_LEN: usize = 16;
+
+/// Session ticket encryption key with its name, the name is prepended to the tickets so they can be decrypted after
+/// the key is rotated.
+struct TicketKey {
+    name: [u8; KEY_NAME_LEN],
+    key: LessSafeKey,
+}
+
+impl TicketKey {
+    fn generate(rng: &SystemRandom) -> Option<Self> {
+        let algorithm = match aes_hardware_available() {
+            true => &aead::AES_256_GCM,
+            false => &aead::CHACHA20_POLY1305,
+        };
+
+        let mut name = [0u8; KEY_NAME_LEN];
+        let mut key = vec![0u8; algorithm.key_len()];
+        rng.fill(&mut name).ok()?;
+        rng.fill(&mut key).ok()?;
+
+        Some(Self {
+            name,
+            key: LessSafeKey::new(UnboundKey::new(algorithm, &key).ok()?),
+        })
+    }
+}
+
+struct TicketKeys {
+    current: TicketKey,
+    previous: Option<TicketKey>,
+    rotated_at: Instant,
+}
+
+/// Session ticket encrypter whose key is replaced every rotation interval. The previous key is kept for another
+/// interval to decrypt the tickets issued before the rotation, so resumption keeps working across rotations while a
+/// leaked key only exposes the sessions of two intervals.
+pub struct RotatingTicketer {
+    rotation: Duration,
+    rng: SystemRandom,
+    keys: Mutex<TicketKeys>,
+}
+
+impl RotatingTicketer {
+    /// Panics if the system random number generator fails.
+    pub fn new(rotation: Duration) -> Arc<Self> {
+        let rng = SystemRandom::new();
+        let current = TicketKey::generate(&rng).expect("Failed to generate session ticket key");
+
+        Arc::new(Self {
+            rotation,
+            rng,
+            keys: Mutex::new(TicketKeys {
+                current,
+                previous: None,
+                rotated_at: Instant::now(),
+            }),
+        })
+    }
+
+    /// Replace the current key if it is older than the rotation interval. Keeps using the current key if a new one
+    /// can't be generated, and tries again on the next ticket.
+    fn maybe_rotate(&self, keys: &mut TicketKeys) {
+        if keys.rotated_at.elapsed() < self.rotation {
+            return;
+        }
+
+        let key = match TicketKey::generate(&self.rng) {
+            Some(key) => key,
+            None => return,
+        };
+
+        // Tickets of the previous key are too old if the server was idle for more than one interval
+        let expired = keys.rotated_at.elapsed() >= self.rotation * 2;
+        let previous = std::mem::replace(&mut keys.current, key);
+        keys.previous = if expired { None } else { Some(previous) };
+        keys.rotated_at = Instant::now();
+
+        info!("Rotated TLS session ticket key");
+        metrics::increment("tls_ticket_key_rotations_total", 1);
+    }
+}
+
+impl ProducesTickets for RotatingTicketer {
+    fn enabled(&self) -> bool {
+        true
+    }
+
+    fn lifetime(&self) -> u32 {
+        self.rotation.as_secs().min(u32::MAX as u64) as u32
+    }
+
+    fn encrypt(&self, plain: &[u8]) -> Option<Vec<u8>> {
+        let mut keys = self.keys.lock().unwrap();
+        self.maybe_rotate(&mut keys);
+
+        let mut nonce = [0u8; NONCE_LEN];
+        self.rng.fill(&mut nonce).ok()?;
+
+        let mut ticket = Vec::with_capacity(KEY_NAME_LEN + NONCE_LEN + plain.len() + 16);
+        ticket.extend_from_slice(&keys.current.name);
+        ticket.extend_from_slice(&nonce);
+
+        let mut payload = plain.to_vec();
+        keys.current
+            .key
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::empty(),
+                &mut payload,
+            )
+            .ok()?;
+        ticket.extend_from_slice(&payload);
+
+        Some(ticket)
+    }
+
+    fn decrypt(&self, cipher: &[u8]) -> Option<Vec<u8>> {
+        if cipher.len() < KEY_NAME_LEN + NONCE_LEN {
+            return None;
+        }
+
+        let (name, rest) = cipher.split_at(KEY_NAME_LEN);
+        let (nonce, payload) = rest.split_at(NONCE_LEN);
+
+        let mut keys = self.keys.lock().unwrap();
+        self.maybe_rotate(&mut keys);
+
+        let key = [Some(&keys.current), keys.previous.as_ref()]
+            .into_iter()
+            .flatten()
+            .find(|key| key.name == name)?;
+
+        let mut payload = payload.to_vec();
+        let plain = key
+            .key
+            .open_in_place(
+                Nonce::try_assume_unique_for_key(nonce).ok()?,
+                Aad::empty(),
+                &mut payload,
+            )
+            .ok()?;
+
+        Some(plain.to_vec())
+    }
+}
@@ -1,17 +1,77 @@
-use log::error;
+use log::{error, info};
+use once_cell::sync::OnceCell;
 
 use std::fs::File;
 use std::io::{BufReader, ErrorKind};
 use std::sync::Arc;
+#[cfg(feature = "server")]
+use std::time::Duration;
 use std::time::SystemTime;
 
 use rustls::client::{ServerCertVerified, ServerCertVerifier, ServerName};
 use rustls::Error;
 use rustls::RootCertStore;
-use rustls::{Certificate, ClientConfig, PrivateKey, ServerConfig};
+#[cfg(feature = "server")]
+use rustls::ServerConfig;
+use rustls::{
+    BulkAlgorithm, SupportedCipherSuite, SupportedKxGroup, ALL_CIPHER_SUITES, ALL_KX_GROUPS,
+};
+use rustls::{Certificate, ClientConfig, PrivateKey};
 use rustls_pemfile::{read_one, Item};
 
-use crate::config::base::{InboundTlsConfig, OutboundTlsConfig};
+#[cfg(feature = "server")]
+use crate::config::base::InboundTlsConfig;
+use crate::config::base::OutboundTlsConfig;
+#[cfg(feature = "server")]
+use crate::config::ticketer::RotatingTicketer;
+
+/// Default interval in seconds of rotating the session ticket key
+#[cfg(feature = "server")]
+const DEFAULT_TICKET_ROTATION: u64 = 6 * 60 * 60;
+
+/// Cipher suites ordered by preference for the CPU, detected on first use.
+static CIPHER_SUITES: OnceCell<Vec<SupportedCipherSuite>> = OnceCell::new();
+
+/// Whether the CPU has instructions accelerating AES-GCM, like AES-NI and PCLMULQDQ on x86.
+pub fn aes_hardware_available() -> bool {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+        std::arch::is_x86_feature_detected!("aes")
+            && std::arch::is_x86_feature_detected!("pclmulqdq")
+    }
+    #[cfg(target_arch = "aarch64")]
+    {
+        std::arch::is_aarch64_feature_detected!("aes")
+            && std::arch::is_aarch64_feature_detected!("pmull")
+    }
+    #[cfg(not(any(target_arch = "x86", target_arch = "x86_64", target_arch = "aarch64")))]
+    {
+        false
+    }
+}
+
+/// Cipher suites supported by rustls, with AES-GCM preferred on CPUs accelerating it and ChaCha20-Poly1305 preferred
+/// otherwise, which is several times faster than AES in software. TLS 1.3 suites always come first.
+pub fn preferred_cipher_suites() -> &'static [SupportedCipherSuite] {
+    CIPHER_SUITES.get_or_init(|| {
+        let prefer_aes = aes_hardware_available();
+        match prefer_aes {
+            true => info!("AES hardware acceleration detected, preferring AES-GCM cipher suites"),
+            false => info!("AES hardware acceleration not detected, preferring ChaCha20-Poly1305"),
+        }
+
+        let mut suites = ALL_CIPHER_SUITES.to_vec();
+        suites.sort_by_key(|suite| {
+            let (tls12, bulk) = match suite {
+                SupportedCipherSuite::Tls12(suite) => (true, &suite.common.bulk),
+                SupportedCipherSuite::Tls13(suite) => (false, &suite.common.bulk),
+            };
+            let chacha = matches!(bulk, BulkAlgorithm::Chacha20Poly1305);
+            (tls12, chacha == prefer_aes)
+        });
+        suites
+    })
+}
 
 /// Stub Certificate verifier that skips certificate verification. It is used when the user
 /// explicitly allows insecure TLS connection in configuration file, by setting
@@ -59,9 +119,26 @@ impl ServerCertVerifier for NoCertificateVerification {
 /// }
 /// ```
 pub fn make_client_config(config: &OutboundTlsConfig) -> Arc<ClientConfig> {
+    Arc::new(make_client_config_with(
+        config,
+        preferred_cipher_suites(),
+        &ALL_KX_GROUPS,
+    ))
+}
+
+/// Create ClientConfig like make_client_config, offering the cipher suites and the key exchange groups in the order
+/// given.
+pub fn make_client_config_with(
+    config: &OutboundTlsConfig,
+    cipher_suites: &[SupportedCipherSuite],
+    kx_groups: &[&'static SupportedKxGroup],
+) -> ClientConfig {
     if config.allow_insecure {
         let mut config = ClientConfig::builder()
-            .with_safe_defaults()
+            .with_cipher_suites(cipher_suites)
+            .with_kx_groups(kx_groups)
+            .with_safe_default_protocol_versions()
+            .unwrap()
             .with_root_certificates(RootCertStore::empty())
             .with_no_client_auth();
 
@@ -69,7 +146,7 @@ pub fn make_client_config(config: &OutboundTlsConfig) -> Arc<ClientConfig> {
             .dangerous()
             .set_certificate_verifier(Arc::new(NoCertificateVerification {}));
 
-        Arc::new(config)
+        config
     } else {
         let mut root_store = RootCertStore::empty();
         root_store.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|ta| {
@@ -80,12 +157,13 @@ pub fn make_client_config(config: &OutboundTlsConfig) -> Arc<ClientConfig> {
             )
         }));
 
-        let config = ClientConfig::builder()
-            .with_safe_defaults()
+        ClientConfig::builder()
+            .with_cipher_suites(cipher_suites)
+            .with_kx_groups(kx_groups)
+            .with_safe_default_protocol_versions()
+            .unwrap()
             .with_root_certificates(root_store)
-            .with_no_client_auth();
-
-        Arc::new(config)
+            .with_no_client_auth()
     }
 }
 
@@ -101,6 +179,7 @@ pub fn make_client_config(config: &OutboundTlsConfig) -> Arc<ClientConfig> {
 ///     }         
 /// }
 /// ```
+#[cfg(feature = "server")]
 pub fn make_server_config(config: &InboundTlsConfig) -> Option<Arc<ServerConfig>> {
     let certificates = match load_certs(&config.cert_path) {
         Ok(certs) => certs,
@@ -112,8 +191,8 @@ pub fn make_server_config(config: &InboundTlsConfig) -> Option<Arc<ServerConfig>
         Err(_) => return None,
     };
 
-    let cfg = ServerConfig::builder()
-        .with_safe_default_cipher_suites()
+    let mut cfg = ServerConfig::builder()
+        .with_cipher_suites(preferred_cipher_suites())
         .with_safe_default_kx_groups()
         .with_safe_default_protocol_versions()
         .unwrap()
@@ -121,10 +200,28 @@ pub fn make_server_config(config: &InboundTlsConfig) -> Option<Arc<ServerConfig>
         .with_single_cert(certificates, key)
         .expect("bad certificate/key");
 
+    // Without AES hardware the server insists on ChaCha20-Poly1305, otherwise it follows the preference of the client,
+    // which may lack AES hardware itself
+    cfg.ignore_client_order = !aes_hardware_available();
+
+    // Session tickets allow resumption without keeping a server side cache, their key is rotated periodically
+    let rotation = config
+        .session_ticket_rotation
+        .unwrap_or(DEFAULT_TICKET_ROTATION);
+    cfg.ticketer = RotatingTicketer::new(Duration::from_secs(rotation));
+
+    if let Some(alpn) = &config.alpn {
+        cfg.alpn_protocols = alpn.iter().map(|p| p.as_bytes().to_vec()).collect();
+    }
+
+    // Kernel TLS needs the keys of the sessions it takes over
+    cfg.enable_secret_extraction = config.ktls.unwrap_or(false);
+
     Some(Arc::new(cfg))
 }
 
-fn load_certs(path: &str) -> std::io::Result<Vec<Certificate>> {
+/// Read the PEM certificates in the file, the server certificate first and then its chain.
+pub fn load_certs(path: &str) -> std::io::Result<Vec<Certificate>> {
     let mut reader = match File::open(path) {
         Ok(file) => BufReader::new(file),
         Err(e) => {
@@ -142,7 +239,8 @@ fn load_certs(path: &str) -> std::io::Result<Vec<Certificate>> {
     };
 }
 
-fn load_private_key(path: &str) -> std::io::Result<PrivateKey> {
+/// Read the first PEM private key in the file, in PKCS#1, PKCS#8 or SEC1 format.
+pub fn load_private_key(path: &str) -> std::io::Result<PrivateKey> {
     let mut reader = match File::open(path) {
         Ok(file) => BufReader::new(file),
         Err(e) => return Err(e),
@@ -0,0 +1,36 @@
+use crate::config::base::TransportsConfig;
+
+use once_cell::sync::OnceCell;
+
+/// Static transport toggles shared by the inbounds and the outbounds
+static TRANSPORTS: OnceCell<Transports> = OnceCell::new();
+
+/// Transports enabled at runtime by the transports section of the configuration, all of them by default. Besides the
+/// configuration being rejected if it still uses a disabled transport, the inbounds and outbounds skip setting up the
+/// disabled ones, like the WebSocket upgrades of the TCP inbound or the QUIC failover of the TCP outbounds.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Transports {
+    pub grpc: bool,
+    pub quic: bool,
+    pub websocket: bool,
+}
+
+impl Transports {
+    pub fn new(config: Option<&TransportsConfig>) -> Self {
+        Self {
+            grpc: config.and_then(|cfg| cfg.grpc).unwrap_or(true),
+            quic: config.and_then(|cfg| cfg.quic).unwrap_or(true),
+            websocket: config.and_then(|cfg| cfg.websocket).unwrap_or(true),
+        }
+    }
+
+    /// Build the toggles shared by the whole process from the configuration.
+    pub fn init(config: Option<&TransportsConfig>) -> &'static Self {
+        TRANSPORTS.get_or_init(|| Self::new(config))
+    }
+
+    /// Toggles shared by the whole process, enabling every transport if they weren't initialized.
+    pub fn get() -> &'static Self {
+        TRANSPORTS.get_or_init(|| Self::new(None))
+    }
+}
@@ -0,0 +1,130 @@
+use crate::dns::Resolver;
+use crate::protocol::common::addr::{IpAddrPort, IpAddress};
+
+use std::io::{Error, ErrorKind, Result};
+use trust_dns_resolver::proto::rr::rdata::svcb::SvcParamValue;
+use trust_dns_resolver::proto::rr::{Name, RData, Record, RecordType};
+
+/// Port of the endpoints in the HTTPS records without one, if the outbound has no port either
+const HTTPS_PORT: u16 = 443;
+
+/// Address of a server discovered from DNS, along with the ALPN protocols it advertises.
+pub struct Endpoint {
+    pub address: IpAddrPort,
+    pub alpn: Vec<String>,
+}
+
+/// DNS name publishing the servers of an outbound, either in SRV records like _trojan._tcp.example.com, or in HTTPS
+/// records which also carry the ALPN protocols of the servers.
+pub enum Discovery {
+    Srv(Name),
+    /// Name of the HTTPS records with the port of the endpoints that don't have one
+    Https(Name, u16),
+}
+
+impl Discovery {
+    /// Parse the discovery of an outbound, srv: or https: followed by the DNS name. Fails with InvalidInput if it is
+    /// invalid.
+    pub fn new(discovery: &str, default_port: Option<u16>) -> Result<Self> {
+        let invalid = |message: String| Err(Error::new(ErrorKind::InvalidInput, message));
+
+        let (kind, name) = match discovery.split_once(':') {
+            Some((kind, name)) => (kind, name),
+            None => {
+                return invalid(format!(
+                    "Invalid discovery {}, expected srv:name or https:name",
+                    discovery
+                ))
+            }
+        };
+        let name = match Name::from_utf8(name) {
+            Ok(name) => name,
+            Err(e) => return invalid(format!("Invalid name of discovery {}: {}", discovery, e)),
+        };
+
+        match kind {
+            "srv" => Ok(Discovery::Srv(name)),
+            "https" => Ok(Discovery::Https(name, default_port.unwrap_or(HTTPS_PORT))),
+            _ => invalid(format!("Unknown kind of discovery {}", discovery)),
+        }
+    }
+
+    /// Look up the endpoints of the servers, in the order they should be tried. Fails if there are none.
+    pub async fn discover(&self) -> Result<Vec<Endpoint>> {
+        let (name, record_type) = match self {
+            Discovery::Srv(name) => (name, RecordType::SRV),
+            Discovery::Https(name, _) => (name, RecordType::HTTPS),
+        };
+
+        let records = Resolver::get()
+            .lookup_records(name.clone(), record_type)
+            .await?;
+        let endpoints = self.endpoints(&records);
+        if endpoints.is_empty() {
+            return Err(Error::new(
+                ErrorKind::NotFound,
+                format!("No servers in the {} records of {}", record_type, name),
+            ));
+        }
+
+        Ok(endpoints)
+    }
+
+    /// Endpoints in the records, by priority. SRV records of the same priority are tried by descending weight, and the
+    /// records with the target . mean the service isn't available there. HTTPS records in alias mode, with the
+    /// priority 0, name no endpoint themselves and are left out.
+    pub fn endpoints(&self, records: &[Record]) -> Vec<Endpoint> {
+        let mut endpoints: Vec<(u16, u16, Endpoint)> = records
+            .iter()
+            .filter_map(|record| match (self, record.data()?) {
+                (Discovery::Srv(_), RData::SRV(srv)) if !srv.target().is_root() => Some((
+                    srv.priority(),
+                    u16::MAX - srv.weight(),
+                    Endpoint {
+                        address: IpAddrPort::new(host(srv.target()), srv.port()),
+                        alpn: Vec::new(),
+                    },
+                )),
+                (Discovery::Https(name, default_port), RData::HTTPS(svcb))
+                    if svcb.svc_priority() != 0 =>
+                {
+                    // Service mode records with the target . are served by the name itself
+                    let target = match svcb.target_name().is_root() {
+                        true => name,
+                        false => svcb.target_name(),
+                    };
+
+                    let mut port = *default_port;
+                    let mut alpn = Vec::new();
+                    for (_, value) in svcb.svc_params() {
+                        match value {
+                            SvcParamValue::Port(value) => port = *value,
+                            SvcParamValue::Alpn(value) => alpn = value.0.clone(),
+                            _ => (),
+                        }
+                    }
+
+                    Some((
+                        svcb.svc_priority(),
+                        0,
+                        Endpoint {
+                            address: IpAddrPort::new(host(target), port),
+                            alpn,
+                        },
+                    ))
+                }
+                _ => None,
+            })
+            .collect();
+
+        endpoints.sort_by_key(|(priority, weight, _)| (*priority, *weight));
+        endpoints
+            .into_iter()
+            .map(|(_, _, endpoint)| endpoint)
+            .collect()
+    }
+}
+
+fn host(name: &Name) -> IpAddress {
+    IpAddress::from_host(name.to_utf8().trim_end_matches('.'))
+}
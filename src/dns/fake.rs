@@ -0,0 +1,260 @@
+use crate::config::base::FakeDnsConfig;
+use crate::dns::{response_to, Resolver};
+use crate::protocol::common::addr::IpAddress;
+use crate::protocol::common::atype::Atype;
+use crate::protocol::common::request::InboundRequest;
+use crate::router::matcher::DomainMatcher;
+
+use bytes::Bytes;
+use ipnet::Ipv4Net;
+use log::{debug, info, warn};
+use once_cell::sync::OnceCell;
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Result};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use tokio::net::UdpSocket;
+use trust_dns_resolver::proto::op::{Message, Query, ResponseCode};
+use trust_dns_resolver::proto::rr::{RData, Record, RecordType};
+
+/// Addresses reserved for benchmarking, which aren't routed on the Internet
+const DEFAULT_IP_RANGE: &str = "198.18.0.0/15";
+
+/// Default TTL in seconds of the fake answers, short so the applications don't keep the addresses after they are reused
+const DEFAULT_TTL: u32 = 1;
+
+/// Largest DNS message over UDP
+const MAX_MESSAGE_SIZE: usize = 4096;
+
+/// Static lifetime FakeDNS shared by the DNS server and the inbound
+static FAKE_DNS: OnceCell<FakeDns> = OnceCell::new();
+
+/// Fake addresses given out, in both directions.
+struct Pool {
+    /// Offset in the range of the next address to give out
+    next: u32,
+    addresses: HashMap<String, Ipv4Addr>,
+    domains: HashMap<Ipv4Addr, String>,
+}
+
+/// FakeDNS answers the queries of the proxied domains with addresses from a reserved range, and remembers which domain
+/// each address stands for. The connections to the fake addresses are then proxied to the domains, so the requests can
+/// be routed by domain and the domains are resolved by the remote server.
+pub struct FakeDns {
+    network: Ipv4Net,
+    domains: Option<DomainMatcher>,
+    ttl: u32,
+    pool: Mutex<Pool>,
+}
+
+impl FakeDns {
+    /// Build the FakeDNS shared by the whole process from the configuration.
+    pub fn init(config: &FakeDnsConfig) -> Result<&'static Self> {
+        FAKE_DNS.get_or_try_init(|| Self::new(config))
+    }
+
+    /// FakeDNS shared by the whole process, if it is configured.
+    #[inline]
+    pub fn get() -> Option<&'static Self> {
+        FAKE_DNS.get()
+    }
+
+    /// Fails with InvalidInput if the ip_range is invalid or has no room for two addresses, or a domain is invalid.
+    pub fn new(config: &FakeDnsConfig) -> Result<Self> {
+        let range = config.ip_range.as_deref().unwrap_or(DEFAULT_IP_RANGE);
+        let network: Ipv4Net = match range.parse() {
+            Ok(network) => network,
+            Err(e) => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("invalid FakeDNS ip_range {}: {}", range, e),
+                ))
+            }
+        };
+        if network.prefix_len() > 30 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("FakeDNS ip_range {} is too small", range),
+            ));
+        }
+
+        Ok(Self {
+            network: network.trunc(),
+            domains: config
+                .domains
+                .as_ref()
+                .map(|domains| DomainMatcher::new(domains, None))
+                .transpose()?,
+            ttl: config.ttl.unwrap_or(DEFAULT_TTL),
+            pool: Mutex::new(Pool {
+                next: 1,
+                addresses: HashMap::new(),
+                domains: HashMap::new(),
+            }),
+        })
+    }
+
+    /// Fake address of the domain, or None if the domain isn't proxied. The domain gets the same address until the
+    /// address is reused.
+    pub fn address(&self, domain: &str) -> Option<Ipv4Addr> {
+        let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+        if let Some(domains) = &self.domains {
+            if !domains.matches_domain(&domain) {
+                return None;
+            }
+        }
+
+        let mut pool = self.pool.lock().unwrap();
+        if let Some(address) = pool.addresses.get(&domain) {
+            return Some(*address);
+        }
+
+        // Skip the network and the broadcast addresses of the range
+        let size = 1u32 << (32 - self.network.prefix_len());
+        let address = Ipv4Addr::from(u32::from(self.network.network()) + pool.next);
+        pool.next = match pool.next + 1 {
+            next if next == size - 1 => 1,
+            next => next,
+        };
+
+        if let Some(previous) = pool.domains.insert(address, domain.clone()) {
+            pool.addresses.remove(&previous);
+        }
+        pool.addresses.insert(domain, address);
+
+        Some(address)
+    }
+
+    /// Domain the fake address stands for.
+    pub fn domain(&self, address: IpAddr) -> Option<String> {
+        match address {
+            IpAddr::V4(address) => self.pool.lock().unwrap().domains.get(&address).cloned(),
+            IpAddr::V6(_) => None,
+        }
+    }
+
+    /// Replace the fake address in the destination of the request with the domain it stands for. Fails if the address
+    /// is in the fake range but isn't given out, which happens after restarting or once it is reused.
+    pub fn restore(&self, request: &mut InboundRequest) -> Result<()> {
+        let address = match request.addr_port.ip {
+            IpAddress::IpAddr(IpAddr::V4(address)) if self.network.contains(&address) => address,
+            _ => return Ok(()),
+        };
+
+        match self.domain(IpAddr::V4(address)) {
+            Some(domain) => {
+                debug!("Mapped fake address {} back to {}", address, domain);
+                request.addr_port.ip = IpAddress::from_bytes(Bytes::from(domain));
+                request.atype = Atype::DomainName;
+                Ok(())
+            }
+            None => Err(Error::new(
+                ErrorKind::NotFound,
+                format!("fake address {} isn't given to any domain", address),
+            )),
+        }
+    }
+
+    /// Answer the DNS queries received by the socket until it fails.
+    pub async fn serve(&'static self, socket: UdpSocket) -> Result<()> {
+        let socket = Arc::new(socket);
+        let mut buf = vec![0u8; MAX_MESSAGE_SIZE];
+
+        loop {
+            let (size, peer) = socket.recv_from(&mut buf).await?;
+            let query = match Message::from_vec(&buf[..size]) {
+                Ok(query) => query,
+                Err(e) => {
+                    debug!("Ignored invalid DNS query from {}: {}", peer, e);
+                    continue;
+                }
+            };
+
+            // Queries passed on to the resolver may take a while
+            let socket = socket.clone();
+            tokio::spawn(async move {
+                let response = match self.answer(query).await.to_vec() {
+                    Ok(response) => response,
+                    Err(e) => {
+                        warn!("Failed to encode DNS response: {}", e);
+                        return;
+                    }
+                };
+                if let Err(e) = socket.send_to(&response, peer).await {
+                    warn!("Failed to send DNS response to {}: {}", peer, e);
+                }
+            });
+        }
+    }
+
+    /// Response to the query, with the fake address for the A queries of the proxied domains.
+    async fn answer(&self, query: Message) -> Message {
+        let mut response = response_to(&query);
+
+        let question = match query.queries().first() {
+            Some(question) => question.clone(),
+            None => {
+                response.set_response_code(ResponseCode::FormErr);
+                return response;
+            }
+        };
+        response.add_query(question.clone());
+
+        if let Some(records) = self.fake_records(&question) {
+            response.add_answers(records);
+            return response;
+        }
+
+        match Resolver::get()
+            .lookup_records(question.name().clone(), question.query_type())
+            .await
+        {
+            Ok(records) => {
+                response.add_answers(records);
+            }
+            Err(e) => {
+                debug!("Failed to look up {}: {}", question.name(), e);
+                response.set_response_code(ResponseCode::ServFail);
+            }
+        }
+
+        response
+    }
+
+    /// Answers of the question if its domain is proxied, the fake address for the A queries and nothing for the other
+    /// queries so that the applications connect to the fake IPv4. None if the domain isn't proxied.
+    pub fn fake_records(&self, question: &Query) -> Option<Vec<Record>> {
+        let name = question.name();
+        let address = self.address(&name.to_utf8())?;
+
+        Some(match question.query_type() {
+            RecordType::A => vec![Record::from_rdata(
+                name.clone(),
+                self.ttl,
+                RData::A(address),
+            )],
+            _ => Vec::new(),
+        })
+    }
+}
+
+/// Start the FakeDNS server listening on the address in the configuration.
+pub async fn start(config: &'static FakeDnsConfig) -> Result<()> {
+    let address: SocketAddr = match (config.address.as_ref(), config.port)
+        .to_socket_addrs()?
+        .next()
+    {
+        Some(address) => address,
+        None => {
+            return Err(Error::new(
+                ErrorKind::AddrNotAvailable,
+                "incorrect FakeDNS address in configuration",
+            ))
+        }
+    };
+
+    let socket = UdpSocket::bind(address).await?;
+    info!("FakeDNS listening on {}", address);
+
+    FakeDns::init(config)?.serve(socket).await
+}
@@ -0,0 +1,302 @@
+use crate::config::base::DnsInboundConfig;
+use crate::dns::fake::FakeDns;
+use crate::dns::{response_to, Resolver};
+use crate::protocol::common::addr::{IpAddrPort, IpAddress};
+use crate::protocol::common::atype::Atype;
+use crate::protocol::common::command::Command;
+use crate::protocol::common::request::{InboundRequest, TransportProtocol};
+use crate::proxy::base::SupportedProtocols;
+use crate::router::{RouteContext, Router};
+
+use log::{debug, info, warn};
+use once_cell::sync::OnceCell;
+use std::io::{Error, ErrorKind, Result};
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use trust_dns_resolver::proto::op::{Message, ResponseCode};
+
+/// Port of the DNS inbound if it isn't configured
+const DEFAULT_PORT: u16 = 53;
+
+/// Largest DNS message over UDP
+const MAX_MESSAGE_SIZE: usize = 4096;
+
+/// Inbound tag the queries sent through the outbounds are routed with
+const INBOUND_TAG: &str = "dns";
+
+/// Time the upstream server has to answer a query
+const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Static lifetime DNS inbound shared by its UDP and TCP servers
+static DNS_HIJACK: OnceCell<DnsHijack> = OnceCell::new();
+
+/// DNS server for the queries captured from the applications. The proxied domains are answered by FakeDNS, and the
+/// other queries by the resolver or by the upstream server through the proxy, so that the DNS traffic of the
+/// applications doesn't leak to the local network.
+pub struct DnsHijack {
+    fake_dns: Option<&'static FakeDns>,
+    upstream: Option<(IpAddrPort, &'static Router)>,
+}
+
+impl DnsHijack {
+    /// Build the DNS inbound shared by the whole process.
+    pub fn init(
+        config: &DnsInboundConfig,
+        fake_dns: Option<&'static FakeDns>,
+        router: Option<&'static Router>,
+    ) -> Result<&'static Self> {
+        DNS_HIJACK.get_or_try_init(|| Self::new(config, fake_dns, router))
+    }
+
+    /// DNS inbound shared by the whole process, if it is configured.
+    pub fn get() -> Option<&'static Self> {
+        DNS_HIJACK.get()
+    }
+
+    /// Build the DNS inbound answering the proxied domains with the FakeDNS, and the other queries through the router
+    /// if there is an upstream server. Fails with InvalidInput if the upstream server is invalid or there is no router
+    /// to reach it.
+    pub fn new(
+        config: &DnsInboundConfig,
+        fake_dns: Option<&'static FakeDns>,
+        router: Option<&'static Router>,
+    ) -> Result<Self> {
+        let upstream = match &config.upstream {
+            Some(upstream) => {
+                let address = upstream_address(upstream)?;
+                let router = match router {
+                    Some(router) => router,
+                    None => {
+                        return Err(Error::new(
+                            ErrorKind::InvalidInput,
+                            format!("upstream DNS server {} requires the router", upstream),
+                        ))
+                    }
+                };
+                Some((
+                    IpAddrPort::new(IpAddress::IpAddr(address.ip()), address.port()),
+                    router,
+                ))
+            }
+            None => None,
+        };
+
+        Ok(Self {
+            fake_dns: fake_dns.filter(|_| config.fake_dns.unwrap_or(true)),
+            upstream,
+        })
+    }
+
+    /// Check the upstream server of the configuration like building the DNS inbound does, fails with InvalidInput if
+    /// it isn't an IP address with a port.
+    pub fn check(config: &DnsInboundConfig) -> Result<()> {
+        config
+            .upstream
+            .as_deref()
+            .map(upstream_address)
+            .transpose()
+            .map(drop)
+    }
+
+    /// Answer the DNS query in wire format, with SERVFAIL if neither the upstream server nor the resolver answers it so
+    /// that the clients don't wait for their timeout. Fails if the query is invalid.
+    pub async fn answer(&self, query: &[u8]) -> Result<Vec<u8>> {
+        let message = match Message::from_vec(query) {
+            Ok(message) => message,
+            Err(e) => return Err(Error::new(ErrorKind::InvalidData, e)),
+        };
+
+        let mut response = response_to(&message);
+        let question = match message.queries().first() {
+            Some(question) => question.clone(),
+            None => {
+                response.set_response_code(ResponseCode::FormErr);
+                return encode(&response);
+            }
+        };
+        response.add_query(question.clone());
+
+        if let Some(records) = self
+            .fake_dns
+            .and_then(|fake_dns| fake_dns.fake_records(&question))
+        {
+            response.add_answers(records);
+            return encode(&response);
+        }
+
+        if let Some((server, router)) = &self.upstream {
+            match tokio::time::timeout(UPSTREAM_TIMEOUT, forward(query, server, router)).await {
+                Ok(Ok(answer)) => return Ok(answer),
+                Ok(Err(e)) => debug!("Upstream DNS server {} failed to answer: {}", server, e),
+                Err(_) => debug!("Upstream DNS server {} timed out", server),
+            }
+            response.set_response_code(ResponseCode::ServFail);
+            return encode(&response);
+        }
+
+        match Resolver::get()
+            .lookup_records(question.name().clone(), question.query_type())
+            .await
+        {
+            Ok(records) => {
+                response.add_answers(records);
+            }
+            Err(e) => {
+                debug!("Failed to look up {}: {}", question.name(), e);
+                response.set_response_code(ResponseCode::ServFail);
+            }
+        }
+
+        encode(&response)
+    }
+
+    /// Answer the DNS queries received by the socket until it fails.
+    pub async fn serve_udp(&'static self, socket: UdpSocket) -> Result<()> {
+        let socket = Arc::new(socket);
+        let mut buf = vec![0u8; MAX_MESSAGE_SIZE];
+
+        loop {
+            let (size, peer) = socket.recv_from(&mut buf).await?;
+            let query = buf[..size].to_vec();
+
+            let socket = socket.clone();
+            tokio::spawn(async move {
+                let response = match self.answer(&query).await {
+                    Ok(response) => response,
+                    Err(e) => {
+                        debug!("Failed to answer DNS query from {}: {}", peer, e);
+                        return;
+                    }
+                };
+                if let Err(e) = socket.send_to(&response, peer).await {
+                    warn!("Failed to send DNS response to {}: {}", peer, e);
+                }
+            });
+        }
+    }
+
+    /// Answer the DNS queries of the connections accepted by the listener until it fails.
+    pub async fn serve_tcp(&'static self, listener: TcpListener) -> Result<()> {
+        loop {
+            let (stream, peer) = listener.accept().await?;
+            tokio::spawn(async move {
+                if let Err(e) = self.serve_stream(stream).await {
+                    debug!("Closed DNS connection from {}: {}", peer, e);
+                }
+            });
+        }
+    }
+
+    /// Answer the length prefixed queries of the stream one after another until it is closed.
+    async fn serve_stream(&self, mut stream: TcpStream) -> Result<()> {
+        loop {
+            let query = match read_message(&mut stream).await {
+                Ok(query) => query,
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+                Err(e) => return Err(e),
+            };
+            let response = self.answer(&query).await?;
+            write_message(&mut stream, &response).await?;
+        }
+    }
+}
+
+/// Send the query to the upstream server over TCP through the outbound the router selects for it.
+async fn forward(query: &[u8], server: &IpAddrPort, router: &Router) -> Result<Vec<u8>> {
+    let atype = match server.ip {
+        IpAddress::IpAddr(IpAddr::V4(_)) => Atype::IPv4,
+        _ => Atype::IPv6,
+    };
+    let request = InboundRequest::new(
+        atype,
+        server.ip.clone(),
+        Command::Connect,
+        server.port,
+        TransportProtocol::TCP,
+        SupportedProtocols::DIRECT,
+    );
+
+    let handler = router.route(&RouteContext {
+        request: &request,
+        inbound_tag: Some(INBOUND_TAG),
+        sniffed_domain: None,
+    });
+    let mut stream = handler.open_stream(server.clone()).await?;
+
+    write_message(&mut stream, query).await?;
+    read_message(&mut stream).await
+}
+
+/// Read a DNS message prefixed by its length, as sent over TCP.
+async fn read_message<T: AsyncRead + Unpin>(stream: &mut T) -> Result<Vec<u8>> {
+    let size = stream.read_u16().await? as usize;
+    let mut message = vec![0u8; size];
+    stream.read_exact(&mut message).await?;
+    Ok(message)
+}
+
+/// Write a DNS message prefixed by its length, as sent over TCP.
+async fn write_message<T: AsyncWrite + Unpin>(stream: &mut T, message: &[u8]) -> Result<()> {
+    let size = match u16::try_from(message.len()) {
+        Ok(size) => size,
+        Err(_) => {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "DNS message is too long",
+            ))
+        }
+    };
+
+    let mut buf = Vec::with_capacity(message.len() + 2);
+    buf.extend_from_slice(&size.to_be_bytes());
+    buf.extend_from_slice(message);
+    stream.write_all(&buf).await?;
+    stream.flush().await
+}
+
+fn encode(response: &Message) -> Result<Vec<u8>> {
+    response
+        .to_vec()
+        .map_err(|e| Error::new(ErrorKind::InvalidData, e))
+}
+
+/// Parse the address of the upstream DNS server.
+fn upstream_address(upstream: &str) -> Result<SocketAddr> {
+    upstream.parse().map_err(|e| {
+        Error::new(
+            ErrorKind::InvalidInput,
+            format!("invalid upstream DNS server {}: {}", upstream, e),
+        )
+    })
+}
+
+/// Start the DNS inbound listening on the address in the configuration over UDP and TCP.
+pub async fn start(
+    config: &'static DnsInboundConfig,
+    fake_dns: Option<&'static FakeDns>,
+    router: Option<&'static Router>,
+) -> Result<()> {
+    let address: SocketAddr = match (config.address.as_ref(), config.port.unwrap_or(DEFAULT_PORT))
+        .to_socket_addrs()?
+        .next()
+    {
+        Some(address) => address,
+        None => {
+            return Err(Error::new(
+                ErrorKind::AddrNotAvailable,
+                "incorrect DNS inbound address in configuration",
+            ))
+        }
+    };
+
+    let socket = UdpSocket::bind(address).await?;
+    let listener = TcpListener::bind(address).await?;
+    info!("DNS inbound listening on {}", address);
+
+    let hijack = DnsHijack::init(config, fake_dns, router)?;
+    tokio::try_join!(hijack.serve_udp(socket), hijack.serve_tcp(listener))?;
+    Ok(())
+}
@@ -0,0 +1,85 @@
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Result};
+use std::net::IpAddr;
+
+/// Most aliases followed for a name, so that the names mapped to each other don't loop forever
+const MAX_ALIASES: usize = 8;
+
+enum Host {
+    Address(IpAddr),
+    Alias(String),
+}
+
+/// Outcome of looking up a name in the hosts.
+pub enum Resolved {
+    /// Fixed address of the name
+    Address(IpAddr),
+    /// Fully qualified name to look up from the name servers in place of the name
+    Alias(String),
+    /// The name isn't in the hosts
+    Unmapped,
+}
+
+/// Static mapping of the domain names consulted before the name servers, like the hosts file of the system. Names are
+/// matched exactly, ignoring the case and the trailing dot.
+pub struct Hosts {
+    hosts: HashMap<String, Host>,
+}
+
+impl Hosts {
+    /// Build the mapping from the names to the IP addresses or the other names, fails with InvalidInput if a target
+    /// is empty.
+    pub fn new(hosts: Option<&HashMap<String, String>>) -> Result<Self> {
+        let hosts = hosts
+            .into_iter()
+            .flatten()
+            .map(|(name, target)| {
+                let host = match target.parse::<IpAddr>() {
+                    Ok(address) => Host::Address(address),
+                    Err(_) if normalize(target).is_empty() => {
+                        return Err(Error::new(
+                            ErrorKind::InvalidInput,
+                            format!("missing the address of host {}", name),
+                        ))
+                    }
+                    Err(_) => Host::Alias(normalize(target)),
+                };
+                Ok((normalize(name), host))
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Self { hosts })
+    }
+
+    /// Follow the mapping of the name until it ends with an address or a name that isn't mapped any further. Fails if
+    /// there are too many aliases in between.
+    pub fn resolve(&self, name: &str) -> Result<Resolved> {
+        let mut alias = None;
+        let mut current = normalize(name);
+
+        for _ in 0..=MAX_ALIASES {
+            match self.hosts.get(&current) {
+                Some(Host::Address(address)) => return Ok(Resolved::Address(*address)),
+                Some(Host::Alias(target)) => {
+                    current = target.clone();
+                    alias = Some(target);
+                }
+                None => {
+                    return Ok(match alias {
+                        Some(alias) => Resolved::Alias(format!("{}.", alias)),
+                        None => Resolved::Unmapped,
+                    })
+                }
+            }
+        }
+
+        Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("Too many aliases of host {} in hosts", name),
+        ))
+    }
+}
+
+fn normalize(name: &str) -> String {
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
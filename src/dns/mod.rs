@@ -0,0 +1,394 @@
+pub mod discovery;
+#[cfg(feature = "client")]
+pub mod fake;
+#[cfg(feature = "client")]
+pub mod hijack;
+pub mod hosts;
+pub mod tls;
+
+use crate::config::base::{DnsConfig, DnsServerConfig, DomainStrategy};
+use crate::dns::hosts::{Hosts, Resolved};
+use crate::dns::tls::UpstreamConnections;
+
+use http::Uri;
+use log::info;
+use once_cell::sync::OnceCell;
+use std::io::{Error, ErrorKind, Result};
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::time::Duration;
+use trust_dns_resolver::config::{
+    NameServerConfig, Protocol, ResolverConfig, ResolverOpts, TlsClientConfig,
+};
+use trust_dns_resolver::error::ResolveErrorKind;
+use trust_dns_resolver::proto::op::{Message, MessageType};
+use trust_dns_resolver::proto::rr::{Name, RData, Record, RecordType};
+use trust_dns_resolver::system_conf::read_system_conf;
+use trust_dns_resolver::{AsyncResolver, TokioConnection};
+
+/// Default number of names kept in the cache of the resolver
+const DEFAULT_CACHE_SIZE: usize = 1024;
+
+/// TTL in seconds of the answers from the hosts
+const HOSTS_TTL: u32 = 60;
+
+/// Port of the name servers listed without one
+const DNS_PORT: u16 = 53;
+
+/// Port of the DNS-over-TLS servers listed without one
+const TLS_PORT: u16 = 853;
+
+/// Port of the DNS-over-HTTPS servers listed without one
+const HTTPS_PORT: u16 = 443;
+
+/// Path of the DNS-over-HTTPS queries
+const DOH_PATH: &str = "/dns-query";
+
+/// Static lifetime resolver shared by the inbounds, the outbounds and the router
+static RESOLVER: OnceCell<Resolver> = OnceCell::new();
+
+/// Resolver looks up the domain names of the proxy requests and of the outbound servers. Answers are cached until
+/// their TTL expires, so that the connections to popular destinations don't wait for DNS every time. Queries can be
+/// sent over TLS or HTTPS, so that they can't be seen or tampered with on the way. The names in the hosts are answered
+/// without asking the servers.
+pub struct Resolver {
+    inner: AsyncResolver<TokioConnection, UpstreamConnections>,
+    hosts: Hosts,
+}
+
+impl Resolver {
+    /// Build the resolver shared by the whole process from the dns configuration.
+    pub fn init(config: Option<&DnsConfig>) -> Result<&'static Self> {
+        RESOLVER.get_or_try_init(|| Self::new(config))
+    }
+
+    /// Resolver shared by the whole process, built with the servers of the system if it wasn't initialized yet.
+    pub fn get() -> &'static Self {
+        // Without servers in the configuration there is nothing invalid to fail on
+        Self::init(None).expect("Failed to build the DNS resolver")
+    }
+
+    /// Fails with InvalidInput if a server or a host is invalid, see check, or if the host of an encrypted server
+    /// can't be looked up.
+    pub fn new(config: Option<&DnsConfig>) -> Result<Self> {
+        let (resolver_config, mut options) = match config.and_then(|c| c.servers.as_ref()) {
+            Some(servers) => {
+                let resolver_config =
+                    ResolverConfig::from_parts(None, Vec::new(), name_servers(servers)?);
+                (resolver_config, ResolverOpts::default())
+            }
+            None => read_system_conf().unwrap_or_else(|e| {
+                info!(
+                    "Using the default name servers, failed to read the system ones: {}",
+                    e
+                );
+                (ResolverConfig::default(), ResolverOpts::default())
+            }),
+        };
+
+        options.cache_size = config
+            .and_then(|c| c.cache_size)
+            .unwrap_or(DEFAULT_CACHE_SIZE);
+        options.positive_min_ttl = config.and_then(|c| c.min_ttl).map(Duration::from_secs);
+        options.positive_max_ttl = config.and_then(|c| c.max_ttl).map(Duration::from_secs);
+
+        let hosts = Hosts::new(config.and_then(|c| c.hosts.as_ref()))?;
+
+        let connections = UpstreamConnections::new(resolver_config.name_servers().to_vec());
+        match AsyncResolver::new_with_conn(resolver_config, options, connections) {
+            Ok(inner) => Ok(Self { inner, hosts }),
+            Err(e) => Err(Error::other(e)),
+        }
+    }
+
+    /// Check the servers and the hosts of the dns configuration like building the resolver does, without looking up
+    /// the hosts of the encrypted servers. Fails with InvalidInput if a server isn't a valid address or url, or has
+    /// TLS settings without being encrypted, or a host has an empty target.
+    pub fn check(config: &DnsConfig) -> Result<()> {
+        Hosts::new(config.hosts.as_ref())?;
+        for server in config.servers.iter().flatten() {
+            let (address, server_name, allow_insecure) = server_settings(server);
+            match server_protocol(address, server_name, allow_insecure)? {
+                Some(protocol) => drop(server_url(address, protocol)?),
+                None => drop(plain_server_address(address)?),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Look up the records of the type, for answering the queries passed on to the resolver.
+    /// The records of the aliases in the hosts are answered under the name queried.
+    pub async fn lookup_records(&self, name: Name, record_type: RecordType) -> Result<Vec<Record>> {
+        let target = match self.hosts.resolve(&name.to_utf8())? {
+            Resolved::Address(address) => {
+                let rdata = match (record_type, address) {
+                    (RecordType::A, IpAddr::V4(address)) => RData::A(address),
+                    (RecordType::AAAA, IpAddr::V6(address)) => RData::AAAA(address),
+                    _ => return Ok(Vec::new()),
+                };
+                return Ok(vec![Record::from_rdata(name, HOSTS_TTL, rdata)]);
+            }
+            Resolved::Alias(alias) => match Name::from_utf8(&alias) {
+                Ok(target) => Some(target),
+                Err(e) => return Err(Error::new(ErrorKind::InvalidData, e)),
+            },
+            Resolved::Unmapped => None,
+        };
+
+        let lookup = target.clone().unwrap_or_else(|| name.clone());
+        let records = match self.inner.lookup(lookup, record_type).await {
+            Ok(lookup) => lookup.records().to_vec(),
+            Err(e) if matches!(e.kind(), ResolveErrorKind::NoRecordsFound { .. }) => Vec::new(),
+            Err(e) => return Err(Error::new(ErrorKind::NotFound, e)),
+        };
+
+        match target {
+            // Only the final records of the alias are kept, as if the name had them itself
+            Some(_) => Ok(records
+                .into_iter()
+                .filter(|record| record.record_type() == record_type)
+                .map(|mut record| {
+                    record.set_name(name.clone());
+                    record
+                })
+                .collect()),
+            None => Ok(records),
+        }
+    }
+
+    /// Look up the address of the domain name, the first address in the answer is used. Names in the hosts are
+    /// answered from there.
+    #[inline]
+    pub async fn lookup(&self, domain: &str) -> Result<IpAddr> {
+        self.lookup_with(domain, DomainStrategy::AS_IS).await
+    }
+
+    /// Look up the address of the domain name of the family picked by the strategy.
+    pub async fn lookup_with(&self, domain: &str, strategy: DomainStrategy) -> Result<IpAddr> {
+        // Safety: the addresses are never empty
+        Ok(self.lookup_all_with(domain, strategy).await?[0])
+    }
+
+    /// Look up all the addresses of the domain name allowed by the strategy, the preferred family first and in the
+    /// order of the answer within each family. Fails if there isn't any.
+    pub async fn lookup_all_with(
+        &self,
+        domain: &str,
+        strategy: DomainStrategy,
+    ) -> Result<Vec<IpAddr>> {
+        let alias;
+        let domain = match self.hosts.resolve(domain)? {
+            Resolved::Address(address) => {
+                return match (strategy, address) {
+                    (DomainStrategy::IPV4_ONLY, IpAddr::V6(_))
+                    | (DomainStrategy::IPV6_ONLY, IpAddr::V4(_)) => Err(Error::new(
+                        ErrorKind::NotFound,
+                        format!("Host {} has no address of the family", domain),
+                    )),
+                    _ => Ok(vec![address]),
+                }
+            }
+            Resolved::Alias(name) => {
+                alias = name;
+                alias.as_str()
+            }
+            Resolved::Unmapped => domain,
+        };
+
+        let record_types: &[RecordType] = match strategy {
+            DomainStrategy::AS_IS => &[],
+            DomainStrategy::PREFER_IPV4 => &[RecordType::A, RecordType::AAAA],
+            DomainStrategy::PREFER_IPV6 => &[RecordType::AAAA, RecordType::A],
+            DomainStrategy::IPV4_ONLY => &[RecordType::A],
+            DomainStrategy::IPV6_ONLY => &[RecordType::AAAA],
+        };
+
+        let mut addresses = Vec::new();
+        if record_types.is_empty() {
+            match self.inner.lookup_ip(domain).await {
+                Ok(lookup) => addresses.extend(lookup.iter()),
+                Err(e) => return Err(Error::new(ErrorKind::NotFound, e)),
+            }
+        }
+        for record_type in record_types {
+            match self.inner.lookup(domain, *record_type).await {
+                Ok(lookup) => {
+                    addresses.extend(lookup.iter().filter_map(|rdata| rdata.to_ip_addr()))
+                }
+                Err(e) if matches!(e.kind(), ResolveErrorKind::NoRecordsFound { .. }) => (),
+                Err(e) => return Err(Error::new(ErrorKind::NotFound, e)),
+            }
+        }
+
+        if addresses.is_empty() {
+            return Err(Error::new(
+                ErrorKind::NotFound,
+                format!("No DNS result for the domain name: {}", domain),
+            ));
+        }
+
+        Ok(addresses)
+    }
+}
+
+/// Empty response to the query, with the header fields of the query copied over. The question and the answers are
+/// left to the server answering it.
+pub fn response_to(query: &Message) -> Message {
+    let mut response = Message::new();
+    response
+        .set_id(query.id())
+        .set_message_type(MessageType::Response)
+        .set_op_code(query.op_code())
+        .set_recursion_desired(query.recursion_desired())
+        .set_recursion_available(true);
+    response
+}
+
+/// Parse the name servers. Plain servers are an IP address with an optional port, queried over UDP and falling back to
+/// TCP for the truncated answers. DNS-over-TLS servers are urls like tls://1.1.1.1, and DNS-over-HTTPS servers are urls
+/// like https://1.1.1.1/dns-query. The name verified against the certificate of an encrypted server is the host of its
+/// url unless set by server_name, and each encrypted server has a client config of its own, which allow_insecure only
+/// applies to. Hosts given by name are looked up with the system resolver once at startup.
+fn name_servers(servers: &[DnsServerConfig]) -> Result<Vec<NameServerConfig>> {
+    let mut name_servers = Vec::new();
+
+    for server in servers {
+        let (address, server_name, allow_insecure) = server_settings(server);
+        let protocol = match server_protocol(address, server_name, allow_insecure)? {
+            Some(protocol) => protocol,
+            None => {
+                let address = plain_server_address(address)?;
+                name_servers.push(NameServerConfig::new(address, Protocol::Udp));
+                name_servers.push(NameServerConfig::new(address, Protocol::Tcp));
+                continue;
+            }
+        };
+
+        let mut name_server = encrypted_name_server(address, protocol, server_name)?;
+        name_server.tls_config = Some(TlsClientConfig(tls::make_client_config(allow_insecure)));
+        name_servers.push(name_server);
+    }
+
+    Ok(name_servers)
+}
+
+/// Address, server name and allow_insecure of the server.
+fn server_settings(server: &DnsServerConfig) -> (&str, Option<&str>, bool) {
+    match server {
+        DnsServerConfig::Address(address) => (address, None, false),
+        DnsServerConfig::Upstream(upstream) => (
+            &upstream.address,
+            upstream.server_name.as_deref(),
+            upstream.allow_insecure.unwrap_or(false),
+        ),
+    }
+}
+
+/// Protocol of the encrypted server from the scheme of its url, or None for a plain server.
+fn server_protocol(
+    address: &str,
+    server_name: Option<&str>,
+    allow_insecure: bool,
+) -> Result<Option<Protocol>> {
+    match address.split_once("://") {
+        Some(("tls", _)) => Ok(Some(Protocol::Tls)),
+        Some(("https", _)) => Ok(Some(Protocol::Https)),
+        Some(_) => Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("unsupported scheme of DNS server {}", address),
+        )),
+        None if server_name.is_some() || allow_insecure => Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "TLS settings of DNS server {} need a tls:// or https:// url",
+                address
+            ),
+        )),
+        None => Ok(None),
+    }
+}
+
+/// Parse the address of a plain name server.
+fn plain_server_address(server: &str) -> Result<SocketAddr> {
+    match server.parse::<SocketAddr>() {
+        Ok(address) => Ok(address),
+        Err(_) => match server.parse::<IpAddr>() {
+            Ok(ip) => Ok(SocketAddr::new(ip, DNS_PORT)),
+            Err(e) => Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("invalid DNS server {}: {}", server, e),
+            )),
+        },
+    }
+}
+
+/// Parse the url of a DNS-over-TLS or DNS-over-HTTPS server into its host and port.
+fn server_url(url: &str, protocol: Protocol) -> Result<(String, u16)> {
+    let uri = match url.parse::<Uri>() {
+        Ok(uri) => uri,
+        Err(e) => {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("invalid DNS server {}: {}", url, e),
+            ))
+        }
+    };
+
+    // The path of DNS-over-HTTPS isn't configurable in the underlying client, and DNS-over-TLS has none
+    let (path, default_port) = match protocol {
+        Protocol::Https => (DOH_PATH, HTTPS_PORT),
+        _ => ("/", TLS_PORT),
+    };
+    if uri.path() != path {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("path of DNS server {} has to be {}", url, path),
+        ));
+    }
+
+    let host = match uri.host() {
+        Some(host) => host.trim_start_matches('[').trim_end_matches(']'),
+        None => {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("missing host of DNS server {}", url),
+            ))
+        }
+    };
+    Ok((host.to_string(), uri.port_u16().unwrap_or(default_port)))
+}
+
+/// Build the name server of a DNS-over-TLS or DNS-over-HTTPS server, looking up its host if given by name.
+fn encrypted_name_server(
+    url: &str,
+    protocol: Protocol,
+    server_name: Option<&str>,
+) -> Result<NameServerConfig> {
+    let (host, port) = server_url(url, protocol)?;
+
+    let address = match host.parse::<IpAddr>() {
+        Ok(ip) => SocketAddr::new(ip, port),
+        Err(_) => match (host.as_str(), port)
+            .to_socket_addrs()
+            .map(|mut addrs| addrs.next())
+        {
+            Ok(Some(address)) => address,
+            Ok(None) => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("no address of DNS server {}", url),
+                ))
+            }
+            Err(e) => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("failed to look up DNS server {}: {}", url, e),
+                ))
+            }
+        },
+    };
+
+    let mut name_server = NameServerConfig::new(address, protocol);
+    name_server.tls_dns_name = Some(server_name.map(str::to_string).unwrap_or(host));
+    Ok(name_server)
+}
@@ -0,0 +1,87 @@
+use crate::config::tls::NoCertificateVerification;
+
+use rustls::{ClientConfig, OwnedTrustAnchor, RootCertStore};
+use std::sync::Arc;
+use trust_dns_resolver::config::{NameServerConfig, ResolverOpts, TlsClientConfig};
+use trust_dns_resolver::{
+    ConnectionProvider, TokioConnection, TokioConnectionProvider, TokioHandle,
+};
+
+/// Client config of a DNS-over-TLS or DNS-over-HTTPS server, which verifies its certificate against the web PKI roots
+/// unless the server allows insecure connections. No ALPN protocol is set, the DNS-over-HTTPS connections offer h2 on
+/// their own while DNS-over-TLS offers none.
+pub fn make_client_config(allow_insecure: bool) -> Arc<ClientConfig> {
+    let builder = ClientConfig::builder().with_safe_defaults();
+    if allow_insecure {
+        return Arc::new(
+            builder
+                .with_custom_certificate_verifier(Arc::new(NoCertificateVerification {}))
+                .with_no_client_auth(),
+        );
+    }
+
+    let mut root_store = RootCertStore::empty();
+    root_store.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|ta| {
+        OwnedTrustAnchor::from_subject_spki_name_constraints(
+            ta.subject,
+            ta.spki,
+            ta.name_constraints,
+        )
+    }));
+    Arc::new(
+        builder
+            .with_root_certificates(root_store)
+            .with_no_client_auth(),
+    )
+}
+
+/// Connects to the name servers with the client configs of their own, which the resolver would replace with a single
+/// one for all of them. Allowing insecure connections to a server thus doesn't skip verifying the others, even the
+/// ones under the same name.
+#[derive(Clone)]
+pub struct UpstreamConnections {
+    inner: TokioConnectionProvider,
+    servers: Arc<Vec<NameServerConfig>>,
+}
+
+impl UpstreamConnections {
+    pub fn new(servers: Vec<NameServerConfig>) -> Self {
+        Self {
+            inner: TokioConnectionProvider::new(TokioHandle),
+            servers: Arc::new(servers),
+        }
+    }
+
+    /// Client config the server was configured with, if any.
+    fn client_config(&self, config: &NameServerConfig) -> Option<&TlsClientConfig> {
+        self.servers
+            .iter()
+            .find(|server| {
+                server.socket_addr == config.socket_addr
+                    && server.protocol == config.protocol
+                    && server.tls_dns_name == config.tls_dns_name
+            })
+            .and_then(|server| server.tls_config.as_ref())
+    }
+}
+
+impl ConnectionProvider for UpstreamConnections {
+    type Conn = TokioConnection;
+    type FutureConn = <TokioConnectionProvider as ConnectionProvider>::FutureConn;
+    type Time = <TokioConnectionProvider as ConnectionProvider>::Time;
+
+    fn new_connection(
+        &self,
+        config: &NameServerConfig,
+        options: &ResolverOpts,
+    ) -> Self::FutureConn {
+        match self.client_config(config) {
+            Some(client_config) => {
+                let mut config = config.clone();
+                config.tls_config = Some(client_config.clone());
+                self.inner.new_connection(&config, options)
+            }
+            None => self.inner.new_connection(config, options),
+        }
+    }
+}
@@ -0,0 +1,157 @@
+pub mod server;
+
+pub mod events_api {
+    tonic::include_proto!("trojan_rust.events");
+}
+
+use crate::proxy::context::TrafficContext;
+
+use once_cell::sync::Lazy;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::broadcast::{self, Receiver, Sender};
+
+/// Most events queued for a watcher, the ones it falls behind by beyond are skipped
+const QUEUE_SIZE: usize = 1024;
+
+/// Bytes relayed from the clients to the destinations, UDP payloads included, for the traffic rate events
+pub static UPLOADED_BYTES: AtomicU64 = AtomicU64::new(0);
+
+/// Bytes relayed from the destinations to the clients, UDP payloads included, for the traffic rate events
+pub static DOWNLOADED_BYTES: AtomicU64 = AtomicU64::new(0);
+
+/// Channel the events are published on, each watcher subscribes to its own receiver
+static EVENTS: Lazy<Sender<TimedEvent>> = Lazy::new(|| broadcast::channel(QUEUE_SIZE).0);
+
+/// Identifier of the next connection reported
+static NEXT_CONNECTION: AtomicU64 = AtomicU64::new(1);
+
+/// Something that happened in the proxy that GUI wrappers show as live activity.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Event {
+    ConnectionOpened {
+        id: u64,
+        source: SocketAddr,
+        context: TrafficContext,
+    },
+    ConnectionClosed {
+        id: u64,
+        duration: Duration,
+    },
+    OutboundSwitched {
+        group: String,
+        from: String,
+        to: String,
+    },
+    /// Failure of the connection with the id, 0 for failures outside connections
+    Error {
+        connection: u64,
+        message: String,
+    },
+    /// Bytes per second relayed either way over the last sampling interval
+    TrafficRate {
+        upload: u64,
+        download: u64,
+    },
+}
+
+/// Event along with the time it happened at.
+#[derive(Clone, Debug)]
+pub struct TimedEvent {
+    pub time: SystemTime,
+    pub event: Event,
+}
+
+/// Publish the event to the watchers, if any.
+pub fn emit(event: Event) {
+    if watched() {
+        let _ = EVENTS.send(TimedEvent {
+            time: SystemTime::now(),
+            event,
+        });
+    }
+}
+
+/// Whether anyone watches the events, so that building the events nobody receives can be skipped.
+#[inline]
+pub fn watched() -> bool {
+    EVENTS.receiver_count() > 0
+}
+
+/// Receive the events published from now on.
+pub fn subscribe() -> Receiver<TimedEvent> {
+    EVENTS.subscribe()
+}
+
+/// Publish the rate of the traffic relayed every interval for as long as the process runs.
+pub async fn sample_traffic(interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    let mut uploaded = UPLOADED_BYTES.load(Ordering::Relaxed);
+    let mut downloaded = DOWNLOADED_BYTES.load(Ordering::Relaxed);
+
+    loop {
+        ticker.tick().await;
+
+        let upload = UPLOADED_BYTES.load(Ordering::Relaxed);
+        let download = DOWNLOADED_BYTES.load(Ordering::Relaxed);
+        let rate = |bytes: u64| (bytes as f64 / interval.as_secs_f64()) as u64;
+        emit(Event::TrafficRate {
+            upload: rate(upload - uploaded),
+            download: rate(download - downloaded),
+        });
+        uploaded = upload;
+        downloaded = download;
+    }
+}
+
+/// Connection reported to the watchers, reported as closed once dropped.
+pub struct Connection {
+    id: u64,
+    started: Instant,
+}
+
+impl Connection {
+    /// Report the connection from the source with the context as opened.
+    pub fn open(source: SocketAddr, context: &TrafficContext) -> Self {
+        let id = NEXT_CONNECTION.fetch_add(1, Ordering::Relaxed);
+        if watched() {
+            emit(Event::ConnectionOpened {
+                id,
+                source,
+                context: context.clone(),
+            });
+        }
+
+        Self {
+            id,
+            started: Instant::now(),
+        }
+    }
+
+    /// Id the events of the connection are reported with.
+    #[inline]
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Report the error the connection failed with.
+    pub fn fail(&self, error: &io::Error) {
+        if watched() {
+            emit(Event::Error {
+                connection: self.id,
+                message: error.to_string(),
+            });
+        }
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        emit(Event::ConnectionClosed {
+            id: self.id,
+            duration: self.started.elapsed(),
+        });
+    }
+}
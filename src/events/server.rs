@@ -0,0 +1,129 @@
+use crate::config::base::EventsConfig;
+use crate::events::events_api::event::Kind;
+use crate::events::events_api::event_service_server::{EventService, EventServiceServer};
+use crate::events::events_api::{
+    ConnectionClosed, ConnectionOpened, Error as ErrorEvent, Event as EventMessage,
+    OutboundSwitched, TrafficRate, WatchEventsRequest,
+};
+use crate::events::{self, Event, TimedEvent};
+
+use futures::Stream;
+use log::{debug, info};
+use std::io::{self, Error, ErrorKind};
+use std::net::ToSocketAddrs;
+use std::pin::Pin;
+use std::time::{Duration, UNIX_EPOCH};
+use tokio::sync::broadcast::error::RecvError;
+use tonic::transport::Server;
+use tonic::{Request, Response, Status};
+
+/// Default interval of sampling the traffic rate in seconds
+const DEFAULT_RATE_INTERVAL: u64 = 1;
+
+/// Start sampling the traffic rate and serving the event stream. The API doesn't authenticate the callers, it should
+/// only listen on the loopback interface or other addresses that are trusted.
+pub async fn start(events_config: &'static EventsConfig) -> io::Result<()> {
+    let address = match (events_config.address.as_ref(), events_config.port)
+        .to_socket_addrs()?
+        .next()
+    {
+        Some(addr) => addr,
+        None => {
+            return Err(Error::new(
+                ErrorKind::AddrNotAvailable,
+                "incorrect events address in configuration",
+            ))
+        }
+    };
+
+    let rate_interval = events_config
+        .rate_interval
+        .unwrap_or(DEFAULT_RATE_INTERVAL)
+        .max(1);
+    tokio::spawn(events::sample_traffic(Duration::from_secs(rate_interval)));
+
+    info!("Event stream listening on {}", address);
+
+    match Server::builder()
+        .add_service(EventServiceServer::new(EventApi))
+        .serve(address)
+        .await
+    {
+        Ok(_) => Ok(()),
+        Err(e) => Err(Error::new(
+            ErrorKind::Interrupted,
+            format!("Failed to start event stream server: {}", e),
+        )),
+    }
+}
+
+pub struct EventApi;
+
+#[tonic::async_trait]
+impl EventService for EventApi {
+    type WatchEventsStream = Pin<Box<dyn Stream<Item = Result<EventMessage, Status>> + Send>>;
+
+    async fn watch_events(
+        &self,
+        _request: Request<WatchEventsRequest>,
+    ) -> Result<Response<Self::WatchEventsStream>, Status> {
+        let stream = futures::stream::unfold(events::subscribe(), |mut receiver| async move {
+            loop {
+                match receiver.recv().await {
+                    Ok(event) => return Some((Ok(to_message(event)), receiver)),
+                    Err(RecvError::Lagged(skipped)) => {
+                        debug!("Event watcher fell behind by {} events", skipped)
+                    }
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        });
+
+        Ok(Response::new(Box::pin(stream)))
+    }
+}
+
+/// Message of the event sent to the watchers.
+pub fn to_message(event: TimedEvent) -> EventMessage {
+    let kind = match event.event {
+        Event::ConnectionOpened {
+            id,
+            source,
+            context,
+        } => Kind::ConnectionOpened(ConnectionOpened {
+            id,
+            source: source.to_string(),
+            destination: context.destination.unwrap_or_default(),
+            inbound: context.inbound.unwrap_or_default(),
+            outbound: context.outbound.unwrap_or_default(),
+            user: context.user.unwrap_or_default(),
+        }),
+        Event::ConnectionClosed { id, duration } => Kind::ConnectionClosed(ConnectionClosed {
+            id,
+            duration_millis: duration.as_millis() as u64,
+        }),
+        Event::OutboundSwitched { group, from, to } => {
+            Kind::OutboundSwitched(OutboundSwitched { group, from, to })
+        }
+        Event::Error {
+            connection,
+            message,
+        } => Kind::Error(ErrorEvent {
+            connection,
+            message,
+        }),
+        Event::TrafficRate { upload, download } => Kind::TrafficRate(TrafficRate {
+            upload_bytes_per_second: upload,
+            download_bytes_per_second: download,
+        }),
+    };
+
+    EventMessage {
+        timestamp: event
+            .time
+            .duration_since(UNIX_EPOCH)
+            .map(|time| time.as_millis() as u64)
+            .unwrap_or(0),
+        kind: Some(kind),
+    }
+}
@@ -0,0 +1,174 @@
+use crate::config::base::{HealthConfig, OutboundConfig, OutboundMode};
+#[cfg(feature = "server")]
+use crate::config::certificate;
+use crate::proxy::socket::SocketOptions;
+
+use hyper::server::conn::Http;
+use hyper::service::service_fn;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use log::{info, warn};
+use serde_json::json;
+use std::convert::Infallible;
+use std::io::Result;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, SystemTime};
+use tokio::net::TcpListener;
+
+/// Default interval between two probes of the outbound in seconds
+const DEFAULT_PROBE_INTERVAL: u64 = 10;
+
+/// Time a probe of the outbound is given to connect
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// Whether the inbound listens for the connections of the clients
+static LISTENING: AtomicBool = AtomicBool::new(false);
+
+/// Whether the last probe of the outbound connected, true as long as it isn't probed
+static OUTBOUND_REACHABLE: AtomicBool = AtomicBool::new(true);
+
+/// Checks the readiness of the proxy is made of.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HealthStatus {
+    pub listening: bool,
+    pub outbound_reachable: bool,
+    /// Days left before the certificate of the inbound expires, None unless the inbound has one that was checked
+    pub certificate_days: Option<u64>,
+    pub certificate_expired: bool,
+}
+
+impl HealthStatus {
+    /// Current state of the checks.
+    pub fn current() -> Self {
+        #[cfg(feature = "server")]
+        let not_after = certificate::not_after();
+        // Only the server builds serve a certificate
+        #[cfg(not(feature = "server"))]
+        let not_after: Option<SystemTime> = None;
+        let remaining =
+            not_after.map(|time| time.duration_since(SystemTime::now()).unwrap_or_default());
+        Self {
+            listening: LISTENING.load(Ordering::Relaxed),
+            outbound_reachable: OUTBOUND_REACHABLE.load(Ordering::Relaxed),
+            certificate_days: remaining.map(|remaining| remaining.as_secs() / SECONDS_PER_DAY),
+            certificate_expired: remaining.is_some_and(|remaining| remaining.is_zero()),
+        }
+    }
+
+    /// Whether the proxy can take connections, so that load balancers send it clients.
+    pub fn ready(&self) -> bool {
+        self.listening && self.outbound_reachable && !self.certificate_expired
+    }
+}
+
+/// Record that the inbound listens for the connections of the clients.
+pub fn mark_listening() {
+    LISTENING.store(true, Ordering::Relaxed);
+}
+
+/// Address the outbound is probed at, the probe configured or else the remote server of the outbounds connecting to
+/// one over TCP. None if the outbound isn't probed.
+fn probe_address(config: &HealthConfig, outbound: &OutboundConfig) -> Option<String> {
+    if let Some(probe) = &config.probe {
+        return Some(probe.clone());
+    }
+    match (&outbound.mode, &outbound.address, outbound.port) {
+        (
+            OutboundMode::TCP | OutboundMode::GRPC | OutboundMode::RACE,
+            Some(address),
+            Some(port),
+        ) => Some(format!("{}:{}", address, port)),
+        _ => None,
+    }
+}
+
+/// Connect to the address with the socket options every interval for as long as the process runs, recording whether
+/// the outbound is reachable.
+async fn run_probes(address: String, interval: Duration, socket: SocketOptions) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+
+        let reachable =
+            match tokio::time::timeout(PROBE_TIMEOUT, socket.connect(address.as_str())).await {
+                Ok(Ok(_)) => true,
+                Ok(Err(e)) => {
+                    warn!("Health probe failed to connect to {}: {}", address, e);
+                    false
+                }
+                Err(_) => {
+                    warn!("Health probe timed out connecting to {}", address);
+                    false
+                }
+            };
+        OUTBOUND_REACHABLE.store(reachable, Ordering::Relaxed);
+    }
+}
+
+/// Answer the probe, /livez as long as the process runs and /readyz with 503 unless the proxy is ready, both with the
+/// state of the checks as a JSON object.
+pub fn respond(request: &Request<Body>) -> Response<Body> {
+    let status = HealthStatus::current();
+    let code = match (request.method(), request.uri().path()) {
+        (&Method::GET, "/livez") => StatusCode::OK,
+        (&Method::GET, "/readyz") if status.ready() => StatusCode::OK,
+        (&Method::GET, "/readyz") => StatusCode::SERVICE_UNAVAILABLE,
+        _ => {
+            return Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(Body::empty())
+                .unwrap()
+        }
+    };
+
+    let body = json!({
+        "ready": status.ready(),
+        "listening": status.listening,
+        "outbound_reachable": status.outbound_reachable,
+        "certificate_days": status.certificate_days,
+        "certificate_expired": status.certificate_expired,
+    });
+    Response::builder()
+        .status(code)
+        .header("content-type", "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+/// Start probing the outbound and serving the health endpoint over HTTP/1. Like the admin API it is not authenticated,
+/// though it doesn't reveal more than whether the proxy works.
+pub async fn start(config: &'static HealthConfig, outbound: &'static OutboundConfig) -> Result<()> {
+    if let Some(address) = probe_address(config, outbound) {
+        let interval = config
+            .probe_interval
+            .unwrap_or(DEFAULT_PROBE_INTERVAL)
+            .max(1);
+        // Without fast open, which would let the connection complete before the server answered
+        let socket = SocketOptions::new(outbound.socket.as_ref())
+            .unwrap_or_default()
+            .without_fast_open();
+        tokio::spawn(run_probes(address, Duration::from_secs(interval), socket));
+    }
+
+    let listener = TcpListener::bind((config.address.as_str(), config.port)).await?;
+    info!(
+        "Serving the health endpoint on {}:{}",
+        config.address, config.port
+    );
+
+    loop {
+        let (stream, _) = listener.accept().await?;
+        tokio::spawn(async move {
+            let service =
+                service_fn(|request| async move { Ok::<_, Infallible>(respond(&request)) });
+            if let Err(e) = Http::new()
+                .http1_only(true)
+                .serve_connection(stream, service)
+                .await
+            {
+                warn!("Failed to serve the health endpoint: {}", e);
+            }
+        });
+    }
+}
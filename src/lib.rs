@@ -1,4 +1,17 @@
+#[cfg(feature = "server")]
+pub mod admin;
+pub mod auth;
+pub mod build_info;
 pub mod config;
+pub mod dns;
+pub mod events;
+pub mod health;
+pub mod logging;
+pub mod metrics;
+pub mod profiling;
 pub mod protocol;
 pub mod proxy;
+pub mod router;
+#[cfg(feature = "otel")]
+pub mod telemetry;
 pub mod transport;
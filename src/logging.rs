@@ -0,0 +1,49 @@
+use crate::config::base::{LogConfig, LogFormat};
+use crate::proxy::context::TrafficContext;
+
+use log::Record;
+use serde_json::{Map, Value};
+use std::io::Write;
+use std::time::SystemTime;
+
+/// Set up the logger of the process from the configuration, at the levels set by RUST_LOG.
+pub fn init(config: Option<&LogConfig>) {
+    let mut builder = env_logger::Builder::from_default_env();
+    if config.and_then(|c| c.format) == Some(LogFormat::JSON) {
+        builder.format(|buf, record| writeln!(buf, "{}", format_json(record)));
+    }
+    builder.init();
+}
+
+/// Record as a single line JSON object, with the values of the context of the current task, if any, as fields. Like
+/// {"connection":12,"destination":"example.com:443","inbound":"main","level":"INFO","message":"...",
+/// "target":"trojan_rust::proxy::tcp::server","timestamp":"2022-08-01T12:00:00.000Z","user":"alice"}
+pub fn format_json(record: &Record) -> String {
+    let mut fields = Map::new();
+    fields.insert(
+        "timestamp".to_string(),
+        humantime::format_rfc3339_millis(SystemTime::now())
+            .to_string()
+            .into(),
+    );
+    fields.insert("level".to_string(), record.level().as_str().into());
+    fields.insert("target".to_string(), record.target().into());
+    fields.insert("message".to_string(), record.args().to_string().into());
+
+    if let Some(context) = TrafficContext::current() {
+        let values = [
+            ("connection", context.connection.map(Value::from)),
+            ("inbound", context.inbound.map(Value::from)),
+            ("outbound", context.outbound.map(Value::from)),
+            ("user", context.user.map(Value::from)),
+            ("destination", context.destination.map(Value::from)),
+        ];
+        for (key, value) in values {
+            if let Some(value) = value {
+                fields.insert(key.to_string(), value);
+            }
+        }
+    }
+
+    Value::Object(fields).to_string()
+}
@@ -1,17 +1,53 @@
 use clap::Arg;
 use clap::{ArgMatches, Command};
 use lazy_static::lazy_static;
-use log::info;
+use log::{info, warn};
 use std::io::Result;
-use trojan_rust::config::base::{InboundConfig, InboundMode, OutboundConfig};
+#[cfg(feature = "server")]
+use trojan_rust::admin;
+use trojan_rust::auth::port::PortAuthorizer;
+#[cfg(feature = "server")]
+use trojan_rust::auth::secret::StaticAuthenticator;
+use trojan_rust::build_info;
+use trojan_rust::config::base::{Config, InboundMode};
+#[cfg(feature = "server")]
+use trojan_rust::config::certificate;
 use trojan_rust::config::parser::read_config;
+use trojan_rust::config::runtime::build_runtime;
+use trojan_rust::config::transports::Transports;
+#[cfg(feature = "client")]
+use trojan_rust::dns::fake::{self, FakeDns};
+#[cfg(feature = "client")]
+use trojan_rust::dns::hijack::{self, DnsHijack};
+use trojan_rust::dns::Resolver;
+use trojan_rust::events;
+use trojan_rust::health;
+use trojan_rust::logging;
+use trojan_rust::metrics;
+use trojan_rust::metrics::access::AccessLog;
+#[cfg(feature = "sqlite")]
+use trojan_rust::metrics::usage::TrafficStats;
+use trojan_rust::proxy::buffer::BufferPool;
+use trojan_rust::proxy::destination::DestinationFilter;
+use trojan_rust::proxy::filter;
 use trojan_rust::proxy::grpc;
+use trojan_rust::proxy::policy::Policies;
+#[cfg(feature = "server")]
 use trojan_rust::proxy::quic;
+use trojan_rust::proxy::reaper::Reaper;
 use trojan_rust::proxy::tcp;
+use trojan_rust::proxy::throttle::{GlobalBandwidth, UserBandwidth};
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+use trojan_rust::proxy::uring::UringWorkers;
+use trojan_rust::router::Router;
+#[cfg(feature = "otel")]
+use trojan_rust::telemetry;
 
 lazy_static! {
+    static ref LONG_VERSION: String = build_info::long_version();
     static ref ARGS: ArgMatches = Command::new("Trojan Rust")
-        .version("0.7.1")
+        .version(build_info::VERSION)
+        .long_version(LONG_VERSION.as_str())
         .author("cty123")
         .about("Trojan Rust is a rust implementation of the trojan protocol to circumvent GFW")
         .arg(
@@ -25,36 +61,157 @@ lazy_static! {
         .get_matches();
     static ref CONFIG_PATH: &'static str =
         ARGS.value_of("config").unwrap_or("./config/config.json");
-    static ref CONFIG: (InboundConfig, OutboundConfig) = {
-        let config = read_config(&CONFIG_PATH).expect("Error parsing the config file");
-        (config.inbound, config.outbound)
-    };
+    static ref CONFIG: Config = read_config(&CONFIG_PATH).expect("Error parsing the config file");
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    env_logger::init();
+fn main() -> Result<()> {
+    logging::init(CONFIG.log.as_ref());
 
     info!(
-        "Reading trojan configuration file from {}",
-        CONFIG_PATH.to_string()
+        "Trojan Rust {} (commit {}, built {})",
+        build_info::VERSION,
+        build_info::GIT_COMMIT,
+        build_info::BUILD_DATE
     );
 
+    info!("Reading trojan configuration file from {}", *CONFIG_PATH);
+
+    // The runtime is sized by the configuration, so it is only built once the configuration is read
+    build_runtime(CONFIG.runtime.as_ref())?.block_on(run())
+}
+
+async fn run() -> Result<()> {
     info!(
         "Starting {:?} server to accept inbound traffic",
-        CONFIG.0.mode
+        CONFIG.inbound.mode
     );
 
+    metrics::export::start(CONFIG.metrics.as_ref());
+    #[cfg(feature = "otel")]
+    if let Some(tracing_config) = &CONFIG.tracing {
+        telemetry::start(tracing_config)?;
+    }
+    AccessLog::init(CONFIG.metrics.as_ref())?;
+    Resolver::init(CONFIG.dns.as_ref())?;
+    Policies::init(&CONFIG)?;
+    BufferPool::init(CONFIG.relay_buffer_size)?;
+    if let Some(bandwidth_config) = &CONFIG.bandwidth {
+        GlobalBandwidth::init(bandwidth_config)?;
+    }
+    UserBandwidth::init(&CONFIG.inbound)?;
+    DestinationFilter::init(&CONFIG.inbound)?;
+    Transports::init(CONFIG.transports.as_ref());
+    PortAuthorizer::init(&CONFIG.inbound)?;
+
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    if let Some(io_uring_config) = &CONFIG.io_uring {
+        UringWorkers::init(io_uring_config);
+    }
+    #[cfg(not(all(target_os = "linux", feature = "io-uring")))]
+    if CONFIG.io_uring.is_some() {
+        warn!("io_uring is configured, but this build doesn't support it");
+    }
+
+    #[cfg(feature = "sqlite")]
+    if let Some(traffic_stats_config) = &CONFIG.traffic_stats {
+        tokio::spawn(TrafficStats::init(traffic_stats_config).await?.run());
+    }
+    #[cfg(not(feature = "sqlite"))]
+    if CONFIG.traffic_stats.is_some() {
+        warn!("traffic_stats is configured, but this build doesn't support it");
+    }
+
+    tokio::spawn(Reaper::init(CONFIG.reaper.as_ref())?.run());
+    tokio::spawn(filter::run_reloads());
+
+    #[cfg(feature = "server")]
+    if let Some(tls_config) = &CONFIG.inbound.tls {
+        certificate::start_checks(tls_config);
+    }
+
+    #[cfg(feature = "client")]
+    if let Some(fake_dns_config) = &CONFIG.fake_dns {
+        // Ready before the inbound accepts the connections to the fake addresses
+        FakeDns::init(fake_dns_config)?;
+        tokio::spawn(async move {
+            if let Err(e) = fake::start(fake_dns_config).await {
+                warn!("FakeDNS server stopped: {}", e);
+            }
+        });
+    }
+
+    // All the inbounds route the requests through the router
+    #[cfg(feature = "server")]
+    let router = Some(Router::init(&CONFIG)?);
+
+    #[cfg(feature = "client")]
+    if let Some(dns_inbound_config) = &CONFIG.dns_inbound {
+        // Queries sent to the upstream server are routed like the other requests
+        let router = dns_inbound_config
+            .upstream
+            .as_ref()
+            .map(|_| Router::init(&CONFIG))
+            .transpose()?;
+        // Ready before the inbound intercepts the queries of its clients
+        DnsHijack::init(dns_inbound_config, FakeDns::get(), router)?;
+        tokio::spawn(async move {
+            if let Err(e) = hijack::start(dns_inbound_config, FakeDns::get(), router).await {
+                warn!("DNS inbound stopped: {}", e);
+            }
+        });
+    }
+
+    #[cfg(feature = "server")]
+    if let Some(admin_config) = &CONFIG.admin {
+        let users = StaticAuthenticator::init(&CONFIG.inbound)?;
+        admin::server::ProxyPortAdmin::init(admin_config, users, router);
+        tokio::spawn(async move {
+            if let Err(e) = admin::server::start(admin_config, users, router).await {
+                warn!("Admin API stopped: {}", e);
+            }
+        });
+    }
+
+    if let Some(health_config) = &CONFIG.health {
+        tokio::spawn(async move {
+            if let Err(e) = health::start(health_config, &CONFIG.outbound).await {
+                warn!("Health endpoint stopped: {}", e);
+            }
+        });
+    }
+
+    if let Some(events_config) = &CONFIG.events {
+        tokio::spawn(async move {
+            if let Err(e) = events::server::start(events_config).await {
+                warn!("Event stream stopped: {}", e);
+            }
+        });
+    }
+
+    let router = Router::init(&CONFIG)?;
+    router.start_health_checks();
+    router.start_connection_pools();
+    router.start_database_updates();
+
     // TODO: Support more types of server, like UDP
-    match CONFIG.0.mode {
+    match CONFIG.inbound.mode {
         InboundMode::TCP => {
-            tcp::server::start(&CONFIG.0, &CONFIG.1).await?;
+            tcp::server::start(&CONFIG.inbound, router).await?;
         }
         InboundMode::GRPC => {
-            grpc::server::start(&CONFIG.0, &CONFIG.1).await?;
+            grpc::server::start(&CONFIG.inbound, &CONFIG.outbound, router).await?;
+        }
+        #[cfg(feature = "server")]
+        InboundMode::QUIC => {
+            quic::server::start(&CONFIG.inbound, &CONFIG.outbound, router).await?;
         }
+        // Rejected by the parser
+        #[cfg(not(feature = "server"))]
         InboundMode::QUIC => {
-            quic::server::start(&CONFIG.0, &CONFIG.1).await?;
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "QUIC inbound requires the server feature",
+            ))
         }
     }
 
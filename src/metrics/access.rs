@@ -0,0 +1,227 @@
+use crate::config::base::MetricsConfig;
+use crate::metrics;
+use crate::metrics::rotation::RotatingFile;
+use crate::protocol::common::addr::{IpAddrPort, IpAddress};
+
+use log::{info, warn};
+use once_cell::sync::OnceCell;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashSet;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::io::{Error, ErrorKind, Result};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+/// Default number of destination hosts counted individually
+const DEFAULT_MAX_DESTINATIONS: usize = 1024;
+
+/// Default size the access log file is rotated at
+const DEFAULT_MAX_SIZE: u64 = 100 * 1024 * 1024;
+
+/// Default number of rotated access log files kept
+const DEFAULT_MAX_FILES: usize = 7;
+
+/// Number of buckets the destination hosts beyond the limit are counted in
+const OVERFLOW_BUCKETS: u64 = 16;
+
+/// Lines waiting to be written to the access log file, the ones logged beyond are dropped
+const QUEUE_SIZE: usize = 4096;
+
+/// Static lifetime access log shared by the inbounds
+static ACCESS_LOG: OnceCell<AccessLog> = OnceCell::new();
+
+/// Sampling of the access log and the per destination request counters. Busy servers see a lot of connections to a
+/// lot of hosts, so only a fraction of the connections is logged, and the hosts beyond a limit share the labels of a
+/// few buckets so that the number of metrics stays bounded. The lines of the requests go to a rotated file of their
+/// own if one is configured, and to the application log otherwise. The file is written, rotated and compressed by a
+/// thread of its own, so that the connections never wait for the disk.
+pub struct AccessLog {
+    enabled: bool,
+    sample_rate: f64,
+    max_destinations: usize,
+    connections: AtomicU64,
+    destinations: Mutex<HashSet<String>>,
+    file: Option<SyncSender<Message>>,
+}
+
+/// Request to the thread writing the access log file.
+enum Message {
+    Line(String),
+    /// Acknowledged once the lines queued before are written
+    Flush(mpsc::Sender<()>),
+}
+
+impl AccessLog {
+    /// Build the access log shared by the whole process from the metrics configuration, fails if the sample rate
+    /// isn't between 0 and 1 or if the access log file fails to open.
+    pub fn init(config: Option<&MetricsConfig>) -> Result<&'static Self> {
+        ACCESS_LOG.get_or_try_init(|| Self::new(config))
+    }
+
+    /// Access log shared by the whole process, logging every connection to the application log if it wasn't
+    /// initialized yet.
+    pub fn get() -> &'static Self {
+        ACCESS_LOG.get_or_init(|| Self::with_file(None, 1.0, true, None))
+    }
+
+    /// Fails if the sample rate isn't between 0 and 1 or if the access log file fails to open.
+    pub fn new(config: Option<&MetricsConfig>) -> Result<Self> {
+        let sample_rate = config.and_then(|c| c.access_log_sample_rate).unwrap_or(1.0);
+        if !(0.0..=1.0).contains(&sample_rate) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("Invalid access log sample rate {}", sample_rate),
+            ));
+        }
+
+        let access_log = config.and_then(|c| c.access_log.as_ref());
+        let enabled = access_log.and_then(|c| c.enabled).unwrap_or(true);
+        let file = match access_log.filter(|_| enabled) {
+            Some(c) => match &c.path {
+                Some(path) => {
+                    let file = RotatingFile::open(
+                        path,
+                        Some(c.max_size.unwrap_or(DEFAULT_MAX_SIZE)),
+                        c.rotate_interval.map(Duration::from_secs),
+                        c.max_files.unwrap_or(DEFAULT_MAX_FILES),
+                        c.compress.unwrap_or(false),
+                    )
+                    .map_err(|e| {
+                        Error::new(
+                            e.kind(),
+                            format!("Failed to open the access log {}: {}", path, e),
+                        )
+                    })?;
+                    Some(spawn_writer(file)?)
+                }
+                None => None,
+            },
+            None => None,
+        };
+
+        Ok(Self::with_file(config, sample_rate, enabled, file))
+    }
+
+    fn with_file(
+        config: Option<&MetricsConfig>,
+        sample_rate: f64,
+        enabled: bool,
+        file: Option<SyncSender<Message>>,
+    ) -> Self {
+        Self {
+            enabled,
+            sample_rate,
+            max_destinations: config
+                .and_then(|c| c.max_destinations)
+                .unwrap_or(DEFAULT_MAX_DESTINATIONS),
+            connections: AtomicU64::new(0),
+            destinations: Mutex::new(HashSet::new()),
+            file,
+        }
+    }
+
+    /// Whether the next connection is written to the access log. Connections are picked evenly rather than at
+    /// random, so that exactly the sample rate of them is logged. None of them is with the access log disabled.
+    pub fn sample(&self) -> bool {
+        if !self.enabled {
+            return false;
+        }
+        let count = self.connections.fetch_add(1, Ordering::Relaxed);
+        (count as f64 * self.sample_rate).floor() < ((count + 1) as f64 * self.sample_rate).floor()
+    }
+
+    /// Whether the lines of the requests go to a file of their own rather than to the application log.
+    #[inline]
+    pub fn separate(&self) -> bool {
+        self.file.is_some()
+    }
+
+    /// Queue the line of a request for the access log file along with the time, or log it at info level without one.
+    /// Lines beyond the room of the queue are dropped and counted in access_log_dropped_total.
+    pub fn log(&self, line: fmt::Arguments) {
+        if !self.enabled {
+            return;
+        }
+
+        match &self.file {
+            Some(file) => {
+                let line = format!(
+                    "{} {}",
+                    humantime::format_rfc3339_millis(SystemTime::now()),
+                    line
+                );
+                match file.try_send(Message::Line(line)) {
+                    Ok(()) => (),
+                    Err(TrySendError::Full(_)) => metrics::increment("access_log_dropped_total", 1),
+                    Err(TrySendError::Disconnected(_)) => {
+                        metrics::increment("access_log_errors_total", 1)
+                    }
+                }
+            }
+            None => info!("{}", line),
+        }
+    }
+
+    /// Wait until the lines logged so far are written to the access log file.
+    pub fn flush(&self) {
+        if let Some(file) = &self.file {
+            let (done, written) = mpsc::channel();
+            if file.send(Message::Flush(done)).is_ok() {
+                let _ = written.recv();
+            }
+        }
+    }
+
+    /// Count a request to the destination in requests_total, labeled by the host of the destination.
+    pub fn record(&self, destination: &IpAddrPort) {
+        let label = self.destination_label(&destination.ip);
+        metrics::increment(&format!("requests_total{{destination=\"{}\"}}", label), 1);
+    }
+
+    /// Label of the destination host in the metrics, the host itself while there is room for it or the bucket it
+    /// is hashed to after that.
+    pub fn destination_label(&self, host: &IpAddress) -> String {
+        // Quotes and backslashes of the domain names would break the label
+        let host = host.to_string().replace(['"', '\\'], "_");
+
+        let mut destinations = self.destinations.lock().unwrap();
+        if destinations.contains(&host) {
+            return host;
+        }
+        if destinations.len() < self.max_destinations {
+            destinations.insert(host.clone());
+            return host;
+        }
+
+        let mut hasher = DefaultHasher::new();
+        host.hash(&mut hasher);
+        format!("<other-{}>", hasher.finish() % OVERFLOW_BUCKETS)
+    }
+}
+
+/// Start the thread writing the lines queued to the sender to the file, until the sender is dropped.
+fn spawn_writer(mut file: RotatingFile) -> Result<SyncSender<Message>> {
+    let (sender, receiver) = mpsc::sync_channel(QUEUE_SIZE);
+    thread::Builder::new()
+        .name("access-log".to_string())
+        .spawn(move || {
+            for message in receiver {
+                match message {
+                    Message::Line(line) => {
+                        if let Err(e) = file.write_line(&line) {
+                            warn!("Failed to write to the access log: {}", e);
+                            metrics::increment("access_log_errors_total", 1);
+                        }
+                    }
+                    Message::Flush(done) => {
+                        let _ = done.send(());
+                    }
+                }
+            }
+        })?;
+    Ok(sender)
+}
@@ -0,0 +1,83 @@
+use log::warn;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Upper bound of distinct domain names tracked individually. Lookups for domains beyond this limit are aggregated
+/// under OVERFLOW_DOMAIN so that a client requesting random subdomains can't grow the table without bound.
+const MAX_TRACKED_DOMAINS: usize = 1024;
+
+/// Bucket name used for the lookups that don't fit into the table anymore
+const OVERFLOW_DOMAIN: &str = "<other>";
+
+/// Lookups taking longer than this are reported in the log as they are likely to be noticed by the user
+const SLOW_LOOKUP_THRESHOLD: Duration = Duration::from_millis(500);
+
+/// Global table of per domain resolution statistics
+static DNS_STATS: Lazy<Mutex<HashMap<String, DomainStats>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Aggregated resolution statistics of a single domain name, recorded when the server resolves a proxy request
+/// with domain name address type.
+#[derive(Clone, Default, Serialize)]
+pub struct DomainStats {
+    pub lookups: u64,
+    pub failures: u64,
+    pub total_latency_ms: u64,
+    pub max_latency_ms: u64,
+}
+
+impl DomainStats {
+    /// Average resolution latency of the domain in milliseconds, failed lookups included
+    #[inline]
+    pub fn avg_latency_ms(&self) -> u64 {
+        if self.lookups == 0 {
+            return 0;
+        }
+
+        self.total_latency_ms / self.lookups
+    }
+}
+
+/// Record the outcome of a single lookup of the domain name.
+pub fn record(domain: &str, latency: Duration, success: bool) {
+    let latency_ms = latency.as_millis() as u64;
+
+    if !success {
+        warn!("Failed to resolve {} after {}ms", domain, latency_ms);
+    } else if latency >= SLOW_LOOKUP_THRESHOLD {
+        warn!("Slow DNS resolution for {}, took {}ms", domain, latency_ms);
+    }
+
+    let mut table = DNS_STATS.lock().unwrap();
+
+    let key = if table.len() >= MAX_TRACKED_DOMAINS && !table.contains_key(domain) {
+        OVERFLOW_DOMAIN
+    } else {
+        domain
+    };
+
+    let stats = table.entry(key.to_string()).or_default();
+    stats.lookups += 1;
+    stats.total_latency_ms += latency_ms;
+    stats.max_latency_ms = stats.max_latency_ms.max(latency_ms);
+    if !success {
+        stats.failures += 1;
+    }
+}
+
+/// Take a copy of the current statistics, sorted by average latency with the slowest domain first.
+pub fn snapshot() -> Vec<(String, DomainStats)> {
+    let mut stats: Vec<(String, DomainStats)> = DNS_STATS
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(domain, stats)| (domain.clone(), stats.clone()))
+        .collect();
+
+    stats.sort_by_key(|(_, stats)| Reverse(stats.avg_latency_ms()));
+    stats
+}
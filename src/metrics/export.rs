@@ -0,0 +1,85 @@
+use crate::config::base::MetricsConfig;
+use crate::metrics::{self, dns};
+
+use log::{info, warn};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::io::{Error, ErrorKind, Result};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::fs;
+
+/// Default interval between two snapshots in seconds
+const DEFAULT_SNAPSHOT_INTERVAL: u64 = 60;
+
+/// Content of the exported snapshot file
+#[derive(Serialize)]
+struct Snapshot {
+    timestamp: u64,
+    metrics: BTreeMap<String, u64>,
+    dns: BTreeMap<String, dns::DomainStats>,
+}
+
+/// Start the background task exporting the snapshots if snapshot_path is configured.
+pub fn start(config: Option<&MetricsConfig>) {
+    let config = match config {
+        Some(config) => config,
+        None => return,
+    };
+
+    let path = match &config.snapshot_path {
+        Some(path) => PathBuf::from(path),
+        None => return,
+    };
+
+    let interval = Duration::from_secs(
+        config
+            .snapshot_interval
+            .unwrap_or(DEFAULT_SNAPSHOT_INTERVAL)
+            .max(1),
+    );
+
+    info!(
+        "Exporting metrics snapshot to {} every {}s",
+        path.display(),
+        interval.as_secs()
+    );
+
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            if let Err(e) = write_snapshot(&path).await {
+                warn!(
+                    "Failed to export metrics snapshot to {}: {}",
+                    path.display(),
+                    e
+                );
+            }
+        }
+    });
+}
+
+/// Write the current metrics to the file at path. The snapshot is written to a temporary file next to it first and
+/// then renamed over the destination, so that readers always see a complete file.
+pub async fn write_snapshot(path: &Path) -> Result<()> {
+    let snapshot = Snapshot {
+        timestamp: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0),
+        metrics: metrics::snapshot(),
+        dns: dns::snapshot().into_iter().collect(),
+    };
+
+    let data = match serde_json::to_vec_pretty(&snapshot) {
+        Ok(data) => data,
+        Err(e) => return Err(Error::new(ErrorKind::InvalidData, e)),
+    };
+
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+
+    fs::write(&tmp_path, data).await?;
+    fs::rename(&tmp_path, path).await
+}
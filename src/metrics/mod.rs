@@ -0,0 +1,78 @@
+pub mod access;
+pub mod dns;
+pub mod export;
+pub mod rotation;
+#[cfg(feature = "sqlite")]
+pub mod usage;
+
+use crate::proxy::context::TrafficContext;
+
+use once_cell::sync::Lazy;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::RwLock;
+
+/// Global registry of named metric values. Names follow the Prometheus convention and may carry labels, for
+/// example `write_stalls_total{side="client"}`.
+static REGISTRY: Lazy<RwLock<HashMap<String, AtomicU64>>> =
+    Lazy::new(|| RwLock::new(HashMap::new()));
+
+/// Add value to the counter with the name, the counter is created on first use.
+pub fn increment(name: &str, value: u64) {
+    if let Some(counter) = REGISTRY.read().unwrap().get(name) {
+        counter.fetch_add(value, Ordering::Relaxed);
+        return;
+    }
+
+    REGISTRY
+        .write()
+        .unwrap()
+        .entry(name.to_string())
+        .or_insert_with(|| AtomicU64::new(0))
+        .fetch_add(value, Ordering::Relaxed);
+}
+
+/// Add value to the counter with the name, labelled with the inbound, outbound and user of the connection handled by
+/// the current task, if any. Only the counters of the connections are attributed, like their number, bytes and
+/// timeouts, while the ones of the packets, the datagrams and the process aren't, so that the labels don't multiply
+/// the series of the busiest counters by the number of users.
+pub fn increment_attributed(name: &str, value: u64) {
+    match TrafficContext::current() {
+        Some(context) => increment(&context.label(name), value),
+        None => increment(name, value),
+    }
+}
+
+/// Value of a label escaped for the Prometheus exposition format, where backslashes, double quotes and line feeds
+/// have to be escaped with a backslash.
+pub fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Overwrite the gauge with the name to the value, the gauge is created on first use.
+pub fn set(name: &str, value: u64) {
+    if let Some(gauge) = REGISTRY.read().unwrap().get(name) {
+        gauge.store(value, Ordering::Relaxed);
+        return;
+    }
+
+    REGISTRY
+        .write()
+        .unwrap()
+        .entry(name.to_string())
+        .or_insert_with(|| AtomicU64::new(0))
+        .store(value, Ordering::Relaxed);
+}
+
+/// Take a copy of all the metric values sorted by name.
+pub fn snapshot() -> BTreeMap<String, u64> {
+    REGISTRY
+        .read()
+        .unwrap()
+        .iter()
+        .map(|(name, value)| (name.clone(), value.load(Ordering::Relaxed)))
+        .collect()
+}
@@ -0,0 +1,193 @@
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use log::warn;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Result, Write};
+use std::path::{Path, PathBuf};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime};
+
+/// File appended to line by line, rotated once it grows past max_size bytes or once it has been open for the rotate
+/// interval. The rotated files are renamed after the time they were rotated at, like access.log.20220801T120000Z, and
+/// compressed with gzip in the background if compress is set. Only the newest max_files of them are kept, pruned once
+/// the compression of the rotated file is done. A rotation waits for the compression of the previous one, and closing
+/// the file waits for the last one.
+pub struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    opened: SystemTime,
+    max_size: Option<u64>,
+    interval: Option<Duration>,
+    max_files: usize,
+    compress: bool,
+    compression: Option<JoinHandle<()>>,
+}
+
+impl RotatingFile {
+    /// Open the file at the path for appending, creating it if needed.
+    pub fn open(
+        path: &str,
+        max_size: Option<u64>,
+        interval: Option<Duration>,
+        max_files: usize,
+        compress: bool,
+    ) -> Result<Self> {
+        let path = PathBuf::from(path);
+        let file = append(&path)?;
+        Ok(Self {
+            size: file.metadata()?.len(),
+            path,
+            file,
+            opened: SystemTime::now(),
+            max_size,
+            interval,
+            max_files,
+            compress,
+            compression: None,
+        })
+    }
+
+    /// Append the line, rotating the file first if it is due.
+    pub fn write_line(&mut self, line: &str) -> Result<()> {
+        let size = line.len() as u64 + 1;
+        let full = self
+            .max_size
+            .is_some_and(|max| self.size > 0 && self.size + size > max);
+        let expired = self.interval.is_some_and(|interval| {
+            self.opened
+                .elapsed()
+                .is_ok_and(|elapsed| elapsed >= interval)
+        });
+        if full || expired {
+            self.rotate()?;
+        }
+
+        self.file.write_all(format!("{}\n", line).as_bytes())?;
+        self.size += size;
+        Ok(())
+    }
+
+    /// Rename the file after the current time and start a new one, then compress and prune the rotated files.
+    pub fn rotate(&mut self) -> Result<()> {
+        let stamp = humantime::format_rfc3339_seconds(SystemTime::now())
+            .to_string()
+            .replace(['-', ':'], "");
+        let mut rotated = suffixed(&self.path, &stamp);
+        let mut count = 1;
+        while rotated.exists() || gzipped(&rotated).exists() {
+            rotated = suffixed(&self.path, &format!("{}.{}", stamp, count));
+            count += 1;
+        }
+
+        // Pruning the files while the previous one is compressed would count or delete its partial copy
+        self.finish_compression();
+
+        fs::rename(&self.path, &rotated)?;
+        self.file = append(&self.path)?;
+        self.size = 0;
+        self.opened = SystemTime::now();
+
+        let (path, max_files) = (self.path.clone(), self.max_files);
+        match self.compress {
+            true => {
+                self.compression = Some(thread::spawn(move || {
+                    if let Err(e) = compress(&rotated) {
+                        warn!("Failed to compress {}: {}", rotated.display(), e);
+                    }
+                    prune(&path, max_files);
+                }));
+            }
+            false => prune(&path, max_files),
+        }
+        Ok(())
+    }
+
+    fn finish_compression(&mut self) {
+        if let Some(compression) = self.compression.take() {
+            let _ = compression.join();
+        }
+    }
+}
+
+impl Drop for RotatingFile {
+    fn drop(&mut self) {
+        self.finish_compression();
+    }
+}
+
+fn append(path: &Path) -> Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+fn suffixed(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", suffix));
+    PathBuf::from(name)
+}
+
+fn gzipped(path: &Path) -> PathBuf {
+    suffixed(path, "gz")
+}
+
+/// Replace the file with its gzip compressed copy, the partial copy is deleted if the compression fails.
+fn compress(path: &Path) -> Result<()> {
+    let compressed = gzipped(path);
+    match gzip(path, &compressed) {
+        Ok(()) => fs::remove_file(path),
+        Err(e) => {
+            let _ = fs::remove_file(&compressed);
+            Err(e)
+        }
+    }
+}
+
+fn gzip(source: &Path, target: &Path) -> Result<()> {
+    let mut encoder = GzEncoder::new(File::create(target)?, Compression::default());
+    io::copy(&mut File::open(source)?, &mut encoder)?;
+    encoder.finish()?.sync_all()
+}
+
+/// Delete the rotated files of the path beyond the newest max_files, which sort last by their names without the gzip
+/// extension.
+fn prune(path: &Path, max_files: usize) {
+    let (dir, name) = match (path.parent(), path.file_name()) {
+        (Some(dir), Some(name)) => (dir, format!("{}.", name.to_string_lossy())),
+        _ => return,
+    };
+    let dir = match dir.as_os_str().is_empty() {
+        true => Path::new("."),
+        false => dir,
+    };
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            warn!(
+                "Failed to list the rotated files in {}: {}",
+                dir.display(),
+                e
+            );
+            return;
+        }
+    };
+
+    let mut rotated: Vec<_> = entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.file_name()
+                .is_some_and(|file| file.to_string_lossy().starts_with(&name))
+        })
+        .collect();
+    // Files rotated within the same second are numbered after the first one, which keeps its place once gzipped
+    rotated.sort_by_key(|path| {
+        let name = path.to_string_lossy();
+        name.strip_suffix(".gz").unwrap_or(&name).to_string()
+    });
+    let excess = rotated.len().saturating_sub(max_files);
+    for path in &rotated[..excess] {
+        if let Err(e) = fs::remove_file(path) {
+            warn!("Failed to delete {}: {}", path.display(), e);
+        }
+    }
+}
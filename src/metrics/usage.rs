@@ -0,0 +1,259 @@
+use crate::config::base::TrafficStatsConfig;
+use crate::proxy::connections::active_connections;
+use crate::proxy::context::TrafficContext;
+use crate::proxy::relay::Transfer;
+
+use log::{info, warn};
+use once_cell::sync::OnceCell;
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePool};
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Result};
+use std::mem;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Default interval between two writes of the counters to the database in seconds
+const DEFAULT_FLUSH_INTERVAL: u64 = 60;
+
+/// Length of a day in seconds
+const DAY: u64 = 86400;
+
+/// Static lifetime traffic statistics of the process, only set if configured
+static TRAFFIC_STATS: OnceCell<TrafficStats> = OnceCell::new();
+
+/// Bytes relayed by a user over a day, the day being the UTC date like 2026-10-16.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DailyTraffic {
+    pub user: String,
+    pub day: String,
+    pub uploaded: u64,
+    pub downloaded: u64,
+}
+
+/// Bytes relayed by each user and day, persisted to a SQLite database so that the usage history survives restarts.
+/// The bytes of a connection are counted in memory as it ends, and the ones the open connections relayed so far at the
+/// end of each day, on the day they were relayed. The counts are added to the database every flush interval and at the
+/// end of each day, the ones not written yet are lost if the process is killed.
+pub struct TrafficStats {
+    pool: SqlitePool,
+    interval: Duration,
+    pending: Mutex<HashMap<(String, String), (u64, u64)>>,
+}
+
+impl TrafficStats {
+    /// Open the database shared by the whole process, creating it and its table if needed.
+    pub async fn init(config: &TrafficStatsConfig) -> Result<&'static Self> {
+        let stats = Self::open(config).await?;
+        info!("Recording the traffic of the users to {}", config.path);
+        Ok(TRAFFIC_STATS.get_or_init(|| stats))
+    }
+
+    /// Traffic statistics shared by the whole process, None unless they are configured.
+    pub fn get() -> Option<&'static Self> {
+        TRAFFIC_STATS.get()
+    }
+
+    pub async fn open(config: &TrafficStatsConfig) -> Result<Self> {
+        let options = SqliteConnectOptions::new()
+            .filename(&config.path)
+            .create_if_missing(true);
+        let pool = SqlitePool::connect_with(options)
+            .await
+            .map_err(Error::other)?;
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS traffic (
+                user TEXT NOT NULL,
+                day TEXT NOT NULL,
+                uploaded INTEGER NOT NULL,
+                downloaded INTEGER NOT NULL,
+                PRIMARY KEY (user, day)
+            )",
+        )
+        .execute(&pool)
+        .await
+        .map_err(Error::other)?;
+
+        Ok(Self {
+            pool,
+            interval: Duration::from_secs(
+                config
+                    .flush_interval
+                    .unwrap_or(DEFAULT_FLUSH_INTERVAL)
+                    .max(1),
+            ),
+            pending: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Count the bytes relayed by the user today.
+    pub fn record(&self, user: &str, uploaded: u64, downloaded: u64) {
+        self.record_on(user, &today(), uploaded, downloaded);
+    }
+
+    /// Count the bytes relayed by the user on the day.
+    pub fn record_on(&self, user: &str, day: &str, uploaded: u64, downloaded: u64) {
+        if uploaded == 0 && downloaded == 0 {
+            return;
+        }
+        let mut pending = self.pending.lock().unwrap();
+        let counts = pending
+            .entry((user.to_string(), day.to_string()))
+            .or_insert((0, 0));
+        counts.0 += uploaded;
+        counts.1 += downloaded;
+    }
+
+    /// Count the bytes the connection relayed since they were last counted, on the day.
+    pub fn book(&self, transfer: &Transfer, user: &str, day: &str) {
+        let (uploaded, downloaded) = transfer.unbooked();
+        self.record_on(user, day, uploaded, downloaded);
+    }
+
+    /// Add the bytes counted since the last flush to the database, they are kept for the next flush if it fails.
+    pub async fn flush(&self) -> Result<()> {
+        let pending = mem::take(&mut *self.pending.lock().unwrap());
+        if pending.is_empty() {
+            return Ok(());
+        }
+
+        if let Err(e) = self.write(&pending).await {
+            let mut current = self.pending.lock().unwrap();
+            for (key, (uploaded, downloaded)) in pending {
+                let counts = current.entry(key).or_insert((0, 0));
+                counts.0 += uploaded;
+                counts.1 += downloaded;
+            }
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    async fn write(&self, pending: &HashMap<(String, String), (u64, u64)>) -> Result<()> {
+        let mut transaction = self.pool.begin().await.map_err(Error::other)?;
+        for ((user, day), (uploaded, downloaded)) in pending {
+            sqlx::query(
+                "INSERT INTO traffic (user, day, uploaded, downloaded) VALUES (?, ?, ?, ?)
+                ON CONFLICT (user, day) DO UPDATE SET
+                    uploaded = uploaded + excluded.uploaded,
+                    downloaded = downloaded + excluded.downloaded",
+            )
+            .bind(user)
+            .bind(day)
+            .bind(*uploaded as i64)
+            .bind(*downloaded as i64)
+            .execute(&mut transaction)
+            .await
+            .map_err(Error::other)?;
+        }
+        transaction.commit().await.map_err(Error::other)
+    }
+
+    /// Bytes relayed each day between since and until, both included and unbounded if empty, by the user or by every
+    /// user if empty, sorted by user and day. The bytes counted since the last flush are written first. Fails with
+    /// InvalidInput if since or until isn't a date like 2026-10-16.
+    pub async fn query(&self, user: &str, since: &str, until: &str) -> Result<Vec<DailyTraffic>> {
+        check_day(since)?;
+        check_day(until)?;
+        self.flush().await?;
+
+        let rows: Vec<(String, String, i64, i64)> = sqlx::query_as(
+            "SELECT user, day, uploaded, downloaded FROM traffic
+            WHERE (? = '' OR user = ?) AND (? = '' OR day >= ?) AND (? = '' OR day <= ?)
+            ORDER BY user, day",
+        )
+        .bind(user)
+        .bind(user)
+        .bind(since)
+        .bind(since)
+        .bind(until)
+        .bind(until)
+        .fetch_all(&self.pool)
+        .await
+        .map_err(Error::other)?;
+
+        Ok(rows
+            .into_iter()
+            .map(|(user, day, uploaded, downloaded)| DailyTraffic {
+                user,
+                day,
+                uploaded: uploaded as u64,
+                downloaded: downloaded as u64,
+            })
+            .collect())
+    }
+
+    /// Flush the counts every interval and at the end of each day for as long as the process runs. As a day ends, the
+    /// bytes the open connections relayed so far are counted on it first.
+    pub async fn run(&self) {
+        let mut ticker = tokio::time::interval(self.interval);
+        ticker.tick().await;
+
+        loop {
+            let day = today();
+            tokio::select! {
+                _ = ticker.tick() => (),
+                _ = tokio::time::sleep(until_tomorrow()) => {
+                    for connection in active_connections() {
+                        if let Some(user) = &connection.context.user {
+                            self.book(&connection.transfer, user, &day);
+                        }
+                    }
+                }
+            }
+            if let Err(e) = self.flush().await {
+                warn!("Failed to write the traffic statistics: {}", e);
+            }
+        }
+    }
+}
+
+/// Counts the bytes of the connection for its user once dropped, if the statistics are configured and the inbound has
+/// users, so that the connections dropped before they end are counted too.
+pub(crate) struct Booking {
+    transfer: Arc<Transfer>,
+    user: Option<String>,
+}
+
+impl Booking {
+    /// Booking of the transfer for the user of the current connection.
+    pub(crate) fn new(transfer: &Arc<Transfer>) -> Self {
+        Self {
+            transfer: transfer.clone(),
+            user: TrafficContext::current().and_then(|context| context.user),
+        }
+    }
+}
+
+impl Drop for Booking {
+    fn drop(&mut self) {
+        if let (Some(stats), Some(user)) = (TrafficStats::get(), &self.user) {
+            stats.book(&self.transfer, user, &today());
+        }
+    }
+}
+
+/// Check that the day is empty or a date like 2026-10-16.
+fn check_day(day: &str) -> Result<()> {
+    if day.is_empty()
+        || (day.len() == 10 && humantime::parse_rfc3339(&format!("{}T00:00:00Z", day)).is_ok())
+    {
+        return Ok(());
+    }
+    Err(Error::new(
+        ErrorKind::InvalidInput,
+        format!("invalid day {}, expected a date like 2026-10-16", day),
+    ))
+}
+
+/// Time until the UTC day ends.
+fn until_tomorrow() -> Duration {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    Duration::from_secs(DAY - now.as_secs() % DAY)
+}
+
+/// UTC date of today, like 2026-10-16.
+fn today() -> String {
+    humantime::format_rfc3339_seconds(SystemTime::now()).to_string()[..10].to_string()
+}
@@ -0,0 +1,34 @@
+use crate::profiling::session;
+
+use std::alloc::{GlobalAlloc, Layout, System};
+
+/// Allocator of the process in profiling builds, which counts the allocations of the session being polled on the
+/// current thread before handing them over to the system allocator.
+struct CountingAllocator;
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        session::record_allocation(layout.size());
+        System.alloc(layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        session::record_allocation(layout.size());
+        System.alloc_zeroed(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        // Only the growth is new memory
+        if new_size > layout.size() {
+            session::record_allocation(new_size - layout.size());
+        }
+        System.realloc(ptr, layout, new_size)
+    }
+}
@@ -0,0 +1,85 @@
+#[cfg(feature = "profiling")]
+mod allocator;
+#[cfg(feature = "profiling")]
+mod session;
+
+use crate::proxy::context::TrafficContext;
+
+use std::fmt::Display;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+/// Resources used by a proxy session so far, collected when the binary is built with the profiling feature.
+#[derive(Debug, Clone)]
+pub struct SessionProfile {
+    pub id: u64,
+    pub source: SocketAddr,
+    pub destination: Option<String>,
+    /// Inbound, outbound and user of the session, known once the request is routed
+    pub context: TrafficContext,
+    /// Bytes allocated by the session, memory freed later is still counted
+    pub allocated_bytes: u64,
+    pub allocations: u64,
+    /// Time spent polling the session, which is CPU time of the worker threads unless they are preempted
+    pub cpu_time: Duration,
+    /// Whether the session is still running, or is one of the recently finished ones
+    pub active: bool,
+}
+
+/// Resource the sessions are ranked by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionOrder {
+    CpuTime,
+    AllocatedBytes,
+}
+
+/// Whether the binary is built with the profiling feature.
+#[inline]
+pub const fn enabled() -> bool {
+    cfg!(feature = "profiling")
+}
+
+/// Attribute the allocations and the CPU time of the future to the session from the source. Tasks spawned by the
+/// future are not attributed to the session. Returns the future itself if the profiling feature is disabled.
+#[cfg(feature = "profiling")]
+pub fn profile<F: Future>(source: SocketAddr, future: F) -> session::Profiled<F> {
+    session::Profiled::new(source, future)
+}
+
+#[cfg(not(feature = "profiling"))]
+#[inline]
+pub fn profile<F: Future>(_source: SocketAddr, future: F) -> F {
+    future
+}
+
+/// Record the destination of the session being polled, does nothing outside of a profiled future.
+#[cfg(feature = "profiling")]
+pub fn set_destination<D: Display>(destination: &D) {
+    session::set_destination(destination.to_string());
+}
+
+#[cfg(not(feature = "profiling"))]
+#[inline]
+pub fn set_destination<D: Display>(_destination: &D) {}
+
+/// Record the inbound, outbound and user of the session being polled, does nothing outside of a profiled future.
+#[cfg(feature = "profiling")]
+pub fn set_context(context: &TrafficContext) {
+    session::set_context(context.clone());
+}
+
+#[cfg(not(feature = "profiling"))]
+#[inline]
+pub fn set_context(_context: &TrafficContext) {}
+
+/// Running and recently finished sessions using the most of the resource, at most limit of them.
+#[cfg(feature = "profiling")]
+pub fn top_sessions(order: SessionOrder, limit: usize) -> Vec<SessionProfile> {
+    session::top_sessions(order, limit)
+}
+
+#[cfg(not(feature = "profiling"))]
+pub fn top_sessions(_order: SessionOrder, _limit: usize) -> Vec<SessionProfile> {
+    Vec::new()
+}
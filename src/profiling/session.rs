@@ -0,0 +1,186 @@
+use crate::profiling::{SessionOrder, SessionProfile};
+use crate::proxy::context::TrafficContext;
+
+use once_cell::sync::Lazy;
+use std::cell::Cell;
+use std::cmp::Reverse;
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::ptr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+/// Number of finished sessions kept for the reports
+const FINISHED_SESSIONS: usize = 256;
+
+/// Sessions being profiled by id
+static ACTIVE: Lazy<Mutex<HashMap<u64, Arc<SessionStats>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Most recently finished sessions, the oldest first
+static FINISHED: Lazy<Mutex<VecDeque<SessionProfile>>> =
+    Lazy::new(|| Mutex::new(VecDeque::with_capacity(FINISHED_SESSIONS)));
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+thread_local! {
+    /// Session being polled on the thread, which the allocations are attributed to. The allocator reads it, so it
+    /// must not allocate itself.
+    static CURRENT: Cell<*const SessionStats> = const { Cell::new(ptr::null()) };
+}
+
+struct SessionStats {
+    id: u64,
+    source: SocketAddr,
+    destination: Mutex<Option<String>>,
+    context: Mutex<TrafficContext>,
+    allocated_bytes: AtomicU64,
+    allocations: AtomicU64,
+    cpu_nanos: AtomicU64,
+}
+
+impl SessionStats {
+    fn profile(&self, active: bool) -> SessionProfile {
+        SessionProfile {
+            id: self.id,
+            source: self.source,
+            destination: self.destination.lock().unwrap().clone(),
+            context: self.context.lock().unwrap().clone(),
+            allocated_bytes: self.allocated_bytes.load(Ordering::Relaxed),
+            allocations: self.allocations.load(Ordering::Relaxed),
+            cpu_time: Duration::from_nanos(self.cpu_nanos.load(Ordering::Relaxed)),
+            active,
+        }
+    }
+}
+
+/// Future of a session, which marks the session as the current one of the thread while it is polled.
+pub struct Profiled<F> {
+    future: Pin<Box<F>>,
+    stats: Arc<SessionStats>,
+}
+
+impl<F: Future> Profiled<F> {
+    pub fn new(source: SocketAddr, future: F) -> Self {
+        let stats = Arc::new(SessionStats {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            source,
+            destination: Mutex::new(None),
+            context: Mutex::new(TrafficContext::default()),
+            allocated_bytes: AtomicU64::new(0),
+            allocations: AtomicU64::new(0),
+            cpu_nanos: AtomicU64::new(0),
+        });
+        ACTIVE.lock().unwrap().insert(stats.id, stats.clone());
+
+        Self {
+            future: Box::pin(future),
+            stats,
+        }
+    }
+}
+
+impl<F: Future> Future for Profiled<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let _guard = PollGuard::enter(&this.stats);
+        this.future.as_mut().poll(cx)
+    }
+}
+
+/// Marks the session as the current one of the thread until dropped, which also happens if the poll panics, and
+/// counts the time in between towards the session.
+struct PollGuard<'a> {
+    stats: &'a SessionStats,
+    previous: *const SessionStats,
+    start: Instant,
+}
+
+impl<'a> PollGuard<'a> {
+    fn enter(stats: &'a Arc<SessionStats>) -> Self {
+        Self {
+            stats,
+            previous: CURRENT.with(|current| current.replace(Arc::as_ptr(stats))),
+            start: Instant::now(),
+        }
+    }
+}
+
+impl Drop for PollGuard<'_> {
+    fn drop(&mut self) {
+        self.stats
+            .cpu_nanos
+            .fetch_add(self.start.elapsed().as_nanos() as u64, Ordering::Relaxed);
+        CURRENT.with(|current| current.set(self.previous));
+    }
+}
+
+impl<F> Drop for Profiled<F> {
+    fn drop(&mut self) {
+        ACTIVE.lock().unwrap().remove(&self.stats.id);
+
+        let mut finished = FINISHED.lock().unwrap();
+        if finished.len() == FINISHED_SESSIONS {
+            finished.pop_front();
+        }
+        finished.push_back(self.stats.profile(false));
+    }
+}
+
+/// Count the allocation towards the session being polled on the thread, if there is one.
+#[inline]
+pub fn record_allocation(size: usize) {
+    // The thread local is gone while the thread exits
+    let _ = CURRENT.try_with(|current| {
+        // Safety: the pointer is set only while the future holding the stats is polled
+        if let Some(stats) = unsafe { current.get().as_ref() } {
+            stats
+                .allocated_bytes
+                .fetch_add(size as u64, Ordering::Relaxed);
+            stats.allocations.fetch_add(1, Ordering::Relaxed);
+        }
+    });
+}
+
+pub fn set_destination(destination: String) {
+    CURRENT.with(|current| {
+        // Safety: the pointer is set only while the future holding the stats is polled
+        if let Some(stats) = unsafe { current.get().as_ref() } {
+            *stats.destination.lock().unwrap() = Some(destination);
+        }
+    });
+}
+
+pub fn set_context(context: TrafficContext) {
+    CURRENT.with(|current| {
+        // Safety: the pointer is set only while the future holding the stats is polled
+        if let Some(stats) = unsafe { current.get().as_ref() } {
+            *stats.context.lock().unwrap() = context;
+        }
+    });
+}
+
+pub fn top_sessions(order: SessionOrder, limit: usize) -> Vec<SessionProfile> {
+    let mut sessions: Vec<SessionProfile> = ACTIVE
+        .lock()
+        .unwrap()
+        .values()
+        .map(|stats| stats.profile(true))
+        .collect();
+    sessions.extend(FINISHED.lock().unwrap().iter().cloned());
+
+    match order {
+        SessionOrder::CpuTime => sessions.sort_by_key(|session| Reverse(session.cpu_time)),
+        SessionOrder::AllocatedBytes => {
+            sessions.sort_by_key(|session| Reverse(session.allocated_bytes))
+        }
+    }
+    sessions.truncate(limit);
+    sessions
+}
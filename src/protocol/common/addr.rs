@@ -1,6 +1,13 @@
+use crate::config::base::DomainStrategy;
+use crate::dns::Resolver;
+use crate::metrics;
+use crate::protocol::common::atype::Atype;
+
 use bytes::Bytes;
 use std::fmt::{self};
+use std::io::{Error, ErrorKind, Result};
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs};
+use std::time::Instant;
 
 pub const IPV4_SIZE: usize = 4;
 pub const IPV6_SIZE: usize = 16;
@@ -8,7 +15,7 @@ pub const IPV6_SIZE: usize = 16;
 /// DomainName is a vector of bytes whose length can go up to 255. This is not the most efficient way of
 /// storing DomainName, should consider using stack memory to avoid calling malloc and free repeatedly.
 /// This may be altered after we perform a thourough benchmark to determine the tradeoffs between slice and Vec.
-#[derive(Clone)]
+#[derive(Clone, PartialEq, Eq, Hash)]
 pub struct DomainName {
     inner: Bytes,
 }
@@ -16,7 +23,7 @@ pub struct DomainName {
 /// Wrap around std::net::IpAddr to create a parent enum for all kinds of IpAddresses used in Trojan.
 /// Apart from the basic IPv4 and IPv6 types provided by standard library, DomainName is commonly used
 /// for proxy protocols, so we need to extend that.
-#[derive(Clone)]
+#[derive(Clone, PartialEq, Eq, Hash)]
 pub enum IpAddress {
     IpAddr(IpAddr),
     Domain(DomainName),
@@ -24,6 +31,7 @@ pub enum IpAddress {
 
 /// Wrapper class that contains the destination ip and port of the proxy request.
 /// The struct is capable of converting to SocketAddr class that can be used to establish an outbound connection.
+#[derive(Clone, PartialEq, Eq, Hash)]
 pub struct IpAddrPort {
     pub ip: IpAddress,
     pub port: u16,
@@ -34,6 +42,73 @@ impl IpAddrPort {
     pub fn new(ip: IpAddress, port: u16) -> Self {
         Self { ip, port }
     }
+
+    /// Resolve the destination into SocketAddr without blocking the runtime. Domain names are looked up through the
+    /// shared resolver, and the latency and outcome of each lookup are recorded in the DNS metrics so that slow
+    /// proxy connections caused by DNS can be told apart from the slow upstreams.
+    #[inline]
+    pub async fn resolve(&self) -> Result<SocketAddr> {
+        self.resolve_with(DomainStrategy::AS_IS).await
+    }
+
+    /// Resolve the destination like resolve, to an address of the family picked by the strategy.
+    pub async fn resolve_with(&self, strategy: DomainStrategy) -> Result<SocketAddr> {
+        // Safety: the addresses are never empty
+        Ok(self.resolve_all_with(strategy).await?[0])
+    }
+
+    /// Resolve the destination to all the addresses of the domain name allowed by the strategy, so that a connection
+    /// can move on to the next address when one of them doesn't answer.
+    pub async fn resolve_all_with(&self, strategy: DomainStrategy) -> Result<Vec<SocketAddr>> {
+        let domain = match &self.ip {
+            IpAddress::IpAddr(addr) => return Ok(vec![SocketAddr::new(*addr, self.port)]),
+            IpAddress::Domain(domain) => domain.to_string(),
+        };
+
+        let start = Instant::now();
+        let result = Resolver::get()
+            .lookup_all_with(&domain, strategy)
+            .await
+            .map(|addrs| {
+                addrs
+                    .into_iter()
+                    .map(|addr| SocketAddr::new(addr, self.port))
+                    .collect()
+            });
+
+        metrics::dns::record(&domain, start.elapsed(), result.is_ok());
+
+        result
+    }
+
+    /// Append the address type, the address and the port to the buffer, encoded like SOCKS5 and trojan do. Fails with
+    /// InvalidInput if the domain name is longer than 255 bytes.
+    pub fn write_to(&self, buf: &mut Vec<u8>) -> Result<()> {
+        match &self.ip {
+            IpAddress::IpAddr(IpAddr::V4(ip)) => {
+                buf.push(Atype::IPv4 as u8);
+                buf.extend_from_slice(&ip.octets());
+            }
+            IpAddress::IpAddr(IpAddr::V6(ip)) => {
+                buf.push(Atype::IPv6 as u8);
+                buf.extend_from_slice(&ip.octets());
+            }
+            IpAddress::Domain(domain) => {
+                let size = domain.encoded_len()?;
+                buf.push(Atype::DomainName as u8);
+                buf.push(size);
+                buf.extend_from_slice(domain.as_bytes());
+            }
+        }
+        buf.extend_from_slice(&self.port.to_be_bytes());
+        Ok(())
+    }
+}
+
+impl fmt::Display for IpAddrPort {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(fmt, "{}:{}", self.ip, self.port)
+    }
 }
 
 impl Into<SocketAddr> for IpAddrPort {
@@ -47,13 +122,13 @@ impl Into<SocketAddr> for IpAddrPort {
                     Ok(a) => a,
                     Err(e) => {
                         panic!("Failed to resolve DNS name: {}, {}", name, e);
-                    },
+                    }
                 };
 
                 return match addrs.into_iter().nth(0) {
                     Some(n) => n,
-                    None => panic!("No DNS result for the domain name: {}", name)
-                }
+                    None => panic!("No DNS result for the domain name: {}", name),
+                };
             }
         }
     }
@@ -83,12 +158,31 @@ impl IpAddress {
     pub fn from_bytes(addr: Bytes) -> IpAddress {
         IpAddress::Domain(DomainName { inner: addr })
     }
+
+    /// Address of a host written in the configuration, which is either an IP address or a domain name.
+    pub fn from_host(host: &str) -> IpAddress {
+        match host.parse::<IpAddr>() {
+            Ok(addr) => IpAddress::IpAddr(addr),
+            Err(_) => IpAddress::from_bytes(Bytes::copy_from_slice(host.as_bytes())),
+        }
+    }
 }
 
 impl DomainName {
     pub fn as_bytes(&self) -> &[u8] {
         &self.inner
     }
+
+    /// Length prefixing the domain name in SOCKS5 and trojan, fails with InvalidInput if it doesn't fit in a byte.
+    pub fn encoded_len(&self) -> Result<u8> {
+        match u8::try_from(self.inner.len()) {
+            Ok(size) => Ok(size),
+            Err(_) => Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("domain name of {} bytes is too long", self.inner.len()),
+            )),
+        }
+    }
 }
 
 impl fmt::Display for DomainName {
@@ -1,3 +1,4 @@
+use crate::auth::User;
 use crate::protocol::common::addr::IpAddrPort;
 use crate::protocol::common::atype::Atype;
 use crate::protocol::common::command::Command;
@@ -5,7 +6,7 @@ use crate::{protocol::common::addr::IpAddress, proxy::base::SupportedProtocols};
 
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum TransportProtocol {
     TCP,
     UDP,
@@ -17,6 +18,7 @@ pub struct InboundRequest {
     pub command: Command,
     pub transport_protocol: TransportProtocol,
     pub proxy_protocol: SupportedProtocols,
+    pub user: Option<User>,
 }
 
 impl InboundRequest {
@@ -35,6 +37,7 @@ impl InboundRequest {
             command,
             transport_protocol,
             proxy_protocol,
+            user: None,
         }
     }
 }
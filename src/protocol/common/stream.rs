@@ -1,11 +1,34 @@
+#[cfg(target_os = "linux")]
+use crate::proxy::ktls::Ktls;
+use crate::transport::websocket::WebSocketByteStream;
+
 use std::pin::Pin;
 use std::task::{Context, Poll};
-use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::io::{AsyncRead, AsyncWrite, DuplexStream, ReadBuf};
+use tokio::net::TcpStream;
+
+/// Byte stream whose transport is only known at runtime, such as the connections tunneled through another outbound.
+pub trait AsyncStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> AsyncStream for T {}
+
+pub type BoxedStream = Box<dyn AsyncStream>;
 
 pub enum StandardTcpStream<T> {
     Plain(T),
     RustlsServer(tokio_rustls::server::TlsStream<T>),
     RustlsClient(tokio_rustls::client::TlsStream<T>),
+    WebSocket(Box<WebSocketByteStream<PrefixedStream<StandardTcpStream<T>>>>),
+}
+
+impl<S> StandardTcpStream<S> {
+    /// Protocol the client and the server agreed on with ALPN, for TLS server streams.
+    pub fn alpn_protocol(&self) -> Option<&[u8]> {
+        match self {
+            StandardTcpStream::RustlsServer(stream) => stream.get_ref().1.alpn_protocol(),
+            _ => None,
+        }
+    }
 }
 
 impl<S: AsyncRead + AsyncWrite + Unpin + Send> AsyncRead for StandardTcpStream<S> {
@@ -19,6 +42,7 @@ impl<S: AsyncRead + AsyncWrite + Unpin + Send> AsyncRead for StandardTcpStream<S
             StandardTcpStream::Plain(ref mut s) => Pin::new(s).poll_read(cx, buf),
             StandardTcpStream::RustlsServer(s) => Pin::new(s).poll_read(cx, buf),
             StandardTcpStream::RustlsClient(s) => Pin::new(s).poll_read(cx, buf),
+            StandardTcpStream::WebSocket(s) => Pin::new(s).poll_read(cx, buf),
         }
     }
 }
@@ -34,6 +58,7 @@ impl<S: AsyncRead + AsyncWrite + Unpin + Send> AsyncWrite for StandardTcpStream<
             StandardTcpStream::Plain(ref mut s) => Pin::new(s).poll_write(cx, buf),
             StandardTcpStream::RustlsServer(ref mut s) => Pin::new(s).poll_write(cx, buf),
             StandardTcpStream::RustlsClient(ref mut s) => Pin::new(s).poll_write(cx, buf),
+            StandardTcpStream::WebSocket(ref mut s) => Pin::new(s).poll_write(cx, buf),
         }
     }
 
@@ -43,6 +68,7 @@ impl<S: AsyncRead + AsyncWrite + Unpin + Send> AsyncWrite for StandardTcpStream<
             StandardTcpStream::Plain(ref mut s) => Pin::new(s).poll_flush(cx),
             StandardTcpStream::RustlsServer(ref mut s) => Pin::new(s).poll_flush(cx),
             StandardTcpStream::RustlsClient(ref mut s) => Pin::new(s).poll_flush(cx),
+            StandardTcpStream::WebSocket(ref mut s) => Pin::new(s).poll_flush(cx),
         }
     }
 
@@ -55,6 +81,135 @@ impl<S: AsyncRead + AsyncWrite + Unpin + Send> AsyncWrite for StandardTcpStream<
             StandardTcpStream::Plain(ref mut s) => Pin::new(s).poll_shutdown(cx),
             StandardTcpStream::RustlsServer(ref mut s) => Pin::new(s).poll_shutdown(cx),
             StandardTcpStream::RustlsClient(ref mut s) => Pin::new(s).poll_shutdown(cx),
+            StandardTcpStream::WebSocket(ref mut s) => Pin::new(s).poll_shutdown(cx),
+        }
+    }
+}
+
+/// Stream wrapper that replays the bytes already consumed from the inner stream before reading from the inner stream
+/// again. It is used when part of the inbound data has to be inspected first, for example the TLS ClientHello for SNI
+/// based routing, while the rest of the pipeline still needs to observe the complete byte stream.
+pub struct PrefixedStream<T> {
+    prefix: Vec<u8>,
+    pos: usize,
+    inner: T,
+}
+
+impl<T> PrefixedStream<T> {
+    #[inline]
+    pub fn new(prefix: Vec<u8>, inner: T) -> Self {
+        Self {
+            prefix,
+            pos: 0,
+            inner,
+        }
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for PrefixedStream<T> {
+    #[inline]
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+
+        // Drain the prefix first before touching the inner stream
+        if this.pos < this.prefix.len() {
+            let len = std::cmp::min(this.prefix.len() - this.pos, buf.remaining());
+            buf.put_slice(&this.prefix[this.pos..this.pos + len]);
+            this.pos += len;
+            return Poll::Ready(Ok(()));
+        }
+
+        Pin::new(&mut this.inner).poll_read(cx, buf)
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for PrefixedStream<T> {
+    #[inline]
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, std::io::Error>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    #[inline]
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), std::io::Error>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    #[inline]
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), std::io::Error>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+/// Streams that may be a plain TCP socket underneath, which the relays can move the data of without going through
+/// userspace.
+pub trait IntoTcpStream: Sized {
+    /// The TCP socket under the stream along with the bytes read from it that the stream hasn't returned yet, or the
+    /// stream itself if TLS or another protocol sits in between.
+    fn into_tcp_stream(self) -> Result<(TcpStream, Vec<u8>), Self>;
+
+    /// Whether the bytes read from the stream so far are known to end with a complete TLS record, and the stream would
+    /// give up the socket with nothing left to replay. Kernel TLS can only take over the sessions of such streams.
+    fn at_record_boundary(&self) -> bool {
+        false
+    }
+}
+
+impl IntoTcpStream for TcpStream {
+    fn into_tcp_stream(self) -> Result<(TcpStream, Vec<u8>), Self> {
+        Ok((self, Vec::new()))
+    }
+}
+
+impl IntoTcpStream for DuplexStream {
+    fn into_tcp_stream(self) -> Result<(TcpStream, Vec<u8>), Self> {
+        Err(self)
+    }
+}
+
+impl<T: IntoTcpStream> IntoTcpStream for PrefixedStream<T> {
+    fn into_tcp_stream(self) -> Result<(TcpStream, Vec<u8>), Self> {
+        let Self { prefix, pos, inner } = self;
+        match inner.into_tcp_stream() {
+            Ok((stream, head)) => {
+                let mut rest = prefix[pos..].to_vec();
+                rest.extend_from_slice(&head);
+                Ok((stream, rest))
+            }
+            Err(inner) => Err(Self { prefix, pos, inner }),
+        }
+    }
+
+    fn at_record_boundary(&self) -> bool {
+        self.pos == self.prefix.len() && self.inner.at_record_boundary()
+    }
+}
+
+impl<T: IntoTcpStream> IntoTcpStream for StandardTcpStream<T> {
+    fn into_tcp_stream(self) -> Result<(TcpStream, Vec<u8>), Self> {
+        match self {
+            StandardTcpStream::Plain(stream) => {
+                stream.into_tcp_stream().map_err(StandardTcpStream::Plain)
+            }
+            // The kernel takes over the TLS session if it's able to, the relays then see a plain socket
+            #[cfg(target_os = "linux")]
+            StandardTcpStream::RustlsServer(stream) => match Ktls::get() {
+                Some(ktls) => ktls
+                    .offload(stream)
+                    .map_err(StandardTcpStream::RustlsServer),
+                None => Err(StandardTcpStream::RustlsServer(stream)),
+            },
+            stream => Err(stream),
         }
     }
 }
@@ -1,3 +1,4 @@
 pub mod socks5;
 pub mod common;
-pub mod trojan;
\ No newline at end of file
+pub mod tls;
+pub mod trojan;
@@ -52,7 +52,7 @@ impl ServerHello {
 
     #[inline]
     pub fn bytes(self) -> [u8; 2] {
-        return [self.version, self.method]
+        return [self.version, self.method];
     }
 }
 
@@ -1,17 +1,35 @@
 pub mod base;
 pub mod parser;
+pub mod reply;
+pub mod udp;
 
 use self::base::{ServerHello, VERSION};
 
-use crate::protocol::common::request::InboundRequest;
+use crate::protocol::common::addr::{IpAddrPort, IpAddress};
+use crate::protocol::common::request::{InboundRequest, TransportProtocol};
 use crate::protocol::common::stream::StandardTcpStream;
 
-use std::io::Result;
+use std::io::{Error, ErrorKind, Result};
+use std::net::SocketAddr;
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
+/// Reply codes of the SOCKS request defined in RFC 1928
+pub const REPLY_SUCCEEDED: u8 = 0x00;
+pub const REPLY_GENERAL_FAILURE: u8 = 0x01;
+pub const REPLY_NOT_ALLOWED: u8 = 0x02;
+pub const REPLY_NETWORK_UNREACHABLE: u8 = 0x03;
+pub const REPLY_HOST_UNREACHABLE: u8 = 0x04;
+pub const REPLY_CONNECTION_REFUSED: u8 = 0x05;
+pub const REPLY_TTL_EXPIRED: u8 = 0x06;
+pub const REPLY_COMMAND_NOT_SUPPORTED: u8 = 0x07;
+
+/// Accept the SOCKS handshake and read the request. The reply to the request is written right away if reply is
+/// true, otherwise the caller is responsible for writing it once the outbound connection is established. UDP
+/// ASSOCIATE is always replied to by the caller, with the address of the relay socket it binds.
 pub async fn accept<T: AsyncRead + AsyncWrite + Unpin + Send>(
     mut stream: StandardTcpStream<T>,
     port: u16,
+    reply: bool,
 ) -> Result<(InboundRequest, StandardTcpStream<T>)> {
     // Initialize the handshake process to establish socks connection
     init_ack(&mut stream).await?;
@@ -20,11 +38,38 @@ pub async fn accept<T: AsyncRead + AsyncWrite + Unpin + Send>(
     let request = parser::parse(&mut stream).await?.into_request();
 
     // Write back the request port
-    write_request_ack(&mut stream, port).await?;
+    if reply && request.transport_protocol == TransportProtocol::TCP {
+        write_request_ack(&mut stream, port).await?;
+    }
 
     Ok((request, stream))
 }
 
+/// Reply to the SOCKS request with the reply code, the bound address is always 127.0.0.1 and the inbound port.
+pub fn request_ack(rep: u8, port: u16) -> Vec<u8> {
+    bound_ack(rep, SocketAddr::from(([127, 0, 0, 1], port)))
+}
+
+/// Reply to the SOCKS request with the reply code and the bound address.
+pub fn bound_ack(rep: u8, addr: SocketAddr) -> Vec<u8> {
+    let mut ack = vec![VERSION, rep, 0];
+    // Only domain names can fail to be encoded
+    let _ = IpAddrPort::new(IpAddress::IpAddr(addr.ip()), addr.port()).write_to(&mut ack);
+    ack
+}
+
+/// Reply code reporting the error of establishing the outbound connection.
+pub fn reply_code(error: &Error) -> u8 {
+    match error.kind() {
+        ErrorKind::ConnectionRefused => REPLY_CONNECTION_REFUSED,
+        ErrorKind::TimedOut => REPLY_TTL_EXPIRED,
+        ErrorKind::NotFound | ErrorKind::AddrNotAvailable => REPLY_HOST_UNREACHABLE,
+        ErrorKind::PermissionDenied => REPLY_NOT_ALLOWED,
+        ErrorKind::Unsupported => REPLY_COMMAND_NOT_SUPPORTED,
+        _ => REPLY_GENERAL_FAILURE,
+    }
+}
+
 async fn init_ack<T: AsyncRead + AsyncWrite + Unpin>(stream: &mut T) -> Result<()> {
     let mut buf = vec![0u8; 32];
 
@@ -44,9 +89,9 @@ async fn write_request_ack<T: AsyncRead + AsyncWrite + Unpin>(
     mut stream: T,
     port: u16,
 ) -> Result<()> {
-    // TODO: Have a better way to write back request ACK
-    stream.write_all(&[VERSION, 0, 0, 1, 127, 0, 0, 1]).await?;
-    stream.write_u16(port).await?;
+    stream
+        .write_all(&request_ack(REPLY_SUCCEEDED, port))
+        .await?;
     stream.flush().await?;
 
     Ok(())
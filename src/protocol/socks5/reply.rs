@@ -0,0 +1,122 @@
+use crate::protocol::common::stream::IntoTcpStream;
+
+use std::io::{Error, ErrorKind, Result};
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::TcpStream;
+use tokio::sync::oneshot;
+
+/// Stream wrapper holding back the reply to the SOCKS request until the outbound connection is established. The
+/// success reply is written before the first read or write of the relay, which only starts after the outbound is
+/// connected. If the stream is dropped before that, the failure reply sent through the channel returned by new is
+/// written instead, so that the client learns why the request failed rather than seeing the connection closed.
+pub struct DeferredReply<T: AsyncRead + AsyncWrite + Unpin + Send + 'static> {
+    // Only taken when the stream is dropped
+    inner: Option<T>,
+    reply: Vec<u8>,
+    pos: usize,
+    flushed: bool,
+    failure: Option<oneshot::Receiver<Vec<u8>>>,
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + 'static> DeferredReply<T> {
+    pub fn new(inner: T, reply: Vec<u8>) -> (Self, oneshot::Sender<Vec<u8>>) {
+        let (sender, receiver) = oneshot::channel();
+        let stream = Self {
+            inner: Some(inner),
+            reply,
+            pos: 0,
+            flushed: false,
+            failure: Some(receiver),
+        };
+        (stream, sender)
+    }
+
+    /// Write the success reply if it hasn't been written yet.
+    fn poll_reply(&mut self, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        if self.flushed {
+            return Poll::Ready(Ok(()));
+        }
+
+        let inner = self.inner.as_mut().unwrap();
+        while self.pos < self.reply.len() {
+            let size = ready!(Pin::new(&mut *inner).poll_write(cx, &self.reply[self.pos..]))?;
+            if size == 0 {
+                return Poll::Ready(Err(Error::new(
+                    ErrorKind::WriteZero,
+                    "Failed to write SOCKS reply",
+                )));
+            }
+            self.pos += size;
+        }
+
+        ready!(Pin::new(inner).poll_flush(cx))?;
+        self.flushed = true;
+        self.failure = None;
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + 'static> AsyncRead for DeferredReply<T> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_reply(cx))?;
+        Pin::new(this.inner.as_mut().unwrap()).poll_read(cx, buf)
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + 'static> AsyncWrite for DeferredReply<T> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_reply(cx))?;
+        Pin::new(this.inner.as_mut().unwrap()).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_reply(cx))?;
+        Pin::new(this.inner.as_mut().unwrap()).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_reply(cx))?;
+        Pin::new(this.inner.as_mut().unwrap()).poll_shutdown(cx)
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + 'static> IntoTcpStream for DeferredReply<T> {
+    /// Never gives up the socket, the reply has to be written by the stream first.
+    fn into_tcp_stream(self) -> std::result::Result<(TcpStream, Vec<u8>), Self> {
+        Err(self)
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + 'static> Drop for DeferredReply<T> {
+    fn drop(&mut self) {
+        // Nothing to do if the reply was already written, at least partially
+        if self.pos > 0 {
+            return;
+        }
+
+        let (mut inner, failure) = match (self.inner.take(), self.failure.take()) {
+            (Some(inner), Some(failure)) => (inner, failure),
+            _ => return,
+        };
+
+        if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+            runtime.spawn(async move {
+                if let Ok(reply) = failure.await {
+                    let _ = inner.write_all(&reply).await;
+                    let _ = inner.shutdown().await;
+                }
+            });
+        }
+    }
+}
@@ -0,0 +1,220 @@
+#[cfg(feature = "client")]
+use crate::dns::hijack::DnsHijack;
+#[cfg(feature = "client")]
+use crate::metrics;
+use crate::protocol::common::addr::{IpAddrPort, IpAddress};
+use crate::protocol::common::atype::Atype;
+use crate::protocol::trojan::packet::TrojanPacketWriter;
+use crate::protocol::trojan::parse_udp;
+
+use bytes::Bytes;
+use log::debug;
+use std::io::{Error, ErrorKind, Result};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, BufReader};
+use tokio::net::UdpSocket;
+use tokio::sync::watch;
+
+/// Largest datagram relayed for the client
+const MAX_DATAGRAM_SIZE: usize = 65536;
+
+/// Size of the RSV and FRAG fields at the start of the UDP request header
+const HEADER_PREFIX_SIZE: usize = 3;
+
+/// Port of the DNS queries answered by the DNS inbound when they are intercepted
+#[cfg(feature = "client")]
+const DNS_PORT: u16 = 53;
+
+/// Read the destination and the payload of a datagram sent by the client, which starts with the UDP request header
+/// defined in RFC 1928: RSV(2) FRAG(1) ATYP DST.ADDR DST.PORT. Fragmented datagrams are not supported.
+pub fn decode(datagram: &[u8]) -> Result<(IpAddrPort, &[u8])> {
+    let invalid = || Error::new(ErrorKind::InvalidData, "truncated SOCKS UDP request header");
+
+    match datagram.get(2) {
+        Some(0) => (),
+        Some(_) => {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                "fragmented SOCKS UDP datagrams are not supported",
+            ))
+        }
+        None => return Err(invalid()),
+    }
+
+    let rest = &datagram[HEADER_PREFIX_SIZE..];
+    let atype = Atype::from(*rest.first().ok_or_else(invalid)?)?;
+    let (ip, rest) = match atype {
+        Atype::IPv4 => {
+            let octets: [u8; 4] = rest.get(1..5).ok_or_else(invalid)?.try_into().unwrap();
+            (IpAddress::IpAddr(IpAddr::from(octets)), &rest[5..])
+        }
+        Atype::IPv6 => {
+            let octets: [u8; 16] = rest.get(1..17).ok_or_else(invalid)?.try_into().unwrap();
+            (IpAddress::IpAddr(IpAddr::from(octets)), &rest[17..])
+        }
+        Atype::DomainName => {
+            let size = *rest.get(1).ok_or_else(invalid)? as usize;
+            let domain = rest.get(2..2 + size).ok_or_else(invalid)?;
+            (
+                IpAddress::from_bytes(Bytes::copy_from_slice(domain)),
+                &rest[2 + size..],
+            )
+        }
+    };
+
+    let port = rest.get(..2).ok_or_else(invalid)?;
+    let port = u16::from_be_bytes([port[0], port[1]]);
+    Ok((IpAddrPort::new(ip, port), &rest[2..]))
+}
+
+/// Prefix the payload with the UDP request header carrying the address it came from, to be sent to the client. Fails
+/// if the domain name is too long.
+pub fn encode(source: &IpAddrPort, payload: &[u8]) -> Result<Vec<u8>> {
+    let mut datagram = Vec::with_capacity(HEADER_PREFIX_SIZE + source.ip.len() + payload.len() + 4);
+    datagram.extend_from_slice(&[0, 0, 0]);
+    source.write_to(&mut datagram)?;
+    datagram.extend_from_slice(payload);
+    Ok(datagram)
+}
+
+/// Relay socket of a UDP ASSOCIATE request. The datagrams of the client are framed as trojan UDP packets on a stream,
+/// so that they go through the outbounds like the UDP requests of the trojan inbound.
+pub struct UdpAssociation {
+    socket: Arc<UdpSocket>,
+    #[cfg(feature = "client")]
+    dns: Option<&'static DnsHijack>,
+}
+
+impl UdpAssociation {
+    /// Bind the relay socket on the address the client connected to, with a port picked by the system.
+    pub async fn bind(ip: IpAddr) -> Result<Self> {
+        Ok(Self {
+            socket: Arc::new(UdpSocket::bind(SocketAddr::new(ip, 0)).await?),
+            #[cfg(feature = "client")]
+            dns: None,
+        })
+    }
+
+    /// Answer the datagrams sent to port 53 with the DNS inbound rather than relaying them.
+    #[cfg(feature = "client")]
+    pub fn intercept_dns(mut self, dns: &'static DnsHijack) -> Self {
+        self.dns = Some(dns);
+        self
+    }
+
+    /// Address of the relay socket, returned to the client in the reply to the request.
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// Write the datagrams of the client to the stream as trojan UDP packets, and send the packets read from the
+    /// stream back to the client, until either side fails. Only the datagrams from the IP of the client are accepted,
+    /// and the replies go to the address it last sent from.
+    pub async fn relay<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        client_ip: IpAddr,
+        stream: S,
+    ) -> Result<()> {
+        let (reader, writer) = tokio::io::split(stream);
+        let mut writer = TrojanPacketWriter::new(writer);
+        let (peer_sender, peer) = watch::channel(None);
+
+        let upstream = async {
+            let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+            loop {
+                let (size, source) = self.socket.recv_from(&mut buf).await?;
+                if source.ip() != client_ip {
+                    debug!("Dropping SOCKS UDP datagram from {}", source);
+                    continue;
+                }
+
+                let (destination, payload) = match decode(&buf[..size]) {
+                    Ok(datagram) => datagram,
+                    Err(e) => {
+                        debug!("Dropping SOCKS UDP datagram from {}: {}", source, e);
+                        continue;
+                    }
+                };
+                let _ = peer_sender.send(Some(source));
+
+                #[cfg(feature = "client")]
+                if let Some(dns) = self.dns.filter(|_| destination.port == DNS_PORT) {
+                    self.answer_dns(dns, destination, payload.to_vec(), source);
+                    continue;
+                }
+
+                writer.push(&destination, payload)?;
+                writer.flush().await?;
+            }
+        };
+
+        let downstream = async {
+            let mut reader = BufReader::new(reader);
+            let mut payload = vec![0u8; MAX_DATAGRAM_SIZE];
+            loop {
+                let header = parse_udp(&mut reader).await?;
+                reader
+                    .read_exact(&mut payload[..header.payload_size])
+                    .await?;
+
+                let peer = *peer.borrow();
+                if let Some(peer) = peer {
+                    self.socket
+                        .send_to(
+                            &encode(&header.dest, &payload[..header.payload_size])?,
+                            peer,
+                        )
+                        .await?;
+                }
+            }
+        };
+
+        let result = tokio::select! {
+            result = upstream => result,
+            result = downstream => result,
+        };
+
+        // Deliver the datagrams still queued and end the stream cleanly
+        if let Err(e) = writer.shutdown().await {
+            debug!("Failed to close SOCKS UDP relay stream: {}", e);
+        }
+        result
+    }
+
+    /// Answer the DNS query in the background, replying as the server it was sent to.
+    #[cfg(feature = "client")]
+    fn answer_dns(
+        &self,
+        dns: &'static DnsHijack,
+        server: IpAddrPort,
+        query: Vec<u8>,
+        peer: SocketAddr,
+    ) {
+        metrics::increment("dns_queries_intercepted_total", 1);
+
+        let socket = self.socket.clone();
+        tokio::spawn(async move {
+            let response = match dns.answer(&query).await {
+                Ok(response) => response,
+                Err(e) => {
+                    debug!(
+                        "Failed to answer DNS query of {} to {}: {}",
+                        peer, server, e
+                    );
+                    return;
+                }
+            };
+            let datagram = match encode(&server, &response) {
+                Ok(datagram) => datagram,
+                Err(e) => {
+                    debug!("Failed to encode DNS response to {}: {}", peer, e);
+                    return;
+                }
+            };
+            if let Err(e) = socket.send_to(&datagram, peer).await {
+                debug!("Failed to send DNS response to {}: {}", peer, e);
+            }
+        });
+    }
+}
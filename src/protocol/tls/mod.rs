@@ -0,0 +1,141 @@
+pub mod record;
+
+use std::io::Result;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// TLS record content type for handshake messages
+const CONTENT_TYPE_HANDSHAKE: u8 = 0x16;
+
+/// Handshake message type of ClientHello
+const HANDSHAKE_CLIENT_HELLO: u8 = 0x01;
+
+/// Extension type of server_name, defined in RFC 6066
+const EXTENSION_SERVER_NAME: u16 = 0x0000;
+
+/// Name type of host_name within the server_name extension
+const NAME_TYPE_HOST_NAME: u8 = 0x00;
+
+/// Size of TLS record header, content type(1) + version(2) + length(2)
+pub(crate) const RECORD_HEADER_SIZE: usize = 5;
+
+/// Maximum size of a TLS plaintext record
+const MAX_RECORD_SIZE: usize = 16384;
+
+/// Read the first TLS record from the stream, which is expected to carry the ClientHello, and extract the server
+/// name from it. All the bytes consumed from the stream are returned along with the server name, so that the caller
+/// is able to replay them to whoever handles the connection next.
+///
+/// The server name is None if the stream doesn't start with a TLS handshake or the ClientHello doesn't carry the
+/// server_name extension.
+pub async fn read_client_hello<T: AsyncRead + Unpin>(
+    stream: &mut T,
+) -> Result<(Vec<u8>, Option<String>)> {
+    let mut buf = vec![0u8; RECORD_HEADER_SIZE];
+    stream.read_exact(&mut buf).await?;
+
+    if buf[0] != CONTENT_TYPE_HANDSHAKE {
+        return Ok((buf, None));
+    }
+
+    let length = u16::from_be_bytes([buf[3], buf[4]]) as usize;
+    if length > MAX_RECORD_SIZE {
+        return Ok((buf, None));
+    }
+
+    buf.resize(RECORD_HEADER_SIZE + length, 0);
+    stream.read_exact(&mut buf[RECORD_HEADER_SIZE..]).await?;
+
+    let server_name = parse_server_name(&buf[RECORD_HEADER_SIZE..]);
+
+    Ok((buf, server_name))
+}
+
+/// Extract the host name in server_name extension from a ClientHello handshake message. Returns None if the message
+/// is malformed, truncated or doesn't contain the extension.
+pub fn parse_server_name(message: &[u8]) -> Option<String> {
+    let mut reader = SliceReader::new(message);
+
+    if reader.read_u8()? != HANDSHAKE_CLIENT_HELLO {
+        return None;
+    }
+
+    // Skip handshake length(3), client version(2) and random(32)
+    reader.skip(3 + 2 + 32)?;
+
+    // Skip session id, cipher suites and compression methods
+    let session_id_len = reader.read_u8()? as usize;
+    reader.skip(session_id_len)?;
+    let cipher_suites_len = reader.read_u16()? as usize;
+    reader.skip(cipher_suites_len)?;
+    let compression_len = reader.read_u8()? as usize;
+    reader.skip(compression_len)?;
+
+    let extensions_len = reader.read_u16()? as usize;
+    let mut extensions = SliceReader::new(reader.read_slice(extensions_len)?);
+
+    while let Some(extension_type) = extensions.read_u16() {
+        let extension_len = extensions.read_u16()? as usize;
+        let extension = extensions.read_slice(extension_len)?;
+
+        if extension_type != EXTENSION_SERVER_NAME {
+            continue;
+        }
+
+        let mut names = SliceReader::new(extension);
+        let list_len = names.read_u16()? as usize;
+        let mut names = SliceReader::new(names.read_slice(list_len)?);
+
+        while let Some(name_type) = names.read_u8() {
+            let name_len = names.read_u16()? as usize;
+            let name = names.read_slice(name_len)?;
+
+            if name_type == NAME_TYPE_HOST_NAME {
+                return std::str::from_utf8(name)
+                    .ok()
+                    .map(|s| s.to_ascii_lowercase());
+            }
+        }
+
+        return None;
+    }
+
+    None
+}
+
+/// Minimal bound checked reader over a byte slice used for parsing handshake messages
+struct SliceReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> SliceReader<'a> {
+    #[inline]
+    fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+
+    #[inline]
+    fn read_slice(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.data.len() < len {
+            return None;
+        }
+
+        let (head, tail) = self.data.split_at(len);
+        self.data = tail;
+        Some(head)
+    }
+
+    #[inline]
+    fn skip(&mut self, len: usize) -> Option<()> {
+        self.read_slice(len).map(|_| ())
+    }
+
+    #[inline]
+    fn read_u8(&mut self) -> Option<u8> {
+        self.read_slice(1).map(|b| b[0])
+    }
+
+    #[inline]
+    fn read_u16(&mut self) -> Option<u16> {
+        self.read_slice(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+}
@@ -0,0 +1,120 @@
+use crate::protocol::common::stream::IntoTcpStream;
+use crate::protocol::tls::RECORD_HEADER_SIZE;
+
+use std::io::{IoSlice, Result};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+
+/// Stream wrapper following the framing of the TLS records read through it, so that it is known whether the bytes
+/// read so far end with a complete record. The TLS session of such a stream can be taken over by kernel TLS, which
+/// expects the next bytes on the socket to start a new record. Nothing is tracked unless tracking is enabled.
+pub struct RecordStream<T> {
+    inner: T,
+    tracking: bool,
+    header: [u8; RECORD_HEADER_SIZE],
+    header_len: usize,
+    remaining: usize,
+}
+
+impl<T> RecordStream<T> {
+    #[inline]
+    pub fn new(inner: T, tracking: bool) -> Self {
+        Self {
+            inner,
+            tracking,
+            header: [0; RECORD_HEADER_SIZE],
+            header_len: 0,
+            remaining: 0,
+        }
+    }
+
+    /// Whether tracking is enabled and the bytes read so far end with a complete record.
+    #[inline]
+    pub fn at_record_boundary(&self) -> bool {
+        self.tracking && self.header_len == 0 && self.remaining == 0
+    }
+
+    fn track(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            if self.remaining > 0 {
+                let len = self.remaining.min(data.len());
+                self.remaining -= len;
+                data = &data[len..];
+                continue;
+            }
+
+            let len = (RECORD_HEADER_SIZE - self.header_len).min(data.len());
+            self.header[self.header_len..self.header_len + len].copy_from_slice(&data[..len]);
+            self.header_len += len;
+            data = &data[len..];
+
+            if self.header_len == RECORD_HEADER_SIZE {
+                self.remaining = u16::from_be_bytes([self.header[3], self.header[4]]) as usize;
+                self.header_len = 0;
+            }
+        }
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for RecordStream<T> {
+    #[inline]
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<()>> {
+        let this = self.get_mut();
+        let filled = buf.filled().len();
+
+        let result = Pin::new(&mut this.inner).poll_read(cx, buf);
+        if this.tracking {
+            if let Poll::Ready(Ok(())) = result {
+                this.track(&buf.filled()[filled..]);
+            }
+        }
+        result
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for RecordStream<T> {
+    #[inline]
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    #[inline]
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write_vectored(cx, bufs)
+    }
+
+    #[inline]
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    #[inline]
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    #[inline]
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+impl IntoTcpStream for RecordStream<TcpStream> {
+    fn into_tcp_stream(self) -> std::result::Result<(TcpStream, Vec<u8>), Self> {
+        Ok((self.inner, Vec::new()))
+    }
+
+    fn at_record_boundary(&self) -> bool {
+        RecordStream::at_record_boundary(self)
+    }
+}
@@ -62,6 +62,11 @@ impl Request {
         };
     }
 
+    #[inline]
+    pub fn hex(&self) -> &[u8] {
+        &self.hex
+    }
+
     #[inline]
     pub fn validate(&self, secret: &[u8]) -> bool {
         if secret.len() != self.hex.len() {
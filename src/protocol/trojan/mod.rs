@@ -3,34 +3,66 @@ mod parser;
 
 pub mod packet;
 
+pub use self::base::Request;
 pub use self::base::CRLF;
 pub use self::base::HEX_SIZE;
 pub use self::parser::parse;
+pub use self::parser::parse_udp;
 
 use crate::protocol::common::addr::IpAddress;
-use crate::protocol::common::{request::InboundRequest, stream::StandardTcpStream};
+use crate::protocol::common::request::InboundRequest;
 
-use std::io::{Error, ErrorKind, Result};
+use std::io::{Cursor, Error, ErrorKind, Result};
 use std::net::IpAddr;
-use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
-/// Helper function to accept an abstract TCP stream to Trojan connection
-pub async fn accept<T: AsyncRead + AsyncWrite + Unpin + Send>(
-    mut stream: StandardTcpStream<T>,
-    secret: &[u8],
-) -> Result<(InboundRequest, StandardTcpStream<T>)> {
-    // Read trojan request header and generate request header
-    let request = parse(&mut stream).await?;
-
-    // Validate the request secret and decide if the connection should be accepted
-    if !request.validate(secret) {
-        return Err(Error::new(
-            ErrorKind::InvalidInput,
-            "Received invalid hex value",
-        ));
-    }
+/// Maximum size of a trojan request header, hex(56) + CRLF(2) + command(1) + atype(1) + domain(1 + 255) + port(2)
+/// + CRLF(2).
+pub const MAX_HEADER_SIZE: usize = 320;
+
+/// Size of the chunks read from the stream while waiting for a complete request header
+const READ_CHUNK_SIZE: usize = 4096;
+
+/// Read trojan request from the stream. Unlike parse, every byte read from the stream is kept in buf, so that the
+/// caller can hand the data over to a fallback server when the request turns out to be invalid. Data that can't be a
+/// trojan request, like a plain HTTP request from a prober, is rejected as soon as the first chunk arrives rather than
+/// waiting for more data.
+///
+/// On success, the request is returned with the size of the header. The bytes in buf after the header are payload
+/// sent along with the request.
+pub async fn read_request<T: AsyncRead + Unpin>(
+    stream: &mut T,
+    buf: &mut Vec<u8>,
+) -> Result<(Request, usize)> {
+    let mut chunk = vec![0u8; READ_CHUNK_SIZE];
+
+    loop {
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                "Connection closed before trojan request completes",
+            ));
+        }
+
+        buf.extend_from_slice(&chunk[..n]);
+
+        if !buf.iter().take(HEX_SIZE).all(|c| c.is_ascii_hexdigit()) {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "Received non trojan request",
+            ));
+        }
 
-    Ok((request.into_request(), stream))
+        let mut cursor = Cursor::new(buf.as_slice());
+        match parse(&mut cursor).await {
+            Ok(request) => return Ok((request, cursor.position() as usize)),
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof && buf.len() < MAX_HEADER_SIZE => {
+                continue
+            }
+            Err(e) => return Err(e),
+        }
+    }
 }
 
 /// Helper function to establish Trojan connection to remote server
@@ -52,7 +84,8 @@ pub async fn handshake<T: AsyncWrite + Unpin>(
             stream.write_all(&ipv6.octets()).await?;
         }
         IpAddress::Domain(domain) => {
-            stream.write_all(&domain.as_bytes()).await?;
+            stream.write_u8(domain.encoded_len()?).await?;
+            stream.write_all(domain.as_bytes()).await?;
         }
     }
     stream.write_u16(request.addr_port.port).await?;
@@ -1,36 +1,53 @@
+use crate::metrics;
 use crate::protocol::common::addr::{IpAddrPort, IpAddress};
 use crate::protocol::common::atype::Atype;
-use crate::protocol::common::request::InboundRequest;
 use crate::protocol::trojan::base::CRLF;
 use crate::protocol::trojan::parser::parse_udp;
-use crate::transport::grpc_stream::GrpcDataReaderStream;
-use crate::transport::grpc_transport::Hunk;
+use crate::proxy::buffer::{Buffer, BufferPool};
+use crate::proxy::quic::datagram::DatagramSender;
+use crate::proxy::relay::Transfer;
+use crate::proxy::udp::batch::RecvBatch;
+use crate::proxy::udp::guard::UdpGuard;
+use crate::proxy::udp::worker::UdpWorkers;
 
 use log::debug;
-use std::io::{self, Cursor, Error, ErrorKind};
-use std::net::{IpAddr, SocketAddr};
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::net::UdpSocket;
-use tokio::sync::mpsc::Sender;
-use tonic::Streaming;
+use tokio::time::timeout;
 
 /// Define the size of the buffer used to transport the data back and forth
 const BUF_SIZE: usize = 4096;
 
+/// Most replies read from the socket of a session at once
+const REPLY_BATCH_SIZE: usize = 8;
+
+/// Time given to the last packets of a session to be written when it ends
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Size of the header of the Trojan UDP packets from IPv6 addresses, the longest one of the replies
+pub const MAX_REPLY_HEADER_SIZE: usize = 1 + 16 + 2 + 2 + 2;
+
 /// According the official documentation for Trojan protocol, the UDP data will be segmented into Trojan UDP packets,
 /// which allows the outbound handler to also forward them as real UDP packets to the desired destinations.
 /// Link: https://trojan-gfw.github.io/trojan/protocol.html
 pub struct TrojanUdpPacketHeader {
     pub atype: Atype,
-    pub dest: SocketAddr,
+    pub dest: IpAddrPort,
     pub payload_size: usize,
 }
 
+/// Read the Trojan UDP packets from the client and hand them over to the UDP workers, which resolve their
+/// destinations and send them out of the socket of the session.
 pub async fn copy_client_reader_to_udp_socket<R: AsyncRead + Unpin>(
     mut client_reader: R,
-    server_writer: &UdpSocket,
+    server_writer: &Arc<UdpSocket>,
+    guard: &Arc<UdpGuard>,
 ) -> io::Result<()> {
-    let mut read_buf = vec![0u8; BUF_SIZE];
+    let workers = UdpWorkers::init();
+    let session = server_writer.local_addr()?;
 
     loop {
         let header = parse_udp(&mut client_reader).await?;
@@ -40,14 +57,8 @@ pub async fn copy_client_reader_to_udp_socket<R: AsyncRead + Unpin>(
             header.payload_size, header.dest
         );
 
-        assert!(
-            header.payload_size <= BUF_SIZE,
-            "Payload size exceeds read buffer size"
-        );
-
-        let size = client_reader
-            .read_exact(&mut read_buf[..header.payload_size])
-            .await?;
+        let mut payload = BufferPool::get().take_zeroed(header.payload_size);
+        let size = client_reader.read_exact(&mut payload).await?;
 
         assert!(
             size == header.payload_size,
@@ -56,177 +67,167 @@ pub async fn copy_client_reader_to_udp_socket<R: AsyncRead + Unpin>(
             size
         );
 
-        server_writer
-            .send_to(&read_buf[..header.payload_size], header.dest)
+        if !guard.fits(packet_size(&header.dest, header.payload_size)) {
+            debug!("Dropping UDP datagram of {} bytes to {}", size, header.dest);
+            metrics::increment("udp_requests_dropped_total{reason=\"too_large\"}", 1);
+            continue;
+        }
+
+        Transfer::count(size as u64, 0);
+        workers
+            .send(session, server_writer, guard, header.dest, payload)
             .await?;
     }
 }
 
+/// Write the datagrams received by the socket to the client as Trojan UDP packets, each carrying the address it came
+/// from. The datagrams waiting on the socket are read together and flushed to the client at once. The writer splits
+/// the ones larger than the packets of the session.
 pub async fn copy_udp_socket_to_client_writer<W: AsyncWrite + Unpin>(
     server_reader: &UdpSocket,
-    mut client_writer: W,
-    addr: IpAddrPort,
+    client_writer: &mut TrojanPacketWriter<W>,
+    guard: &UdpGuard,
 ) -> io::Result<()> {
-    let mut read_buf = vec![0u8; BUF_SIZE];
-    let (addr, port) = (addr.ip, addr.port);
+    let mut batch = RecvBatch::new(REPLY_BATCH_SIZE, BUF_SIZE);
 
     loop {
-        let (size, _dest) = server_reader.recv_from(&mut read_buf).await?;
+        batch.recv(server_reader).await?;
 
-        match addr {
-            IpAddress::IpAddr(IpAddr::V4(addr)) => {
-                client_writer.write_u8(Atype::IPv4 as u8).await?;
-                client_writer.write_all(&addr.octets()).await?;
-            }
-            IpAddress::IpAddr(IpAddr::V6(addr)) => {
-                client_writer.write_u8(Atype::IPv6 as u8).await?;
-                client_writer.write_all(&addr.octets()).await?;
+        for (payload, source) in batch.iter() {
+            if !guard.check_reply(source, payload.len()) {
+                continue;
             }
-            IpAddress::Domain(ref domain) => {
-                client_writer.write_u8(Atype::DomainName as u8).await?;
-                client_writer
-                    .write_u8(domain.as_bytes().len() as u8)
-                    .await?;
-                client_writer.write_all(domain.as_bytes()).await?;
-            }
-        }
 
-        client_writer.write_u16(port).await?;
-        client_writer.write_u16(size as u16).await?;
-        client_writer.write_u16(CRLF).await?;
-        client_writer.write_all(&read_buf[..size]).await?;
+            let source = IpAddrPort::new(IpAddress::IpAddr(source.ip()), source.port());
+            Transfer::count(0, payload.len() as u64);
+            client_writer.push(&source, payload)?;
+        }
         client_writer.flush().await?;
     }
 }
 
-pub async fn copy_client_reader_to_udp_server_writer<
-    R: AsyncRead + Unpin,
-    W: AsyncWrite + Unpin,
->(
-    mut client_reader: R,
-    mut server_writer: W,
-    request: InboundRequest,
-) -> io::Result<()> {
-    let mut read_buf = vec![0u8; BUF_SIZE];
-
-    loop {
-        let size = client_reader.read(&mut read_buf).await?;
-
-        server_writer.write_u8(request.atype as u8).await?;
+/// Writes Trojan UDP packets to a stream without ever leaving a packet cut in half. The packets are queued whole and
+/// only leave the queue once the stream took them, so a flush cancelled by the end of the session is finished by
+/// shutdown, which then closes the stream cleanly. Otherwise the last packets of a session, often DNS answers, could
+/// reach the other end truncated or not at all. Over QUIC, the packets go as datagrams instead when they can.
+pub struct TrojanPacketWriter<W> {
+    inner: W,
+    pending: Buffer,
+    datagrams: Option<DatagramSender>,
+    max_packet_size: Option<usize>,
+}
 
-        match request.addr_port.ip {
-            IpAddress::IpAddr(IpAddr::V4(addr)) => {
-                server_writer.write_all(&addr.octets()).await?;
-            }
-            IpAddress::IpAddr(IpAddr::V6(addr)) => {
-                server_writer.write_all(&addr.octets()).await?;
-            }
-            IpAddress::Domain(ref domain) => {
-                server_writer
-                    .write_u8(domain.as_bytes().len() as u8)
-                    .await?;
-                server_writer.write_all(domain.as_bytes()).await?;
-            }
+impl<W: AsyncWrite + Unpin> TrojanPacketWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            pending: BufferPool::get().take(),
+            datagrams: None,
+            max_packet_size: None,
         }
-
-        server_writer.write_u16(request.addr_port.port).await?;
-        server_writer.write_u16(size as u16).await?;
-        server_writer.write_u16(CRLF).await?;
-        server_writer.write_all(&read_buf[..size]).await?;
-        server_writer.flush().await?;
     }
-}
-
-pub async fn copy_udp_server_reader_to_client_writer<
-    R: AsyncRead + Unpin,
-    W: AsyncWrite + Unpin,
->(
-    mut server_reader: R,
-    mut client_writer: W,
-) -> io::Result<()> {
-    let mut read_buf = vec![0u8; BUF_SIZE];
-
-    loop {
-        let header = parse_udp(&mut server_reader).await?;
-
-        server_reader
-            .read_exact(&mut read_buf[..header.payload_size])
-            .await?;
 
-        client_writer
-            .write_all(&read_buf[..header.payload_size])
-            .await?;
+    /// Split the payloads into as many packets as needed for each packet to take the size at most, header included.
+    /// The size has to leave room for the header of the addresses besides the payload, which the parser checks for
+    /// the replies of the sessions.
+    pub fn with_max_packet_size(mut self, max_packet_size: Option<usize>) -> Self {
+        self.max_packet_size = max_packet_size;
+        self
     }
-}
-
-pub async fn copy_client_reader_to_server_grpc_writer<R: AsyncRead + Unpin>(
-    mut client_reader: R,
-    server_writer: Sender<Hunk>,
-    request: InboundRequest,
-) -> io::Result<()> {
-    loop {
-        let mut read_buf = vec![0u8; BUF_SIZE];
-
-        let n = client_reader.read(&mut read_buf).await?;
 
-        read_buf.truncate(n);
+    /// Send the packets as QUIC datagrams of the session, the ones the sender can't take are written to the stream.
+    pub fn with_datagrams(mut self, datagrams: DatagramSender) -> Self {
+        self.datagrams = Some(datagrams);
+        self
+    }
 
-        let mut cursor = Cursor::new(vec![0u8; 512]);
+    /// Queue the packets carrying the payload to or from the address, they are written by the next flush. A payload too
+    /// large for a packet is split across several, each reaching the other side as a datagram of its own. Fails if the
+    /// domain name is too long.
+    pub fn push(&mut self, addr: &IpAddrPort, payload: &[u8]) -> io::Result<()> {
+        let size = match self.max_packet_size {
+            Some(max_packet_size) if packet_size(addr, payload.len()) > max_packet_size => {
+                max_packet_size.saturating_sub(packet_size(addr, 0)).max(1)
+            }
+            _ => return self.push_packet(addr, payload),
+        };
 
-        cursor.write_u8(request.atype as u8).await?;
+        debug!(
+            "Splitting UDP payload of {} bytes from {}",
+            payload.len(),
+            addr
+        );
+        metrics::increment("udp_payloads_split_total", 1);
+        for chunk in payload.chunks(size) {
+            self.push_packet(addr, chunk)?;
+        }
+        Ok(())
+    }
 
-        match request.addr_port.ip {
-            IpAddress::IpAddr(IpAddr::V4(addr)) => {
-                cursor.write_all(&addr.octets()).await?;
-            }
-            IpAddress::IpAddr(IpAddr::V6(addr)) => {
-                cursor.write_all(&addr.octets()).await?;
-            }
-            IpAddress::Domain(ref domain) => {
-                cursor.write_u8(domain.as_bytes().len() as u8).await?;
-                cursor.write_all(domain.as_bytes()).await?;
+    fn push_packet(&mut self, addr: &IpAddrPort, payload: &[u8]) -> io::Result<()> {
+        if let Some(datagrams) = &self.datagrams {
+            if datagrams.send(addr, payload) {
+                return Ok(());
             }
         }
+        write_udp(&mut self.pending, addr, payload)
+    }
 
-        cursor.write_u16(request.addr_port.port).await?;
-        cursor.write_u16(n as u16).await?;
-        cursor.write_u16(CRLF).await?;
-
-        let (pos, mut header) = (cursor.position(), cursor.into_inner());
-        header.truncate(pos as usize);
-
-        if let Err(_) = server_writer.send(Hunk { data: header }).await {
-            return Err(Error::new(
-                ErrorKind::ConnectionReset,
-                "Failed to send GRPC packet",
-            ));
+    /// Write the queued packets to the stream and flush it. Safe to cancel, the bytes not written yet stay queued.
+    pub async fn flush(&mut self) -> io::Result<()> {
+        while !self.pending.is_empty() {
+            let size = self.inner.write(&self.pending).await?;
+            if size == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::WriteZero,
+                    "failed to write trojan udp packets",
+                ));
+            }
+            self.pending.drain(..size);
         }
+        self.inner.flush().await
+    }
 
-        if let Err(_) = server_writer.send(Hunk { data: read_buf }).await {
-            return Err(Error::new(
-                ErrorKind::ConnectionReset,
-                "Failed to send GRPC packet",
-            ));
+    /// Write the packets still queued and shut the stream down, giving up after a few seconds on a peer that stopped
+    /// reading.
+    pub async fn shutdown(&mut self) -> io::Result<()> {
+        let close = async {
+            self.flush().await?;
+            self.inner.shutdown().await
+        };
+        match timeout(SHUTDOWN_TIMEOUT, close).await {
+            Ok(result) => result,
+            Err(_) => Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "timed out writing the last trojan udp packets",
+            )),
         }
     }
 }
 
-pub async fn copy_server_grpc_reader_to_client_writer<W: AsyncWrite + Unpin>(
-    server_reader: Streaming<Hunk>,
-    mut client_writer: W,
-) -> io::Result<()> {
-    let mut server_reader = GrpcDataReaderStream::from_reader(server_reader);
-    let mut read_buf = vec![0u8; BUF_SIZE];
-
-    loop {
-        let header = parse_udp(&mut server_reader).await?;
+/// Size of the Trojan UDP packet carrying payload_size bytes to or from the address.
+pub fn packet_size(addr: &IpAddrPort, payload_size: usize) -> usize {
+    // ATYP, the address with the length of domain names, the port, the length and CRLF
+    let address_size = match addr.ip {
+        IpAddress::Domain(_) => 1 + addr.ip.len(),
+        _ => addr.ip.len(),
+    };
+    1 + address_size + 2 + 2 + 2 + payload_size
+}
 
-        server_reader
-            .read_exact(&mut read_buf[..header.payload_size])
-            .await?;
+/// Encode the payload as a Trojan UDP packet to or from the address, fails if the domain name is too long.
+pub fn encode_udp(addr: &IpAddrPort, payload: &[u8]) -> io::Result<Vec<u8>> {
+    let mut packet = Vec::with_capacity(packet_size(addr, payload.len()));
+    write_udp(&mut packet, addr, payload)?;
+    Ok(packet)
+}
 
-        client_writer
-            .write_all(&read_buf[..header.payload_size])
-            .await?;
-    }
+/// Append the payload encoded as a Trojan UDP packet to or from the address to the buffer, fails if the domain name
+/// is too long.
+pub fn write_udp(buf: &mut Vec<u8>, addr: &IpAddrPort, payload: &[u8]) -> io::Result<()> {
+    addr.write_to(buf)?;
+    buf.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+    buf.extend_from_slice(&CRLF.to_be_bytes());
+    buf.extend_from_slice(payload);
+    Ok(())
 }
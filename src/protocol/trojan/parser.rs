@@ -1,4 +1,4 @@
-use crate::protocol::common::addr::{IpAddress, IPV4_SIZE, IPV6_SIZE, IpAddrPort};
+use crate::protocol::common::addr::{IpAddrPort, IpAddress, IPV4_SIZE, IPV6_SIZE};
 use crate::protocol::common::atype::Atype;
 use crate::protocol::common::command::Command;
 use crate::protocol::trojan::base::{Request, HEX_SIZE};
@@ -85,7 +85,7 @@ pub async fn parse_udp<T: AsyncRead + Unpin>(reader: &mut T) -> Result<TrojanUdp
 
     Ok(TrojanUdpPacketHeader {
         atype,
-        dest: IpAddrPort::new(addr, port).into(),
-        payload_size: length as usize
+        dest: IpAddrPort::new(addr, port),
+        payload_size: length as usize,
     })
 }
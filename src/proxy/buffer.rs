@@ -0,0 +1,137 @@
+use once_cell::sync::OnceCell;
+use std::io::{Error, ErrorKind, Result};
+use std::ops::{Deref, DerefMut};
+use std::sync::Mutex;
+
+/// Default capacity of the pooled buffers, the largest TLS record
+pub const BUFFER_SIZE: usize = 16384;
+
+/// Smallest buffer size accepted from the configuration
+pub const MIN_BUFFER_SIZE: usize = 1024;
+
+/// Most idle buffers kept by the shared pool, the ones returned beyond are freed
+const MAX_IDLE: usize = 1024;
+
+/// Buffers grown beyond this many times their size are freed rather than returned, so that a few large packets don't
+/// pin memory for good
+const MAX_GROWTH: usize = 4;
+
+/// Pool shared by the copy loops and the UDP relays of the whole process
+static POOL: OnceCell<BufferPool> = OnceCell::new();
+
+/// Pool of reusable byte buffers. Connections and packets take their buffers from the pool and give them back when
+/// done, rather than allocating and freeing them each time, which dominates the profiles under load.
+pub struct BufferPool {
+    size: usize,
+    max_idle: usize,
+    idle: Mutex<Vec<Vec<u8>>>,
+}
+
+impl BufferPool {
+    /// Pool of buffers with room for size bytes, keeping up to max_idle of them around.
+    pub fn new(size: usize, max_idle: usize) -> Self {
+        Self {
+            size,
+            max_idle,
+            idle: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Build the pool shared by the whole process with buffers of the size, the default size if None. Fails if the
+    /// size is too small to hold a packet header.
+    pub fn init(size: Option<usize>) -> Result<&'static Self> {
+        let size = size.unwrap_or(BUFFER_SIZE);
+        if size < MIN_BUFFER_SIZE {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("relay_buffer_size must be at least {}", MIN_BUFFER_SIZE),
+            ));
+        }
+        Ok(POOL.get_or_init(|| Self::new(size, MAX_IDLE)))
+    }
+
+    /// Pool shared by the whole process, with buffers of the default size if it wasn't initialized yet.
+    #[inline]
+    pub fn get() -> &'static Self {
+        POOL.get_or_init(|| Self::new(BUFFER_SIZE, MAX_IDLE))
+    }
+
+    /// Capacity of the buffers of the pool.
+    #[inline]
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Empty buffer with room for at least the size of the pool.
+    pub fn take(&'static self) -> Buffer {
+        let buf = self
+            .idle
+            .lock()
+            .unwrap()
+            .pop()
+            .unwrap_or_else(|| Vec::with_capacity(self.size));
+        Buffer { buf, pool: self }
+    }
+
+    /// Buffer of len zeroed bytes, ready to be read into.
+    pub fn take_zeroed(&'static self, len: usize) -> Buffer {
+        let mut buf = self.take();
+        buf.resize(len, 0);
+        buf
+    }
+
+    /// Number of idle buffers in the pool.
+    pub fn idle(&self) -> usize {
+        self.idle.lock().unwrap().len()
+    }
+
+    fn put(&self, mut buf: Vec<u8>) {
+        if buf.capacity() < self.size || buf.capacity() > self.size * MAX_GROWTH {
+            return;
+        }
+        buf.clear();
+
+        let mut idle = self.idle.lock().unwrap();
+        if idle.len() < self.max_idle {
+            idle.push(buf);
+        }
+    }
+}
+
+/// Byte buffer going back to its pool once dropped.
+pub struct Buffer {
+    buf: Vec<u8>,
+    pool: &'static BufferPool,
+}
+
+impl Deref for Buffer {
+    type Target = Vec<u8>;
+
+    #[inline]
+    fn deref(&self) -> &Vec<u8> {
+        &self.buf
+    }
+}
+
+impl DerefMut for Buffer {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut Vec<u8> {
+        &mut self.buf
+    }
+}
+
+impl From<Vec<u8>> for Buffer {
+    /// Buffer going to the shared pool once dropped, if it has the right size.
+    fn from(buf: Vec<u8>) -> Self {
+        Self {
+            buf,
+            pool: BufferPool::get(),
+        }
+    }
+}
+
+impl Drop for Buffer {
+    fn drop(&mut self) {
+        self.pool.put(std::mem::take(&mut self.buf));
+    }
+}
@@ -0,0 +1,93 @@
+use crate::proxy::context::TrafficContext;
+use crate::proxy::relay::Transfer;
+
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::future::Future;
+use std::io::{Error, ErrorKind, Result};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use tokio_util::sync::CancellationToken;
+
+/// Connections being handled, by id, with the tokens closing them
+static ACTIVE: Lazy<Mutex<HashMap<u64, (ActiveConnection, CancellationToken)>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Connection being handled by the proxy, listed from the time it is routed until it ends, for the operators to see
+/// who is connected right now. The transport is the one of the inbound, tcp, quic or grpc, and the mode the one of the
+/// request, tcp for the connections relayed and udp for the UDP sessions.
+#[derive(Clone)]
+pub struct ActiveConnection {
+    pub id: u64,
+    pub source: SocketAddr,
+    pub context: TrafficContext,
+    pub transport: &'static str,
+    pub mode: &'static str,
+    pub transfer: Arc<Transfer>,
+}
+
+impl ActiveConnection {
+    /// List the connection among the active ones until the returned guard is dropped.
+    pub fn register(self) -> Registration {
+        let id = self.id;
+        let closed = CancellationToken::new();
+        ACTIVE.lock().unwrap().insert(id, (self, closed.clone()));
+        Registration { id, closed }
+    }
+}
+
+/// Keeps a connection listed among the active ones while it lives.
+pub struct Registration {
+    id: u64,
+    closed: CancellationToken,
+}
+
+impl Registration {
+    /// Run the handling of the connection until it ends, or fail it once the connection is closed on demand, which
+    /// drops the streams of the connection along with the future.
+    pub async fn until_closed<T, F: Future<Output = Result<T>>>(&self, future: F) -> Result<T> {
+        tokio::select! {
+            result = future => result,
+            _ = self.closed.cancelled() => Err(Error::new(
+                ErrorKind::ConnectionAborted,
+                "connection closed on demand",
+            )),
+        }
+    }
+
+    /// Whether the connection was closed on demand.
+    pub fn is_closed(&self) -> bool {
+        self.closed.is_cancelled()
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        ACTIVE.lock().unwrap().remove(&self.id);
+    }
+}
+
+/// Connections being handled, the oldest first.
+pub fn active_connections() -> Vec<ActiveConnection> {
+    let mut connections: Vec<_> = ACTIVE
+        .lock()
+        .unwrap()
+        .values()
+        .map(|(connection, _)| connection.clone())
+        .collect();
+    connections.sort_by_key(|connection| connection.id);
+    connections
+}
+
+/// Close the active connections matching the filter, returns the number of connections closed. They stay listed until
+/// their handling ends, which happens as soon as it is polled again.
+pub fn close_connections<F: Fn(&ActiveConnection) -> bool>(filter: F) -> usize {
+    let mut closed = 0;
+    for (connection, token) in ACTIVE.lock().unwrap().values() {
+        if filter(connection) && !token.is_cancelled() {
+            token.cancel();
+            closed += 1;
+        }
+    }
+    closed
+}
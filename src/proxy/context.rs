@@ -0,0 +1,97 @@
+use crate::metrics::escape_label;
+
+use std::fmt;
+use std::future::Future;
+
+tokio::task_local! {
+    /// Context of the connection handled by the task
+    static CURRENT: TrafficContext;
+}
+
+/// Inbound, outbound and user the traffic of a connection is attributed to. The context is set for the task handling
+/// the connection once it is routed, so that the traffic counted and the lines logged along the way carry it
+/// without passing it down to every function. The id and the destination of the connection are only kept for the
+/// logs, they are neither displayed with the context nor part of its labels.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TrafficContext {
+    pub inbound: Option<String>,
+    pub outbound: Option<String>,
+    pub user: Option<String>,
+    pub destination: Option<String>,
+    pub connection: Option<u64>,
+}
+
+impl TrafficContext {
+    pub fn new(inbound: Option<&str>, outbound: Option<&str>, user: Option<&str>) -> Self {
+        Self {
+            inbound: inbound.map(str::to_string),
+            outbound: outbound.map(str::to_string),
+            user: user.map(str::to_string),
+            destination: None,
+            connection: None,
+        }
+    }
+
+    /// Context with the id of the connection, the one its events are reported with.
+    pub fn with_connection(mut self, id: u64) -> Self {
+        self.connection = Some(id);
+        self
+    }
+
+    /// Context with the destination of the connection.
+    pub fn with_destination(mut self, destination: String) -> Self {
+        self.destination = Some(destination);
+        self
+    }
+
+    /// Run the future with the context as the current one.
+    pub async fn scope<F: Future>(self, future: F) -> F::Output {
+        CURRENT.scope(self, future).await
+    }
+
+    /// Context of the connection handled by the current task, if any.
+    pub fn current() -> Option<Self> {
+        CURRENT.try_with(|context| context.clone()).ok()
+    }
+
+    /// Prometheus labels of the context, the missing values are empty.
+    pub fn labels(&self) -> String {
+        format!(
+            "inbound=\"{}\",outbound=\"{}\",user=\"{}\"",
+            escape_label(self.inbound.as_deref().unwrap_or_default()),
+            escape_label(self.outbound.as_deref().unwrap_or_default()),
+            escape_label(self.user.as_deref().unwrap_or_default())
+        )
+    }
+
+    /// Add the labels of the context to the metric name, after the labels it already has.
+    pub fn label(&self, name: &str) -> String {
+        match name.strip_suffix('}') {
+            Some(name) => format!("{},{}}}", name, self.labels()),
+            None => format!("{}{{{}}}", name, self.labels()),
+        }
+    }
+}
+
+impl fmt::Display for TrafficContext {
+    /// Only the known values, like inbound=socks outbound=proxy user=alice
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let values = [
+            ("inbound", &self.inbound),
+            ("outbound", &self.outbound),
+            ("user", &self.user),
+        ];
+
+        let mut first = true;
+        for (key, value) in values {
+            if let Some(value) = value {
+                if !first {
+                    fmt.write_str(" ")?;
+                }
+                write!(fmt, "{}={}", key, value)?;
+                first = false;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,139 @@
+use crate::config::base::InboundConfig;
+use crate::metrics;
+use crate::proxy::policy::Policies;
+
+use log::debug;
+use std::future::Future;
+use std::io::{Error, ErrorKind, Result};
+use std::time::Duration;
+use tokio::time::error::Elapsed;
+use tokio::time::{timeout_at, Instant};
+use tracing::field::{display, Empty};
+use tracing::{info_span, Instrument};
+
+/// Default time budget of a proxy request in seconds
+const DEFAULT_REQUEST_DEADLINE: u64 = 30;
+
+/// Stages bounded by the handshake timeout of the policy on top of the deadline
+const HANDSHAKE_STAGES: [&str; 2] = ["tls", "handshake"];
+
+/// Deadline is created when a connection is accepted and carried through all the stages needed to set up the proxy
+/// request, like TLS, the proxy handshake, DNS resolution and dialing the destination. Every stage runs with the time
+/// left in the budget rather than a timeout of its own, so that the client always sees the request succeed or fail
+/// within the configured time. Data transfer after the setup is not bounded by the deadline. Stages can be bounded
+/// further by the timeouts of the policy, which fail the stage early without waiting for the whole budget. The TLS and
+/// proxy handshake stages share the handshake timeout, which counts from the connection being accepted, so that a
+/// client stalling anywhere in the handshakes is dropped once it runs out.
+#[derive(Clone, Copy, Debug)]
+pub struct Deadline {
+    at: Instant,
+    handshake_at: Option<Instant>,
+}
+
+impl Deadline {
+    /// Start the deadline of a new request accepted by the inbound, using request_deadline of the configuration and
+    /// the handshake timeout of the policy of the inbound.
+    pub fn new(inbound: &InboundConfig) -> Self {
+        let policy = Policies::get().policy(inbound.policy.as_deref());
+        Self::after(Duration::from_secs(
+            inbound.request_deadline.unwrap_or(DEFAULT_REQUEST_DEADLINE),
+        ))
+        .with_handshake_timeout(policy.handshake_timeout)
+    }
+
+    #[inline]
+    pub fn after(budget: Duration) -> Self {
+        Self {
+            at: Instant::now() + budget,
+            handshake_at: None,
+        }
+    }
+
+    /// Bound the TLS and proxy handshake stages by the timeout from now on as well, all of them together.
+    #[inline]
+    pub fn with_handshake_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.handshake_at = timeout.map(|timeout| Instant::now() + timeout);
+        self
+    }
+
+    /// Time left before the deadline expires.
+    #[inline]
+    pub fn remaining(&self) -> Duration {
+        self.at.saturating_duration_since(Instant::now())
+    }
+
+    /// Run one stage of the request setup, fails with TimedOut if the deadline expires before the stage is done.
+    pub async fn run<T, F: Future<Output = Result<T>>>(&self, stage: &str, future: F) -> Result<T> {
+        let at = match self.handshake_at {
+            Some(handshake_at) if HANDSHAKE_STAGES.contains(&stage) => self.at.min(handshake_at),
+            _ => self.at,
+        };
+        self.run_until(stage, at, future).await
+    }
+
+    /// Run one stage of the request setup, fails with TimedOut if the stage isn't done within the timeout or before
+    /// the deadline expires, whichever comes first.
+    pub async fn run_within<T, F: Future<Output = Result<T>>>(
+        &self,
+        stage: &str,
+        timeout: Option<Duration>,
+        future: F,
+    ) -> Result<T> {
+        let at = match timeout {
+            Some(timeout) => self.at.min(Instant::now() + timeout),
+            None => self.at,
+        };
+        self.run_until(stage, at, future).await
+    }
+
+    /// Run one stage of the request setup, fails with TimedOut if it isn't done at the instant, which is no later
+    /// than the deadline.
+    async fn run_until<T, F: Future<Output = Result<T>>>(
+        &self,
+        stage: &str,
+        at: Instant,
+        future: F,
+    ) -> Result<T> {
+        let span = info_span!("stage", otel.name = stage, otel.status_message = Empty);
+        let result = self.expire(
+            stage,
+            at,
+            timeout_at(at, future).instrument(span.clone()).await,
+        );
+        if let Err(e) = &result {
+            span.record("otel.status_message", display(e));
+        }
+        result
+    }
+
+    /// Result of the stage, or the error of the timeout if it expired at the instant.
+    fn expire<T>(
+        &self,
+        stage: &str,
+        at: Instant,
+        result: std::result::Result<Result<T>, Elapsed>,
+    ) -> Result<T> {
+        match result {
+            Ok(result) => result,
+            Err(_) if at < self.at => {
+                debug!("Timed out during {}", stage);
+                metrics::increment_attributed(&format!("timeouts_total{{stage=\"{}\"}}", stage), 1);
+                Err(Error::new(
+                    ErrorKind::TimedOut,
+                    format!("{} timed out", stage),
+                ))
+            }
+            Err(_) => {
+                debug!("Deadline exceeded during {}", stage);
+                metrics::increment(
+                    &format!("deadline_exceeded_total{{stage=\"{}\"}}", stage),
+                    1,
+                );
+                Err(Error::new(
+                    ErrorKind::TimedOut,
+                    format!("request deadline exceeded during {}", stage),
+                ))
+            }
+        }
+    }
+}
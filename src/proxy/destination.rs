@@ -0,0 +1,174 @@
+use crate::config::base::InboundConfig;
+use crate::metrics;
+
+use ipnet::IpNet;
+use log::{debug, info, warn};
+use once_cell::sync::OnceCell;
+use std::io::{Error, ErrorKind, Result};
+use std::net::{IpAddr, SocketAddr};
+
+/// Static destination filter of the inbound shared by all the outbounds
+static DESTINATION_FILTER: OnceCell<DestinationFilter> = OnceCell::new();
+
+/// Keeps the clients of the inbound from reaching the internal services of the host and its network through the
+/// direct outbounds. With block_private enabled, connections and datagrams to the private, loopback, link local and
+/// unspecified addresses are refused, along with the addresses of the network interfaces of the host, unless they are
+/// in allowed_destinations. The destinations are checked once resolved, so that domains pointing at such addresses are
+/// refused as well. Refused destinations are counted in destinations_blocked_total.
+pub struct DestinationFilter {
+    enabled: bool,
+    allowed: Vec<IpNet>,
+    own: Vec<IpAddr>,
+}
+
+impl DestinationFilter {
+    /// Build the filter shared by the whole process from the inbound configuration.
+    pub fn init(inbound: &InboundConfig) -> Result<&'static Self> {
+        DESTINATION_FILTER.get_or_try_init(|| {
+            let filter = Self::new(inbound)?;
+            if filter.enabled {
+                info!(
+                    "Blocking the private destinations and {} addresses of the host",
+                    filter.own.len()
+                );
+            }
+            Ok(filter)
+        })
+    }
+
+    /// Filter shared by the whole process, allowing every destination if it wasn't initialized.
+    pub fn get() -> &'static Self {
+        DESTINATION_FILTER.get_or_init(|| Self {
+            enabled: false,
+            allowed: Vec::new(),
+            own: Vec::new(),
+        })
+    }
+
+    /// Fails with InvalidInput if an allowed destination is neither an address nor a CIDR range.
+    pub fn new(inbound: &InboundConfig) -> Result<Self> {
+        let enabled = inbound.block_private.unwrap_or(false);
+        let allowed = inbound
+            .allowed_destinations
+            .iter()
+            .flatten()
+            .map(|ip| match ip.parse::<IpNet>() {
+                Ok(net) => Ok(net),
+                Err(_) => match ip.parse::<IpAddr>() {
+                    Ok(addr) => Ok(IpNet::from(addr)),
+                    Err(e) => Err(Error::new(
+                        ErrorKind::InvalidInput,
+                        format!("invalid allowed destination {}: {}", ip, e),
+                    )),
+                },
+            })
+            .collect::<Result<_>>()?;
+
+        let own = match enabled {
+            true => interface_addresses(),
+            false => Vec::new(),
+        };
+
+        Ok(Self {
+            enabled,
+            allowed,
+            own,
+        })
+    }
+
+    /// Whether the clients may reach the address.
+    pub fn allows(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        !self.enabled
+            || self.allowed.iter().any(|net| net.contains(&ip))
+            || !(is_private(ip) || self.own.contains(&ip))
+    }
+
+    /// Fails with PermissionDenied if the clients may not reach the destination, counting it for the transport.
+    pub fn check(&self, dest: SocketAddr, transport: &str) -> Result<()> {
+        if self.allows(dest.ip()) {
+            return Ok(());
+        }
+
+        debug!(
+            "Refusing {} request to private destination {}",
+            transport, dest
+        );
+        metrics::increment(
+            &format!("destinations_blocked_total{{transport=\"{}\"}}", transport),
+            1,
+        );
+        Err(Error::new(
+            ErrorKind::PermissionDenied,
+            format!("destination {} is private", dest),
+        ))
+    }
+}
+
+/// Whether the address is private, loopback, link local or unspecified, which reaches the host itself.
+fn is_private(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            ip.is_private() || ip.is_loopback() || ip.is_link_local() || ip.is_unspecified()
+        }
+        IpAddr::V6(ip) => {
+            // Unique local fc00::/7 and link local fe80::/10
+            ip.is_loopback()
+                || ip.is_unspecified()
+                || (ip.segments()[0] & 0xfe00) == 0xfc00
+                || (ip.segments()[0] & 0xffc0) == 0xfe80
+        }
+    }
+}
+
+/// Addresses of the network interfaces of the host.
+#[cfg(target_os = "linux")]
+fn interface_addresses() -> Vec<IpAddr> {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    let mut addresses = Vec::new();
+    let mut interfaces: *mut libc::ifaddrs = std::ptr::null_mut();
+
+    // Safety: the list is only read before it is freed, and the addresses are read as the type of their family
+    unsafe {
+        if libc::getifaddrs(&mut interfaces) != 0 {
+            warn!(
+                "Failed to list the addresses of the host: {}",
+                Error::last_os_error()
+            );
+            return addresses;
+        }
+
+        let mut interface = interfaces;
+        while !interface.is_null() {
+            let addr = (*interface).ifa_addr;
+            if !addr.is_null() {
+                match (*addr).sa_family as libc::c_int {
+                    libc::AF_INET => {
+                        let addr = &*(addr as *const libc::sockaddr_in);
+                        addresses.push(IpAddr::V4(Ipv4Addr::from(u32::from_be(
+                            addr.sin_addr.s_addr,
+                        ))));
+                    }
+                    libc::AF_INET6 => {
+                        let addr = &*(addr as *const libc::sockaddr_in6);
+                        addresses.push(IpAddr::V6(Ipv6Addr::from(addr.sin6_addr.s6_addr)));
+                    }
+                    _ => (),
+                }
+            }
+            interface = (*interface).ifa_next;
+        }
+
+        libc::freeifaddrs(interfaces);
+    }
+
+    addresses
+}
+
+/// Addresses of the network interfaces of the host, which aren't listed elsewhere than on Linux, only the private
+/// ranges are blocked there.
+#[cfg(not(target_os = "linux"))]
+fn interface_addresses() -> Vec<IpAddr> {
+    Vec::new()
+}
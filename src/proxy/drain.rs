@@ -0,0 +1,80 @@
+use crate::metrics;
+
+use log::info;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+/// Sessions relayed by an outbound, and whether the outbound is draining. An outbound about to be taken out of
+/// rotation is marked draining, the outbound groups then stop picking it for new sessions while the sessions it
+/// already relays run to completion. The number of live sessions is exported as the outbound_sessions_active gauge
+/// labelled with the outbound, so that the server can be rotated out once it reaches zero.
+pub struct SessionDrain {
+    tag: String,
+    gauge: String,
+    draining: AtomicBool,
+    active: AtomicUsize,
+}
+
+impl SessionDrain {
+    pub fn new(tag: &str) -> Self {
+        Self {
+            tag: tag.to_string(),
+            gauge: format!(
+                "outbound_sessions_active{{outbound=\"{}\"}}",
+                metrics::escape_label(tag)
+            ),
+            draining: AtomicBool::new(false),
+            active: AtomicUsize::new(0),
+        }
+    }
+
+    /// Count a new session until the returned handle is dropped.
+    pub fn start(&self) -> DrainSession<'_> {
+        let active = self.active.fetch_add(1, Ordering::Relaxed) + 1;
+        metrics::set(&self.gauge, active as u64);
+        DrainSession { drain: self }
+    }
+
+    /// Mark the outbound as draining or put it back in rotation.
+    pub fn set_draining(&self, draining: bool) {
+        if self.draining.swap(draining, Ordering::Relaxed) != draining {
+            match draining {
+                true => info!(
+                    "Draining outbound {}, {} sessions remaining",
+                    self.tag,
+                    self.active()
+                ),
+                false => info!("Outbound {} is back in rotation", self.tag),
+            }
+        }
+    }
+
+    #[inline]
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed)
+    }
+
+    /// Number of live sessions.
+    #[inline]
+    pub fn active(&self) -> usize {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    fn finish(&self) {
+        let active = self.active.fetch_sub(1, Ordering::Relaxed) - 1;
+        metrics::set(&self.gauge, active as u64);
+        if active == 0 && self.is_draining() {
+            info!("Outbound {} is drained", self.tag);
+        }
+    }
+}
+
+/// Live session of an outbound, counted until dropped.
+pub struct DrainSession<'a> {
+    drain: &'a SessionDrain,
+}
+
+impl Drop for DrainSession<'_> {
+    fn drop(&mut self) {
+        self.drain.finish();
+    }
+}
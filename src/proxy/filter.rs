@@ -0,0 +1,191 @@
+use crate::config::base::IpFilterConfig;
+use crate::metrics;
+
+use ipnet::IpNet;
+use log::{debug, info, warn};
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use std::time::{Duration, SystemTime};
+
+/// Interval the files of the filters are checked for changes at
+const RELOAD_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Filters with files, which are reloaded when the files change
+static FILTERS: Mutex<Vec<Weak<IpFilter>>> = Mutex::new(Vec::new());
+
+/// Allow and deny lists of address ranges, for the client addresses accepted by the inbound or the destinations the
+/// outbounds connect to. An address in the deny list is refused, and so is an address missing from the allow list if
+/// there is one. The lists are given in the configuration and in files with one address or CIDR range per line, the
+/// files are reloaded when they change, keeping the lists in use if they fail to load. Refused addresses are counted
+/// in ip_filter_rejections_total.
+#[derive(Debug)]
+pub struct IpFilter {
+    scope: &'static str,
+    config: IpFilterConfig,
+    lists: RwLock<Lists>,
+    modified: Mutex<Vec<Option<SystemTime>>>,
+}
+
+#[derive(Debug)]
+struct Lists {
+    allow: Option<Vec<IpNet>>,
+    deny: Vec<IpNet>,
+}
+
+impl IpFilter {
+    /// Build the filter of the scope, source or destination, from the config. Fails with InvalidInput if the lists
+    /// fail to load.
+    pub fn new(config: &IpFilterConfig, scope: &'static str) -> Result<Arc<Self>> {
+        let lists = load(config).map_err(|e| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!("failed to load the {} address filter: {}", scope, e),
+            )
+        })?;
+
+        let filter = Arc::new(Self {
+            scope,
+            config: config.clone(),
+            lists: RwLock::new(lists),
+            modified: Mutex::new(modified_times(config)),
+        });
+        if config.allow_file.is_some() || config.deny_file.is_some() {
+            FILTERS.lock().unwrap().push(Arc::downgrade(&filter));
+        }
+        Ok(filter)
+    }
+
+    /// Whether the address passes the filter.
+    pub fn allows(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        let lists = self.lists.read().unwrap();
+
+        let allowed = !lists.deny.iter().any(|net| net.contains(&ip))
+            && match &lists.allow {
+                Some(allow) => allow.iter().any(|net| net.contains(&ip)),
+                None => true,
+            };
+        if !allowed {
+            debug!("Refusing {} address {}", self.scope, ip);
+            metrics::increment(
+                &format!("ip_filter_rejections_total{{scope=\"{}\"}}", self.scope),
+                1,
+            );
+        }
+        allowed
+    }
+
+    /// Fails with PermissionDenied if the address doesn't pass the filter.
+    pub fn check(&self, ip: IpAddr) -> Result<()> {
+        match self.allows(ip) {
+            true => Ok(()),
+            false => Err(Error::new(
+                ErrorKind::PermissionDenied,
+                format!("{} address {} is filtered", self.scope, ip),
+            )),
+        }
+    }
+
+    /// Load the lists again if their files changed since they were loaded, returns whether they were reloaded. The
+    /// lists in use are kept if the files fail to load.
+    pub fn reload(&self) -> Result<bool> {
+        let modified = modified_times(&self.config);
+        if *self.modified.lock().unwrap() == modified {
+            return Ok(false);
+        }
+
+        let lists = load(&self.config)?;
+        *self.lists.write().unwrap() = lists;
+        *self.modified.lock().unwrap() = modified;
+        Ok(true)
+    }
+}
+
+/// Reload the filters whose files changed every RELOAD_INTERVAL, for the lifetime of the process.
+pub async fn run_reloads() {
+    let mut ticker = tokio::time::interval(RELOAD_INTERVAL);
+    ticker.tick().await;
+
+    loop {
+        ticker.tick().await;
+
+        let filters: Vec<_> = {
+            let mut filters = FILTERS.lock().unwrap();
+            filters.retain(|filter| filter.strong_count() > 0);
+            filters.iter().filter_map(Weak::upgrade).collect()
+        };
+        for filter in filters {
+            match filter.reload() {
+                Ok(true) => {
+                    info!("Reloaded the {} address filter", filter.scope);
+                    metrics::increment("ip_filter_reloads_total", 1);
+                }
+                Ok(false) => (),
+                Err(e) => {
+                    warn!(
+                        "Failed to reload the {} address filter: {}",
+                        filter.scope, e
+                    );
+                    metrics::increment("ip_filter_reload_failures_total", 1);
+                }
+            }
+        }
+    }
+}
+
+/// Lists of the config along with the ones of its files.
+fn load(config: &IpFilterConfig) -> Result<Lists> {
+    let allow = match (&config.allow, &config.allow_file) {
+        (None, None) => None,
+        (allow, file) => Some(entries(allow.as_deref(), file.as_deref())?),
+    };
+    let deny = entries(config.deny.as_deref(), config.deny_file.as_deref())?;
+    Ok(Lists { allow, deny })
+}
+
+/// Parse the entries given inline and in the file, skipping empty lines and # comments of the file.
+fn entries(inline: Option<&[String]>, file: Option<&str>) -> Result<Vec<IpNet>> {
+    let content = match file {
+        Some(path) => fs::read_to_string(path)
+            .map_err(|e| Error::new(e.kind(), format!("failed to read {}: {}", path, e)))?,
+        None => String::new(),
+    };
+    let lines = content
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or_default().trim())
+        .filter(|line| !line.is_empty());
+
+    inline
+        .iter()
+        .flat_map(|entries| entries.iter().map(String::as_str))
+        .chain(lines)
+        .map(parse_net)
+        .collect()
+}
+
+/// Parse an entry in CIDR notation or a plain IP address.
+fn parse_net(entry: &str) -> Result<IpNet> {
+    match entry.parse::<IpNet>() {
+        Ok(net) => Ok(net),
+        Err(_) => match entry.parse::<IpAddr>() {
+            Ok(addr) => Ok(IpNet::from(addr)),
+            Err(e) => Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("invalid address {}: {}", entry, e),
+            )),
+        },
+    }
+}
+
+/// Modification times of the files of the config, None for the ones missing.
+fn modified_times(config: &IpFilterConfig) -> Vec<Option<SystemTime>> {
+    [&config.allow_file, &config.deny_file]
+        .into_iter()
+        .map(|file| {
+            file.as_ref()
+                .and_then(|path| fs::metadata(path).and_then(|m| m.modified()).ok())
+        })
+        .collect()
+}
@@ -1,4 +1,5 @@
 use crate::{
+    auth::AuthChain,
     config::base::InboundConfig,
     protocol::{common::request::InboundRequest, trojan},
     proxy::base::SupportedProtocols,
@@ -6,7 +7,6 @@ use crate::{
 };
 
 use once_cell::sync::OnceCell;
-use sha2::{Digest, Sha224};
 use std::io::{self, Error, ErrorKind};
 use tonic::{Request, Streaming};
 
@@ -18,28 +18,17 @@ static GRPC_ACCEPTOR: OnceCell<GrpcAcceptor> = OnceCell::new();
 /// enabled TLS.
 pub struct GrpcAcceptor {
     protocol: SupportedProtocols,
-    secret: Vec<u8>,
+    auth: &'static AuthChain,
 }
 
 /// GrpcAcceptor should implment 2 types of GRPC transport protocol, Hunk and MultiHunk.
 impl GrpcAcceptor {
-    pub fn new(inbound_config: &InboundConfig) -> &'static GrpcAcceptor {
-        let secret = match inbound_config.protocol {
-            SupportedProtocols::TROJAN if inbound_config.secret.is_some() => {
-                let secret = inbound_config.secret.as_ref().unwrap();
-                Sha224::digest(secret.as_bytes())
-                    .iter()
-                    .map(|x| format!("{:02x}", x))
-                    .collect::<String>()
-                    .as_bytes()
-                    .to_vec()
-            }
-            _ => Vec::new(),
-        };
-
-        GRPC_ACCEPTOR.get_or_init(|| Self {
-            protocol: inbound_config.protocol,
-            secret,
+    pub fn new(inbound_config: &InboundConfig) -> io::Result<&'static GrpcAcceptor> {
+        GRPC_ACCEPTOR.get_or_try_init(|| {
+            Ok(Self {
+                protocol: inbound_config.protocol,
+                auth: AuthChain::init(inbound_config)?,
+            })
         })
     }
 
@@ -49,6 +38,7 @@ impl GrpcAcceptor {
         request: Request<Streaming<Hunk>>,
     ) -> io::Result<(InboundRequest, GrpcDataReaderStream<Hunk>)> {
         // Convert request into inbound reader stream
+        let source = request.remote_addr();
         let mut inbound_reader = GrpcDataReaderStream::from_reader(request.into_inner());
 
         // Based on the protocol, decide how to proceed with the inbound stream
@@ -57,15 +47,11 @@ impl GrpcAcceptor {
                 // Read trojan request from the inbound stream
                 let trojan_request = trojan::parse(&mut inbound_reader).await?;
 
-                // Validate trojan request before dispatching
-                if !trojan_request.validate(&self.secret) {
-                    return Err(Error::new(
-                        ErrorKind::InvalidData,
-                        "Incorrect trojan credentials",
-                    ));
-                }
-
-                trojan_request.into_request()
+                // Authenticate trojan request before dispatching
+                let hex = trojan_request.hex().to_vec();
+                let mut request = trojan_request.into_request();
+                request.user = Some(self.auth.authenticate(&hex, source, &request).await?);
+                request
             }
             // TODO: Support more protocols than just Trojan
             _ => return Err(Error::new(ErrorKind::Unsupported, "Unsupported protocol")),
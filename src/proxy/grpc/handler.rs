@@ -1,5 +1,12 @@
+use crate::auth::port;
+use crate::config::base::{OutboundConfig, UdpConfig};
 use crate::protocol::common::addr::IpAddress;
 use crate::protocol::trojan::{self, CRLF};
+use crate::proxy::deadline::Deadline;
+use crate::proxy::policy::{Policies, Policy};
+use crate::proxy::relay::Transfer;
+use crate::proxy::tcp::handler::TcpHandler;
+use crate::proxy::udp::guard::UdpGuard;
 use crate::{
     protocol::common::request::InboundRequest,
     proxy::base::SupportedProtocols,
@@ -9,9 +16,10 @@ use crate::{
 use bytes::BufMut;
 use once_cell::sync::OnceCell;
 use std::io::{self, Error, ErrorKind};
-use std::net::{IpAddr, SocketAddr};
+use std::net::IpAddr;
+use std::sync::Arc;
 use tokio::io::{AsyncRead, AsyncReadExt};
-use tokio::net::{TcpStream, UdpSocket};
+use tokio::net::UdpSocket;
 use tokio::sync::mpsc::Sender;
 use tonic::Status;
 
@@ -24,48 +32,48 @@ static GRPC_HANDLER: OnceCell<GrpcHandler> = OnceCell::new();
 /// GrpcHandler is responsible for handling outbound traffic for GRPC inbound streams
 pub struct GrpcHandler {
     protocol: SupportedProtocols,
+    udp: Option<UdpConfig>,
+    policy: &'static Policy,
 }
 
 impl GrpcHandler {
-    pub fn new() -> &'static GrpcHandler {
+    pub fn new(outbound_config: &OutboundConfig) -> &'static GrpcHandler {
         GRPC_HANDLER.get_or_init(|| Self {
             protocol: SupportedProtocols::TROJAN,
+            udp: outbound_config.udp.clone(),
+            policy: Policies::get().policy(outbound_config.policy.as_deref()),
         })
     }
 
+    /// Relay the UDP session of the GRPC stream to its destinations.
     pub async fn handle_hunk(
         &self,
-        mut client_reader: GrpcDataReaderStream<Hunk>,
+        client_reader: GrpcDataReaderStream<Hunk>,
         client_writer: Sender<Result<Hunk, Status>>,
         request: InboundRequest,
     ) -> io::Result<()> {
         match self.protocol {
             SupportedProtocols::TROJAN => {
                 return match request.command {
-                    crate::protocol::common::command::Command::Connect => {
-                        let ip_port: SocketAddr = request.addr_port.into();
-
-                        // Establish connection to remote server as specified by proxy request
-                        let (mut server_reader, mut server_writer) =
-                            match TcpStream::connect(ip_port).await {
-                                Ok(stream) => tokio::io::split(stream),
-                                Err(e) => return Err(e),
-                            };
-
-                        tokio::select!(
-                            _ = tokio::io::copy(&mut client_reader, &mut server_writer) => (),
-                            _ = copy_server_reader_to_client_grpc_writer(&mut server_reader, client_writer) => (),
-                        );
-
-                        Ok(())
-                    }
+                    // The TCP requests go through the handler of their outbound like on the other inbounds
+                    crate::protocol::common::command::Command::Connect => Err(Error::new(
+                        ErrorKind::InvalidInput,
+                        "TCP requests are relayed by the handlers of the outbounds",
+                    )),
                     crate::protocol::common::command::Command::Udp => {
                         // Establish UDP connection to remote host
-                        let socket = UdpSocket::bind("0.0.0.0:0").await?;
+                        let socket = Arc::new(UdpSocket::bind("0.0.0.0:0").await?);
+                        let guard = Arc::new(
+                            UdpGuard::new(self.udp.as_ref())
+                                .with_destinations(self.policy.destinations.clone()),
+                        );
+                        if guard.broadcast() {
+                            socket.set_broadcast(true)?;
+                        }
 
                         tokio::select!(
-                            _ = trojan::packet::copy_client_reader_to_udp_socket(client_reader, &socket) => (),
-                            _ = copy_udp_socket_to_client_grpc_writer(&socket, client_writer, request) => ()
+                            _ = trojan::packet::copy_client_reader_to_udp_socket(client_reader, &socket, &guard) => (),
+                            _ = copy_udp_socket_to_client_grpc_writer(&socket, client_writer, request, &guard) => ()
                         );
 
                         Ok(())
@@ -89,6 +97,22 @@ impl GrpcHandler {
     }
 }
 
+/// Relay the GRPC stream of the client through the handler of the outbound the request is routed to.
+pub async fn dispatch_hunk(
+    handler: &TcpHandler,
+    client_reader: GrpcDataReaderStream<Hunk>,
+    client_writer: Sender<Result<Hunk, Status>>,
+    request: InboundRequest,
+    deadline: Deadline,
+) -> io::Result<()> {
+    port::check_request(&request, "grpc")?;
+    handler
+        .dispatch_piped(request, deadline, client_reader, |server_reader| {
+            copy_server_reader_to_client_grpc_writer(server_reader, client_writer)
+        })
+        .await
+}
+
 async fn copy_server_reader_to_client_grpc_writer<R: AsyncRead + Unpin>(
     mut reader: R,
     writer: Sender<Result<Hunk, Status>>,
@@ -97,6 +121,7 @@ async fn copy_server_reader_to_client_grpc_writer<R: AsyncRead + Unpin>(
         let mut buf = Vec::with_capacity(BUFFER_SIZE);
 
         match reader.read_buf(&mut buf).await {
+            Ok(0) => return Ok(()),
             Ok(_) => (),
             Err(_) => {
                 return Err(io::Error::new(
@@ -122,12 +147,14 @@ async fn copy_udp_socket_to_client_grpc_writer(
     udp_socket: &UdpSocket,
     client_sender: Sender<Result<Hunk, Status>>,
     request: InboundRequest,
+    guard: &UdpGuard,
 ) -> io::Result<()> {
     let mut udp_buffer = vec![0u8; BUFFER_SIZE];
 
     loop {
-        let n = match udp_socket.recv(&mut udp_buffer).await {
-            Ok(n) => n,
+        let n = match udp_socket.recv_from(&mut udp_buffer).await {
+            Ok((n, source)) if guard.check_reply(source, n) => n,
+            Ok(_) => continue,
             Err(_) => {
                 return Err(io::Error::new(
                     io::ErrorKind::BrokenPipe,
@@ -150,7 +177,7 @@ async fn copy_udp_socket_to_client_grpc_writer(
                 buf.put_slice(&addr.octets());
             }
             IpAddress::Domain(ref domain) => {
-                buf.put_u8(domain.as_bytes().len() as u8);
+                buf.put_u8(domain.encoded_len()?);
                 buf.put_slice(domain.as_bytes());
             }
         }
@@ -161,6 +188,7 @@ async fn copy_udp_socket_to_client_grpc_writer(
         buf.put_u16(CRLF);
         buf.put_slice(&udp_buffer[..n]);
 
+        Transfer::count(0, n as u64);
         match client_sender.send(Ok(Hunk { data: buf })).await {
             Ok(_) => (),
             Err(_) => {
@@ -1,19 +1,37 @@
 use crate::config::base::{InboundConfig, OutboundConfig};
+use crate::events;
+use crate::health;
+use crate::metrics;
+use crate::metrics::access::AccessLog;
+use crate::protocol::common::request::TransportProtocol;
+use crate::proxy::connections::ActiveConnection;
+use crate::proxy::context::TrafficContext;
+use crate::proxy::deadline::Deadline;
+use crate::proxy::filter::IpFilter;
+use crate::proxy::limiter::{acquire_slots, ConcurrencyLimit, ConnectionSlot};
+use crate::proxy::relay::Transfer;
+use crate::router::{RouteContext, Router, DEFAULT_OUTBOUND_TAG};
 use crate::transport::grpc_transport::grpc_service_server::GrpcService;
 use crate::transport::grpc_transport::grpc_service_server::GrpcServiceServer;
 use crate::transport::grpc_transport::{Hunk, MultiHunk};
 
-use futures::Stream;
+use futures::{stream, Stream};
 use log::{info, warn};
 use std::io::{self, Error, ErrorKind};
-use std::net::ToSocketAddrs;
+use std::net::{SocketAddr, ToSocketAddrs};
 use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
 use tokio::sync::mpsc;
+use tonic::transport::server::{Connected, TcpConnectInfo};
 use tonic::transport::{Identity, Server, ServerTlsConfig};
 use tonic::{Request, Response, Status, Streaming};
+use tracing::field::Empty;
+use tracing::{info_span, Instrument};
 
 use super::acceptor::GrpcAcceptor;
-use super::handler::GrpcHandler;
+use super::handler::{dispatch_hunk, GrpcHandler};
 
 // TODO: Need more discretion in detemining the value for channel size, or make it configurable
 const CHANNEL_SIZE: usize = 16;
@@ -21,7 +39,8 @@ const CHANNEL_SIZE: usize = 16;
 /// Start running the GRPC server
 pub async fn start(
     inbound_config: &'static InboundConfig,
-    _outbound_config: &'static OutboundConfig,
+    outbound_config: &'static OutboundConfig,
+    router: &'static Router,
 ) -> io::Result<()> {
     // Extract the address that the server should listen on
     let address = match (inbound_config.address.as_ref(), inbound_config.port)
@@ -49,18 +68,71 @@ pub async fn start(
 
     // Initialize and start the GRPC server to serve GRPC requests
     let mut server = match tls_config {
-        Some(cfg) => Server::builder()
-            .tls_config(cfg)
-            .expect("Failed to build GRPC server"),
+        Some(cfg) => Server::builder().tls_config(cfg).map_err(|e| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!("Failed to build GRPC server: {}", e),
+            )
+        })?,
         None => Server::builder(),
     };
 
+    let listener = TcpListener::bind(address).await?;
+    health::mark_listening();
+
+    // The clients refused by the source filter or over the connection caps are dropped before their TLS handshake,
+    // the listener being the whole inbound
+    let sources = inbound_config
+        .sources
+        .as_ref()
+        .map(|sources| IpFilter::new(sources, "source"))
+        .transpose()?;
+    let connections = match &inbound_config.connection_limit {
+        Some(config) => [
+            ConcurrencyLimit::for_listener(config, listener.local_addr()?)?,
+            ConcurrencyLimit::for_inbound(config)?,
+        ],
+        None => [None, None],
+    };
+    let incoming = stream::unfold(
+        (listener, sources, connections),
+        |(listener, sources, connections)| async move {
+            let limits: Vec<_> = connections.iter().flatten().collect();
+            loop {
+                let (socket, addr) = match listener.accept().await {
+                    Ok(accepted) => accepted,
+                    Err(e) => break Some((Err(e), (listener, sources, connections))),
+                };
+                if !sources
+                    .as_ref()
+                    .is_none_or(|sources| sources.allows(addr.ip()))
+                {
+                    continue;
+                }
+                // Held until the connection is closed
+                if let Some(slots) = acquire_slots(&limits).await {
+                    let stream = SlottedStream {
+                        stream: socket,
+                        _slots: slots,
+                    };
+                    break Some((Ok(stream), (listener, sources, connections)));
+                }
+            }
+        },
+    );
+
     return match server
         .add_service(GrpcServiceServer::new(GrpcProxyService::new(
-            GrpcAcceptor::new(&inbound_config),
-            GrpcHandler::new(),
+            inbound_config,
+            GrpcAcceptor::new(&inbound_config)?,
+            GrpcHandler::new(outbound_config),
+            outbound_config
+                .tag
+                .as_deref()
+                .unwrap_or(DEFAULT_OUTBOUND_TAG),
+            router,
         )))
-        .serve(address)
+        .serve_with_incoming(incoming)
         .await
     {
         Ok(_) => Ok(()),
@@ -71,14 +143,71 @@ pub async fn start(
     };
 }
 
+/// Connection accepted by the GRPC inbound, taking its slots of the connection caps until it is closed.
+struct SlottedStream {
+    stream: TcpStream,
+    _slots: Vec<ConnectionSlot>,
+}
+
+impl Connected for SlottedStream {
+    type ConnectInfo = TcpConnectInfo;
+
+    fn connect_info(&self) -> Self::ConnectInfo {
+        self.stream.connect_info()
+    }
+}
+
+impl AsyncRead for SlottedStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for SlottedStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
 pub struct GrpcProxyService {
+    inbound_config: &'static InboundConfig,
     acceptor: &'static GrpcAcceptor,
     handler: &'static GrpcHandler,
+    outbound_tag: &'static str,
+    router: &'static Router,
 }
 
 impl GrpcProxyService {
-    pub fn new(acceptor: &'static GrpcAcceptor, handler: &'static GrpcHandler) -> Self {
-        Self { acceptor, handler }
+    pub fn new(
+        inbound_config: &'static InboundConfig,
+        acceptor: &'static GrpcAcceptor,
+        handler: &'static GrpcHandler,
+        outbound_tag: &'static str,
+        router: &'static Router,
+    ) -> Self {
+        Self {
+            inbound_config,
+            acceptor,
+            handler,
+            outbound_tag,
+            router,
+        }
     }
 }
 
@@ -91,28 +220,130 @@ impl GrpcService for GrpcProxyService {
         &self,
         request: Request<Streaming<Hunk>>,
     ) -> Result<Response<Self::TunStream>, Status> {
-        info!("Received GRPC request");
-
-        let (acceptor, handler) = (self.acceptor, self.handler);
+        let (acceptor, handler, router) = (self.acceptor, self.handler, self.router);
+        let outbound_tag = self.outbound_tag;
+        let inbound_tag = self.inbound_config.tag.as_deref();
         let (tx, rx) = mpsc::channel(CHANNEL_SIZE);
+        let deadline = Deadline::new(self.inbound_config);
+        // Without a TCP connection there is no address of the client
+        let source = request
+            .remote_addr()
+            .unwrap_or_else(|| SocketAddr::from(([0, 0, 0, 0], 0)));
+        let sampled = AccessLog::get().sample();
+        if sampled && !AccessLog::get().separate() {
+            info!("Received GRPC request from {}", source);
+        }
+        let span = info_span!(
+            "connection",
+            otel.kind = "server",
+            source = %source,
+            uploaded = Empty,
+            downloaded = Empty
+        );
 
-        tokio::spawn(async move {
-            let (request, client_reader) = match acceptor.accept_hunk(request).await {
-                Ok((req, reader)) => (req, reader),
-                Err(e) => {
-                    warn!("Failed to accept the inbound traffic: {}", e);
-                    return;
-                }
-            };
+        tokio::spawn(
+            async move {
+                let (request, client_reader) = match deadline
+                    .run("handshake", acceptor.accept_hunk(request))
+                    .await
+                {
+                    Ok((req, reader)) => (req, reader),
+                    Err(e) => {
+                        warn!("Failed to accept the inbound traffic: {}", e);
+                        return;
+                    }
+                };
 
-            match handler.handle_hunk(client_reader, tx, request).await {
-                Ok(_) => return,
-                Err(e) => {
-                    warn!("Failed to handle inbound traffic: {}", e);
-                    return;
+                // The UDP sessions are relayed by the GRPC handler to the outbound of the server, and the TCP requests
+                // go through the handlers of the outbounds they are routed to
+                let routed = match request.transport_protocol {
+                    TransportProtocol::UDP => None,
+                    _ => Some(router.route(&RouteContext {
+                        request: &request,
+                        inbound_tag,
+                        sniffed_domain: None,
+                    })),
+                };
+                let context = TrafficContext::new(
+                    inbound_tag,
+                    Some(routed.map_or(outbound_tag, |routed| routed.tag())),
+                    request.user.as_ref().map(|user| user.name.as_str()),
+                )
+                .with_destination(request.addr_port.to_string());
+                let connection = events::Connection::open(source, &context);
+                let context = context.with_connection(connection.id());
+                let transfer = Transfer::new();
+                let registration = ActiveConnection {
+                    id: connection.id(),
+                    source,
+                    context: context.clone(),
+                    transport: "grpc",
+                    mode: match request.transport_protocol {
+                        TransportProtocol::UDP => "udp",
+                        _ => "tcp",
+                    },
+                    transfer: transfer.clone(),
                 }
-            };
-        });
+                .register();
+                context
+                    .clone()
+                    .scope(async move {
+                        metrics::increment_attributed("connections_total", 1);
+                        AccessLog::get().record(&request.addr_port);
+                        if sampled && !AccessLog::get().separate() {
+                            info!(
+                                "GRPC stream from {} requests {} ({})",
+                                source, request.addr_port, context
+                            );
+                        }
+                        let destination = request.addr_port.to_string();
+                        let result = transfer
+                            .scope(registration.until_closed(async {
+                                match routed {
+                                    None => handler.handle_hunk(client_reader, tx, request).await,
+                                    Some(routed) => {
+                                        dispatch_hunk(routed, client_reader, tx, request, deadline)
+                                            .await
+                                    }
+                                }
+                            }))
+                            .await;
+                        match result {
+                            Ok(()) => {
+                                if sampled {
+                                    AccessLog::get().log(format_args!(
+                                        "GRPC stream from {} to {} ({}) has finished: {}",
+                                        source, destination, context, transfer
+                                    ));
+                                }
+                            }
+                            Err(e)
+                                if e.kind() == ErrorKind::PermissionDenied
+                                    || registration.is_closed() =>
+                            {
+                                if sampled {
+                                    AccessLog::get().log(format_args!(
+                                        "GRPC stream from {} to {} ({}) is closed: {}",
+                                        source, destination, context, e
+                                    ));
+                                }
+                            }
+                            Err(e) => {
+                                warn!("Failed to handle GRPC stream ({}): {}", context, e);
+                                if sampled && AccessLog::get().separate() {
+                                    AccessLog::get().log(format_args!(
+                                        "GRPC stream from {} to {} ({}) has failed: {}",
+                                        source, destination, context, e
+                                    ));
+                                }
+                                connection.fail(&e);
+                            }
+                        }
+                    })
+                    .await
+            }
+            .instrument(span),
+        );
 
         Ok(Response::new(Box::pin(
             tokio_stream::wrappers::ReceiverStream::new(rx),
@@ -0,0 +1,246 @@
+use crate::metrics;
+use crate::protocol::common::stream::IntoTcpStream;
+
+use log::{info, warn};
+use once_cell::sync::OnceCell;
+use rustls::{BulkAlgorithm, ConnectionTrafficSecrets, SupportedCipherSuite};
+use socket2::SockRef;
+use std::io::{Error, ErrorKind, Read, Result};
+use std::mem;
+use std::net::{Shutdown, TcpListener, TcpStream as StdTcpStream};
+use std::os::unix::io::AsRawFd;
+use tokio::net::TcpStream;
+use tokio_rustls::server::TlsStream;
+
+/// TLS versions and ciphers probed at startup, the ones rustls negotiates
+const CIPHERS: [(u16, u16); 6] = [
+    (libc::TLS_1_2_VERSION, libc::TLS_CIPHER_AES_GCM_128),
+    (libc::TLS_1_2_VERSION, libc::TLS_CIPHER_AES_GCM_256),
+    (libc::TLS_1_2_VERSION, libc::TLS_CIPHER_CHACHA20_POLY1305),
+    (libc::TLS_1_3_VERSION, libc::TLS_CIPHER_AES_GCM_128),
+    (libc::TLS_1_3_VERSION, libc::TLS_CIPHER_AES_GCM_256),
+    (libc::TLS_1_3_VERSION, libc::TLS_CIPHER_CHACHA20_POLY1305),
+];
+
+/// Static kernel TLS of the process, None if the kernel isn't able to take over any session
+static KTLS: OnceCell<Option<Ktls>> = OnceCell::new();
+
+/// Kernel TLS takes over the inbound TLS sessions relayed as plain TCP once the request is read, so that the relays
+/// move the data with splice as they do for plain TCP and the kernel encrypts it on the way, rather than copying
+/// every byte through rustls. Only the sessions whose version and cipher the kernel supports are taken over, and only
+/// if rustls has nothing buffered besides decrypted data, the others stay with rustls. Each session taken over is
+/// counted in ktls_connections_total.
+///
+/// The kernel doesn't handle the control records on its own, so the sessions end with an error when the client sends
+/// an alert, including close_notify, or updates its keys.
+pub struct Ktls {
+    supported: Vec<(u16, u16)>,
+}
+
+impl Ktls {
+    /// Probe which TLS versions and ciphers the kernel supports, warns and returns None if it supports none.
+    pub fn init() -> Option<&'static Self> {
+        KTLS.get_or_init(|| {
+            let supported: Vec<_> = CIPHERS
+                .iter()
+                .copied()
+                .filter(|&(version, cipher)| probe(version, cipher).is_ok())
+                .collect();
+
+            if supported.is_empty() {
+                warn!("Kernel TLS is unavailable, is the tls module loaded?");
+                return None;
+            }
+            info!(
+                "Kernel TLS takes over the sessions with {} of {} ciphers",
+                supported.len(),
+                CIPHERS.len()
+            );
+            Some(Self { supported })
+        })
+        .as_ref()
+    }
+
+    /// Kernel TLS of the process, None if it wasn't initialized or is unavailable.
+    #[inline]
+    pub fn get() -> Option<&'static Self> {
+        KTLS.get().and_then(Option::as_ref)
+    }
+
+    /// Whether the kernel supports the TLS version and cipher, as the TLS_*_VERSION and TLS_CIPHER_* constants.
+    #[inline]
+    pub fn supports(&self, version: u16, cipher: u16) -> bool {
+        self.supported.contains(&(version, cipher))
+    }
+
+    /// Hand the session of the stream over to the kernel, returning the socket along with the data rustls already
+    /// decrypted, or the stream itself if the kernel can't take it over. The connection is shut down if the kernel
+    /// fails to take over the session once rustls has given it up, as it can't go on in plaintext.
+    #[allow(clippy::result_large_err)]
+    pub fn offload<T: IntoTcpStream>(
+        &self,
+        stream: TlsStream<T>,
+    ) -> std::result::Result<(TcpStream, Vec<u8>), TlsStream<T>> {
+        let (io, session) = stream.get_ref();
+        let (version, cipher) = match session.negotiated_cipher_suite() {
+            Some(suite) if !session.is_handshaking() => kernel_cipher(suite),
+            _ => return Err(stream),
+        };
+        if session.wants_write() || !io.at_record_boundary() || !self.supports(version, cipher) {
+            return Err(stream);
+        }
+
+        let (io, mut session) = stream.into_inner();
+        let socket = match io.into_tcp_stream() {
+            Ok((socket, _)) => socket,
+            Err(_) => unreachable!("streams at a record boundary give up their socket"),
+        };
+
+        // Everything rustls read is decrypted, the reader fails once there is nothing left
+        let mut head = Vec::new();
+        let _ = session.reader().read_to_end(&mut head);
+
+        let result = session
+            .extract_secrets()
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e))
+            .and_then(|secrets| {
+                set_ulp(&socket)?;
+                set_crypto_info(&socket, libc::TLS_TX, version, secrets.tx.0, &secrets.tx.1)?;
+                set_crypto_info(&socket, libc::TLS_RX, version, secrets.rx.0, &secrets.rx.1)
+            });
+
+        match result {
+            Ok(()) => {
+                metrics::increment("ktls_connections_total", 1);
+                Ok((socket, head))
+            }
+            Err(e) => {
+                warn!("Failed to hand the TLS session over to the kernel: {}", e);
+                let _ = SockRef::from(&socket).shutdown(Shutdown::Both);
+                Ok((socket, Vec::new()))
+            }
+        }
+    }
+}
+
+/// TLS version and cipher of the suite, as the kernel names them.
+fn kernel_cipher(suite: SupportedCipherSuite) -> (u16, u16) {
+    let (version, bulk) = match suite {
+        SupportedCipherSuite::Tls12(suite) => (libc::TLS_1_2_VERSION, &suite.common.bulk),
+        SupportedCipherSuite::Tls13(suite) => (libc::TLS_1_3_VERSION, &suite.common.bulk),
+    };
+    let cipher = match bulk {
+        BulkAlgorithm::Aes128Gcm => libc::TLS_CIPHER_AES_GCM_128,
+        BulkAlgorithm::Aes256Gcm => libc::TLS_CIPHER_AES_GCM_256,
+        BulkAlgorithm::Chacha20Poly1305 => libc::TLS_CIPHER_CHACHA20_POLY1305,
+    };
+    (version, cipher)
+}
+
+/// Take over a session with the version and cipher on a loopback connection, using keys of zeros.
+fn probe(version: u16, cipher: u16) -> Result<()> {
+    let secrets = match cipher {
+        libc::TLS_CIPHER_AES_GCM_128 => ConnectionTrafficSecrets::Aes128Gcm {
+            key: [0; 16],
+            salt: [0; 4],
+            iv: [0; 8],
+        },
+        libc::TLS_CIPHER_AES_GCM_256 => ConnectionTrafficSecrets::Aes256Gcm {
+            key: [0; 32],
+            salt: [0; 4],
+            iv: [0; 8],
+        },
+        _ => ConnectionTrafficSecrets::Chacha20Poly1305 {
+            key: [0; 32],
+            iv: [0; 12],
+        },
+    };
+
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let socket = StdTcpStream::connect(listener.local_addr()?)?;
+    let _peer = listener.accept()?;
+
+    set_ulp(&socket)?;
+    set_crypto_info(&socket, libc::TLS_TX, version, 0, &secrets)?;
+    set_crypto_info(&socket, libc::TLS_RX, version, 0, &secrets)
+}
+
+/// Attach the TLS upper layer protocol to the socket, which the keys are then given to.
+fn set_ulp<S: AsRawFd>(socket: &S) -> Result<()> {
+    setsockopt(socket, libc::SOL_TCP, libc::TCP_ULP, b"tls")
+}
+
+/// Give the keys of one direction to the kernel, seq is the sequence number of the next record that way.
+fn set_crypto_info<S: AsRawFd>(
+    socket: &S,
+    direction: libc::c_int,
+    version: u16,
+    seq: u64,
+    secrets: &ConnectionTrafficSecrets,
+) -> Result<()> {
+    let rec_seq = seq.to_be_bytes();
+    match *secrets {
+        ConnectionTrafficSecrets::Aes128Gcm { key, salt, iv } => {
+            let info = libc::tls12_crypto_info_aes_gcm_128 {
+                info: libc::tls_crypto_info {
+                    version,
+                    cipher_type: libc::TLS_CIPHER_AES_GCM_128,
+                },
+                iv,
+                key,
+                salt,
+                rec_seq,
+            };
+            setsockopt(socket, libc::SOL_TLS, direction, &info)
+        }
+        ConnectionTrafficSecrets::Aes256Gcm { key, salt, iv } => {
+            let info = libc::tls12_crypto_info_aes_gcm_256 {
+                info: libc::tls_crypto_info {
+                    version,
+                    cipher_type: libc::TLS_CIPHER_AES_GCM_256,
+                },
+                iv,
+                key,
+                salt,
+                rec_seq,
+            };
+            setsockopt(socket, libc::SOL_TLS, direction, &info)
+        }
+        ConnectionTrafficSecrets::Chacha20Poly1305 { key, iv } => {
+            let info = libc::tls12_crypto_info_chacha20_poly1305 {
+                info: libc::tls_crypto_info {
+                    version,
+                    cipher_type: libc::TLS_CIPHER_CHACHA20_POLY1305,
+                },
+                iv,
+                key,
+                salt: [],
+                rec_seq,
+            };
+            setsockopt(socket, libc::SOL_TLS, direction, &info)
+        }
+        _ => Err(Error::new(ErrorKind::Unsupported, "unsupported cipher")),
+    }
+}
+
+fn setsockopt<S: AsRawFd, T: ?Sized>(
+    socket: &S,
+    level: libc::c_int,
+    name: libc::c_int,
+    value: &T,
+) -> Result<()> {
+    // Safety: the option value is borrowed for the whole call and its size is the one given
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            value as *const T as *const libc::c_void,
+            mem::size_of_val(value) as libc::socklen_t,
+        )
+    };
+    match result {
+        0 => Ok(()),
+        _ => Err(Error::last_os_error()),
+    }
+}
@@ -0,0 +1,322 @@
+use crate::config::base::ConnectionLimitConfig;
+use crate::metrics;
+
+use log::debug;
+use std::collections::{HashMap, VecDeque};
+use std::io::{Error, ErrorKind, Result};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::Instant;
+
+/// Number of source addresses tracked by the per address limit before the ones seen first are forgotten
+pub const MAX_TRACKED_SOURCES: usize = 4096;
+
+/// Token bucket that refills at a constant rate up to its capacity. Each unit of work, like a byte or a connection,
+/// takes one token out of the bucket, and the work is rejected when the bucket runs dry.
+pub struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    /// Create a full bucket that refills rate tokens per second and holds at most capacity tokens.
+    pub fn new(rate: u64, capacity: u64) -> Self {
+        Self {
+            rate: rate as f64,
+            capacity: capacity as f64,
+            tokens: capacity as f64,
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// Take amount tokens out of the bucket if there are enough of them, returns whether the tokens are taken.
+    pub fn try_acquire(&mut self, amount: u64) -> bool {
+        self.refill();
+
+        if self.tokens < amount as f64 {
+            return false;
+        }
+
+        self.tokens -= amount as f64;
+        true
+    }
+
+    /// Take amount tokens out of the bucket, borrowing the ones it lacks from the refill to come if that takes no
+    /// longer than max_wait. Returns how long to wait for the borrowed tokens, or None if nothing is taken.
+    pub fn reserve(&mut self, amount: u64, max_wait: Duration) -> Option<Duration> {
+        self.refill();
+
+        let missing = amount as f64 - self.tokens;
+        let wait = match missing > 0.0 {
+            true => Duration::from_secs_f64(missing / self.rate),
+            false => Duration::ZERO,
+        };
+        if wait > max_wait {
+            return None;
+        }
+
+        self.tokens -= amount as f64;
+        Some(wait)
+    }
+
+    /// Whether the bucket has refilled completely, in which case it is the same as a newly created bucket.
+    pub fn is_full(&mut self) -> bool {
+        self.refill();
+        self.tokens >= self.capacity
+    }
+
+    #[inline]
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.last_refill = now;
+    }
+}
+
+/// Limit of the new connections accepted per second, both in total and from each source address, checked right after
+/// the connection is accepted so that floods are turned away before they reach the TLS and proxy handshakes. Sources
+/// going over their rate may be delayed rather than turned away, for up to per_source_delay.
+pub struct ConnectionLimiter {
+    global: Option<Mutex<TokenBucket>>,
+    per_source: Option<(u64, u64)>,
+    per_source_delay: Duration,
+    sources: Mutex<Sources>,
+}
+
+/// Buckets of the source addresses, with the order the sources were first seen in. At most MAX_TRACKED_SOURCES are
+/// tracked, so that a scan from many addresses neither grows the map without bound nor slows down every new source.
+#[derive(Default)]
+struct Sources {
+    buckets: HashMap<IpAddr, TokenBucket>,
+    order: VecDeque<IpAddr>,
+}
+
+impl Sources {
+    /// Bucket of the source, created full if the source is new, in which case the source seen first is forgotten if
+    /// too many are tracked.
+    fn bucket(&mut self, source: IpAddr, rate: u64, burst: u64) -> &mut TokenBucket {
+        if !self.buckets.contains_key(&source) {
+            if self.buckets.len() >= MAX_TRACKED_SOURCES {
+                if let Some(oldest) = self.order.pop_front() {
+                    self.buckets.remove(&oldest);
+                }
+            }
+            self.order.push_back(source);
+        }
+
+        self.buckets
+            .entry(source)
+            .or_insert_with(|| TokenBucket::new(rate, burst))
+    }
+}
+
+impl ConnectionLimiter {
+    pub fn new(config: &ConnectionLimitConfig) -> Self {
+        Self {
+            global: config
+                .rate
+                .map(|rate| Mutex::new(TokenBucket::new(rate, config.burst.unwrap_or(rate)))),
+            per_source: config
+                .per_ip_rate
+                .map(|rate| (rate, config.per_ip_burst.unwrap_or(rate))),
+            per_source_delay: Duration::from_secs(config.per_ip_delay.unwrap_or(0)),
+            sources: Mutex::new(Sources::default()),
+        }
+    }
+
+    /// Number of source addresses tracked by the per address limit.
+    pub fn tracked_sources(&self) -> usize {
+        self.sources.lock().unwrap().buckets.len()
+    }
+
+    /// Whether a new connection from the source address is allowed, right away or after the delay of admit.
+    pub fn allow(&self, source: IpAddr) -> bool {
+        self.admit(source).is_some()
+    }
+
+    /// How long a new connection from the source address has to wait before being handled, None if it is refused.
+    pub fn admit(&self, source: IpAddr) -> Option<Duration> {
+        let mut delay = Duration::ZERO;
+        if let Some((rate, burst)) = self.per_source {
+            let mut sources = self.sources.lock().unwrap();
+            match sources
+                .bucket(source, rate, burst)
+                .reserve(1, self.per_source_delay)
+            {
+                Some(wait) => delay = wait,
+                None => {
+                    debug!("Refusing connection from {}, rate limit exceeded", source);
+                    metrics::increment("connections_throttled_total{scope=\"source\"}", 1);
+                    return None;
+                }
+            }
+        }
+
+        if let Some(global) = &self.global {
+            if !global.lock().unwrap().try_acquire(1) {
+                debug!(
+                    "Refusing connection from {}, global rate limit exceeded",
+                    source
+                );
+                metrics::increment("connections_throttled_total{scope=\"global\"}", 1);
+                return None;
+            }
+        }
+
+        if !delay.is_zero() {
+            debug!(
+                "Delaying connection from {} by {}ms, rate limit exceeded",
+                source,
+                delay.as_millis()
+            );
+            metrics::increment("connections_delayed_total", 1);
+        }
+        Some(delay)
+    }
+}
+
+/// Cap on the connections open at the same time, either through all the listeners of the inbound or through one of
+/// them. Connections over the cap are closed right after being accepted, or if queue is set, they wait for an open
+/// connection to close while the accept loop holds off, so that the next ones queue in the backlog of the listener.
+/// The connections open are exported as the gauge, and the ones closed for the cap are counted in
+/// connections_over_limit_total labelled with the scope.
+pub struct ConcurrencyLimit {
+    slots: Arc<Semaphore>,
+    max: usize,
+    queue: bool,
+    scope: &'static str,
+    gauge: Arc<str>,
+}
+
+impl ConcurrencyLimit {
+    /// Fails with InvalidInput if max is zero, as no connection would ever get through.
+    pub fn new(max: usize, queue: bool, scope: &'static str, gauge: String) -> Result<Self> {
+        if max == 0 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "the connections through the {} must be capped above zero",
+                    scope
+                ),
+            ));
+        }
+
+        Ok(Self {
+            slots: Arc::new(Semaphore::new(max)),
+            max,
+            queue,
+            scope,
+            gauge: gauge.into(),
+        })
+    }
+
+    /// Limit of the connections through all the listeners of the inbound, if it is configured.
+    pub fn for_inbound(config: &ConnectionLimitConfig) -> Result<Option<Self>> {
+        config
+            .max_connections
+            .map(|max| {
+                Self::new(
+                    max,
+                    config.queue.unwrap_or(false),
+                    "inbound",
+                    "active_connections".to_string(),
+                )
+            })
+            .transpose()
+    }
+
+    /// Limit of the connections through the listener on the address, if it is configured.
+    pub fn for_listener(
+        config: &ConnectionLimitConfig,
+        address: SocketAddr,
+    ) -> Result<Option<Self>> {
+        config
+            .max_connections_per_listener
+            .map(|max| {
+                Self::new(
+                    max,
+                    config.queue.unwrap_or(false),
+                    "listener",
+                    format!("active_connections{{listener=\"{}\"}}", address),
+                )
+            })
+            .transpose()
+    }
+
+    /// Slot for a new connection, held until the connection closes. Waits for a slot if the limit queues the
+    /// connections, otherwise returns None if all the slots are taken.
+    pub async fn acquire(&self) -> Option<ConnectionSlot> {
+        let permit = match self.queue {
+            true => self.slots.clone().acquire_owned().await.ok(),
+            false => self.slots.clone().try_acquire_owned().ok(),
+        };
+
+        match permit {
+            Some(permit) => {
+                let slot = ConnectionSlot {
+                    permit: Some(permit),
+                    slots: self.slots.clone(),
+                    max: self.max,
+                    gauge: self.gauge.clone(),
+                };
+                slot.report();
+                Some(slot)
+            }
+            None => {
+                debug!(
+                    "Refusing connection, {} connections open through the {}",
+                    self.max, self.scope
+                );
+                metrics::increment(
+                    &format!("connections_over_limit_total{{scope=\"{}\"}}", self.scope),
+                    1,
+                );
+                None
+            }
+        }
+    }
+
+    /// Number of connections open.
+    pub fn active(&self) -> usize {
+        self.max - self.slots.available_permits()
+    }
+}
+
+/// Slots for a new connection in each of the limits, None if it is over any of them.
+pub async fn acquire_slots(limits: &[&ConcurrencyLimit]) -> Option<Vec<ConnectionSlot>> {
+    let mut slots = Vec::with_capacity(limits.len());
+    for limit in limits {
+        slots.push(limit.acquire().await?);
+    }
+    Some(slots)
+}
+
+/// Connection counted by a concurrency limit until dropped.
+pub struct ConnectionSlot {
+    permit: Option<OwnedSemaphorePermit>,
+    slots: Arc<Semaphore>,
+    max: usize,
+    gauge: Arc<str>,
+}
+
+impl ConnectionSlot {
+    fn report(&self) {
+        metrics::set(
+            &self.gauge,
+            (self.max - self.slots.available_permits()) as u64,
+        );
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        // Give the slot back before counting the connections left
+        self.permit.take();
+        self.report();
+    }
+}
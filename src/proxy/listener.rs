@@ -0,0 +1,65 @@
+use log::info;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::io::{Error, ErrorKind, Result};
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use tokio::net::TcpListener;
+
+/// Backlog of the listener sockets, same as the one used by tokio
+const LISTEN_BACKLOG: i32 = 1024;
+
+/// Resolve the listen address in the configuration into the socket addresses to bind. IPv6 addresses may be written
+/// with or without brackets, and host names like localhost resolve to the addresses of all the families.
+pub fn resolve_listen_addresses(address: &str, port: u16) -> Result<Vec<SocketAddr>> {
+    let address = address
+        .strip_prefix('[')
+        .and_then(|address| address.strip_suffix(']'))
+        .unwrap_or(address);
+
+    let mut addresses: Vec<SocketAddr> = match address.parse::<IpAddr>() {
+        Ok(ip) => vec![SocketAddr::new(ip, port)],
+        Err(_) => (address, port).to_socket_addrs()?.collect(),
+    };
+
+    addresses.sort();
+    addresses.dedup();
+
+    if addresses.is_empty() {
+        return Err(Error::new(
+            ErrorKind::AddrNotAvailable,
+            format!("{} doesn't resolve to any address", address),
+        ));
+    }
+
+    Ok(addresses)
+}
+
+/// Bind TCP listeners on all the addresses of the listen address. Whether the IPv6 sockets also accept IPv4
+/// connections is platform dependent by default, so it is always set explicitly: the unspecified address :: listens on
+/// both families unless v6only is enabled.
+pub fn bind_tcp(address: &str, port: u16, v6only: Option<bool>) -> Result<Vec<TcpListener>> {
+    let mut listeners = Vec::new();
+
+    for address in resolve_listen_addresses(address, port)? {
+        let socket = Socket::new(
+            Domain::for_address(address),
+            Type::STREAM,
+            Some(Protocol::TCP),
+        )?;
+
+        if address.is_ipv6() {
+            socket.set_only_v6(v6only.unwrap_or(!address.ip().is_unspecified()))?;
+        }
+
+        #[cfg(not(windows))]
+        socket.set_reuse_address(true)?;
+        socket.set_nonblocking(true)?;
+        socket.bind(&address.into())?;
+        socket.listen(LISTEN_BACKLOG)?;
+
+        info!("Listening on {}", address);
+
+        listeners.push(TcpListener::from_std(socket.into())?);
+    }
+
+    Ok(listeners)
+}
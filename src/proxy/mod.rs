@@ -1,4 +1,27 @@
 pub mod base;
+pub mod buffer;
+pub mod connections;
+pub mod context;
+pub mod deadline;
+pub mod destination;
+pub mod drain;
+pub mod filter;
 pub mod grpc;
-pub mod tcp;
+#[cfg(target_os = "linux")]
+pub mod ktls;
+pub mod limiter;
+pub mod listener;
+pub mod policy;
 pub mod quic;
+pub mod reaper;
+pub mod relay;
+pub mod reset;
+pub mod servers;
+pub mod socket;
+#[cfg(target_os = "linux")]
+pub mod splice;
+pub mod tcp;
+pub mod throttle;
+pub mod udp;
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+pub mod uring;
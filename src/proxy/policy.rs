@@ -0,0 +1,208 @@
+use crate::config::base::{Config, PolicyConfig};
+use crate::metrics;
+use crate::proxy::deadline::Deadline;
+use crate::proxy::filter::IpFilter;
+
+use log::debug;
+use once_cell::sync::OnceCell;
+use std::collections::HashMap;
+use std::future::Future;
+use std::io::{Error, ErrorKind, Result};
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Default wait in milliseconds before the first retry of a failed connection attempt
+const DEFAULT_RETRY_BACKOFF: u64 = 100;
+
+/// Default time in seconds the other side of a relayed connection has to finish once one side stopped sending
+const DEFAULT_HALF_CLOSE_TIMEOUT: u64 = 30;
+
+/// Static lifetime policies shared by the inbounds and the outbounds
+static POLICIES: OnceCell<Policies> = OnceCell::new();
+
+/// Timeouts and retries applied to the connections of an inbound or an outbound.
+#[derive(Clone, Debug)]
+pub struct Policy {
+    pub handshake_timeout: Option<Duration>,
+    pub connect_timeout: Option<Duration>,
+    pub retries: u32,
+    pub retry_backoff: Duration,
+    pub idle_timeout: Option<Duration>,
+    pub max_connection_lifetime: Option<Duration>,
+    pub half_close_timeout: Duration,
+    pub udp_session_ttl: Option<Duration>,
+    pub max_upload: Option<u64>,
+    pub max_download: Option<u64>,
+    pub destinations: Option<Arc<IpFilter>>,
+}
+
+impl Policy {
+    /// Build the policy from the configuration, taking the values it leaves out from the base configuration. Fails
+    /// with InvalidInput if the destination filter fails to load.
+    pub fn new(config: &PolicyConfig, base: &PolicyConfig) -> Result<Self> {
+        let secs = |value: Option<u64>, base: Option<u64>| value.or(base).map(Duration::from_secs);
+
+        Ok(Self {
+            handshake_timeout: secs(config.handshake_timeout, base.handshake_timeout),
+            connect_timeout: secs(config.connect_timeout, base.connect_timeout),
+            retries: config.retries.or(base.retries).unwrap_or(0),
+            retry_backoff: Duration::from_millis(
+                config
+                    .retry_backoff
+                    .or(base.retry_backoff)
+                    .unwrap_or(DEFAULT_RETRY_BACKOFF),
+            ),
+            idle_timeout: secs(config.idle_timeout, base.idle_timeout),
+            max_connection_lifetime: secs(
+                config.max_connection_lifetime,
+                base.max_connection_lifetime,
+            ),
+            half_close_timeout: Duration::from_secs(
+                config
+                    .half_close_timeout
+                    .or(base.half_close_timeout)
+                    .unwrap_or(DEFAULT_HALF_CLOSE_TIMEOUT),
+            ),
+            udp_session_ttl: secs(config.udp_session_ttl, base.udp_session_ttl),
+            max_upload: config.max_upload.or(base.max_upload),
+            max_download: config.max_download.or(base.max_download),
+            destinations: config
+                .destinations
+                .as_ref()
+                .or(base.destinations.as_ref())
+                .map(|destinations| IpFilter::new(destinations, "destination"))
+                .transpose()?,
+        })
+    }
+
+    /// Fails with PermissionDenied if the destinations of the policy don't include the address.
+    pub fn check_destination(&self, ip: IpAddr) -> Result<()> {
+        match &self.destinations {
+            Some(destinations) => destinations.check(ip),
+            None => Ok(()),
+        }
+    }
+
+    /// Run the connection attempts until one of them succeeds, each bounded by the connect timeout. Failed attempts
+    /// are retried up to the number of retries after an exponential backoff, as long as the deadline leaves time for
+    /// the backoff.
+    pub async fn connect<T, F, Fut>(&self, deadline: &Deadline, mut connect: F) -> Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let mut backoff = self.retry_backoff;
+        let mut attempt = 0;
+
+        loop {
+            match deadline
+                .run_within("connect", self.connect_timeout, connect())
+                .await
+            {
+                Ok(connection) => return Ok(connection),
+                Err(e) if attempt < self.retries && deadline.remaining() > backoff => {
+                    attempt += 1;
+                    debug!(
+                        "Connection attempt {} failed, retrying in {}ms: {}",
+                        attempt,
+                        backoff.as_millis(),
+                        e
+                    );
+                    metrics::increment("connect_retries_total", 1);
+
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Self {
+            handshake_timeout: None,
+            connect_timeout: None,
+            retries: 0,
+            retry_backoff: Duration::from_millis(DEFAULT_RETRY_BACKOFF),
+            idle_timeout: None,
+            max_connection_lifetime: None,
+            half_close_timeout: Duration::from_secs(DEFAULT_HALF_CLOSE_TIMEOUT),
+            udp_session_ttl: None,
+            max_upload: None,
+            max_download: None,
+            destinations: None,
+        }
+    }
+}
+
+/// Default policy of the process along with the named policies, which the inbounds and the outbounds pick by name.
+pub struct Policies {
+    default: Policy,
+    named: HashMap<String, Policy>,
+}
+
+impl Policies {
+    /// Build the policies shared by the whole process.
+    pub fn init(config: &Config) -> Result<&'static Self> {
+        POLICIES.get_or_try_init(|| Self::from_config(config))
+    }
+
+    /// Policies shared by the whole process, only the default values if they weren't initialized yet.
+    pub fn get() -> &'static Self {
+        POLICIES.get_or_init(|| Self {
+            default: Policy::default(),
+            named: HashMap::new(),
+        })
+    }
+
+    /// Build the policies of the configuration, fails with InvalidInput if an inbound or outbound refers to an
+    /// unknown policy.
+    pub fn from_config(config: &Config) -> Result<Self> {
+        let policies = Self::new(config.policy.as_ref(), config.policies.as_ref())?;
+
+        let outbounds = config.outbounds.iter().flatten();
+        let names = std::iter::once(&config.inbound.policy)
+            .chain(std::iter::once(&config.outbound.policy))
+            .chain(outbounds.map(|outbound| &outbound.policy));
+        for name in names.flatten() {
+            if !policies.named.contains_key(name) {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("unknown policy {}", name),
+                ));
+            }
+        }
+
+        Ok(policies)
+    }
+
+    pub fn new(
+        default: Option<&PolicyConfig>,
+        named: Option<&HashMap<String, PolicyConfig>>,
+    ) -> Result<Self> {
+        let default = default.cloned().unwrap_or_default();
+
+        Ok(Self {
+            default: Policy::new(&default, &default)?,
+            named: named
+                .into_iter()
+                .flatten()
+                .map(|(name, config)| Ok((name.clone(), Policy::new(config, &default)?)))
+                .collect::<Result<_>>()?,
+        })
+    }
+
+    /// Named policy, or the default policy if there is no name. Panics if there is no policy with the name.
+    pub fn policy(&self, name: Option<&str>) -> &Policy {
+        match name {
+            Some(name) => match self.named.get(name) {
+                Some(policy) => policy,
+                None => panic!("Unknown policy {}", name),
+            },
+            None => &self.default,
+        }
+    }
+}
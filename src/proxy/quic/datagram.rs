@@ -0,0 +1,302 @@
+use crate::metrics;
+use crate::protocol::common::addr::IpAddrPort;
+use crate::protocol::trojan::packet::{encode_udp, TrojanPacketWriter};
+use crate::protocol::trojan::parse_udp;
+use crate::proxy::buffer::{Buffer, BufferPool};
+
+use bytes::Bytes;
+use futures::StreamExt;
+use log::debug;
+use quinn::{Connection, Datagrams, SendDatagramError};
+use std::collections::HashMap;
+use std::future::Future;
+use std::io::{Error, ErrorKind, Result};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite};
+use tokio::sync::mpsc::{self, Receiver, Sender};
+
+/// Most packets queued for a session, the datagrams arriving for a full queue are dropped
+const SESSION_QUEUE_SIZE: usize = 64;
+
+/// Destination or source of a UDP payload and the payload itself.
+pub type UdpPacket = (IpAddrPort, Buffer);
+
+/// Queue of the packets received for a session.
+struct SessionQueue {
+    sender: Sender<UdpPacket>,
+    active: Arc<AtomicBool>,
+}
+
+/// UDP sessions carried by a QUIC connection in DATAGRAM frames. A session starts as a trojan UDP request on a
+/// bidirectional stream and is identified by the index of that stream, which each datagram starts with as a QUIC
+/// variable length integer, followed by a trojan UDP packet. Datagrams are not retransmitted nor ordered, so a lost
+/// packet doesn't hold the following ones back the way it does on a stream, which matters for real time traffic like
+/// games and calls. Neither end sends datagrams before it has received one for the session, so that the clients which
+/// only speak the stream keep getting their packets there. The client announces that it supports datagrams with a
+/// datagram carrying only the index of the session, which it sends along with its packets until the server answers
+/// with a datagram, as the ones arriving before the server has read the request and opened the session are dropped.
+/// The packets sent before then, and the ones too large for a datagram, go on the stream of the session.
+pub struct QuicDatagrams {
+    connection: Connection,
+    sessions: Mutex<HashMap<u64, SessionQueue>>,
+}
+
+impl QuicDatagrams {
+    pub fn new(connection: Connection) -> Self {
+        Self {
+            connection,
+            sessions: Mutex::new(HashMap::new()),
+        }
+    }
+
+    #[inline]
+    pub fn connection(&self) -> &Connection {
+        &self.connection
+    }
+
+    /// Register the session of the stream with the index, until the returned session is dropped.
+    pub fn open(self: &Arc<Self>, id: u64) -> DatagramSession {
+        let (sender, receiver) = mpsc::channel(SESSION_QUEUE_SIZE);
+        let active = Arc::new(AtomicBool::new(false));
+        self.sessions.lock().unwrap().insert(
+            id,
+            SessionQueue {
+                sender: sender.clone(),
+                active: active.clone(),
+            },
+        );
+
+        DatagramSession {
+            datagrams: self.clone(),
+            id,
+            sender,
+            receiver,
+            active,
+        }
+    }
+
+    /// Hand the datagrams received on the connection over to their sessions, until the connection is closed. A
+    /// session sends its packets as datagrams too once the peer has sent one for it, an announcement included.
+    pub async fn receive(&self, mut datagrams: Datagrams) {
+        while let Some(Ok(datagram)) = datagrams.next().await {
+            let (id, packet) = match decode(&datagram).await {
+                Ok(decoded) => decoded,
+                Err(e) => {
+                    debug!("Dropping malformed QUIC datagram: {}", e);
+                    metrics::increment("quic_datagrams_dropped_total{reason=\"malformed\"}", 1);
+                    continue;
+                }
+            };
+
+            let sessions = self.sessions.lock().unwrap();
+            let queue = match sessions.get(&id) {
+                Some(queue) => queue,
+                None => {
+                    debug!("Dropping QUIC datagram of unknown session {}", id);
+                    metrics::increment(
+                        "quic_datagrams_dropped_total{reason=\"unknown_session\"}",
+                        1,
+                    );
+                    continue;
+                }
+            };
+            queue.active.store(true, Ordering::Relaxed);
+            let packet = match packet {
+                Some(packet) => packet,
+                None => continue,
+            };
+            if queue.sender.try_send(packet).is_err() {
+                metrics::increment("quic_datagrams_dropped_total{reason=\"queue_full\"}", 1);
+                continue;
+            }
+            metrics::increment("quic_datagrams_received_total", 1);
+        }
+    }
+
+    fn close(&self, id: u64) {
+        self.sessions.lock().unwrap().remove(&id);
+    }
+}
+
+/// UDP session carried by the datagrams of a QUIC connection.
+pub struct DatagramSession {
+    datagrams: Arc<QuicDatagrams>,
+    id: u64,
+    sender: Sender<UdpPacket>,
+    receiver: Receiver<UdpPacket>,
+    active: Arc<AtomicBool>,
+}
+
+impl DatagramSession {
+    /// Sender of the packets of the session to the peer.
+    pub fn sender(&self) -> DatagramSender {
+        DatagramSender {
+            connection: self.datagrams.connection.clone(),
+            header: encode_varint(self.id),
+            active: self.active.clone(),
+            announce: false,
+        }
+    }
+
+    /// Next packet received for the session, from the datagrams or the stream.
+    pub async fn recv(&mut self) -> Option<UdpPacket> {
+        self.receiver.recv().await
+    }
+
+    /// Queue the trojan UDP packets read from the stream of the session along with its datagrams. The future doesn't
+    /// borrow the session, so that the packets can be received meanwhile.
+    pub fn copy_stream<R: AsyncRead + Unpin>(
+        &self,
+        mut reader: R,
+    ) -> impl Future<Output = Result<()>> {
+        let sender = self.sender.clone();
+        async move {
+            loop {
+                let packet = read_packet(&mut reader).await?;
+                if sender.send(packet).await.is_err() {
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+impl Drop for DatagramSession {
+    fn drop(&mut self) {
+        self.datagrams.close(self.id);
+    }
+}
+
+/// Sends the packets of a session as datagrams, when the session is active and they fit.
+#[derive(Clone)]
+pub struct DatagramSender {
+    connection: Connection,
+    header: Vec<u8>,
+    active: Arc<AtomicBool>,
+    announce: bool,
+}
+
+impl DatagramSender {
+    /// Announce the support of datagrams to the peer with each packet sent on the stream, until it sends a datagram.
+    /// Only the client announces, the server waits for it.
+    pub fn announcing(mut self) -> Self {
+        self.announce = true;
+        self
+    }
+
+    /// Send the packet to or from the address as a datagram, false if it has to go on the stream instead.
+    pub fn send(&self, addr: &IpAddrPort, payload: &[u8]) -> bool {
+        if !self.active.load(Ordering::Relaxed) {
+            if self.announce && self.connection.max_datagram_size().is_some() {
+                if let Err(e) = self
+                    .connection
+                    .send_datagram(Bytes::from(self.header.clone()))
+                {
+                    debug!("Failed to announce QUIC datagrams: {}", e);
+                }
+            }
+            return false;
+        }
+
+        // A packet that can't be encoded fails on the stream instead
+        let mut datagram = self.header.clone();
+        match encode_udp(addr, payload) {
+            Ok(packet) => datagram.extend_from_slice(&packet),
+            Err(_) => return false,
+        }
+        match self.connection.max_datagram_size() {
+            Some(size) if datagram.len() <= size => (),
+            _ => return false,
+        }
+
+        match self.connection.send_datagram(Bytes::from(datagram)) {
+            Ok(()) => {
+                metrics::increment("quic_datagrams_sent_total", 1);
+                true
+            }
+            Err(SendDatagramError::TooLarge) => false,
+            // Lost like any UDP datagram, a closed connection ends the session through its stream
+            Err(e) => {
+                debug!("Failed to send QUIC datagram: {}", e);
+                true
+            }
+        }
+    }
+}
+
+/// Read the trojan UDP packets from the reader and queue them to the peer, as datagrams when possible.
+pub async fn copy_reader_to_packet_writer<R: AsyncRead + Unpin, W: AsyncWrite + Unpin>(
+    mut reader: R,
+    writer: &mut TrojanPacketWriter<W>,
+) -> Result<()> {
+    loop {
+        let (addr, payload) = read_packet(&mut reader).await?;
+        writer.push(&addr, &payload)?;
+        writer.flush().await?;
+    }
+}
+
+/// Write the packets received for the session to the writer.
+pub async fn copy_session_to_packet_writer<W: AsyncWrite + Unpin>(
+    session: &mut DatagramSession,
+    writer: &mut TrojanPacketWriter<W>,
+) -> Result<()> {
+    while let Some((addr, payload)) = session.recv().await {
+        writer.push(&addr, &payload)?;
+        writer.flush().await?;
+    }
+    Ok(())
+}
+
+/// Read a whole trojan UDP packet.
+async fn read_packet<R: AsyncRead + Unpin>(reader: &mut R) -> Result<UdpPacket> {
+    let header = parse_udp(reader).await?;
+    let mut payload = BufferPool::get().take_zeroed(header.payload_size);
+    reader.read_exact(&mut payload).await?;
+    Ok((header.dest, payload))
+}
+
+/// Split the datagram into the index of its session and the trojan UDP packet it carries, None for an announcement.
+async fn decode(datagram: &[u8]) -> Result<(u64, Option<UdpPacket>)> {
+    let (id, mut packet) = decode_varint(datagram)?;
+    if packet.is_empty() {
+        return Ok((id, None));
+    }
+    let (addr, payload) = read_packet(&mut packet).await?;
+    if !packet.is_empty() {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "trailing bytes after the trojan udp packet",
+        ));
+    }
+    Ok((id, Some((addr, payload))))
+}
+
+/// Encode the value as a QUIC variable length integer, the two high bits of the first byte hold the length.
+pub fn encode_varint(value: u64) -> Vec<u8> {
+    match value {
+        0..=0x3f => vec![value as u8],
+        0x40..=0x3fff => (value as u16 | 0x4000).to_be_bytes().to_vec(),
+        0x4000..=0x3fff_ffff => (value as u32 | 0x8000_0000).to_be_bytes().to_vec(),
+        _ => (value | 0xc000_0000_0000_0000).to_be_bytes().to_vec(),
+    }
+}
+
+/// Decode the QUIC variable length integer the bytes start with, along with the bytes after it.
+pub fn decode_varint(buf: &[u8]) -> Result<(u64, &[u8])> {
+    let size = match buf.first() {
+        Some(first) => 1 << (first >> 6),
+        None => return Err(Error::new(ErrorKind::UnexpectedEof, "missing session")),
+    };
+    if buf.len() < size {
+        return Err(Error::new(ErrorKind::UnexpectedEof, "truncated session"));
+    }
+
+    let value = buf[1..size]
+        .iter()
+        .fold((buf[0] & 0x3f) as u64, |value, byte| {
+            value << 8 | *byte as u64
+        });
+    Ok((value, &buf[size..]))
+}
@@ -1 +1,3 @@
-pub mod server;
\ No newline at end of file
+pub mod datagram;
+#[cfg(feature = "server")]
+pub mod server;
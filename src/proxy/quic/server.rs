@@ -1,17 +1,75 @@
 use crate::{
-    config::base::InboundConfig,
+    auth::{port, AuthChain},
+    config::base::{BandwidthConfig, DomainStrategy, InboundConfig, InboundQuicConfig},
     config::{base::OutboundConfig, tls::make_server_config},
+    events, health, metrics,
+    metrics::access::AccessLog,
+    profiling,
+    protocol::common::request::{InboundRequest, TransportProtocol},
+    protocol::trojan::packet::{
+        copy_client_reader_to_udp_socket, copy_udp_socket_to_client_writer, packet_size,
+        TrojanPacketWriter,
+    },
     protocol::trojan::parse,
+    proxy::connections::ActiveConnection,
+    proxy::context::TrafficContext,
+    proxy::deadline::Deadline,
+    proxy::destination::DestinationFilter,
+    proxy::filter::IpFilter,
+    proxy::limiter::{acquire_slots, ConcurrencyLimit, ConnectionLimiter},
+    proxy::policy::{Policies, Policy},
+    proxy::quic::datagram::{DatagramSession, QuicDatagrams},
+    proxy::relay::{copy_limited, relay_with_policy, Transfer},
+    proxy::socket::SocketOptions,
+    proxy::throttle::{GlobalBandwidth, Throttled, UserBandwidth},
+    proxy::udp::bind::UdpBinder,
+    proxy::udp::guard::UdpGuard,
+    proxy::udp::sessions::UdpSessions,
+    proxy::udp::worker::UdpWorkers,
+    router::{RouteContext, Router, DEFAULT_OUTBOUND_TAG},
 };
 use futures::StreamExt;
-use quinn;
-use std::{io::Result, net::SocketAddr};
+use log::{debug, info, warn};
+use quinn::{self, IdleTimeout, RecvStream, SendStream, TransportConfig};
+use std::future::pending;
+use std::io::{Error, ErrorKind, Result};
 use std::net::ToSocketAddrs;
-use tokio::net::TcpStream;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::net::UdpSocket;
+use tracing::field::{display, Empty};
+use tracing::{info_span, Instrument, Span};
+
+/// Interval of checking whether the client has moved to another address
+const MIGRATION_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Default time in seconds a connection is kept without hearing from the client. Roaming clients are silent while
+/// switching networks, so this is longer than the default of quinn.
+const DEFAULT_IDLE_TIMEOUT: u64 = 60;
+
+/// Outbound the QUIC server connects the requests of its clients through, to their destinations.
+struct QuicOutbound {
+    policy: &'static Policy,
+    tags: (Option<&'static str>, &'static str),
+    config: &'static OutboundConfig,
+    udp_binder: UdpBinder,
+    udp_sessions: &'static UdpSessions,
+    bandwidth: Option<&'static BandwidthConfig>,
+    socket: SocketOptions,
+}
+
+impl QuicOutbound {
+    #[inline]
+    fn domain_strategy(&self) -> DomainStrategy {
+        self.config.domain_strategy.unwrap_or(DomainStrategy::AS_IS)
+    }
+}
 
 pub async fn start(
     inbound_config: &'static InboundConfig,
-    _outboud_config: &'static OutboundConfig,
+    outbound_config: &'static OutboundConfig,
+    router: &'static Router,
 ) -> Result<()> {
     let address = (inbound_config.address.clone(), inbound_config.port)
         .to_socket_addrs()
@@ -23,49 +81,464 @@ pub async fn start(
     // TODO: Avoid using unwrap
     let server_crypto = make_server_config(&inbound_config.tls.clone().unwrap()).unwrap();
 
-    let config = quinn::ServerConfig::with_crypto(server_crypto);
+    let mut config = quinn::ServerConfig::with_crypto(server_crypto);
+
+    // Allow the clients to keep the connections when their addresses change, e.g. switching from Wi-Fi to LTE
+    let quic_config = inbound_config.quic.as_ref();
+    config.migration(quic_config.and_then(|c| c.migration).unwrap_or(true));
+
+    let mut transport = TransportConfig::default();
+    transport.max_idle_timeout(Some(idle_timeout(quic_config)?));
+    config.transport = Arc::new(transport);
+
+    let auth = AuthChain::init(inbound_config)?;
+    let outbound_tag = outbound_config
+        .tag
+        .as_deref()
+        .unwrap_or(DEFAULT_OUTBOUND_TAG);
+    // The UDP sessions count against the cap of the outbound along with the ones of the other inbounds
+    let udp_sessions = match router.handler(outbound_tag) {
+        Some(handler) => handler.udp_sessions(),
+        None => {
+            return Err(Error::new(
+                ErrorKind::NotFound,
+                format!("outbound {} of the QUIC inbound isn't routed", outbound_tag),
+            ))
+        }
+    };
+    let outbound = Arc::new(QuicOutbound {
+        policy: Policies::get().policy(outbound_config.policy.as_deref()),
+        tags: (inbound_config.tag.as_deref(), outbound_tag),
+        config: outbound_config,
+        udp_binder: UdpBinder::new(outbound_config.udp.as_ref())?,
+        udp_sessions,
+        bandwidth: inbound_config.bandwidth.as_ref(),
+        socket: SocketOptions::new(outbound_config.socket.as_ref()).unwrap_or_default(),
+    });
+    let limiter = inbound_config
+        .connection_limit
+        .as_ref()
+        .map(ConnectionLimiter::new);
+    let sources = inbound_config
+        .sources
+        .as_ref()
+        .map(|sources| IpFilter::new(sources, "source"))
+        .transpose()?;
 
     // Create QUIC server socket
     let (_endpoint, mut socket) = quinn::Endpoint::server(config, address).unwrap();
+    health::mark_listening();
+
+    // The endpoint is both the only listener and the whole inbound
+    let connections = match &inbound_config.connection_limit {
+        Some(config) => [
+            ConcurrencyLimit::for_listener(config, address)?,
+            ConcurrencyLimit::for_inbound(config)?,
+        ],
+        None => [None, None],
+    };
+    let limits: Vec<_> = connections.iter().flatten().collect();
 
     // Start accept loop to handle incomming QUIC connections
     while let Some(conn) = socket.next().await {
+        if !sources
+            .as_ref()
+            .is_none_or(|sources| sources.allows(conn.remote_address().ip()))
+        {
+            continue;
+        }
+
+        // The connection is closed when it is dropped, sources over their rate may be held back for a while instead
+        let delay = match limiter
+            .as_ref()
+            .map(|limiter| limiter.admit(conn.remote_address().ip()))
+        {
+            Some(Some(delay)) => delay,
+            Some(None) => continue,
+            None => Duration::ZERO,
+        };
+
+        // Held until the connection is closed
+        let slots = match acquire_slots(&limits).await {
+            Some(slots) => slots,
+            None => continue,
+        };
+
+        let outbound = outbound.clone();
+
         // Handle the new connection
         tokio::spawn(async move {
+            let _slots = slots;
+            if !delay.is_zero() {
+                tokio::time::sleep(delay).await;
+            }
+
+            let deadline = Deadline::new(inbound_config);
+
             // Establish QUIC connection with handshake
             let quinn::NewConnection {
-                connection: _,
+                connection,
                 mut bi_streams,
+                datagrams: incoming,
                 ..
-            } = match conn.await {
+            } = match deadline
+                .run("tls", async {
+                    conn.await
+                        .map_err(|e| Error::new(ErrorKind::ConnectionAborted, e))
+                })
+                .await
+            {
                 Ok(c) => c,
                 Err(_) => return,
             };
 
-            // Extract reader stream and writer stream from the established connection
-            let (mut client_writer, mut client_reader) = match bi_streams.next().await {
-                Some(stream) => stream.unwrap(),
-                None => return,
-            };
+            let mut remote_address = connection.remote_address();
+            let mut ticker = tokio::time::interval(MIGRATION_CHECK_INTERVAL);
 
-            // Read proxy request from the client stream
-            let request = parse(&mut client_reader).await.unwrap().into_request();
+            // Hand the datagrams over to the UDP sessions of the connection
+            let datagrams = Arc::new(QuicDatagrams::new(connection.clone()));
+            let receiver = datagrams.clone();
+            tokio::spawn(async move { receiver.receive(incoming).await });
 
-            // Connect to remote server
-            let addr_port: SocketAddr = request.addr_port.into();
-            let outbound_connection = TcpStream::connect(addr_port)
-                .await
-                .unwrap();
+            // Serve the streams opened by the client until the connection is closed, and watch the address of the
+            // client in the meantime
+            loop {
+                tokio::select! {
+                    stream = bi_streams.next() => match stream {
+                        Some(Ok((client_writer, client_reader))) => {
+                            let deadline = Deadline::new(inbound_config);
+                            let span = info_span!(
+                                "connection",
+                                otel.kind = "server",
+                                source = %remote_address,
+                                inbound = outbound.tags.0,
+                                destination = Empty,
+                                user = Empty,
+                                uploaded = Empty,
+                                downloaded = Empty
+                            );
+                            tokio::spawn(profiling::profile(
+                                remote_address,
+                                handle_stream(
+                                    datagrams.clone(),
+                                    client_writer,
+                                    client_reader,
+                                    deadline,
+                                    auth,
+                                    outbound.clone(),
+                                    router,
+                                )
+                                .instrument(span),
+                            ));
+                        }
+                        _ => break,
+                    },
+                    _ = ticker.tick() => {
+                        let current_address = connection.remote_address();
+                        if current_address != remote_address {
+                            info!(
+                                "QUIC connection migrated from {} to {}",
+                                remote_address, current_address
+                            );
+                            metrics::increment("quic_migrations_total", 1);
+                            remote_address = current_address;
+                        }
+                    }
+                }
+            }
+        });
+    }
+
+    Ok(())
+}
 
-            // Transport data between client and remote server
-            let (mut server_reader, mut server_writer) = tokio::io::split(outbound_connection);
+/// Read the proxy request from the QUIC stream and transport data between the client and the destination.
+/// Idle timeout of the connections, fails with InvalidInput if it is too large for QUIC.
+pub fn idle_timeout(config: Option<&InboundQuicConfig>) -> Result<IdleTimeout> {
+    let idle_timeout = config
+        .and_then(|c| c.idle_timeout)
+        .unwrap_or(DEFAULT_IDLE_TIMEOUT);
+    IdleTimeout::try_from(Duration::from_secs(idle_timeout)).map_err(|_| {
+        Error::new(
+            ErrorKind::InvalidInput,
+            format!("QUIC idle_timeout {} is too large", idle_timeout),
+        )
+    })
+}
 
-            tokio::select!(
-                _ = tokio::io::copy(&mut client_reader, &mut server_writer) => (),
-                _ = tokio::io::copy(&mut server_reader, &mut client_writer) => ()
-            );
-        });
+async fn handle_stream(
+    datagrams: Arc<QuicDatagrams>,
+    client_writer: SendStream,
+    mut client_reader: RecvStream,
+    deadline: Deadline,
+    auth: &'static AuthChain,
+    outbound: Arc<QuicOutbound>,
+    router: &'static Router,
+) {
+    // Read proxy request from the client stream and authenticate it
+    let request = match deadline.run("handshake", parse(&mut client_reader)).await {
+        Ok(request) => request,
+        Err(_) => return,
+    };
+    let hex = request.hex().to_vec();
+    let mut request = request.into_request();
+    let remote_address = datagrams.connection().remote_address();
+    match deadline
+        .run(
+            "authentication",
+            auth.authenticate(&hex, Some(remote_address), &request),
+        )
+        .await
+    {
+        Ok(user) => request.user = Some(user),
+        Err(e) => {
+            warn!("Failed to authenticate QUIC connection: {}", e);
+            return;
+        }
+    }
+    profiling::set_destination(&request.addr_port);
+    let span = Span::current();
+    span.record("destination", display(&request.addr_port));
+    if let Some(user) = &request.user {
+        span.record("user", user.name.as_str());
+    }
+    let user_rate = request
+        .user
+        .as_ref()
+        .and_then(|user| UserBandwidth::get().user(&user.name));
+
+    // The outbound of the server relays the requests routed to it and the UDP sessions itself, and the requests routed
+    // to the other outbounds go through their handlers
+    let (inbound_tag, outbound_tag) = outbound.tags;
+    let handler = match request.transport_protocol {
+        TransportProtocol::UDP => None,
+        _ => Some(router.route(&RouteContext {
+            request: &request,
+            inbound_tag,
+            sniffed_domain: None,
+        })),
+    }
+    .filter(|handler| handler.tag() != outbound_tag);
+    let context = TrafficContext::new(
+        inbound_tag,
+        Some(handler.map_or(outbound_tag, |handler| handler.tag())),
+        request.user.as_ref().map(|user| user.name.as_str()),
+    )
+    .with_destination(request.addr_port.to_string());
+    let connection = events::Connection::open(remote_address, &context);
+    let context = context.with_connection(connection.id());
+    profiling::set_context(&context);
+    let transfer = Transfer::new();
+    let registration = ActiveConnection {
+        id: connection.id(),
+        source: remote_address,
+        context: context.clone(),
+        transport: "quic",
+        mode: match request.transport_protocol {
+            TransportProtocol::UDP => "udp",
+            _ => "tcp",
+        },
+        transfer: transfer.clone(),
     }
+    .register();
+    let sampled = AccessLog::get().sample();
+    context
+        .clone()
+        .scope(async move {
+            metrics::increment_attributed("connections_total", 1);
+            AccessLog::get().record(&request.addr_port);
+            if sampled && !AccessLog::get().separate() {
+                info!(
+                    "QUIC stream from {} requests {} ({})",
+                    remote_address, request.addr_port, context
+                );
+            }
+            let destination = request.addr_port.to_string();
+            let result = transfer
+                .scope(registration.until_closed(async {
+                    match request.transport_protocol {
+                        TransportProtocol::UDP => {
+                            let session = datagrams.open(client_writer.id().index());
+                            relay_udp(&outbound, session, client_reader, client_writer).await
+                        }
+                        _ => {
+                            port::check_request(&request, "quic")?;
+                            let client_reader = Throttled::new(client_reader, outbound.bandwidth)
+                                .with_user(user_rate)
+                                .with_global(GlobalBandwidth::get());
+                            let mut client_writer =
+                                Throttled::new(client_writer, outbound.bandwidth)
+                                    .with_user(user_rate)
+                                    .with_global(GlobalBandwidth::get());
+                            match handler {
+                                Some(handler) => {
+                                    handler
+                                        .dispatch_piped(
+                                            request,
+                                            deadline,
+                                            client_reader,
+                                            |mut server_reader| async move {
+                                                copy_limited(
+                                                    &mut server_reader,
+                                                    &mut client_writer,
+                                                    None,
+                                                )
+                                                .await?;
+                                                client_writer.shutdown().await
+                                            },
+                                        )
+                                        .await
+                                }
+                                None => {
+                                    connect(
+                                        request,
+                                        deadline,
+                                        &outbound,
+                                        client_reader,
+                                        client_writer,
+                                    )
+                                    .await
+                                }
+                            }
+                        }
+                    }
+                }))
+                .await;
+            match result {
+                Ok(()) => {
+                    if sampled {
+                        AccessLog::get().log(format_args!(
+                            "QUIC stream from {} to {} ({}) has finished: {}",
+                            remote_address, destination, context, transfer
+                        ));
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::PermissionDenied || registration.is_closed() => {
+                    if sampled {
+                        AccessLog::get().log(format_args!(
+                            "QUIC stream from {} to {} ({}) is closed: {}",
+                            remote_address, destination, context, e
+                        ));
+                    }
+                }
+                Err(e) => {
+                    warn!("Failed to handle QUIC stream ({}): {}", context, e);
+                    if sampled && AccessLog::get().separate() {
+                        AccessLog::get().log(format_args!(
+                            "QUIC stream from {} to {} ({}) has failed: {}",
+                            remote_address, destination, context, e
+                        ));
+                    }
+                    connection.fail(&e);
+                }
+            }
+        })
+        .await
+}
+
+/// Connect to the destination of the request and transport data between it and the client.
+async fn connect(
+    request: InboundRequest,
+    deadline: Deadline,
+    outbound: &QuicOutbound,
+    client_reader: Throttled<RecvStream>,
+    client_writer: Throttled<SendStream>,
+) -> Result<()> {
+    // Connect to remote server
+    let addr_port = deadline.run("dns", request.addr_port.resolve()).await?;
+    DestinationFilter::get().check(addr_port, "tcp")?;
+    let policy = outbound.policy;
+    policy.check_destination(addr_port.ip())?;
+    let outbound_connection = match policy
+        .connect(&deadline, || outbound.socket.connect(addr_port))
+        .await
+    {
+        Ok(connection) => connection,
+        Err(e) => {
+            return Err(Error::new(
+                e.kind(),
+                format!("failed to connect to {}: {}", addr_port, e),
+            ))
+        }
+    };
+
+    // Transport data between client and remote server
+    let (server_reader, server_writer) = tokio::io::split(outbound_connection);
 
+    relay_with_policy(
+        client_reader,
+        client_writer,
+        server_reader,
+        server_writer,
+        policy,
+    )
+    .await
+}
+
+/// Relay the UDP session of the request between the client and its destinations. The packets go both ways on the
+/// stream of the request or as datagrams of the session, and the replies are sent as datagrams once the client has
+/// sent one. The session ends when the client closes the stream or it is idle for the UDP session TTL.
+async fn relay_udp(
+    outbound: &QuicOutbound,
+    mut session: DatagramSession,
+    client_reader: RecvStream,
+    client_writer: SendStream,
+) -> Result<()> {
+    let strategy = outbound.domain_strategy();
+    let socket = Arc::new(outbound.udp_binder.bind(strategy).await?);
+    let _entry = outbound.udp_sessions.open(socket.local_addr()?)?;
+    let guard = Arc::new(
+        UdpGuard::new(outbound.config.udp.as_ref())
+            .with_domain_strategy(strategy)
+            .with_destinations(outbound.policy.destinations.clone()),
+    );
+    let mut client_writer = TrojanPacketWriter::new(client_writer)
+        .with_datagrams(session.sender())
+        .with_max_packet_size(guard.max_packet_size());
+
+    // Close the session once no datagram goes either way for the TTL
+    let idle = async {
+        match outbound.policy.udp_session_ttl {
+            Some(ttl) => guard.idle(ttl).await,
+            None => pending().await,
+        }
+    };
+
+    tokio::select!(
+        _ = copy_client_reader_to_udp_socket(client_reader, &socket, &guard) => (),
+        _ = copy_session_to_udp_socket(&mut session, &socket, &guard) => (),
+        _ = copy_udp_socket_to_client_writer(&socket, &mut client_writer, &guard) => (),
+        _ = idle => {
+            debug!("Closing idle UDP session");
+            metrics::increment_attributed("idle_timeouts_total{transport=\"udp\"}", 1);
+        }
+    );
+
+    // Deliver the replies still queued and end the stream cleanly
+    client_writer.shutdown().await
+}
+
+/// Hand the packets the client sent as datagrams over to the UDP workers.
+async fn copy_session_to_udp_socket(
+    session: &mut DatagramSession,
+    socket: &Arc<UdpSocket>,
+    guard: &Arc<UdpGuard>,
+) -> Result<()> {
+    let workers = UdpWorkers::init();
+    let local = socket.local_addr()?;
+
+    while let Some((dest, payload)) = session.recv().await {
+        if !guard.fits(packet_size(&dest, payload.len())) {
+            debug!(
+                "Dropping UDP datagram of {} bytes to {}",
+                payload.len(),
+                dest
+            );
+            metrics::increment("udp_requests_dropped_total{reason=\"too_large\"}", 1);
+            continue;
+        }
+        Transfer::count(payload.len() as u64, 0);
+        workers.send(local, socket, guard, dest, payload).await?;
+    }
     Ok(())
 }
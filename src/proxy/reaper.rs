@@ -0,0 +1,188 @@
+use crate::config::base::ReaperConfig;
+use crate::metrics;
+use crate::proxy::context::TrafficContext;
+use crate::proxy::relay::Activity;
+
+use log::{debug, info};
+use once_cell::sync::OnceCell;
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Result};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::Notify;
+use tokio::time::Instant;
+
+/// Default interval of sweeping the sessions in seconds
+const DEFAULT_INTERVAL: u64 = 60;
+
+/// Static lifetime reaper of the sessions relayed by the process
+static REAPER: OnceCell<Reaper> = OnceCell::new();
+
+/// Session known to the reaper.
+struct Session {
+    activity: Arc<Activity>,
+    idle_timeout: Option<Duration>,
+    reaped: Arc<Notify>,
+}
+
+/// Reaper keeps track of the relayed sessions and closes the ones idle for longer than their policy allows, or for
+/// longer than the idle timeout of the reaper if their policy never closes them, so that half-dead connections whose
+/// peers vanished without a FIN don't pile up. Every session closed for being idle is logged with its age, user and
+/// destination, and counted in reaped_sessions_total.
+pub struct Reaper {
+    idle_timeout: Option<Duration>,
+    interval: Duration,
+    sessions: Mutex<HashMap<u64, Session>>,
+    next_id: AtomicU64,
+}
+
+impl Reaper {
+    /// Build the reaper shared by the whole process.
+    pub fn init(config: Option<&ReaperConfig>) -> Result<&'static Self> {
+        REAPER.get_or_try_init(|| Self::new(config))
+    }
+
+    /// Reaper shared by the whole process, with the default values if it wasn't initialized yet.
+    pub fn get() -> &'static Self {
+        REAPER.get_or_init(|| Self::with_interval(None, DEFAULT_INTERVAL))
+    }
+
+    /// Fails with InvalidInput if the interval is zero, as the sessions would be swept without a break.
+    pub fn new(config: Option<&ReaperConfig>) -> Result<Self> {
+        let idle_timeout = config.and_then(|config| config.idle_timeout);
+        let interval = config
+            .and_then(|config| config.interval)
+            .unwrap_or(DEFAULT_INTERVAL);
+        if interval == 0 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "interval of the reaper must be positive",
+            ));
+        }
+
+        Ok(Self::with_interval(idle_timeout, interval))
+    }
+
+    fn with_interval(idle_timeout: Option<u64>, interval: u64) -> Self {
+        Self {
+            idle_timeout: idle_timeout.map(Duration::from_secs),
+            interval: Duration::from_secs(interval),
+            sessions: Mutex::new(HashMap::new()),
+            next_id: AtomicU64::new(0),
+        }
+    }
+
+    /// Keep track of the session with the activity until the returned session is dropped, idle_timeout is the one of
+    /// its policy.
+    pub fn track(
+        &self,
+        activity: Arc<Activity>,
+        idle_timeout: Option<Duration>,
+    ) -> TrackedSession<'_> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let reaped = Arc::new(Notify::new());
+        self.sessions.lock().unwrap().insert(
+            id,
+            Session {
+                activity: activity.clone(),
+                idle_timeout,
+                reaped: reaped.clone(),
+            },
+        );
+
+        TrackedSession {
+            reaper: self,
+            id,
+            started: Instant::now(),
+            activity,
+            reaped,
+        }
+    }
+
+    /// Number of sessions tracked.
+    pub fn len(&self) -> usize {
+        self.sessions.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Close the sessions idle beyond their timeout, returns the number of sessions closed.
+    pub fn sweep(&self) -> usize {
+        let mut reaped = 0;
+        self.sessions.lock().unwrap().retain(|_, session| {
+            let timeout = match session.idle_timeout.or(self.idle_timeout) {
+                Some(timeout) => timeout,
+                None => return true,
+            };
+            if session.activity.idle_for() < timeout {
+                return true;
+            }
+
+            // The relay of the session logs and counts it as it closes the connection
+            session.reaped.notify_one();
+            reaped += 1;
+            false
+        });
+        reaped
+    }
+
+    /// Sweep the sessions every interval for as long as the process runs.
+    pub async fn run(&self) {
+        let mut ticker = tokio::time::interval(self.interval);
+
+        loop {
+            ticker.tick().await;
+
+            let reaped = self.sweep();
+            if reaped > 0 {
+                debug!("Reaper closed {} idle sessions", reaped);
+            }
+            metrics::set("relayed_sessions", self.len() as u64);
+        }
+    }
+
+    fn untrack(&self, id: u64) {
+        self.sessions.lock().unwrap().remove(&id);
+    }
+}
+
+/// Session tracked by the reaper.
+pub struct TrackedSession<'a> {
+    reaper: &'a Reaper,
+    id: u64,
+    started: Instant,
+    activity: Arc<Activity>,
+    reaped: Arc<Notify>,
+}
+
+impl TrackedSession<'_> {
+    /// Wait until the reaper closes the session.
+    pub async fn reaped(&self) {
+        self.reaped.notified().await
+    }
+
+    /// Log and count the session closed for being idle, whether by the reaper or by the timer of its relay.
+    pub fn report(&self) {
+        let context = TrafficContext::current().unwrap_or_default();
+        info!(
+            "Reaped connection to {} ({}) idle for {}s, open for {}s",
+            context
+                .destination
+                .as_deref()
+                .unwrap_or("unknown destination"),
+            context,
+            self.activity.idle_for().as_secs(),
+            self.started.elapsed().as_secs()
+        );
+        metrics::increment("reaped_sessions_total", 1);
+    }
+}
+
+impl Drop for TrackedSession<'_> {
+    fn drop(&mut self) {
+        self.reaper.untrack(self.id);
+    }
+}
@@ -0,0 +1,854 @@
+use crate::events::{DOWNLOADED_BYTES, UPLOADED_BYTES};
+use crate::metrics;
+use crate::protocol::common::stream::PrefixedStream;
+use crate::proxy::buffer::{Buffer, BufferPool};
+use crate::proxy::policy::Policy;
+use crate::proxy::reaper::Reaper;
+#[cfg(target_os = "linux")]
+use crate::proxy::splice::{self, Pipe};
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+use crate::proxy::uring::{self, UringWorkers};
+
+use futures::future::Either;
+use log::debug;
+#[cfg(target_os = "linux")]
+use socket2::SockRef;
+use std::fmt;
+use std::future::{pending, Future};
+use std::io::{self, IoSlice};
+#[cfg(target_os = "linux")]
+use std::net::Shutdown;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::TcpStream;
+use tracing::{info_span, Instrument, Span};
+
+/// Writes blocked on a full send buffer for longer than this are reported as stalls
+const STALL_THRESHOLD: Duration = Duration::from_millis(200);
+
+tokio::task_local! {
+    /// Transfer of the connection handled by the task
+    static TRANSFER: Arc<Transfer>;
+}
+
+/// Bytes relayed each way for a connection and the time it started at, for the summary logged once it ends and the
+/// list of the active connections. The relays running within the scope of the transfer add the bytes to it as they
+/// move them, and the UDP sessions through `count`. The bytes are counted in the usage statistics of the user from it.
+pub struct Transfer {
+    started: Instant,
+    uploaded: AtomicU64,
+    downloaded: AtomicU64,
+    /// Bytes of each way already counted in the usage statistics
+    #[cfg(feature = "sqlite")]
+    booked: [AtomicU64; 2],
+}
+
+impl Transfer {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self {
+            started: Instant::now(),
+            uploaded: AtomicU64::new(0),
+            downloaded: AtomicU64::new(0),
+            #[cfg(feature = "sqlite")]
+            booked: [AtomicU64::new(0), AtomicU64::new(0)],
+        })
+    }
+
+    /// Run the future with the transfer as the one the relays count their bytes in, and count them in the usage
+    /// statistics of the user of the connection once it ends.
+    pub async fn scope<F: Future>(self: &Arc<Self>, future: F) -> F::Output {
+        #[cfg(feature = "sqlite")]
+        let _booking = metrics::usage::Booking::new(self);
+        TRANSFER.scope(self.clone(), future).await
+    }
+
+    /// Bytes relayed from the client to the destination.
+    #[inline]
+    pub fn uploaded(&self) -> u64 {
+        self.uploaded.load(Ordering::Relaxed)
+    }
+
+    /// Bytes relayed from the destination to the client.
+    #[inline]
+    pub fn downloaded(&self) -> u64 {
+        self.downloaded.load(Ordering::Relaxed)
+    }
+
+    /// Bytes relayed each way since the last call, for the usage statistics.
+    #[cfg(feature = "sqlite")]
+    pub(crate) fn unbooked(&self) -> (u64, u64) {
+        let uploaded = self.uploaded();
+        let downloaded = self.downloaded();
+        (
+            uploaded.saturating_sub(self.booked[0].fetch_max(uploaded, Ordering::Relaxed)),
+            downloaded.saturating_sub(self.booked[1].fetch_max(downloaded, Ordering::Relaxed)),
+        )
+    }
+
+    /// Time since the connection started.
+    #[inline]
+    pub fn duration(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// Add the bytes relayed outside of the relays of this module, like the payloads of the UDP packets, to the
+    /// transfer of the current task, if any, and to the traffic rate events.
+    pub fn count(uploaded: u64, downloaded: u64) {
+        UPLOADED_BYTES.fetch_add(uploaded, Ordering::Relaxed);
+        DOWNLOADED_BYTES.fetch_add(downloaded, Ordering::Relaxed);
+        let _ = TRANSFER.try_with(|transfer| {
+            transfer.uploaded.fetch_add(uploaded, Ordering::Relaxed);
+            transfer.downloaded.fetch_add(downloaded, Ordering::Relaxed);
+        });
+    }
+
+    /// Transfer of the current task, or one nobody reads outside the scope of any.
+    fn current() -> Arc<Self> {
+        TRANSFER
+            .try_with(Arc::clone)
+            .unwrap_or_else(|_| Self::new())
+    }
+}
+
+impl fmt::Display for Transfer {
+    /// Like 1200 bytes up and 5400 bytes down in 3021ms
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            fmt,
+            "{} bytes up and {} bytes down in {}ms",
+            self.uploaded(),
+            self.downloaded(),
+            self.duration().as_millis()
+        )
+    }
+}
+
+/// Transport data between the client and the server in both directions until both sides terminate the connection, or
+/// until either fails. Once one side stops sending, the end is passed on to the other side and the data it still sends
+/// back keeps flowing, for the half close timeout of the policy at most. Writes towards each side are monitored, such
+/// that stalls on the client side point to a slow client, while stalls on the upstream side point to a slow
+/// destination.
+pub async fn relay<CR, CW, SR, SW>(
+    client_reader: CR,
+    client_writer: CW,
+    server_reader: SR,
+    server_writer: SW,
+) -> io::Result<()>
+where
+    CR: AsyncRead + Unpin,
+    CW: AsyncWrite + Unpin,
+    SR: AsyncRead + Unpin,
+    SW: AsyncWrite + Unpin,
+{
+    relay_with_idle_timeout(
+        client_reader,
+        client_writer,
+        server_reader,
+        server_writer,
+        None,
+    )
+    .await
+}
+
+/// Transport data like relay, and also terminate the connection once no data is read from either side for the idle
+/// timeout.
+pub async fn relay_with_idle_timeout<CR, CW, SR, SW>(
+    client_reader: CR,
+    client_writer: CW,
+    server_reader: SR,
+    server_writer: SW,
+    idle_timeout: Option<Duration>,
+) -> io::Result<()>
+where
+    CR: AsyncRead + Unpin,
+    CW: AsyncWrite + Unpin,
+    SR: AsyncRead + Unpin,
+    SW: AsyncWrite + Unpin,
+{
+    let policy = Policy {
+        idle_timeout,
+        ..Default::default()
+    };
+    relay_with_policy(
+        client_reader,
+        client_writer,
+        server_reader,
+        server_writer,
+        &policy,
+    )
+    .await
+}
+
+/// Transport data like relay within the limits of the policy, terminating the connection once it is idle for the idle
+/// timeout, once it is open for the max connection lifetime or once the bytes sent either way reach the cap of the
+/// policy.
+pub async fn relay_with_policy<CR, CW, SR, SW>(
+    client_reader: CR,
+    client_writer: CW,
+    server_reader: SR,
+    server_writer: SW,
+    policy: &Policy,
+) -> io::Result<()>
+where
+    CR: AsyncRead + Unpin,
+    CW: AsyncWrite + Unpin,
+    SR: AsyncRead + Unpin,
+    SW: AsyncWrite + Unpin,
+{
+    let activity = Arc::new(Activity::new());
+    let transfer = Transfer::current();
+    let mut client_reader = ActivityMonitor::new(
+        client_reader,
+        &activity,
+        [&UPLOADED_BYTES, &transfer.uploaded],
+    );
+    let mut server_reader = ActivityMonitor::new(
+        server_reader,
+        &activity,
+        [&DOWNLOADED_BYTES, &transfer.downloaded],
+    );
+    let mut client_writer = StallMonitor::new(client_writer, "client");
+    let mut server_writer = StallMonitor::new(server_writer, "upstream");
+
+    let upload = async {
+        let copied =
+            copy_limited(&mut client_reader, &mut server_writer, policy.max_upload).await?;
+        if check_limit(copied, policy.max_upload, "upload") {
+            return Ok(false);
+        }
+        server_writer.shutdown().await?;
+        Ok(true)
+    };
+    let download = async {
+        let copied =
+            copy_limited(&mut server_reader, &mut client_writer, policy.max_download).await?;
+        if check_limit(copied, policy.max_download, "download") {
+            return Ok(false);
+        }
+        client_writer.shutdown().await?;
+        Ok(true)
+    };
+
+    supervise(
+        &activity,
+        policy.idle_timeout,
+        policy.max_connection_lifetime,
+        drain(upload, download, policy.half_close_timeout),
+    )
+    .await;
+
+    count_traffic(client_reader.read, server_reader.read);
+    Ok(())
+}
+
+/// Transport data like relay_with_policy between two plain TCP sockets, head holding the bytes already read from the
+/// client that go to the server first. On Linux the data is moved from one socket to the other with splice, without
+/// copying it through userspace, and write stalls aren't monitored. Elsewhere, or if the kernel can't provide the
+/// pipes, the data is copied like relay_with_policy does. With the io_uring workers running, they copy the data
+/// instead.
+pub async fn relay_tcp_with_policy(
+    client: TcpStream,
+    head: Vec<u8>,
+    server: TcpStream,
+    policy: &Policy,
+) -> io::Result<()> {
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    if let Some(workers) = UringWorkers::get() {
+        return relay_uring(workers, client, head, server, policy).await;
+    }
+
+    #[cfg(target_os = "linux")]
+    match (Pipe::new(), Pipe::new()) {
+        (Ok(upload), Ok(download)) => {
+            return relay_spliced(client, head, server, policy, upload, download).await
+        }
+        (Err(e), _) | (_, Err(e)) => debug!("Failed to create pipes to splice: {}", e),
+    }
+
+    let (client_reader, client_writer) = tokio::io::split(PrefixedStream::new(head, client));
+    let (server_reader, server_writer) = tokio::io::split(server);
+    relay_with_policy(
+        client_reader,
+        client_writer,
+        server_reader,
+        server_writer,
+        policy,
+    )
+    .await
+}
+
+#[cfg(target_os = "linux")]
+async fn relay_spliced(
+    client: TcpStream,
+    head: Vec<u8>,
+    mut server: TcpStream,
+    policy: &Policy,
+    upload_pipe: Pipe,
+    download_pipe: Pipe,
+) -> io::Result<()> {
+    metrics::increment("spliced_connections_total", 1);
+    server.write_all(&head).await?;
+    UPLOADED_BYTES.fetch_add(head.len() as u64, Ordering::Relaxed);
+    let transfer = Transfer::current();
+    transfer
+        .uploaded
+        .fetch_add(head.len() as u64, Ordering::Relaxed);
+
+    let activity = Arc::new(Activity::new());
+    let uploaded = AtomicU64::new(head.len() as u64);
+    let downloaded = AtomicU64::new(0);
+
+    let max_upload = policy
+        .max_upload
+        .map(|limit| limit.saturating_sub(head.len() as u64));
+    let upload = async {
+        let copied = splice::copy(
+            &client,
+            &server,
+            &upload_pipe,
+            max_upload,
+            &activity,
+            &[&uploaded, &UPLOADED_BYTES, &transfer.uploaded],
+        )
+        .await?;
+        if check_limit(copied, max_upload, "upload") {
+            return Ok(false);
+        }
+        SockRef::from(&server).shutdown(Shutdown::Write)?;
+        io::Result::Ok(true)
+    };
+    let download = async {
+        let copied = splice::copy(
+            &server,
+            &client,
+            &download_pipe,
+            policy.max_download,
+            &activity,
+            &[&downloaded, &DOWNLOADED_BYTES, &transfer.downloaded],
+        )
+        .await?;
+        if check_limit(copied, policy.max_download, "download") {
+            return Ok(false);
+        }
+        SockRef::from(&client).shutdown(Shutdown::Write)?;
+        io::Result::Ok(true)
+    };
+
+    supervise(
+        &activity,
+        policy.idle_timeout,
+        policy.max_connection_lifetime,
+        drain(upload, download, policy.half_close_timeout),
+    )
+    .await;
+    count_traffic(
+        uploaded.load(Ordering::Relaxed),
+        downloaded.load(Ordering::Relaxed),
+    );
+    Ok(())
+}
+
+/// Relay on an io_uring worker, the relay ends on the worker as soon as the returned future is done or dropped.
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+async fn relay_uring(
+    workers: &UringWorkers,
+    client: TcpStream,
+    head: Vec<u8>,
+    mut server: TcpStream,
+    policy: &Policy,
+) -> io::Result<()> {
+    metrics::increment("uring_connections_total", 1);
+    server.write_all(&head).await?;
+    UPLOADED_BYTES.fetch_add(head.len() as u64, Ordering::Relaxed);
+    let transfer = Transfer::current();
+    transfer
+        .uploaded
+        .fetch_add(head.len() as u64, Ordering::Relaxed);
+
+    let activity = Arc::new(Activity::new());
+    let uploaded = Arc::new(AtomicU64::new(head.len() as u64));
+    let downloaded = Arc::new(AtomicU64::new(0));
+
+    let max_upload = policy
+        .max_upload
+        .map(|limit| limit.saturating_sub(head.len() as u64));
+    let max_download = policy.max_download;
+    let half_close_timeout = policy.half_close_timeout;
+    let size = BufferPool::get().size();
+    let (client, server) = (client.into_std()?, server.into_std()?);
+    let (mut done, finished) = tokio::sync::oneshot::channel();
+
+    let task = {
+        let (activity, uploaded, downloaded, transfer) = (
+            activity.clone(),
+            uploaded.clone(),
+            downloaded.clone(),
+            transfer.clone(),
+        );
+        move || async move {
+            let client = tokio_uring::net::TcpStream::from_std(client);
+            let server = tokio_uring::net::TcpStream::from_std(server);
+
+            let upload = async {
+                let copied = uring::copy(
+                    &client,
+                    &server,
+                    size,
+                    max_upload,
+                    &activity,
+                    &[&uploaded, &UPLOADED_BYTES, &transfer.uploaded],
+                )
+                .await?;
+                if check_limit(copied, max_upload, "upload") {
+                    return Ok(false);
+                }
+                server.shutdown(Shutdown::Write)?;
+                io::Result::Ok(true)
+            };
+            let download = async {
+                let copied = uring::copy(
+                    &server,
+                    &client,
+                    size,
+                    max_download,
+                    &activity,
+                    &[&downloaded, &DOWNLOADED_BYTES, &transfer.downloaded],
+                )
+                .await?;
+                if check_limit(copied, max_download, "download") {
+                    return Ok(false);
+                }
+                client.shutdown(Shutdown::Write)?;
+                io::Result::Ok(true)
+            };
+
+            tokio::select!(
+                _ = drain(upload, download, half_close_timeout) => (),
+                _ = done.closed() => (),
+            );
+            // The reads still in flight keep the sockets open until they complete, which shutting them down forces
+            let _ = client.shutdown(Shutdown::Both);
+            let _ = server.shutdown(Shutdown::Both);
+            let _ = done.send(());
+        }
+    };
+    workers.spawn(task);
+
+    supervise(
+        &activity,
+        policy.idle_timeout,
+        policy.max_connection_lifetime,
+        finished,
+    )
+    .await;
+    count_traffic(
+        uploaded.load(Ordering::Relaxed),
+        downloaded.load(Ordering::Relaxed),
+    );
+    Ok(())
+}
+
+/// Run the copies in each direction until the reader of one of them ends and the other direction is drained, until
+/// either copy fails or reaches its limit. Each copy returns whether its reader ended, in which case it has passed the
+/// end on to its writer. The other direction gets the grace period to finish, so that a peer which never closes its
+/// side doesn't hold the connection open forever.
+async fn drain<U, D>(upload: U, download: D, grace: Duration)
+where
+    U: Future<Output = io::Result<bool>>,
+    D: Future<Output = io::Result<bool>>,
+{
+    tokio::pin!(upload, download);
+    let other = tokio::select!(
+        ended = &mut upload => match ended {
+            Ok(true) => Either::Left(download),
+            _ => return,
+        },
+        ended = &mut download => match ended {
+            Ok(true) => Either::Right(upload),
+            _ => return,
+        },
+    );
+
+    if tokio::time::timeout(grace, other).await.is_err() {
+        debug!("Closing half closed connection after {:?}", grace);
+        metrics::increment_attributed("half_close_timeouts_total", 1);
+    }
+}
+
+/// Run the copies until they are done, until the session is idle for the timeout or open for the max lifetime, or
+/// until it is closed by the reaper.
+async fn supervise<F: Future>(
+    activity: &Arc<Activity>,
+    idle_timeout: Option<Duration>,
+    max_lifetime: Option<Duration>,
+    copies: F,
+) {
+    let session = Reaper::get().track(activity.clone(), idle_timeout);
+    let idle = async {
+        match idle_timeout {
+            Some(timeout) => activity.idle(timeout).await,
+            None => pending().await,
+        }
+    };
+    let expired = async {
+        match max_lifetime {
+            Some(lifetime) => tokio::time::sleep(lifetime).await,
+            None => pending().await,
+        }
+    };
+
+    let relay = async {
+        tokio::select!(
+            _ = copies => (),
+            _ = idle => {
+                debug!("Closing connection idle for {:?}", idle_timeout.unwrap_or_default());
+                metrics::increment_attributed("idle_timeouts_total{transport=\"tcp\"}", 1);
+                session.report();
+            }
+            _ = expired => {
+                debug!("Closing connection open for {:?}", max_lifetime.unwrap_or_default());
+                metrics::increment_attributed("lifetime_timeouts_total{transport=\"tcp\"}", 1);
+            }
+            _ = session.reaped() => session.report(),
+        );
+    };
+    relay.instrument(info_span!("relay")).await
+}
+
+fn count_traffic(upload: u64, download: u64) {
+    let span = Span::current();
+    span.record("uploaded", upload);
+    span.record("downloaded", download);
+    metrics::increment_attributed("traffic_bytes_total{direction=\"upload\"}", upload);
+    metrics::increment_attributed("traffic_bytes_total{direction=\"download\"}", download);
+}
+
+/// Copy the data until the reader ends or the limit is reached, whichever comes first, through a buffer of the shared
+/// pool, which holds relay_buffer_size bytes.
+pub(crate) async fn copy_limited<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    limit: Option<u64>,
+) -> io::Result<u64>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let pool = BufferPool::get();
+    RingCopy {
+        reader,
+        writer,
+        buf: pool.take_zeroed(pool.size()),
+        start: 0,
+        len: 0,
+        remaining: limit.unwrap_or(u64::MAX),
+        read_done: limit == Some(0),
+        read_error: None,
+        needs_flush: false,
+        copied: 0,
+    }
+    .await
+}
+
+/// Report the connection closed for reaching the limit of bytes in the direction, returns whether it is reached.
+fn check_limit(copied: u64, limit: Option<u64>, direction: &str) -> bool {
+    match limit {
+        Some(limit) if copied >= limit => (),
+        _ => return false,
+    }
+
+    debug!("Closing connection after {} bytes of {}", copied, direction);
+    metrics::increment_attributed(
+        &format!("byte_limits_exceeded_total{{direction=\"{}\"}}", direction),
+        1,
+    );
+    true
+}
+
+/// Copy through a ring buffer, reading into the free part of the buffer while the writer is still busy with the data
+/// read before, so that a slow write doesn't hold the reads back on links with a high bandwidth-delay product, as long
+/// as the buffer has room. Data wrapping around the end of the buffer goes out in a single vectored write.
+struct RingCopy<'a, R, W> {
+    reader: &'a mut R,
+    writer: &'a mut W,
+    buf: Buffer,
+    /// Position and length of the data read but not written yet
+    start: usize,
+    len: usize,
+    /// Bytes left to read before the limit
+    remaining: u64,
+    read_done: bool,
+    /// Error the reader failed with, returned once the data read before is written
+    read_error: Option<io::Error>,
+    needs_flush: bool,
+    copied: u64,
+}
+
+impl<R: AsyncRead + Unpin, W: AsyncWrite + Unpin> Future for RingCopy<'_, R, W> {
+    type Output = io::Result<u64>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        let this = self.get_mut();
+        let capacity = this.buf.len();
+
+        loop {
+            let mut progress = false;
+
+            if !this.read_done && this.len < capacity {
+                // Fill the free space after the data, up to the end of the buffer or to the start of the data
+                if this.len == 0 {
+                    this.start = 0;
+                }
+                let end = this.start + this.len;
+                let (from, to) = match end < capacity {
+                    true => (end, capacity),
+                    false => (end - capacity, this.start),
+                };
+                let to = from + (to - from).min(this.remaining.min(usize::MAX as u64) as usize);
+
+                let mut read_buf = ReadBuf::new(&mut this.buf[from..to]);
+                match Pin::new(&mut *this.reader).poll_read(cx, &mut read_buf) {
+                    Poll::Ready(Ok(())) => {
+                        let read = read_buf.filled().len();
+                        this.len += read;
+                        this.remaining -= read as u64;
+                        this.read_done = read == 0 || this.remaining == 0;
+                        progress = true;
+                    }
+                    Poll::Ready(Err(e)) => {
+                        this.read_done = true;
+                        this.read_error = Some(e);
+                        progress = true;
+                    }
+                    // Nothing more to come for now, push out what the writer may hold back
+                    Poll::Pending if this.len == 0 && this.needs_flush => {
+                        ready!(Pin::new(&mut *this.writer).poll_flush(cx))?;
+                        this.needs_flush = false;
+                    }
+                    Poll::Pending => (),
+                }
+            }
+
+            if this.len > 0 {
+                let end = this.start + this.len;
+                let poll = match end <= capacity {
+                    true => Pin::new(&mut *this.writer).poll_write(cx, &this.buf[this.start..end]),
+                    false => {
+                        let slices = [
+                            IoSlice::new(&this.buf[this.start..]),
+                            IoSlice::new(&this.buf[..end - capacity]),
+                        ];
+                        Pin::new(&mut *this.writer).poll_write_vectored(cx, &slices)
+                    }
+                };
+                match poll {
+                    Poll::Ready(Ok(0)) => {
+                        return Poll::Ready(Err(io::Error::new(
+                            io::ErrorKind::WriteZero,
+                            "write zero bytes into writer",
+                        )))
+                    }
+                    Poll::Ready(Ok(written)) => {
+                        this.start = (this.start + written) % capacity;
+                        this.len -= written;
+                        this.copied += written as u64;
+                        this.needs_flush = true;
+                        progress = true;
+                    }
+                    Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                    Poll::Pending => (),
+                }
+            }
+
+            if this.read_done && this.len == 0 {
+                ready!(Pin::new(&mut *this.writer).poll_flush(cx))?;
+                return Poll::Ready(match this.read_error.take() {
+                    Some(e) => Err(e),
+                    None => Ok(this.copied),
+                });
+            }
+            if !progress {
+                return Poll::Pending;
+            }
+        }
+    }
+}
+
+/// Time of the last data moved by a session, shared by the futures moving the data in each direction.
+pub struct Activity {
+    // Tokio clock, so that paused time in the tests applies as well
+    start: tokio::time::Instant,
+    /// Milliseconds from start to the last activity
+    last: AtomicU64,
+}
+
+impl Activity {
+    pub fn new() -> Self {
+        Self {
+            start: tokio::time::Instant::now(),
+            last: AtomicU64::new(0),
+        }
+    }
+
+    /// Record activity of the session now.
+    #[inline]
+    pub fn touch(&self) {
+        self.last
+            .store(self.start.elapsed().as_millis() as u64, Ordering::Relaxed);
+    }
+
+    /// Time since the last activity of the session.
+    pub fn idle_for(&self) -> Duration {
+        let last = Duration::from_millis(self.last.load(Ordering::Relaxed));
+        self.start.elapsed().saturating_sub(last)
+    }
+
+    /// Wait until there is no activity for the timeout.
+    pub async fn idle(&self, timeout: Duration) {
+        loop {
+            let idle = self.idle_for();
+            if idle >= timeout {
+                return;
+            }
+            tokio::time::sleep(timeout - idle).await;
+        }
+    }
+}
+
+impl Default for Activity {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Reader wrapper that records the activity whenever data is read, and counts the bytes read, adding them to the
+/// total of the process for the traffic rate events and to the transfer of the connection.
+struct ActivityMonitor<'a, R> {
+    inner: R,
+    activity: &'a Activity,
+    read: u64,
+    totals: [&'a AtomicU64; 2],
+}
+
+impl<'a, R> ActivityMonitor<'a, R> {
+    fn new(inner: R, activity: &'a Activity, totals: [&'a AtomicU64; 2]) -> Self {
+        Self {
+            inner,
+            activity,
+            read: 0,
+            totals,
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for ActivityMonitor<'_, R> {
+    #[inline]
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let filled = buf.filled().len();
+        let poll = Pin::new(&mut this.inner).poll_read(cx, buf);
+        if buf.filled().len() > filled {
+            let read = (buf.filled().len() - filled) as u64;
+            this.activity.touch();
+            this.read += read;
+            for total in this.totals {
+                total.fetch_add(read, Ordering::Relaxed);
+            }
+        }
+        poll
+    }
+}
+
+/// Writer wrapper that measures the time the inner writer spends returning Pending, which happens when the send
+/// buffer is full and the peer isn't draining it fast enough.
+struct StallMonitor<W> {
+    inner: W,
+    side: &'static str,
+    stalled_since: Option<Instant>,
+}
+
+impl<W> StallMonitor<W> {
+    fn new(inner: W, side: &'static str) -> Self {
+        Self {
+            inner,
+            side,
+            stalled_since: None,
+        }
+    }
+
+    /// Update the stall state based on the latest poll result of the inner writer
+    fn observe<T>(&mut self, poll: &Poll<T>) {
+        match (poll, self.stalled_since) {
+            (Poll::Pending, None) => self.stalled_since = Some(Instant::now()),
+            (Poll::Ready(_), Some(since)) => {
+                self.stalled_since = None;
+
+                let stall = since.elapsed();
+                if stall < STALL_THRESHOLD {
+                    return;
+                }
+
+                debug!("Write to {} stalled for {}ms", self.side, stall.as_millis());
+
+                metrics::increment(&format!("write_stalls_total{{side=\"{}\"}}", self.side), 1);
+                metrics::increment(
+                    &format!("write_stall_ms_total{{side=\"{}\"}}", self.side),
+                    stall.as_millis() as u64,
+                );
+            }
+            _ => (),
+        }
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for StallMonitor<W> {
+    #[inline]
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.inner).poll_write(cx, buf);
+        this.observe(&poll);
+        poll
+    }
+
+    #[inline]
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.inner).poll_write_vectored(cx, bufs);
+        this.observe(&poll);
+        poll
+    }
+
+    #[inline]
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    #[inline]
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.inner).poll_flush(cx);
+        this.observe(&poll);
+        poll
+    }
+
+    #[inline]
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
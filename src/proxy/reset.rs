@@ -0,0 +1,159 @@
+use crate::metrics;
+
+use log::warn;
+use std::io::{ErrorKind, Result};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::Instant;
+
+/// Resets within this long after connecting are counted as early resets
+const EARLY_RESET_WINDOW: Duration = Duration::from_secs(5);
+
+/// Time the outbound keeps using QUIC after a connection to its server was reset
+const FAILOVER_PERIOD: Duration = Duration::from_secs(300);
+
+/// Stage of the connection to the remote server a reset happened in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResetStage {
+    /// Nothing was received from the server yet, typically right after the TLS ClientHello
+    Handshake,
+    /// Within the first seconds of the connection
+    Early,
+}
+
+impl ResetStage {
+    fn label(&self) -> &'static str {
+        match self {
+            ResetStage::Handshake => "handshake",
+            ResetStage::Early => "early",
+        }
+    }
+}
+
+/// Connection resets seen by an outbound on the connections to its remote server. Censors cut the connections they
+/// block by injecting TCP RST, either as soon as they see the server name in the TLS ClientHello or after looking at
+/// the first packets, while resets later on are usually the server or the network going away. Those early resets are
+/// counted in connection_resets_total by stage, and with failover the outbound switches to QUIC for a while after one.
+pub struct ResetTracker {
+    failover: bool,
+    last_reset: Mutex<Option<Instant>>,
+}
+
+impl ResetTracker {
+    pub fn new(failover: bool) -> Self {
+        Self {
+            failover,
+            last_reset: Mutex::new(None),
+        }
+    }
+
+    /// Record the reset of a connection to the server.
+    pub fn record(&self, server: &str, stage: ResetStage) {
+        warn!(
+            "Connection to server {} was reset in the {} stage, it may be blocked",
+            server,
+            stage.label()
+        );
+        metrics::increment(
+            &format!("connection_resets_total{{stage=\"{}\"}}", stage.label()),
+            1,
+        );
+        *self.last_reset.lock().unwrap() = Some(Instant::now());
+    }
+
+    /// Whether the connections should go over QUIC instead of TCP, after a recent reset with failover enabled.
+    pub fn failing_over(&self) -> bool {
+        if !self.failover {
+            return false;
+        }
+        match *self.last_reset.lock().unwrap() {
+            Some(last_reset) => last_reset.elapsed() < FAILOVER_PERIOD,
+            None => false,
+        }
+    }
+}
+
+/// Stream to the remote server that reports to the tracker when it is reset early. A reset before anything is read is
+/// a reset in the handshake, as the TLS ClientHello is the first thing sent.
+pub struct ResetMonitor<S> {
+    inner: S,
+    tracker: Arc<ResetTracker>,
+    server: String,
+    started: Instant,
+    received: bool,
+    reported: bool,
+}
+
+impl<S> ResetMonitor<S> {
+    pub fn new(inner: S, tracker: Arc<ResetTracker>, server: String) -> Self {
+        Self {
+            inner,
+            tracker,
+            server,
+            started: Instant::now(),
+            received: false,
+            reported: false,
+        }
+    }
+
+    /// Report the error to the tracker if it is the first early reset of the stream
+    fn observe<T>(&mut self, poll: &Poll<Result<T>>) {
+        let error = match poll {
+            Poll::Ready(Err(e)) => e,
+            _ => return,
+        };
+        if error.kind() != ErrorKind::ConnectionReset {
+            return;
+        }
+
+        let stage = match (self.received, self.started.elapsed() < EARLY_RESET_WINDOW) {
+            (false, _) => ResetStage::Handshake,
+            (true, true) => ResetStage::Early,
+            (true, false) => return,
+        };
+        if !self.reported {
+            self.reported = true;
+            self.tracker.record(&self.server, stage);
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for ResetMonitor<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<()>> {
+        let this = self.get_mut();
+        let filled = buf.filled().len();
+        let poll = Pin::new(&mut this.inner).poll_read(cx, buf);
+        this.observe(&poll);
+        if buf.filled().len() > filled {
+            this.received = true;
+        }
+        poll
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for ResetMonitor<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.inner).poll_write(cx, buf);
+        this.observe(&poll);
+        poll
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.inner).poll_flush(cx);
+        this.observe(&poll);
+        poll
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,268 @@
+use crate::config::base::{OutboundConfig, ServerOrder};
+use crate::dns::discovery::Discovery;
+use crate::metrics;
+use crate::protocol::common::addr::{IpAddrPort, IpAddress};
+
+use log::{debug, warn};
+use std::future::Future;
+use std::io::{Error, ErrorKind, Result};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Addresses that failed within this period are tried after the others
+const FAILURE_PENALTY: Duration = Duration::from_secs(60);
+
+/// Address of the remote server along with the outcome of the last connection to it.
+struct Server {
+    address: IpAddrPort,
+    /// ALPN protocols the server advertises in DNS, empty if it doesn't
+    alpn: Vec<String>,
+    /// Milliseconds taken by the last successful connection, 0 if there wasn't one yet
+    latency: AtomicU64,
+    failed_at: Mutex<Option<Instant>>,
+}
+
+/// Addresses of the remote server of an outbound. Connections go to the first address that accepts them, so that the
+/// outbound keeps working while some of the addresses are blocked or down. Outbounds with a discovery also use the
+/// servers published in DNS, looked up before the first connection and again whenever none of the servers accepts
+/// the connection.
+pub struct ServerList {
+    /// Addresses in the configuration, tried before the discovered ones
+    fixed: Vec<IpAddrPort>,
+    servers: RwLock<Vec<Arc<Server>>>,
+    order: ServerOrder,
+    discovery: Option<Discovery>,
+    discovered: AtomicBool,
+}
+
+impl ServerList {
+    /// Addresses of the remote server from the outbound configuration, None if the outbound has no remote server.
+    /// Fails with InvalidInput if the address or the port is missing, a server is invalid or the discovery is invalid.
+    pub fn new(outbound: &OutboundConfig) -> Result<Option<Self>> {
+        let mut addresses = Vec::new();
+        let has_servers = outbound.servers.is_some() || outbound.discovery.is_some();
+        match (&outbound.address, outbound.port) {
+            (Some(address), Some(port)) => {
+                addresses.push(IpAddrPort::new(IpAddress::from_host(address), port))
+            }
+            (Some(_), None) => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "missing port while address is present",
+                ))
+            }
+            (None, Some(_)) if !has_servers => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "missing address while port is present",
+                ))
+            }
+            // Without addresses the outbound uses the destination of each request
+            _ => (),
+        }
+        for server in outbound.servers.iter().flatten() {
+            addresses.push(parse_server(server, outbound.port)?);
+        }
+
+        let discovery = outbound
+            .discovery
+            .as_ref()
+            .map(|discovery| Discovery::new(discovery, outbound.port))
+            .transpose()?;
+        if addresses.is_empty() && discovery.is_none() {
+            return Ok(None);
+        }
+
+        Ok(Some(Self {
+            servers: RwLock::new(
+                addresses
+                    .iter()
+                    .map(|address| Arc::new(Server::new(address.clone(), Vec::new())))
+                    .collect(),
+            ),
+            fixed: addresses,
+            order: outbound.server_order.unwrap_or(ServerOrder::ORDER),
+            discovery,
+            discovered: AtomicBool::new(false),
+        }))
+    }
+
+    /// Addresses in the order they are tried.
+    pub fn candidates(&self) -> Vec<IpAddrPort> {
+        self.ordered()
+            .into_iter()
+            .map(|server| server.address.clone())
+            .collect()
+    }
+
+    /// ALPN protocols the server at the address advertises in DNS, None if it doesn't advertise any.
+    pub fn alpn(&self, address: &IpAddrPort) -> Option<Vec<Vec<u8>>> {
+        self.servers
+            .read()
+            .unwrap()
+            .iter()
+            .find(|server| &server.address == address && !server.alpn.is_empty())
+            .map(|server| server.alpn.iter().map(|p| p.as_bytes().to_vec()).collect())
+    }
+
+    fn ordered(&self) -> Vec<Arc<Server>> {
+        let mut servers = self.servers.read().unwrap().clone();
+        match self.order {
+            ServerOrder::ORDER => servers.sort_by_key(|server| server.recently_failed()),
+            ServerOrder::LATENCY => servers.sort_by_key(|server| {
+                (
+                    server.recently_failed(),
+                    server.latency.load(Ordering::Relaxed),
+                )
+            }),
+        }
+        servers
+    }
+
+    /// Look up the servers published in DNS, replacing the ones discovered before. The servers found again keep the
+    /// outcome of their previous connections.
+    pub async fn discover(&self) -> Result<()> {
+        let discovery = match &self.discovery {
+            Some(discovery) => discovery,
+            None => return Ok(()),
+        };
+
+        let endpoints = discovery.discover().await?;
+        debug!("Discovered {} servers of the outbound", endpoints.len());
+        metrics::increment("outbound_server_discoveries_total", 1);
+
+        self.discovered.store(true, Ordering::Relaxed);
+
+        let mut servers = self.servers.write().unwrap();
+        let previous = std::mem::take(&mut *servers);
+        let reuse = |address: &IpAddrPort, alpn: Vec<String>| match previous
+            .iter()
+            .find(|server| &server.address == address)
+        {
+            Some(server) if server.alpn == alpn => server.clone(),
+            _ => Arc::new(Server::new(address.clone(), alpn)),
+        };
+
+        for address in &self.fixed {
+            servers.push(reuse(address, Vec::new()));
+        }
+        for endpoint in endpoints {
+            if !servers
+                .iter()
+                .any(|server| server.address == endpoint.address)
+            {
+                servers.push(reuse(&endpoint.address, endpoint.alpn));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Connect to the addresses one at a time until one of them succeeds, fails with the error of the last address
+    /// if none of them does. The servers published in DNS are looked up again once all of them failed.
+    pub async fn connect<T, F, Fut>(&self, mut connect: F) -> Result<T>
+    where
+        F: FnMut(IpAddrPort) -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        if self.discovery.is_some() && !self.discovered.load(Ordering::Relaxed) {
+            if let Err(e) = self.discover().await {
+                warn!("Failed to discover the servers of the outbound: {}", e);
+            }
+        }
+
+        match self.connect_once(&mut connect).await {
+            Err(e) if self.discovery.is_some() => match self.discover().await {
+                Ok(()) => self.connect_once(&mut connect).await,
+                Err(_) => Err(e),
+            },
+            result => result,
+        }
+    }
+
+    async fn connect_once<T, F, Fut>(&self, connect: &mut F) -> Result<T>
+    where
+        F: FnMut(IpAddrPort) -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let mut last_error = None;
+
+        for server in self.ordered() {
+            let start = Instant::now();
+            match connect(server.address.clone()).await {
+                Ok(connection) => {
+                    let latency = start.elapsed().as_millis().max(1) as u64;
+                    server.latency.store(latency, Ordering::Relaxed);
+                    *server.failed_at.lock().unwrap() = None;
+                    return Ok(connection);
+                }
+                Err(e) => {
+                    debug!("Failed to connect to server {}: {}", server.address, e);
+                    metrics::increment("outbound_server_failures_total", 1);
+                    *server.failed_at.lock().unwrap() = Some(Instant::now());
+                    last_error = Some(e);
+                }
+            }
+        }
+
+        match last_error {
+            Some(e) => Err(e),
+            None => Err(Error::new(
+                ErrorKind::NotConnected,
+                "no servers of the outbound were discovered",
+            )),
+        }
+    }
+}
+
+impl Server {
+    fn new(address: IpAddrPort, alpn: Vec<String>) -> Self {
+        Self {
+            address,
+            alpn,
+            latency: AtomicU64::new(0),
+            failed_at: Mutex::new(None),
+        }
+    }
+
+    fn recently_failed(&self) -> bool {
+        match *self.failed_at.lock().unwrap() {
+            Some(failed_at) => failed_at.elapsed() < FAILURE_PENALTY,
+            None => false,
+        }
+    }
+}
+
+/// Parse an address in the servers of an outbound, either host or host:port with IPv6 addresses in brackets.
+fn parse_server(server: &str, default_port: Option<u16>) -> Result<IpAddrPort> {
+    if let Ok(address) = server.parse::<SocketAddr>() {
+        return Ok(IpAddrPort::new(
+            IpAddress::IpAddr(address.ip()),
+            address.port(),
+        ));
+    }
+
+    let (host, port) = match server.rsplit_once(':') {
+        Some((host, port)) if !host.contains(':') => match port.parse() {
+            Ok(port) => (host, Some(port)),
+            Err(_) => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("invalid port of server {}", server),
+                ))
+            }
+        },
+        _ => (server.trim_start_matches('[').trim_end_matches(']'), None),
+    };
+
+    match port.or(default_port) {
+        Some(port) => Ok(IpAddrPort::new(IpAddress::from_host(host), port)),
+        None => Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("missing port of server {}", server),
+        )),
+    }
+}
@@ -0,0 +1,196 @@
+use crate::config::base::SocketConfig;
+
+use socket2::{SockRef, TcpKeepalive};
+use std::io::{Error, ErrorKind, Result};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::{lookup_host, TcpListener, TcpStream, ToSocketAddrs};
+
+/// Connections waiting for the handshake to complete that may have sent data in their SYN, same as the backlog of the
+/// listeners
+#[cfg(target_os = "linux")]
+const FAST_OPEN_QUEUE: libc::c_int = 1024;
+
+/// Options applied to the TCP sockets of an inbound or an outbound as they are accepted or connected. Every TCP
+/// connection the process makes goes through connect, with the options of the outbound or inbound it is made for.
+#[derive(Clone)]
+pub struct SocketOptions {
+    nodelay: bool,
+    keepalive: Option<TcpKeepalive>,
+    fast_open: bool,
+}
+
+impl SocketOptions {
+    /// Fails with InvalidInput if the keepalive options are set without keepalive, or to zero, which the parser checks
+    /// first.
+    pub fn new(config: Option<&SocketConfig>) -> Result<Self> {
+        let config = config.cloned().unwrap_or_default();
+        if config.keepalive.is_none()
+            && (config.keepalive_interval.is_some() || config.keepalive_probes.is_some())
+        {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "keepalive_interval and keepalive_probes need keepalive to be set",
+            ));
+        }
+        if [
+            config.keepalive,
+            config.keepalive_interval,
+            config.keepalive_probes.map(u64::from),
+        ]
+        .contains(&Some(0))
+        {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "keepalive options must be positive",
+            ));
+        }
+
+        let keepalive = config.keepalive.map(|time| {
+            let keepalive = TcpKeepalive::new().with_time(Duration::from_secs(time));
+            // The interval and the number of probes can't be set on the other platforms
+            #[cfg(any(
+                target_os = "linux",
+                target_os = "android",
+                target_os = "freebsd",
+                target_vendor = "apple"
+            ))]
+            let keepalive = {
+                let mut keepalive = keepalive;
+                if let Some(interval) = config.keepalive_interval {
+                    keepalive = keepalive.with_interval(Duration::from_secs(interval));
+                }
+                if let Some(probes) = config.keepalive_probes {
+                    keepalive = keepalive.with_retries(probes);
+                }
+                keepalive
+            };
+            keepalive
+        });
+
+        Ok(Self {
+            nodelay: config.nodelay.unwrap_or(true),
+            keepalive,
+            fast_open: config.fast_open.unwrap_or(false),
+        })
+    }
+
+    /// The same options without TCP Fast Open, for the connections that have to be established when connect returns,
+    /// like the ones of the probes, and for the servers behind an inbound, as fast_open of an inbound is only meant for
+    /// its listener.
+    pub fn without_fast_open(&self) -> Self {
+        Self {
+            fast_open: false,
+            ..self.clone()
+        }
+    }
+
+    /// Enable TCP Fast Open on the listener if fast_open is set, so that the clients that connected before can send
+    /// their first data along with the SYN. Only supported on Linux.
+    pub fn listen(&self, listener: &TcpListener) -> Result<()> {
+        #[cfg(target_os = "linux")]
+        if self.fast_open {
+            linux::set_option(listener, libc::TCP_FASTOPEN, FAST_OPEN_QUEUE)?;
+        }
+        #[cfg(not(target_os = "linux"))]
+        let _ = listener;
+        Ok(())
+    }
+
+    /// Whether the connections may skip the handshake with TCP Fast Open, so that they are only known to fail once
+    /// they are written to.
+    #[inline]
+    pub fn fast_open(&self) -> bool {
+        cfg!(target_os = "linux") && self.fast_open
+    }
+
+    /// Set the options on the socket.
+    pub fn apply(&self, stream: &TcpStream) -> Result<()> {
+        stream.set_nodelay(self.nodelay)?;
+        if let Some(keepalive) = &self.keepalive {
+            SockRef::from(stream).set_tcp_keepalive(keepalive)?;
+        }
+        Ok(())
+    }
+
+    /// Connect to the first of the addresses that accepts the connection and set the options on the socket. With
+    /// fast_open on Linux, once the server handed out a cookie on an earlier connection, the connection completes
+    /// right away and the SYN goes out with the first write carrying its data, which saves a round trip. An
+    /// unreachable address is then only reported by the first read or write of the stream.
+    pub async fn connect<A: ToSocketAddrs>(&self, addrs: A) -> Result<TcpStream> {
+        let mut last_error = None;
+        for addr in lookup_host(addrs).await? {
+            match self.connect_addr(addr).await {
+                Ok(stream) => return Ok(stream),
+                Err(e) => last_error = Some(e),
+            }
+        }
+        Err(last_error.unwrap_or_else(|| {
+            Error::new(ErrorKind::InvalidInput, "could not resolve to any address")
+        }))
+    }
+
+    async fn connect_addr(&self, addr: SocketAddr) -> Result<TcpStream> {
+        #[cfg(target_os = "linux")]
+        let stream = match self.fast_open {
+            true => linux::connect_fast_open(addr).await?,
+            false => TcpStream::connect(addr).await?,
+        };
+        #[cfg(not(target_os = "linux"))]
+        let stream = TcpStream::connect(addr).await?;
+
+        self.apply(&stream)?;
+        Ok(stream)
+    }
+}
+
+/// Nagle's algorithm off, no keepalive and no TCP Fast Open, like the sockets of the configurations without socket.
+impl Default for SocketOptions {
+    fn default() -> Self {
+        Self {
+            nodelay: true,
+            keepalive: None,
+            fast_open: false,
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use log::debug;
+    use std::io::{Error, Result};
+    use std::mem;
+    use std::net::SocketAddr;
+    use std::os::unix::io::AsRawFd;
+    use tokio::net::{TcpSocket, TcpStream};
+
+    /// Set the TCP level option of the socket.
+    pub fn set_option<S: AsRawFd>(socket: &S, name: libc::c_int, value: libc::c_int) -> Result<()> {
+        // Safety: the option value is a c_int that outlives the call
+        let result = unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                libc::IPPROTO_TCP,
+                name,
+                &value as *const _ as *const libc::c_void,
+                mem::size_of_val(&value) as libc::socklen_t,
+            )
+        };
+        match result {
+            0 => Ok(()),
+            _ => Err(Error::last_os_error()),
+        }
+    }
+
+    /// Connect with TCP_FASTOPEN_CONNECT, or the usual way if the kernel doesn't know it.
+    pub async fn connect_fast_open(addr: SocketAddr) -> Result<TcpStream> {
+        let socket = match addr {
+            SocketAddr::V4(_) => TcpSocket::new_v4()?,
+            SocketAddr::V6(_) => TcpSocket::new_v6()?,
+        };
+        if let Err(e) = set_option(&socket, libc::TCP_FASTOPEN_CONNECT, 1) {
+            debug!("Failed to enable TCP Fast Open to {}: {}", addr, e);
+        }
+        socket.connect(addr).await
+    }
+}
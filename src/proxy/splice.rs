@@ -0,0 +1,115 @@
+use crate::proxy::relay::Activity;
+
+use std::io::{Error, ErrorKind, Result};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::ptr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::io::Interest;
+use tokio::net::TcpStream;
+
+/// Most bytes moved by a single splice call, the default capacity of a pipe
+const PIPE_SIZE: usize = 65536;
+
+/// Pipe the data spliced out of one socket goes through on its way into the other, splice needs a pipe on one end.
+pub struct Pipe {
+    read: RawFd,
+    write: RawFd,
+}
+
+impl Pipe {
+    pub fn new() -> Result<Self> {
+        let mut fds = [0 as RawFd; 2];
+        // Safety: the array holds the two descriptors written by the call
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) } < 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(Self {
+            read: fds[0],
+            write: fds[1],
+        })
+    }
+}
+
+impl Drop for Pipe {
+    fn drop(&mut self) {
+        // Safety: the descriptors are owned by the pipe and closed only once
+        unsafe {
+            libc::close(self.read);
+            libc::close(self.write);
+        }
+    }
+}
+
+/// Move the data from the reader to the writer through the pipe, until the reader ends or the limit is reached. The
+/// data never leaves the kernel, which saves copying it into userspace and back. Every read records the activity and
+/// adds the bytes to the counters, so that they are known even if the copy is cancelled.
+pub async fn copy(
+    reader: &TcpStream,
+    writer: &TcpStream,
+    pipe: &Pipe,
+    limit: Option<u64>,
+    activity: &Activity,
+    counters: &[&AtomicU64],
+) -> Result<u64> {
+    let mut copied = 0;
+
+    loop {
+        let size = match limit {
+            Some(limit) if copied >= limit => return Ok(copied),
+            Some(limit) => PIPE_SIZE.min((limit - copied) as usize),
+            None => PIPE_SIZE,
+        };
+
+        let read = loop {
+            reader.readable().await?;
+            match reader.try_io(Interest::READABLE, || {
+                splice(reader.as_raw_fd(), pipe.write, size)
+            }) {
+                Ok(read) => break read,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => continue,
+                Err(e) => return Err(e),
+            }
+        };
+        if read == 0 {
+            return Ok(copied);
+        }
+        activity.touch();
+
+        // Empty the pipe before reading more, so that the reads never block on a full pipe
+        let mut pending = read;
+        while pending > 0 {
+            writer.writable().await?;
+            match writer.try_io(Interest::WRITABLE, || {
+                splice(pipe.read, writer.as_raw_fd(), pending)
+            }) {
+                Ok(0) => return Err(Error::new(ErrorKind::WriteZero, "failed to splice")),
+                Ok(written) => pending -= written,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => continue,
+                Err(e) => return Err(e),
+            }
+        }
+
+        copied += read as u64;
+        for counter in counters {
+            counter.fetch_add(read as u64, Ordering::Relaxed);
+        }
+    }
+}
+
+fn splice(from: RawFd, to: RawFd, size: usize) -> Result<usize> {
+    // Safety: both descriptors stay open for the call, and the null offsets make it use the file positions
+    let result = unsafe {
+        libc::splice(
+            from,
+            ptr::null_mut(),
+            to,
+            ptr::null_mut(),
+            size,
+            libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK,
+        )
+    };
+    match result {
+        -1 => Err(Error::last_os_error()),
+        size => Ok(size as usize),
+    }
+}
@@ -1,14 +1,34 @@
-use crate::config::base::InboundConfig;
+#[cfg(feature = "server")]
+use crate::admin::server::{is_http2, AdminHandoff, ProxyPortAdmin};
+use crate::auth::AuthChain;
+use crate::config::base::{
+    BandwidthConfig, DialFailureMode, DomainResolution, FallbackConfig, InboundConfig,
+    InboundWebSocketConfig,
+};
+#[cfg(feature = "server")]
 use crate::config::tls::make_server_config;
+use crate::config::transports::Transports;
 use crate::protocol::common::request::InboundRequest;
-use crate::protocol::common::stream::StandardTcpStream;
+use crate::protocol::common::stream::{PrefixedStream, StandardTcpStream};
 use crate::protocol::socks5;
-use crate::protocol::trojan;
+use crate::protocol::tls::read_client_hello;
+use crate::protocol::trojan::{self, MAX_HEADER_SIZE};
 use crate::proxy::base::SupportedProtocols;
+use crate::proxy::deadline::Deadline;
+use crate::proxy::filter::IpFilter;
+#[cfg(target_os = "linux")]
+use crate::proxy::ktls::Ktls;
+use crate::proxy::socket::SocketOptions;
+use crate::proxy::tcp::fallback::Fallback;
+use crate::proxy::tcp::sni::SniRouter;
+use crate::proxy::tcp::sniff::Sniffer;
+use crate::transport::websocket;
 
+use log::warn;
 use once_cell::sync::OnceCell;
-use sha2::{Digest, Sha224};
 use std::io::{Error, ErrorKind, Result};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
 use tokio::io::{AsyncRead, AsyncWrite};
 use tokio_rustls::TlsAcceptor;
 
@@ -19,29 +39,30 @@ static TCP_ACCEPTOR: OnceCell<TcpAcceptor> = OnceCell::new();
 /// the configuration. It is also responsible for escalating TCP connection to TLS connection if the user
 /// enabled TLS.
 pub struct TcpAcceptor {
+    tag: Option<String>,
     tls_acceptor: Option<TlsAcceptor>,
+    sni_router: Option<SniRouter>,
+    fallback: Fallback,
     port: u16,
     protocol: SupportedProtocols,
-    secret: Vec<u8>,
+    auth: &'static AuthChain,
+    deferred_reply: bool,
+    websocket: Option<InboundWebSocketConfig>,
+    resolution: Option<DomainResolution>,
+    intercept_dns: bool,
+    sniffer: Sniffer,
+    kernel_tls: bool,
+    bandwidth: Option<BandwidthConfig>,
+    sources: Option<Arc<IpFilter>>,
+    backends: SocketOptions,
 }
 
 impl TcpAcceptor {
-    /// Instantiate a new acceptor based on InboundConfig passed by the user. It will generate the secret based on
-    /// secret in the config file and the selected protocol and instantiate TLS acceptor is it is enabled.
-    pub fn init(inbound: &InboundConfig) -> &'static Self {
-        let secret = match inbound.protocol {
-            SupportedProtocols::TROJAN if inbound.secret.is_some() => {
-                let secret = inbound.secret.as_ref().unwrap();
-                Sha224::digest(secret.as_bytes())
-                    .iter()
-                    .map(|x| format!("{:02x}", x))
-                    .collect::<String>()
-                    .as_bytes()
-                    .to_vec()
-            }
-            _ => Vec::new(),
-        };
-
+    /// Instantiate a new acceptor based on InboundConfig passed by the user. It will build the authentication chain
+    /// from the users in the config file and instantiate TLS acceptor is it is enabled.
+    pub fn init(inbound: &InboundConfig) -> Result<&'static Self> {
+        // Serving the certificate is left to the server builds, the parser rejects inbound TLS in the others
+        #[cfg(feature = "server")]
         let tls_acceptor = match &inbound.tls {
             Some(tls) => match make_server_config(&tls) {
                 Some(cfg) => Some(TlsAcceptor::from(cfg)),
@@ -49,51 +70,223 @@ impl TcpAcceptor {
             },
             None => None,
         };
+        #[cfg(not(feature = "server"))]
+        let tls_acceptor = None;
+
+        let sni_router = match &inbound.tls {
+            Some(tls) => SniRouter::new(tls),
+            None => None,
+        };
+
+        let kernel_tls = match &inbound.tls {
+            Some(tls) if tls.ktls.unwrap_or(false) => kernel_tls_available(),
+            _ => false,
+        };
+
+        // The fallback takes the streams the fallbacks listed before it don't
+        let mut fallbacks = inbound.fallbacks.clone().unwrap_or_default();
+        if let Some(address) = &inbound.fallback {
+            fallbacks.push(FallbackConfig {
+                address: address.clone(),
+                alpn: None,
+                path: None,
+            });
+        }
+
+        // The servers behind the inbound are connected to with its socket options
+        let backends = SocketOptions::new(inbound.socket.as_ref())
+            .unwrap_or_default()
+            .without_fast_open();
 
-        TCP_ACCEPTOR.get_or_init(|| Self {
-            tls_acceptor,
-            port: inbound.port,
-            protocol: inbound.protocol,
-            secret,
+        TCP_ACCEPTOR.get_or_try_init(|| {
+            Ok(Self {
+                tag: inbound.tag.clone(),
+                tls_acceptor,
+                sni_router,
+                fallback: Fallback::new(
+                    fallbacks,
+                    inbound.paranoid.unwrap_or(false),
+                    backends.clone(),
+                ),
+                port: inbound.port,
+                protocol: inbound.protocol,
+                auth: AuthChain::init(inbound)?,
+                deferred_reply: matches!(inbound.protocol, SupportedProtocols::SOCKS)
+                    && inbound.dial_failure == Some(DialFailureMode::RESPOND),
+                websocket: inbound
+                    .websocket
+                    .clone()
+                    .filter(|_| Transports::get().websocket),
+                resolution: inbound.resolve,
+                intercept_dns: inbound.intercept_dns.unwrap_or(false),
+                sniffer: Sniffer::new(inbound.sniffing.as_ref())?,
+                kernel_tls,
+                bandwidth: inbound.bandwidth.clone(),
+                sources: inbound
+                    .sources
+                    .as_ref()
+                    .map(|sources| IpFilter::new(sources, "source"))
+                    .transpose()?,
+                backends,
+            })
         })
     }
 
+    /// Socket options of the connections to the servers behind the inbound, like the SNI backends.
+    #[inline]
+    pub fn backends(&self) -> &SocketOptions {
+        &self.backends
+    }
+
+    /// Tag of the inbound used by the routing rules.
+    #[inline]
+    pub fn tag(&self) -> Option<&str> {
+        self.tag.as_deref()
+    }
+
+    /// Port the inbound listens on.
+    #[inline]
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Whether the reply to the SOCKS request is left to the caller, to be written once the outbound connection is
+    /// established or failed.
+    #[inline]
+    pub fn deferred_reply(&self) -> bool {
+        self.deferred_reply
+    }
+
+    /// Where the domain names in the requests are resolved when the routing rules leave it to the inbound.
+    #[inline]
+    pub fn resolution(&self) -> Option<DomainResolution> {
+        self.resolution
+    }
+
+    /// Whether the DNS queries in the UDP datagrams of SOCKS clients are answered by the DNS inbound.
+    #[inline]
+    pub fn intercept_dns(&self) -> bool {
+        self.intercept_dns
+    }
+
+    /// Servers taking over the streams that fail the trojan handshake.
+    #[inline]
+    pub fn fallback(&self) -> &Fallback {
+        &self.fallback
+    }
+
+    /// Sniffer of the domains of the requests to IP addresses.
+    #[inline]
+    pub fn sniffer(&self) -> &Sniffer {
+        &self.sniffer
+    }
+
+    /// Whether kernel TLS may take over the TLS sessions of the inbound connections.
+    #[inline]
+    pub fn kernel_tls(&self) -> bool {
+        self.kernel_tls
+    }
+
+    /// Whether the sources filter of the inbound accepts connections from the address.
+    #[inline]
+    pub fn allows_source(&self, ip: IpAddr) -> bool {
+        self.sources
+            .as_ref()
+            .is_none_or(|sources| sources.allows(ip))
+    }
+
+    /// Bandwidth of each inbound connection, unlimited without a config.
+    #[inline]
+    pub fn bandwidth(&self) -> Option<&BandwidthConfig> {
+        self.bandwidth.as_ref()
+    }
+
+    /// Whether the inbound connections should be routed by the server name in TLS ClientHello before being accepted.
+    #[inline]
+    pub fn sni_routing_enabled(&self) -> bool {
+        self.sni_router.is_some()
+    }
+
+    /// Read the TLS ClientHello from the inbound stream and decide where the connection should go. Returns the
+    /// stream with the ClientHello replayed in front of it, and the backend address if the connection should be
+    /// passed through instead of being accepted by the proxy.
+    pub async fn route_sni<T: AsyncRead + Unpin>(
+        &self,
+        mut inbound_stream: T,
+    ) -> Result<(PrefixedStream<T>, Option<&str>)> {
+        let (client_hello, server_name) = read_client_hello(&mut inbound_stream).await?;
+
+        let backend = match &self.sni_router {
+            Some(router) => router.route(server_name.as_deref()),
+            None => None,
+        };
+
+        Ok((PrefixedStream::new(client_hello, inbound_stream), backend))
+    }
+
     /// Takes an inbound TCP stream, escalate to TLS if possible and then escalate to application level data stream
-    /// to be ready to read user's request and process them.
-    pub async fn accept<T: AsyncRead + AsyncWrite + Send + Unpin>(
+    /// to be ready to read user's request and process them. The returned stream replays the payload that arrived
+    /// along with the request before reading from the connection again. TLS and the proxy handshake have to finish
+    /// before the deadline.
+    pub async fn accept<T: AsyncRead + AsyncWrite + Send + Unpin + 'static>(
         &self,
         inbound_stream: T,
-    ) -> Result<(InboundRequest, StandardTcpStream<T>)> {
+        source: SocketAddr,
+        deadline: Deadline,
+    ) -> Result<(InboundRequest, PrefixedStream<StandardTcpStream<T>>)> {
         match self.protocol {
             // Socks5 with or without TLS
             SupportedProtocols::SOCKS if self.tls_acceptor.is_some() => {
-                let tls_stream = self
-                    .tls_acceptor
-                    .as_ref()
-                    .unwrap()
-                    .accept(inbound_stream)
+                let tls_stream = deadline
+                    .run(
+                        "tls",
+                        self.tls_acceptor.as_ref().unwrap().accept(inbound_stream),
+                    )
+                    .await?;
+                let (request, stream) = deadline
+                    .run(
+                        "handshake",
+                        socks5::accept(
+                            StandardTcpStream::RustlsServer(tls_stream),
+                            self.port,
+                            !self.deferred_reply,
+                        ),
+                    )
                     .await?;
-                Ok(socks5::accept(StandardTcpStream::RustlsServer(tls_stream), self.port).await?)
+                Ok((request, PrefixedStream::new(Vec::new(), stream)))
             }
             SupportedProtocols::SOCKS => {
-                Ok(socks5::accept(StandardTcpStream::Plain(inbound_stream), self.port).await?)
+                let (request, stream) = deadline
+                    .run(
+                        "handshake",
+                        socks5::accept(
+                            StandardTcpStream::Plain(inbound_stream),
+                            self.port,
+                            !self.deferred_reply,
+                        ),
+                    )
+                    .await?;
+                Ok((request, PrefixedStream::new(Vec::new(), stream)))
             }
             // Trojan with or without TLS
             SupportedProtocols::TROJAN if self.tls_acceptor.is_some() => {
-                let tls_stream = self
-                    .tls_acceptor
-                    .as_ref()
-                    .unwrap()
-                    .accept(inbound_stream)
+                let tls_stream = deadline
+                    .run(
+                        "tls",
+                        self.tls_acceptor.as_ref().unwrap().accept(inbound_stream),
+                    )
                     .await?;
 
-                Ok(
-                    trojan::accept(StandardTcpStream::RustlsServer(tls_stream), &self.secret)
-                        .await?,
+                self.accept_trojan(
+                    StandardTcpStream::RustlsServer(tls_stream),
+                    source,
+                    deadline,
                 )
+                .await
             }
             SupportedProtocols::TROJAN => {
-                Ok(trojan::accept(StandardTcpStream::Plain(inbound_stream), &self.secret).await?)
+                self.accept_trojan(StandardTcpStream::Plain(inbound_stream), source, deadline)
+                    .await
             }
             // Shutdown the connection if the protocol is currently unsupported
             _ => Err(Error::new(
@@ -102,4 +295,127 @@ impl TcpAcceptor {
             )),
         }
     }
+
+    /// Read and validate trojan request from the application level data stream, or from the WebSocket connection the
+    /// stream is upgraded to if the inbound accepts WebSocket. Streams that fail the handshake are handed over to the
+    /// fallback together with the bytes consumed so far, the fallback itself isn't bounded by the deadline.
+    async fn accept_trojan<T: AsyncRead + AsyncWrite + Send + Unpin + 'static>(
+        &self,
+        mut stream: StandardTcpStream<T>,
+        source: SocketAddr,
+        deadline: Deadline,
+    ) -> Result<(InboundRequest, PrefixedStream<StandardTcpStream<T>>)> {
+        let mut buf = Vec::with_capacity(MAX_HEADER_SIZE);
+        let mut result = self
+            .read_trojan(&mut stream, &mut buf, source, deadline)
+            .await;
+
+        // Requests that aren't trojan may be WebSocket upgrades carrying trojan
+        if let (Err(_), Some(config)) = (&result, &self.websocket) {
+            if buf.starts_with(b"GET ") {
+                let upgrade = deadline
+                    .run(
+                        "handshake",
+                        websocket::read_upgrade_request(&mut stream, &mut buf, config),
+                    )
+                    .await;
+
+                match upgrade {
+                    Ok(true) => {
+                        let ws_stream = deadline
+                            .run(
+                                "handshake",
+                                websocket::accept(PrefixedStream::new(buf, stream)),
+                            )
+                            .await?;
+                        return self
+                            .accept_websocket(
+                                StandardTcpStream::WebSocket(Box::new(ws_stream)),
+                                source,
+                                deadline,
+                            )
+                            .await;
+                    }
+                    Ok(false) => (),
+                    Err(e) => result = Err(e),
+                }
+            }
+        }
+
+        match result {
+            Ok((request, header_size)) => {
+                buf.drain(..header_size);
+                Ok((request, PrefixedStream::new(buf, stream)))
+            }
+            Err(e) => {
+                // gRPC clients may be calling the admin API multiplexed on the port
+                #[cfg(feature = "server")]
+                if let Some(admin) = ProxyPortAdmin::get() {
+                    if is_http2(&mut stream, &mut buf).await? {
+                        admin.serve(PrefixedStream::new(buf, stream)).await?;
+                        return Err(Error::new(ErrorKind::ConnectionAborted, AdminHandoff));
+                    }
+                }
+
+                let alpn = stream.alpn_protocol().map(|alpn| alpn.to_vec());
+                if let Err(fallback_err) = self.fallback.serve(buf, stream, alpn.as_deref()).await {
+                    warn!("Failed to serve fallback: {}", fallback_err);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// Read and validate trojan request from the WebSocket connection, which has no fallback as the client has already
+    /// shown it speaks WebSocket on the configured path.
+    async fn accept_websocket<T: AsyncRead + AsyncWrite + Send + Unpin>(
+        &self,
+        mut stream: StandardTcpStream<T>,
+        source: SocketAddr,
+        deadline: Deadline,
+    ) -> Result<(InboundRequest, PrefixedStream<StandardTcpStream<T>>)> {
+        let mut buf = Vec::with_capacity(MAX_HEADER_SIZE);
+        let (request, header_size) = self
+            .read_trojan(&mut stream, &mut buf, source, deadline)
+            .await?;
+
+        buf.drain(..header_size);
+        Ok((request, PrefixedStream::new(buf, stream)))
+    }
+
+    /// Read the trojan request into buf and authenticate it, returns the request with the size of its header.
+    async fn read_trojan<S: AsyncRead + Unpin>(
+        &self,
+        stream: &mut S,
+        buf: &mut Vec<u8>,
+        source: SocketAddr,
+        deadline: Deadline,
+    ) -> Result<(InboundRequest, usize)> {
+        let (request, header_size) = deadline
+            .run("handshake", trojan::read_request(stream, buf))
+            .await?;
+
+        let hex = request.hex().to_vec();
+        let mut request = request.into_request();
+        let user = deadline
+            .run(
+                "authentication",
+                self.auth.authenticate(&hex, Some(source), &request),
+            )
+            .await?;
+        request.user = Some(user);
+
+        Ok((request, header_size))
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn kernel_tls_available() -> bool {
+    Ktls::init().is_some()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn kernel_tls_available() -> bool {
+    warn!("Kernel TLS is only available on Linux, the TLS sessions stay in userspace");
+    false
 }
@@ -0,0 +1,243 @@
+use crate::config::base::FallbackConfig;
+use crate::metrics;
+use crate::protocol::common::stream::PrefixedStream;
+use crate::proxy::relay::relay;
+use crate::proxy::socket::SocketOptions;
+
+use futures::future::join_all;
+use log::{debug, info, warn};
+use std::io::{Error, ErrorKind, Result};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite};
+use tokio::time::timeout;
+
+/// In paranoid mode, connections that can't be handed to the fallback server are kept open and drained until the
+/// client stays silent for this long, similar to how a web server waits for the rest of an incomplete request.
+const PARANOID_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Interval of probing the fallback servers
+const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Time a fallback server has to accept the connection of a probe
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Most bytes read looking for the end of the request line, when the fallbacks are chosen by path
+const MAX_REQUEST_LINE_SIZE: usize = 8192;
+
+/// Server the streams can fall back to, along with the conditions they have to meet.
+struct FallbackTarget {
+    address: String,
+    alpn: Option<String>,
+    path: Option<String>,
+    healthy: AtomicBool,
+}
+
+impl FallbackTarget {
+    fn matches(&self, alpn: Option<&[u8]>, path: Option<&str>) -> bool {
+        let alpn_matches = match &self.alpn {
+            Some(expected) => alpn == Some(expected.as_bytes()),
+            None => true,
+        };
+        let path_matches = match &self.path {
+            Some(prefix) => path.is_some_and(|path| path.starts_with(prefix.as_str())),
+            None => true,
+        };
+        alpn_matches && path_matches
+    }
+
+    fn set_healthy(&self, healthy: bool) {
+        if self.healthy.swap(healthy, Ordering::Relaxed) != healthy {
+            match healthy {
+                true => info!("Fallback server {} is up", self.address),
+                false => warn!("Fallback server {} is down", self.address),
+            }
+        }
+        metrics::set(
+            &format!(
+                "fallback_healthy{{address=\"{}\"}}",
+                metrics::escape_label(&self.address)
+            ),
+            healthy as u64,
+        );
+    }
+}
+
+/// Fallback takes over the inbound streams that failed the proxy handshake. With fallback servers configured, the
+/// stream, including the bytes already consumed by the handshake, is forwarded to the first of them matching the
+/// stream, so that the proxy looks like an ordinary web server to active probers. The servers are matched by the ALPN
+/// protocol of the TLS connection and the path of the HTTP request, and the ones that are down are tried last.
+///
+/// In paranoid mode the server never reacts to bad input on its own: it never closes the connection right away or
+/// writes anything that doesn't come from the fallback server, which leaves no timing or size differences for the
+/// probers to fingerprint.
+pub struct Fallback {
+    targets: Vec<FallbackTarget>,
+    paranoid: bool,
+    socket: SocketOptions,
+}
+
+impl Fallback {
+    /// Fallback to the servers, connected to with the socket options.
+    pub fn new(targets: Vec<FallbackConfig>, paranoid: bool, socket: SocketOptions) -> Self {
+        Self {
+            targets: targets
+                .into_iter()
+                .map(|target| FallbackTarget {
+                    address: target.address,
+                    alpn: target.alpn,
+                    path: target.path,
+                    // Servers are assumed to be up until probed
+                    healthy: AtomicBool::new(true),
+                })
+                .collect(),
+            paranoid,
+            socket,
+        }
+    }
+
+    /// Take over the stream that failed the handshake, head holds the bytes consumed by the handshake and alpn the
+    /// protocol negotiated by TLS.
+    pub async fn serve<T: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        mut head: Vec<u8>,
+        mut stream: T,
+        alpn: Option<&[u8]>,
+    ) -> Result<()> {
+        if self.targets.is_empty() {
+            return self.give_up(PrefixedStream::new(head, stream)).await;
+        }
+
+        // The path decides between the fallbacks only once the request line is whole
+        if self.targets.iter().any(|target| target.path.is_some()) {
+            read_request_line(&mut head, &mut stream).await?;
+        }
+        let path = request_path(&head);
+
+        // Healthy servers first, the others in case they came back since the last probe
+        let candidates = self
+            .targets
+            .iter()
+            .filter(|target| target.matches(alpn, path))
+            .filter(|target| target.healthy.load(Ordering::Relaxed))
+            .chain(
+                self.targets
+                    .iter()
+                    .filter(|target| target.matches(alpn, path))
+                    .filter(|target| !target.healthy.load(Ordering::Relaxed)),
+            );
+
+        let mut last_error = None;
+        for target in candidates {
+            match self.socket.connect(target.address.as_str()).await {
+                Ok(outbound_stream) => {
+                    target.set_healthy(true);
+                    info!(
+                        "Forwarding failed handshake to fallback server {}",
+                        target.address
+                    );
+
+                    let (client_reader, client_writer) =
+                        tokio::io::split(PrefixedStream::new(head, stream));
+                    let (server_reader, server_writer) = tokio::io::split(outbound_stream);
+                    return relay(client_reader, client_writer, server_reader, server_writer).await;
+                }
+                Err(e) => {
+                    warn!(
+                        "Failed to connect to fallback server {}: {}",
+                        target.address, e
+                    );
+                    target.set_healthy(false);
+                    last_error = Some(e);
+                }
+            }
+        }
+
+        match last_error {
+            Some(e) if !self.paranoid => Err(e),
+            _ => {
+                debug!("No fallback server matches the stream");
+                self.give_up(PrefixedStream::new(head, stream)).await
+            }
+        }
+    }
+
+    /// Probe the fallback servers every interval for as long as the process runs.
+    pub async fn run_health_checks(&self) {
+        let mut ticker = tokio::time::interval(HEALTH_CHECK_INTERVAL);
+
+        loop {
+            ticker.tick().await;
+
+            let probes = self.targets.iter().map(|target| async move {
+                match timeout(PROBE_TIMEOUT, self.socket.connect(target.address.as_str())).await {
+                    Ok(Ok(_)) => Ok(()),
+                    Ok(Err(e)) => Err(e),
+                    Err(_) => Err(Error::new(ErrorKind::TimedOut, "probe timed out")),
+                }
+            });
+
+            for (target, result) in self.targets.iter().zip(join_all(probes).await) {
+                if let Err(e) = &result {
+                    debug!("Failed to probe fallback server {}: {}", target.address, e);
+                }
+                target.set_healthy(result.is_ok());
+            }
+        }
+    }
+
+    /// Close the stream no fallback server took, or keep draining it in paranoid mode.
+    async fn give_up<T: AsyncRead + Unpin>(&self, stream: T) -> Result<()> {
+        match self.paranoid {
+            true => drain(stream).await,
+            false => Ok(()),
+        }
+    }
+}
+
+/// Read the rest of the request line if the head is the start of an HTTP request.
+async fn read_request_line<T: AsyncRead + Unpin>(head: &mut Vec<u8>, stream: &mut T) -> Result<()> {
+    let mut buf = vec![0u8; 1024];
+
+    while request_method(head).is_some()
+        && !head.windows(2).any(|window| window == b"\r\n")
+        && head.len() < MAX_REQUEST_LINE_SIZE
+    {
+        match timeout(PARANOID_IDLE_TIMEOUT, stream.read(&mut buf)).await {
+            Ok(Ok(0)) | Err(_) => break,
+            Ok(Ok(size)) => head.extend_from_slice(&buf[..size]),
+            Ok(Err(e)) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Method of the HTTP request the head starts with.
+fn request_method(head: &[u8]) -> Option<&[u8]> {
+    let end = head.iter().position(|byte| *byte == b' ')?;
+    match end > 0 && head[..end].iter().all(u8::is_ascii_uppercase) {
+        true => Some(&head[..end]),
+        false => None,
+    }
+}
+
+/// Path of the HTTP request the head starts with, once the request line is whole.
+fn request_path(head: &[u8]) -> Option<&str> {
+    let method = request_method(head)?;
+    let line_end = head.windows(2).position(|window| window == b"\r\n")?;
+    let target = std::str::from_utf8(&head[method.len() + 1..line_end]).ok()?;
+    target.split(' ').next()
+}
+
+/// Discard everything the client sends until it closes the connection or stays idle for PARANOID_IDLE_TIMEOUT.
+async fn drain<T: AsyncRead + Unpin>(mut stream: T) -> Result<()> {
+    let mut buf = vec![0u8; 1024];
+
+    while let Ok(result) = timeout(PARANOID_IDLE_TIMEOUT, stream.read(&mut buf)).await {
+        if result? == 0 {
+            break;
+        }
+    }
+
+    Ok(())
+}
@@ -1,48 +1,105 @@
-use crate::config::base::{OutboundConfig, OutboundMode};
-use crate::config::tls::{make_client_config, NoCertificateVerification};
+use crate::config::base::{DomainStrategy, OutboundConfig, OutboundMode, UdpConfig};
+#[cfg(feature = "client")]
+use crate::config::fingerprint::Fingerprints;
+use crate::config::tls::{make_client_config, preferred_cipher_suites, NoCertificateVerification};
+use crate::config::transports::Transports;
+use crate::metrics;
+use crate::protocol::common::addr::{IpAddrPort, IpAddress};
+use crate::protocol::common::atype::Atype;
+use crate::protocol::common::command::Command;
 use crate::protocol::common::request::{InboundRequest, TransportProtocol};
-use crate::protocol::common::stream::StandardTcpStream;
-use crate::protocol::trojan::{self, handshake, HEX_SIZE};
+use crate::protocol::common::stream::{BoxedStream, IntoTcpStream, StandardTcpStream};
+use crate::protocol::trojan::packet::{
+    copy_client_reader_to_udp_socket, copy_udp_socket_to_client_writer, TrojanPacketWriter,
+};
+use crate::protocol::trojan::{handshake, HEX_SIZE};
 use crate::proxy::base::SupportedProtocols;
+use crate::proxy::deadline::Deadline;
+use crate::proxy::destination::DestinationFilter;
+use crate::proxy::drain::SessionDrain;
+use crate::proxy::policy::{Policies, Policy};
+use crate::proxy::quic::datagram::{
+    copy_reader_to_packet_writer, copy_session_to_packet_writer, QuicDatagrams,
+};
+use crate::proxy::relay::{copy_limited, relay_tcp_with_policy, relay_with_policy};
+use crate::proxy::reset::{ResetMonitor, ResetTracker};
+use crate::proxy::servers::ServerList;
+use crate::proxy::socket::SocketOptions;
+use crate::proxy::tcp::pool::{self, ConnectionPool};
+use crate::proxy::udp::bind::UdpBinder;
+use crate::proxy::udp::guard::UdpGuard;
+use crate::proxy::udp::sessions::UdpSessions;
+use crate::router::DEFAULT_OUTBOUND_TAG;
 use crate::transport::grpc_transport::grpc_service_client::GrpcServiceClient;
 use crate::transport::grpc_transport::Hunk;
 
-use futures::Stream;
-use log::info;
-use once_cell::sync::OnceCell;
+use futures::future::BoxFuture;
+use futures::{FutureExt, Stream};
+use log::{debug, warn};
+use quinn::{RecvStream, SendStream};
 use rustls::{ClientConfig, ServerName};
 use sha2::{Digest, Sha224};
+use std::future::{pending, Future};
 use std::io::{self, Cursor, Error, ErrorKind};
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, BufWriter};
-use tokio::net::{TcpStream, UdpSocket};
+use tokio::io::{
+    AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, DuplexStream, ReadHalf,
+};
+use tokio::net::TcpStream;
 use tokio::sync::mpsc::{self, Sender};
 use tokio_rustls::TlsConnector;
 use tokio_stream::wrappers::ReceiverStream;
 use tokio_stream::StreamExt;
+use tonic::transport::Channel;
 use tonic::Status;
 
-/// Static life time TCP server outbound traffic handler to avoid ARC
-/// The handler is initialized through init() function
-static TCP_HANDLER: OnceCell<TcpHandler> = OnceCell::new();
+/// Size of the pipe between the inbounds whose streams aren't byte streams and the handler relaying them
+const PIPE_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Bidirectional stream to the remote proxy server over QUIC, along with the connection carrying it
+struct QuicStream {
+    connection: quinn::NewConnection,
+    writer: SendStream,
+    reader: RecvStream,
+}
+
+/// Connection to the remote proxy server that won the race between TCP and QUIC dials
+enum RaceWinner {
+    Tcp(Box<StandardTcpStream<BoxedStream>>),
+    Quic(SendStream, RecvStream),
+}
 
 /// Handler is responsible for taking user's request and process them and send back the result.
 /// It may need to dial to remote using TCP, UDP and TLS, in which it will be responsible for
 /// establishing a tranport level connection and escalate it to application data stream.
 pub struct TcpHandler {
+    tag: String,
     mode: OutboundMode,
     protocol: SupportedProtocols,
-    destination: Option<SocketAddr>,
+    servers: Option<ServerList>,
+    pool: Option<ConnectionPool<StandardTcpStream<BoxedStream>>>,
+    domain_strategy: DomainStrategy,
+    socket: SocketOptions,
     tls: Option<(Arc<ClientConfig>, ServerName)>,
+    #[cfg(feature = "client")]
+    fingerprints: Option<Fingerprints>,
     secret: Vec<u8>,
+    udp: Option<UdpConfig>,
+    udp_sessions: UdpSessions,
+    udp_binder: UdpBinder,
+    dialer: Option<Arc<TcpHandler>>,
+    policy: Policy,
+    resets: Arc<ResetTracker>,
+    drain: SessionDrain,
 }
 
 impl TcpHandler {
     /// Instantiate a new Handler instance based on OutboundConfig passed by the user. It will evaluate the
     /// TLS option particularly to be able to later determine whether it should escalate the connection to
-    /// TLS first or not.
-    pub fn init(outbound: &OutboundConfig) -> &'static TcpHandler {
+    /// TLS first or not. Fails with InvalidInput if the servers, the socket options, the UDP binding or the
+    /// TLS fingerprint of the outbound are invalid.
+    pub fn new(outbound: &OutboundConfig) -> io::Result<Self> {
         // Get outbound TLS configuration and host dns name if TLS is enabled
         let tls = match &outbound.tls {
             Some(cfg) => {
@@ -56,21 +113,30 @@ impl TcpHandler {
             None => None,
         };
 
-        // Attempt to extract destination address and port from OutboundConfig.
-        let destination = match (outbound.address.clone(), outbound.port) {
-            (Some(addr), Some(port)) => match format!("{}:{}", addr, port).parse() {
-                Ok(s) => Some(s),
-                Err(e) => panic!("Failed to parse destination address: {}", e),
-            },
-            (Some(_), None) => {
-                panic!("Missing port while address is present")
-            }
-            (None, Some(_)) => {
-                panic!("Missing address while address is present")
+        // Client configurations of the fingerprint profiles the TLS connections rotate through, if any
+        #[cfg(feature = "client")]
+        let fingerprints = outbound
+            .tls
+            .as_ref()
+            .map(Fingerprints::new)
+            .transpose()?
+            .flatten();
+
+        // Attempt to extract the server addresses from OutboundConfig, without them the address and port in each
+        // request are used
+        let servers = ServerList::new(outbound)?;
+
+        // Connections established ahead of the requests, only for the modes that connect to the server over TCP
+        let pool = outbound.pool.as_ref().and_then(|config| {
+            let tag = outbound.tag.as_deref().unwrap_or(DEFAULT_OUTBOUND_TAG);
+            match ConnectionPool::new(tag, &outbound.mode, config) {
+                Ok(pool) => Some(pool),
+                Err(e) => {
+                    warn!("Outbound {} runs without a connection pool: {}", tag, e);
+                    None
+                }
             }
-            // No destination address and port specified, will use the address and port in each request
-            (None, None) => None,
-        };
+        });
 
         // Extract the plaintext of the secret and process it
         let secret = match outbound.protocol {
@@ -87,90 +153,346 @@ impl TcpHandler {
             _ => Vec::new(),
         };
 
-        TCP_HANDLER.get_or_init(|| Self {
+        Ok(Self {
+            tag: outbound
+                .tag
+                .clone()
+                .unwrap_or_else(|| DEFAULT_OUTBOUND_TAG.to_string()),
             mode: outbound.mode.clone(),
             protocol: outbound.protocol,
-            destination,
+            servers,
+            pool,
+            domain_strategy: outbound.domain_strategy.unwrap_or(DomainStrategy::AS_IS),
+            socket: SocketOptions::new(outbound.socket.as_ref())?,
             tls,
+            #[cfg(feature = "client")]
+            fingerprints,
             secret,
+            udp: outbound.udp.clone(),
+            udp_binder: UdpBinder::new(outbound.udp.as_ref())?,
+            udp_sessions: UdpSessions::new(
+                outbound.tag.as_deref().unwrap_or(DEFAULT_OUTBOUND_TAG),
+                outbound.udp.as_ref().and_then(|udp| udp.max_sessions),
+            ),
+            dialer: None,
+            policy: Policies::get().policy(outbound.policy.as_deref()).clone(),
+            // Failing over to QUIC needs the QUIC transport
+            resets: Arc::new(ResetTracker::new(
+                outbound.reset_failover.unwrap_or(false) && Transports::get().quic,
+            )),
+            drain: SessionDrain::new(outbound.tag.as_deref().unwrap_or(DEFAULT_OUTBOUND_TAG)),
         })
     }
 
+    /// Tag of the outbound, the traffic it carries is attributed to in the metrics and the logs.
+    #[inline]
+    pub fn tag(&self) -> &str {
+        &self.tag
+    }
+
+    /// UDP sessions relayed by the outbound.
+    #[inline]
+    pub fn udp_sessions(&self) -> &UdpSessions {
+        &self.udp_sessions
+    }
+
+    /// Sessions relayed by the outbound and whether it is draining.
+    #[inline]
+    pub fn drain(&self) -> &SessionDrain {
+        &self.drain
+    }
+
+    /// Family of the addresses the domain names are resolved to for this outbound.
+    #[inline]
+    pub fn domain_strategy(&self) -> DomainStrategy {
+        self.domain_strategy
+    }
+
+    /// Tunnel the connections to the remote proxy server through the dialer outbound instead of connecting to the
+    /// server directly.
+    pub fn with_dialer(mut self, dialer: Arc<TcpHandler>) -> Self {
+        self.dialer = Some(dialer);
+        self
+    }
+
     /// Given an abstract inbound stream, it will read the request to standard request format and then process it.
     /// After taking the request, the handler will then establish the outbound connection based on the user configuration,
-    /// and transport data back and forth until one side terminate the connection.
+    /// and transport data back and forth until one side terminate the connection. The outbound connection has to
+    /// be established before the deadline of the request.
     #[inline]
-    pub async fn dispatch<T: AsyncRead + AsyncWrite + Unpin + Send + 'static>(
+    pub async fn dispatch<T: AsyncRead + AsyncWrite + IntoTcpStream + Unpin + Send + 'static>(
         &self,
-        inbound_stream: StandardTcpStream<T>,
+        inbound_stream: T,
         request: InboundRequest,
+        deadline: Deadline,
     ) -> io::Result<()> {
+        let _session = self.drain.start();
+
         match self.mode {
-            OutboundMode::DIRECT => self.handle_direct_stream(request, inbound_stream).await?,
-            OutboundMode::TCP => self.handle_tcp_stream(request, inbound_stream).await?,
-            OutboundMode::QUIC => self.handle_quic_stream(request, inbound_stream).await?,
-            OutboundMode::GRPC => self.handle_grpc_stream(request, inbound_stream).await?,
+            OutboundMode::DIRECT => {
+                self.handle_direct_stream(request, inbound_stream, deadline)
+                    .await?
+            }
+            OutboundMode::QUIC | OutboundMode::RACE if self.udp_over_tcp(&request) => {
+                debug!("Carrying UDP request to {} over TCP", request.addr_port);
+                self.handle_tcp_stream(request, inbound_stream, deadline)
+                    .await?
+            }
+            OutboundMode::TCP if self.resets.failing_over() => {
+                debug!("Connecting to the server over QUIC after a reset");
+                metrics::increment("transport_failovers_total", 1);
+                self.handle_quic_stream(request, inbound_stream, deadline)
+                    .await?
+            }
+            OutboundMode::TCP => {
+                self.handle_tcp_stream(request, inbound_stream, deadline)
+                    .await?
+            }
+            OutboundMode::QUIC => {
+                self.handle_quic_stream(request, inbound_stream, deadline)
+                    .await?
+            }
+            OutboundMode::GRPC => {
+                self.handle_grpc_stream(request, inbound_stream, deadline)
+                    .await?
+            }
+            OutboundMode::RACE => {
+                self.handle_race_stream(request, inbound_stream, deadline)
+                    .await?
+            }
+            OutboundMode::BLOCK => {
+                // The inbound stream is closed when it is dropped
+                metrics::increment_attributed("blocked_requests_total", 1);
+                return Err(Error::new(
+                    ErrorKind::PermissionDenied,
+                    format!("request to {} is blocked", request.addr_port),
+                ));
+            }
         }
 
         Ok(())
     }
 
+    /// Dispatch the request of an inbound whose client stream the handler can't take as it is, like the QUIC and GRPC
+    /// streams. The handler relays the other end of a pipe as if it were the inbound connection, the data of the client
+    /// reader is written into the pipe, and download moves the data coming out of it to the client. The request is
+    /// done once the handler finishes and the client has been sent everything.
+    pub async fn dispatch_piped<R, F, Fut>(
+        &self,
+        request: InboundRequest,
+        deadline: Deadline,
+        mut client_reader: R,
+        download: F,
+    ) -> io::Result<()>
+    where
+        R: AsyncRead + Unpin,
+        F: FnOnce(ReadHalf<DuplexStream>) -> Fut,
+        Fut: Future<Output = io::Result<()>>,
+    {
+        let (client, inbound) = tokio::io::duplex(PIPE_BUFFER_SIZE);
+        let (pipe_reader, mut pipe_writer) = tokio::io::split(client);
+
+        // The client may keep its side open after the handler is done, so the upload never ends the request
+        let upload = async {
+            if copy_limited(&mut client_reader, &mut pipe_writer, None)
+                .await
+                .is_ok()
+            {
+                let _ = pipe_writer.shutdown().await;
+            }
+            pending::<()>().await
+        };
+        let download = download(pipe_reader);
+        tokio::pin!(download);
+
+        tokio::select! {
+            _ = upload => unreachable!(),
+            result = &mut download => result,
+            // The data for the client may still be buffered in the pipe when the relay finishes
+            result = self.dispatch(inbound, request, deadline) => match result {
+                Ok(()) => download.await,
+                Err(e) => Err(e),
+            },
+        }
+    }
+
+    /// Whether the request is a UDP request to be carried over the TCP connection to the server instead of QUIC.
+    fn udp_over_tcp(&self, request: &InboundRequest) -> bool {
+        request.transport_protocol == TransportProtocol::UDP
+            && self
+                .udp
+                .as_ref()
+                .and_then(|udp| udp.over_tcp)
+                .unwrap_or(false)
+    }
+
+    /// Check whether the remote proxy server is reachable by establishing the connection the proxy requests would
+    /// use, without sending any request over it. Direct and block outbounds have no remote server and are always
+    /// reachable.
+    pub async fn probe(&self) -> io::Result<()> {
+        match self.mode {
+            OutboundMode::DIRECT | OutboundMode::BLOCK => Ok(()),
+            OutboundMode::QUIC => self.connect_quic(None).await.map(|_| ()),
+            // GRPC runs over the same TCP and TLS connection
+            OutboundMode::TCP | OutboundMode::GRPC | OutboundMode::RACE => {
+                self.connect_tcp(None).await.map(|_| ())
+            }
+        }
+    }
+
+    /// Open a stream to the destination through this outbound, for the outbounds dialing their servers through it.
+    /// Only direct and TCP outbounds can carry the connections of other outbounds. The future is boxed as the dialer
+    /// may be chained behind another outbound itself.
+    pub fn open_stream(&self, destination: IpAddrPort) -> BoxFuture<'_, io::Result<BoxedStream>> {
+        async move {
+            match (self.mode.clone(), self.protocol) {
+                (OutboundMode::DIRECT, _) => {
+                    let addr = destination.resolve_with(self.domain_strategy).await?;
+                    let stream: BoxedStream = Box::new(self.socket.connect(addr).await?);
+                    Ok(stream)
+                }
+                (OutboundMode::TCP, SupportedProtocols::TROJAN)
+                    if self.secret.len() == HEX_SIZE =>
+                {
+                    // Domain names are resolved by the server of this outbound
+                    let atype = match destination.ip {
+                        IpAddress::IpAddr(IpAddr::V4(_)) => Atype::IPv4,
+                        IpAddress::IpAddr(IpAddr::V6(_)) => Atype::IPv6,
+                        IpAddress::Domain(_) => Atype::DomainName,
+                    };
+                    let request = InboundRequest::new(
+                        atype,
+                        destination.ip,
+                        Command::Connect,
+                        destination.port,
+                        TransportProtocol::TCP,
+                        SupportedProtocols::TROJAN,
+                    );
+
+                    let mut stream = self.connect_pooled(None).await?;
+                    handshake(&mut stream, &request, &self.secret).await?;
+
+                    let stream: BoxedStream = Box::new(stream);
+                    Ok(stream)
+                }
+                _ => Err(Error::new(
+                    ErrorKind::Unsupported,
+                    "Only direct and trojan over TCP outbounds can be used as dialers",
+                )),
+            }
+        }
+        .boxed()
+    }
+
     /// Handle inbound TCP stream with direct outbound proxy strategy. Based on the inbound request, the handler
     /// will need to determine the way the input data is encrypted from the proxy request body and decrypt it to
     /// get the actual payload. Finally, it forwards the payload directly either with TCP or UDP flow.
-    async fn handle_direct_stream<T: AsyncRead + AsyncWrite + Unpin + Send>(
+    async fn handle_direct_stream<T: AsyncRead + AsyncWrite + IntoTcpStream + Unpin + Send>(
         &self,
         request: InboundRequest,
-        inbound_stream: StandardTcpStream<T>,
+        inbound_stream: T,
+        deadline: Deadline,
     ) -> io::Result<()> {
         let (proxy_protocol, transport_protocol) =
             (request.proxy_protocol, request.transport_protocol);
 
+        // The datagrams of SOCKS UDP ASSOCIATE are framed as trojan UDP packets by the inbound
+        let proxy_protocol = match (proxy_protocol, transport_protocol) {
+            (SupportedProtocols::SOCKS, TransportProtocol::UDP) => SupportedProtocols::TROJAN,
+            (proxy_protocol, _) => proxy_protocol,
+        };
+
         // Based on the protocol in the request body, decrypt the payload respectively
         match proxy_protocol {
             SupportedProtocols::TROJAN => {
                 match transport_protocol {
                     TransportProtocol::TCP => {
                         // Extract the destination port and address from the proxy request
-                        let addr: SocketAddr = request.addr_port.into();
+                        let addr = deadline
+                            .run("dns", request.addr_port.resolve_with(self.domain_strategy))
+                            .await?;
+                        DestinationFilter::get().check(addr, "tcp")?;
+                        self.policy.check_destination(addr.ip())?;
 
                         // Connect to remote server from the proxy request
-                        let outbound_stream = match TcpStream::connect(addr).await {
+                        let outbound_stream = match self
+                            .policy
+                            .connect(&deadline, || self.socket.connect(addr))
+                            .await
+                        {
                             Ok(stream) => stream,
                             Err(e) => {
                                 return Err(Error::new(
-                                    ErrorKind::ConnectionRefused,
+                                    e.kind(),
                                     format!("failed to connect to tcp {}: {}", addr, e),
                                 ))
                             }
                         };
 
-                        let (mut client_reader, mut client_writer) =
-                            tokio::io::split(inbound_stream);
-                        let (mut server_reader, mut server_writer) =
-                            tokio::io::split(outbound_stream);
-
-                        // Obtain reader and writer for inbound and outbound streams
-                        tokio::select!(
-                            _ = tokio::io::copy(&mut client_reader, &mut server_writer) => (),
-                            _ = tokio::io::copy(&mut server_reader, &mut client_writer) => ()
+                        // Plain TCP on both ends is relayed in the kernel where possible
+                        match inbound_stream.into_tcp_stream() {
+                            Ok((client, head)) => {
+                                relay_tcp_with_policy(client, head, outbound_stream, &self.policy)
+                                    .await?
+                            }
+                            Err(inbound_stream) => {
+                                // Obtain reader and writer for inbound and outbound streams
+                                let (client_reader, client_writer) =
+                                    tokio::io::split(inbound_stream);
+                                let (server_reader, server_writer) =
+                                    tokio::io::split(outbound_stream);
 
-                        );
+                                relay_with_policy(
+                                    client_reader,
+                                    client_writer,
+                                    server_reader,
+                                    server_writer,
+                                    &self.policy,
+                                )
+                                .await?;
+                            }
+                        }
                     }
                     TransportProtocol::UDP => {
                         // Establish UDP connection to remote host
-                        let socket = UdpSocket::bind("0.0.0.0:0").await?;
+                        let socket = Arc::new(self.udp_binder.bind(self.domain_strategy).await?);
+                        let _session = self.udp_sessions.open(socket.local_addr()?)?;
+                        let guard = Arc::new(
+                            UdpGuard::new(self.udp.as_ref())
+                                .with_destinations(self.policy.destinations.clone())
+                                .with_domain_strategy(self.domain_strategy),
+                        );
 
                         let (client_reader, client_writer) = tokio::io::split(inbound_stream);
+                        let mut client_writer = TrojanPacketWriter::new(client_writer)
+                            .with_max_packet_size(guard.max_packet_size());
 
+                        // Close the session once no datagram goes either way for the TTL
+                        let idle = async {
+                            match self.policy.udp_session_ttl {
+                                Some(ttl) => guard.idle(ttl).await,
+                                None => pending().await,
+                            }
+                        };
+
+                        let client_reader = BufReader::new(client_reader);
                         tokio::select!(
-                            _ = trojan::packet::copy_client_reader_to_udp_socket(BufReader::new(client_reader), &socket) => (),
-                            _ = trojan::packet::copy_udp_socket_to_client_writer(&socket, BufWriter::new(client_writer), request.addr_port) => ()
+                            _ = copy_client_reader_to_udp_socket(client_reader, &socket, &guard) => (),
+                            _ = copy_udp_socket_to_client_writer(&socket, &mut client_writer, &guard) => (),
+                            _ = idle => {
+                                debug!("Closing idle UDP session");
+                                metrics::increment_attributed("idle_timeouts_total{transport=\"udp\"}", 1);
+                            }
                         );
+
+                        // Deliver the replies still queued and end the stream cleanly
+                        if let Err(e) = client_writer.shutdown().await {
+                            debug!("Failed to close UDP session: {}", e);
+                        }
                     }
                 };
             }
-            // Handler currently doesn't support SOCKS protocol.
+            // Handler currently doesn't support SOCKS protocol besides UDP ASSOCIATE.
             // Also not sure if we should support SOCKS protocol for the scope of this project.
             SupportedProtocols::SOCKS => {
                 return Err(Error::new(
@@ -186,8 +508,6 @@ impl TcpHandler {
             }
         };
 
-        info!("Connection finished");
-
         Ok(())
     }
 
@@ -196,39 +516,72 @@ impl TcpHandler {
     async fn handle_quic_stream<T: AsyncRead + AsyncWrite + Unpin + Send + 'static>(
         &self,
         request: InboundRequest,
-        inbound_stream: StandardTcpStream<T>,
+        inbound_stream: T,
+        deadline: Deadline,
     ) -> io::Result<()> {
-        // Dial remote proxy server
-        let _roots = rustls::RootCertStore::empty();
-        let client_crypto = rustls::ClientConfig::builder()
-            .with_safe_defaults()
-            .with_custom_certificate_verifier(Arc::new(NoCertificateVerification {}))
-            .with_no_client_auth();
-        let mut endpoint = quinn::Endpoint::client("[::]:0".parse().unwrap()).unwrap();
-        endpoint.set_default_client_config(quinn::ClientConfig::new(Arc::new(client_crypto)));
+        let stream = self.connect_quic(Some(&deadline)).await?;
+        if request.transport_protocol == TransportProtocol::UDP {
+            return self
+                .relay_quic_datagrams(request, inbound_stream, stream, deadline)
+                .await;
+        }
+        self.forward(
+            request,
+            inbound_stream,
+            stream.reader,
+            stream.writer,
+            deadline,
+        )
+        .await
+    }
 
-        // Establish connection with remote proxy server using QUIC protocol
-        let connection = endpoint
-            .connect("127.0.0.1:8081".parse().unwrap(), "example.com")
-            .unwrap()
-            .await
-            .unwrap();
+    /// Relay the trojan UDP packets of the inbound stream as datagrams of the QUIC connection, which don't hold the
+    /// packets back behind a lost one like the stream does. The UDP request is sent on the stream, which carries the
+    /// packets until the first datagram of the server, and the ones too large for a datagram. The packets sent on the
+    /// stream announce the datagrams to the server.
+    async fn relay_quic_datagrams<T: AsyncRead + AsyncWrite + Unpin + Send>(
+        &self,
+        request: InboundRequest,
+        inbound_stream: T,
+        stream: QuicStream,
+        deadline: Deadline,
+    ) -> io::Result<()> {
+        let QuicStream {
+            connection,
+            mut writer,
+            reader,
+        } = stream;
 
-        let quinn::NewConnection {
-            connection: conn, ..
-        } = connection;
+        if self.secret.len() != HEX_SIZE {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("Hex in trojan protocol is not {} bytes", HEX_SIZE),
+            ));
+        }
+        deadline
+            .run("handshake", handshake(&mut writer, &request, &self.secret))
+            .await?;
 
-        let (mut server_writer, mut server_reader) = conn.open_bi().await.unwrap();
-        let (mut client_reader, mut client_writer) = tokio::io::split(inbound_stream);
+        let datagrams = Arc::new(QuicDatagrams::new(connection.connection));
+        let mut session = datagrams.open(writer.id().index());
 
-        handshake(&mut server_writer, &request, &self.secret).await?;
+        let (client_reader, client_writer) = tokio::io::split(inbound_stream);
+        let mut client_writer = TrojanPacketWriter::new(client_writer);
+        let mut server_writer =
+            TrojanPacketWriter::new(writer).with_datagrams(session.sender().announcing());
 
         tokio::select!(
-            _ = tokio::spawn(async move {tokio::io::copy(&mut client_reader, &mut server_writer).await}) => (),
-            _ = tokio::spawn(async move {tokio::io::copy(&mut server_reader, &mut client_writer).await}) => (),
+            _ = datagrams.receive(connection.datagrams) => (),
+            _ = copy_reader_to_packet_writer(BufReader::new(client_reader), &mut server_writer) => (),
+            _ = session.copy_stream(reader) => (),
+            _ = copy_session_to_packet_writer(&mut session, &mut client_writer) => (),
         );
 
-        Ok(())
+        // Deliver the packets still queued both ways and end the streams cleanly
+        if let Err(e) = server_writer.shutdown().await {
+            debug!("Failed to close UDP session with the server: {}", e);
+        }
+        client_writer.shutdown().await
     }
 
     /// Handle inbound TCP stream with TCP outbound proxy strategy. This function is used when the program serves as
@@ -237,30 +590,298 @@ impl TcpHandler {
     async fn handle_tcp_stream<T: AsyncRead + AsyncWrite + Unpin + Send>(
         &self,
         request: InboundRequest,
-        inbound_stream: StandardTcpStream<T>,
+        inbound_stream: T,
+        deadline: Deadline,
     ) -> io::Result<()> {
-        // Establish the initial connection with remote server
-        let connection = match self.destination {
-            Some(dest) => TcpStream::connect(dest).await?,
-            None => {
-                return Err(Error::new(
-                    ErrorKind::NotConnected,
-                    "missing address of the remote server",
-                ))
+        let outbound_stream = self.connect_pooled(Some(&deadline)).await?;
+        let (server_reader, server_writer) = tokio::io::split(outbound_stream);
+        self.forward(
+            request,
+            inbound_stream,
+            server_reader,
+            server_writer,
+            deadline,
+        )
+        .await
+    }
+
+    /// Dial the remote proxy server with TCP and QUIC concurrently and forward the proxy request through the first
+    /// connection established, the other dial is cancelled. If one of them fails, the handler waits for the other one
+    /// to finish, so that the request only fails when UDP and TCP are both unreachable. A pooled TCP connection wins
+    /// without a race.
+    async fn handle_race_stream<T: AsyncRead + AsyncWrite + Unpin + Send + 'static>(
+        &self,
+        request: InboundRequest,
+        inbound_stream: T,
+        deadline: Deadline,
+    ) -> io::Result<()> {
+        let winner = match self.take_pooled() {
+            Some(stream) => RaceWinner::Tcp(Box::new(stream)),
+            None => self.race(&deadline).await?,
+        };
+
+        match winner {
+            RaceWinner::Tcp(outbound_stream) => {
+                debug!("Forwarding request through TCP");
+                let (server_reader, server_writer) = tokio::io::split(outbound_stream);
+                self.forward(
+                    request,
+                    inbound_stream,
+                    server_reader,
+                    server_writer,
+                    deadline,
+                )
+                .await
+            }
+            RaceWinner::Quic(server_writer, server_reader) => {
+                debug!("Forwarding request through QUIC");
+                self.forward(
+                    request,
+                    inbound_stream,
+                    server_reader,
+                    server_writer,
+                    deadline,
+                )
+                .await
             }
+        }
+    }
+
+    /// Dial the remote proxy server with TCP and QUIC concurrently, the first connection established wins.
+    async fn race(&self, deadline: &Deadline) -> io::Result<RaceWinner> {
+        let tcp = self.connect_tcp(Some(deadline));
+        let quic = self.connect_quic(Some(deadline));
+        tokio::pin!(tcp, quic);
+
+        let winner = tokio::select! {
+            result = &mut tcp => match result {
+                Ok(stream) => RaceWinner::Tcp(Box::new(stream)),
+                Err(e) => {
+                    debug!("TCP dial lost the race with error: {}", e);
+                    let stream = quic.await?;
+                    RaceWinner::Quic(stream.writer, stream.reader)
+                }
+            },
+            result = &mut quic => match result {
+                Ok(stream) => RaceWinner::Quic(stream.writer, stream.reader),
+                Err(e) => {
+                    debug!("QUIC dial lost the race with error: {}", e);
+                    RaceWinner::Tcp(Box::new(tcp.await?))
+                }
+            },
+        };
+        Ok(winner)
+    }
+
+    /// Take an idle connection to the remote proxy server from the pool if there is one still open, or establish a new
+    /// one like connect_tcp.
+    async fn connect_pooled(
+        &self,
+        deadline: Option<&Deadline>,
+    ) -> io::Result<StandardTcpStream<BoxedStream>> {
+        match self.take_pooled() {
+            Some(stream) => Ok(stream),
+            None => self.connect_tcp(deadline).await,
+        }
+    }
+
+    /// Idle connection to the remote proxy server from the pool, skipping the ones the server closed meanwhile.
+    fn take_pooled(&self) -> Option<StandardTcpStream<BoxedStream>> {
+        self.pool.as_ref()?.take_if(pool::is_open)
+    }
+
+    /// Keep the connection pool of the outbound full for as long as the process runs, if it has one.
+    pub async fn run_pool(&self) {
+        if let Some(pool) = &self.pool {
+            pool.fill(|| self.connect_tcp(None)).await
+        }
+    }
+
+    /// Establish the connection with the remote proxy server through the first of its addresses that accepts it.
+    /// Connections for a proxy request follow the policy of the outbound within the deadline of the request.
+    async fn connect_tcp(
+        &self,
+        deadline: Option<&Deadline>,
+    ) -> io::Result<StandardTcpStream<BoxedStream>> {
+        self.servers()?
+            .connect(|server| self.attempt(deadline, move || self.connect_tcp_to(server.clone())))
+            .await
+    }
+
+    /// Run the connection attempts to one address of the server as the policy allows, or a single attempt outside
+    /// of the proxy requests.
+    async fn attempt<T, F, Fut>(&self, deadline: Option<&Deadline>, mut connect: F) -> io::Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = io::Result<T>>,
+    {
+        match deadline {
+            Some(deadline) => self.policy.connect(deadline, connect).await,
+            None => connect().await,
+        }
+    }
+
+    /// Establish the connection with the address of the remote proxy server, escalated to TLS if tls config is
+    /// present. The connection goes through the dialer outbound if there is one.
+    async fn connect_tcp_to(
+        &self,
+        server: IpAddrPort,
+    ) -> io::Result<StandardTcpStream<BoxedStream>> {
+        // Establish the initial connection with remote server
+        let connection: BoxedStream = match &self.dialer {
+            Some(dialer) => dialer.open_stream(server.clone()).await?,
+            None => Box::new(self.connect_resolved(&server).await?),
         };
+        let connection: BoxedStream = Box::new(ResetMonitor::new(
+            connection,
+            self.resets.clone(),
+            server.to_string(),
+        ));
 
         // Escalate the connection to TLS connection if tls config is present
-        let mut outbound_stream = match &self.tls {
+        match &self.tls {
             Some((client_config, domain)) => {
-                let connector = TlsConnector::from(client_config.clone());
-                StandardTcpStream::RustlsClient(
+                #[cfg(feature = "client")]
+                let client_config = match &self.fingerprints {
+                    Some(fingerprints) => fingerprints.current(),
+                    None => client_config.clone(),
+                };
+                #[cfg(not(feature = "client"))]
+                let client_config = client_config.clone();
+
+                // Offer the ALPN protocols the server advertises in DNS
+                let alpn = self
+                    .servers
+                    .as_ref()
+                    .and_then(|servers| servers.alpn(&server));
+                let client_config = match alpn {
+                    Some(alpn) => {
+                        let mut client_config = (*client_config).clone();
+                        client_config.alpn_protocols = alpn;
+                        Arc::new(client_config)
+                    }
+                    None => client_config,
+                };
+                let connector = TlsConnector::from(client_config);
+                Ok(StandardTcpStream::RustlsClient(
                     connector.connect(domain.clone(), connection).await?,
-                )
+                ))
+            }
+            None => Ok(StandardTcpStream::Plain(connection)),
+        }
+    }
+
+    /// Connect to the addresses of the server one at a time until one of them accepts the connection. Names are looked
+    /// up again for every connection, so the addresses follow the DNS answers as their TTL expires. With TCP Fast Open
+    /// the connection is only confirmed by its first write, so only the first address is used.
+    async fn connect_resolved(&self, server: &IpAddrPort) -> io::Result<TcpStream> {
+        let mut last_error = None;
+
+        let mut addrs = server.resolve_all_with(self.domain_strategy).await?;
+        if self.socket.fast_open() {
+            addrs.truncate(1);
+        }
+        for addr in addrs {
+            match self.socket.connect(addr).await {
+                Ok(stream) => return Ok(stream),
+                Err(e) => {
+                    debug!("Failed to connect to {} of server {}: {}", addr, server, e);
+                    last_error = Some(e);
+                }
+            }
+        }
+
+        // Safety: there is at least one address
+        Err(last_error.unwrap())
+    }
+
+    /// Addresses of the remote proxy server, domain names are resolved when connecting to the server.
+    fn servers(&self) -> io::Result<&ServerList> {
+        match &self.servers {
+            Some(servers) => Ok(servers),
+            None => Err(Error::new(
+                ErrorKind::NotConnected,
+                "missing address of the remote server",
+            )),
+        }
+    }
+
+    /// Establish a QUIC connection with the remote proxy server through the first of its addresses that accepts it,
+    /// like connect_tcp.
+    async fn connect_quic(&self, deadline: Option<&Deadline>) -> io::Result<QuicStream> {
+        self.servers()?
+            .connect(|server| self.attempt(deadline, move || self.connect_quic_to(server.clone())))
+            .await
+    }
+
+    /// Establish a QUIC connection with the address of the remote proxy server and open a bidirectional stream on
+    /// it. The server certificate is verified according to the tls config, and not verified at all if tls config is
+    /// absent.
+    async fn connect_quic_to(&self, server: IpAddrPort) -> io::Result<QuicStream> {
+        let destination = server.resolve_with(self.domain_strategy).await?;
+
+        let (client_crypto, server_name) = match &self.tls {
+            Some((client_config, ServerName::DnsName(name))) => {
+                (client_config.clone(), name.as_ref().to_string())
             }
-            None => StandardTcpStream::Plain(connection),
+            Some((client_config, _)) => (client_config.clone(), destination.ip().to_string()),
+            None => (
+                Arc::new(
+                    rustls::ClientConfig::builder()
+                        .with_cipher_suites(preferred_cipher_suites())
+                        .with_safe_default_kx_groups()
+                        .with_safe_default_protocol_versions()
+                        .unwrap()
+                        .with_custom_certificate_verifier(Arc::new(NoCertificateVerification {}))
+                        .with_no_client_auth(),
+                ),
+                destination.ip().to_string(),
+            ),
         };
 
+        let bind_address = match destination {
+            SocketAddr::V4(_) => "0.0.0.0:0",
+            SocketAddr::V6(_) => "[::]:0",
+        };
+
+        let mut endpoint = quinn::Endpoint::client(bind_address.parse().unwrap())?;
+        endpoint.set_default_client_config(quinn::ClientConfig::new(client_crypto));
+
+        // Establish connection with remote proxy server using QUIC protocol
+        let connecting = match endpoint.connect(destination, &server_name) {
+            Ok(connecting) => connecting,
+            Err(e) => return Err(Error::new(ErrorKind::InvalidInput, e)),
+        };
+
+        let connection = match connecting.await {
+            Ok(connection) => connection,
+            Err(e) => return Err(Error::new(ErrorKind::ConnectionRefused, e)),
+        };
+
+        match connection.connection.open_bi().await {
+            Ok((writer, reader)) => Ok(QuicStream {
+                connection,
+                writer,
+                reader,
+            }),
+            Err(e) => Err(Error::new(ErrorKind::ConnectionRefused, e)),
+        }
+    }
+
+    /// Send the proxy request to the remote proxy server over the established connection and transport data back and
+    /// forth until one side terminate the connection.
+    async fn forward<
+        T: AsyncRead + AsyncWrite + Unpin + Send,
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    >(
+        &self,
+        request: InboundRequest,
+        inbound_stream: T,
+        server_reader: R,
+        mut server_writer: W,
+        deadline: Deadline,
+    ) -> io::Result<()> {
         // Handshake to form the proxy stream
         match self.protocol {
             SupportedProtocols::TROJAN => {
@@ -273,31 +894,23 @@ impl TcpHandler {
                 }
 
                 // Start handshake to establish proxy stream
-                handshake(&mut outbound_stream, &request, &self.secret).await?;
-
-                match request.transport_protocol {
-                    TransportProtocol::TCP => {
-                        let (mut client_reader, mut client_writer) =
-                            tokio::io::split(inbound_stream);
-                        let (mut server_reader, mut server_writer) =
-                            tokio::io::split(outbound_stream);
+                deadline
+                    .run(
+                        "handshake",
+                        handshake(&mut server_writer, &request, &self.secret),
+                    )
+                    .await?;
 
-                        // Obtain reader and writer for inbound and outbound streams
-                        tokio::select!(
-                            _ = tokio::io::copy(&mut client_reader, &mut server_writer) => (),
-                            _ = tokio::io::copy(&mut server_reader, &mut client_writer) => ()
-                        );
-                    }
-                    TransportProtocol::UDP => {
-                        let (client_reader, client_writer) = tokio::io::split(inbound_stream);
-                        let (server_reader, server_writer) = tokio::io::split(outbound_stream);
-
-                        tokio::select!(
-                            _ = trojan::packet::copy_client_reader_to_udp_server_writer(client_reader, BufWriter::new(server_writer), request) => (),
-                            _ = trojan::packet::copy_udp_server_reader_to_client_writer(BufReader::new(server_reader), client_writer) => (),
-                        );
-                    }
-                }
+                // The inbound stream of UDP requests carries trojan UDP packets already, which are relayed as they are
+                let (client_reader, client_writer) = tokio::io::split(inbound_stream);
+                relay_with_policy(
+                    client_reader,
+                    client_writer,
+                    server_reader,
+                    server_writer,
+                    &self.policy,
+                )
+                .await?;
             }
             SupportedProtocols::SOCKS => {
                 return Err(Error::new(ErrorKind::Unsupported, "Unsupported protocol"))
@@ -307,39 +920,47 @@ impl TcpHandler {
             }
         };
 
-        info!("Connection finished");
         Ok(())
     }
 
+    /// Establish GRPC connection with the address of the remote server.
+    async fn connect_grpc_to(
+        &self,
+        server: IpAddrPort,
+        deadline: Deadline,
+    ) -> io::Result<GrpcServiceClient<Channel>> {
+        let destination = deadline
+            .run("dns", server.resolve_with(self.domain_strategy))
+            .await?;
+        let endpoint = match self.tls {
+            None => format!("http://{}", destination),
+            Some(_) => format!("https://{}", destination),
+        };
+
+        match GrpcServiceClient::connect(endpoint).await {
+            Ok(c) => Ok(c),
+            Err(_) => Err(Error::new(
+                ErrorKind::ConnectionRefused,
+                "Failed to connect to remote GRPC server",
+            )),
+        }
+    }
+
     async fn handle_grpc_stream<T: AsyncRead + AsyncWrite + Unpin + Send>(
         &self,
         request: InboundRequest,
-        inbound_stream: StandardTcpStream<T>,
+        inbound_stream: T,
+        deadline: Deadline,
     ) -> io::Result<()> {
         // Remote GrpcService can not be None, otherwise we have no idea how to handle the proxy request
-        if self.destination == None {
-            return Err(Error::new(
-                ErrorKind::Unsupported,
-                "Destination can not be null",
-            ));
-        }
-
-        // Safety: We have checked previous for self.destination equals None condition, so that the unwrap will always work.
-        let endpoint = match self.tls {
-            None => format!("http://{}", self.destination.unwrap()),
-            Some(_) => format!("https://{}", self.destination.unwrap()),
-        };
-
-        // Establish GRPC connection with remote server
-        let mut connection = match GrpcServiceClient::connect(endpoint).await {
-            Ok(c) => c,
-            Err(_) => {
-                return Err(Error::new(
-                    ErrorKind::ConnectionRefused,
-                    "Failed to connect to remote GRPC server",
-                ))
-            }
-        };
+        let mut connection = self
+            .servers()?
+            .connect(|server| {
+                self.policy.connect(&deadline, move || {
+                    self.connect_grpc_to(server.clone(), deadline)
+                })
+            })
+            .await?;
 
         let (tx, rx) = mpsc::channel(16);
 
@@ -371,18 +992,14 @@ impl TcpHandler {
 
         // Dispatch the request based on the proxy command
         match request.command {
-            crate::protocol::common::command::Command::Connect => {
+            // The inbound stream of UDP requests carries trojan UDP packets already, which are relayed as they are
+            crate::protocol::common::command::Command::Connect
+            | crate::protocol::common::command::Command::Udp => {
                 tokio::select!(
                     _ = copy_client_reader_to_server_grpc_writer(client_reader, tx) => (),
                     _ = copy_server_grpc_reader_to_client_writer(server_reader, client_writer) => ()
                 );
             }
-            crate::protocol::common::command::Command::Udp => {
-                tokio::select!(
-                    _ = trojan::packet::copy_client_reader_to_server_grpc_writer(client_reader, tx, request) => (),
-                    _ = trojan::packet::copy_server_grpc_reader_to_client_writer(server_reader, client_writer) => (),
-                );
-            }
             crate::protocol::common::command::Command::Bind => {
                 return Err(Error::new(
                     ErrorKind::Unsupported,
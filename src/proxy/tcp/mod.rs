@@ -1,3 +1,7 @@
 pub mod acceptor;
+pub mod fallback;
 pub mod handler;
+pub mod pool;
 pub mod server;
+pub mod sni;
+pub mod sniff;
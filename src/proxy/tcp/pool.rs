@@ -0,0 +1,192 @@
+use crate::config::base::{OutboundMode, PoolConfig};
+use crate::metrics;
+
+use futures::task::noop_waker_ref;
+use log::debug;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::io::{self, Error, ErrorKind};
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, ReadBuf};
+use tokio::sync::Notify;
+use tokio::time::Instant;
+
+/// Default seconds an idle connection is kept before being replaced
+const DEFAULT_MAX_IDLE: u64 = 20;
+
+/// Wait before filling the pool again after a connection failed
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Pool of connections established ahead of the requests. The requests take the connections from the pool and the pool
+/// is refilled in the background, so that the requests don't wait for the handshakes as long as the pool keeps up.
+/// Connections idle for longer than max_idle are replaced, as the server would close them before the request arrives.
+/// The requests served from the pool and the ones that found it empty are counted in connection_pool_requests_total
+/// labelled with the outbound, the connections found closed by the server when taken in
+/// connection_pool_stale_total.
+pub struct ConnectionPool<T> {
+    tag: String,
+    size: usize,
+    max_idle: Duration,
+    idle: Mutex<VecDeque<(Instant, T)>>,
+    taken: Notify,
+    hits: String,
+    misses: String,
+    stale: String,
+}
+
+impl<T> ConnectionPool<T> {
+    /// Pool of the outbound with the tag, fails with InvalidInput if it would never hold a connection or the mode of
+    /// the outbound doesn't connect to the server over TCP.
+    pub fn new(tag: &str, mode: &OutboundMode, config: &PoolConfig) -> io::Result<Self> {
+        if !matches!(mode, OutboundMode::TCP | OutboundMode::RACE) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "Outbound {} has to be in TCP or RACE mode to pool connections",
+                    tag
+                ),
+            ));
+        }
+        let max_idle = config.max_idle.unwrap_or(DEFAULT_MAX_IDLE);
+        if config.size == 0 || max_idle == 0 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("size and max_idle of the pool of {} must be positive", tag),
+            ));
+        }
+
+        let label = metrics::escape_label(tag);
+        Ok(Self {
+            tag: tag.to_string(),
+            size: config.size,
+            max_idle: Duration::from_secs(max_idle),
+            idle: Mutex::new(VecDeque::with_capacity(config.size)),
+            taken: Notify::new(),
+            hits: format!(
+                "connection_pool_requests_total{{outbound=\"{}\",result=\"hit\"}}",
+                label
+            ),
+            misses: format!(
+                "connection_pool_requests_total{{outbound=\"{}\",result=\"miss\"}}",
+                label
+            ),
+            stale: format!("connection_pool_stale_total{{outbound=\"{}\"}}", label),
+        })
+    }
+
+    /// Most recently established idle connection, if any is still fresh. The pool is topped up in the background.
+    pub fn take(&self) -> Option<T> {
+        self.take_if(|_| true)
+    }
+
+    /// Most recently established idle connection that is still fresh and usable, the ones found unusable are dropped
+    /// and the next one is tried. The pool is topped up in the background.
+    pub fn take_if<F: FnMut(&mut T) -> bool>(&self, mut usable: F) -> Option<T> {
+        let connection = loop {
+            let mut connection = {
+                let mut idle = self.idle.lock().unwrap();
+                self.expire(&mut idle);
+                match idle.pop_back() {
+                    Some((_, connection)) => connection,
+                    None => break None,
+                }
+            };
+            if usable(&mut connection) {
+                break Some(connection);
+            }
+            debug!(
+                "Dropping pooled connection of {} closed by the server",
+                self.tag
+            );
+            metrics::increment(&self.stale, 1);
+        };
+        self.taken.notify_one();
+
+        match &connection {
+            Some(_) => metrics::increment(&self.hits, 1),
+            None => metrics::increment(&self.misses, 1),
+        }
+        connection
+    }
+
+    /// Give back an established connection that went unused, it is dropped if the pool is full.
+    pub fn put(&self, connection: T) {
+        let mut idle = self.idle.lock().unwrap();
+        if idle.len() < self.size {
+            idle.push_back((Instant::now(), connection));
+        }
+    }
+
+    /// Number of idle connections, including the ones about to expire.
+    pub fn len(&self) -> usize {
+        self.idle.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Keep the pool full with the connections established by connect for as long as the process runs. The pool is
+    /// topped up whenever a connection is taken or expires, and RETRY_INTERVAL after a connection failed.
+    pub async fn fill<F, Fut>(&self, mut connect: F)
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = io::Result<T>>,
+    {
+        loop {
+            let missing = {
+                let mut idle = self.idle.lock().unwrap();
+                self.expire(&mut idle);
+                self.size - idle.len()
+            };
+
+            let mut failed = false;
+            for _ in 0..missing {
+                match connect().await {
+                    Ok(connection) => self.put(connection),
+                    Err(e) => {
+                        debug!("Failed to fill the connection pool of {}: {}", self.tag, e);
+                        failed = true;
+                        break;
+                    }
+                }
+            }
+
+            if failed {
+                tokio::time::sleep(RETRY_INTERVAL).await;
+                continue;
+            }
+
+            // Until the oldest connection expires
+            let wait = match self.idle.lock().unwrap().front() {
+                Some((established, _)) => self.max_idle.saturating_sub(established.elapsed()),
+                None => self.max_idle,
+            };
+            let _ = tokio::time::timeout(wait, self.taken.notified()).await;
+        }
+    }
+
+    fn expire(&self, idle: &mut VecDeque<(Instant, T)>) {
+        while let Some((established, _)) = idle.front() {
+            if established.elapsed() < self.max_idle {
+                break;
+            }
+            idle.pop_front();
+        }
+    }
+}
+
+/// Whether the idle connection is still open, checked without waiting: a connection the server closed or reset reads
+/// the end of the stream or an error right away. A connection with data nobody asked for is no longer usable either.
+pub fn is_open<T: AsyncRead + Unpin>(connection: &mut T) -> bool {
+    let mut byte = [0u8; 1];
+    let mut buf = ReadBuf::new(&mut byte);
+    let mut cx = Context::from_waker(noop_waker_ref());
+    matches!(
+        Pin::new(connection).poll_read(&mut cx, &mut buf),
+        Poll::Pending
+    )
+}
@@ -1,59 +1,460 @@
-use crate::config::base::{InboundConfig, OutboundConfig};
+#[cfg(feature = "server")]
+use crate::admin::server::AdminHandoff;
+use crate::auth::port;
+use crate::config::base::{DomainResolution, DomainStrategy, InboundConfig};
+#[cfg(feature = "client")]
+use crate::dns::fake::FakeDns;
+#[cfg(feature = "client")]
+use crate::dns::hijack::DnsHijack;
+use crate::events;
+use crate::health;
+use crate::metrics;
+use crate::metrics::access::AccessLog;
+use crate::profiling;
+use crate::protocol::common::addr::IpAddress;
+use crate::protocol::common::atype::Atype;
+use crate::protocol::common::request::{InboundRequest, TransportProtocol};
+use crate::protocol::common::stream::{IntoTcpStream, PrefixedStream};
+use crate::protocol::socks5::udp::UdpAssociation;
+use crate::protocol::socks5::{self, reply::DeferredReply};
+use crate::protocol::tls::record::RecordStream;
+use crate::proxy::base::SupportedProtocols;
+use crate::proxy::connections::ActiveConnection;
+use crate::proxy::context::TrafficContext;
+use crate::proxy::deadline::Deadline;
+use crate::proxy::limiter::{acquire_slots, ConcurrencyLimit, ConnectionLimiter};
+use crate::proxy::listener::bind_tcp;
+use crate::proxy::relay::Transfer;
+use crate::proxy::socket::SocketOptions;
 use crate::proxy::tcp::acceptor::TcpAcceptor;
 use crate::proxy::tcp::handler::TcpHandler;
+use crate::proxy::tcp::sni::pass_through;
+use crate::proxy::throttle::{GlobalBandwidth, Throttled, UserBandwidth};
+use crate::router::{RouteContext, Router};
 
-use log::{info, warn};
-use std::io::Result;
-use std::net::ToSocketAddrs;
+use futures::future::try_join_all;
+use log::{debug, info, warn};
+use std::io::{ErrorKind, Result};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
 use tokio::net::TcpListener;
+use tracing::field::{display, Empty};
+use tracing::{info_span, Instrument, Span};
 
-pub async fn start(
+/// Size of the in-memory stream carrying the trojan UDP packets of a SOCKS UDP association
+const UDP_PACKETS_BUFFER_SIZE: usize = 64 * 1024;
+
+pub async fn start(inbound_config: &'static InboundConfig, router: &'static Router) -> Result<()> {
+    // Start the TCP server listener sockets, a host name may resolve to addresses of both families
+    let listeners = bind_tcp(
+        &inbound_config.address,
+        inbound_config.port,
+        inbound_config.v6only,
+    )?;
+    health::mark_listening();
+
+    // Create TCP server acceptor
+    let acceptor = TcpAcceptor::init(&inbound_config)?;
+    if inbound_config.fallbacks.is_some() {
+        tokio::spawn(acceptor.fallback().run_health_checks());
+    }
+    let limiter = inbound_config
+        .connection_limit
+        .as_ref()
+        .map(ConnectionLimiter::new);
+    let connections = match &inbound_config.connection_limit {
+        Some(config) => ConcurrencyLimit::for_inbound(config)?,
+        None => None,
+    };
+
+    // Run the accept loop of every listener until one of them fails
+    try_join_all(listeners.into_iter().map(|listener| {
+        serve(
+            listener,
+            inbound_config,
+            acceptor,
+            router,
+            limiter.as_ref(),
+            connections.as_ref(),
+        )
+    }))
+    .await?;
+
+    Ok(())
+}
+
+/// Server listener socket accept loop
+async fn serve(
+    listener: TcpListener,
     inbound_config: &'static InboundConfig,
-    outbound_config: &'static OutboundConfig,
+    acceptor: &'static TcpAcceptor,
+    router: &'static Router,
+    limiter: Option<&ConnectionLimiter>,
+    connections: Option<&ConcurrencyLimit>,
 ) -> Result<()> {
-    // Extract the inbound client address
-    let address = (inbound_config.address.clone(), inbound_config.port)
-        .to_socket_addrs()
-        .unwrap()
-        .next()
-        .unwrap();
-
-    // Start the TCP server listener socket
-    let listener = TcpListener::bind(address).await?;
-
-    // Create TCP server acceptor and handler
-    let (acceptor, handler) = (
-        TcpAcceptor::init(&inbound_config),
-        TcpHandler::init(&outbound_config),
-    );
-
-    // Enter server listener socket accept loop
+    let socket_options = SocketOptions::new(inbound_config.socket.as_ref())?;
+    socket_options.listen(&listener)?;
+
+    // Connections through this listener are capped on top of the ones through the whole inbound
+    let listener_connections = match &inbound_config.connection_limit {
+        Some(config) => ConcurrencyLimit::for_listener(config, listener.local_addr()?)?,
+        None => None,
+    };
+    let limits: Vec<_> = listener_connections.iter().chain(connections).collect();
+
     loop {
         info!("Ready to accept new socket connection");
 
         let (socket, addr) = listener.accept().await?;
+        let local = match socket.local_addr() {
+            Ok(local) => local,
+            Err(e) => {
+                warn!(
+                    "Failed to get the local address of connection from {}: {}",
+                    addr, e
+                );
+                continue;
+            }
+        };
 
-        info!("Received new connection from {}", addr);
+        if !acceptor.allows_source(addr.ip()) {
+            continue;
+        }
 
-        let (acceptor, handler) = (acceptor, handler);
+        // Sources over their rate may be held back for a while rather than closed
+        let delay = match limiter.map(|limiter| limiter.admit(addr.ip())) {
+            Some(Some(delay)) => delay,
+            Some(None) => continue,
+            None => Duration::ZERO,
+        };
 
-        tokio::spawn(async move {
-            let (request, inbound_stream) = match acceptor.accept(socket).await {
-                Ok(stream) => stream,
-                Err(e) => {
-                    warn!("Failed to accept inbound connection from {}: {}", addr, e);
+        // Held until the connection is closed
+        let slots = match acquire_slots(&limits).await {
+            Some(slots) => slots,
+            None => continue,
+        };
+
+        if let Err(e) = socket_options.apply(&socket) {
+            warn!(
+                "Failed to set socket options of connection from {}: {}",
+                addr, e
+            );
+        }
+
+        let sampled = AccessLog::get().sample();
+        if sampled && !AccessLog::get().separate() {
+            info!("Received new connection from {}", addr);
+        }
+
+        // Kernel TLS can only take over at the end of a record
+        let socket = RecordStream::new(socket, acceptor.kernel_tls());
+
+        let span = info_span!(
+            "connection",
+            otel.kind = "server",
+            source = %addr,
+            inbound = acceptor.tag(),
+            destination = Empty,
+            user = Empty,
+            uploaded = Empty,
+            downloaded = Empty
+        );
+        tokio::spawn(profiling::profile(
+            addr,
+            async move {
+                let _slots = slots;
+                if !delay.is_zero() {
+                    tokio::time::sleep(delay).await;
+                }
+
+                let deadline = Deadline::new(inbound_config);
+                if !acceptor.sni_routing_enabled() {
+                    return handle(socket, addr, local, deadline, acceptor, router, sampled).await;
+                }
+
+                // Route the connection by the server name in TLS ClientHello
+                let (stream, backend) = match deadline.run("tls", acceptor.route_sni(socket)).await
+                {
+                    Ok(route) => route,
+                    Err(e) => {
+                        warn!("Failed to read TLS ClientHello from {}: {}", addr, e);
+                        return;
+                    }
+                };
+
+                match backend {
+                    Some(backend) => {
+                        if let Err(e) = pass_through(stream, backend, acceptor.backends()).await {
+                            warn!("Failed to pass through connection from {}: {}", addr, e);
+                        }
+                    }
+                    None => handle(stream, addr, local, deadline, acceptor, router, sampled).await,
+                }
+            }
+            .instrument(span),
+        ));
+    }
+}
+
+/// Accept the inbound stream as proxy traffic and dispatch the request to the outbound handler selected by the router.
+/// The connection is only written to the access log if it was sampled, failures are logged either way.
+async fn handle<T: AsyncRead + AsyncWrite + IntoTcpStream + Unpin + Send + 'static>(
+    socket: T,
+    addr: SocketAddr,
+    local: SocketAddr,
+    deadline: Deadline,
+    acceptor: &'static TcpAcceptor,
+    router: &'static Router,
+    sampled: bool,
+) {
+    let (mut request, inbound_stream) = match acceptor.accept(socket, addr, deadline).await {
+        Ok(stream) => stream,
+        #[cfg(feature = "server")]
+        Err(e) if AdminHandoff::is(&e) => {
+            debug!("Served admin API connection from {}", addr);
+            return;
+        }
+        Err(e) => {
+            warn!("Failed to accept inbound connection from {}: {}", addr, e);
+            return;
+        }
+    };
+
+    // Connect to the domain the application looked up rather than to its fake address
+    #[cfg(feature = "client")]
+    if let Some(fake_dns) = FakeDns::get() {
+        if let Err(e) = fake_dns.restore(&mut request) {
+            warn!("Failed to handle connection from {}: {}", addr, e);
+            return;
+        }
+    }
+
+    // Learn the domain of requests to IP addresses from their traffic, unless the client waits for the SOCKS reply
+    // before sending anything
+    let (inbound_stream, sniffed_domain) = match acceptor.deferred_reply() {
+        true => (PrefixedStream::new(Vec::new(), inbound_stream), None),
+        false => match acceptor.sniffer().sniff(&mut request, inbound_stream).await {
+            Ok(sniffed) => sniffed,
+            Err(e) => {
+                warn!("Failed to handle connection from {}: {}", addr, e);
+                return;
+            }
+        },
+    };
+    profiling::set_destination(&request.addr_port);
+    let span = Span::current();
+    span.record("destination", display(&request.addr_port));
+    if let Some(user) = &request.user {
+        span.record("user", user.name.as_str());
+    }
+    let user_rate = request
+        .user
+        .as_ref()
+        .and_then(|user| UserBandwidth::get().user(&user.name));
+    let inbound_stream = Throttled::new(inbound_stream, acceptor.bandwidth())
+        .with_user(user_rate)
+        .with_global(GlobalBandwidth::get());
+
+    let (handler, resolution) = router.route_with_resolution(&RouteContext {
+        request: &request,
+        inbound_tag: acceptor.tag(),
+        sniffed_domain: sniffed_domain.as_deref(),
+    });
+    let context = TrafficContext::new(
+        acceptor.tag(),
+        Some(handler.tag()),
+        request.user.as_ref().map(|user| user.name.as_str()),
+    )
+    .with_destination(request.addr_port.to_string());
+    let connection = events::Connection::open(addr, &context);
+    let context = context.with_connection(connection.id());
+    profiling::set_context(&context);
+    let transfer = Transfer::new();
+    let registration = ActiveConnection {
+        id: connection.id(),
+        source: addr,
+        context: context.clone(),
+        transport: "tcp",
+        mode: match request.transport_protocol {
+            TransportProtocol::UDP => "udp",
+            _ => "tcp",
+        },
+        transfer: transfer.clone(),
+    }
+    .register();
+
+    // Everything counted and logged from here on is attributed to the inbound, outbound and user of the connection
+    context
+        .clone()
+        .scope(async move {
+            metrics::increment_attributed("connections_total", 1);
+            AccessLog::get().record(&request.addr_port);
+            if sampled && !AccessLog::get().separate() {
+                info!(
+                    "Connection from {} requests {} ({})",
+                    addr, request.addr_port, context
+                );
+            }
+
+            if resolution.or(acceptor.resolution()) == Some(DomainResolution::LOCAL) {
+                if let Err(e) =
+                    resolve_locally(&mut request, handler.domain_strategy(), deadline).await
+                {
+                    warn!(
+                        "Failed to handle connection from {} ({}): {}",
+                        addr, context, e
+                    );
+                    connection.fail(&e);
                     return;
                 }
-            };
+            }
+
+            let destination = request.addr_port.to_string();
+            let result = transfer
+                .scope(registration.until_closed(dispatch(
+                    inbound_stream,
+                    request,
+                    addr,
+                    local,
+                    deadline,
+                    acceptor,
+                    handler,
+                )))
+                .await;
 
-            match handler.dispatch(inbound_stream, request).await {
+            match result {
                 Ok(_) => {
-                    info!("Connection from {} has finished", addr);
+                    if sampled {
+                        AccessLog::get().log(format_args!(
+                            "Connection from {} to {} ({}) has finished: {}",
+                            addr, destination, context, transfer
+                        ));
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::PermissionDenied || registration.is_closed() => {
+                    if sampled {
+                        AccessLog::get().log(format_args!(
+                            "Connection from {} to {} ({}) is closed: {}",
+                            addr, destination, context, e
+                        ));
+                    }
                 }
                 Err(e) => {
-                    warn!("Failed to handle the inbound stream: {}", e);
+                    warn!(
+                        "Failed to handle the inbound stream from {} ({}): {}",
+                        addr, context, e
+                    );
+                    if sampled && AccessLog::get().separate() {
+                        AccessLog::get().log(format_args!(
+                            "Connection from {} to {} ({}) has failed: {}",
+                            addr, destination, context, e
+                        ));
+                    }
+                    connection.fail(&e);
                 }
             }
-        });
+        })
+        .await
+}
+
+/// Dispatch the request to the outbound handler, replying to SOCKS requests as the acceptor is configured to.
+async fn dispatch<T: AsyncRead + AsyncWrite + IntoTcpStream + Unpin + Send + 'static>(
+    inbound_stream: T,
+    request: InboundRequest,
+    addr: SocketAddr,
+    local: SocketAddr,
+    deadline: Deadline,
+    acceptor: &'static TcpAcceptor,
+    handler: &TcpHandler,
+) -> Result<()> {
+    port::check_request(&request, "tcp")?;
+    let udp_associate = matches!(request.proxy_protocol, SupportedProtocols::SOCKS)
+        && request.transport_protocol == TransportProtocol::UDP;
+    match acceptor.deferred_reply() {
+        _ if udp_associate => {
+            associate(
+                inbound_stream,
+                request,
+                addr,
+                local,
+                handler,
+                deadline,
+                acceptor.intercept_dns(),
+            )
+            .await
+        }
+        true => {
+            // Reply to the SOCKS request once the outbound is connected, or with the error if it fails
+            let ack = socks5::request_ack(socks5::REPLY_SUCCEEDED, acceptor.port());
+            let (inbound_stream, failure) = DeferredReply::new(inbound_stream, ack);
+            let result = handler.dispatch(inbound_stream, request, deadline).await;
+            if let Err(e) = &result {
+                let _ = failure.send(socks5::request_ack(socks5::reply_code(e), acceptor.port()));
+            }
+            result
+        }
+        false => handler.dispatch(inbound_stream, request, deadline).await,
+    }
+}
+
+/// Replace the domain name in the destination of the request with its address, so that the outbound passes on the
+/// address rather than the domain name.
+async fn resolve_locally(
+    request: &mut InboundRequest,
+    strategy: DomainStrategy,
+    deadline: Deadline,
+) -> Result<()> {
+    if !matches!(request.addr_port.ip, IpAddress::Domain(_)) {
+        return Ok(());
+    }
+
+    let addr = deadline
+        .run("dns", request.addr_port.resolve_with(strategy))
+        .await?;
+    debug!("Resolved {} locally to {}", request.addr_port, addr);
+
+    request.addr_port.ip = IpAddress::IpAddr(addr.ip());
+    request.atype = match addr {
+        SocketAddr::V4(_) => Atype::IPv4,
+        SocketAddr::V6(_) => Atype::IPv6,
+    };
+    Ok(())
+}
+
+/// Serve the UDP ASSOCIATE request of a SOCKS client. A relay socket is bound on the address the client connected
+/// to, and the datagrams it receives go through the outbound as trojan UDP packets for as long as the client keeps
+/// the connection of the request open. The DNS queries are answered by the DNS inbound instead if they are intercepted.
+async fn associate<T: AsyncRead + AsyncWrite + Unpin + Send + 'static>(
+    mut inbound_stream: T,
+    request: InboundRequest,
+    addr: SocketAddr,
+    local: SocketAddr,
+    handler: &TcpHandler,
+    deadline: Deadline,
+    intercept_dns: bool,
+) -> Result<()> {
+    let association = UdpAssociation::bind(local.ip()).await?;
+    #[cfg(feature = "client")]
+    let association = match DnsHijack::get().filter(|_| intercept_dns) {
+        Some(dns) => association.intercept_dns(dns),
+        None => association,
+    };
+    #[cfg(not(feature = "client"))]
+    let _ = intercept_dns;
+    let relay_addr = association.local_addr()?;
+    inbound_stream
+        .write_all(&socks5::bound_ack(socks5::REPLY_SUCCEEDED, relay_addr))
+        .await?;
+    inbound_stream.flush().await?;
+    debug!("Relaying UDP datagrams of {} on {}", addr, relay_addr);
+
+    let (packets, outbound_stream) = tokio::io::duplex(UDP_PACKETS_BUFFER_SIZE);
+    let mut sink = tokio::io::sink();
+    tokio::select! {
+        result = handler.dispatch(outbound_stream, request, deadline) => result,
+        result = association.relay(addr.ip(), packets) => result,
+        // Nothing else is sent on the connection, which is closed once the client is done
+        result = tokio::io::copy(&mut inbound_stream, &mut sink) => result.map(|_| ()),
     }
 }
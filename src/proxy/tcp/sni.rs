@@ -0,0 +1,81 @@
+use crate::config::base::InboundTlsConfig;
+use crate::proxy::relay::relay;
+use crate::proxy::socket::SocketOptions;
+
+use log::info;
+use std::io::Result;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// SniRouter decides where a TLS connection should go based on the server name in the ClientHello. Connections for
+/// the proxy server names are terminated and handled as proxy traffic, while the others may be passed through to
+/// local backends as raw TLS streams. This allows a single port 443 to host both the proxy and real websites.
+pub struct SniRouter {
+    server_names: Vec<String>,
+    routes: Vec<(String, String)>,
+}
+
+impl SniRouter {
+    /// Build the router from inbound TLS configuration, returns None if no SNI route is configured.
+    pub fn new(config: &InboundTlsConfig) -> Option<Self> {
+        let routes = match &config.sni_routes {
+            Some(routes) if !routes.is_empty() => routes
+                .iter()
+                .map(|r| (r.server_name.to_ascii_lowercase(), r.backend.clone()))
+                .collect(),
+            _ => return None,
+        };
+
+        let server_names = config
+            .server_names
+            .iter()
+            .flatten()
+            .map(|name| name.to_ascii_lowercase())
+            .collect();
+
+        Some(Self {
+            server_names,
+            routes,
+        })
+    }
+
+    /// Look up the backend that the connection with the server name should be passed through to. None means the
+    /// connection should be handled by the proxy itself.
+    pub fn route(&self, server_name: Option<&str>) -> Option<&str> {
+        let name = server_name?;
+
+        if self.server_names.iter().any(|p| matches(p, name)) {
+            return None;
+        }
+
+        self.routes
+            .iter()
+            .find(|(pattern, _)| matches(pattern, name))
+            .map(|(_, backend)| backend.as_str())
+    }
+}
+
+/// Match server name against the pattern, which can either be an exact name, *.suffix or *.
+fn matches(pattern: &str, name: &str) -> bool {
+    match pattern.strip_prefix('*') {
+        Some("") => true,
+        Some(suffix) if suffix.starts_with('.') => name.ends_with(suffix),
+        _ => pattern == name,
+    }
+}
+
+/// Forward the raw inbound stream to the backend, connected with the socket options, and transport data back and forth
+/// until either side terminates.
+pub async fn pass_through<T: AsyncRead + AsyncWrite + Unpin>(
+    inbound_stream: T,
+    backend: &str,
+    socket: &SocketOptions,
+) -> Result<()> {
+    let outbound_stream = socket.connect(backend).await?;
+
+    info!("Passing through TLS connection to {}", backend);
+
+    let (client_reader, client_writer) = tokio::io::split(inbound_stream);
+    let (server_reader, server_writer) = tokio::io::split(outbound_stream);
+
+    relay(client_reader, client_writer, server_reader, server_writer).await
+}
@@ -0,0 +1,219 @@
+use crate::config::base::{PortConfig, SniffProtocol, SniffingConfig};
+use crate::metrics;
+use crate::protocol::common::addr::{IpAddrPort, IpAddress};
+use crate::protocol::common::atype::Atype;
+use crate::protocol::common::request::{InboundRequest, TransportProtocol};
+use crate::protocol::common::stream::PrefixedStream;
+use crate::protocol::tls::parse_server_name;
+use crate::router::matcher::parse_port_range;
+
+use log::debug;
+use std::io::{Error, ErrorKind, Result};
+use std::net::IpAddr;
+use std::ops::RangeInclusive;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::time::{timeout_at, Instant};
+
+/// Default milliseconds the client has to send enough bytes to be sniffed
+const DEFAULT_TIMEOUT: u64 = 300;
+
+/// Size of TLS record header, content type(1) + version(2) + length(2)
+const RECORD_HEADER_SIZE: usize = 5;
+
+/// Most bytes read while sniffing, a TLS record header and the largest record
+const MAX_SNIFF_SIZE: usize = RECORD_HEADER_SIZE + 16384;
+
+/// TLS record content type for handshake messages
+const CONTENT_TYPE_HANDSHAKE: u8 = 0x16;
+
+/// Outcome of sniffing the bytes read so far.
+enum Sniff {
+    Domain(String),
+    Incomplete,
+    Unknown,
+}
+
+struct PortRule {
+    ports: RangeInclusive<u16>,
+    protocol: SniffProtocol,
+    override_destination: bool,
+}
+
+/// Sniffer reads the domain of the TCP requests to IP addresses from the first bytes the client sends, the server
+/// name of a TLS ClientHello or the Host header of an HTTP request, so that they are routed by domain. Only the
+/// protocol configured for the destination port is tried, which bounds the cost of sniffing and keeps, say, an HTTP
+/// looking payload on a game port from being taken for a web request. The bytes read are replayed to the outbound.
+pub struct Sniffer {
+    rules: Vec<PortRule>,
+    timeout: Duration,
+}
+
+impl Sniffer {
+    /// Fails with InvalidInput on invalid port ranges.
+    pub fn new(config: Option<&SniffingConfig>) -> Result<Self> {
+        let rules = config
+            .map(|config| {
+                config
+                    .ports
+                    .iter()
+                    .map(|rule| {
+                        Ok(PortRule {
+                            ports: match &rule.port {
+                                PortConfig::Single(port) => *port..=*port,
+                                PortConfig::Range(range) => match parse_port_range(range) {
+                                    Some(range) => range,
+                                    None => {
+                                        return Err(Error::new(
+                                            ErrorKind::InvalidInput,
+                                            format!("invalid sniffing port range {}", range),
+                                        ))
+                                    }
+                                },
+                            },
+                            protocol: rule.protocol,
+                            override_destination: rule.override_destination.unwrap_or(true),
+                        })
+                    })
+                    .collect::<Result<_>>()
+            })
+            .transpose()?
+            .unwrap_or_default();
+
+        Ok(Self {
+            rules,
+            timeout: Duration::from_millis(
+                config
+                    .and_then(|config| config.timeout)
+                    .unwrap_or(DEFAULT_TIMEOUT),
+            ),
+        })
+    }
+
+    /// Sniff the domain of the request from the stream. With destination override the domain replaces the address
+    /// of the request, otherwise it is returned to be matched by the routing rules. The returned stream replays the
+    /// bytes read.
+    pub async fn sniff<T: AsyncRead + Unpin>(
+        &self,
+        request: &mut InboundRequest,
+        mut stream: T,
+    ) -> Result<(PrefixedStream<T>, Option<String>)> {
+        let rule = match &request.addr_port.ip {
+            IpAddress::IpAddr(_) if request.transport_protocol == TransportProtocol::TCP => self
+                .rules
+                .iter()
+                .find(|rule| rule.ports.contains(&request.addr_port.port)),
+            _ => None,
+        };
+        let rule = match rule {
+            Some(rule) if rule.protocol != SniffProtocol::NONE => rule,
+            _ => return Ok((PrefixedStream::new(Vec::new(), stream), None)),
+        };
+
+        let deadline = Instant::now() + self.timeout;
+        let mut buf = Vec::new();
+        let mut chunk = vec![0u8; 4096];
+        let domain = loop {
+            let sniff = match rule.protocol {
+                SniffProtocol::TLS => sniff_tls(&buf),
+                SniffProtocol::HTTP => sniff_http(&buf),
+                SniffProtocol::NONE => Sniff::Unknown,
+            };
+            match sniff {
+                Sniff::Domain(domain) => break Some(domain),
+                Sniff::Unknown => break None,
+                Sniff::Incomplete if buf.len() >= MAX_SNIFF_SIZE => break None,
+                Sniff::Incomplete => (),
+            }
+
+            match timeout_at(deadline, stream.read(&mut chunk)).await {
+                Ok(Ok(0)) | Err(_) => break None,
+                Ok(Ok(size)) => buf.extend_from_slice(&chunk[..size]),
+                Ok(Err(e)) => return Err(e),
+            }
+        };
+
+        let stream = PrefixedStream::new(buf, stream);
+        // Addresses in the server name or the Host header tell nothing the request doesn't
+        let domain = match domain {
+            Some(domain) if domain.parse::<IpAddr>().is_err() => domain,
+            _ => return Ok((stream, None)),
+        };
+
+        debug!(
+            "Sniffed {:?} domain {} of {}",
+            rule.protocol, domain, request.addr_port
+        );
+        let protocol = match rule.protocol {
+            SniffProtocol::TLS => "tls",
+            _ => "http",
+        };
+        metrics::increment(
+            &format!("sniffed_requests_total{{protocol=\"{}\"}}", protocol),
+            1,
+        );
+
+        if !rule.override_destination {
+            return Ok((stream, Some(domain)));
+        }
+        request.atype = Atype::DomainName;
+        request.addr_port = IpAddrPort::new(IpAddress::from_host(&domain), request.addr_port.port);
+        Ok((stream, None))
+    }
+}
+
+/// Server name of the TLS ClientHello the bytes start with.
+fn sniff_tls(buf: &[u8]) -> Sniff {
+    if buf.len() < RECORD_HEADER_SIZE {
+        return Sniff::Incomplete;
+    }
+    if buf[0] != CONTENT_TYPE_HANDSHAKE {
+        return Sniff::Unknown;
+    }
+
+    let length = u16::from_be_bytes([buf[3], buf[4]]) as usize;
+    if buf.len() < RECORD_HEADER_SIZE + length {
+        return Sniff::Incomplete;
+    }
+    match parse_server_name(&buf[RECORD_HEADER_SIZE..RECORD_HEADER_SIZE + length]) {
+        Some(server_name) => Sniff::Domain(server_name),
+        None => Sniff::Unknown,
+    }
+}
+
+/// Host header of the HTTP/1 request the bytes start with, without the port.
+fn sniff_http(buf: &[u8]) -> Sniff {
+    let head = match buf.windows(4).position(|window| window == b"\r\n\r\n") {
+        Some(end) => &buf[..end],
+        None => return Sniff::Incomplete,
+    };
+    let head = match std::str::from_utf8(head) {
+        Ok(head) => head,
+        Err(_) => return Sniff::Unknown,
+    };
+
+    let mut lines = head.split("\r\n");
+    match lines.next() {
+        Some(line) if line.ends_with(" HTTP/1.1") || line.ends_with(" HTTP/1.0") => (),
+        _ => return Sniff::Unknown,
+    }
+
+    for line in lines {
+        if let Some((name, value)) = line.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("host") {
+                let host = value.trim();
+                // Strip the port, unless the host is a bracketed IPv6 address without one
+                let host = match host.rsplit_once(':') {
+                    Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => host,
+                    _ => host,
+                };
+                let host = host.trim_start_matches('[').trim_end_matches(']');
+                return match host.is_empty() {
+                    true => Sniff::Unknown,
+                    false => Sniff::Domain(host.to_string()),
+                };
+            }
+        }
+    }
+    Sniff::Unknown
+}
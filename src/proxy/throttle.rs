@@ -0,0 +1,380 @@
+use crate::config::base::{BandwidthConfig, GlobalBandwidthConfig, InboundConfig};
+use crate::metrics;
+use crate::protocol::common::stream::IntoTcpStream;
+use crate::proxy::buffer::BufferPool;
+use crate::proxy::limiter::TokenBucket;
+
+use log::info;
+use once_cell::sync::OnceCell;
+use std::collections::HashMap;
+use std::future::Future;
+use std::io::{Error, ErrorKind, IoSlice, Result};
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio::time::Sleep;
+
+/// Bytes per second in a megabit per second
+const BYTES_PER_MBPS: u64 = 125_000;
+
+/// Static bandwidth shared by all the relays of the process
+static GLOBAL_BANDWIDTH: OnceCell<GlobalBandwidth> = OnceCell::new();
+
+/// Static bandwidth of the users of the inbound
+static USER_BANDWIDTH: OnceCell<UserBandwidth> = OnceCell::new();
+
+/// Token bucket shared by several streams, which take the bytes they move out of it in turn.
+pub struct SharedBucket {
+    bucket: Mutex<TokenBucket>,
+}
+
+impl SharedBucket {
+    /// Bucket of mbps megabits per second, allowing bursts of a second worth of data.
+    fn new(mbps: u64) -> Self {
+        let rate = mbps.saturating_mul(BYTES_PER_MBPS);
+        Self {
+            bucket: Mutex::new(TokenBucket::new(rate, rate)),
+        }
+    }
+
+    /// Take the bytes moved out of the bucket, returns how long to wait for it to pay them back.
+    fn reserve(&self, amount: usize) -> Duration {
+        self.bucket
+            .lock()
+            .unwrap()
+            .reserve(amount as u64, Duration::MAX)
+            .unwrap_or_default()
+    }
+}
+
+/// Bandwidth shared by all the relays of the process, which the bytes they send out to the clients and to the
+/// destinations alike are taken from. The relays going over it wait in turn for the bytes sent before them to be paid
+/// back, so that they all get their share of it.
+pub struct GlobalBandwidth {
+    bucket: SharedBucket,
+}
+
+impl GlobalBandwidth {
+    /// Start sharing the bandwidth of the config between the relays of the process.
+    pub fn init(config: &GlobalBandwidthConfig) -> Result<&'static Self> {
+        let bandwidth = Self::new(config)?;
+        Ok(GLOBAL_BANDWIDTH.get_or_init(|| {
+            info!("Relaying at most {} Mbps in total", config.mbps);
+            bandwidth
+        }))
+    }
+
+    /// Bandwidth shared by the relays of the process, None if it wasn't initialized.
+    #[inline]
+    pub fn get() -> Option<&'static Self> {
+        GLOBAL_BANDWIDTH.get()
+    }
+
+    /// Bandwidth of mbps megabits per second, allowing bursts of a second worth of data. Fails with InvalidInput if
+    /// mbps is 0.
+    pub fn new(config: &GlobalBandwidthConfig) -> Result<Self> {
+        if config.mbps == 0 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "The bandwidth of the process has to be at least 1 Mbps",
+            ));
+        }
+        Ok(Self {
+            bucket: SharedBucket::new(config.mbps),
+        })
+    }
+}
+
+/// Bandwidth of the users of the inbound by their bandwidth_class, shared by all the connections of each user. The
+/// users without a class, including the ones only known to the authentication backend, are unlimited.
+pub struct UserBandwidth {
+    users: HashMap<String, UserRate>,
+}
+
+/// Upload and download bandwidth of a user.
+pub struct UserRate {
+    upload: SharedBucket,
+    download: SharedBucket,
+}
+
+impl UserBandwidth {
+    /// Share the bandwidth of each user between their connections from now on.
+    pub fn init(inbound: &InboundConfig) -> Result<&'static Self> {
+        let bandwidth = Self::new(inbound)?;
+        Ok(USER_BANDWIDTH.get_or_init(|| bandwidth))
+    }
+
+    /// Bandwidth of the users, all of them unlimited if it wasn't initialized.
+    pub fn get() -> &'static Self {
+        USER_BANDWIDTH.get_or_init(|| Self {
+            users: HashMap::new(),
+        })
+    }
+
+    /// Fails with InvalidInput if a user refers to an unknown class or a class has a bandwidth of 0.
+    pub fn new(inbound: &InboundConfig) -> Result<Self> {
+        let classes = inbound.bandwidth_classes.as_ref();
+        let mut users = HashMap::new();
+
+        for user in inbound.users.iter().flatten() {
+            let name = match &user.bandwidth_class {
+                Some(name) => name,
+                None => continue,
+            };
+            let class = match classes.and_then(|classes| classes.get(name)) {
+                Some(class) => class,
+                None => {
+                    return Err(Error::new(
+                        ErrorKind::InvalidInput,
+                        format!("Unknown bandwidth class {} of user {}", name, user.name),
+                    ))
+                }
+            };
+
+            let mbps = match class.mbps {
+                Some(0) => {
+                    return Err(Error::new(
+                        ErrorKind::InvalidInput,
+                        format!("The bandwidth of class {} has to be at least 1 Mbps", name),
+                    ))
+                }
+                Some(mbps) => mbps,
+                None => continue,
+            };
+            users.insert(
+                user.name.clone(),
+                UserRate {
+                    upload: SharedBucket::new(mbps),
+                    download: SharedBucket::new(mbps),
+                },
+            );
+        }
+
+        if !users.is_empty() {
+            info!("Limiting the bandwidth of {} users", users.len());
+        }
+        Ok(Self { users })
+    }
+
+    /// Bandwidth of the user, None if it is unlimited.
+    #[inline]
+    pub fn user(&self, name: &str) -> Option<&UserRate> {
+        self.users.get(name)
+    }
+}
+
+/// Stream wrapper holding the bytes read from it to the upload rate and the bytes written to it to the download rate
+/// of the bandwidth config, each through a token bucket of its own, and to the bandwidth of the user and the global
+/// bandwidth if they are shared with the stream. The bytes over the rate are let through and paid back by waiting
+/// before the next read or write, and no read or write moves more than the burst at once. The time spent waiting is
+/// counted in bandwidth_throttled_ms_total.
+pub struct Throttled<T> {
+    inner: T,
+    upload: Option<Limit>,
+    download: Option<Limit>,
+}
+
+impl<T> Throttled<T> {
+    /// Wrap the stream, which is left unlimited without a config. The rates are positive, as checked by the parser.
+    pub fn new(inner: T, config: Option<&BandwidthConfig>) -> Self {
+        let limit = |rate: Option<u64>, direction| {
+            rate.map(|rate| Limit::new(Some(rate), config.and_then(|c| c.burst), direction))
+        };
+        Self {
+            inner,
+            upload: limit(config.and_then(|c| c.upload), "upload"),
+            download: limit(config.and_then(|c| c.download), "download"),
+        }
+    }
+
+    /// Hold both directions of the stream to the global bandwidth as well, if there is one.
+    pub fn with_global(self, global: Option<&'static GlobalBandwidth>) -> Self {
+        match global {
+            Some(global) => self.with_shared(&global.bucket, &global.bucket),
+            None => self,
+        }
+    }
+
+    /// Hold the stream to the bandwidth of the user as well, if the user has one.
+    pub fn with_user(self, user: Option<&'static UserRate>) -> Self {
+        match user {
+            Some(user) => self.with_shared(&user.upload, &user.download),
+            None => self,
+        }
+    }
+
+    fn with_shared(
+        mut self,
+        upload: &'static SharedBucket,
+        download: &'static SharedBucket,
+    ) -> Self {
+        for (limit, bucket, direction) in [
+            (&mut self.upload, upload, "upload"),
+            (&mut self.download, download, "download"),
+        ] {
+            limit
+                .get_or_insert_with(|| Limit::new(None, None, direction))
+                .shared
+                .push(bucket);
+        }
+        self
+    }
+
+    /// Whether either direction of the stream is limited.
+    #[inline]
+    pub fn is_limited(&self) -> bool {
+        self.upload.is_some() || self.download.is_some()
+    }
+}
+
+/// Rate of one direction of a throttled stream.
+struct Limit {
+    bucket: Option<TokenBucket>,
+    shared: Vec<&'static SharedBucket>,
+    burst: usize,
+    wait: Option<Pin<Box<Sleep>>>,
+    metric: &'static str,
+}
+
+impl Limit {
+    /// Limit of the rate, or only of the burst without one, the relay buffer size by default. A rate of 0, which the
+    /// parser rejects, would never pay anything back and is taken as 1.
+    fn new(rate: Option<u64>, burst: Option<u64>, direction: &str) -> Self {
+        let rate = rate.map(|rate| rate.max(1));
+        let burst = burst
+            .or(rate)
+            .unwrap_or(BufferPool::get().size() as u64)
+            .max(1);
+        Self {
+            bucket: rate.map(|rate| TokenBucket::new(rate, burst)),
+            shared: Vec::new(),
+            burst: burst.min(usize::MAX as u64) as usize,
+            wait: None,
+            metric: match direction {
+                "upload" => "bandwidth_throttled_ms_total{direction=\"upload\"}",
+                _ => "bandwidth_throttled_ms_total{direction=\"download\"}",
+            },
+        }
+    }
+
+    /// Wait until the bytes moved over the rate before are paid back.
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        if let Some(wait) = &mut self.wait {
+            ready!(wait.as_mut().poll(cx));
+            self.wait = None;
+        }
+        Poll::Ready(())
+    }
+
+    /// Take the bytes moved out of the buckets, going into debt if they lack them.
+    fn consume(&mut self, amount: usize) {
+        let own = self.bucket.as_mut().map_or(Duration::ZERO, |bucket| {
+            bucket
+                .reserve(amount as u64, Duration::MAX)
+                .unwrap_or_default()
+        });
+        let wait = self
+            .shared
+            .iter()
+            .map(|bucket| bucket.reserve(amount))
+            .fold(own, Duration::max);
+        if !wait.is_zero() {
+            metrics::increment(self.metric, wait.as_millis() as u64);
+            self.wait = Some(Box::pin(tokio::time::sleep(wait)));
+        }
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for Throttled<T> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<()>> {
+        let this = self.get_mut();
+        let limit = match &mut this.upload {
+            Some(limit) => limit,
+            None => return Pin::new(&mut this.inner).poll_read(cx, buf),
+        };
+        ready!(limit.poll_ready(cx));
+
+        let len = buf.remaining().min(limit.burst);
+        let mut limited = ReadBuf::new(buf.initialize_unfilled_to(len));
+        ready!(Pin::new(&mut this.inner).poll_read(cx, &mut limited))?;
+
+        let read = limited.filled().len();
+        buf.advance(read);
+        limit.consume(read);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for Throttled<T> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize>> {
+        let this = self.get_mut();
+        let limit = match &mut this.download {
+            Some(limit) => limit,
+            None => return Pin::new(&mut this.inner).poll_write(cx, buf),
+        };
+        ready!(limit.poll_ready(cx));
+
+        let len = buf.len().min(limit.burst);
+        let written = ready!(Pin::new(&mut this.inner).poll_write(cx, &buf[..len]))?;
+        limit.consume(written);
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<Result<usize>> {
+        match self.download {
+            Some(_) => {
+                let buf = bufs
+                    .iter()
+                    .find(|buf| !buf.is_empty())
+                    .map_or(&[][..], |buf| &**buf);
+                self.poll_write(cx, buf)
+            }
+            None => Pin::new(&mut self.get_mut().inner).poll_write_vectored(cx, bufs),
+        }
+    }
+
+    #[inline]
+    fn is_write_vectored(&self) -> bool {
+        self.download.is_none() && self.inner.is_write_vectored()
+    }
+
+    #[inline]
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    #[inline]
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+/// Unlimited streams give up their socket like the stream they wrap, limited ones never do, as the data relayed in the
+/// kernel can't be held to the rates.
+impl<T: IntoTcpStream> IntoTcpStream for Throttled<T> {
+    fn into_tcp_stream(self) -> std::result::Result<(TcpStream, Vec<u8>), Self> {
+        if self.is_limited() {
+            return Err(self);
+        }
+        self.inner.into_tcp_stream().map_err(|inner| Self {
+            inner,
+            upload: None,
+            download: None,
+        })
+    }
+
+    fn at_record_boundary(&self) -> bool {
+        !self.is_limited() && self.inner.at_record_boundary()
+    }
+}
@@ -0,0 +1,377 @@
+use crate::proxy::buffer::{Buffer, BufferPool};
+
+use log::debug;
+use std::io::Result;
+use std::net::SocketAddr;
+use std::sync::atomic::AtomicBool;
+#[cfg(target_os = "linux")]
+use std::sync::atomic::Ordering;
+use tokio::net::UdpSocket;
+
+/// Most datagrams sent by a single batch
+pub const BATCH_SIZE: usize = 32;
+
+/// Buffers of the datagrams read by a batch, reused from one batch to the next. On Linux the datagrams waiting on the
+/// socket are read with a single recvmmsg call, elsewhere one datagram is read at a time.
+pub struct RecvBatch {
+    bufs: Vec<Buffer>,
+    received: Vec<(usize, SocketAddr)>,
+}
+
+impl RecvBatch {
+    /// Buffers for up to count datagrams of size bytes each.
+    pub fn new(count: usize, size: usize) -> Self {
+        Self {
+            bufs: (0..count.max(1))
+                .map(|_| BufferPool::get().take_zeroed(size))
+                .collect(),
+            received: Vec::with_capacity(count.max(1)),
+        }
+    }
+
+    /// Wait for at least one datagram on the socket and read the ones available, up to the number of buffers.
+    pub async fn recv(&mut self, socket: &UdpSocket) -> Result<()> {
+        #[cfg(target_os = "linux")]
+        loop {
+            socket.readable().await?;
+            let (bufs, received) = (&mut self.bufs, &mut self.received);
+            match socket.try_io(tokio::io::Interest::READABLE, || {
+                linux::recvmmsg(socket, bufs, received)
+            }) {
+                Ok(()) => return Ok(()),
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => continue,
+                Err(e) => return Err(e),
+            }
+        }
+
+        #[cfg(not(target_os = "linux"))]
+        {
+            let datagram = socket.recv_from(&mut self.bufs[0]).await?;
+            self.received.clear();
+            self.received.push(datagram);
+            Ok(())
+        }
+    }
+
+    /// Datagrams read by the last batch along with their sources.
+    pub fn iter(&self) -> impl Iterator<Item = (&[u8], SocketAddr)> {
+        self.received
+            .iter()
+            .zip(&self.bufs)
+            .map(|((size, source), buf)| (&buf[..*size], *source))
+    }
+}
+
+/// Send the datagrams out of the socket, with as few sendmmsg calls as possible on Linux and one send_to per datagram
+/// elsewhere. Where the kernel supports UDP segmentation offload, consecutive datagrams of the same size to the same
+/// destination go down the stack as a single message that is split into datagrams at the last moment, by the network
+/// card if it can. Segmentation is given up for the socket once the kernel refuses a segmented message, as the path of
+/// the socket can't carry it, and for the whole process once a network card fails one. The datagrams of the refused
+/// message are sent again one at a time. A datagram that fails to send is skipped, like the rest of the UDP relay does.
+#[cfg_attr(not(target_os = "linux"), allow(unused_variables))]
+pub async fn send_batch(
+    socket: &UdpSocket,
+    datagrams: &[(&[u8], SocketAddr)],
+    segmentation: &AtomicBool,
+) -> Result<()> {
+    #[cfg(target_os = "linux")]
+    {
+        let gso = linux::gso_enabled() && segmentation.load(Ordering::Relaxed);
+        let mut messages = linux::segment(datagrams, gso);
+        let mut sent = 0;
+        while sent < messages.len() {
+            socket.writable().await?;
+            match socket.try_io(tokio::io::Interest::WRITABLE, || {
+                linux::sendmmsg(socket, &messages[sent..])
+            }) {
+                Ok(count) => sent += count,
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => continue,
+                // Network cards without checksum offload fail segmented messages, send them one datagram at a time
+                Err(e) if e.raw_os_error() == Some(libc::EIO) && messages[sent].len() > 1 => {
+                    linux::disable_gso();
+                    let message = messages[sent];
+                    messages.splice(sent..=sent, message.chunks(1));
+                }
+                // Segments larger than the path allows, or more of them than the kernel takes
+                Err(e)
+                    if matches!(e.raw_os_error(), Some(libc::EINVAL | libc::EMSGSIZE))
+                        && messages[sent].len() > 1 =>
+                {
+                    if segmentation.swap(false, Ordering::Relaxed) {
+                        debug!(
+                            "Kernel refused segmented UDP message to {}, sending one datagram at a time: {}",
+                            messages[sent][0].1, e
+                        );
+                    }
+                    let message = messages[sent];
+                    messages.splice(sent..=sent, message.chunks(1));
+                }
+                Err(e) => {
+                    debug!(
+                        "Failed to send UDP datagram to {}: {}",
+                        messages[sent][0].1, e
+                    );
+                    sent += 1;
+                }
+            }
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    for (payload, dest) in datagrams {
+        if let Err(e) = socket.send_to(payload, dest).await {
+            debug!("Failed to send UDP datagram to {}: {}", dest, e);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use crate::proxy::buffer::Buffer;
+
+    use log::warn;
+    use once_cell::sync::Lazy;
+    use socket2::SockAddr;
+    use std::io::{Error, ErrorKind, Result};
+    use std::mem;
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6};
+    use std::os::unix::io::AsRawFd;
+    use std::ptr;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use tokio::net::UdpSocket;
+
+    /// Most datagrams the kernel accepts in a segmented message, UDP_MAX_SEGMENTS in linux/udp.h
+    const MAX_SEGMENTS: usize = 64;
+
+    /// Most bytes of a segmented message, the largest UDP payload
+    const MAX_SEGMENTED_SIZE: usize = 65507;
+
+    /// Largest datagram sent as a segment, the payload filling a 1500 byte Ethernet frame over IPv6. Larger datagrams
+    /// are sent on their own, as segments above the MTU of the path are refused by the kernel.
+    const MAX_SEGMENT_SIZE: usize = 1452;
+
+    /// Whether the kernel accepts segmented messages, until a network card fails one
+    static GSO: Lazy<AtomicBool> = Lazy::new(|| AtomicBool::new(probe_gso()));
+
+    /// Check the kernel knows UDP_SEGMENT by setting it on a throwaway socket.
+    fn probe_gso() -> bool {
+        let socket = match std::net::UdpSocket::bind("127.0.0.1:0") {
+            Ok(socket) => socket,
+            Err(_) => return false,
+        };
+        let size: libc::c_int = 1500;
+        // Safety: the option value is a c_int that outlives the call
+        let result = unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                libc::SOL_UDP,
+                libc::UDP_SEGMENT,
+                &size as *const _ as *const libc::c_void,
+                mem::size_of_val(&size) as libc::socklen_t,
+            )
+        };
+        result == 0
+    }
+
+    pub fn gso_enabled() -> bool {
+        GSO.load(Ordering::Relaxed)
+    }
+
+    pub fn disable_gso() {
+        if GSO.swap(false, Ordering::Relaxed) {
+            warn!(
+                "Network card failed a segmented UDP message, disabling UDP segmentation offload"
+            );
+        }
+    }
+
+    /// Split the datagrams into the messages they are sent as. With segmentation, a message holds consecutive
+    /// datagrams to the same destination that are all as large as the first one, except for the last which may be
+    /// smaller, up to MAX_SEGMENTS datagrams and MAX_SEGMENTED_SIZE bytes. Datagrams larger than MAX_SEGMENT_SIZE and
+    /// all datagrams without segmentation are messages of their own.
+    pub fn segment<'a, 'b>(
+        datagrams: &'a [(&'b [u8], SocketAddr)],
+        gso: bool,
+    ) -> Vec<&'a [(&'b [u8], SocketAddr)]> {
+        let mut messages = Vec::with_capacity(datagrams.len());
+        let mut start = 0;
+        while start < datagrams.len() {
+            let (first, dest) = datagrams[start];
+            let mut end = start + 1;
+            let mut size = first.len();
+            let segmented = gso && !first.is_empty() && first.len() <= MAX_SEGMENT_SIZE;
+            while segmented && end < datagrams.len() && end - start < MAX_SEGMENTS {
+                let (payload, next) = datagrams[end];
+                if next != dest
+                    || payload.len() > first.len()
+                    || size + payload.len() > MAX_SEGMENTED_SIZE
+                {
+                    break;
+                }
+                size += payload.len();
+                end += 1;
+                if payload.len() < first.len() {
+                    break;
+                }
+            }
+            messages.push(&datagrams[start..end]);
+            start = end;
+        }
+        messages
+    }
+
+    fn header(
+        name: *mut libc::c_void,
+        name_len: u32,
+        iov: *mut libc::iovec,
+        iov_len: usize,
+    ) -> libc::mmsghdr {
+        // Safety: msghdr is plain data, all zeros is an empty header
+        let mut header: libc::msghdr = unsafe { mem::zeroed() };
+        header.msg_name = name;
+        header.msg_namelen = name_len;
+        header.msg_iov = iov;
+        header.msg_iovlen = iov_len as _;
+        libc::mmsghdr {
+            msg_hdr: header,
+            msg_len: 0,
+        }
+    }
+
+    /// Read the datagrams waiting on the socket into the buffers, fails with WouldBlock if there are none.
+    pub fn recvmmsg(
+        socket: &UdpSocket,
+        bufs: &mut [Buffer],
+        received: &mut Vec<(usize, SocketAddr)>,
+    ) -> Result<()> {
+        // Safety: sockaddr_storage is plain data, all zeros is an empty address
+        let mut names: Vec<libc::sockaddr_storage> = vec![unsafe { mem::zeroed() }; bufs.len()];
+        let mut iovs: Vec<libc::iovec> = bufs
+            .iter_mut()
+            .map(|buf| libc::iovec {
+                iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+                iov_len: buf.len(),
+            })
+            .collect();
+        let mut headers: Vec<libc::mmsghdr> = names
+            .iter_mut()
+            .zip(iovs.iter_mut())
+            .map(|(name, iov)| {
+                header(
+                    name as *mut _ as *mut libc::c_void,
+                    mem::size_of::<libc::sockaddr_storage>() as u32,
+                    iov,
+                    1,
+                )
+            })
+            .collect();
+
+        // Safety: the headers point to the names and the buffers, which outlive the call
+        let count = unsafe {
+            libc::recvmmsg(
+                socket.as_raw_fd(),
+                headers.as_mut_ptr(),
+                headers.len() as libc::c_uint,
+                0,
+                ptr::null_mut(),
+            )
+        };
+        if count < 0 {
+            return Err(Error::last_os_error());
+        }
+
+        received.clear();
+        for (header, name) in headers.iter().zip(&names).take(count as usize) {
+            received.push((header.msg_len as usize, socket_addr(name)?));
+        }
+        Ok(())
+    }
+
+    /// Send the messages with a single call, returns how many of them were sent. The datagrams of a message after
+    /// the first are sent as segments of it.
+    pub fn sendmmsg(socket: &UdpSocket, messages: &[&[(&[u8], SocketAddr)]]) -> Result<usize> {
+        let names: Vec<SockAddr> = messages.iter().map(|message| message[0].1.into()).collect();
+        let mut iovs: Vec<libc::iovec> = messages
+            .iter()
+            .flat_map(|message| message.iter())
+            .map(|(payload, _)| libc::iovec {
+                iov_base: payload.as_ptr() as *mut libc::c_void,
+                iov_len: payload.len(),
+            })
+            .collect();
+        // Room for a control message carrying the segment size, aligned for its header
+        let mut controls: Vec<[u64; 4]> = vec![[0; 4]; messages.len()];
+
+        let mut headers = Vec::with_capacity(messages.len());
+        let mut offset = 0;
+        for ((message, name), control) in messages.iter().zip(&names).zip(controls.iter_mut()) {
+            let mut header = header(
+                name.as_ptr() as *mut libc::c_void,
+                name.len(),
+                iovs[offset..].as_mut_ptr(),
+                message.len(),
+            );
+            offset += message.len();
+
+            if message.len() > 1 {
+                let segment_size = message[0].0.len() as u16;
+                header.msg_hdr.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+                // Safety: the control buffer is large enough for a u16 control message and outlives the call
+                unsafe {
+                    header.msg_hdr.msg_controllen =
+                        libc::CMSG_SPACE(mem::size_of::<u16>() as u32) as _;
+                    let cmsg = libc::CMSG_FIRSTHDR(&header.msg_hdr);
+                    (*cmsg).cmsg_level = libc::SOL_UDP;
+                    (*cmsg).cmsg_type = libc::UDP_SEGMENT;
+                    (*cmsg).cmsg_len = libc::CMSG_LEN(mem::size_of::<u16>() as u32) as _;
+                    ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut u16, segment_size);
+                }
+            }
+            headers.push(header);
+        }
+
+        // Safety: the headers point to the names, the payloads and the control messages, which outlive the call, and
+        // are only read
+        let count = unsafe {
+            libc::sendmmsg(
+                socket.as_raw_fd(),
+                headers.as_mut_ptr(),
+                headers.len() as libc::c_uint,
+                0,
+            )
+        };
+        match count {
+            count if count < 0 => Err(Error::last_os_error()),
+            count => Ok(count as usize),
+        }
+    }
+
+    fn socket_addr(name: &libc::sockaddr_storage) -> Result<SocketAddr> {
+        match name.ss_family as libc::c_int {
+            libc::AF_INET => {
+                // Safety: the family says the storage holds an IPv4 address
+                let addr = unsafe { &*(name as *const _ as *const libc::sockaddr_in) };
+                Ok(SocketAddr::new(
+                    IpAddr::V4(Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr))),
+                    u16::from_be(addr.sin_port),
+                ))
+            }
+            libc::AF_INET6 => {
+                // Safety: the family says the storage holds an IPv6 address
+                let addr = unsafe { &*(name as *const _ as *const libc::sockaddr_in6) };
+                Ok(SocketAddr::V6(SocketAddrV6::new(
+                    Ipv6Addr::from(addr.sin6_addr.s6_addr),
+                    u16::from_be(addr.sin6_port),
+                    addr.sin6_flowinfo,
+                    addr.sin6_scope_id,
+                )))
+            }
+            family => Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("unknown address family {} of UDP datagram", family),
+            )),
+        }
+    }
+}
@@ -0,0 +1,119 @@
+use crate::config::base::{DomainStrategy, UdpConfig};
+use crate::router::matcher::parse_port_range;
+
+use log::debug;
+use socket2::SockRef;
+use std::io::{Error, ErrorKind, Result};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::ops::RangeInclusive;
+use std::sync::atomic::{AtomicU32, Ordering};
+use tokio::net::UdpSocket;
+
+/// Binds the sockets the UDP sessions of an outbound send their datagrams from. By default they are bound on the
+/// unspecified address with a port picked by the system. Hosts with several addresses can pick the one the datagrams
+/// leave from, and hosts whose firewall only lets a range of ports out can keep the sockets within it, in which case
+/// the ports are handed out in turn, skipping the ones in use. The socket buffers can be enlarged for bursty traffic
+/// like video calls, which overflows the kernel defaults and loses datagrams. With broadcast, the sockets are allowed
+/// to send to broadcast addresses.
+pub struct UdpBinder {
+    ip: Option<IpAddr>,
+    ports: Option<RangeInclusive<u16>>,
+    next: AtomicU32,
+    recv_buffer_size: Option<usize>,
+    send_buffer_size: Option<usize>,
+    broadcast: bool,
+}
+
+impl UdpBinder {
+    /// Fails with InvalidInput if the bind address or the port range of the configuration is invalid.
+    pub fn new(config: Option<&UdpConfig>) -> Result<Self> {
+        let ip = config
+            .and_then(|config| config.bind_address.as_ref())
+            .map(|address| {
+                address.parse().map_err(|e| {
+                    Error::new(
+                        ErrorKind::InvalidInput,
+                        format!("invalid UDP bind address {}: {}", address, e),
+                    )
+                })
+            })
+            .transpose()?;
+        let ports = config
+            .and_then(|config| config.port_range.as_ref())
+            .map(|range| match parse_port_range(range) {
+                Some(ports) if *ports.start() > 0 => Ok(ports),
+                _ => Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("invalid UDP port range {}", range),
+                )),
+            })
+            .transpose()?;
+
+        Ok(Self {
+            ip,
+            ports,
+            next: AtomicU32::new(0),
+            recv_buffer_size: config.and_then(|config| config.recv_buffer_size),
+            send_buffer_size: config.and_then(|config| config.send_buffer_size),
+            broadcast: config.and_then(|config| config.broadcast).unwrap_or(false),
+        })
+    }
+
+    /// Bind a socket for a new session. Without a bind address, the sessions resolving their destinations to IPv6
+    /// first get a dual stack socket.
+    pub async fn bind(&self, strategy: DomainStrategy) -> Result<UdpSocket> {
+        let socket = self.bind_port(strategy).await?;
+
+        let socket_ref = SockRef::from(&socket);
+        if let Some(size) = self.recv_buffer_size {
+            socket_ref.set_recv_buffer_size(size)?;
+        }
+        if let Some(size) = self.send_buffer_size {
+            socket_ref.set_send_buffer_size(size)?;
+        }
+        if self.broadcast {
+            socket.set_broadcast(true)?;
+        }
+        Ok(socket)
+    }
+
+    async fn bind_port(&self, strategy: DomainStrategy) -> Result<UdpSocket> {
+        let ip = self.ip.unwrap_or(match strategy {
+            DomainStrategy::PREFER_IPV6 | DomainStrategy::IPV6_ONLY => {
+                IpAddr::V6(Ipv6Addr::UNSPECIFIED)
+            }
+            _ => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        });
+
+        let ports = match &self.ports {
+            Some(ports) => ports,
+            None => return UdpSocket::bind(SocketAddr::new(ip, 0)).await,
+        };
+
+        // Start after the port handed out last, so that the sessions don't all probe the same busy ports
+        let size = (*ports.end() - *ports.start()) as u32 + 1;
+        let first = self.next.fetch_add(1, Ordering::Relaxed);
+        for offset in 0..size {
+            let port = *ports.start() + (first.wrapping_add(offset) % size) as u16;
+            match UdpSocket::bind(SocketAddr::new(ip, port)).await {
+                Ok(socket) => {
+                    self.next
+                        .store(first.wrapping_add(offset + 1), Ordering::Relaxed);
+                    return Ok(socket);
+                }
+                Err(e) if e.kind() == ErrorKind::AddrInUse => continue,
+                Err(e) => return Err(e),
+            }
+        }
+
+        debug!("All the UDP ports in {:?} are in use", ports);
+        Err(Error::new(
+            ErrorKind::AddrInUse,
+            format!(
+                "no free UDP port between {} and {}",
+                ports.start(),
+                ports.end()
+            ),
+        ))
+    }
+}
@@ -0,0 +1,201 @@
+use crate::auth::port::PortAuthorizer;
+use crate::config::base::{DomainStrategy, NatBehavior, UdpConfig};
+use crate::metrics;
+use crate::proxy::destination::DestinationFilter;
+use crate::proxy::filter::IpFilter;
+use crate::proxy::limiter::TokenBucket;
+use crate::proxy::relay::Activity;
+
+use log::debug;
+use std::collections::HashSet;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::AtomicBool;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Maximum number of distinct destinations a single UDP session may talk to
+const MAX_PEERS: usize = 256;
+
+/// UdpGuard keeps the UDP relay of a single authenticated session from being abused as an amplification reflector.
+/// Replies are only relayed back to the client if the NAT behavior of the session accepts their source, by default
+/// a destination that the client has sent datagrams to, and the reply bytes are rate limited if the limit is
+/// configured. Datagrams to broadcast and multicast addresses are dropped unless broadcast is enabled, in which case
+/// the replies to them are accepted from any host of the local network, as LAN discovery protocols like SSDP are
+/// answered by the devices from their own addresses. The guard also tracks the activity of the session, so that idle
+/// sessions can be closed, and carries how the destinations of the session are resolved, how large its packets can be
+/// and whether its socket can send segmented messages.
+pub struct UdpGuard {
+    peers: Mutex<HashSet<SocketAddr>>,
+    nat: NatBehavior,
+    broadcast: bool,
+    limiter: Option<Mutex<TokenBucket>>,
+    max_packet_size: Option<usize>,
+    activity: Activity,
+    domain_strategy: DomainStrategy,
+    destinations: Option<Arc<IpFilter>>,
+    segmentation: AtomicBool,
+}
+
+impl UdpGuard {
+    pub fn new(config: Option<&UdpConfig>) -> Self {
+        let limiter = config
+            .and_then(|cfg| cfg.reply_rate_limit.map(|rate| (rate, cfg.reply_burst)))
+            .map(|(rate, burst)| Mutex::new(TokenBucket::new(rate, burst.unwrap_or(rate))));
+
+        Self {
+            peers: Mutex::new(HashSet::new()),
+            nat: config
+                .and_then(|cfg| cfg.nat)
+                .unwrap_or(NatBehavior::PORT_RESTRICTED_CONE),
+            broadcast: config.and_then(|cfg| cfg.broadcast).unwrap_or(false),
+            limiter,
+            max_packet_size: config.and_then(|cfg| cfg.max_packet_size),
+            activity: Activity::new(),
+            domain_strategy: DomainStrategy::AS_IS,
+            destinations: None,
+            segmentation: AtomicBool::new(true),
+        }
+    }
+
+    /// Resolve the domain names the client sends datagrams to according to the strategy.
+    pub fn with_domain_strategy(mut self, strategy: DomainStrategy) -> Self {
+        self.domain_strategy = strategy;
+        self
+    }
+
+    /// Only send datagrams to the destinations passing the filter.
+    pub fn with_destinations(mut self, destinations: Option<Arc<IpFilter>>) -> Self {
+        self.destinations = destinations;
+        self
+    }
+
+    #[inline]
+    pub fn domain_strategy(&self) -> DomainStrategy {
+        self.domain_strategy
+    }
+
+    /// Whether the session can send datagrams to broadcast and multicast addresses.
+    #[inline]
+    pub fn broadcast(&self) -> bool {
+        self.broadcast
+    }
+
+    /// Whether the socket of the session may send segmented messages, cleared once the kernel refuses one.
+    #[inline]
+    pub fn segmentation(&self) -> &AtomicBool {
+        &self.segmentation
+    }
+
+    /// Whether a trojan UDP packet of the size can be relayed by the session.
+    #[inline]
+    pub fn fits(&self, packet_size: usize) -> bool {
+        match self.max_packet_size {
+            Some(max_packet_size) => packet_size <= max_packet_size,
+            None => true,
+        }
+    }
+
+    /// Most bytes of a trojan UDP packet relayed by the session, header included, if capped.
+    #[inline]
+    pub fn max_packet_size(&self) -> Option<usize> {
+        self.max_packet_size
+    }
+
+    /// Register the destination the client is sending a datagram to, returns false if the session has reached the
+    /// maximum number of destinations and the datagram should be dropped.
+    pub fn register(&self, dest: SocketAddr) -> bool {
+        if !self.broadcast && is_discovery(dest.ip()) {
+            debug!("Dropping UDP datagram to broadcast address {}", dest);
+            metrics::increment("udp_requests_dropped_total{reason=\"broadcast\"}", 1);
+            return false;
+        }
+
+        if !DestinationFilter::get().allows(dest.ip()) {
+            debug!("Dropping UDP datagram to private destination {}", dest);
+            metrics::increment("destinations_blocked_total{transport=\"udp\"}", 1);
+            return false;
+        }
+        if let Some(destinations) = &self.destinations {
+            if !destinations.allows(dest.ip()) {
+                return false;
+            }
+        }
+        if let Some(ports) = PortAuthorizer::get() {
+            if ports.check(dest.port(), "udp").is_err() {
+                return false;
+            }
+        }
+
+        let mut peers = self.peers.lock().unwrap();
+
+        if peers.len() >= MAX_PEERS && !peers.contains(&dest) {
+            metrics::increment("udp_requests_dropped_total{reason=\"peer_limit\"}", 1);
+            return false;
+        }
+
+        peers.insert(dest);
+        self.activity.touch();
+        true
+    }
+
+    /// Check if the reply from source with size bytes can be relayed back to the client.
+    pub fn check_reply(&self, source: SocketAddr, size: usize) -> bool {
+        let known = {
+            let peers = self.peers.lock().unwrap();
+            let known = match self.nat {
+                NatBehavior::FULL_CONE => !peers.is_empty(),
+                NatBehavior::RESTRICTED_CONE => peers.iter().any(|peer| peer.ip() == source.ip()),
+                NatBehavior::PORT_RESTRICTED_CONE => peers.contains(&source),
+            };
+            known
+                || (self.broadcast
+                    && is_local(source.ip())
+                    && peers.iter().any(|peer| is_discovery(peer.ip())))
+        };
+        if !known {
+            debug!("Dropping UDP reply from unknown peer {}", source);
+            metrics::increment("udp_replies_dropped_total{reason=\"unknown_peer\"}", 1);
+            return false;
+        }
+
+        if let Some(limiter) = &self.limiter {
+            if !limiter.lock().unwrap().try_acquire(size as u64) {
+                debug!("Dropping UDP reply from {}, rate limit exceeded", source);
+                metrics::increment("udp_replies_dropped_total{reason=\"rate_limit\"}", 1);
+                return false;
+            }
+        }
+
+        self.activity.touch();
+        true
+    }
+
+    /// Wait until the client and its peers haven't exchanged a datagram for the ttl.
+    pub async fn idle(&self, ttl: Duration) {
+        self.activity.idle(ttl).await
+    }
+}
+
+/// Whether datagrams to the address reach several hosts, the limited broadcast address, multicast groups and the
+/// broadcast addresses of private /24 networks, the usual subnet of home networks.
+fn is_discovery(ip: IpAddr) -> bool {
+    match ip.to_canonical() {
+        IpAddr::V4(ip) => {
+            ip.is_broadcast() || ip.is_multicast() || (ip.is_private() && ip.octets()[3] == 255)
+        }
+        IpAddr::V6(ip) => ip.is_multicast(),
+    }
+}
+
+/// Whether the address belongs to a host of the local network.
+fn is_local(ip: IpAddr) -> bool {
+    match ip.to_canonical() {
+        IpAddr::V4(ip) => ip.is_private() || ip.is_link_local() || ip.is_loopback(),
+        IpAddr::V6(ip) => {
+            // Unique local fc00::/7 and link local fe80::/10
+            ip.is_loopback()
+                || (ip.segments()[0] & 0xfe00) == 0xfc00
+                || (ip.segments()[0] & 0xffc0) == 0xfe80
+        }
+    }
+}
@@ -0,0 +1,5 @@
+pub mod batch;
+pub mod bind;
+pub mod guard;
+pub mod sessions;
+pub mod worker;
@@ -0,0 +1,119 @@
+use crate::metrics;
+
+use log::debug;
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Result};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// UDP session relayed by an outbound, identified by the local address of its socket.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UdpSessionInfo {
+    pub id: u64,
+    pub local: SocketAddr,
+    pub age: Duration,
+}
+
+/// Table of the UDP sessions relayed by an outbound. Sessions are added when their socket is bound and removed when
+/// the returned handle is dropped, which happens once the client leaves or the session is idle for the UDP session
+/// TTL of the policy. New sessions are refused once the table holds max_sessions of them, so that a client opening
+/// sessions in a loop can't run the server out of sockets. The number of live sessions is exported as the
+/// udp_sessions_active gauge labelled with the outbound.
+pub struct UdpSessions {
+    gauge: String,
+    max_sessions: Option<usize>,
+    sessions: Mutex<HashMap<u64, (SocketAddr, Instant)>>,
+    next_id: AtomicU64,
+}
+
+impl UdpSessions {
+    pub fn new(outbound: &str, max_sessions: Option<usize>) -> Self {
+        Self {
+            gauge: format!(
+                "udp_sessions_active{{outbound=\"{}\"}}",
+                metrics::escape_label(outbound)
+            ),
+            max_sessions,
+            sessions: Mutex::new(HashMap::new()),
+            next_id: AtomicU64::new(1),
+        }
+    }
+
+    /// Add the session of the socket bound on the local address, fails if the table is full.
+    pub fn open(&self, local: SocketAddr) -> Result<UdpSession<'_>> {
+        let mut sessions = self.sessions.lock().unwrap();
+        if let Some(max_sessions) = self.max_sessions {
+            if sessions.len() >= max_sessions {
+                debug!(
+                    "Refusing UDP session on {}, {} are open",
+                    local, max_sessions
+                );
+                metrics::increment("udp_sessions_refused_total", 1);
+                return Err(Error::new(
+                    ErrorKind::ConnectionRefused,
+                    format!("limit of {} UDP sessions reached", max_sessions),
+                ));
+            }
+        }
+
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        sessions.insert(id, (local, Instant::now()));
+        metrics::set(&self.gauge, sessions.len() as u64);
+
+        Ok(UdpSession { sessions: self, id })
+    }
+
+    /// Number of live sessions.
+    pub fn len(&self) -> usize {
+        self.sessions.lock().unwrap().len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Live sessions, oldest first.
+    pub fn list(&self) -> Vec<UdpSessionInfo> {
+        let mut sessions: Vec<UdpSessionInfo> = self
+            .sessions
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, (local, started))| UdpSessionInfo {
+                id: *id,
+                local: *local,
+                age: started.elapsed(),
+            })
+            .collect();
+        sessions.sort_by_key(|session| session.id);
+        sessions
+    }
+
+    fn close(&self, id: u64) {
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.remove(&id);
+        metrics::set(&self.gauge, sessions.len() as u64);
+    }
+}
+
+/// Entry of a live session in the table, removed when dropped.
+pub struct UdpSession<'a> {
+    sessions: &'a UdpSessions,
+    id: u64,
+}
+
+impl UdpSession<'_> {
+    #[inline]
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+}
+
+impl Drop for UdpSession<'_> {
+    fn drop(&mut self) {
+        self.sessions.close(self.id);
+    }
+}
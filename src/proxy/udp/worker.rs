@@ -0,0 +1,179 @@
+use crate::metrics;
+use crate::protocol::common::addr::IpAddrPort;
+use crate::proxy::buffer::Buffer;
+use crate::proxy::udp::batch::{send_batch, BATCH_SIZE};
+use crate::proxy::udp::guard::UdpGuard;
+
+use log::debug;
+use once_cell::sync::OnceCell;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::io::{Error, ErrorKind, Result};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc::{self, Receiver, Sender};
+
+/// Number of datagrams each worker can have waiting before the sessions feeding it are slowed down
+const QUEUE_SIZE: usize = 1024;
+
+/// Static lifetime worker pool shared by all the UDP sessions
+static UDP_WORKERS: OnceCell<UdpWorkers> = OnceCell::new();
+
+/// Datagram from the client waiting to be sent to its resolved destination
+struct Datagram {
+    socket: Arc<UdpSocket>,
+    guard: Arc<UdpGuard>,
+    dest: SocketAddr,
+    payload: Buffer,
+}
+
+/// Pool of tasks sending the client datagrams out. Datagrams of the same flow, the same session and destination,
+/// always go to the same worker so their order is kept, while different flows are spread over the workers so that a
+/// busy flow doesn't hold up the others. The destinations are resolved by the sessions before their datagrams are
+/// queued, so that a slow DNS lookup only holds up the session waiting for it.
+pub struct UdpWorkers {
+    queues: Vec<Sender<Datagram>>,
+}
+
+impl UdpWorkers {
+    /// Get the worker pool shared by the whole process, started on first use with one worker per CPU.
+    pub fn init() -> &'static Self {
+        UDP_WORKERS.get_or_init(|| {
+            let workers = std::thread::available_parallelism()
+                .map(|n| n.get())
+                .unwrap_or(1);
+            Self::new(workers)
+        })
+    }
+
+    /// Spawn the workers, has to be called within the tokio runtime.
+    pub fn new(workers: usize) -> Self {
+        let queues = (0..workers.max(1))
+            .map(|index| {
+                let (sender, receiver) = mpsc::channel(QUEUE_SIZE);
+                tokio::spawn(run_worker(index, receiver));
+                sender
+            })
+            .collect();
+
+        Self { queues }
+    }
+
+    /// Number of workers in the pool.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.queues.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.queues.is_empty()
+    }
+
+    /// Index of the worker handling the flow from the session, identified by the local address of its socket, to
+    /// the destination.
+    pub fn worker_of(&self, session: SocketAddr, dest: &IpAddrPort) -> usize {
+        let mut hasher = DefaultHasher::new();
+        session.hash(&mut hasher);
+        dest.hash(&mut hasher);
+        (hasher.finish() % self.queues.len() as u64) as usize
+    }
+
+    /// Resolve the destination of the datagram and queue it to be sent from the socket of the session, waits if the
+    /// queue of the worker is full. The session is the local address of the socket. Datagrams whose destination can't
+    /// be resolved or isn't allowed by the guard are dropped.
+    pub async fn send(
+        &self,
+        session: SocketAddr,
+        socket: &Arc<UdpSocket>,
+        guard: &Arc<UdpGuard>,
+        dest: IpAddrPort,
+        payload: impl Into<Buffer>,
+    ) -> Result<()> {
+        let index = self.worker_of(session, &dest);
+        let queue = &self.queues[index];
+
+        let dest = match prepare(socket, guard, &dest).await {
+            Some(dest) => dest,
+            None => return Ok(()),
+        };
+        let datagram = Datagram {
+            socket: socket.clone(),
+            guard: guard.clone(),
+            dest,
+            payload: payload.into(),
+        };
+        if queue.send(datagram).await.is_err() {
+            return Err(Error::new(ErrorKind::BrokenPipe, "UDP worker has stopped"));
+        }
+
+        metrics::set(
+            &format!("udp_worker_queue_depth{{worker=\"{}\"}}", index),
+            (QUEUE_SIZE - queue.capacity()) as u64,
+        );
+
+        Ok(())
+    }
+}
+
+/// Take the datagrams off the queue a batch at a time, so that the datagrams of a busy session are sent together.
+async fn run_worker(index: usize, mut queue: Receiver<Datagram>) {
+    let processed = format!("udp_worker_datagrams_total{{worker=\"{}\"}}", index);
+
+    while let Some(datagram) = queue.recv().await {
+        let mut datagrams = vec![datagram];
+        while datagrams.len() < BATCH_SIZE {
+            match queue.try_recv() {
+                Ok(datagram) => datagrams.push(datagram),
+                Err(_) => break,
+            }
+        }
+        metrics::increment(&processed, datagrams.len() as u64);
+
+        // Consecutive datagrams of the same session go out of its socket in one batch
+        let mut start = 0;
+        while start < datagrams.len() {
+            let (socket, guard) = (&datagrams[start].socket, &datagrams[start].guard);
+            let end = datagrams[start..]
+                .iter()
+                .position(|datagram| !Arc::ptr_eq(&datagram.socket, socket))
+                .map_or(datagrams.len(), |count| start + count);
+
+            let batch: Vec<(&[u8], SocketAddr)> = datagrams[start..end]
+                .iter()
+                .map(|datagram| (datagram.payload.as_slice(), datagram.dest))
+                .collect();
+            if let Err(e) = send_batch(socket, &batch, guard.segmentation()).await {
+                debug!("Failed to send UDP datagrams: {}", e);
+            }
+            start = end;
+        }
+    }
+}
+
+/// Resolve the destination of a datagram of the session and register it with the guard of the session, None if the
+/// datagram is dropped.
+async fn prepare(socket: &UdpSocket, guard: &UdpGuard, dest: &IpAddrPort) -> Option<SocketAddr> {
+    let strategy = guard.domain_strategy();
+    let resolved = match dest.resolve_with(strategy).await {
+        Ok(resolved) => resolved,
+        Err(e) => {
+            debug!("Failed to resolve UDP destination {}: {}", dest, e);
+            return None;
+        }
+    };
+
+    // Sockets of the sessions preferring IPv6 are dual stack, IPv4 destinations are reached at the mapped addresses
+    let dest = match (resolved, socket.local_addr()) {
+        (SocketAddr::V4(v4), Ok(SocketAddr::V6(_))) => {
+            SocketAddr::new(IpAddr::V6(v4.ip().to_ipv6_mapped()), v4.port())
+        }
+        (dest, _) => dest,
+    };
+
+    if !guard.register(dest) {
+        return None;
+    }
+    Some(dest)
+}
@@ -0,0 +1,164 @@
+use crate::config::base::IoUringConfig;
+use crate::proxy::relay::Activity;
+
+use log::{info, warn};
+use once_cell::sync::OnceCell;
+use std::future::Future;
+use std::io::{Error, ErrorKind, Result};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+use tokio_uring::buf::IoBuf;
+use tokio_uring::net::TcpStream;
+
+/// Default submission queue entries of the ring of each worker
+const DEFAULT_ENTRIES: u32 = 256;
+
+/// Workers of the process, None if io_uring turned out to be unavailable
+static WORKERS: OnceCell<Option<UringWorkers>> = OnceCell::new();
+
+/// Task handed to a worker, built on the worker thread as the io_uring streams can't leave it
+type Job = Box<dyn FnOnce() -> Pin<Box<dyn Future<Output = ()>>> + Send>;
+
+/// Threads running the relays on io_uring, each with its own ring. Reads and writes are submitted to the kernel in
+/// batches and complete without a readiness notification and a syscall per operation as with epoll, which leaves more
+/// of the CPU to the copies on fast links. The relays are handed to the workers in turn.
+pub struct UringWorkers {
+    senders: Vec<UnboundedSender<Job>>,
+    next: AtomicUsize,
+}
+
+impl UringWorkers {
+    /// Start the workers shared by the whole process, None if the kernel doesn't support io_uring or the configuration
+    /// asks for no workers, which the parser rejects.
+    pub fn init(config: &IoUringConfig) -> Option<&'static Self> {
+        WORKERS
+            .get_or_init(|| match Self::start(config) {
+                Ok(workers) => {
+                    info!(
+                        "Relaying plain TCP on {} io_uring workers",
+                        workers.senders.len()
+                    );
+                    Some(workers)
+                }
+                Err(e) => {
+                    warn!("Failed to set up io_uring, relaying without it: {}", e);
+                    None
+                }
+            })
+            .as_ref()
+    }
+
+    /// Workers started by init, if any.
+    #[inline]
+    pub fn get() -> Option<&'static Self> {
+        WORKERS.get().and_then(Option::as_ref)
+    }
+
+    fn start(config: &IoUringConfig) -> Result<Self> {
+        let workers = config.workers.unwrap_or_else(|| {
+            thread::available_parallelism()
+                .map(|workers| workers.get())
+                .unwrap_or(1)
+        });
+        if workers == 0 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "workers of io_uring must be positive",
+            ));
+        }
+        let entries = config.entries.unwrap_or(DEFAULT_ENTRIES);
+
+        let mut senders = Vec::with_capacity(workers);
+        for index in 0..workers {
+            let (sender, mut receiver) = unbounded_channel::<Job>();
+            let (ready, started) = mpsc::channel();
+
+            thread::Builder::new()
+                .name(format!("uring-worker-{}", index))
+                .spawn(move || {
+                    let runtime =
+                        match tokio_uring::Runtime::new(tokio_uring::builder().entries(entries)) {
+                            Ok(runtime) => runtime,
+                            Err(e) => {
+                                let _ = ready.send(Err(e));
+                                return;
+                            }
+                        };
+                    let _ = ready.send(Ok(()));
+
+                    runtime.block_on(async move {
+                        while let Some(job) = receiver.recv().await {
+                            tokio_uring::spawn(job());
+                        }
+                    });
+                })?;
+
+            started
+                .recv()
+                .map_err(|_| Error::other("io_uring worker exited"))??;
+            senders.push(sender);
+        }
+
+        Ok(Self {
+            senders,
+            next: AtomicUsize::new(0),
+        })
+    }
+
+    /// Run the future built by task on the next worker.
+    pub fn spawn<F, Fut>(&self, task: F)
+    where
+        F: FnOnce() -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + 'static,
+    {
+        let index = self.next.fetch_add(1, Ordering::Relaxed) % self.senders.len();
+        let job: Job = Box::new(move || Box::pin(task()));
+        // Workers never stop, the job is only lost if one panicked
+        let _ = self.senders[index].send(job);
+    }
+}
+
+/// Copy the data from the reader to the writer through a buffer of size bytes until the reader ends or the limit is
+/// reached, like splice::copy. Every read records the activity and adds the bytes to the counters.
+pub async fn copy(
+    reader: &TcpStream,
+    writer: &TcpStream,
+    size: usize,
+    limit: Option<u64>,
+    activity: &Activity,
+    counters: &[&AtomicU64],
+) -> Result<u64> {
+    // The ring owns the buffer while an operation is in flight, so it is passed by value and handed back
+    let mut buf = Vec::with_capacity(size);
+    let mut copied = 0;
+
+    loop {
+        let remaining = match limit {
+            Some(limit) if copied >= limit => return Ok(copied),
+            Some(limit) => limit - copied,
+            None => u64::MAX,
+        };
+
+        buf.clear();
+        let (result, read_buf) = reader.read(buf).await;
+        let read = result?;
+        if read == 0 {
+            return Ok(copied);
+        }
+        activity.touch();
+
+        // The bytes read beyond the limit are dropped along with the connection
+        let read = (read as u64).min(remaining) as usize;
+        let (result, slice) = writer.write_all(read_buf.slice(..read)).await;
+        result?;
+        buf = slice.into_inner();
+
+        copied += read as u64;
+        for counter in counters {
+            counter.fetch_add(read as u64, Ordering::Relaxed);
+        }
+    }
+}
@@ -0,0 +1,72 @@
+use ipnet::IpNet;
+use log::info;
+use maxminddb::{geoip2, Reader};
+use std::io::{Error, ErrorKind, Result};
+use std::net::IpAddr;
+
+/// Prefix of the GeoIP values in ip_cidr of the routing rules, like geoip:cn
+pub const GEOIP_PREFIX: &str = "geoip:";
+
+/// Special GeoIP code matching the private, loopback, link local and other non global addresses, it is built in and
+/// doesn't need the database.
+pub const PRIVATE_CODE: &str = "private";
+
+const PRIVATE_RANGES: [&str; 14] = [
+    "0.0.0.0/8",
+    "10.0.0.0/8",
+    "100.64.0.0/10",
+    "127.0.0.0/8",
+    "169.254.0.0/16",
+    "172.16.0.0/12",
+    "192.168.0.0/16",
+    "224.0.0.0/4",
+    "240.0.0.0/4",
+    "::/128",
+    "::1/128",
+    "fc00::/7",
+    "fe80::/10",
+    "ff00::/8",
+];
+
+/// Address ranges matched by geoip:private.
+pub fn private_ranges() -> Vec<IpNet> {
+    PRIVATE_RANGES
+        .iter()
+        .map(|range| range.parse().unwrap())
+        .collect()
+}
+
+/// Country database in MaxMind mmdb format, loaded into memory at startup.
+pub struct GeoIp {
+    reader: Reader<Vec<u8>>,
+}
+
+impl GeoIp {
+    pub fn open(path: &str) -> Result<Self> {
+        Self::from_bytes(std::fs::read(path)?)
+    }
+
+    /// Load the database from the content of a mmdb file.
+    pub fn from_bytes(buf: Vec<u8>) -> Result<Self> {
+        let reader = match Reader::from_source(buf) {
+            Ok(reader) => reader,
+            Err(e) => return Err(Error::new(ErrorKind::InvalidData, e)),
+        };
+
+        info!(
+            "Loaded GeoIP database {} built at {}",
+            reader.metadata.database_type, reader.metadata.build_epoch
+        );
+
+        Ok(Self { reader })
+    }
+
+    /// Lower case ISO code of the country the address belongs to.
+    pub fn country(&self, ip: IpAddr) -> Option<String> {
+        let country: geoip2::Country = self.reader.lookup(ip).ok()?;
+        country
+            .country
+            .and_then(|country| country.iso_code)
+            .map(|code| code.to_ascii_lowercase())
+    }
+}
@@ -0,0 +1,98 @@
+use log::info;
+use prost::Message;
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Result};
+
+use self::proto::domain::Type;
+
+/// Prefix of the geosite categories in domain of the routing rules, like geosite:google or geosite:google@cn to only
+/// keep the domains with the cn attribute.
+pub const GEOSITE_PREFIX: &str = "geosite:";
+
+/// Messages of the v2ray geosite.dat file.
+pub mod proto {
+    tonic::include_proto!("trojan_rust.geosite");
+}
+
+/// Single entry of a domain list, the value is in lower case.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DomainEntry {
+    /// The domain contains the keyword
+    Keyword(String),
+    /// The domain matches the regular expression
+    Regex(String),
+    /// The domain itself and all of its subdomains
+    Suffix(String),
+    /// Exactly the domain
+    Full(String),
+}
+
+/// Domain lists grouped by category, loaded from a v2ray geosite.dat file at startup.
+pub struct GeoSite {
+    categories: HashMap<String, Vec<proto::Domain>>,
+}
+
+impl GeoSite {
+    pub fn open(path: &str) -> Result<Self> {
+        let geosite = Self::decode(&std::fs::read(path)?)?;
+
+        info!(
+            "Loaded {} geosite categories from {}",
+            geosite.categories.len(),
+            path
+        );
+
+        Ok(geosite)
+    }
+
+    /// Decode the content of a geosite.dat file.
+    pub fn decode(buf: &[u8]) -> Result<Self> {
+        let list = match proto::GeoSiteList::decode(buf) {
+            Ok(list) => list,
+            Err(e) => return Err(Error::new(ErrorKind::InvalidData, e)),
+        };
+
+        Ok(Self {
+            categories: list
+                .entry
+                .into_iter()
+                .map(|site| (site.country_code.to_ascii_lowercase(), site.domain))
+                .collect(),
+        })
+    }
+
+    /// Domains of the category, the name can be followed by @attribute to only keep the domains having the
+    /// attribute. Returns None if there is no such category.
+    pub fn category(&self, name: &str) -> Option<Vec<DomainEntry>> {
+        let name = name.to_ascii_lowercase();
+        let (name, attribute) = match name.split_once('@') {
+            Some((name, attribute)) => (name, Some(attribute)),
+            None => (name.as_str(), None),
+        };
+
+        let domains = self.categories.get(name)?;
+
+        Some(
+            domains
+                .iter()
+                .filter(|domain| match attribute {
+                    Some(attribute) => domain
+                        .attribute
+                        .iter()
+                        .any(|attr| attr.key.eq_ignore_ascii_case(attribute)),
+                    None => true,
+                })
+                // Skip the domain types added by later versions of the format
+                .filter_map(|domain| {
+                    let value = domain.value.to_ascii_lowercase();
+                    match Type::from_i32(domain.r#type)? {
+                        Type::Plain => Some(DomainEntry::Keyword(value)),
+                        Type::Regex => Some(DomainEntry::Regex(domain.value.clone())),
+                        Type::Domain => Some(DomainEntry::Suffix(value)),
+                        Type::Full => Some(DomainEntry::Full(value)),
+                    }
+                })
+                .collect(),
+        )
+    }
+}
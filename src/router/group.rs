@@ -0,0 +1,439 @@
+use crate::config::base::{GroupType, OutboundGroupConfig};
+use crate::events::{self, Event};
+use crate::metrics;
+use crate::protocol::common::addr::IpAddress;
+use crate::protocol::common::atype::Atype;
+use crate::protocol::common::command::Command;
+use crate::protocol::common::request::{InboundRequest, TransportProtocol};
+use crate::proxy::base::SupportedProtocols;
+use crate::proxy::deadline::Deadline;
+use crate::proxy::tcp::handler::TcpHandler;
+
+use bytes::Bytes;
+use futures::future::join_all;
+use http::Uri;
+use log::{debug, info, warn};
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Result};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+/// Default interval in seconds of probing the members
+const DEFAULT_INTERVAL: u64 = 30;
+
+/// Default time in seconds a probe has to finish in
+const DEFAULT_TIMEOUT: u64 = 5;
+
+/// Default number of consecutive failed probes before a member is considered down
+const DEFAULT_FALL: u32 = 3;
+
+/// Default number of consecutive successful probes before a member is considered up again
+const DEFAULT_RISE: u32 = 2;
+
+/// Default url fetched through the members of the url test groups
+const DEFAULT_TEST_URL: &str = "http://www.gstatic.com/generate_204";
+
+/// Default milliseconds a member has to be faster than the current one for the url test group to switch to it
+const DEFAULT_TOLERANCE: u64 = 50;
+
+/// Health of a member, only flips after enough consecutive probes with the opposite result so that a member failing
+/// intermittently doesn't make the group switch back and forth.
+struct Health {
+    healthy: bool,
+    successes: u32,
+    failures: u32,
+    latency: Option<Duration>,
+}
+
+struct Member {
+    tag: String,
+    health: Mutex<Health>,
+    /// Draining members are only picked when all the members are draining
+    draining: AtomicBool,
+}
+
+/// Group of outbounds selecting one of its members for each proxy request.
+pub struct OutboundGroup {
+    tag: String,
+    group_type: GroupType,
+    members: Vec<Member>,
+    active: AtomicUsize,
+    interval: Duration,
+    timeout: Duration,
+    fall: u32,
+    rise: u32,
+    url: Option<TestUrl>,
+    tolerance: Duration,
+}
+
+impl OutboundGroup {
+    /// Fails with InvalidInput if the group has no members or the test url is invalid.
+    pub fn new(config: &OutboundGroupConfig) -> Result<Self> {
+        if config.members.is_empty() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("outbound group {} has no members", config.tag),
+            ));
+        }
+
+        let url = match config.group_type {
+            GroupType::URL_TEST => {
+                let url = config.url.as_deref().unwrap_or(DEFAULT_TEST_URL);
+                match TestUrl::parse(url) {
+                    Ok(url) => Some(url),
+                    Err(e) => {
+                        return Err(Error::new(
+                            ErrorKind::InvalidInput,
+                            format!("invalid url of outbound group {}: {}", config.tag, e),
+                        ))
+                    }
+                }
+            }
+            GroupType::FAILOVER | GroupType::SELECT => None,
+        };
+
+        Ok(Self {
+            tag: config.tag.clone(),
+            group_type: config.group_type,
+            members: config
+                .members
+                .iter()
+                .map(|tag| Member {
+                    tag: tag.clone(),
+                    // Members are assumed to be healthy until probed
+                    health: Mutex::new(Health {
+                        healthy: true,
+                        successes: 0,
+                        failures: 0,
+                        latency: None,
+                    }),
+                    draining: AtomicBool::new(false),
+                })
+                .collect(),
+            active: AtomicUsize::new(0),
+            interval: Duration::from_secs(config.interval.unwrap_or(DEFAULT_INTERVAL)),
+            timeout: Duration::from_secs(config.timeout.unwrap_or(DEFAULT_TIMEOUT)),
+            fall: config.fall.unwrap_or(DEFAULT_FALL).max(1),
+            rise: config.rise.unwrap_or(DEFAULT_RISE).max(1),
+            url,
+            tolerance: Duration::from_millis(config.tolerance.unwrap_or(DEFAULT_TOLERANCE)),
+        })
+    }
+
+    #[inline]
+    pub fn tag(&self) -> &str {
+        &self.tag
+    }
+
+    #[inline]
+    pub fn group_type(&self) -> GroupType {
+        self.group_type
+    }
+
+    /// Tags of the members in the configured order.
+    pub fn members(&self) -> impl Iterator<Item = &str> {
+        self.members.iter().map(|member| member.tag.as_str())
+    }
+
+    /// Tag of the member the proxy requests currently go to.
+    #[inline]
+    pub fn selected(&self) -> &str {
+        &self.members[self.active.load(Ordering::Relaxed)].tag
+    }
+
+    /// Record the result of probing the member at index, and select the member the requests go to afterwards.
+    pub fn record(&self, index: usize, success: bool) {
+        self.record_latency(index, success.then_some(Duration::ZERO));
+    }
+
+    /// Record the latency measured by probing the member at index, None if the probe failed, and select the member
+    /// the requests go to afterwards.
+    pub fn record_latency(&self, index: usize, latency: Option<Duration>) {
+        let member = &self.members[index];
+        {
+            let mut health = member.health.lock().unwrap();
+            health.latency = latency;
+            match latency.is_some() {
+                true => {
+                    health.successes += 1;
+                    health.failures = 0;
+                    if !health.healthy && health.successes >= self.rise {
+                        info!("Outbound {} in group {} is up", member.tag, self.tag);
+                        health.healthy = true;
+                    }
+                }
+                false => {
+                    health.failures += 1;
+                    health.successes = 0;
+                    if health.healthy && health.failures >= self.fall {
+                        warn!("Outbound {} in group {} is down", member.tag, self.tag);
+                        events::emit(Event::Error {
+                            connection: 0,
+                            message: format!(
+                                "outbound {} in group {} is down",
+                                member.tag, self.tag
+                            ),
+                        });
+                        health.healthy = false;
+                    }
+                }
+            }
+
+            metrics::set(
+                &format!(
+                    "outbound_healthy{{group=\"{}\",outbound=\"{}\"}}",
+                    metrics::escape_label(&self.tag),
+                    metrics::escape_label(&member.tag)
+                ),
+                health.healthy as u64,
+            );
+        }
+
+        self.reselect();
+    }
+
+    /// Mark the member with the tag as draining or put it back in rotation, returns false if there is no such
+    /// member. New requests move off a draining member, including the one picked by hand in select groups, while
+    /// the requests already being relayed keep using it.
+    pub fn set_draining(&self, tag: &str, draining: bool) -> bool {
+        let member = match self.members.iter().find(|member| member.tag == tag) {
+            Some(member) => member,
+            None => return false,
+        };
+        member.draining.store(draining, Ordering::Relaxed);
+        self.reselect();
+        true
+    }
+
+    /// Tags of the members being drained.
+    pub fn draining(&self) -> impl Iterator<Item = &str> {
+        self.members
+            .iter()
+            .filter(|member| member.draining.load(Ordering::Relaxed))
+            .map(|member| member.tag.as_str())
+    }
+
+    /// Select the member the requests go to after the health or the draining of the members changed.
+    fn reselect(&self) {
+        let selected = match self.group_type {
+            GroupType::FAILOVER => self.first_healthy(),
+            GroupType::URL_TEST => self.fastest(),
+            GroupType::SELECT => match self.members[self.active.load(Ordering::Relaxed)]
+                .draining
+                .load(Ordering::Relaxed)
+            {
+                true => self.first_in_rotation(),
+                false => None,
+            },
+        };
+
+        // Stay on the current member if none of them is usable
+        if let Some(index) = selected {
+            self.switch(index);
+        }
+    }
+
+    /// Send the requests to the member with the tag from now on, returns false if there is no such member. Requests
+    /// already being relayed keep using the member they were routed to.
+    pub fn select(&self, tag: &str) -> bool {
+        match self.members.iter().position(|member| member.tag == tag) {
+            Some(index) => {
+                self.switch(index);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn first_healthy(&self) -> Option<usize> {
+        self.members.iter().position(|member| {
+            !member.draining.load(Ordering::Relaxed) && member.health.lock().unwrap().healthy
+        })
+    }
+
+    fn first_in_rotation(&self) -> Option<usize> {
+        self.members
+            .iter()
+            .position(|member| !member.draining.load(Ordering::Relaxed))
+    }
+
+    /// The healthy member with the lowest latency, unless the current member isn't slower than it by more than the
+    /// tolerance.
+    fn fastest(&self) -> Option<usize> {
+        let latencies: Vec<Option<Duration>> = self
+            .members
+            .iter()
+            .map(|member| {
+                let health = member.health.lock().unwrap();
+                let draining = member.draining.load(Ordering::Relaxed);
+                health.latency.filter(|_| health.healthy && !draining)
+            })
+            .collect();
+
+        let (fastest, latency) = latencies
+            .iter()
+            .enumerate()
+            .filter_map(|(index, latency)| latency.map(|latency| (index, latency)))
+            .min_by_key(|(_, latency)| *latency)?;
+
+        let active = self.active.load(Ordering::Relaxed);
+        match latencies[active] {
+            Some(current) if current <= latency + self.tolerance => Some(active),
+            _ => Some(fastest),
+        }
+    }
+
+    fn switch(&self, index: usize) {
+        let previous = self.active.swap(index, Ordering::Relaxed);
+        if previous != index {
+            info!(
+                "Outbound group {} switched from {} to {}",
+                self.tag, self.members[previous].tag, self.members[index].tag
+            );
+            metrics::increment(
+                &format!(
+                    "outbound_group_switches_total{{group=\"{}\"}}",
+                    metrics::escape_label(&self.tag)
+                ),
+                1,
+            );
+            events::emit(Event::OutboundSwitched {
+                group: self.tag.clone(),
+                from: self.members[previous].tag.clone(),
+                to: self.members[index].tag.clone(),
+            });
+        }
+    }
+
+    /// Probe the member through its handler, returns the latency of the probe.
+    async fn probe(&self, handler: &TcpHandler) -> Result<Duration> {
+        let start = Instant::now();
+        let probe = async {
+            match &self.url {
+                Some(url) => url.fetch(handler, Deadline::after(self.timeout)).await,
+                None => handler.probe().await,
+            }
+        };
+
+        match tokio::time::timeout(self.timeout, probe).await {
+            Ok(Ok(())) => Ok(start.elapsed()),
+            Ok(Err(e)) => Err(e),
+            Err(_) => Err(Error::new(ErrorKind::TimedOut, "probe timed out")),
+        }
+    }
+
+    /// Probe all the members every interval for as long as the process runs.
+    pub async fn run_health_checks(&self, handlers: &HashMap<String, Arc<TcpHandler>>) {
+        let mut ticker = tokio::time::interval(self.interval);
+
+        loop {
+            ticker.tick().await;
+
+            let probes = self.members.iter().map(|member| async {
+                // Safety: the members are checked to exist when the router is built
+                match self.probe(&handlers[&member.tag]).await {
+                    Ok(latency) => {
+                        debug!("Probed outbound {} in {:?}", member.tag, latency);
+                        Some(latency)
+                    }
+                    Err(e) => {
+                        warn!("Failed to probe outbound {}: {}", member.tag, e);
+                        None
+                    }
+                }
+            });
+
+            for (index, latency) in join_all(probes).await.into_iter().enumerate() {
+                self.record_latency(index, latency);
+            }
+        }
+    }
+}
+
+/// Plain http url fetched through the members of the url test groups.
+pub struct TestUrl {
+    host: String,
+    port: u16,
+    path: String,
+}
+
+impl TestUrl {
+    pub fn parse(url: &str) -> Result<Self> {
+        let uri: Uri = match url.parse() {
+            Ok(uri) => uri,
+            Err(e) => return Err(Error::new(ErrorKind::InvalidInput, e)),
+        };
+
+        if uri.scheme_str() != Some("http") {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "only http urls are supported",
+            ));
+        }
+
+        let host = match uri.host() {
+            Some(host) => host.trim_start_matches('[').trim_end_matches(']'),
+            None => return Err(Error::new(ErrorKind::InvalidInput, "missing host")),
+        };
+
+        Ok(Self {
+            host: host.to_string(),
+            port: uri.port_u16().unwrap_or(80),
+            path: uri
+                .path_and_query()
+                .map(|path| path.as_str())
+                .unwrap_or("/")
+                .to_string(),
+        })
+    }
+
+    /// Send a HEAD request for the url through the handler, and wait for the status line of the response.
+    pub async fn fetch(&self, handler: &TcpHandler, deadline: Deadline) -> Result<()> {
+        let (atype, addr) = match self.host.parse::<IpAddr>() {
+            Ok(ip @ IpAddr::V4(_)) => (Atype::IPv4, IpAddress::IpAddr(ip)),
+            Ok(ip @ IpAddr::V6(_)) => (Atype::IPv6, IpAddress::IpAddr(ip)),
+            Err(_) => (
+                Atype::DomainName,
+                IpAddress::from_bytes(Bytes::from(self.host.clone())),
+            ),
+        };
+        let request = InboundRequest::new(
+            atype,
+            addr,
+            Command::Connect,
+            self.port,
+            TransportProtocol::TCP,
+            SupportedProtocols::TROJAN,
+        );
+
+        // The handler relays the other end of the pipe as if it were an inbound connection
+        let (mut client, inbound) = tokio::io::duplex(4096);
+        let exchange = async {
+            let head = format!(
+                "HEAD {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+                self.path, self.host
+            );
+            client.write_all(head.as_bytes()).await?;
+
+            let mut status = [0u8; 5];
+            client.read_exact(&mut status).await?;
+            match &status == b"HTTP/" {
+                true => Ok(()),
+                false => Err(Error::new(ErrorKind::InvalidData, "invalid http response")),
+            }
+        };
+
+        tokio::pin!(exchange);
+        tokio::select! {
+            result = &mut exchange => result,
+            // The response may still be buffered in the pipe when the relay finishes
+            result = handler.dispatch(inbound, request, deadline) => match result {
+                Ok(()) => exchange.await,
+                Err(e) => Err(e),
+            },
+        }
+    }
+}
@@ -0,0 +1,306 @@
+use crate::config::base::PortConfig;
+use crate::protocol::common::addr::IpAddress;
+use crate::protocol::common::request::TransportProtocol;
+use crate::router::geoip::{private_ranges, GeoIp, GEOIP_PREFIX, PRIVATE_CODE};
+use crate::router::geosite::{DomainEntry, GeoSite, GEOSITE_PREFIX};
+use crate::router::RouteContext;
+
+use ipnet::IpNet;
+use regex::RegexSet;
+use std::collections::HashSet;
+use std::io::{Error, ErrorKind, Result};
+use std::ops::RangeInclusive;
+use std::sync::Arc;
+
+/// Condition of a routing rule evaluated against the proxy request.
+pub trait Matcher: Send + Sync {
+    fn matches(&self, context: &RouteContext) -> bool;
+}
+
+/// Match the requests by destination domain. Values can be prefixed with the type of the match: full: for exactly the
+/// domain, domain: for the domain and all of its subdomains, keyword: for the domains containing the keyword, regexp:
+/// for the domains matching the regular expression, and geosite: for a category of the geosite database. Values
+/// without a prefix are the same as domain:.
+pub struct DomainMatcher {
+    full: HashSet<String>,
+    suffixes: HashSet<String>,
+    keywords: Vec<String>,
+    regexes: Option<RegexSet>,
+}
+
+impl DomainMatcher {
+    /// Fails with InvalidInput on invalid regular expressions, or if the geosite category doesn't exist or the
+    /// geosite database is needed but not loaded.
+    pub fn new(domains: &[String], geosite: Option<&GeoSite>) -> Result<Self> {
+        let mut entries = Vec::new();
+
+        for domain in domains {
+            let entry = match domain.split_once(':') {
+                Some(("full", value)) => DomainEntry::Full(value.to_ascii_lowercase()),
+                Some(("domain", value)) => DomainEntry::Suffix(value.to_ascii_lowercase()),
+                Some(("keyword", value)) => DomainEntry::Keyword(value.to_ascii_lowercase()),
+                Some(("regexp", value)) => DomainEntry::Regex(value.to_string()),
+                _ if domain.starts_with(GEOSITE_PREFIX) => {
+                    let category = &domain[GEOSITE_PREFIX.len()..];
+                    let geosite = geosite.ok_or_else(|| {
+                        Error::new(
+                            ErrorKind::InvalidInput,
+                            format!("{} requires geosite_database in the router config", domain),
+                        )
+                    })?;
+                    match geosite.category(category) {
+                        Some(category) => entries.extend(category),
+                        None => {
+                            return Err(Error::new(
+                                ErrorKind::InvalidInput,
+                                format!(
+                                    "geosite category {} is missing from the database",
+                                    category
+                                ),
+                            ))
+                        }
+                    }
+                    continue;
+                }
+                _ => DomainEntry::Suffix(domain.to_ascii_lowercase()),
+            };
+            entries.push(entry);
+        }
+
+        let (mut full, mut suffixes, mut keywords, mut regexes) =
+            (HashSet::new(), HashSet::new(), Vec::new(), Vec::new());
+
+        for entry in entries {
+            match entry {
+                DomainEntry::Full(value) => {
+                    full.insert(value.trim_end_matches('.').to_string());
+                }
+                DomainEntry::Suffix(value) => {
+                    suffixes.insert(value.trim_end_matches('.').to_string());
+                }
+                DomainEntry::Keyword(value) => keywords.push(value),
+                DomainEntry::Regex(value) => regexes.push(value),
+            }
+        }
+
+        let regexes = match regexes.is_empty() {
+            true => None,
+            false => Some(RegexSet::new(&regexes).map_err(|e| {
+                Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("invalid regexp in domain: {}", e),
+                )
+            })?),
+        };
+
+        Ok(Self {
+            full,
+            suffixes,
+            keywords,
+            regexes,
+        })
+    }
+
+    /// Whether the lower case domain matches any of the values.
+    pub fn matches_domain(&self, domain: &str) -> bool {
+        if self.full.contains(domain) {
+            return true;
+        }
+
+        // Look up the domain and each of its parent domains
+        let mut suffix = domain;
+        loop {
+            if self.suffixes.contains(suffix) {
+                return true;
+            }
+            match suffix.split_once('.') {
+                Some((_, parent)) => suffix = parent,
+                None => break,
+            }
+        }
+
+        if self
+            .keywords
+            .iter()
+            .any(|keyword| domain.contains(keyword.as_str()))
+        {
+            return true;
+        }
+
+        match &self.regexes {
+            Some(regexes) => regexes.is_match(domain),
+            None => false,
+        }
+    }
+}
+
+impl Matcher for DomainMatcher {
+    fn matches(&self, context: &RouteContext) -> bool {
+        let domain = match (&context.request.addr_port.ip, context.sniffed_domain) {
+            (IpAddress::Domain(domain), _) => domain.to_string().to_ascii_lowercase(),
+            (IpAddress::IpAddr(_), Some(domain)) => domain.to_ascii_lowercase(),
+            (IpAddress::IpAddr(_), None) => return false,
+        };
+
+        self.matches_domain(domain.trim_end_matches('.'))
+    }
+}
+
+/// Match the requests whose destination is an IP address within the ranges, or located in the countries according
+/// to the GeoIP database.
+pub struct IpCidrMatcher {
+    ranges: Vec<IpNet>,
+    countries: Vec<String>,
+    geoip: Option<Arc<GeoIp>>,
+}
+
+impl IpCidrMatcher {
+    /// CIDR notation, plain IP addresses and GeoIP codes like geoip:cn are accepted, fails with InvalidInput on
+    /// invalid values or if the GeoIP database is needed but not loaded.
+    pub fn new(ranges: &[String], geoip: Option<&Arc<GeoIp>>) -> Result<Self> {
+        let (mut networks, mut countries) = (Vec::new(), Vec::new());
+
+        for range in ranges {
+            if let Some(code) = range.strip_prefix(GEOIP_PREFIX) {
+                match code.to_ascii_lowercase() {
+                    code if code == PRIVATE_CODE => networks.extend(private_ranges()),
+                    _ if geoip.is_none() => {
+                        return Err(Error::new(
+                            ErrorKind::InvalidInput,
+                            format!("{} requires geoip_database in the router config", range),
+                        ))
+                    }
+                    code => countries.push(code),
+                }
+                continue;
+            }
+
+            networks.push(match range.parse::<IpNet>() {
+                Ok(net) => net,
+                Err(_) => match range.parse::<std::net::IpAddr>() {
+                    Ok(addr) => IpNet::from(addr),
+                    Err(e) => {
+                        return Err(Error::new(
+                            ErrorKind::InvalidInput,
+                            format!("invalid ip_cidr {}: {}", range, e),
+                        ))
+                    }
+                },
+            });
+        }
+
+        Ok(Self {
+            ranges: networks,
+            countries,
+            geoip: geoip.cloned(),
+        })
+    }
+}
+
+impl Matcher for IpCidrMatcher {
+    fn matches(&self, context: &RouteContext) -> bool {
+        let addr = match &context.request.addr_port.ip {
+            IpAddress::IpAddr(addr) => addr,
+            IpAddress::Domain(_) => return false,
+        };
+
+        if self.ranges.iter().any(|net| net.contains(addr)) {
+            return true;
+        }
+
+        match (&self.geoip, self.countries.is_empty()) {
+            (Some(geoip), false) => match geoip.country(*addr) {
+                Some(country) => self.countries.contains(&country),
+                None => false,
+            },
+            _ => false,
+        }
+    }
+}
+
+/// Match the requests by destination port or port range.
+pub struct PortMatcher {
+    ranges: Vec<RangeInclusive<u16>>,
+}
+
+impl PortMatcher {
+    /// Fails with InvalidInput on invalid port ranges.
+    pub fn new(ports: &[PortConfig]) -> Result<Self> {
+        Ok(Self {
+            ranges: ports
+                .iter()
+                .map(|port| match port {
+                    PortConfig::Single(port) => Ok(*port..=*port),
+                    PortConfig::Range(range) => parse_port_range(range).ok_or_else(|| {
+                        Error::new(
+                            ErrorKind::InvalidInput,
+                            format!("invalid port range {}", range),
+                        )
+                    }),
+                })
+                .collect::<Result<_>>()?,
+        })
+    }
+}
+
+/// Parse a port range like 1000-2000, or a single port.
+pub fn parse_port_range(range: &str) -> Option<RangeInclusive<u16>> {
+    let (start, end) = match range.split_once('-') {
+        Some((start, end)) => (start.trim().parse().ok()?, end.trim().parse().ok()?),
+        None => {
+            let port = range.trim().parse().ok()?;
+            (port, port)
+        }
+    };
+
+    match start <= end {
+        true => Some(start..=end),
+        false => None,
+    }
+}
+
+impl Matcher for PortMatcher {
+    fn matches(&self, context: &RouteContext) -> bool {
+        let port = context.request.addr_port.port;
+        self.ranges.iter().any(|range| range.contains(&port))
+    }
+}
+
+/// Match the requests by the tag of the inbound accepting them.
+pub struct InboundTagMatcher {
+    tags: Vec<String>,
+}
+
+impl InboundTagMatcher {
+    pub fn new(tags: &[String]) -> Self {
+        Self {
+            tags: tags.to_vec(),
+        }
+    }
+}
+
+impl Matcher for InboundTagMatcher {
+    fn matches(&self, context: &RouteContext) -> bool {
+        match context.inbound_tag {
+            Some(tag) => self.tags.iter().any(|t| t == tag),
+            None => false,
+        }
+    }
+}
+
+/// Match the requests by transport protocol, TCP or UDP.
+pub struct TransportMatcher {
+    transport: TransportProtocol,
+}
+
+impl TransportMatcher {
+    pub fn new(transport: TransportProtocol) -> Self {
+        Self { transport }
+    }
+}
+
+impl Matcher for TransportMatcher {
+    fn matches(&self, context: &RouteContext) -> bool {
+        context.request.transport_protocol == self.transport
+    }
+}
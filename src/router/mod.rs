@@ -0,0 +1,523 @@
+pub mod geoip;
+pub mod geosite;
+pub mod group;
+pub mod matcher;
+pub mod update;
+
+use crate::config::base::{
+    Config, DomainResolution, GroupType, OutboundConfig, OutboundMode, RouterConfig, RuleConfig,
+};
+use crate::metrics;
+use crate::protocol::common::request::InboundRequest;
+use crate::proxy::tcp::handler::TcpHandler;
+
+use log::{debug, info, warn};
+use once_cell::sync::OnceCell;
+use std::collections::HashMap;
+use std::io::{self, Error, ErrorKind};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use self::geoip::GeoIp;
+use self::geosite::GeoSite;
+use self::group::OutboundGroup;
+use self::matcher::{
+    DomainMatcher, InboundTagMatcher, IpCidrMatcher, Matcher, PortMatcher, TransportMatcher,
+};
+
+/// Tag of the outbound in the outbound field of the configuration if it isn't tagged explicitly
+pub const DEFAULT_OUTBOUND_TAG: &str = "default";
+
+/// Default time in seconds between the downloads of the routing databases
+const DEFAULT_UPDATE_INTERVAL: u64 = 24 * 60 * 60;
+
+/// Static lifetime router shared by the inbound servers
+static ROUTER: OnceCell<Router> = OnceCell::new();
+
+/// Information about the proxy request available to the routing rules
+pub struct RouteContext<'a> {
+    pub request: &'a InboundRequest,
+    pub inbound_tag: Option<&'a str>,
+    /// Domain sniffed from the traffic of a request to an IP address, matched by the domain rules in its place
+    pub sniffed_domain: Option<&'a str>,
+}
+
+/// Routing rule, which sends the requests meeting all of its conditions to the outbound.
+pub struct Rule {
+    matchers: Vec<Box<dyn Matcher>>,
+    outbound: String,
+    resolve: Option<DomainResolution>,
+}
+
+impl Rule {
+    /// Fails with InvalidInput if a condition of the rule is invalid.
+    pub fn new(
+        config: &RuleConfig,
+        geoip: Option<&Arc<GeoIp>>,
+        geosite: Option<&GeoSite>,
+    ) -> io::Result<Self> {
+        let mut matchers: Vec<Box<dyn Matcher>> = Vec::new();
+
+        if let Some(domains) = &config.domain {
+            matchers.push(Box::new(DomainMatcher::new(domains, geosite)?));
+        }
+        if let Some(ranges) = &config.ip_cidr {
+            matchers.push(Box::new(IpCidrMatcher::new(ranges, geoip)?));
+        }
+        if let Some(ports) = &config.port {
+            matchers.push(Box::new(PortMatcher::new(ports)?));
+        }
+        if let Some(tags) = &config.inbound_tag {
+            matchers.push(Box::new(InboundTagMatcher::new(tags)));
+        }
+        if let Some(transport) = config.transport {
+            matchers.push(Box::new(TransportMatcher::new(transport)));
+        }
+
+        Ok(Self {
+            matchers,
+            outbound: config.outbound.clone(),
+            resolve: config.resolve,
+        })
+    }
+
+    #[inline]
+    pub fn matches(&self, context: &RouteContext) -> bool {
+        self.matchers.iter().all(|matcher| matcher.matches(context))
+    }
+}
+
+/// Build the handler of the outbound after the handler of its dialer, the dialers have been checked by check_dialers.
+fn build_handler(
+    tag: &str,
+    outbounds: &HashMap<String, &OutboundConfig>,
+    handlers: &mut HashMap<String, Arc<TcpHandler>>,
+) -> io::Result<Arc<TcpHandler>> {
+    if let Some(handler) = handlers.get(tag) {
+        return Ok(handler.clone());
+    }
+
+    let outbound = outbounds[tag];
+    let mut handler = TcpHandler::new(outbound)?;
+    if let Some(dialer) = &outbound.dialer {
+        handler = handler.with_dialer(build_handler(dialer, outbounds, handlers)?);
+    }
+
+    let handler = Arc::new(handler);
+    handlers.insert(tag.to_string(), handler.clone());
+    Ok(handler)
+}
+
+/// Check the dialers of the outbounds, fails with InvalidInput if a dialer is unknown, can't carry the connections of
+/// other outbounds, or the dialers form a loop.
+fn check_dialers(outbounds: &HashMap<String, &OutboundConfig>) -> io::Result<()> {
+    for (tag, outbound) in outbounds.iter() {
+        let dialer = match &outbound.dialer {
+            Some(dialer) => dialer,
+            None => continue,
+        };
+        let dialer_config = match outbounds.get(dialer) {
+            Some(config) => config,
+            None => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("outbound {} refers to unknown dialer {}", tag, dialer),
+                ))
+            }
+        };
+        if !matches!(outbound.mode, OutboundMode::TCP) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("outbound {} has to be in TCP mode to use a dialer", tag),
+            ));
+        }
+        if !matches!(dialer_config.mode, OutboundMode::DIRECT | OutboundMode::TCP) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "dialer {} of outbound {} has to be in DIRECT or TCP mode",
+                    dialer, tag
+                ),
+            ));
+        }
+    }
+
+    // Every dialer is known at this point, so following the dialers either ends or comes back around
+    for (tag, outbound) in outbounds.iter() {
+        let mut chain = vec![tag.as_str()];
+        let mut next = outbound.dialer.as_deref();
+        while let Some(dialer) = next {
+            if chain.contains(&dialer) {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("outbound {} is dialed through itself", dialer),
+                ));
+            }
+            chain.push(dialer);
+            next = outbounds[dialer].dialer.as_deref();
+        }
+    }
+
+    Ok(())
+}
+
+/// Load the routing databases from their files, fails with InvalidInput if a database can't be loaded.
+fn load_databases(config: &RouterConfig) -> io::Result<(Option<Arc<GeoIp>>, Option<GeoSite>)> {
+    let invalid = |name: &str, path: &str, e: Error| {
+        Error::new(
+            ErrorKind::InvalidInput,
+            format!("failed to load {} database {}: {}", name, path, e),
+        )
+    };
+
+    let geoip = config
+        .geoip_database
+        .as_ref()
+        .map(|path| GeoIp::open(path).map_err(|e| invalid("GeoIP", path, e)))
+        .transpose()?
+        .map(Arc::new);
+
+    let geosite = config
+        .geosite_database
+        .as_ref()
+        .map(|path| GeoSite::open(path).map_err(|e| invalid("geosite", path, e)))
+        .transpose()?;
+
+    Ok((geoip, geosite))
+}
+
+/// Outbounds, groups and rules of the configuration, checked against each other.
+struct Routes<'a> {
+    outbounds: HashMap<String, &'a OutboundConfig>,
+    groups: HashMap<String, OutboundGroup>,
+    rules: Vec<Rule>,
+}
+
+/// Router owns the handlers of all the configured outbounds and selects one of them for each proxy request based on
+/// the routing rules. Rules can also refer to outbound groups, which pass the request on to one of their members.
+/// The rules are rebuilt and swapped as a whole when the routing databases are updated, so the requests are always
+/// routed by either the old or the new rules.
+pub struct Router {
+    rules: RwLock<Arc<Vec<Rule>>>,
+    handlers: HashMap<String, Arc<TcpHandler>>,
+    groups: HashMap<String, OutboundGroup>,
+    default: String,
+    config: Option<RouterConfig>,
+}
+
+impl Router {
+    /// Build the router shared by the whole process from the configuration.
+    pub fn init(config: &Config) -> io::Result<&'static Self> {
+        ROUTER.get_or_try_init(|| Self::new(config))
+    }
+
+    /// Fails with InvalidInput if the outbounds, the groups or the rules are invalid, see check.
+    pub fn new(config: &Config) -> io::Result<Self> {
+        let Routes {
+            outbounds,
+            groups,
+            rules,
+        } = Self::routes(config)?;
+
+        let mut handlers = HashMap::new();
+        for tag in outbounds.keys() {
+            build_handler(tag, &outbounds, &mut handlers)?;
+        }
+
+        Ok(Self {
+            rules: RwLock::new(Arc::new(rules)),
+            handlers,
+            groups,
+            default: config
+                .outbound
+                .tag
+                .clone()
+                .unwrap_or_else(|| DEFAULT_OUTBOUND_TAG.to_string()),
+            config: config.router.clone(),
+        })
+    }
+
+    /// Check the outbounds, the groups and the rules of the configuration like building the router does, without
+    /// building the handlers of the outbounds. Fails with InvalidInput if an outbound in outbounds has no tag, the
+    /// tags aren't unique, a dialer or a group is invalid, a routing database can't be loaded or a rule refers to an
+    /// unknown outbound.
+    pub fn check(config: &Config) -> io::Result<()> {
+        Self::routes(config).map(drop)
+    }
+
+    fn routes(config: &Config) -> io::Result<Routes<'_>> {
+        let default = config
+            .outbound
+            .tag
+            .clone()
+            .unwrap_or_else(|| DEFAULT_OUTBOUND_TAG.to_string());
+
+        let mut outbounds = HashMap::new();
+        outbounds.insert(default, &config.outbound);
+
+        for outbound in config.outbounds.iter().flatten() {
+            let tag = match &outbound.tag {
+                Some(tag) => tag.clone(),
+                None => {
+                    return Err(Error::new(
+                        ErrorKind::InvalidInput,
+                        "missing tag of the outbound in outbounds",
+                    ))
+                }
+            };
+
+            if outbounds.insert(tag.clone(), outbound).is_some() {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("duplicate outbound tag {}", tag),
+                ));
+            }
+        }
+
+        check_dialers(&outbounds)?;
+
+        let mut groups = HashMap::new();
+        for group in config.outbound_groups.iter().flatten() {
+            let group = OutboundGroup::new(group)?;
+
+            if outbounds.contains_key(group.tag()) || groups.contains_key(group.tag()) {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("duplicate outbound tag {}", group.tag()),
+                ));
+            }
+            if let Some(member) = group
+                .members()
+                .find(|member| !outbounds.contains_key(*member))
+            {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "outbound group {} refers to unknown outbound {}",
+                        group.tag(),
+                        member
+                    ),
+                ));
+            }
+
+            groups.insert(group.tag().to_string(), group);
+        }
+
+        let rules: Vec<Rule> = match &config.router {
+            Some(router) => {
+                if router.geoip_url.is_some() && router.geoip_database.is_none() {
+                    return Err(Error::new(
+                        ErrorKind::InvalidInput,
+                        "geoip_url requires geoip_database in the router config",
+                    ));
+                }
+                if router.geosite_url.is_some() && router.geosite_database.is_none() {
+                    return Err(Error::new(
+                        ErrorKind::InvalidInput,
+                        "geosite_url requires geosite_database in the router config",
+                    ));
+                }
+
+                // The geosite database is only needed while building the rules, the matchers keep the domains they use
+                let (geoip, geosite) = load_databases(router)?;
+                Self::build_rules(router, geoip.as_ref(), geosite.as_ref())?
+            }
+            None => Vec::new(),
+        };
+
+        for rule in rules.iter() {
+            if !outbounds.contains_key(&rule.outbound) && !groups.contains_key(&rule.outbound) {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("routing rule refers to unknown outbound {}", rule.outbound),
+                ));
+            }
+        }
+
+        Ok(Routes {
+            outbounds,
+            groups,
+            rules,
+        })
+    }
+
+    fn build_rules(
+        config: &RouterConfig,
+        geoip: Option<&Arc<GeoIp>>,
+        geosite: Option<&GeoSite>,
+    ) -> io::Result<Vec<Rule>> {
+        config
+            .rules
+            .iter()
+            .map(|rule| Rule::new(rule, geoip, geosite))
+            .collect()
+    }
+
+    /// Download the routing databases that have urls in the configuration, rebuild the rules from them and swap the
+    /// rules in. The databases without urls are reloaded from their files. The downloaded databases replace their
+    /// files so they are also used after restarting. The current rules are kept if any of the databases can't be
+    /// downloaded or loaded, or lacks the geosite categories used by the rules.
+    pub async fn update_databases(&self) -> io::Result<()> {
+        let result = self.try_update_databases().await;
+
+        match &result {
+            Ok(()) => {
+                info!("Updated routing databases");
+                metrics::increment("routing_database_updates_total", 1);
+            }
+            Err(e) => {
+                warn!("Failed to update routing databases: {}", e);
+                metrics::increment("routing_database_update_failures_total", 1);
+            }
+        }
+
+        result
+    }
+
+    async fn try_update_databases(&self) -> io::Result<()> {
+        let config = match &self.config {
+            Some(config) => config,
+            None => {
+                return Err(Error::new(
+                    ErrorKind::NotFound,
+                    "no router in the configuration",
+                ))
+            }
+        };
+
+        let geoip_data = match &config.geoip_url {
+            Some(url) => Some(update::download(url).await?),
+            None => None,
+        };
+        let geosite_data = match &config.geosite_url {
+            Some(url) => Some(update::download(url).await?),
+            None => None,
+        };
+
+        let geoip = match (&geoip_data, &config.geoip_database) {
+            (Some(data), _) => Some(Arc::new(GeoIp::from_bytes(data.clone())?)),
+            (None, Some(path)) => Some(Arc::new(GeoIp::open(path)?)),
+            (None, None) => None,
+        };
+        let geosite = match (&geosite_data, &config.geosite_database) {
+            (Some(data), _) => Some(GeoSite::decode(data)?),
+            (None, Some(path)) => Some(GeoSite::open(path)?),
+            (None, None) => None,
+        };
+
+        let rules = Self::build_rules(config, geoip.as_ref(), geosite.as_ref())?;
+
+        // Both urls require the database paths
+        if let (Some(data), Some(path)) = (&geoip_data, &config.geoip_database) {
+            update::replace_file(path, data)?;
+        }
+        if let (Some(data), Some(path)) = (&geosite_data, &config.geosite_database) {
+            update::replace_file(path, data)?;
+        }
+
+        *self.rules.write().unwrap() = Arc::new(rules);
+        Ok(())
+    }
+
+    /// Update the routing databases in the background every update_interval seconds, if any of them has a url.
+    pub fn start_database_updates(&'static self) {
+        let config = match &self.config {
+            Some(config) if config.geoip_url.is_some() || config.geosite_url.is_some() => config,
+            _ => return,
+        };
+
+        let period = Duration::from_secs(config.update_interval.unwrap_or(DEFAULT_UPDATE_INTERVAL));
+        tokio::spawn(async move {
+            // The databases were just loaded from their files, the first update happens after a full period
+            let mut ticker = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+            loop {
+                ticker.tick().await;
+                let _ = self.update_databases().await;
+            }
+        });
+    }
+
+    /// Start probing the members of the outbound groups in the background, the members of the select groups are
+    /// chosen by hand and not probed.
+    pub fn start_health_checks(&'static self) {
+        for group in self.groups.values() {
+            if group.group_type() != GroupType::SELECT {
+                tokio::spawn(group.run_health_checks(&self.handlers));
+            }
+        }
+    }
+
+    /// Start keeping the connection pools of the outbounds full in the background, for the outbounds that have one.
+    pub fn start_connection_pools(&'static self) {
+        for handler in self.handlers.values() {
+            tokio::spawn(handler.run_pool());
+        }
+    }
+
+    /// Mark the outbound with the tag as draining, or put it back in rotation, in all the groups it belongs to.
+    /// Returns the number of sessions the outbound still relays, None if there is no such outbound.
+    pub fn drain(&self, tag: &str, draining: bool) -> Option<usize> {
+        let handler = self.handlers.get(tag)?;
+        handler.drain().set_draining(draining);
+        for group in self.groups.values() {
+            group.set_draining(tag, draining);
+        }
+        Some(handler.drain().active())
+    }
+
+    /// Handler of the outbound with the tag, if there is one.
+    pub fn handler(&self, tag: &str) -> Option<&TcpHandler> {
+        self.handlers.get(tag).map(|handler| handler.as_ref())
+    }
+
+    /// Outbound group with the tag, if there is one.
+    pub fn group(&self, tag: &str) -> Option<&OutboundGroup> {
+        self.groups.get(tag)
+    }
+
+    /// All the outbound groups, sorted by tag.
+    pub fn groups(&self) -> Vec<&OutboundGroup> {
+        let mut groups: Vec<&OutboundGroup> = self.groups.values().collect();
+        groups.sort_by_key(|group| group.tag());
+        groups
+    }
+
+    /// Tag of the outbound selected for the request.
+    pub fn select(&self, context: &RouteContext) -> String {
+        self.select_with_resolution(context).0
+    }
+
+    /// Tag of the outbound selected for the request, along with where the rule selecting it resolves the domain names.
+    fn select_with_resolution(&self, context: &RouteContext) -> (String, Option<DomainResolution>) {
+        let rules = self.rules.read().unwrap().clone();
+        match rules.iter().find(|rule| rule.matches(context)) {
+            Some(rule) => (rule.outbound.clone(), rule.resolve),
+            None => (self.default.clone(), None),
+        }
+    }
+
+    /// Handler of the outbound selected for the request, resolving the outbound groups to their selected members.
+    pub fn route(&self, context: &RouteContext) -> &TcpHandler {
+        self.route_with_resolution(context).0
+    }
+
+    /// Handler of the outbound selected for the request like route, along with where the domain name of the request
+    /// is resolved, None if the rule leaves it to the outbound.
+    pub fn route_with_resolution(
+        &self,
+        context: &RouteContext,
+    ) -> (&TcpHandler, Option<DomainResolution>) {
+        let (mut tag, resolve) = self.select_with_resolution(context);
+        if let Some(group) = self.groups.get(&tag) {
+            tag = group.selected().to_string();
+        }
+
+        debug!(
+            "Routing request to {} through outbound {}",
+            context.request.addr_port, tag
+        );
+
+        // Safety: the outbounds of all the rules and groups are checked to exist when the router is built
+        (&self.handlers[&tag], resolve)
+    }
+}
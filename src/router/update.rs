@@ -0,0 +1,211 @@
+use crate::config::base::OutboundTlsConfig;
+use crate::config::tls::make_client_config;
+use crate::protocol::common::addr::{IpAddrPort, IpAddress};
+use crate::protocol::common::stream::BoxedStream;
+use crate::proxy::socket::SocketOptions;
+
+use http::header::{CONTENT_TYPE, HOST, LOCATION};
+use http::{Method, Request, Response, StatusCode, Uri};
+use hyper::body::HttpBody;
+use hyper::client::conn;
+use hyper::Body;
+use rustls::ServerName;
+use std::io::{Error, ErrorKind, Result};
+use std::path::Path;
+use std::time::Duration;
+use tokio_rustls::TlsConnector;
+
+/// Most redirects followed while downloading a database
+const MAX_REDIRECTS: usize = 5;
+
+/// Longest time a download may take, redirects included
+pub const DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Largest content accepted from a download, the databases are far smaller
+pub const MAX_DOWNLOAD_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Download the content at the http or https url, following the redirects. Fails if the download takes longer than
+/// DOWNLOAD_TIMEOUT or the content is larger than MAX_DOWNLOAD_SIZE.
+pub async fn download(url: &str) -> Result<Vec<u8>> {
+    match tokio::time::timeout(DOWNLOAD_TIMEOUT, fetch(url)).await {
+        Ok(result) => result,
+        Err(_) => Err(Error::new(
+            ErrorKind::TimedOut,
+            format!("Timed out downloading {}", url),
+        )),
+    }
+}
+
+async fn fetch(url: &str) -> Result<Vec<u8>> {
+    let mut uri: Uri = match url.parse() {
+        Ok(uri) => uri,
+        Err(e) => return Err(Error::new(ErrorKind::InvalidInput, e)),
+    };
+
+    for _ in 0..=MAX_REDIRECTS {
+        let response = send(&uri, Method::GET, None, Vec::new()).await?;
+        let status = response.status();
+
+        if status.is_redirection() {
+            let location = match response
+                .headers()
+                .get(LOCATION)
+                .and_then(|location| location.to_str().ok())
+            {
+                Some(location) => location,
+                None => return Err(Error::other(format!("{} redirected without location", uri))),
+            };
+            uri = redirect_uri(&uri, location)?;
+            continue;
+        }
+
+        if status != StatusCode::OK {
+            return Err(Error::other(format!("{} responded with {}", uri, status)));
+        }
+
+        return read_body(&uri, response.into_body()).await;
+    }
+
+    Err(Error::other(format!(
+        "Too many redirects downloading {}",
+        url
+    )))
+}
+
+/// Read the whole body of the response, fails as soon as it turns out to be larger than MAX_DOWNLOAD_SIZE.
+async fn read_body(uri: &Uri, mut body: Body) -> Result<Vec<u8>> {
+    let too_large = || {
+        Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("{} is larger than {} bytes", uri, MAX_DOWNLOAD_SIZE),
+        ))
+    };
+
+    // The length announced by the server is checked before reading anything
+    if body.size_hint().lower() > MAX_DOWNLOAD_SIZE {
+        return too_large();
+    }
+
+    let mut data = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(Error::other)?;
+        if (data.len() + chunk.len()) as u64 > MAX_DOWNLOAD_SIZE {
+            return too_large();
+        }
+        data.extend_from_slice(&chunk);
+    }
+
+    Ok(data)
+}
+
+/// Send the data to the http or https url in a POST request, fails unless the server responds with success.
+pub async fn upload(url: &str, content_type: &str, data: Vec<u8>) -> Result<()> {
+    let uri: Uri = match url.parse() {
+        Ok(uri) => uri,
+        Err(e) => return Err(Error::new(ErrorKind::InvalidInput, e)),
+    };
+
+    let status = send(&uri, Method::POST, Some(content_type), data)
+        .await?
+        .status();
+    match status.is_success() {
+        true => Ok(()),
+        false => Err(Error::other(format!("{} responded with {}", uri, status))),
+    }
+}
+
+/// Replace the file with the data, the data is written next to the file first so readers never see a partial file.
+pub fn replace_file(path: &str, data: &[u8]) -> Result<()> {
+    let temp_path = format!("{}.tmp", path);
+    std::fs::write(&temp_path, data)?;
+    std::fs::rename(&temp_path, Path::new(path))
+}
+
+/// Send a request with the method and the body to the url over a new connection.
+async fn send(
+    uri: &Uri,
+    method: Method,
+    content_type: Option<&str>,
+    body: Vec<u8>,
+) -> Result<Response<Body>> {
+    let https = match uri.scheme_str() {
+        Some("https") => true,
+        Some("http") => false,
+        _ => {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("Unsupported url {}", uri),
+            ))
+        }
+    };
+    let (host, authority) = match (uri.host(), uri.authority()) {
+        (Some(host), Some(authority)) => (host.trim_matches(|c| c == '[' || c == ']'), authority),
+        _ => {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("Missing host in url {}", uri),
+            ))
+        }
+    };
+    let port = uri.port_u16().unwrap_or(if https { 443 } else { 80 });
+
+    let address = IpAddrPort::new(IpAddress::from_host(host), port)
+        .resolve()
+        .await?;
+    let stream = SocketOptions::default().connect(address).await?;
+
+    let stream: BoxedStream = if https {
+        let config = make_client_config(&OutboundTlsConfig {
+            host_name: host.to_string(),
+            allow_insecure: false,
+            fingerprint: None,
+        });
+        let server_name = match ServerName::try_from(host) {
+            Ok(name) => name,
+            Err(e) => return Err(Error::new(ErrorKind::InvalidInput, e)),
+        };
+        Box::new(
+            TlsConnector::from(config)
+                .connect(server_name, stream)
+                .await?,
+        )
+    } else {
+        Box::new(stream)
+    };
+
+    let (mut sender, connection) = match conn::handshake(stream).await {
+        Ok(handshake) => handshake,
+        Err(e) => return Err(Error::other(e)),
+    };
+    tokio::spawn(connection);
+
+    let mut request = Request::builder()
+        .method(method)
+        .uri(uri.path_and_query().map_or("/", |path| path.as_str()))
+        .header(HOST, authority.as_str());
+    if let Some(content_type) = content_type {
+        request = request.header(CONTENT_TYPE, content_type);
+    }
+    let request = match request.body(Body::from(body)) {
+        Ok(request) => request,
+        Err(e) => return Err(Error::new(ErrorKind::InvalidInput, e)),
+    };
+
+    sender.send_request(request).await.map_err(Error::other)
+}
+
+/// Url the response redirects to, the location may be relative to the requested url.
+fn redirect_uri(uri: &Uri, location: &str) -> Result<Uri> {
+    if location.starts_with('/') {
+        let mut parts = uri.clone().into_parts();
+        parts.path_and_query = match location.parse() {
+            Ok(path) => Some(path),
+            Err(e) => return Err(Error::new(ErrorKind::InvalidData, e)),
+        };
+        Uri::from_parts(parts).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+    } else {
+        location
+            .parse()
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e))
+    }
+}
@@ -0,0 +1,72 @@
+use crate::config::base::TracingConfig;
+use crate::metrics;
+
+use log::{info, warn};
+use opentelemetry::sdk::trace::{self, Sampler, Tracer};
+use opentelemetry::sdk::Resource;
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::WithExportConfig;
+use std::io::{Error, Result};
+use tracing::Subscriber;
+use tracing_subscriber::filter::filter_fn;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::{Layer, Registry};
+
+/// Default name of the process in the traces
+const DEFAULT_SERVICE_NAME: &str = "trojan-rust";
+
+/// Sampler of the traces. Each span without a parent starts a trace, which is sampled at the sample rate, and the
+/// spans within it follow the decision of their parent. The sample rate is checked by the parser.
+pub fn sampler(sample_rate: f64) -> Sampler {
+    Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(sample_rate)))
+}
+
+/// Subscriber recording the spans of the process with the tracer, the ones of the connections, the stages of the
+/// requests and the relays they run in. Only the spans of this crate are recorded, the events are left to the logs. A
+/// span named by its otel.name field is exported under that name.
+pub fn subscriber(tracer: Tracer) -> impl Subscriber + Send + Sync {
+    let filter =
+        filter_fn(|metadata| metadata.is_span() && metadata.target().starts_with("trojan_rust"));
+    Registry::default().with(
+        tracing_opentelemetry::layer()
+            .with_tracer(tracer)
+            .with_filter(filter),
+    )
+}
+
+/// Export the spans of the process to the collector of the configuration with OTLP over HTTP, in batches sent in the
+/// background. Fails if the exporter can't be built, warns if another subscriber was set already.
+pub fn start(config: &TracingConfig) -> Result<()> {
+    let service_name = config
+        .service_name
+        .clone()
+        .unwrap_or_else(|| DEFAULT_SERVICE_NAME.to_string());
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .http()
+                .with_endpoint(&config.endpoint),
+        )
+        .with_trace_config(
+            trace::config()
+                .with_sampler(sampler(config.sample_rate.unwrap_or(1.0)))
+                .with_resource(Resource::new([KeyValue::new("service.name", service_name)])),
+        )
+        .install_batch(opentelemetry::runtime::Tokio)
+        .map_err(Error::other)?;
+
+    if let Err(e) = opentelemetry::global::set_error_handler(|e| {
+        warn!("Failed to export traces: {}", e);
+        metrics::increment("tracing_export_failures_total", 1);
+    }) {
+        warn!("Failed to set up the handler of the tracing errors: {}", e);
+    }
+    if let Err(e) = tracing::subscriber::set_global_default(subscriber(tracer)) {
+        warn!("Failed to set up tracing: {}", e);
+        return Ok(());
+    }
+
+    info!("Exporting traces to {}", config.endpoint);
+    Ok(())
+}
@@ -1,4 +1,5 @@
 pub mod grpc_stream;
+pub mod websocket;
 
 pub mod grpc_transport {
     tonic::include_proto!("trojan_rust.transport.grpc");
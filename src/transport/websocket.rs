@@ -0,0 +1,162 @@
+use crate::config::base::InboundWebSocketConfig;
+
+use futures::{ready, Sink, Stream};
+use std::io::{self, Error, ErrorKind};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::WebSocketStream;
+
+/// Upper bound of the HTTP request head read while looking for a WebSocket upgrade
+const MAX_REQUEST_HEAD_SIZE: usize = 8192;
+
+/// Maximum number of headers parsed from the HTTP request head
+const MAX_HEADERS: usize = 64;
+
+/// Read the rest of the HTTP request head whose beginning is already in buf, and check whether it asks to upgrade to
+/// WebSocket on the configured path and host. The bytes read are appended to buf, so that requests for anything else
+/// can be replayed to the fallback.
+pub async fn read_upgrade_request<T: AsyncRead + Unpin>(
+    stream: &mut T,
+    buf: &mut Vec<u8>,
+    config: &InboundWebSocketConfig,
+) -> io::Result<bool> {
+    let mut chunk = vec![0u8; 1024];
+
+    while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+        if buf.len() >= MAX_REQUEST_HEAD_SIZE {
+            return Ok(false);
+        }
+
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                "Connection closed before HTTP request head completes",
+            ));
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut request = httparse::Request::new(&mut headers);
+    if request.parse(buf).is_err() {
+        return Ok(false);
+    }
+
+    let path = request.path.unwrap_or_default();
+    let path = path.split('?').next().unwrap_or_default();
+    if request.method != Some("GET") || path != config.path {
+        return Ok(false);
+    }
+
+    let header = |name: &str| {
+        request
+            .headers
+            .iter()
+            .find(|h| h.name.eq_ignore_ascii_case(name))
+            .and_then(|h| std::str::from_utf8(h.value).ok())
+    };
+
+    if !matches!(header("upgrade"), Some(v) if v.eq_ignore_ascii_case("websocket")) {
+        return Ok(false);
+    }
+
+    // The port is left out of the comparison as CDNs don't agree on whether to keep it
+    let host = header("host").map(|v| v.rsplit_once(':').map_or(v, |(name, _)| name));
+    match &config.host {
+        Some(expected) => Ok(matches!(host, Some(v) if v.eq_ignore_ascii_case(expected))),
+        None => Ok(true),
+    }
+}
+
+/// Complete the WebSocket handshake on the stream, which replays the upgrade request, and carry the bytes of the
+/// connection in binary messages afterwards.
+pub async fn accept<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: S,
+) -> io::Result<WebSocketByteStream<S>> {
+    match tokio_tungstenite::accept_async(stream).await {
+        Ok(inner) => Ok(WebSocketByteStream::new(inner)),
+        Err(e) => Err(Error::new(ErrorKind::InvalidData, e)),
+    }
+}
+
+/// Byte stream carried by the binary messages of a WebSocket connection. Text messages are treated as bytes as well,
+/// ping and pong are answered by the WebSocket stream itself, and a close message ends the byte stream.
+pub struct WebSocketByteStream<S> {
+    inner: WebSocketStream<S>,
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl<S> WebSocketByteStream<S> {
+    #[inline]
+    pub fn new(inner: WebSocketStream<S>) -> Self {
+        Self {
+            inner,
+            buf: Vec::new(),
+            pos: 0,
+        }
+    }
+}
+
+fn to_io_error(e: tokio_tungstenite::tungstenite::Error) -> Error {
+    match e {
+        tokio_tungstenite::tungstenite::Error::Io(e) => e,
+        e => Error::new(ErrorKind::ConnectionReset, e),
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for WebSocketByteStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        while this.pos >= this.buf.len() {
+            match ready!(Pin::new(&mut this.inner).poll_next(cx)) {
+                Some(Ok(Message::Binary(data))) => this.buf = data,
+                Some(Ok(Message::Text(text))) => this.buf = text.into_bytes(),
+                Some(Ok(Message::Close(_))) | None => return Poll::Ready(Ok(())),
+                Some(Ok(_)) => continue,
+                Some(Err(e)) => return Poll::Ready(Err(to_io_error(e))),
+            }
+            this.pos = 0;
+        }
+
+        let len = std::cmp::min(this.buf.len() - this.pos, buf.remaining());
+        buf.put_slice(&this.buf[this.pos..this.pos + len]);
+        this.pos += len;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for WebSocketByteStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let inner = &mut self.get_mut().inner;
+        ready!(Pin::new(&mut *inner).poll_ready(cx)).map_err(to_io_error)?;
+        match Pin::new(inner).start_send(Message::Binary(buf.to_vec())) {
+            Ok(()) => Poll::Ready(Ok(buf.len())),
+            Err(e) => Poll::Ready(Err(to_io_error(e))),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner)
+            .poll_flush(cx)
+            .map_err(to_io_error)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner)
+            .poll_close(cx)
+            .map_err(to_io_error)
+    }
+}
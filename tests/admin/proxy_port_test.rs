@@ -0,0 +1,91 @@
+use crate::proxy::quic_server_test::SECRET;
+
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+use tonic::metadata::MetadataValue;
+use tonic::{Code, Request};
+use trojan_rust::admin::admin_api::admin_service_client::AdminServiceClient;
+use trojan_rust::admin::admin_api::GetBuildInfoRequest;
+use trojan_rust::admin::server::{is_http2, AdminHandoff, ProxyPortAdmin};
+use trojan_rust::auth::secret::StaticAuthenticator;
+use trojan_rust::config::base::{AdminConfig, InboundConfig};
+use trojan_rust::proxy::deadline::Deadline;
+use trojan_rust::proxy::tcp::acceptor::TcpAcceptor;
+
+#[tokio::test]
+async fn test_admin_on_proxy_port() {
+    // The users are shared by the whole process, the inbound has the secret of the other test initializing them
+    let inbound: InboundConfig = serde_json::from_str(&format!(
+        r#"{{ "mode": "TCP", "protocol": "TROJAN", "address": "127.0.0.1", "port": 443, "secret": "{}" }}"#,
+        SECRET
+    ))
+    .unwrap();
+    let admin_config: AdminConfig = serde_json::from_str(
+        r#"{ "address": "127.0.0.1", "port": 8080, "psk": "hunter2", "proxy_port": true }"#,
+    )
+    .unwrap();
+    ProxyPortAdmin::init(
+        &admin_config,
+        StaticAuthenticator::init(&inbound).unwrap(),
+        None,
+    )
+    .unwrap();
+    let acceptor = TcpAcceptor::init(&inbound).unwrap();
+
+    // The acceptor hands the connections failing the trojan handshake over to the admin API
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let (handoffs, mut handed_off) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        loop {
+            let (socket, source) = listener.accept().await.unwrap();
+            let handoffs = handoffs.clone();
+            tokio::spawn(async move {
+                let deadline = Deadline::after(Duration::from_secs(5));
+                if let Err(e) = acceptor.accept(socket, source, deadline).await {
+                    let _ = handoffs.send(AdminHandoff::is(&e));
+                }
+            });
+        }
+    });
+
+    let mut client = AdminServiceClient::connect(format!("http://{}", address))
+        .await
+        .unwrap();
+    let refused = client
+        .get_build_info(GetBuildInfoRequest {})
+        .await
+        .unwrap_err();
+    assert_eq!(refused.code(), Code::Unauthenticated);
+
+    let mut request = Request::new(GetBuildInfoRequest {});
+    request.metadata_mut().insert(
+        "authorization",
+        MetadataValue::from_static("Bearer hunter2"),
+    );
+    let info = client.get_build_info(request).await.unwrap().into_inner();
+    assert_eq!(info.version, env!("CARGO_PKG_VERSION"));
+
+    // The admin API is only built with the server feature
+    assert!(info.features.iter().any(|feature| feature == "server"));
+    assert_eq!(
+        info.features.iter().any(|feature| feature == "client"),
+        cfg!(feature = "client")
+    );
+
+    // Closing the connection ends it as an admin handoff rather than as a failed proxy connection
+    drop(client);
+    assert!(handed_off.recv().await.unwrap());
+}
+
+#[tokio::test]
+async fn test_is_http2() {
+    let mut rest: &[u8] = b"TP/2.0\r\n\r\nSM\r\n\r\n";
+    let mut buf = b"PRI * HT".to_vec();
+    assert!(is_http2(&mut rest, &mut buf).await.unwrap());
+
+    let mut rest: &[u8] = b"";
+    let mut buf = b"GET / HTTP/1.1\r\n".to_vec();
+    assert!(!is_http2(&mut rest, &mut buf).await.unwrap());
+}
@@ -0,0 +1,62 @@
+use async_trait::async_trait;
+use std::io::Result;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use trojan_rust::auth::cache::CachedAuthenticator;
+use trojan_rust::auth::{Authenticator, User};
+
+/// Authenticator that knows a single credential and counts how many times it is consulted
+struct CountingAuthenticator {
+    calls: Arc<AtomicUsize>,
+}
+
+#[async_trait]
+impl Authenticator for CountingAuthenticator {
+    async fn authenticate(&self, credential: &[u8]) -> Result<Option<User>> {
+        self.calls.fetch_add(1, Ordering::SeqCst);
+        Ok((credential == b"known").then(|| User {
+            name: "known".to_string(),
+            expires_at: None,
+        }))
+    }
+}
+
+#[tokio::test]
+async fn test_cached_authenticator() {
+    let calls = Arc::new(AtomicUsize::new(0));
+    let authenticator = CachedAuthenticator::new(
+        Box::new(CountingAuthenticator {
+            calls: calls.clone(),
+        }),
+        Duration::from_secs(60),
+    );
+
+    for _ in 0..3 {
+        let user = authenticator.authenticate(b"known").await.unwrap();
+        assert_eq!(user.unwrap().name, "known");
+        assert!(authenticator
+            .authenticate(b"unknown")
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    assert_eq!(calls.load(Ordering::SeqCst), 2);
+}
+
+#[tokio::test]
+async fn test_cached_authenticator_expires() {
+    let calls = Arc::new(AtomicUsize::new(0));
+    let authenticator = CachedAuthenticator::new(
+        Box::new(CountingAuthenticator {
+            calls: calls.clone(),
+        }),
+        Duration::ZERO,
+    );
+
+    authenticator.authenticate(b"known").await.unwrap();
+    authenticator.authenticate(b"known").await.unwrap();
+
+    assert_eq!(calls.load(Ordering::SeqCst), 2);
+}
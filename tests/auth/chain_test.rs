@@ -0,0 +1,152 @@
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+use trojan_rust::auth::expiry::ExpiryAuthorizer;
+use trojan_rust::auth::ip::IpAuthorizer;
+use trojan_rust::auth::port::PortAuthorizer;
+use trojan_rust::auth::secret::{secret_hex, StaticAuthenticator};
+use trojan_rust::auth::AuthChain;
+use trojan_rust::config::base::InboundConfig;
+use trojan_rust::protocol::common::addr::IpAddress;
+use trojan_rust::protocol::common::atype::Atype;
+use trojan_rust::protocol::common::command::Command;
+use trojan_rust::protocol::common::request::{InboundRequest, TransportProtocol};
+use trojan_rust::proxy::base::SupportedProtocols;
+
+fn inbound_config() -> InboundConfig {
+    serde_json::from_str(
+        r#"{
+            "mode": "TCP",
+            "protocol": "TROJAN",
+            "address": "0.0.0.0",
+            "port": 443,
+            "secret": "123123",
+            "users": [
+                { "name": "alice", "secret": "alice-secret" },
+                { "name": "bob", "secret": "bob-secret", "expires_at": "2020-01-01T00:00:00Z" },
+                { "name": "carol", "secret": "carol-secret", "expires_at": "2999-01-01T00:00:00Z" }
+            ],
+            "allowed_ips": ["10.0.0.0/8", "127.0.0.1"]
+        }"#,
+    )
+    .unwrap()
+}
+
+fn build_chain(inbound: &InboundConfig) -> AuthChain {
+    let mut chain = AuthChain::new();
+    chain.add_authenticator(Box::new(StaticAuthenticator::new(inbound).unwrap()));
+    chain.add_authorizer(Box::new(ExpiryAuthorizer));
+    chain.add_authorizer(Box::new(IpAuthorizer::new(inbound).unwrap().unwrap()));
+    chain
+}
+
+fn request() -> InboundRequest {
+    InboundRequest::new(
+        Atype::IPv4,
+        IpAddress::from_u32(0x7f000001),
+        Command::Connect,
+        80,
+        TransportProtocol::TCP,
+        SupportedProtocols::TROJAN,
+    )
+}
+
+#[tokio::test]
+async fn test_auth_chain_identifies_users() {
+    let chain = build_chain(&inbound_config());
+    let source: SocketAddr = "10.1.2.3:5000".parse().unwrap();
+
+    let user = chain
+        .authenticate(&secret_hex("123123"), Some(source), &request())
+        .await
+        .unwrap();
+    assert_eq!(user.name, "default");
+
+    let user = chain
+        .authenticate(&secret_hex("alice-secret"), Some(source), &request())
+        .await
+        .unwrap();
+    assert_eq!(user.name, "alice");
+
+    let err = chain
+        .authenticate(&secret_hex("wrong"), Some(source), &request())
+        .await
+        .err()
+        .unwrap();
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+}
+
+#[tokio::test]
+async fn test_auth_chain_rejects_unknown_source() {
+    let chain = build_chain(&inbound_config());
+    let source: SocketAddr = "192.168.1.1:5000".parse().unwrap();
+
+    let err = chain
+        .authenticate(&secret_hex("123123"), Some(source), &request())
+        .await
+        .err()
+        .unwrap();
+    assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+}
+
+#[tokio::test]
+async fn test_auth_chain_rejects_expired_user() {
+    let chain = build_chain(&inbound_config());
+    let source: SocketAddr = "10.1.2.3:5000".parse().unwrap();
+
+    let err = chain
+        .authenticate(&secret_hex("bob-secret"), Some(source), &request())
+        .await
+        .err()
+        .unwrap();
+    assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+
+    let user = chain
+        .authenticate(&secret_hex("carol-secret"), Some(source), &request())
+        .await
+        .unwrap();
+    assert_eq!(user.name, "carol");
+}
+
+#[tokio::test]
+async fn test_auth_chain_restricts_destination_ports() {
+    let mut inbound = inbound_config();
+    inbound.destination_ports =
+        serde_json::from_str(r#"{ "allow": [80, "1000-2000"], "deny": [1500] }"#).unwrap();
+    let mut chain = build_chain(&inbound);
+    chain.add_authorizer(Box::new(PortAuthorizer::new(&inbound).unwrap().unwrap()));
+    let source: SocketAddr = "10.1.2.3:5000".parse().unwrap();
+
+    for (port, allowed) in [(80, true), (1200, true), (1500, false), (25, false)] {
+        let mut request = request();
+        request.addr_port.port = port;
+        let result = chain
+            .authenticate(&secret_hex("alice-secret"), Some(source), &request)
+            .await;
+        match allowed {
+            true => assert!(result.is_ok(), "port {} refused", port),
+            false => assert_eq!(result.err().unwrap().kind(), ErrorKind::PermissionDenied),
+        }
+    }
+}
+
+#[test]
+fn test_port_policy_checks_dispatched_requests() {
+    let mut inbound = inbound_config();
+    inbound.destination_ports = serde_json::from_str(r#"{ "allow": [80, 443, 53] }"#).unwrap();
+    let policy = PortAuthorizer::new(&inbound).unwrap().unwrap();
+
+    // SOCKS requests skip the trojan authorization chain, they are checked once dispatched
+    let mut request = request();
+    request.proxy_protocol = SupportedProtocols::SOCKS;
+    request.addr_port.port = 8080;
+    assert_eq!(
+        policy.check_request(&request, "tcp").unwrap_err().kind(),
+        ErrorKind::PermissionDenied
+    );
+
+    // The datagrams of UDP requests are checked one by one by their sessions instead
+    request.transport_protocol = TransportProtocol::UDP;
+    assert!(policy.check_request(&request, "tcp").is_ok());
+    assert!(policy.check(53, "udp").is_ok());
+    assert!(policy.check(5353, "udp").is_err());
+}
@@ -0,0 +1,97 @@
+use std::io::ErrorKind;
+use std::time::{Duration, UNIX_EPOCH};
+use trojan_rust::auth::secret::{parse_expires_at, secret_hex, StaticAuthenticator};
+use trojan_rust::auth::Authenticator;
+use trojan_rust::config::base::InboundConfig;
+
+fn inbound_config() -> InboundConfig {
+    serde_json::from_str(
+        r#"{
+            "mode": "TCP",
+            "protocol": "TROJAN",
+            "address": "0.0.0.0",
+            "port": 443,
+            "secret": "123123"
+        }"#,
+    )
+    .unwrap()
+}
+
+#[tokio::test]
+async fn test_add_and_remove_users() {
+    let authenticator = StaticAuthenticator::new(&inbound_config()).unwrap();
+
+    authenticator
+        .add_user("alice", "first-secret", None)
+        .unwrap();
+    let user = authenticator
+        .authenticate(&secret_hex("first-secret"))
+        .await
+        .unwrap();
+    assert_eq!(user.unwrap().name, "alice");
+
+    // Adding the user again replaces the secret
+    authenticator
+        .add_user("alice", "second-secret", None)
+        .unwrap();
+    assert!(authenticator
+        .authenticate(&secret_hex("first-secret"))
+        .await
+        .unwrap()
+        .is_none());
+    assert!(authenticator
+        .authenticate(&secret_hex("second-secret"))
+        .await
+        .unwrap()
+        .is_some());
+
+    // The secret of another user isn't taken over
+    let e = authenticator
+        .add_user("bob", "second-secret", None)
+        .unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::AlreadyExists);
+    let user = authenticator
+        .authenticate(&secret_hex("second-secret"))
+        .await
+        .unwrap();
+    assert_eq!(user.unwrap().name, "alice");
+
+    let names: Vec<String> = authenticator.users().into_iter().map(|u| u.name).collect();
+    assert_eq!(names, vec!["alice", "default"]);
+
+    assert!(authenticator.remove_user("alice"));
+    assert!(!authenticator.remove_user("alice"));
+    assert!(authenticator
+        .authenticate(&secret_hex("second-secret"))
+        .await
+        .unwrap()
+        .is_none());
+}
+
+#[test]
+fn test_parse_expires_at() {
+    let new_year = UNIX_EPOCH + Duration::from_secs(1672531200);
+    for timestamp in [
+        "2023-01-01T00:00:00Z",
+        "2023-01-01 00:00:00",
+        "2023-01-01T08:00:00+08:00",
+        "2022-12-31T19:30:00-04:30",
+        "2023-01-01T00:00:00+00:00",
+    ] {
+        assert_eq!(
+            parse_expires_at(timestamp).unwrap(),
+            new_year,
+            "{}",
+            timestamp
+        );
+    }
+
+    for timestamp in [
+        "2023-01-01T00:00:00+25:00",
+        "2023-01-01T00:00:00+0800",
+        "tomorrow",
+    ] {
+        let e = parse_expires_at(timestamp).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidInput, "{}", timestamp);
+    }
+}
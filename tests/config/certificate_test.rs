@@ -0,0 +1,72 @@
+use ring::rand::SystemRandom;
+use ring::signature::Ed25519KeyPair;
+use rustls::PrivateKey;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use trojan_rust::config::certificate::check_certificate;
+use trojan_rust::config::tls::{load_certs, load_private_key};
+
+const DAY: u64 = 24 * 60 * 60;
+
+#[test]
+fn test_check_certificate() {
+    let certs = load_certs("./config/cert.pem").unwrap();
+    let key = load_private_key("./config/key.pem").unwrap();
+    let not_after = UNIX_EPOCH + Duration::from_secs(1658765839);
+    let warning = Duration::from_secs(14 * DAY);
+
+    // The self-signed certificate matching its key has nothing to report until it nears expiry
+    let check = check_certificate(
+        &certs,
+        &key,
+        warning,
+        not_after - Duration::from_secs(30 * DAY),
+    )
+    .unwrap();
+    assert_eq!(check.not_after, not_after);
+    assert_eq!(check.expiry_days, 30);
+    assert!(check.warnings.is_empty(), "{:?}", check.warnings);
+
+    let check = check_certificate(
+        &certs,
+        &key,
+        warning,
+        not_after - Duration::from_secs(10 * DAY),
+    )
+    .unwrap();
+    assert_eq!(check.expiry_days, 10);
+    assert_eq!(check.warnings, ["certificate expires in 10 days"]);
+
+    let check = check_certificate(&certs, &key, warning, SystemTime::now()).unwrap();
+    assert_eq!(check.expiry_days, 0);
+    assert_eq!(check.warnings, ["certificate has expired"]);
+}
+
+#[test]
+fn test_check_certificate_mismatches() {
+    let certs = load_certs("./config/cert.pem").unwrap();
+    let not_after = UNIX_EPOCH + Duration::from_secs(1658765839);
+    let now = not_after - Duration::from_secs(30 * DAY);
+    let warning = Duration::from_secs(14 * DAY);
+
+    let other_key = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+    let check = check_certificate(
+        &certs,
+        &PrivateKey(other_key.as_ref().to_vec()),
+        warning,
+        now,
+    )
+    .unwrap();
+    assert_eq!(
+        check.warnings,
+        ["private key doesn't match the certificate"]
+    );
+
+    // A chain that doesn't lead to a public root
+    let key = load_private_key("./config/key.pem").unwrap();
+    let chain = vec![certs[0].clone(), certs[0].clone()];
+    let check = check_certificate(&chain, &key, warning, now).unwrap();
+    assert_eq!(check.warnings.len(), 1);
+    assert!(check.warnings[0].starts_with("certificate chain"));
+
+    assert!(check_certificate(&[], &key, warning, now).is_err());
+}
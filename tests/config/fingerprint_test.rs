@@ -0,0 +1,62 @@
+use std::collections::HashSet;
+use std::time::{Duration, UNIX_EPOCH};
+use trojan_rust::config::base::{FingerprintConfig, FingerprintProfile, OutboundTlsConfig};
+use trojan_rust::config::fingerprint::Fingerprints;
+
+fn tls_config(fingerprint: Option<FingerprintConfig>) -> OutboundTlsConfig {
+    OutboundTlsConfig {
+        host_name: String::from("example.com"),
+        allow_insecure: true,
+        fingerprint,
+    }
+}
+
+#[test]
+fn test_fingerprint_rotation() {
+    assert!(Fingerprints::new(&tls_config(None)).unwrap().is_none());
+
+    let fingerprints = Fingerprints::new(&tls_config(Some(FingerprintConfig {
+        profiles: None,
+        rotation_interval: Some(60),
+    })))
+    .unwrap()
+    .unwrap();
+
+    // The profile stays the same within a rotation interval
+    let start = UNIX_EPOCH + Duration::from_secs(1_800_000_000);
+    let profile = fingerprints.profile_at(start);
+    assert_eq!(
+        fingerprints.profile_at(start + Duration::from_secs(59)),
+        profile
+    );
+
+    // Every profile is used sooner or later
+    let profiles: HashSet<FingerprintProfile> = (0..200)
+        .map(|period| fingerprints.profile_at(start + Duration::from_secs(60 * period)))
+        .collect();
+    assert_eq!(profiles.len(), 3);
+
+    let config = fingerprints.config_at(start);
+    assert_eq!(
+        config.alpn_protocols,
+        vec![b"h2".to_vec(), b"http/1.1".to_vec()]
+    );
+}
+
+#[test]
+fn test_fingerprint_single_profile() {
+    let fingerprints = Fingerprints::new(&tls_config(Some(FingerprintConfig {
+        profiles: Some(vec![
+            FingerprintProfile::FIREFOX,
+            FingerprintProfile::FIREFOX,
+        ]),
+        rotation_interval: None,
+    })))
+    .unwrap()
+    .unwrap();
+
+    for hour in 0..24 {
+        let time = UNIX_EPOCH + Duration::from_secs(3600 * hour);
+        assert_eq!(fingerprints.profile_at(time), FingerprintProfile::FIREFOX);
+    }
+}
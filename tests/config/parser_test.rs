@@ -0,0 +1,337 @@
+use serde_json::{json, Value};
+use std::io::ErrorKind;
+use trojan_rust::config::base::Config;
+use trojan_rust::config::parser::{
+    check_admin, check_bandwidth, check_dns, check_features, check_inbound, check_metrics,
+    check_outbounds, check_relay, check_router, check_runtime, check_tracing,
+};
+
+/// Configuration of a TCP inbound and a direct outbound, with the fields of the patch merged into it.
+fn config(patch: Value) -> Config {
+    fn merge(base: &mut Value, patch: Value) {
+        match (base, patch) {
+            (Value::Object(base), Value::Object(patch)) => {
+                for (key, value) in patch {
+                    merge(base.entry(key).or_insert(Value::Null), value);
+                }
+            }
+            (base, patch) => *base = patch,
+        }
+    }
+
+    let mut config = json!({
+        "inbound": { "mode": "TCP", "protocol": "TROJAN", "address": "127.0.0.1", "port": 443 },
+        "outbound": { "mode": "DIRECT", "protocol": "DIRECT" }
+    });
+    merge(&mut config, patch);
+    serde_json::from_value(config).unwrap()
+}
+
+#[test]
+fn test_check_admin() {
+    let admin = |psk: &str| {
+        config(
+            json!({ "admin": { "address": "127.0.0.1", "port": 9090, "psk": psk, "proxy_port": true } }),
+        )
+    };
+    assert!(check_admin(&config(json!({}))).is_ok());
+    assert!(check_admin(&admin("hunter2")).is_ok());
+
+    let err = check_admin(&admin("")).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    assert!(err.to_string().contains("psk"));
+}
+
+#[test]
+fn test_check_bandwidth() {
+    assert!(check_bandwidth(&config(json!({ "bandwidth": { "mbps": 100 } }))).is_ok());
+
+    for patch in [
+        json!({ "bandwidth": { "mbps": 0 } }),
+        json!({ "inbound": { "bandwidth": { "upload": 0 } } }),
+        json!({ "inbound": { "users": [{ "name": "alice", "secret": "a", "bandwidth_class": "basic" }] } }),
+    ] {
+        let err = check_bandwidth(&config(patch)).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    }
+}
+
+#[test]
+fn test_check_dns() {
+    let servers = |servers: Value| config(json!({ "dns": { "servers": servers } }));
+    assert!(check_dns(&servers(json!([
+        "1.1.1.1",
+        "tls://dns.example",
+        "https://1.1.1.1/dns-query"
+    ])))
+    .is_ok());
+
+    for patch in [
+        json!(["quic://1.1.1.1"]),
+        json!(["dns.example"]),
+        json!(["https://1.1.1.1/resolve"]),
+        json!([{ "address": "1.1.1.1", "server_name": "one.one.one.one" }]),
+    ] {
+        let err = check_dns(&servers(patch)).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    }
+
+    let hosts = json!({ "dns": { "hosts": { "internal.test": "." } } });
+    let err = check_dns(&config(hosts)).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    assert!(err.to_string().contains("internal.test"));
+
+    let fake_dns = |ip_range: &str| {
+        config(
+            json!({ "fake_dns": { "address": "127.0.0.1", "port": 5353, "ip_range": ip_range } }),
+        )
+    };
+    if cfg!(feature = "client") {
+        assert!(check_dns(&fake_dns("198.18.0.0/15")).is_ok());
+        for ip_range in ["198.18.0.0", "10.0.0.0/31"] {
+            let err = check_dns(&fake_dns(ip_range)).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        }
+
+        let dns_inbound =
+            json!({ "dns_inbound": { "address": "127.0.0.1", "upstream": "8.8.8.8" } });
+        let err = check_dns(&config(dns_inbound)).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        assert!(err.to_string().contains("8.8.8.8"));
+    }
+}
+
+#[test]
+fn test_check_features() {
+    assert!(check_features(&config(json!({}))).is_ok());
+
+    // The authentication backends are only built with their features
+    let backend = |url: &str| {
+        config(json!({ "inbound": { "auth_backend": { "backend": "REDIS", "url": url } } }))
+    };
+    match cfg!(feature = "redis") {
+        true => {
+            assert!(check_features(&backend("redis://127.0.0.1")).is_ok());
+            let err = check_inbound(&backend("127.0.0.1")).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        }
+        false => {
+            let backend = backend("redis://127.0.0.1");
+            let err = check_features(&backend).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidData);
+            assert!(err.to_string().contains("redis feature"));
+        }
+    }
+}
+
+#[test]
+fn test_check_inbound() {
+    assert!(check_inbound(&config(json!({}))).is_ok());
+
+    let err = check_inbound(&config(json!({ "inbound": { "intercept_dns": true } }))).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    assert!(err.to_string().contains("dns_inbound"));
+
+    let socket = json!({ "inbound": { "socket": { "keepalive_probes": 3 } } });
+    let err = check_inbound(&config(socket)).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+
+    let sources = json!({ "inbound": { "sources": { "deny": ["not an address"] } } });
+    let err = check_inbound(&config(sources)).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    assert!(err.to_string().contains("source address filter"));
+
+    let allowed =
+        json!({ "inbound": { "block_private": true, "allowed_destinations": ["10.0.0.0/33"] } });
+    let err = check_inbound(&config(allowed)).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    assert!(err.to_string().contains("allowed destination"));
+
+    let sniffing = json!({ "sniffing": { "ports": [{ "port": "443-80", "protocol": "TLS" }] } });
+    let err = check_inbound(&config(json!({ "inbound": sniffing }))).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    assert!(err.to_string().contains("443-80"));
+
+    let users = json!({ "inbound": { "users": [{ "name": "alice", "secret": "a", "expires_at": "soon" }] } });
+    let err = check_inbound(&config(users)).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    assert!(err.to_string().contains("alice"));
+
+    let ips = json!({ "inbound": { "allowed_ips": ["192.0.2.0/24", "localhost"] } });
+    let err = check_inbound(&config(ips)).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    assert!(err.to_string().contains("localhost"));
+
+    if cfg!(feature = "server") {
+        let quic = json!({ "inbound": { "quic": { "idle_timeout": u64::MAX } } });
+        let err = check_inbound(&config(quic)).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        assert!(err.to_string().contains("idle_timeout"));
+    }
+
+    let ports = json!({ "destination_ports": { "deny": ["25", "6000-"] } });
+    let err = check_inbound(&config(json!({ "inbound": ports }))).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    assert!(err.to_string().contains("6000-"));
+
+    for limit in [
+        json!({ "max_connections": 0 }),
+        json!({ "max_connections_per_listener": 0 }),
+    ] {
+        let err = check_inbound(&config(json!({ "inbound": { "connection_limit": limit } })))
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    }
+}
+
+#[test]
+fn test_check_metrics() {
+    assert!(check_metrics(&config(
+        json!({ "metrics": { "access_log_sample_rate": 0.1 } })
+    ))
+    .is_ok());
+
+    let err = check_metrics(&config(
+        json!({ "metrics": { "access_log_sample_rate": -1.0 } }),
+    ))
+    .unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+}
+
+#[test]
+fn test_check_outbounds() {
+    let discovery = |discovery: &str| {
+        config(
+            json!({ "outbound": { "mode": "TCP", "protocol": "TROJAN", "discovery": discovery } }),
+        )
+    };
+    assert!(check_outbounds(&discovery("srv:_trojan._tcp.example.com")).is_ok());
+
+    let err = check_outbounds(&discovery("example.com")).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+
+    let servers = |servers: Value| {
+        config(json!({ "outbound": { "mode": "TCP", "protocol": "TROJAN", "servers": servers } }))
+    };
+    for patch in [json!(["10.0.0.1:https"]), json!(["10.0.0.1"])] {
+        let err = check_outbounds(&servers(patch)).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        assert!(err.to_string().contains("10.0.0.1"));
+    }
+
+    let udp = |max_packet_size: usize| {
+        config(json!({ "outbound": { "udp": { "max_packet_size": max_packet_size } } }))
+    };
+    assert!(check_outbounds(&udp(1400)).is_ok());
+    let err = check_outbounds(&udp(23)).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+
+    for udp in [
+        json!({ "bind_address": "192.0.2" }),
+        json!({ "port_range": "0-1024" }),
+    ] {
+        let err = check_outbounds(&config(json!({ "outbound": { "udp": udp } }))).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    }
+
+    if cfg!(feature = "client") {
+        let tls = json!({ "host_name": "example.com", "allow_insecure": false, "fingerprint": { "profiles": [] } });
+        let err = check_outbounds(&config(json!({ "outbound": { "tls": tls } }))).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        assert!(err.to_string().contains("fingerprint"));
+    }
+
+    let pool = |mode: &str| config(json!({ "outbound": { "mode": mode, "pool": { "size": 2 } } }));
+    assert!(check_outbounds(&pool("TCP")).is_ok());
+    let err = check_outbounds(&pool("QUIC")).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+}
+
+#[test]
+fn test_check_relay() {
+    assert!(check_relay(&config(json!({}))).is_ok());
+    assert!(check_relay(&config(json!({ "relay_buffer_size": 1024 }))).is_ok());
+
+    let err = check_relay(&config(json!({ "relay_buffer_size": 512 }))).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    assert!(err.to_string().contains("relay_buffer_size"));
+
+    let err = check_relay(&config(json!({ "inbound": { "policy": "patient" } }))).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    assert!(err.to_string().contains("patient"));
+
+    let destinations =
+        json!({ "policy": { "destinations": { "deny_file": "/nonexistent/deny.txt" } } });
+    let err = check_relay(&config(destinations)).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    assert!(err.to_string().contains("destination address filter"));
+
+    let err = check_relay(&config(json!({ "reaper": { "interval": 0 } }))).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    assert!(err.to_string().contains("reaper"));
+}
+
+#[test]
+fn test_check_router() {
+    let direct = json!({ "tag": "direct", "mode": "DIRECT", "protocol": "DIRECT" });
+    let rules = json!({ "rules": [{ "domain": ["example.com"], "outbound": "direct" }] });
+    assert!(check_router(&config(json!({ "outbounds": [direct], "router": rules }))).is_ok());
+
+    for patch in [
+        json!({ "outbounds": [{ "mode": "DIRECT", "protocol": "DIRECT" }] }),
+        json!({ "outbounds": [direct, direct] }),
+        json!({ "router": rules }),
+        json!({ "router": { "rules": [{ "ip_cidr": ["geoip:cn"], "outbound": "default" }] } }),
+        json!({ "router": { "rules": [{ "ip_cidr": ["10.0.0.0/40"], "outbound": "default" }] } }),
+        json!({ "router": { "rules": [{ "domain": ["geosite:cn"], "outbound": "default" }] } }),
+        json!({ "router": { "rules": [{ "domain": ["regexp:("], "outbound": "default" }] } }),
+        json!({ "router": { "rules": [{ "port": ["8080-80"], "outbound": "default" }] } }),
+        json!({ "router": { "rules": [], "geoip_url": "https://example.com/geoip.mmdb" } }),
+        json!({ "router": { "rules": [], "geosite_database": "/nonexistent/geosite.dat" } }),
+        json!({ "outbounds": [{ "tag": "a", "mode": "TCP", "protocol": "TROJAN", "dialer": "b" }] }),
+        json!({ "outbounds": [{ "tag": "a", "mode": "QUIC", "protocol": "TROJAN", "dialer": "default" }] }),
+        json!({ "outbounds": [
+            { "tag": "a", "mode": "TCP", "protocol": "TROJAN", "dialer": "b" },
+            { "tag": "b", "mode": "TCP", "protocol": "TROJAN", "dialer": "a" },
+        ] }),
+        json!({ "outbound_groups": [{ "tag": "auto", "type": "FAILOVER", "members": [] }] }),
+        json!({ "outbound_groups": [{ "tag": "auto", "type": "FAILOVER", "members": ["direct"] }] }),
+        json!({ "outbound_groups": [{ "tag": "default", "type": "SELECT", "members": ["default"] }] }),
+        json!({ "outbound_groups": [{ "tag": "auto", "type": "URL_TEST", "members": ["default"], "url": "ftp://x" }] }),
+    ] {
+        let err = check_router(&config(patch)).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    }
+}
+
+#[test]
+fn test_check_runtime() {
+    assert!(check_runtime(&config(json!({}))).is_ok());
+    assert!(check_runtime(&config(json!({ "runtime": { "worker_threads": 2 } }))).is_ok());
+
+    for runtime in [
+        json!({ "worker_threads": 0 }),
+        json!({ "max_blocking_threads": 0 }),
+        json!({ "cpu_affinity": [] }),
+    ] {
+        let err = check_runtime(&config(json!({ "runtime": runtime }))).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    }
+
+    let err = check_runtime(&config(json!({ "io_uring": { "workers": 0 } }))).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+}
+
+#[test]
+fn test_check_tracing() {
+    let tracing = |sample_rate: f64| {
+        config(
+            json!({ "tracing": { "endpoint": "http://127.0.0.1:4318/v1/traces", "sample_rate": sample_rate } }),
+        )
+    };
+    assert!(check_tracing(&config(json!({}))).is_ok());
+    assert!(check_tracing(&tracing(0.5)).is_ok());
+
+    let err = check_tracing(&tracing(1.5)).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+}
@@ -0,0 +1,29 @@
+use trojan_rust::config::base::RuntimeConfig;
+use trojan_rust::config::runtime::build_runtime;
+
+#[test]
+fn test_runtime_pins_workers() {
+    let runtime = build_runtime(Some(&RuntimeConfig {
+        worker_threads: Some(2),
+        max_blocking_threads: Some(4),
+        cpu_affinity: Some(vec![0]),
+    }))
+    .unwrap();
+
+    // Tasks run on the workers, which are all pinned to the first CPU
+    let cpus = runtime
+        .block_on(runtime.spawn(async { current_cpus() }))
+        .unwrap();
+    assert_eq!(cpus, vec![0]);
+}
+
+fn current_cpus() -> Vec<usize> {
+    // Safety: the set is zeroed and sized for the call
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        libc::sched_getaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &mut set);
+        (0..libc::CPU_SETSIZE as usize)
+            .filter(|cpu| libc::CPU_ISSET(*cpu, &set))
+            .collect()
+    }
+}
@@ -0,0 +1,67 @@
+use rustls::server::ProducesTickets;
+use std::time::Duration;
+use tokio::time::advance;
+use trojan_rust::config::base::Config;
+use trojan_rust::config::parser::check_tls;
+use trojan_rust::config::ticketer::RotatingTicketer;
+
+#[test]
+fn test_ticketer_round_trip() {
+    let ticketer = RotatingTicketer::new(Duration::from_secs(3600));
+    assert!(ticketer.enabled());
+    assert_eq!(ticketer.lifetime(), 3600);
+
+    let ticket = ticketer.encrypt(b"session state").unwrap();
+    assert_eq!(ticketer.decrypt(&ticket).unwrap(), b"session state");
+
+    // Tampered tickets and tickets from another server are refused
+    let mut tampered = ticket.clone();
+    *tampered.last_mut().unwrap() ^= 1;
+    assert!(ticketer.decrypt(&tampered).is_none());
+    assert!(ticketer.decrypt(&ticket[..10]).is_none());
+
+    let other = RotatingTicketer::new(Duration::from_secs(3600));
+    assert!(other.decrypt(&ticket).is_none());
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_ticketer_rotation() {
+    let ticketer = RotatingTicketer::new(Duration::from_millis(200));
+    let first = ticketer.encrypt(b"first").unwrap();
+
+    // Not rotated before the interval is over
+    advance(Duration::from_millis(199)).await;
+    assert_eq!(ticketer.encrypt(b"first").unwrap()[..16], first[..16]);
+
+    // The key is rotated, tickets of the previous key are still accepted
+    advance(Duration::from_millis(1)).await;
+    let second = ticketer.encrypt(b"second").unwrap();
+    assert_ne!(first[..16], second[..16]);
+    assert_eq!(ticketer.decrypt(&first).unwrap(), b"first");
+
+    // After another rotation only the tickets of the previous key are accepted
+    advance(Duration::from_millis(200)).await;
+    assert_eq!(ticketer.decrypt(&second).unwrap(), b"second");
+    assert!(ticketer.decrypt(&first).is_none());
+}
+
+#[test]
+fn test_zero_rotation_rejected() {
+    let config = |rotation: u64| -> Config {
+        serde_json::from_str(&format!(
+            r#"{{
+                "inbound": {{
+                    "mode": "TCP", "protocol": "TROJAN", "address": "127.0.0.1", "port": 443,
+                    "tls": {{ "cert_path": "cert.pem", "key_path": "key.pem", "session_ticket_rotation": {} }}
+                }},
+                "outbound": {{ "mode": "DIRECT", "protocol": "DIRECT" }}
+            }}"#,
+            rotation
+        ))
+        .unwrap()
+    };
+
+    assert!(check_tls(&config(60)).is_ok());
+    let err = check_tls(&config(0)).unwrap_err();
+    assert!(err.to_string().contains("session_ticket_rotation"));
+}
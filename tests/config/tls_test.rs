@@ -0,0 +1,23 @@
+use rustls::{CipherSuite, SupportedCipherSuite, ALL_CIPHER_SUITES};
+use trojan_rust::config::tls::{aes_hardware_available, preferred_cipher_suites};
+
+#[test]
+fn test_preferred_cipher_suites() {
+    let suites = preferred_cipher_suites();
+    assert_eq!(suites.len(), ALL_CIPHER_SUITES.len());
+
+    // TLS 1.3 suites come before the TLS 1.2 ones
+    let tls13 = suites
+        .iter()
+        .take_while(|suite| matches!(suite, SupportedCipherSuite::Tls13(_)))
+        .count();
+    assert!(suites[tls13..]
+        .iter()
+        .all(|suite| matches!(suite, SupportedCipherSuite::Tls12(_))));
+
+    let first = suites[0].suite();
+    match aes_hardware_available() {
+        true => assert_ne!(first, CipherSuite::TLS13_CHACHA20_POLY1305_SHA256),
+        false => assert_eq!(first, CipherSuite::TLS13_CHACHA20_POLY1305_SHA256),
+    }
+}
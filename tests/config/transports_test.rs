@@ -0,0 +1,52 @@
+use trojan_rust::config::base::Config;
+use trojan_rust::config::parser::check_transports;
+use trojan_rust::config::transports::Transports;
+
+fn config(transports: &str) -> Config {
+    serde_json::from_str(&format!(
+        r#"{{
+            "inbound": {{
+                "mode": "TCP", "protocol": "TROJAN", "address": "127.0.0.1", "port": 443,
+                "websocket": {{ "path": "/ws" }}
+            }},
+            "outbound": {{ "mode": "RACE", "protocol": "TROJAN", "address": "127.0.0.1", "port": 443 }},
+            "outbounds": [
+                {{ "tag": "grpc", "mode": "GRPC", "protocol": "TROJAN", "address": "127.0.0.1", "port": 443 }}
+            ]
+            {}
+        }}"#,
+        transports
+    ))
+    .unwrap()
+}
+
+#[test]
+fn test_disabled_transports() {
+    assert!(check_transports(&config("")).is_ok());
+    assert!(check_transports(&config(r#", "transports": { "quic": true }"#)).is_ok());
+
+    // The RACE outbound dials with QUIC as well
+    let err = check_transports(&config(r#", "transports": { "quic": false }"#)).unwrap_err();
+    assert!(err.to_string().contains("Outbound default"));
+
+    let err = check_transports(&config(r#", "transports": { "grpc": false }"#)).unwrap_err();
+    assert!(err.to_string().contains("Outbound grpc"));
+}
+
+#[test]
+fn test_disabled_websocket() {
+    let err = check_transports(&config(r#", "transports": { "websocket": false }"#)).unwrap_err();
+    assert!(err.to_string().contains("WebSocket"));
+}
+
+#[test]
+fn test_transports_enabled_by_default() {
+    let transports = Transports::new(
+        config(r#", "transports": { "quic": false }"#)
+            .transports
+            .as_ref(),
+    );
+    assert!(transports.grpc && transports.websocket);
+    assert!(!transports.quic);
+    assert_eq!(Transports::new(None), *Transports::get());
+}
@@ -0,0 +1,89 @@
+use std::io::ErrorKind;
+use trojan_rust::dns::discovery::Discovery;
+use trust_dns_resolver::proto::rr::rdata::svcb::{Alpn, SvcParamKey, SvcParamValue, SVCB};
+use trust_dns_resolver::proto::rr::rdata::SRV;
+use trust_dns_resolver::proto::rr::{Name, RData, Record};
+
+fn name(name: &str) -> Name {
+    Name::from_ascii(name).unwrap()
+}
+
+fn endpoints(discovery: &Discovery, records: Vec<RData>) -> Vec<(String, Vec<String>)> {
+    let records: Vec<Record> = records
+        .into_iter()
+        .map(|rdata| Record::from_rdata(name("example.com."), 300, rdata))
+        .collect();
+    discovery
+        .endpoints(&records)
+        .into_iter()
+        .map(|endpoint| (endpoint.address.to_string(), endpoint.alpn))
+        .collect()
+}
+
+#[test]
+fn test_discovery_srv_records() {
+    let discovery = Discovery::new("srv:_trojan._tcp.example.com", Some(443)).unwrap();
+    let srv =
+        |priority, weight, port, target| RData::SRV(SRV::new(priority, weight, port, name(target)));
+
+    // Lower priorities first, then higher weights, and the records without a target are left out
+    let found = endpoints(
+        &discovery,
+        vec![
+            srv(20, 0, 443, "backup.example.com."),
+            srv(10, 5, 8443, "b.example.com."),
+            srv(10, 50, 443, "a.example.com."),
+            srv(0, 0, 0, "."),
+        ],
+    );
+    assert_eq!(
+        found,
+        [
+            ("a.example.com:443".to_string(), vec![]),
+            ("b.example.com:8443".to_string(), vec![]),
+            ("backup.example.com:443".to_string(), vec![]),
+        ]
+    );
+}
+
+#[test]
+fn test_discovery_https_records() {
+    let discovery = Discovery::new("https:example.com", None).unwrap();
+    let https = |priority, target, params| RData::HTTPS(SVCB::new(priority, name(target), params));
+
+    let found = endpoints(
+        &discovery,
+        vec![
+            https(
+                2,
+                "edge.example.net.",
+                vec![(SvcParamKey::Port, SvcParamValue::Port(8443))],
+            ),
+            https(
+                1,
+                ".",
+                vec![(
+                    SvcParamKey::Alpn,
+                    SvcParamValue::Alpn(Alpn(vec!["h2".to_string()])),
+                )],
+            ),
+            // Records in alias mode only point to another name
+            https(0, "alias.example.net.", vec![]),
+        ],
+    );
+    assert_eq!(
+        found,
+        [
+            ("example.com:443".to_string(), vec!["h2".to_string()]),
+            ("edge.example.net:8443".to_string(), vec![]),
+        ]
+    );
+}
+
+#[test]
+fn test_discovery_invalid() {
+    for discovery in ["example.com", "txt:example.com", "srv:exa mple..com"] {
+        let e = Discovery::new(discovery, None).err().unwrap();
+        assert_eq!(e.kind(), ErrorKind::InvalidInput);
+    }
+}
@@ -0,0 +1,110 @@
+use std::net::{IpAddr, Ipv4Addr};
+use tokio::net::UdpSocket;
+use trojan_rust::config::base::FakeDnsConfig;
+use trojan_rust::dns::fake::FakeDns;
+use trojan_rust::protocol::common::addr::IpAddress;
+use trojan_rust::protocol::common::atype::Atype;
+use trojan_rust::protocol::common::command::Command;
+use trojan_rust::protocol::common::request::{InboundRequest, TransportProtocol};
+use trojan_rust::proxy::base::SupportedProtocols;
+use trust_dns_resolver::proto::op::{Message, Query, ResponseCode};
+use trust_dns_resolver::proto::rr::{Name, RData, RecordType};
+
+fn fake_dns(ip_range: &str) -> FakeDns {
+    FakeDns::new(&FakeDnsConfig {
+        address: "127.0.0.1".to_string(),
+        port: 0,
+        ip_range: Some(ip_range.to_string()),
+        domains: Some(vec!["example.com".to_string()]),
+        ttl: None,
+    })
+    .unwrap()
+}
+
+fn request(addr: [u8; 4]) -> InboundRequest {
+    InboundRequest::new(
+        Atype::IPv4,
+        IpAddress::from_u32(u32::from_be_bytes(addr)),
+        Command::Connect,
+        443,
+        TransportProtocol::TCP,
+        SupportedProtocols::SOCKS,
+    )
+}
+
+#[test]
+fn test_fake_addresses() {
+    // Only 10.0.0.1 and 10.0.0.2 are given out
+    let fake_dns = fake_dns("10.0.0.0/30");
+    let first = Ipv4Addr::new(10, 0, 0, 1);
+    let second = Ipv4Addr::new(10, 0, 0, 2);
+
+    assert_eq!(fake_dns.address("www.example.com."), Some(first));
+    assert_eq!(fake_dns.address("WWW.example.com"), Some(first));
+    assert_eq!(fake_dns.address("example.org"), None);
+    assert_eq!(fake_dns.address("a.example.com"), Some(second));
+
+    // The first address is reused once all of them are taken
+    assert_eq!(fake_dns.address("b.example.com"), Some(first));
+    assert_eq!(
+        fake_dns.domain(IpAddr::V4(first)).as_deref(),
+        Some("b.example.com")
+    );
+    assert_eq!(fake_dns.address("www.example.com"), Some(second));
+}
+
+#[test]
+fn test_restore_request() {
+    let fake_dns = fake_dns("10.0.0.0/24");
+    fake_dns.address("www.example.com").unwrap();
+
+    let mut mapped = request([10, 0, 0, 1]);
+    fake_dns.restore(&mut mapped).unwrap();
+    assert_eq!(mapped.addr_port.to_string(), "www.example.com:443");
+    assert!(matches!(mapped.atype, Atype::DomainName));
+
+    let mut real = request([8, 8, 8, 8]);
+    fake_dns.restore(&mut real).unwrap();
+    assert_eq!(real.addr_port.to_string(), "8.8.8.8:443");
+
+    let mut unknown = request([10, 0, 0, 2]);
+    assert!(fake_dns.restore(&mut unknown).is_err());
+}
+
+#[tokio::test]
+async fn test_fake_dns_server() {
+    let fake_dns: &'static FakeDns = Box::leak(Box::new(fake_dns("198.18.0.0/15")));
+    let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let address = server.local_addr().unwrap();
+    tokio::spawn(fake_dns.serve(server));
+
+    let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    client.connect(address).await.unwrap();
+
+    let mut buf = vec![0u8; 512];
+    for (id, record_type) in [(1, RecordType::A), (2, RecordType::AAAA)] {
+        let mut query = Message::new();
+        query.set_id(id).add_query(Query::query(
+            Name::from_ascii("www.example.com.").unwrap(),
+            record_type,
+        ));
+        client.send(&query.to_vec().unwrap()).await.unwrap();
+
+        let n = client.recv(&mut buf).await.unwrap();
+        let response = Message::from_vec(&buf[..n]).unwrap();
+        assert_eq!(response.id(), id);
+        assert_eq!(response.response_code(), ResponseCode::NoError);
+
+        match record_type {
+            RecordType::A => {
+                assert_eq!(response.answers().len(), 1);
+                assert_eq!(response.answers()[0].ttl(), 1);
+                assert_eq!(
+                    response.answers()[0].data(),
+                    Some(&RData::A(Ipv4Addr::new(198, 18, 0, 1)))
+                );
+            }
+            _ => assert!(response.answers().is_empty()),
+        }
+    }
+}
@@ -0,0 +1,227 @@
+use std::net::{Ipv4Addr, SocketAddr};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use trojan_rust::config::base::{Config, DnsInboundConfig, FakeDnsConfig};
+use trojan_rust::dns::fake::FakeDns;
+use trojan_rust::dns::hijack::DnsHijack;
+use trojan_rust::protocol::common::addr::{IpAddrPort, IpAddress};
+use trojan_rust::protocol::socks5::udp::{self, UdpAssociation};
+use trojan_rust::protocol::trojan::parse_udp;
+use trojan_rust::router::Router;
+use trust_dns_resolver::proto::op::{Message, Query, ResponseCode};
+use trust_dns_resolver::proto::rr::{Name, RData, Record, RecordType};
+
+fn dns_inbound_config(fake_dns: Option<bool>) -> DnsInboundConfig {
+    DnsInboundConfig {
+        address: "127.0.0.1".to_string(),
+        port: Some(0),
+        fake_dns,
+        upstream: None,
+    }
+}
+
+fn query(id: u16, name: &str, record_type: RecordType) -> Vec<u8> {
+    let mut query = Message::new();
+    query
+        .set_id(id)
+        .add_query(Query::query(Name::from_ascii(name).unwrap(), record_type));
+    query.to_vec().unwrap()
+}
+
+fn fake_hijack() -> &'static DnsHijack {
+    let fake_dns: &'static FakeDns = Box::leak(Box::new(
+        FakeDns::new(&FakeDnsConfig {
+            address: "127.0.0.1".to_string(),
+            port: 0,
+            ip_range: Some("198.18.0.0/15".to_string()),
+            domains: Some(vec!["example.com".to_string()]),
+            ttl: None,
+        })
+        .unwrap(),
+    ));
+    Box::leak(Box::new(
+        DnsHijack::new(&dns_inbound_config(None), Some(fake_dns), None).unwrap(),
+    ))
+}
+
+#[tokio::test]
+async fn test_dns_hijack_over_tcp() {
+    let hijack = fake_hijack();
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(hijack.serve_tcp(listener));
+
+    // Several length prefixed queries share the connection
+    let mut stream = TcpStream::connect(address).await.unwrap();
+    for (id, record_type) in [(1, RecordType::A), (2, RecordType::AAAA)] {
+        let query = query(id, "www.example.com.", record_type);
+        stream.write_u16(query.len() as u16).await.unwrap();
+        stream.write_all(&query).await.unwrap();
+
+        let size = stream.read_u16().await.unwrap() as usize;
+        let mut buf = vec![0u8; size];
+        stream.read_exact(&mut buf).await.unwrap();
+        let response = Message::from_vec(&buf).unwrap();
+        assert_eq!(response.id(), id);
+        assert_eq!(response.response_code(), ResponseCode::NoError);
+
+        match record_type {
+            RecordType::A => assert_eq!(
+                response.answers()[0].data(),
+                Some(&RData::A(Ipv4Addr::new(198, 18, 0, 1)))
+            ),
+            _ => assert!(response.answers().is_empty()),
+        }
+    }
+}
+
+/// DNS inbound sending the queries to the upstream server through a DIRECT outbound, serving them over UDP.
+async fn upstream_hijack(upstream: SocketAddr) -> SocketAddr {
+    let config: Config = serde_json::from_str(
+        r#"{
+            "inbound": { "mode": "TCP", "protocol": "SOCKS", "address": "127.0.0.1", "port": 1080 },
+            "outbound": { "mode": "DIRECT", "protocol": "DIRECT" }
+        }"#,
+    )
+    .unwrap();
+    let router: &'static Router = Box::leak(Box::new(Router::new(&config).unwrap()));
+    let hijack: &'static DnsHijack = Box::leak(Box::new(
+        DnsHijack::new(
+            &DnsInboundConfig {
+                upstream: Some(upstream.to_string()),
+                ..dns_inbound_config(Some(false))
+            },
+            None,
+            Some(router),
+        )
+        .unwrap(),
+    ));
+
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let address = socket.local_addr().unwrap();
+    tokio::spawn(hijack.serve_udp(socket));
+    address
+}
+
+/// Send the query to the DNS inbound over UDP and wait for its response.
+async fn ask(address: SocketAddr, query: &[u8]) -> Message {
+    let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    client.send_to(query, address).await.unwrap();
+    let mut buf = vec![0u8; 4096];
+    let (size, _) = client.recv_from(&mut buf).await.unwrap();
+    Message::from_vec(&buf[..size]).unwrap()
+}
+
+#[tokio::test]
+async fn test_dns_hijack_forwards_to_upstream() {
+    // Upstream server answering the length prefixed query over TCP
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let upstream = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let size = stream.read_u16().await.unwrap() as usize;
+        let mut buf = vec![0u8; size];
+        stream.read_exact(&mut buf).await.unwrap();
+
+        let query = Message::from_vec(&buf).unwrap();
+        let mut response = Message::new();
+        response
+            .set_id(query.id())
+            .add_query(query.queries()[0].clone())
+            .add_answer(Record::from_rdata(
+                query.queries()[0].name().clone(),
+                60,
+                RData::A(Ipv4Addr::new(93, 184, 216, 34)),
+            ));
+        let response = response.to_vec().unwrap();
+        stream.write_u16(response.len() as u16).await.unwrap();
+        stream.write_all(&response).await.unwrap();
+    });
+
+    let address = upstream_hijack(upstream).await;
+    let response = ask(address, &query(4, "www.example.com.", RecordType::A)).await;
+    assert_eq!(response.id(), 4);
+    assert_eq!(
+        response.answers()[0].data(),
+        Some(&RData::A(Ipv4Addr::new(93, 184, 216, 34)))
+    );
+}
+
+#[tokio::test]
+async fn test_dns_hijack_upstream_failure() {
+    // Nothing listens on the port of the upstream server once the listener is dropped
+    let upstream = TcpListener::bind("127.0.0.1:0")
+        .await
+        .unwrap()
+        .local_addr()
+        .unwrap();
+
+    let address = upstream_hijack(upstream).await;
+    let response = ask(address, &query(5, "www.example.com.", RecordType::A)).await;
+    assert_eq!(response.id(), 5);
+    assert_eq!(response.response_code(), ResponseCode::ServFail);
+    assert_eq!(response.queries()[0].name().to_ascii(), "www.example.com.");
+}
+
+#[tokio::test]
+async fn test_dns_hijack_invalid_queries() {
+    let hijack = DnsHijack::new(&dns_inbound_config(Some(false)), None, None).unwrap();
+
+    assert!(hijack.answer(b"not a query").await.is_err());
+
+    let mut empty = Message::new();
+    empty.set_id(7);
+    let response = hijack.answer(&empty.to_vec().unwrap()).await.unwrap();
+    let response = Message::from_vec(&response).unwrap();
+    assert_eq!(response.id(), 7);
+    assert_eq!(response.response_code(), ResponseCode::FormErr);
+}
+
+#[tokio::test]
+async fn test_dns_hijack_intercepts_socks_datagrams() {
+    let association = UdpAssociation::bind("127.0.0.1".parse().unwrap())
+        .await
+        .unwrap()
+        .intercept_dns(fake_hijack());
+    let relay_addr = association.local_addr().unwrap();
+    let (packets, mut outbound) = tokio::io::duplex(64 * 1024);
+    let relay = tokio::spawn(async move {
+        association
+            .relay("127.0.0.1".parse().unwrap(), packets)
+            .await
+    });
+
+    // The query to the hardcoded resolver is answered by the DNS inbound as if it came from the resolver
+    let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let resolver = IpAddrPort::new(IpAddress::from_host("8.8.8.8"), 53);
+    client
+        .send_to(
+            &udp::encode(&resolver, &query(3, "www.example.com.", RecordType::A)).unwrap(),
+            relay_addr,
+        )
+        .await
+        .unwrap();
+
+    let mut buf = vec![0u8; 4096];
+    let (size, _) = client.recv_from(&mut buf).await.unwrap();
+    let (source, payload) = udp::decode(&buf[..size]).unwrap();
+    assert_eq!(source.to_string(), "8.8.8.8:53");
+    let response = Message::from_vec(payload).unwrap();
+    assert_eq!(response.id(), 3);
+    assert_eq!(
+        response.answers()[0].data(),
+        Some(&RData::A(Ipv4Addr::new(198, 18, 0, 1)))
+    );
+
+    // Only the other datagrams reach the outbound
+    let destination = IpAddrPort::new(IpAddress::from_host("1.1.1.1"), 443);
+    client
+        .send_to(&udp::encode(&destination, b"data").unwrap(), relay_addr)
+        .await
+        .unwrap();
+    let header = parse_udp(&mut outbound).await.unwrap();
+    assert_eq!(header.dest.to_string(), "1.1.1.1:443");
+
+    relay.abort();
+}
@@ -0,0 +1,95 @@
+use std::io::{Error, ErrorKind};
+use std::time::Duration;
+use tokio::sync::broadcast::Receiver;
+use trojan_rust::config::base::OutboundGroupConfig;
+use trojan_rust::events::events_api::event::Kind;
+use trojan_rust::events::server::to_message;
+use trojan_rust::events::{self, Connection, Event, TimedEvent};
+use trojan_rust::proxy::context::TrafficContext;
+use trojan_rust::proxy::relay::Transfer;
+use trojan_rust::router::group::OutboundGroup;
+
+/// Events received so far, which may include the ones emitted by the other tests running meanwhile.
+fn received(receiver: &mut Receiver<TimedEvent>) -> Vec<Event> {
+    let mut events = Vec::new();
+    while let Ok(event) = receiver.try_recv() {
+        events.push(event.event);
+    }
+    events
+}
+
+#[test]
+fn test_connection_events() {
+    let mut receiver = events::subscribe();
+    let context = TrafficContext::new(Some("socks"), Some("proxy"), Some("alice"))
+        .with_destination("example.com:443".to_string());
+
+    let connection = Connection::open("127.0.0.1:50000".parse().unwrap(), &context);
+    connection.fail(&Error::new(ErrorKind::TimedOut, "connect timed out"));
+    drop(connection);
+
+    let events = received(&mut receiver);
+    let id = events
+        .iter()
+        .find_map(|event| match event {
+            Event::ConnectionOpened { id, context: c, .. } if *c == context => Some(*id),
+            _ => None,
+        })
+        .unwrap();
+    assert!(events.contains(&Event::Error {
+        connection: id,
+        message: "connect timed out".to_string(),
+    }));
+    assert!(events
+        .iter()
+        .any(|event| matches!(event, Event::ConnectionClosed { id: closed, .. } if *closed == id)));
+}
+
+#[test]
+fn test_outbound_switch_event() {
+    let mut receiver = events::subscribe();
+    let config: OutboundGroupConfig =
+        serde_json::from_str(r#"{ "tag": "watched", "type": "SELECT", "members": ["a", "b"] }"#)
+            .unwrap();
+    let group = OutboundGroup::new(&config).unwrap();
+    group.select("b");
+
+    let switched = Event::OutboundSwitched {
+        group: "watched".to_string(),
+        from: "a".to_string(),
+        to: "b".to_string(),
+    };
+    assert!(received(&mut receiver).contains(&switched));
+
+    // Sent to the watchers as a message with the fields spelled out
+    let message = to_message(TimedEvent {
+        time: std::time::SystemTime::now(),
+        event: switched,
+    });
+    match message.kind {
+        Some(Kind::OutboundSwitched(switched)) => assert_eq!(switched.to, "b"),
+        kind => panic!("unexpected event {:?}", kind),
+    }
+}
+
+/// Next traffic rate event, skipping the others.
+async fn next_rate(receiver: &mut Receiver<TimedEvent>) -> (u64, u64) {
+    loop {
+        if let Event::TrafficRate { upload, download } = receiver.recv().await.unwrap().event {
+            return (upload, download);
+        }
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_traffic_rate_counts_udp_payloads() {
+    let mut receiver = events::subscribe();
+    tokio::spawn(events::sample_traffic(Duration::from_secs(2)));
+
+    // The first sample is taken right away, the payloads are counted in the next one
+    next_rate(&mut receiver).await;
+    Transfer::count(4000, 8000);
+    let (upload, download) = next_rate(&mut receiver).await;
+    assert!(upload >= 2000);
+    assert!(download >= 4000);
+}
@@ -0,0 +1,35 @@
+use hyper::{Body, Request, StatusCode};
+use trojan_rust::health::{self, respond, HealthStatus};
+
+fn get(path: &str) -> Request<Body> {
+    Request::get(path).body(Body::empty()).unwrap()
+}
+
+#[tokio::test]
+async fn test_health_endpoint() {
+    assert_eq!(respond(&get("/livez")).status(), StatusCode::OK);
+    assert_eq!(respond(&get("/metrics")).status(), StatusCode::NOT_FOUND);
+
+    health::mark_listening();
+    let status = HealthStatus::current();
+    assert!(status.listening);
+    assert!(status.ready());
+
+    let response = respond(&get("/readyz"));
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(body["ready"], true);
+    assert_eq!(body["certificate_expired"], false);
+}
+
+#[test]
+fn test_not_ready_with_expired_certificate() {
+    let status = HealthStatus {
+        listening: true,
+        outbound_reachable: true,
+        certificate_days: Some(0),
+        certificate_expired: true,
+    };
+    assert!(!status.ready());
+}
@@ -0,0 +1,40 @@
+use log::{Level, Record};
+use serde_json::Value;
+use trojan_rust::logging::format_json;
+use trojan_rust::proxy::context::TrafficContext;
+
+fn format(message: &str) -> Value {
+    let line = format_json(
+        &Record::builder()
+            .args(format_args!("{}", message))
+            .level(Level::Info)
+            .target("trojan_rust::proxy::tcp::server")
+            .build(),
+    );
+    assert!(!line.contains('\n'));
+    serde_json::from_str(&line).unwrap()
+}
+
+#[tokio::test]
+async fn test_json_record_fields() {
+    let record = format("Connection from 127.0.0.1:5000 has finished");
+    assert_eq!(record["level"], "INFO");
+    assert_eq!(record["target"], "trojan_rust::proxy::tcp::server");
+    assert_eq!(
+        record["message"],
+        "Connection from 127.0.0.1:5000 has finished"
+    );
+    assert!(record["timestamp"].as_str().unwrap().ends_with('Z'));
+    assert!(record.get("connection").is_none());
+
+    let context = TrafficContext::new(Some("main"), Some("direct"), Some("alice"))
+        .with_destination("example.com:443".to_string())
+        .with_connection(12);
+    let record = context.scope(async { format("quoted \"message\"") }).await;
+    assert_eq!(record["message"], "quoted \"message\"");
+    assert_eq!(record["connection"], 12);
+    assert_eq!(record["inbound"], "main");
+    assert_eq!(record["outbound"], "direct");
+    assert_eq!(record["user"], "alice");
+    assert_eq!(record["destination"], "example.com:443");
+}
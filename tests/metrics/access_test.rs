@@ -0,0 +1,87 @@
+use std::io::ErrorKind;
+use trojan_rust::config::base::MetricsConfig;
+use trojan_rust::metrics::access::AccessLog;
+use trojan_rust::protocol::common::addr::IpAddress;
+
+fn metrics_config(sample_rate: f64, max_destinations: usize) -> MetricsConfig {
+    MetricsConfig {
+        snapshot_path: None,
+        snapshot_interval: None,
+        access_log_sample_rate: Some(sample_rate),
+        max_destinations: Some(max_destinations),
+        access_log: None,
+    }
+}
+
+#[test]
+fn test_access_log_sampling() {
+    let access_log = AccessLog::new(Some(&metrics_config(0.25, 1024))).unwrap();
+    let sampled = (0..100).filter(|_| access_log.sample()).count();
+    assert_eq!(sampled, 25);
+
+    let access_log = AccessLog::new(Some(&metrics_config(0.0, 1024))).unwrap();
+    assert!((0..100).all(|_| !access_log.sample()));
+
+    let access_log = AccessLog::new(None).unwrap();
+    assert!((0..100).all(|_| access_log.sample()));
+
+    let err = AccessLog::new(Some(&metrics_config(1.5, 1024)))
+        .err()
+        .unwrap();
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+}
+
+#[test]
+fn test_access_log_destination_cardinality() {
+    let access_log = AccessLog::new(Some(&metrics_config(1.0, 2))).unwrap();
+
+    let first = IpAddress::from_host("first.example.com");
+    assert_eq!(access_log.destination_label(&first), "first.example.com");
+    assert_eq!(
+        access_log.destination_label(&IpAddress::from_host("10.0.0.1")),
+        "10.0.0.1"
+    );
+
+    // Hosts beyond the limit share a few buckets, the same host always lands in the same one
+    let labels: Vec<String> = (0..100)
+        .map(|i| access_log.destination_label(&IpAddress::from_host(&format!("{}.example.com", i))))
+        .collect();
+    assert!(labels.iter().all(|label| label.starts_with("<other-")));
+    assert!(
+        labels
+            .iter()
+            .collect::<std::collections::HashSet<_>>()
+            .len()
+            <= 16
+    );
+    assert_eq!(
+        access_log.destination_label(&IpAddress::from_host("7.example.com")),
+        labels[7]
+    );
+
+    assert_eq!(access_log.destination_label(&first), "first.example.com");
+}
+
+#[test]
+fn test_access_log_file() {
+    let path = std::env::temp_dir().join(format!("trojan-access-{}.log", std::process::id()));
+    let mut config = metrics_config(1.0, 1024);
+    config.access_log =
+        serde_json::from_str(&format!(r#"{{ "path": "{}" }}"#, path.to_str().unwrap())).unwrap();
+
+    let access_log = AccessLog::new(Some(&config)).unwrap();
+    assert!(access_log.separate());
+    access_log.log(format_args!(
+        "Connection from {} has finished",
+        "127.0.0.1:5000"
+    ));
+    access_log.flush();
+    let content = std::fs::read_to_string(&path).unwrap();
+    assert!(content.ends_with(" Connection from 127.0.0.1:5000 has finished\n"));
+
+    config.access_log = serde_json::from_str(r#"{ "enabled": false }"#).unwrap();
+    let access_log = AccessLog::new(Some(&config)).unwrap();
+    assert!((0..10).all(|_| !access_log.sample()));
+
+    std::fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,18 @@
+use trojan_rust::metrics;
+use trojan_rust::metrics::export::write_snapshot;
+
+#[tokio::test]
+async fn test_write_snapshot() {
+    metrics::increment("export_test_total", 3);
+
+    let path =
+        std::env::temp_dir().join(format!("trojan-rust-metrics-{}.json", std::process::id()));
+    write_snapshot(&path).await.unwrap();
+
+    let data = std::fs::read(&path).unwrap();
+    let snapshot: serde_json::Value = serde_json::from_slice(&data).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(snapshot["metrics"]["export_test_total"], 3);
+    assert!(snapshot["timestamp"].as_u64().unwrap() > 0);
+}
@@ -0,0 +1,93 @@
+use flate2::read::GzDecoder;
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use trojan_rust::metrics::rotation::RotatingFile;
+
+/// Files rotated out of the path, oldest first.
+fn rotated(path: &Path) -> Vec<PathBuf> {
+    let mut rotated: Vec<_> = fs::read_dir(path.parent().unwrap())
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|entry| entry != path)
+        .collect();
+    rotated.sort();
+    rotated
+}
+
+#[test]
+fn test_rotation_by_size() {
+    let dir = std::env::temp_dir().join(format!("trojan-rotation-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("access.log");
+
+    let mut file = RotatingFile::open(path.to_str().unwrap(), Some(16), None, 2, false).unwrap();
+    for line in ["first line", "second line", "third line", "fourth line"] {
+        file.write_line(line).unwrap();
+    }
+    assert_eq!(fs::read_to_string(&path).unwrap(), "fourth line\n");
+
+    // Each line went over the size, three rotations of which the newest two are kept
+    let rotated = rotated(&path);
+    assert_eq!(rotated.len(), 2);
+    assert_eq!(fs::read_to_string(&rotated[0]).unwrap(), "second line\n");
+    assert_eq!(fs::read_to_string(&rotated[1]).unwrap(), "third line\n");
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_rotation_by_time() {
+    let dir = std::env::temp_dir().join(format!("trojan-rotation-time-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("access.log");
+
+    let interval = Duration::from_millis(100);
+    let mut file =
+        RotatingFile::open(path.to_str().unwrap(), None, Some(interval), 2, false).unwrap();
+    file.write_line("first line").unwrap();
+    file.write_line("second line").unwrap();
+    assert!(rotated(&path).is_empty());
+
+    // The file open for the interval is rotated by the next line
+    std::thread::sleep(interval);
+    file.write_line("third line").unwrap();
+    assert_eq!(fs::read_to_string(&path).unwrap(), "third line\n");
+    let rotated = rotated(&path);
+    assert_eq!(rotated.len(), 1);
+    assert_eq!(
+        fs::read_to_string(&rotated[0]).unwrap(),
+        "first line\nsecond line\n"
+    );
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_rotation_compressed() {
+    let dir = std::env::temp_dir().join(format!("trojan-rotation-gzip-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("access.log");
+
+    let mut file = RotatingFile::open(path.to_str().unwrap(), Some(16), None, 2, true).unwrap();
+    for line in ["first line", "second line", "third line", "fourth line"] {
+        file.write_line(line).unwrap();
+    }
+    // Closing the file waits for the last compression
+    drop(file);
+
+    let mut rotated = rotated(&path);
+    rotated.sort_by_key(|path| path.to_string_lossy().replace(".gz", ""));
+    assert_eq!(rotated.len(), 2);
+    for (rotated, line) in rotated.iter().zip(["second line\n", "third line\n"]) {
+        assert_eq!(rotated.extension().unwrap(), "gz");
+        let mut content = String::new();
+        GzDecoder::new(fs::File::open(rotated).unwrap())
+            .read_to_string(&mut content)
+            .unwrap();
+        assert_eq!(content, line);
+    }
+
+    fs::remove_dir_all(&dir).unwrap();
+}
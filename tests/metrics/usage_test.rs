@@ -0,0 +1,65 @@
+use std::io::ErrorKind;
+use trojan_rust::config::base::TrafficStatsConfig;
+use trojan_rust::metrics::usage::TrafficStats;
+use trojan_rust::proxy::relay::Transfer;
+
+#[tokio::test]
+async fn test_traffic_stats_survive_reopening() {
+    let path = std::env::temp_dir().join(format!("trojan-usage-{}.db", std::process::id()));
+    let config = TrafficStatsConfig {
+        path: path.to_string_lossy().to_string(),
+        flush_interval: None,
+    };
+
+    let stats = TrafficStats::open(&config).await.unwrap();
+    stats.record("alice", 100, 1000);
+    stats.record("bob", 5, 50);
+    stats.flush().await.unwrap();
+    stats.record("alice", 20, 200);
+    // The bytes not flushed yet are written before the query
+    let days = stats.query("alice", "", "").await.unwrap();
+    assert_eq!(days.len(), 1);
+    assert_eq!((days[0].uploaded, days[0].downloaded), (120, 1200));
+    drop(stats);
+
+    let stats = TrafficStats::open(&config).await.unwrap();
+    let days = stats.query("", "", "").await.unwrap();
+    let users: Vec<_> = days.iter().map(|day| day.user.as_str()).collect();
+    assert_eq!(users, ["alice", "bob"]);
+    assert!(stats.query("", "", "2000-01-01").await.unwrap().is_empty());
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn test_traffic_stats_book_transfers() {
+    let path = std::env::temp_dir().join(format!("trojan-booking-{}.db", std::process::id()));
+    let config = TrafficStatsConfig {
+        path: path.to_string_lossy().to_string(),
+        flush_interval: None,
+    };
+    let stats = TrafficStats::open(&config).await.unwrap();
+
+    // The bytes of a connection open across days are split between them
+    let transfer = Transfer::new();
+    transfer.scope(async { Transfer::count(100, 1000) }).await;
+    stats.book(&transfer, "alice", "2026-10-15");
+    transfer.scope(async { Transfer::count(20, 200) }).await;
+    stats.book(&transfer, "alice", "2026-10-16");
+    stats.book(&transfer, "alice", "2026-10-16");
+
+    let days = stats
+        .query("alice", "2026-10-01", "2026-10-31")
+        .await
+        .unwrap();
+    let counts: Vec<_> = days
+        .iter()
+        .map(|day| (day.day.as_str(), day.uploaded, day.downloaded))
+        .collect();
+    assert_eq!(counts, [("2026-10-15", 100, 1000), ("2026-10-16", 20, 200)]);
+
+    for day in ["2026-13-01", "2026-10-16T00:00:00Z", "yesterday"] {
+        let e = stats.query("", day, "").await.unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidInput);
+    }
+    std::fs::remove_file(&path).unwrap();
+}
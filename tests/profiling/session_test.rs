@@ -0,0 +1,26 @@
+use std::net::SocketAddr;
+use trojan_rust::profiling::{self, SessionOrder};
+
+#[tokio::test]
+async fn test_profile_session() {
+    let source: SocketAddr = "192.0.2.1:40000".parse().unwrap();
+
+    let size = profiling::profile(source, async {
+        profiling::set_destination(&"example.com:443");
+        tokio::task::yield_now().await;
+        let buf = vec![1u8; 1 << 20];
+        buf.iter().map(|b| *b as usize).sum::<usize>()
+    })
+    .await;
+    assert_eq!(size, 1 << 20);
+
+    let session = profiling::top_sessions(SessionOrder::AllocatedBytes, usize::MAX)
+        .into_iter()
+        .find(|session| session.source == source)
+        .unwrap();
+    assert!(!session.active);
+    assert!(session.allocated_bytes >= 1 << 20);
+    assert!(session.allocations >= 1);
+    assert!(!session.cpu_time.is_zero());
+    assert_eq!(session.destination.as_deref(), Some("example.com:443"));
+}
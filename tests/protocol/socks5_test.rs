@@ -0,0 +1,156 @@
+use std::io::{Error, ErrorKind};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::UdpSocket;
+use trojan_rust::protocol::common::addr::{IpAddrPort, IpAddress};
+use trojan_rust::protocol::common::atype::Atype;
+use trojan_rust::protocol::common::request::InboundRequest;
+use trojan_rust::protocol::common::stream::StandardTcpStream;
+use trojan_rust::protocol::socks5::reply::DeferredReply;
+use trojan_rust::protocol::socks5::udp::{self, UdpAssociation};
+use trojan_rust::protocol::socks5::{self, reply_code, request_ack};
+use trojan_rust::protocol::trojan::packet::encode_udp;
+use trojan_rust::protocol::trojan::parse_udp;
+
+/// Send the SOCKS handshake and the request like a client, and return the request accepted by the inbound.
+async fn accept_request(request: &[u8]) -> InboundRequest {
+    let (mut client, server) = tokio::io::duplex(1024);
+    let accept = tokio::spawn(socks5::accept(StandardTcpStream::Plain(server), 1080, true));
+
+    client.write_all(&[5, 1, 0]).await.unwrap();
+    let mut hello = [0u8; 2];
+    client.read_exact(&mut hello).await.unwrap();
+
+    client.write_all(request).await.unwrap();
+    let mut ack = [0u8; 10];
+    client.read_exact(&mut ack).await.unwrap();
+    assert_eq!(ack.to_vec(), request_ack(socks5::REPLY_SUCCEEDED, 1080));
+
+    accept.await.unwrap().unwrap().0
+}
+
+#[tokio::test]
+async fn test_accept_request_with_hostname() {
+    // curl --socks5-hostname leaves the domain name to the proxy
+    let mut request = vec![5, 1, 0, 3, 11];
+    request.extend_from_slice(b"example.com");
+    request.extend_from_slice(&443u16.to_be_bytes());
+
+    let request = accept_request(&request).await;
+    assert!(matches!(request.atype, Atype::DomainName));
+    assert_eq!(request.addr_port.to_string(), "example.com:443");
+}
+
+#[tokio::test]
+async fn test_accept_request_with_address() {
+    // curl --socks5 resolves the domain name itself and sends the address
+    let request = accept_request(&[5, 1, 0, 1, 93, 184, 216, 34, 0, 80]).await;
+    assert!(matches!(request.atype, Atype::IPv4));
+    assert_eq!(request.addr_port.to_string(), "93.184.216.34:80");
+}
+
+#[tokio::test]
+async fn test_deferred_reply_success() {
+    let (mut client, server) = tokio::io::duplex(1024);
+
+    let ack = request_ack(socks5::REPLY_SUCCEEDED, 1080);
+    let (mut stream, _failure) = DeferredReply::new(server, ack.clone());
+
+    // Nothing is replied until the stream is used
+    stream.write_all(b"hello").await.unwrap();
+    stream.flush().await.unwrap();
+
+    let mut buf = vec![0u8; ack.len() + 5];
+    client.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf[..ack.len()], &ack[..]);
+    assert_eq!(&buf[ack.len()..], b"hello");
+
+    client.write_all(b"world").await.unwrap();
+    let mut buf = [0u8; 5];
+    stream.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"world");
+}
+
+#[tokio::test]
+async fn test_deferred_reply_failure() {
+    let (mut client, server) = tokio::io::duplex(1024);
+
+    let ack = request_ack(socks5::REPLY_SUCCEEDED, 1080);
+    let (stream, failure) = DeferredReply::new(server, ack);
+
+    // The outbound failed before the stream was used
+    drop(stream);
+    let error = Error::new(ErrorKind::ConnectionRefused, "refused");
+    failure.send(request_ack(reply_code(&error), 1080)).unwrap();
+
+    let mut buf = Vec::new();
+    client.read_to_end(&mut buf).await.unwrap();
+    assert_eq!(buf, request_ack(socks5::REPLY_CONNECTION_REFUSED, 1080));
+}
+
+#[test]
+fn test_reply_code() {
+    let code = |kind| reply_code(&Error::new(kind, "error"));
+    assert_eq!(code(ErrorKind::TimedOut), socks5::REPLY_TTL_EXPIRED);
+    assert_eq!(code(ErrorKind::NotFound), socks5::REPLY_HOST_UNREACHABLE);
+    assert_eq!(code(ErrorKind::Other), socks5::REPLY_GENERAL_FAILURE);
+}
+
+#[test]
+fn test_udp_datagram_header() {
+    let mut datagram = vec![0, 0, 0, 3, 11];
+    datagram.extend_from_slice(b"example.com");
+    datagram.extend_from_slice(&53u16.to_be_bytes());
+    datagram.extend_from_slice(b"query");
+
+    let (destination, payload) = udp::decode(&datagram).unwrap();
+    assert_eq!(destination.to_string(), "example.com:53");
+    assert_eq!(payload, b"query");
+    assert_eq!(udp::encode(&destination, payload).unwrap(), datagram);
+
+    // Fragments and truncated headers are rejected
+    let err = udp::decode(&[0, 0, 1, 1, 127, 0, 0, 1, 0, 53])
+        .err()
+        .unwrap();
+    assert_eq!(err.kind(), ErrorKind::Unsupported);
+    assert!(udp::decode(&[0, 0, 0, 1, 127, 0]).is_err());
+}
+
+#[tokio::test]
+async fn test_udp_association_relay() {
+    let association = UdpAssociation::bind("127.0.0.1".parse().unwrap())
+        .await
+        .unwrap();
+    let relay_addr = association.local_addr().unwrap();
+    let (packets, mut outbound) = tokio::io::duplex(64 * 1024);
+    let relay = tokio::spawn(async move {
+        association
+            .relay("127.0.0.1".parse().unwrap(), packets)
+            .await
+    });
+
+    let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let destination = IpAddrPort::new(IpAddress::from_host("1.1.1.1"), 53);
+    client
+        .send_to(&udp::encode(&destination, b"query").unwrap(), relay_addr)
+        .await
+        .unwrap();
+
+    // The outbound receives the datagram as a trojan UDP packet
+    let header = parse_udp(&mut outbound).await.unwrap();
+    assert_eq!(header.dest.to_string(), "1.1.1.1:53");
+    let mut payload = vec![0u8; header.payload_size];
+    outbound.read_exact(&mut payload).await.unwrap();
+    assert_eq!(payload, b"query");
+
+    // And the reply goes back to the client with the address it came from
+    outbound
+        .write_all(&encode_udp(&destination, b"answer").unwrap())
+        .await
+        .unwrap();
+    let mut buf = vec![0u8; 1024];
+    let (size, source) = client.recv_from(&mut buf).await.unwrap();
+    assert_eq!(source, relay_addr);
+    assert_eq!(&buf[..size], udp::encode(&destination, b"answer").unwrap());
+
+    relay.abort();
+}
@@ -0,0 +1,94 @@
+use tokio::io::AsyncReadExt;
+use trojan_rust::protocol::tls::record::RecordStream;
+use trojan_rust::protocol::tls::{parse_server_name, read_client_hello};
+
+/// Build a minimal ClientHello handshake message carrying the given extensions
+fn client_hello(extensions: &[u8]) -> Vec<u8> {
+    let mut body = vec![0x03, 0x03];
+    body.extend_from_slice(&[0u8; 32]);
+    // Empty session id, a single cipher suite and null compression
+    body.extend_from_slice(&[0x00, 0x00, 0x02, 0x13, 0x01, 0x01, 0x00]);
+    body.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+    body.extend_from_slice(extensions);
+
+    let mut message = vec![0x01];
+    message.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+    message.extend_from_slice(&body);
+    message
+}
+
+fn server_name_extension(name: &str) -> Vec<u8> {
+    let mut list = vec![0x00];
+    list.extend_from_slice(&(name.len() as u16).to_be_bytes());
+    list.extend_from_slice(name.as_bytes());
+
+    let mut data = (list.len() as u16).to_be_bytes().to_vec();
+    data.extend_from_slice(&list);
+
+    let mut extension = vec![0x00, 0x00];
+    extension.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    extension.extend_from_slice(&data);
+    extension
+}
+
+#[test]
+fn test_parse_server_name() {
+    // Put an unrelated extension in front of server_name
+    let mut extensions = vec![0x00, 0x17, 0x00, 0x00];
+    extensions.extend_from_slice(&server_name_extension("Example.COM"));
+
+    let message = client_hello(&extensions);
+    assert_eq!(parse_server_name(&message).as_deref(), Some("example.com"));
+}
+
+#[test]
+fn test_parse_server_name_missing() {
+    assert_eq!(parse_server_name(&client_hello(&[])), None);
+
+    let message = client_hello(&server_name_extension("example.com"));
+    assert_eq!(parse_server_name(&message[..message.len() - 3]), None);
+}
+
+#[tokio::test]
+async fn test_read_client_hello() {
+    let message = client_hello(&server_name_extension("example.com"));
+    let mut record = vec![0x16, 0x03, 0x01];
+    record.extend_from_slice(&(message.len() as u16).to_be_bytes());
+    record.extend_from_slice(&message);
+
+    let mut stream = record.as_slice();
+    let (consumed, server_name) = read_client_hello(&mut stream).await.unwrap();
+
+    assert_eq!(consumed, record);
+    assert_eq!(server_name.as_deref(), Some("example.com"));
+}
+
+#[tokio::test]
+async fn test_record_stream_boundary() {
+    let records = [
+        &[0x16, 0x03, 0x03, 0x00, 0x02, 0xaa, 0xbb][..],
+        &[0x17, 0x03, 0x03, 0x00, 0x03, 0x01, 0x02, 0x03][..],
+    ]
+    .concat();
+
+    let mut stream = RecordStream::new(records.as_slice(), true);
+    assert!(stream.at_record_boundary());
+
+    // Within the header and then within the payload of the first record
+    let mut buf = vec![0u8; 3];
+    stream.read_exact(&mut buf).await.unwrap();
+    assert!(!stream.at_record_boundary());
+    stream.read_exact(&mut buf[..3]).await.unwrap();
+    assert!(!stream.at_record_boundary());
+    stream.read_exact(&mut buf[..1]).await.unwrap();
+    assert!(stream.at_record_boundary());
+
+    let mut rest = Vec::new();
+    stream.read_to_end(&mut rest).await.unwrap();
+    assert_eq!(rest.len(), 8);
+    assert!(stream.at_record_boundary());
+
+    // Nothing is known of the streams that aren't tracked
+    let stream = RecordStream::new(records.as_slice(), false);
+    assert!(!stream.at_record_boundary());
+}
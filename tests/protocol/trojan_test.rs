@@ -0,0 +1,150 @@
+use std::io::ErrorKind;
+use std::time::Duration;
+use tokio::io::AsyncReadExt;
+use trojan_rust::protocol::common::addr::{IpAddrPort, IpAddress};
+use trojan_rust::protocol::common::atype::Atype;
+use trojan_rust::protocol::common::command::Command;
+use trojan_rust::protocol::common::request::{InboundRequest, TransportProtocol};
+use trojan_rust::protocol::trojan::packet::{encode_udp, packet_size, TrojanPacketWriter};
+use trojan_rust::protocol::trojan::{handshake, read_request, HEX_SIZE};
+use trojan_rust::proxy::base::SupportedProtocols;
+
+fn request_header() -> Vec<u8> {
+    let mut header = vec![b'a'; HEX_SIZE];
+    header.extend_from_slice(b"\r\n");
+    // CONNECT to 127.0.0.1:80
+    header.extend_from_slice(&[0x01, 0x01, 127, 0, 0, 1, 0x00, 0x50]);
+    header.extend_from_slice(b"\r\n");
+    header
+}
+
+#[tokio::test]
+async fn test_read_request_with_payload() {
+    let mut data = request_header();
+    data.extend_from_slice(b"payload");
+
+    let mut stream = data.as_slice();
+    let mut buf = Vec::new();
+    let (request, header_size) = read_request(&mut stream, &mut buf).await.unwrap();
+
+    assert!(request.validate(&[b'a'; HEX_SIZE]));
+    assert_eq!(header_size, request_header().len());
+    assert_eq!(&buf[header_size..], b"payload");
+}
+
+#[tokio::test]
+async fn test_read_request_rejects_http() {
+    let data = b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n".to_vec();
+
+    let mut stream = data.as_slice();
+    let mut buf = Vec::new();
+    let err = read_request(&mut stream, &mut buf).await.err().unwrap();
+
+    assert_eq!(err.kind(), ErrorKind::InvalidData);
+    assert_eq!(buf, data);
+}
+
+#[tokio::test]
+async fn test_handshake_keeps_domain() {
+    let request = InboundRequest::new(
+        Atype::DomainName,
+        IpAddress::from_host("example.com"),
+        Command::Connect,
+        443,
+        TransportProtocol::TCP,
+        SupportedProtocols::SOCKS,
+    );
+
+    let mut data = Vec::new();
+    handshake(&mut data, &request, &[b'a'; HEX_SIZE])
+        .await
+        .unwrap();
+
+    let mut stream = data.as_slice();
+    let mut buf = Vec::new();
+    let (request, header_size) = read_request(&mut stream, &mut buf).await.unwrap();
+    assert_eq!(header_size, data.len());
+
+    let request = request.into_request();
+    assert!(matches!(request.atype, Atype::DomainName));
+    assert_eq!(request.addr_port.to_string(), "example.com:443");
+}
+
+#[tokio::test]
+async fn test_long_domain_rejected() {
+    let domain = format!("{}.example.com", "a".repeat(250));
+    let request = InboundRequest::new(
+        Atype::DomainName,
+        IpAddress::from_host(&domain),
+        Command::Connect,
+        443,
+        TransportProtocol::TCP,
+        SupportedProtocols::SOCKS,
+    );
+
+    // The length of the domain name doesn't fit in its byte
+    let mut data = Vec::new();
+    let e = handshake(&mut data, &request, &[b'a'; HEX_SIZE])
+        .await
+        .unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::InvalidInput);
+
+    let e = encode_udp(&request.addr_port, b"payload").unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::InvalidInput);
+}
+
+#[test]
+fn test_udp_packet_size() {
+    for host in ["1.1.1.1", "2606:4700::1111", "example.com"] {
+        let addr = IpAddrPort::new(IpAddress::from_host(host), 53);
+        assert_eq!(
+            packet_size(&addr, 100),
+            encode_udp(&addr, &[0u8; 100]).unwrap().len()
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_packet_writer_shutdown_after_cancelled_flush() {
+    // The stream only takes a few bytes until the other end reads, so the flush is cancelled mid packet
+    let (client, mut server) = tokio::io::duplex(16);
+    let mut writer = TrojanPacketWriter::new(client);
+    let addr = IpAddrPort::new(IpAddress::from_host("1.1.1.1"), 53);
+    writer.push(&addr, &[1u8; 100]).unwrap();
+    writer.push(&addr, &[2u8; 100]).unwrap();
+    assert!(
+        tokio::time::timeout(Duration::from_millis(10), writer.flush())
+            .await
+            .is_err()
+    );
+
+    let reader = tokio::spawn(async move {
+        let mut received = Vec::new();
+        server.read_to_end(&mut received).await.unwrap();
+        received
+    });
+    writer.shutdown().await.unwrap();
+
+    let mut expected = encode_udp(&addr, &[1u8; 100]).unwrap();
+    expected.extend_from_slice(&encode_udp(&addr, &[2u8; 100]).unwrap());
+    assert_eq!(reader.await.unwrap(), expected);
+}
+
+#[tokio::test]
+async fn test_packet_writer_splits_large_payloads() {
+    let (client, mut server) = tokio::io::duplex(4096);
+    let addr = IpAddrPort::new(IpAddress::from_host("1.1.1.1"), 53);
+    let mut writer =
+        TrojanPacketWriter::new(client).with_max_packet_size(Some(packet_size(&addr, 100)));
+    writer.push(&addr, &[1u8; 250]).unwrap();
+    writer.push(&addr, &[2u8; 100]).unwrap();
+    writer.shutdown().await.unwrap();
+
+    let mut received = Vec::new();
+    server.read_to_end(&mut received).await.unwrap();
+    let mut expected = Vec::new();
+    for payload in [&[1u8; 100][..], &[1u8; 100], &[1u8; 50], &[2u8; 100]] {
+        expected.extend_from_slice(&encode_udp(&addr, payload).unwrap());
+    }
+    assert_eq!(received, expected);
+}
@@ -0,0 +1,38 @@
+use std::io::ErrorKind;
+use std::time::Duration;
+use tokio::io::AsyncReadExt;
+use trojan_rust::config::base::OutboundConfig;
+use trojan_rust::protocol::common::addr::IpAddress;
+use trojan_rust::protocol::common::atype::Atype;
+use trojan_rust::protocol::common::command::Command;
+use trojan_rust::protocol::common::request::{InboundRequest, TransportProtocol};
+use trojan_rust::proxy::base::SupportedProtocols;
+use trojan_rust::proxy::deadline::Deadline;
+use trojan_rust::proxy::tcp::handler::TcpHandler;
+
+#[tokio::test]
+async fn test_block_closes_connection() {
+    let config: OutboundConfig =
+        serde_json::from_str(r#"{ "mode": "BLOCK", "protocol": "DIRECT" }"#).unwrap();
+    let handler = TcpHandler::new(&config).unwrap();
+
+    let request = InboundRequest::new(
+        Atype::IPv4,
+        IpAddress::IpAddr("10.0.0.1".parse().unwrap()),
+        Command::Connect,
+        80,
+        TransportProtocol::TCP,
+        SupportedProtocols::TROJAN,
+    );
+    let (mut client, inbound) = tokio::io::duplex(64);
+
+    let err = handler
+        .dispatch(inbound, request, Deadline::after(Duration::from_secs(5)))
+        .await
+        .unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+
+    // Nothing is sent back before the connection is closed
+    let mut buf = Vec::new();
+    assert_eq!(client.read_to_end(&mut buf).await.unwrap(), 0);
+}
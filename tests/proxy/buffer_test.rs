@@ -0,0 +1,35 @@
+use trojan_rust::proxy::buffer::BufferPool;
+
+fn pool(size: usize, max_idle: usize) -> &'static BufferPool {
+    Box::leak(Box::new(BufferPool::new(size, max_idle)))
+}
+
+#[test]
+fn test_buffers_are_reused() {
+    let pool = pool(64, 2);
+
+    let mut buf = pool.take();
+    buf.extend_from_slice(b"packet");
+    let address = buf.as_ptr();
+    drop(buf);
+    assert_eq!(pool.idle(), 1);
+
+    // The same allocation comes back empty
+    let buf = pool.take_zeroed(16);
+    assert_eq!(buf.as_ptr(), address);
+    assert_eq!(buf.as_slice(), &[0u8; 16]);
+    assert_eq!(pool.idle(), 0);
+}
+
+#[test]
+fn test_pool_bounds_idle_memory() {
+    let pool = pool(64, 2);
+
+    // Buffers grown far beyond the size of the pool are freed
+    drop(pool.take_zeroed(4096));
+    assert_eq!(pool.idle(), 0);
+
+    let buffers: Vec<_> = (0..3).map(|_| pool.take()).collect();
+    drop(buffers);
+    assert_eq!(pool.idle(), 2);
+}
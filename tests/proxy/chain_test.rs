@@ -0,0 +1,42 @@
+use std::sync::Arc;
+use tokio::io::AsyncReadExt;
+use tokio::net::TcpListener;
+use trojan_rust::auth::secret::secret_hex;
+use trojan_rust::config::base::OutboundConfig;
+use trojan_rust::proxy::tcp::handler::TcpHandler;
+
+fn handler(config: &str) -> TcpHandler {
+    let config: OutboundConfig = serde_json::from_str(config).unwrap();
+    TcpHandler::new(&config).unwrap()
+}
+
+#[tokio::test]
+async fn test_dial_through_another_outbound() {
+    let first_hop = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let first_port = first_hop.local_addr().unwrap().port();
+
+    let dialer = handler(&format!(
+        r#"{{ "mode": "TCP", "protocol": "TROJAN", "address": "127.0.0.1", "port": {}, "secret": "first" }}"#,
+        first_port
+    ));
+    let chained = handler(
+        r#"{
+            "mode": "TCP", "protocol": "TROJAN", "address": "10.0.0.2", "port": 8443, "secret": "second",
+            "dialer": "first"
+        }"#,
+    )
+    .with_dialer(Arc::new(dialer));
+
+    tokio::spawn(async move { chained.probe().await });
+
+    // The first hop is asked to connect to the server of the chained outbound
+    let (mut stream, _) = first_hop.accept().await.unwrap();
+    let mut header = vec![0u8; 56 + 2 + 1 + 1 + 4 + 2 + 2];
+    stream.read_exact(&mut header).await.unwrap();
+
+    assert_eq!(&header[..56], secret_hex("first").as_slice());
+    assert_eq!(&header[56..60], &[b'\r', b'\n', 1, 1]);
+    assert_eq!(&header[60..64], &[10, 0, 0, 2]);
+    assert_eq!(&header[64..66], &8443u16.to_be_bytes());
+    assert_eq!(&header[66..], b"\r\n");
+}
@@ -0,0 +1,170 @@
+use crate::proxy::sim::{run_shared, ScriptedReader, ScriptedWriter, Step};
+
+use std::future::pending;
+use std::io::ErrorKind;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::UdpSocket;
+use trojan_rust::protocol::trojan::packet::{
+    copy_client_reader_to_udp_socket, copy_udp_socket_to_client_writer, TrojanPacketWriter,
+};
+use trojan_rust::proxy::connections::{active_connections, close_connections, ActiveConnection};
+use trojan_rust::proxy::context::TrafficContext;
+use trojan_rust::proxy::relay::{relay, Transfer};
+use trojan_rust::proxy::udp::guard::UdpGuard;
+
+const ID: u64 = u64::MAX - 1;
+
+const UDP_ID: u64 = u64::MAX - 3;
+
+fn listed_by_id(id: u64) -> Option<ActiveConnection> {
+    active_connections()
+        .into_iter()
+        .find(|connection| connection.id == id)
+}
+
+fn listed() -> Option<ActiveConnection> {
+    listed_by_id(ID)
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_active_connections_count_bytes_live() {
+    let transfer = Transfer::new();
+    let registration = ActiveConnection {
+        id: ID,
+        source: "127.0.0.1:40000".parse().unwrap(),
+        context: TrafficContext::new(Some("trojan"), Some("direct"), Some("alice"))
+            .with_destination("example.com:443".to_string()),
+        transport: "tcp",
+        mode: "tcp",
+        transfer: transfer.clone(),
+    }
+    .register();
+
+    let (server_writer, _) = ScriptedWriter::new(16);
+    let (client_writer, _) = ScriptedWriter::new(16);
+    let relayed = transfer.clone();
+    let relay = tokio::spawn(async move {
+        relayed
+            .scope(relay(
+                ScriptedReader::new(vec![
+                    Step::Data(b"hello"),
+                    Step::Pause(Duration::from_secs(2)),
+                    Step::Close,
+                ]),
+                client_writer,
+                ScriptedReader::new(vec![
+                    Step::Data(b"reply!"),
+                    Step::Pause(Duration::from_secs(3)),
+                    Step::Close,
+                ]),
+                server_writer,
+            ))
+            .await
+    });
+
+    // The bytes are counted while the relay is still running
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    let connection = listed().unwrap();
+    assert_eq!(connection.context.user.as_deref(), Some("alice"));
+    assert_eq!(connection.transfer.uploaded(), 5);
+    assert_eq!(connection.transfer.downloaded(), 6);
+
+    relay.await.unwrap().unwrap();
+    drop(registration);
+    assert!(listed().is_none());
+}
+
+#[tokio::test]
+async fn test_close_connections_of_user() {
+    let registration = ActiveConnection {
+        id: ID - 1,
+        source: "127.0.0.1:40001".parse().unwrap(),
+        context: TrafficContext::new(Some("trojan"), Some("direct"), Some("mallory")),
+        transport: "quic",
+        mode: "tcp",
+        transfer: Transfer::new(),
+    }
+    .register();
+
+    let user = Some("mallory".to_string());
+    assert_eq!(
+        close_connections(|connection| connection.context.user == user),
+        1
+    );
+    // Connections already closed aren't counted again
+    assert_eq!(
+        close_connections(|connection| connection.context.user == user),
+        0
+    );
+
+    assert!(registration.is_closed());
+    let result = registration
+        .until_closed(pending::<std::io::Result<()>>())
+        .await;
+    assert_eq!(result.unwrap_err().kind(), ErrorKind::ConnectionAborted);
+}
+
+#[test]
+fn test_active_connections_count_udp_bytes() {
+    run_shared(async {
+        let transfer = Transfer::new();
+        let registration = ActiveConnection {
+            id: UDP_ID,
+            source: "127.0.0.1:40001".parse().unwrap(),
+            context: TrafficContext::new(Some("trojan"), Some("direct"), Some("bob")),
+            transport: "tcp",
+            mode: "udp",
+            transfer: transfer.clone(),
+        }
+        .register();
+
+        let echo = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let port = echo.local_addr().unwrap().port();
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let guard = Arc::new(UdpGuard::new(None));
+
+        // Trojan UDP packet carrying hello to the echo socket
+        let mut packet = vec![1, 127, 0, 0, 1];
+        packet.extend_from_slice(&port.to_be_bytes());
+        packet.extend_from_slice(&5u16.to_be_bytes());
+        packet.extend_from_slice(b"\r\nhello");
+
+        let (mut client, client_reader) = tokio::io::duplex(1024);
+        let (mut replies, client_writer) = tokio::io::duplex(1024);
+        client.write_all(&packet).await.unwrap();
+
+        let relayed = transfer.clone();
+        let session = tokio::spawn(async move {
+            let mut client_writer = TrojanPacketWriter::new(client_writer);
+            relayed
+                .scope(async {
+                    tokio::select!(
+                        _ = copy_client_reader_to_udp_socket(client_reader, &socket, &guard) => (),
+                        _ = copy_udp_socket_to_client_writer(&socket, &mut client_writer, &guard) => (),
+                    )
+                })
+                .await
+        });
+
+        let mut buf = [0u8; 16];
+        let (size, source) = echo.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..size], b"hello");
+        echo.send_to(b"reply!", source).await.unwrap();
+
+        // The reply reaches the client as a trojan UDP packet once it is counted
+        let mut reply = [0u8; 17];
+        replies.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply[11..], b"reply!");
+
+        let connection = listed_by_id(UDP_ID).unwrap();
+        assert_eq!(connection.mode, "udp");
+        assert_eq!(connection.transfer.uploaded(), 5);
+        assert_eq!(connection.transfer.downloaded(), 6);
+
+        session.abort();
+        drop(registration);
+        assert!(listed_by_id(UDP_ID).is_none());
+    });
+}
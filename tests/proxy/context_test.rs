@@ -0,0 +1,51 @@
+use trojan_rust::metrics;
+use trojan_rust::proxy::context::TrafficContext;
+
+#[test]
+fn test_context_labels() {
+    let context = TrafficContext::new(Some("socks"), Some("proxy"), None);
+
+    assert_eq!(
+        context.label("connections_total"),
+        "connections_total{inbound=\"socks\",outbound=\"proxy\",user=\"\"}"
+    );
+    assert_eq!(
+        context.label("timeouts_total{stage=\"dns\"}"),
+        "timeouts_total{stage=\"dns\",inbound=\"socks\",outbound=\"proxy\",user=\"\"}"
+    );
+    assert_eq!(context.to_string(), "inbound=socks outbound=proxy");
+}
+
+#[test]
+fn test_context_labels_escaped() {
+    let context = TrafficContext::new(Some("socks"), None, Some("a\"b\\c\nd"));
+
+    assert_eq!(
+        context.labels(),
+        "inbound=\"socks\",outbound=\"\",user=\"a\\\"b\\\\c\\nd\""
+    );
+}
+
+#[tokio::test]
+async fn test_context_scope_labels_counters() {
+    let context = TrafficContext::new(Some("trojan"), Some("direct"), Some("alice"));
+
+    metrics::increment("context_test_total", 1);
+    context
+        .clone()
+        .scope(async {
+            assert_eq!(TrafficContext::current(), Some(context.clone()));
+            metrics::increment("context_test_total", 2);
+            metrics::increment_attributed("context_test_total", 4);
+        })
+        .await;
+    assert_eq!(TrafficContext::current(), None);
+
+    // Only the attributed counters carry the labels
+    let snapshot = metrics::snapshot();
+    assert_eq!(snapshot["context_test_total"], 3);
+    assert_eq!(
+        snapshot["context_test_total{inbound=\"trojan\",outbound=\"direct\",user=\"alice\"}"],
+        4
+    );
+}
@@ -0,0 +1,65 @@
+use std::io::ErrorKind;
+use std::time::Duration;
+use tokio::time::{advance, sleep};
+use trojan_rust::proxy::deadline::Deadline;
+
+#[tokio::test(start_paused = true)]
+async fn test_deadline_shared_between_stages() {
+    let deadline = Deadline::after(Duration::from_millis(200));
+
+    // The first stage consumes most of the budget
+    let result = deadline
+        .run("dns", async {
+            advance(Duration::from_millis(150)).await;
+            Ok(())
+        })
+        .await;
+    assert!(result.is_ok());
+    assert_eq!(deadline.remaining(), Duration::from_millis(50));
+
+    // The next stage only gets what is left
+    let err = deadline
+        .run("connect", async {
+            sleep(Duration::from_millis(150)).await;
+            Ok(())
+        })
+        .await
+        .err()
+        .unwrap();
+    assert_eq!(err.kind(), ErrorKind::TimedOut);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_handshake_timeout_shared_between_handshakes() {
+    let deadline = Deadline::after(Duration::from_secs(30))
+        .with_handshake_timeout(Some(Duration::from_secs(5)));
+
+    // TLS leaves the proxy handshake the rest of the handshake timeout
+    let result = deadline
+        .run("tls", async {
+            sleep(Duration::from_secs(3)).await;
+            Ok(())
+        })
+        .await;
+    assert!(result.is_ok());
+
+    let err = deadline
+        .run("handshake", async {
+            sleep(Duration::from_secs(3)).await;
+            Ok(())
+        })
+        .await
+        .err()
+        .unwrap();
+    assert_eq!(err.kind(), ErrorKind::TimedOut);
+    assert_eq!(err.to_string(), "handshake timed out");
+
+    // The stages after the handshakes only answer to the deadline
+    let result = deadline
+        .run("dns", async {
+            sleep(Duration::from_secs(3)).await;
+            Ok(())
+        })
+        .await;
+    assert!(result.is_ok());
+}
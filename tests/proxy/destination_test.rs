@@ -0,0 +1,48 @@
+use std::io::ErrorKind;
+use trojan_rust::config::base::InboundConfig;
+use trojan_rust::proxy::destination::DestinationFilter;
+
+fn filter(options: &str) -> DestinationFilter {
+    let inbound: InboundConfig = serde_json::from_str(&format!(
+        r#"{{ "mode": "TCP", "protocol": "TROJAN", "address": "0.0.0.0", "port": 443 {} }}"#,
+        options
+    ))
+    .unwrap();
+    DestinationFilter::new(&inbound).unwrap()
+}
+
+#[test]
+fn test_private_destinations_blocked() {
+    let filter =
+        filter(r#", "block_private": true, "allowed_destinations": ["10.1.0.0/16", "fd00::53"]"#);
+
+    for ip in [
+        "10.0.0.1",
+        "192.168.1.1",
+        "127.0.0.1",
+        "169.254.169.254",
+        "0.0.0.0",
+        "::1",
+        "fe80::1",
+        "fd00::1",
+        "::ffff:172.16.0.1",
+    ] {
+        assert!(!filter.allows(ip.parse().unwrap()), "{}", ip);
+    }
+    for ip in ["8.8.8.8", "2001:4860:4860::8888", "10.1.2.3", "fd00::53"] {
+        assert!(filter.allows(ip.parse().unwrap()), "{}", ip);
+    }
+
+    let err = filter
+        .check("127.0.0.1:22".parse().unwrap(), "tcp")
+        .unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+    assert!(filter.check("1.1.1.1:443".parse().unwrap(), "tcp").is_ok());
+}
+
+#[test]
+fn test_private_destinations_allowed_by_default() {
+    let filter = filter("");
+    assert!(filter.allows("127.0.0.1".parse().unwrap()));
+    assert!(filter.allows("10.0.0.1".parse().unwrap()));
+}
@@ -0,0 +1,91 @@
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use trojan_rust::config::base::FallbackConfig;
+use trojan_rust::proxy::socket::SocketOptions;
+use trojan_rust::proxy::tcp::fallback::Fallback;
+
+const API_REQUEST: &[u8] = b"GET /api/users HTTP/1.1\r\nHost: example.com\r\n\r\n";
+const PAGE_REQUEST: &[u8] = b"GET /index.html HTTP/1.1\r\nHost: example.com\r\n\r\n";
+
+fn target(address: String, alpn: Option<&str>, path: Option<&str>) -> FallbackConfig {
+    FallbackConfig {
+        address,
+        alpn: alpn.map(String::from),
+        path: path.map(String::from),
+    }
+}
+
+/// Serve the stream, with its head split where the trojan handshake gave up, and return what the server got.
+async fn fall_back(
+    fallback: &Fallback,
+    server: &TcpListener,
+    request: &[u8],
+    alpn: Option<&[u8]>,
+) -> Vec<u8> {
+    let (mut client, inbound) = tokio::io::duplex(1024);
+    client.write_all(&request[12..]).await.unwrap();
+    client.shutdown().await.unwrap();
+
+    let serve = fallback.serve(request[..12].to_vec(), inbound, alpn);
+    let accept = async {
+        let (mut stream, _) = server.accept().await.unwrap();
+        let mut buf = vec![0u8; request.len()];
+        stream.read_exact(&mut buf).await.unwrap();
+        buf
+    };
+    let (_, received) = tokio::join!(serve, accept);
+    received
+}
+
+#[tokio::test]
+async fn test_fallback_conditions() {
+    let api = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let h2 = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let web = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = |listener: &TcpListener| listener.local_addr().unwrap().to_string();
+
+    let fallback = Fallback::new(
+        vec![
+            target(address(&h2), Some("h2"), None),
+            target(address(&api), None, Some("/api/")),
+            target(address(&web), None, None),
+        ],
+        false,
+        SocketOptions::default(),
+    );
+
+    // The request line is read whole before matching the path
+    assert_eq!(
+        fall_back(&fallback, &api, API_REQUEST, None).await,
+        API_REQUEST
+    );
+    assert_eq!(
+        fall_back(&fallback, &h2, API_REQUEST, Some(b"h2")).await,
+        API_REQUEST
+    );
+    assert_eq!(
+        fall_back(&fallback, &web, PAGE_REQUEST, Some(b"http/1.1")).await,
+        PAGE_REQUEST
+    );
+}
+
+#[tokio::test]
+async fn test_fallback_skips_unreachable() {
+    let down = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let down_address = down.local_addr().unwrap().to_string();
+    drop(down);
+    let web = TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+    let fallback = Fallback::new(
+        vec![
+            target(down_address, None, None),
+            target(web.local_addr().unwrap().to_string(), None, None),
+        ],
+        false,
+        SocketOptions::default(),
+    );
+    assert_eq!(
+        fall_back(&fallback, &web, PAGE_REQUEST, None).await,
+        PAGE_REQUEST
+    );
+}
@@ -0,0 +1,66 @@
+use std::fs::{self, File};
+use std::io::ErrorKind;
+use std::time::{Duration, SystemTime};
+use trojan_rust::config::base::IpFilterConfig;
+use trojan_rust::proxy::filter::IpFilter;
+
+#[test]
+fn test_allow_and_deny_lists() {
+    let config: IpFilterConfig = serde_json::from_str(
+        r#"{ "allow": ["10.0.0.0/8", "2001:db8::/32"], "deny": ["10.0.0.1"] }"#,
+    )
+    .unwrap();
+    let filter = IpFilter::new(&config, "source").unwrap();
+
+    assert!(filter.allows("10.1.2.3".parse().unwrap()));
+    assert!(filter.allows("::ffff:10.1.2.3".parse().unwrap()));
+    assert!(filter.allows("2001:db8::1".parse().unwrap()));
+    // Denied within the allowed range, and missing from the allow list
+    assert!(!filter.allows("10.0.0.1".parse().unwrap()));
+    assert!(!filter.allows("8.8.8.8".parse().unwrap()));
+
+    let err = filter.check("8.8.8.8".parse().unwrap()).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+}
+
+#[test]
+fn test_deny_file_reloaded() {
+    let path = std::env::temp_dir().join(format!("trojan-filter-{}.txt", std::process::id()));
+    fs::write(&path, "# scanners\n192.0.2.0/24\n").unwrap();
+    let config = IpFilterConfig {
+        allow: None,
+        deny: None,
+        allow_file: None,
+        deny_file: Some(path.to_str().unwrap().to_string()),
+    };
+    let filter = IpFilter::new(&config, "source").unwrap();
+    assert!(!filter.allows("192.0.2.7".parse().unwrap()));
+    assert!(filter.allows("198.51.100.7".parse().unwrap()));
+    assert!(!filter.reload().unwrap());
+
+    fs::write(&path, "198.51.100.7 # abuse\n").unwrap();
+    let modified = SystemTime::now() + Duration::from_secs(10);
+    File::options()
+        .write(true)
+        .open(&path)
+        .unwrap()
+        .set_modified(modified)
+        .unwrap();
+    assert!(filter.reload().unwrap());
+    assert!(filter.allows("192.0.2.7".parse().unwrap()));
+    assert!(!filter.allows("198.51.100.7".parse().unwrap()));
+
+    // A broken file keeps the lists in use
+    fs::write(&path, "not an address\n").unwrap();
+    let modified = modified + Duration::from_secs(10);
+    File::options()
+        .write(true)
+        .open(&path)
+        .unwrap()
+        .set_modified(modified)
+        .unwrap();
+    assert!(filter.reload().is_err());
+    assert!(!filter.allows("198.51.100.7".parse().unwrap()));
+
+    fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,76 @@
+use rustls::{ClientConfig, ServerName};
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+use trojan_rust::config::base::InboundTlsConfig;
+use trojan_rust::config::tls::{make_server_config, NoCertificateVerification};
+use trojan_rust::protocol::common::stream::{IntoTcpStream, StandardTcpStream};
+use trojan_rust::protocol::tls::record::RecordStream;
+use trojan_rust::proxy::ktls::Ktls;
+
+#[tokio::test]
+async fn test_ktls_takes_over_session() {
+    // Nothing to test where the kernel doesn't support TLS
+    if Ktls::init().is_none() {
+        return;
+    }
+
+    let server_config = make_server_config(&InboundTlsConfig {
+        cert_path: "./config/cert.pem".to_string(),
+        key_path: "./config/key.pem".to_string(),
+        server_names: None,
+        sni_routes: None,
+        session_ticket_rotation: None,
+        expiry_warning_days: None,
+        alpn: None,
+        ktls: Some(true),
+    })
+    .unwrap();
+    let client_config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_custom_certificate_verifier(Arc::new(NoCertificateVerification {}))
+        .with_no_client_auth();
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let server = tokio::spawn(async move {
+        let (socket, _) = listener.accept().await.unwrap();
+        let tls = TlsAcceptor::from(server_config)
+            .accept(RecordStream::new(socket, true))
+            .await
+            .unwrap();
+        let mut stream = StandardTcpStream::RustlsServer(tls);
+
+        let mut request = [0u8; 5];
+        stream.read_exact(&mut request).await.unwrap();
+        assert_eq!(&request, b"hello");
+
+        // The rest of the first record was decrypted by rustls already, the kernel decrypts and encrypts from now on
+        let (mut socket, head) = match stream.into_tcp_stream() {
+            Ok(offloaded) => offloaded,
+            Err(_) => panic!("kernel TLS didn't take over the session"),
+        };
+        assert_eq!(head, b"world");
+
+        socket.write_all(b"pong").await.unwrap();
+        let mut again = [0u8; 5];
+        socket.read_exact(&mut again).await.unwrap();
+        assert_eq!(&again, b"again");
+    });
+
+    let socket = TcpStream::connect(addr).await.unwrap();
+    let mut client = TlsConnector::from(Arc::new(client_config))
+        .connect(ServerName::try_from("localhost").unwrap(), socket)
+        .await
+        .unwrap();
+    client.write_all(b"helloworld").await.unwrap();
+
+    let mut reply = [0u8; 4];
+    client.read_exact(&mut reply).await.unwrap();
+    assert_eq!(&reply, b"pong");
+    client.write_all(b"again").await.unwrap();
+
+    server.await.unwrap();
+}
@@ -0,0 +1,103 @@
+use std::net::IpAddr;
+use std::time::Duration;
+use trojan_rust::config::base::ConnectionLimitConfig;
+use trojan_rust::metrics;
+use trojan_rust::proxy::limiter::{
+    acquire_slots, ConcurrencyLimit, ConnectionLimiter, MAX_TRACKED_SOURCES,
+};
+
+fn limiter(config: &str) -> ConnectionLimiter {
+    let config: ConnectionLimitConfig = serde_json::from_str(config).unwrap();
+    ConnectionLimiter::new(&config)
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_per_source_limit() {
+    let limiter = limiter(r#"{ "per_ip_rate": 1, "per_ip_burst": 2 }"#);
+    let (a, b): (IpAddr, IpAddr) = ("10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap());
+
+    assert!(limiter.allow(a));
+    assert!(limiter.allow(a));
+    assert!(!limiter.allow(a));
+
+    // Other sources have their own budget
+    assert!(limiter.allow(b));
+
+    // The budget refills at the rate
+    tokio::time::advance(Duration::from_secs(1)).await;
+    assert!(limiter.allow(a));
+    assert!(!limiter.allow(a));
+}
+
+#[test]
+fn test_per_source_limit_tracks_bounded_sources() {
+    let limiter = limiter(r#"{ "per_ip_rate": 1, "per_ip_burst": 1 }"#);
+    let first: IpAddr = "10.0.0.1".parse().unwrap();
+
+    assert!(limiter.allow(first));
+    assert!(!limiter.allow(first));
+
+    // A scan from many addresses forgets the sources seen first instead of tracking them all
+    for i in 0..MAX_TRACKED_SOURCES as u32 {
+        assert!(limiter.allow(IpAddr::from((0x0b00_0000 + i).to_be_bytes())));
+    }
+    assert_eq!(limiter.tracked_sources(), MAX_TRACKED_SOURCES);
+    assert!(limiter.allow(first));
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_per_source_delay() {
+    let limiter = limiter(r#"{ "per_ip_rate": 1, "per_ip_burst": 1, "per_ip_delay": 2 }"#);
+    let source: IpAddr = "10.0.0.1".parse().unwrap();
+
+    // Each connection over the rate waits for the ones delayed before it, until the wait gets too long
+    assert_eq!(limiter.admit(source), Some(Duration::ZERO));
+    assert_eq!(limiter.admit(source), Some(Duration::from_secs(1)));
+    assert_eq!(limiter.admit(source), Some(Duration::from_secs(2)));
+    assert_eq!(limiter.admit(source), None);
+}
+
+#[test]
+fn test_global_limit() {
+    let limiter = limiter(r#"{ "rate": 1, "burst": 3 }"#);
+
+    for i in 0..3 {
+        assert!(limiter.allow(IpAddr::from([10, 0, 0, i])));
+    }
+    assert!(!limiter.allow("10.0.0.100".parse().unwrap()));
+}
+
+#[tokio::test]
+async fn test_concurrency_limit_rejects() {
+    let inbound =
+        ConcurrencyLimit::new(3, false, "inbound", "limiter_test_inbound".to_string()).unwrap();
+    let listener =
+        ConcurrencyLimit::new(2, false, "listener", "limiter_test_listener".to_string()).unwrap();
+
+    // The listener runs out first, and a rejected connection takes no slot of the inbound
+    let first = acquire_slots(&[&listener, &inbound]).await.unwrap();
+    let second = acquire_slots(&[&listener, &inbound]).await.unwrap();
+    assert!(acquire_slots(&[&listener, &inbound]).await.is_none());
+    assert_eq!(inbound.active(), 2);
+    assert_eq!(metrics::snapshot()["limiter_test_listener"], 2);
+
+    drop(first);
+    assert_eq!(listener.active(), 1);
+    assert_eq!(metrics::snapshot()["limiter_test_inbound"], 1);
+    assert!(acquire_slots(&[&listener, &inbound]).await.is_some());
+    drop(second);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_concurrency_limit_queues() {
+    let limit =
+        ConcurrencyLimit::new(1, true, "inbound", "limiter_test_queue".to_string()).unwrap();
+    let slot = limit.acquire().await.unwrap();
+
+    // The next connection waits until the open one is closed
+    let queued = tokio::time::timeout(Duration::from_secs(1), limit.acquire()).await;
+    assert!(queued.is_err());
+
+    drop(slot);
+    assert!(limit.acquire().await.is_some());
+}
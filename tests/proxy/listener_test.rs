@@ -0,0 +1,24 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use trojan_rust::proxy::listener::{bind_tcp, resolve_listen_addresses};
+
+#[test]
+fn test_resolve_listen_addresses() {
+    assert_eq!(
+        resolve_listen_addresses("[::1]", 1080).unwrap(),
+        vec![SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 1080)]
+    );
+    assert_eq!(
+        resolve_listen_addresses("::", 1080).unwrap(),
+        vec![SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 1080)]
+    );
+
+    let localhost = resolve_listen_addresses("localhost", 1080).unwrap();
+    assert!(localhost.contains(&SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 1080)));
+}
+
+#[tokio::test]
+async fn test_bind_tcp() {
+    let listeners = bind_tcp("127.0.0.1", 0, None).unwrap();
+    assert_eq!(listeners.len(), 1);
+    assert!(listeners[0].local_addr().unwrap().ip().is_loopback());
+}
@@ -0,0 +1,56 @@
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use trojan_rust::config::base::OutboundConfig;
+use trojan_rust::protocol::common::addr::IpAddress;
+use trojan_rust::protocol::common::atype::Atype;
+use trojan_rust::protocol::common::command::Command;
+use trojan_rust::protocol::common::request::{InboundRequest, TransportProtocol};
+use trojan_rust::proxy::base::SupportedProtocols;
+use trojan_rust::proxy::deadline::Deadline;
+use trojan_rust::proxy::tcp::handler::TcpHandler;
+
+#[tokio::test]
+async fn test_dispatch_piped() {
+    let config: OutboundConfig =
+        serde_json::from_str(r#"{ "mode": "DIRECT", "protocol": "DIRECT" }"#).unwrap();
+    let handler = TcpHandler::new(&config).unwrap();
+
+    // The destination answers the request and closes the connection
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut buf = [0u8; 4];
+        stream.read_exact(&mut buf).await.unwrap();
+        stream
+            .write_all(&[&buf[..], b" pong"].concat())
+            .await
+            .unwrap();
+    });
+
+    let request = InboundRequest::new(
+        Atype::IPv4,
+        IpAddress::IpAddr("127.0.0.1".parse().unwrap()),
+        Command::Connect,
+        port,
+        TransportProtocol::TCP,
+        SupportedProtocols::TROJAN,
+    );
+    // The client keeps its side open, the request ends with the connection to the destination
+    let (mut client, client_reader) = tokio::io::duplex(64);
+    client.write_all(b"ping").await.unwrap();
+    let mut downloaded = Vec::new();
+    let buf = &mut downloaded;
+    handler
+        .dispatch_piped(
+            request,
+            Deadline::after(Duration::from_secs(5)),
+            client_reader,
+            |mut server_reader| async move { server_reader.read_to_end(buf).await.map(|_| ()) },
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(downloaded, b"ping pong");
+}
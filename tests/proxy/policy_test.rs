@@ -0,0 +1,75 @@
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Duration;
+use tokio::time::{sleep, Instant};
+use trojan_rust::config::base::PolicyConfig;
+use trojan_rust::proxy::deadline::Deadline;
+use trojan_rust::proxy::policy::Policies;
+
+fn policies() -> Policies {
+    let default = PolicyConfig {
+        connect_timeout: Some(1),
+        retries: Some(2),
+        retry_backoff: Some(100),
+        ..Default::default()
+    };
+    let named = HashMap::from([(
+        "patient".to_string(),
+        PolicyConfig {
+            connect_timeout: Some(5),
+            ..Default::default()
+        },
+    )]);
+
+    Policies::new(Some(&default), Some(&named)).unwrap()
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_policy_retries_with_backoff() {
+    let policies = policies();
+    let policy = policies.policy(None);
+    let attempts = AtomicU32::new(0);
+
+    // The first two attempts are refused, the last one goes through after 100ms and 200ms of backoff
+    let start = Instant::now();
+    let result = policy
+        .connect(&Deadline::after(Duration::from_secs(30)), || async {
+            match attempts.fetch_add(1, Ordering::Relaxed) {
+                0 | 1 => Err(Error::from(ErrorKind::ConnectionRefused)),
+                _ => Ok(()),
+            }
+        })
+        .await;
+
+    assert!(result.is_ok());
+    assert_eq!(attempts.load(Ordering::Relaxed), 3);
+    assert_eq!(start.elapsed(), Duration::from_millis(300));
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_policy_connect_timeout() {
+    let policies = policies();
+    let attempts = AtomicU32::new(0);
+
+    // Each attempt hangs until its timeout, and the retries run out well before the deadline
+    let start = Instant::now();
+    let err = policies
+        .policy(None)
+        .connect(&Deadline::after(Duration::from_secs(30)), || async {
+            attempts.fetch_add(1, Ordering::Relaxed);
+            sleep(Duration::from_secs(60)).await;
+            Ok(())
+        })
+        .await
+        .unwrap_err();
+
+    assert_eq!(err.kind(), ErrorKind::TimedOut);
+    assert_eq!(attempts.load(Ordering::Relaxed), 3);
+    assert_eq!(start.elapsed(), Duration::from_millis(3300));
+
+    // The named policy overrides the timeout and keeps the retries of the default one
+    let policy = policies.policy(Some("patient"));
+    assert_eq!(policy.connect_timeout, Some(Duration::from_secs(5)));
+    assert_eq!(policy.retries, 2);
+}
@@ -0,0 +1,126 @@
+use std::io::{Error, ErrorKind};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::time::Duration;
+use tokio::io::{AsyncWriteExt, DuplexStream};
+use trojan_rust::config::base::{OutboundMode, PoolConfig};
+use trojan_rust::proxy::tcp::pool::{is_open, ConnectionPool};
+
+/// Let the filling task catch up with the pool.
+async fn settle() {
+    for _ in 0..10 {
+        tokio::task::yield_now().await;
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_pool_refills_and_replaces_expired_connections() {
+    let pool: &'static ConnectionPool<u32> = Box::leak(Box::new(
+        ConnectionPool::new(
+            "pooled",
+            &OutboundMode::TCP,
+            &PoolConfig {
+                size: 2,
+                max_idle: Some(10),
+            },
+        )
+        .unwrap(),
+    ));
+    let established: &'static AtomicU32 = Box::leak(Box::new(AtomicU32::new(0)));
+    tokio::spawn(pool.fill(|| async { Ok(established.fetch_add(1, Ordering::Relaxed)) }));
+    settle().await;
+    assert_eq!(pool.len(), 2);
+
+    // The most recent connection goes first, and the taken one is replaced
+    assert_eq!(pool.take(), Some(1));
+    settle().await;
+    assert_eq!(pool.len(), 2);
+    assert_eq!(established.load(Ordering::Relaxed), 3);
+
+    // Connections idle for too long are replaced before a request gets them
+    tokio::time::advance(Duration::from_secs(10)).await;
+    settle().await;
+    assert_eq!(pool.len(), 2);
+    assert_eq!(established.load(Ordering::Relaxed), 5);
+    assert_eq!(pool.take(), Some(4));
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_pool_retries_failed_connections() {
+    let pool: &'static ConnectionPool<u32> = Box::leak(Box::new(
+        ConnectionPool::new(
+            "failing",
+            &OutboundMode::TCP,
+            &PoolConfig {
+                size: 1,
+                max_idle: None,
+            },
+        )
+        .unwrap(),
+    ));
+    let reachable: &'static AtomicBool = Box::leak(Box::new(AtomicBool::new(false)));
+    tokio::spawn(pool.fill(|| async {
+        match reachable.load(Ordering::Relaxed) {
+            true => Ok(0),
+            false => Err(Error::new(ErrorKind::ConnectionRefused, "refused")),
+        }
+    }));
+    settle().await;
+    assert_eq!(pool.take(), None);
+
+    // The requests connect on their own until the server is back
+    reachable.store(true, Ordering::Relaxed);
+    settle().await;
+    assert!(pool.is_empty());
+    tokio::time::advance(Duration::from_secs(5)).await;
+    settle().await;
+    assert_eq!(pool.take(), Some(0));
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_pool_skips_connections_closed_by_server() {
+    let pool: &'static ConnectionPool<DuplexStream> = Box::leak(Box::new(
+        ConnectionPool::new(
+            "closing",
+            &OutboundMode::TCP,
+            &PoolConfig {
+                size: 2,
+                max_idle: None,
+            },
+        )
+        .unwrap(),
+    ));
+    let (first, server_first) = tokio::io::duplex(64);
+    let (second, mut server_second) = tokio::io::duplex(64);
+    pool.put(first);
+    pool.put(second);
+
+    // The server closed the most recent connection, the older one is still open
+    server_second.shutdown().await.unwrap();
+    let mut taken = pool.take_if(is_open).unwrap();
+    assert!(is_open(&mut taken));
+    assert!(pool.is_empty());
+
+    drop(server_first);
+    assert!(!is_open(&mut taken));
+}
+
+#[test]
+fn test_pool_invalid() {
+    let config = PoolConfig {
+        size: 0,
+        max_idle: None,
+    };
+    let err = ConnectionPool::<()>::new("empty", &OutboundMode::TCP, &config)
+        .err()
+        .unwrap();
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+
+    let config = PoolConfig {
+        size: 1,
+        max_idle: None,
+    };
+    let err = ConnectionPool::<()>::new("quic", &OutboundMode::QUIC, &config)
+        .err()
+        .unwrap();
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+}
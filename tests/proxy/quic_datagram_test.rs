@@ -0,0 +1,25 @@
+use trojan_rust::proxy::quic::datagram::{decode_varint, encode_varint};
+
+#[test]
+fn test_session_header_varint() {
+    for (id, size) in [
+        (0, 1),
+        (63, 1),
+        (64, 2),
+        (16383, 2),
+        (16384, 4),
+        (1 << 30, 8),
+    ] {
+        let mut datagram = encode_varint(id);
+        assert_eq!(datagram.len(), size);
+
+        datagram.extend_from_slice(b"packet");
+        let (decoded, rest) = decode_varint(&datagram).unwrap();
+        assert_eq!(decoded, id);
+        assert_eq!(rest, b"packet");
+    }
+
+    // A two byte header cut short
+    assert!(decode_varint(&[0x40]).is_err());
+    assert!(decode_varint(&[]).is_err());
+}
@@ -0,0 +1,139 @@
+use crate::proxy::sim::run_shared;
+
+use futures::StreamExt;
+use quinn::{ClientConfig, Endpoint, NewConnection};
+use sha2::{Digest, Sha224};
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::timeout;
+use trojan_rust::config::base::Config;
+use trojan_rust::config::tls::NoCertificateVerification;
+use trojan_rust::protocol::common::addr::{IpAddrPort, IpAddress};
+use trojan_rust::protocol::common::atype::Atype;
+use trojan_rust::protocol::common::command::Command;
+use trojan_rust::protocol::common::request::{InboundRequest, TransportProtocol};
+use trojan_rust::protocol::trojan::packet::encode_udp;
+use trojan_rust::protocol::trojan::{handshake, parse_udp};
+use trojan_rust::proxy::base::SupportedProtocols;
+use trojan_rust::proxy::quic::datagram::{decode_varint, encode_varint};
+use trojan_rust::proxy::quic::server;
+use trojan_rust::router::Router;
+
+pub const SECRET: &str = "quic-server-test";
+
+/// Start a QUIC inbound relaying to a DIRECT outbound on a free port of the loopback interface.
+fn start_server() -> SocketAddr {
+    let _ = env_logger::builder().is_test(true).try_init();
+    let address = UdpSocket::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let config: &'static Config = Box::leak(Box::new(
+        serde_json::from_str(&format!(
+            r#"{{
+                "inbound": {{
+                    "mode": "QUIC", "protocol": "TROJAN", "address": "127.0.0.1", "port": {}, "secret": "{}",
+                    "tls": {{ "cert_path": "./config/cert.pem", "key_path": "./config/key.pem" }}
+                }},
+                "outbound": {{ "mode": "DIRECT", "protocol": "DIRECT" }}
+            }}"#,
+            address.port(),
+            SECRET
+        ))
+        .unwrap(),
+    ));
+    let router: &'static Router = Box::leak(Box::new(Router::new(config).unwrap()));
+    tokio::spawn(server::start(&config.inbound, &config.outbound, router));
+    address
+}
+
+/// Connect to the server, retrying until it is listening.
+async fn connect(server: SocketAddr) -> NewConnection {
+    let crypto = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_custom_certificate_verifier(Arc::new(NoCertificateVerification {}))
+        .with_no_client_auth();
+    let mut endpoint = Endpoint::client("127.0.0.1:0".parse().unwrap()).unwrap();
+    endpoint.set_default_client_config(ClientConfig::new(Arc::new(crypto)));
+
+    for _ in 0..50 {
+        if let Ok(connection) = endpoint.connect(server, "localhost").unwrap().await {
+            return connection;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    panic!("QUIC server didn't start");
+}
+
+#[test]
+fn test_udp_replies_on_stream_until_client_sends_datagram() {
+    run_shared(async {
+        let echo = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let destination = IpAddrPort::new(
+            IpAddress::IpAddr(echo.local_addr().unwrap().ip()),
+            echo.local_addr().unwrap().port(),
+        );
+        tokio::spawn(async move {
+            let mut buf = [0u8; 64];
+            loop {
+                let (size, peer) = echo.recv_from(&mut buf).await.unwrap();
+                echo.send_to(&buf[..size], peer).await.unwrap();
+            }
+        });
+
+        let NewConnection {
+            connection,
+            mut datagrams,
+            ..
+        } = connect(start_server()).await;
+        let (mut writer, mut reader) = connection.open_bi().await.unwrap();
+        let request = InboundRequest::new(
+            Atype::IPv4,
+            destination.ip.clone(),
+            Command::Udp,
+            destination.port,
+            TransportProtocol::UDP,
+            SupportedProtocols::TROJAN,
+        );
+        let secret = Sha224::digest(SECRET.as_bytes())
+            .iter()
+            .map(|x| format!("{:02x}", x))
+            .collect::<String>();
+        handshake(&mut writer, &request, secret.as_bytes())
+            .await
+            .unwrap();
+
+        // A client that only speaks the stream gets its replies there, although the connection supports datagrams
+        writer
+            .write_all(&encode_udp(&destination, b"stream").unwrap())
+            .await
+            .unwrap();
+        let header = timeout(Duration::from_secs(5), parse_udp(&mut reader))
+            .await
+            .expect("reply wasn't sent on the stream")
+            .unwrap();
+        let mut payload = vec![0u8; header.payload_size];
+        reader.read_exact(&mut payload).await.unwrap();
+        assert_eq!(payload, b"stream");
+
+        // Once the client announces the datagrams of the session, the replies come back in datagrams
+        connection
+            .send_datagram(encode_varint(writer.id().index()).into())
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        writer
+            .write_all(&encode_udp(&destination, b"datagram").unwrap())
+            .await
+            .unwrap();
+        let datagram = timeout(Duration::from_secs(5), datagrams.next())
+            .await
+            .expect("reply wasn't sent in a datagram")
+            .unwrap()
+            .unwrap();
+        let (id, mut packet) = decode_varint(&datagram).unwrap();
+        assert_eq!(id, writer.id().index());
+        let header = parse_udp(&mut packet).await.unwrap();
+        assert_eq!(&packet[..header.payload_size], b"datagram");
+    });
+}
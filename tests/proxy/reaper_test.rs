@@ -0,0 +1,43 @@
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::timeout;
+use trojan_rust::config::base::ReaperConfig;
+use trojan_rust::proxy::reaper::Reaper;
+use trojan_rust::proxy::relay::Activity;
+
+#[tokio::test(start_paused = true)]
+async fn test_reaper_closes_idle_sessions() {
+    let reaper = Reaper::new(Some(&ReaperConfig {
+        idle_timeout: Some(60),
+        interval: None,
+    }))
+    .unwrap();
+    let quiet = Arc::new(Activity::new());
+    let busy = Arc::new(Activity::new());
+    let strict = Arc::new(Activity::new());
+    let quiet_session = reaper.track(quiet, None);
+    let busy_session = reaper.track(busy.clone(), None);
+    let strict_session = reaper.track(strict, Some(Duration::from_secs(10)));
+    assert_eq!(reaper.len(), 3);
+
+    // The timeout of the policy comes before the one of the reaper
+    tokio::time::advance(Duration::from_secs(30)).await;
+    busy.touch();
+    assert_eq!(reaper.sweep(), 1);
+    assert!(timeout(Duration::from_millis(1), strict_session.reaped())
+        .await
+        .is_ok());
+
+    tokio::time::advance(Duration::from_secs(30)).await;
+    assert_eq!(reaper.sweep(), 1);
+    assert!(timeout(Duration::from_millis(1), quiet_session.reaped())
+        .await
+        .is_ok());
+    assert!(timeout(Duration::from_millis(1), busy_session.reaped())
+        .await
+        .is_err());
+
+    // Closed sessions are forgotten
+    drop(busy_session);
+    assert!(reaper.is_empty());
+}
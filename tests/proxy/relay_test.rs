@@ -0,0 +1,386 @@
+use crate::proxy::sim::{ScriptedReader, ScriptedWriter, Step};
+
+use std::io::ErrorKind;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::Instant;
+use trojan_rust::config::base::PolicyConfig;
+use trojan_rust::proxy::deadline::Deadline;
+use trojan_rust::proxy::policy::Policy;
+use trojan_rust::proxy::relay::{
+    relay, relay_tcp_with_policy, relay_with_idle_timeout, relay_with_policy, Transfer,
+};
+
+#[tokio::test(start_paused = true)]
+async fn test_relay_partial_writes_and_pauses() {
+    let client_reader = ScriptedReader::new(vec![
+        Step::Data(b"hello "),
+        Step::Pause(Duration::from_secs(1)),
+        Step::Data(b"world"),
+        Step::Close,
+    ]);
+    let (server_writer, upstream) = ScriptedWriter::new(2);
+    let (client_writer, _) = ScriptedWriter::new(2);
+
+    let start = Instant::now();
+    relay(
+        client_reader,
+        client_writer,
+        ScriptedReader::new(vec![Step::Close]),
+        server_writer,
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(upstream.lock().unwrap().as_slice(), b"hello world");
+    assert_eq!(start.elapsed(), Duration::from_secs(1));
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_relay_half_close_drains_other_direction() {
+    // The client stops sending while the server still has a response on the way, which reaches the client
+    let (server_writer, upstream) = ScriptedWriter::new(16);
+    let (client_writer, downstream) = ScriptedWriter::new(16);
+
+    let start = Instant::now();
+    relay(
+        ScriptedReader::new(vec![Step::Data(b"request"), Step::Close]),
+        client_writer,
+        ScriptedReader::new(vec![
+            Step::Pause(Duration::from_secs(5)),
+            Step::Data(b"late"),
+            Step::Close,
+        ]),
+        server_writer,
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(upstream.lock().unwrap().as_slice(), b"request");
+    assert_eq!(downstream.lock().unwrap().as_slice(), b"late");
+    assert_eq!(start.elapsed(), Duration::from_secs(5));
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_relay_with_policy_half_close() {
+    let config = PolicyConfig {
+        idle_timeout: Some(10),
+        ..Default::default()
+    };
+    let policy = Policy::new(&config, &PolicyConfig::default()).unwrap();
+    let (server_writer, upstream) = ScriptedWriter::new(16);
+    let (client_writer, downstream) = ScriptedWriter::new(16);
+
+    // The server is done first, the upload goes on until the connection is idle for the timeout
+    let start = Instant::now();
+    relay_with_policy(
+        ScriptedReader::new(vec![
+            Step::Data(b"ping"),
+            Step::Pause(Duration::from_secs(8)),
+            Step::Data(b"more"),
+        ]),
+        client_writer,
+        ScriptedReader::new(vec![Step::Data(b"pong"), Step::Close]),
+        server_writer,
+        &policy,
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(upstream.lock().unwrap().as_slice(), b"pingmore");
+    assert_eq!(downstream.lock().unwrap().as_slice(), b"pong");
+    assert_eq!(start.elapsed(), Duration::from_secs(18));
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_relay_half_close_timeout() {
+    let config = PolicyConfig {
+        half_close_timeout: Some(5),
+        ..Default::default()
+    };
+    let policy = Policy::new(&config, &PolicyConfig::default()).unwrap();
+    let (server_writer, upstream) = ScriptedWriter::new(16);
+    let (client_writer, _) = ScriptedWriter::new(16);
+
+    // The server never finishes after the client stopped sending, the connection is closed after the grace period
+    let start = Instant::now();
+    relay_with_policy(
+        ScriptedReader::new(vec![Step::Data(b"request"), Step::Close]),
+        client_writer,
+        ScriptedReader::new(vec![]),
+        server_writer,
+        &policy,
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(upstream.lock().unwrap().as_slice(), b"request");
+    assert_eq!(start.elapsed(), Duration::from_secs(5));
+    assert_eq!(
+        Policy::new(&PolicyConfig::default(), &PolicyConfig::default())
+            .unwrap()
+            .half_close_timeout,
+        Duration::from_secs(30)
+    );
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_relay_abrupt_close() {
+    // The server resets the connection in the middle of the response
+    let (client_writer, downstream) = ScriptedWriter::new(3);
+    let (server_writer, _) = ScriptedWriter::new(3);
+    relay(
+        ScriptedReader::new(vec![]),
+        client_writer,
+        ScriptedReader::new(vec![Step::Data(b"partial"), Step::Reset]),
+        server_writer,
+    )
+    .await
+    .unwrap();
+    assert_eq!(downstream.lock().unwrap().as_slice(), b"partial");
+
+    // The client goes away while the upload is still being written to it
+    let (server_writer, upstream) = ScriptedWriter::with_limit(3, 4);
+    let (client_writer, _) = ScriptedWriter::new(3);
+    relay(
+        ScriptedReader::new(vec![Step::Data(b"upload")]),
+        client_writer,
+        ScriptedReader::new(vec![]),
+        server_writer,
+    )
+    .await
+    .unwrap();
+    assert_eq!(upstream.lock().unwrap().as_slice(), b"uplo");
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_relay_cancelled_by_deadline() {
+    let (server_writer, upstream) = ScriptedWriter::new(16);
+    let (client_writer, downstream) = ScriptedWriter::new(16);
+
+    // Both peers go silent, so the relay only ends when it is cancelled
+    let start = Instant::now();
+    let err = Deadline::after(Duration::from_secs(10))
+        .run(
+            "relay",
+            relay(
+                ScriptedReader::new(vec![Step::Data(b"ping")]),
+                client_writer,
+                ScriptedReader::new(vec![]),
+                server_writer,
+            ),
+        )
+        .await
+        .unwrap_err();
+
+    assert_eq!(err.kind(), ErrorKind::TimedOut);
+    assert_eq!(start.elapsed(), Duration::from_secs(10));
+    assert_eq!(upstream.lock().unwrap().as_slice(), b"ping");
+
+    // Both writers are released once the relay is cancelled
+    assert_eq!(Arc::strong_count(&upstream), 1);
+    assert_eq!(Arc::strong_count(&downstream), 1);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_relay_closes_idle_connection() {
+    let (server_writer, upstream) = ScriptedWriter::new(16);
+    let (client_writer, downstream) = ScriptedWriter::new(16);
+
+    // Data in either direction keeps the connection open, the timeout counts from the last of it
+    let start = Instant::now();
+    relay_with_idle_timeout(
+        ScriptedReader::new(vec![
+            Step::Data(b"ping"),
+            Step::Pause(Duration::from_secs(20)),
+            Step::Data(b"late"),
+        ]),
+        client_writer,
+        ScriptedReader::new(vec![
+            Step::Pause(Duration::from_secs(8)),
+            Step::Data(b"pong"),
+        ]),
+        server_writer,
+        Some(Duration::from_secs(10)),
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(start.elapsed(), Duration::from_secs(18));
+    assert_eq!(upstream.lock().unwrap().as_slice(), b"ping");
+    assert_eq!(downstream.lock().unwrap().as_slice(), b"pong");
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_relay_caps_connection_bytes() {
+    let config = PolicyConfig {
+        max_upload: Some(4),
+        ..Default::default()
+    };
+    let policy = Policy::new(&config, &PolicyConfig::default()).unwrap();
+    let (server_writer, upstream) = ScriptedWriter::new(16);
+    let (client_writer, _) = ScriptedWriter::new(16);
+
+    // The connection is closed as soon as the client sent the cap, without waiting for the rest
+    let start = Instant::now();
+    relay_with_policy(
+        ScriptedReader::new(vec![
+            Step::Data(b"0123456789"),
+            Step::Pause(Duration::from_secs(60)),
+        ]),
+        client_writer,
+        ScriptedReader::new(vec![Step::Pause(Duration::from_secs(60))]),
+        server_writer,
+        &policy,
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(upstream.lock().unwrap().as_slice(), b"0123");
+    assert_eq!(start.elapsed(), Duration::ZERO);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_relay_closes_connection_at_max_lifetime() {
+    let config = PolicyConfig {
+        idle_timeout: Some(10),
+        max_connection_lifetime: Some(12),
+        ..Default::default()
+    };
+    let policy = Policy::new(&config, &PolicyConfig::default()).unwrap();
+    let (server_writer, upstream) = ScriptedWriter::new(16);
+    let (client_writer, _) = ScriptedWriter::new(16);
+
+    // The connection never goes idle, yet it is closed once it reaches its lifetime
+    let mut steps = Vec::new();
+    for _ in 0..12 {
+        steps.push(Step::Data(b"a"));
+        steps.push(Step::Pause(Duration::from_secs(5)));
+    }
+    let start = Instant::now();
+    relay_with_policy(
+        ScriptedReader::new(steps),
+        client_writer,
+        ScriptedReader::new(vec![Step::Pause(Duration::from_secs(60))]),
+        server_writer,
+        &policy,
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(start.elapsed(), Duration::from_secs(12));
+    assert_eq!(upstream.lock().unwrap().as_slice(), b"aaa");
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_relay_data_larger_than_buffer() {
+    // Small writes behind large reads make the data wrap around the end of the ring buffer
+    let data: &'static [u8] = Box::leak((0..100_000).map(|i| (i % 251) as u8).collect());
+    let steps = data.chunks(7000).map(Step::Data).chain([Step::Close]);
+    let (server_writer, upstream) = ScriptedWriter::new(3000);
+    let (client_writer, _) = ScriptedWriter::new(16);
+
+    relay(
+        ScriptedReader::new(steps.collect()),
+        client_writer,
+        ScriptedReader::new(vec![Step::Close]),
+        server_writer,
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(upstream.lock().unwrap().as_slice(), data);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_relay_counts_transfer() {
+    let (server_writer, _) = ScriptedWriter::new(16);
+    let (client_writer, _) = ScriptedWriter::new(16);
+
+    let transfer = Transfer::new();
+    transfer
+        .scope(relay(
+            ScriptedReader::new(vec![
+                Step::Data(b"hello"),
+                Step::Pause(Duration::from_secs(1)),
+                Step::Close,
+            ]),
+            client_writer,
+            ScriptedReader::new(vec![
+                Step::Data(b"reply!"),
+                Step::Pause(Duration::from_secs(2)),
+                Step::Close,
+            ]),
+            server_writer,
+        ))
+        .await
+        .unwrap();
+
+    assert_eq!(transfer.uploaded(), 5);
+    assert_eq!(transfer.downloaded(), 6);
+    assert!(transfer
+        .to_string()
+        .starts_with("5 bytes up and 6 bytes down in "));
+}
+
+/// Both ends of a TCP connection on the loopback interface.
+async fn tcp_pair() -> (TcpStream, TcpStream) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let connect = TcpStream::connect(listener.local_addr().unwrap());
+    let (connected, accepted) = tokio::join!(connect, listener.accept());
+    (connected.unwrap(), accepted.unwrap().0)
+}
+
+#[tokio::test]
+async fn test_relay_tcp_half_close() {
+    let (mut client, inbound) = tcp_pair().await;
+    let (outbound, mut server) = tcp_pair().await;
+    let policy = Policy::new(&PolicyConfig::default(), &PolicyConfig::default()).unwrap();
+    let relay =
+        tokio::spawn(
+            async move { relay_tcp_with_policy(inbound, Vec::new(), outbound, &policy).await },
+        );
+
+    // The server sees the end of the request and answers it afterwards
+    client.write_all(b"request").await.unwrap();
+    client.shutdown().await.unwrap();
+    let mut request = Vec::new();
+    server.read_to_end(&mut request).await.unwrap();
+    assert_eq!(request, b"request");
+
+    server.write_all(b"response").await.unwrap();
+    drop(server);
+    let mut response = Vec::new();
+    client.read_to_end(&mut response).await.unwrap();
+    assert_eq!(response, b"response");
+    relay.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn test_relay_tcp_sockets() {
+    let (mut client, inbound) = tcp_pair().await;
+    let (outbound, mut server) = tcp_pair().await;
+    let config = PolicyConfig {
+        max_download: Some(8),
+        ..Default::default()
+    };
+    let policy = Policy::new(&config, &PolicyConfig::default()).unwrap();
+    let relay = tokio::spawn(async move {
+        relay_tcp_with_policy(inbound, b"GET ".to_vec(), outbound, &policy).await
+    });
+
+    // The bytes read by the inbound go first
+    client.write_all(b"/ HTTP/1.1\r\n").await.unwrap();
+    let mut request = [0u8; 16];
+    server.read_exact(&mut request).await.unwrap();
+    assert_eq!(&request, b"GET / HTTP/1.1\r\n");
+
+    // The connection is closed once the server sent the cap
+    server.write_all(b"HTTP/1.1 200 OK\r\n").await.unwrap();
+    let mut response = Vec::new();
+    client.read_to_end(&mut response).await.unwrap();
+    assert_eq!(response, b"HTTP/1.1");
+    relay.await.unwrap().unwrap();
+}
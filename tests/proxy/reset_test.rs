@@ -0,0 +1,50 @@
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use trojan_rust::metrics;
+use trojan_rust::proxy::reset::{ResetMonitor, ResetTracker};
+
+/// Connect to a server that resets the connection after sending the greeting.
+async fn reset_connection(greeting: &'static [u8]) -> TcpStream {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        stream.write_all(greeting).await.unwrap();
+        // Closing with a zero linger sends RST instead of FIN
+        stream.set_linger(Some(Duration::ZERO)).unwrap();
+    });
+    TcpStream::connect(address).await.unwrap()
+}
+
+fn resets(stage: &str) -> u64 {
+    let name = format!("connection_resets_total{{stage=\"{}\"}}", stage);
+    metrics::snapshot().get(&name).copied().unwrap_or(0)
+}
+
+#[tokio::test]
+async fn test_reset_monitor_stages() {
+    let tracker = Arc::new(ResetTracker::new(true));
+    assert!(!tracker.failing_over());
+
+    // Reset before the server sent anything
+    let handshake = resets("handshake");
+    let stream = reset_connection(b"").await;
+    let mut stream = ResetMonitor::new(stream, tracker.clone(), "server".to_string());
+    let mut buf = [0u8; 16];
+    assert!(stream.read(&mut buf).await.is_err());
+    assert_eq!(resets("handshake"), handshake + 1);
+    assert!(tracker.failing_over());
+
+    // Reset once the server replied
+    let early = resets("early");
+    let stream = reset_connection(b"hello").await;
+    let mut stream = ResetMonitor::new(stream, tracker.clone(), "server".to_string());
+    stream.read_exact(&mut buf[..5]).await.unwrap();
+    assert!(stream.read(&mut buf).await.is_err());
+    assert_eq!(resets("early"), early + 1);
+
+    // Failover is only used when enabled
+    assert!(!ResetTracker::new(false).failing_over());
+}
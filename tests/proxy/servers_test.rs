@@ -0,0 +1,80 @@
+use std::io::{Error, ErrorKind};
+use tokio::net::TcpListener;
+use trojan_rust::config::base::OutboundConfig;
+use trojan_rust::proxy::servers::ServerList;
+use trojan_rust::proxy::tcp::handler::TcpHandler;
+
+fn outbound(config: &str) -> OutboundConfig {
+    serde_json::from_str(config).unwrap()
+}
+
+fn candidates(servers: &ServerList) -> Vec<String> {
+    servers
+        .candidates()
+        .iter()
+        .map(|server| server.to_string())
+        .collect()
+}
+
+#[tokio::test]
+async fn test_failed_server_is_tried_last() {
+    let servers = ServerList::new(&outbound(
+        r#"{ "mode": "TCP", "protocol": "TROJAN", "address": "cdn.example.com", "port": 443,
+            "servers": ["10.0.0.1", "10.0.0.2:8443", "[2001:db8::1]:9443"] }"#,
+    ))
+    .unwrap()
+    .unwrap();
+    assert_eq!(
+        candidates(&servers),
+        [
+            "cdn.example.com:443",
+            "10.0.0.1:443",
+            "10.0.0.2:8443",
+            "2001:db8::1:9443"
+        ]
+    );
+
+    // The first two addresses are blocked, the request goes through the third one
+    let connected = servers
+        .connect(|server| async move {
+            match server.port {
+                443 => Err(Error::from(ErrorKind::TimedOut)),
+                _ => Ok(server.to_string()),
+            }
+        })
+        .await
+        .unwrap();
+    assert_eq!(connected, "10.0.0.2:8443");
+
+    assert_eq!(
+        candidates(&servers),
+        [
+            "10.0.0.2:8443",
+            "2001:db8::1:9443",
+            "cdn.example.com:443",
+            "10.0.0.1:443"
+        ]
+    );
+}
+
+#[tokio::test]
+async fn test_outbound_fails_over_to_next_server() {
+    let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = server.local_addr().unwrap().port();
+
+    // Nothing listens on the port of the first address any more
+    let closed_port = {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        listener.local_addr().unwrap().port()
+    };
+
+    let handler = TcpHandler::new(&outbound(&format!(
+        r#"{{ "mode": "TCP", "protocol": "TROJAN", "address": "127.0.0.1", "port": {}, "secret": "secret",
+            "servers": ["127.0.0.1:{}"] }}"#,
+        closed_port, port
+    )))
+    .unwrap();
+
+    tokio::spawn(async move { server.accept().await });
+    handler.probe().await.unwrap();
+}
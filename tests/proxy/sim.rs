@@ -0,0 +1,159 @@
+use once_cell::sync::Lazy;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::io::{self, ErrorKind};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::runtime::Runtime;
+use tokio::time::Sleep;
+
+/// Runtime of the tests going through the UDP workers shared by the whole process, which keep running on the runtime
+/// that first used them and stop along with it.
+static SHARED_RUNTIME: Lazy<Runtime> = Lazy::new(|| Runtime::new().unwrap());
+
+/// Run the test on the runtime shared with the other tests using the UDP workers.
+pub fn run_shared<F: Future>(test: F) -> F::Output {
+    SHARED_RUNTIME.block_on(test)
+}
+
+/// One step of the script played by a simulated peer. Pauses run on the clock of tokio, so that tests on the paused
+/// clock see exactly the scripted timing regardless of the load of the machine.
+pub enum Step {
+    /// Hand the bytes to the reader, possibly over several reads if its buffer is small
+    Data(&'static [u8]),
+    /// Keep the reader waiting for the duration
+    Pause(Duration),
+    /// End of stream, every read afterwards returns nothing
+    Close,
+    /// Fail the read as if the peer reset the connection
+    Reset,
+}
+
+/// Reader side of a simulated peer playing the steps in order. Once the script runs out without a close or a reset,
+/// the reader hangs forever like a peer that went silent.
+pub struct ScriptedReader {
+    steps: VecDeque<Step>,
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl ScriptedReader {
+    pub fn new(steps: Vec<Step>) -> Self {
+        Self {
+            steps: steps.into(),
+            sleep: None,
+        }
+    }
+}
+
+impl AsyncRead for ScriptedReader {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        loop {
+            if let Some(sleep) = &mut this.sleep {
+                if sleep.as_mut().poll(cx).is_pending() {
+                    return Poll::Pending;
+                }
+                this.sleep = None;
+            }
+
+            match this.steps.pop_front() {
+                Some(Step::Data(data)) => {
+                    let n = data.len().min(buf.remaining());
+                    buf.put_slice(&data[..n]);
+                    if n < data.len() {
+                        this.steps.push_front(Step::Data(&data[n..]));
+                    }
+                    return Poll::Ready(Ok(()));
+                }
+                Some(Step::Pause(duration)) => {
+                    this.sleep = Some(Box::pin(tokio::time::sleep(duration)));
+                }
+                Some(Step::Close) => {
+                    this.steps.push_front(Step::Close);
+                    return Poll::Ready(Ok(()));
+                }
+                Some(Step::Reset) => {
+                    return Poll::Ready(Err(io::Error::new(
+                        ErrorKind::ConnectionReset,
+                        "simulated reset",
+                    )))
+                }
+                None => return Poll::Pending,
+            }
+        }
+    }
+}
+
+/// Bytes received by a simulated peer, still readable after the writer is dropped.
+pub type Received = Arc<Mutex<Vec<u8>>>;
+
+/// Writer side of a simulated peer. It accepts at most chunk bytes per write and turns every other write away with
+/// Pending first, like a socket with a tiny send buffer, and fails all the writes once limit bytes are received as
+/// if the peer closed the connection abruptly.
+pub struct ScriptedWriter {
+    received: Received,
+    chunk: usize,
+    limit: usize,
+    backpressure: bool,
+}
+
+impl ScriptedWriter {
+    pub fn new(chunk: usize) -> (Self, Received) {
+        Self::with_limit(chunk, usize::MAX)
+    }
+
+    pub fn with_limit(chunk: usize, limit: usize) -> (Self, Received) {
+        let received = Received::default();
+        let writer = Self {
+            received: received.clone(),
+            chunk,
+            limit,
+            backpressure: false,
+        };
+        (writer, received)
+    }
+}
+
+impl AsyncWrite for ScriptedWriter {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+
+        this.backpressure = !this.backpressure;
+        if this.backpressure {
+            cx.waker().wake_by_ref();
+            return Poll::Pending;
+        }
+
+        let mut received = this.received.lock().unwrap();
+        if received.len() >= this.limit {
+            return Poll::Ready(Err(io::Error::new(
+                ErrorKind::BrokenPipe,
+                "simulated close",
+            )));
+        }
+
+        let n = buf.len().min(this.chunk).min(this.limit - received.len());
+        received.extend_from_slice(&buf[..n]);
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
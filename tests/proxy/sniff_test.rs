@@ -0,0 +1,96 @@
+use std::net::{IpAddr, Ipv4Addr};
+use tokio::io::AsyncReadExt;
+use trojan_rust::config::base::SniffingConfig;
+use trojan_rust::protocol::common::addr::IpAddress;
+use trojan_rust::protocol::common::atype::Atype;
+use trojan_rust::protocol::common::command::Command;
+use trojan_rust::protocol::common::request::{InboundRequest, TransportProtocol};
+use trojan_rust::proxy::base::SupportedProtocols;
+use trojan_rust::proxy::tcp::sniff::Sniffer;
+
+fn sniffer() -> Sniffer {
+    let config: SniffingConfig = serde_json::from_str(
+        r#"{
+            "ports": [
+                { "port": 443, "protocol": "TLS" },
+                { "port": 80, "protocol": "HTTP", "override_destination": false }
+            ]
+        }"#,
+    )
+    .unwrap();
+    Sniffer::new(Some(&config)).unwrap()
+}
+
+fn request_to(port: u16) -> InboundRequest {
+    InboundRequest::new(
+        Atype::IPv4,
+        IpAddress::IpAddr(IpAddr::V4(Ipv4Addr::new(93, 184, 216, 34))),
+        Command::Connect,
+        port,
+        TransportProtocol::TCP,
+        SupportedProtocols::TROJAN,
+    )
+}
+
+fn client_hello(server_name: &str) -> Vec<u8> {
+    let mut list = vec![0x00];
+    list.extend_from_slice(&(server_name.len() as u16).to_be_bytes());
+    list.extend_from_slice(server_name.as_bytes());
+    let mut extension = vec![0x00, 0x00];
+    extension.extend_from_slice(&(list.len() as u16 + 2).to_be_bytes());
+    extension.extend_from_slice(&(list.len() as u16).to_be_bytes());
+    extension.extend_from_slice(&list);
+
+    let mut body = vec![0x03, 0x03];
+    body.extend_from_slice(&[0u8; 32]);
+    // Empty session id, a single cipher suite and null compression
+    body.extend_from_slice(&[0x00, 0x00, 0x02, 0x13, 0x01, 0x01, 0x00]);
+    body.extend_from_slice(&(extension.len() as u16).to_be_bytes());
+    body.extend_from_slice(&extension);
+
+    let mut message = vec![0x01];
+    message.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+    message.extend_from_slice(&body);
+
+    let mut record = vec![0x16, 0x03, 0x01];
+    record.extend_from_slice(&(message.len() as u16).to_be_bytes());
+    record.extend_from_slice(&message);
+    record
+}
+
+#[tokio::test]
+async fn test_sniff_tls_overrides_destination() {
+    let hello = client_hello("example.com");
+    let mut request = request_to(443);
+    let (mut stream, routing_domain) = sniffer()
+        .sniff(&mut request, hello.as_slice())
+        .await
+        .unwrap();
+
+    assert_eq!(request.addr_port.to_string(), "example.com:443");
+    assert!(routing_domain.is_none());
+
+    // The ClientHello still reaches the outbound
+    let mut replayed = Vec::new();
+    stream.read_to_end(&mut replayed).await.unwrap();
+    assert_eq!(replayed, hello);
+}
+
+#[tokio::test]
+async fn test_sniff_by_port() {
+    let head: &[u8] = b"GET / HTTP/1.1\r\nHost: example.org:8080\r\n\r\n";
+
+    // HTTP is only sniffed for routing on port 80
+    let mut request = request_to(80);
+    let (_, routing_domain) = sniffer().sniff(&mut request, head).await.unwrap();
+    assert_eq!(routing_domain.as_deref(), Some("example.org"));
+    assert_eq!(request.addr_port.to_string(), "93.184.216.34:80");
+
+    // Ports without a rule are not sniffed, nor is HTTP on the TLS port
+    for port in [8080, 443] {
+        let mut request = request_to(port);
+        let (_, routing_domain) = sniffer().sniff(&mut request, head).await.unwrap();
+        assert!(routing_domain.is_none());
+        assert!(matches!(request.addr_port.ip, IpAddress::IpAddr(_)));
+    }
+}
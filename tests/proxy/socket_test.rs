@@ -0,0 +1,175 @@
+use socket2::SockRef;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use trojan_rust::config::base::SocketConfig;
+use trojan_rust::proxy::socket::SocketOptions;
+
+#[tokio::test]
+async fn test_socket_options_are_applied() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    // Nagle is off by default and keepalive is left to the kernel
+    let stream = SocketOptions::new(None)
+        .unwrap()
+        .connect(addr)
+        .await
+        .unwrap();
+    assert!(stream.nodelay().unwrap());
+    assert!(!SockRef::from(&stream).keepalive().unwrap());
+
+    let options = SocketOptions::new(Some(&SocketConfig {
+        nodelay: Some(false),
+        keepalive: Some(30),
+        keepalive_interval: Some(5),
+        keepalive_probes: Some(3),
+        fast_open: None,
+    }))
+    .unwrap();
+    let stream = TcpStream::connect(addr).await.unwrap();
+    options.apply(&stream).unwrap();
+
+    let socket = SockRef::from(&stream);
+    assert!(!stream.nodelay().unwrap());
+    assert!(socket.keepalive().unwrap());
+    assert_eq!(socket.keepalive_time().unwrap(), Duration::from_secs(30));
+    #[cfg(target_os = "linux")]
+    {
+        assert_eq!(socket.keepalive_interval().unwrap(), Duration::from_secs(5));
+        assert_eq!(socket.keepalive_retries().unwrap(), 3);
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn test_fast_open_connection() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let options = SocketOptions::new(Some(&SocketConfig {
+        fast_open: Some(true),
+        ..Default::default()
+    }))
+    .unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    options.listen(&listener).unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    assert!(options.fast_open());
+    assert_eq!(option(&listener, libc::TCP_FASTOPEN), 1024);
+
+    // The data written right after connecting goes out with the SYN
+    let mut client = options.connect(addr).await.unwrap();
+    assert_eq!(option(&client, libc::TCP_FASTOPEN_CONNECT), 1);
+    client.write_all(b"hello").await.unwrap();
+    let (mut server, _) = listener.accept().await.unwrap();
+    let mut buf = [0u8; 5];
+    server.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"hello");
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn test_fast_open_sends_data_in_syn() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    // The kernel has to allow TCP Fast Open on both sides, which the sysctl doesn't by default
+    let sysctl = std::fs::read_to_string("/proc/sys/net/ipv4/tcp_fastopen").unwrap();
+    if sysctl.trim().parse::<u32>().unwrap() & 3 != 3 {
+        return;
+    }
+
+    let options = SocketOptions::new(Some(&SocketConfig {
+        fast_open: Some(true),
+        ..Default::default()
+    }))
+    .unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    options.listen(&listener).unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    // The first connection fetches the cookie unless the kernel has one for the address, the next one carries its data
+    // in the SYN
+    let mut syn_data = false;
+    for _ in 0..2 {
+        let mut client = options.connect(addr).await.unwrap();
+        client.write_all(b"hello").await.unwrap();
+        let (mut server, _) = listener.accept().await.unwrap();
+        let mut buf = [0u8; 5];
+        server.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+        syn_data = tcp_info_options(&server) & TCPI_OPT_SYN_DATA != 0;
+    }
+    assert!(syn_data);
+}
+
+/// Data in the SYN was acknowledged, from linux/tcp.h
+#[cfg(target_os = "linux")]
+const TCPI_OPT_SYN_DATA: u8 = 32;
+
+/// Options of the connection in its TCP_INFO.
+#[cfg(target_os = "linux")]
+fn tcp_info_options<S: std::os::unix::io::AsRawFd>(socket: &S) -> u8 {
+    // tcpi_state, tcpi_ca_state, tcpi_retransmits, tcpi_probes, tcpi_backoff and tcpi_options lead the struct
+    let mut info = [0u8; 6];
+    let mut len = info.len() as libc::socklen_t;
+    // Safety: the buffer and its length outlive the call, the kernel copies at most len bytes
+    let result = unsafe {
+        libc::getsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            libc::TCP_INFO,
+            info.as_mut_ptr() as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    assert_eq!(result, 0);
+    info[5]
+}
+
+/// Read the TCP level option of the socket.
+#[cfg(target_os = "linux")]
+fn option<S: std::os::unix::io::AsRawFd>(socket: &S, name: libc::c_int) -> libc::c_int {
+    let mut value: libc::c_int = 0;
+    let mut len = std::mem::size_of_val(&value) as libc::socklen_t;
+    // Safety: the value and its length outlive the call
+    let result = unsafe {
+        libc::getsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            name,
+            &mut value as *mut _ as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    assert_eq!(result, 0);
+    value
+}
+
+#[test]
+fn test_socket_options_invalid() {
+    for config in [
+        SocketConfig {
+            keepalive_probes: Some(3),
+            ..Default::default()
+        },
+        SocketConfig {
+            keepalive: Some(0),
+            ..Default::default()
+        },
+    ] {
+        let err = SocketOptions::new(Some(&config)).err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    }
+}
+
+#[tokio::test]
+async fn test_connect_to_host_name() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    let stream = SocketOptions::default()
+        .connect(("localhost", port))
+        .await
+        .unwrap();
+    assert!(stream.nodelay().unwrap());
+}
@@ -0,0 +1,136 @@
+use std::io::ErrorKind;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::time::Instant;
+use trojan_rust::config::base::{BandwidthConfig, GlobalBandwidthConfig, InboundConfig};
+use trojan_rust::proxy::throttle::{GlobalBandwidth, Throttled, UserBandwidth};
+
+const RATE: u64 = 64 * 1024;
+
+fn bandwidth(upload: Option<u64>, download: Option<u64>) -> BandwidthConfig {
+    BandwidthConfig {
+        upload,
+        download,
+        burst: Some(RATE / 4),
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_throttled_download() {
+    let (client, mut peer) = tokio::io::duplex(1024 * 1024);
+    let config = bandwidth(None, Some(RATE));
+    let mut client = Throttled::new(client, Some(&config));
+
+    // The burst goes out right away and the rest at the rate, the last write is only paid back by the next one
+    let started = Instant::now();
+    client.write_all(&[0; RATE as usize]).await.unwrap();
+    client.flush().await.unwrap();
+    let elapsed = started.elapsed();
+    assert!(elapsed >= Duration::from_millis(450), "{:?}", elapsed);
+    assert!(elapsed < Duration::from_secs(2), "{:?}", elapsed);
+
+    // Reads are left alone
+    peer.write_all(&[1; 4096]).await.unwrap();
+    let mut buf = [0; 4096];
+    client.read_exact(&mut buf).await.unwrap();
+    assert_eq!(buf, [1; 4096]);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_throttled_upload() {
+    let (client, mut peer) = tokio::io::duplex(1024 * 1024);
+    let config = bandwidth(Some(RATE), None);
+    let mut client = Throttled::new(client, Some(&config));
+    peer.write_all(&[0; RATE as usize]).await.unwrap();
+
+    let started = Instant::now();
+    let mut buf = vec![0; RATE as usize];
+    client.read_exact(&mut buf).await.unwrap();
+    let elapsed = started.elapsed();
+    assert!(elapsed >= Duration::from_millis(450), "{:?}", elapsed);
+    assert!(elapsed < Duration::from_secs(2), "{:?}", elapsed);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_global_bandwidth_shared() {
+    // 1 Mbps, the first second worth of data goes out right away
+    let global: &'static GlobalBandwidth = Box::leak(Box::new(
+        GlobalBandwidth::new(&GlobalBandwidthConfig { mbps: 1 }).unwrap(),
+    ));
+    let (first, _first_peer) = tokio::io::duplex(1024 * 1024);
+    let (second, _second_peer) = tokio::io::duplex(1024 * 1024);
+    let mut first = Throttled::new(first, None).with_global(Some(global));
+    let mut second = Throttled::new(second, None).with_global(Some(global));
+
+    // Together the streams send a second worth of data over the burst, which they pay back in turn
+    let started = Instant::now();
+    let data = vec![0; 125_000];
+    let (a, b) = tokio::join!(first.write_all(&data), second.write_all(&data));
+    a.unwrap();
+    b.unwrap();
+    let elapsed = started.elapsed();
+    assert!(elapsed >= Duration::from_millis(700), "{:?}", elapsed);
+    assert!(elapsed < Duration::from_secs(3), "{:?}", elapsed);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_user_bandwidth_shared() {
+    let inbound: InboundConfig = serde_json::from_str(
+        r#"{
+            "mode": "TCP",
+            "protocol": "TROJAN",
+            "address": "0.0.0.0",
+            "port": 443,
+            "users": [
+                { "name": "alice", "secret": "a", "bandwidth_class": "basic" },
+                { "name": "bob", "secret": "b", "bandwidth_class": "premium" },
+                { "name": "carol", "secret": "c" }
+            ],
+            "bandwidth_classes": { "basic": { "mbps": 1 }, "premium": {} }
+        }"#,
+    )
+    .unwrap();
+    let users: &'static UserBandwidth = Box::leak(Box::new(UserBandwidth::new(&inbound).unwrap()));
+    assert!(users.user("bob").is_none());
+    assert!(users.user("carol").is_none());
+
+    // The connections of the user share the class bandwidth, paying back a second worth of data over the burst
+    let (first, _first_peer) = tokio::io::duplex(1024 * 1024);
+    let (second, _second_peer) = tokio::io::duplex(1024 * 1024);
+    let mut first = Throttled::new(first, None).with_user(users.user("alice"));
+    let mut second = Throttled::new(second, None).with_user(users.user("alice"));
+
+    let started = Instant::now();
+    let data = vec![0; 125_000];
+    let (a, b) = tokio::join!(first.write_all(&data), second.write_all(&data));
+    a.unwrap();
+    b.unwrap();
+    let elapsed = started.elapsed();
+    assert!(elapsed >= Duration::from_millis(700), "{:?}", elapsed);
+    assert!(elapsed < Duration::from_secs(3), "{:?}", elapsed);
+}
+
+#[test]
+fn test_bandwidth_invalid() {
+    let err = GlobalBandwidth::new(&GlobalBandwidthConfig { mbps: 0 })
+        .err()
+        .unwrap();
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+
+    for classes in [r#"{}"#, r#"{ "basic": { "mbps": 0 } }"#] {
+        let inbound: InboundConfig = serde_json::from_str(&format!(
+            r#"{{
+                "mode": "TCP",
+                "protocol": "TROJAN",
+                "address": "0.0.0.0",
+                "port": 443,
+                "users": [{{ "name": "alice", "secret": "a", "bandwidth_class": "basic" }}],
+                "bandwidth_classes": {}
+            }}"#,
+            classes
+        ))
+        .unwrap();
+        let err = UserBandwidth::new(&inbound).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    }
+}
@@ -0,0 +1,79 @@
+use std::net::SocketAddr;
+use std::sync::atomic::AtomicBool;
+use tokio::net::UdpSocket;
+use trojan_rust::proxy::udp::batch::{send_batch, RecvBatch};
+
+#[tokio::test]
+async fn test_udp_batch_round_trip() {
+    let sender = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let receiver = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let dest: SocketAddr = receiver.local_addr().unwrap();
+
+    let payloads: [&[u8]; 3] = [b"first", b"second", b"third"];
+    let datagrams: Vec<(&[u8], SocketAddr)> =
+        payloads.iter().map(|payload| (*payload, dest)).collect();
+    send_batch(&sender, &datagrams, &AtomicBool::new(true))
+        .await
+        .unwrap();
+
+    let mut batch = RecvBatch::new(8, 64);
+    let mut received = Vec::new();
+    while received.len() < payloads.len() {
+        batch.recv(&receiver).await.unwrap();
+        for (payload, source) in batch.iter() {
+            assert_eq!(source, sender.local_addr().unwrap());
+            received.push(payload.to_vec());
+        }
+    }
+    assert_eq!(received, payloads.map(|payload| payload.to_vec()));
+}
+
+#[tokio::test]
+async fn test_udp_batch_segments() {
+    // Datagrams of the same size to the same destination may go out as one segmented message, they still have to
+    // arrive as separate datagrams
+    let sender = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let receiver = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let dest = receiver.local_addr().unwrap();
+
+    let full = [7u8; 100];
+    let mut datagrams: Vec<(&[u8], SocketAddr)> = vec![(&full, dest); 5];
+    datagrams.push((&full[..50], dest));
+    datagrams.push((&full[..100], dest));
+    send_batch(&sender, &datagrams, &AtomicBool::new(true))
+        .await
+        .unwrap();
+
+    let mut batch = RecvBatch::new(8, 1024);
+    let mut sizes = Vec::new();
+    while sizes.len() < datagrams.len() {
+        batch.recv(&receiver).await.unwrap();
+        sizes.extend(batch.iter().map(|(payload, _)| payload.len()));
+    }
+    assert_eq!(sizes, [100, 100, 100, 100, 100, 50, 100]);
+}
+
+#[tokio::test]
+async fn test_udp_batch_large_datagrams() {
+    // Datagrams larger than a segment fits are sent on their own, along with the smaller ones
+    let sender = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let receiver = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let dest = receiver.local_addr().unwrap();
+
+    let large = [9u8; 4000];
+    let mut datagrams: Vec<(&[u8], SocketAddr)> = vec![(&large, dest); 20];
+    datagrams.extend(vec![(&large[..1000], dest); 3]);
+    let segmentation = AtomicBool::new(true);
+    send_batch(&sender, &datagrams, &segmentation)
+        .await
+        .unwrap();
+
+    let mut batch = RecvBatch::new(32, 8192);
+    let mut sizes = Vec::new();
+    while sizes.len() < datagrams.len() {
+        batch.recv(&receiver).await.unwrap();
+        sizes.extend(batch.iter().map(|(payload, _)| payload.len()));
+    }
+    assert_eq!(sizes[..20], [4000; 20]);
+    assert_eq!(sizes[20..], [1000; 3]);
+}
@@ -0,0 +1,48 @@
+use socket2::SockRef;
+use std::io::ErrorKind;
+use tokio::net::UdpSocket;
+use trojan_rust::config::base::{DomainStrategy, UdpConfig};
+use trojan_rust::proxy::udp::bind::UdpBinder;
+
+#[tokio::test]
+async fn test_udp_binder_port_range() {
+    let port = {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        socket.local_addr().unwrap().port()
+    };
+    let config: UdpConfig = serde_json::from_str(&format!(
+        r#"{{ "bind_address": "127.0.0.1", "port_range": "{}-{}" }}"#,
+        port, port
+    ))
+    .unwrap();
+    let binder = UdpBinder::new(Some(&config)).unwrap();
+
+    let socket = binder.bind(DomainStrategy::AS_IS).await.unwrap();
+    assert_eq!(
+        socket.local_addr().unwrap().to_string(),
+        format!("127.0.0.1:{}", port)
+    );
+
+    // The only port of the range is taken until the session closes
+    let busy = binder.bind(DomainStrategy::AS_IS).await;
+    assert_eq!(busy.err().unwrap().kind(), ErrorKind::AddrInUse);
+    drop(socket);
+    assert!(binder.bind(DomainStrategy::AS_IS).await.is_ok());
+}
+
+#[tokio::test]
+async fn test_udp_binder_buffer_sizes() {
+    let config: UdpConfig =
+        serde_json::from_str(r#"{ "recv_buffer_size": 65536, "send_buffer_size": 32768 }"#)
+            .unwrap();
+    let socket = UdpBinder::new(Some(&config))
+        .unwrap()
+        .bind(DomainStrategy::AS_IS)
+        .await
+        .unwrap();
+
+    // Linux reports twice the size asked for, to account for its bookkeeping
+    let socket = SockRef::from(&socket);
+    assert!(socket.recv_buffer_size().unwrap() >= 65536);
+    assert!(socket.send_buffer_size().unwrap() >= 32768);
+}
@@ -0,0 +1,72 @@
+use trojan_rust::config::base::{NatBehavior, UdpConfig};
+use trojan_rust::proxy::udp::guard::UdpGuard;
+
+fn guard(nat: Option<NatBehavior>) -> UdpGuard {
+    UdpGuard::new(Some(&UdpConfig {
+        reply_rate_limit: None,
+        reply_burst: None,
+        nat,
+        max_sessions: None,
+        over_tcp: None,
+        bind_address: None,
+        port_range: None,
+        max_packet_size: None,
+        recv_buffer_size: None,
+        send_buffer_size: None,
+        broadcast: None,
+    }))
+}
+
+#[test]
+fn test_guard_nat_behaviors() {
+    let peer = "10.0.0.1:3478".parse().unwrap();
+    let other_port = "10.0.0.1:3479".parse().unwrap();
+    let other_host = "10.0.0.2:3478".parse().unwrap();
+
+    // Port restricted cone by default, only the exact peers reply
+    let port_restricted = guard(None);
+    assert!(port_restricted.register(peer));
+    assert!(port_restricted.check_reply(peer, 100));
+    assert!(!port_restricted.check_reply(other_port, 100));
+    assert!(!port_restricted.check_reply(other_host, 100));
+
+    let restricted = guard(Some(NatBehavior::RESTRICTED_CONE));
+    assert!(restricted.register(peer));
+    assert!(restricted.check_reply(other_port, 100));
+    assert!(!restricted.check_reply(other_host, 100));
+
+    // Full cone accepts any host, but only once the client has sent a datagram
+    let full = guard(Some(NatBehavior::FULL_CONE));
+    assert!(!full.check_reply(other_host, 100));
+    assert!(full.register(peer));
+    assert!(full.check_reply(other_host, 100));
+}
+
+#[test]
+fn test_guard_max_packet_size() {
+    let config: UdpConfig = serde_json::from_str(r#"{ "max_packet_size": 1400 }"#).unwrap();
+    let limited = UdpGuard::new(Some(&config));
+    assert!(limited.fits(1400));
+    assert!(!limited.fits(1401));
+
+    assert!(guard(None).fits(65535));
+}
+
+#[test]
+fn test_guard_broadcast() {
+    let ssdp = "239.255.255.250:1900".parse().unwrap();
+    let device = "192.168.1.20:49152".parse().unwrap();
+    let remote = "8.8.8.8:1900".parse().unwrap();
+
+    // Dropped unless enabled
+    assert!(!guard(None).register(ssdp));
+    assert!(!guard(None).register("255.255.255.255:9".parse().unwrap()));
+
+    let config: UdpConfig = serde_json::from_str(r#"{ "broadcast": true }"#).unwrap();
+    let broadcast = UdpGuard::new(Some(&config));
+    assert!(!broadcast.check_reply(device, 100));
+    assert!(broadcast.register(ssdp));
+    // Devices of the local network answer from their own address, hosts out there don't
+    assert!(broadcast.check_reply(device, 100));
+    assert!(!broadcast.check_reply(remote, 100));
+}
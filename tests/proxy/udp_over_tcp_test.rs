@@ -0,0 +1,45 @@
+use std::time::Duration;
+use tokio::io::AsyncReadExt;
+use tokio::net::TcpListener;
+use trojan_rust::config::base::OutboundConfig;
+use trojan_rust::protocol::common::addr::IpAddress;
+use trojan_rust::protocol::common::atype::Atype;
+use trojan_rust::protocol::common::command::Command;
+use trojan_rust::protocol::common::request::{InboundRequest, TransportProtocol};
+use trojan_rust::proxy::base::SupportedProtocols;
+use trojan_rust::proxy::deadline::Deadline;
+use trojan_rust::proxy::tcp::handler::TcpHandler;
+
+#[tokio::test]
+async fn test_quic_outbound_carries_udp_over_tcp() {
+    let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let config: OutboundConfig = serde_json::from_str(&format!(
+        r#"{{ "mode": "QUIC", "protocol": "TROJAN", "address": "127.0.0.1", "port": {}, "secret": "secret",
+            "udp": {{ "over_tcp": true }} }}"#,
+        server.local_addr().unwrap().port()
+    ))
+    .unwrap();
+    let handler = TcpHandler::new(&config).unwrap();
+
+    let request = InboundRequest::new(
+        Atype::IPv4,
+        IpAddress::from_host("1.1.1.1"),
+        Command::Udp,
+        53,
+        TransportProtocol::UDP,
+        SupportedProtocols::TROJAN,
+    );
+    let (_client, inbound) = tokio::io::duplex(1024);
+    tokio::spawn(async move {
+        handler
+            .dispatch(inbound, request, Deadline::after(Duration::from_secs(5)))
+            .await
+    });
+
+    // The UDP request reaches the server over TCP rather than QUIC
+    let (mut stream, _) = server.accept().await.unwrap();
+    let mut header = vec![0u8; 56 + 2 + 1 + 1 + 4 + 2 + 2];
+    stream.read_exact(&mut header).await.unwrap();
+    assert_eq!(header[58], Command::Udp as u8);
+    assert_eq!(&header[60..64], &[1, 1, 1, 1]);
+}
@@ -0,0 +1,34 @@
+use std::io::ErrorKind;
+use trojan_rust::metrics;
+use trojan_rust::proxy::udp::sessions::UdpSessions;
+
+#[test]
+fn test_udp_sessions_limit() {
+    let sessions = UdpSessions::new("udp-sessions-test", Some(2));
+    let first = sessions.open("127.0.0.1:10001".parse().unwrap()).unwrap();
+    let second = sessions.open("127.0.0.1:10002".parse().unwrap()).unwrap();
+
+    let refused = sessions.open("127.0.0.1:10003".parse().unwrap());
+    assert_eq!(refused.err().unwrap().kind(), ErrorKind::ConnectionRefused);
+    assert_eq!(
+        metrics::snapshot()["udp_sessions_active{outbound=\"udp-sessions-test\"}"],
+        2
+    );
+
+    // Closing a session makes room for a new one
+    let first_id = first.id();
+    drop(first);
+    let third = sessions.open("127.0.0.1:10003".parse().unwrap()).unwrap();
+
+    let live: Vec<u64> = sessions.list().iter().map(|session| session.id).collect();
+    assert_eq!(live, vec![second.id(), third.id()]);
+    assert!(!live.contains(&first_id));
+
+    drop(second);
+    drop(third);
+    assert!(sessions.is_empty());
+    assert_eq!(
+        metrics::snapshot()["udp_sessions_active{outbound=\"udp-sessions-test\"}"],
+        0
+    );
+}
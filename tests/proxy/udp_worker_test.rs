@@ -0,0 +1,57 @@
+use std::sync::Arc;
+use tokio::net::UdpSocket;
+use trojan_rust::protocol::common::addr::{IpAddrPort, IpAddress};
+use trojan_rust::proxy::udp::guard::UdpGuard;
+use trojan_rust::proxy::udp::worker::UdpWorkers;
+
+fn dest(port: u16) -> IpAddrPort {
+    IpAddrPort::new(
+        IpAddress::from_u32(u32::from_be_bytes([127, 0, 0, 1])),
+        port,
+    )
+}
+
+#[tokio::test]
+async fn test_flows_spread_over_workers() {
+    let workers = UdpWorkers::new(4);
+    assert_eq!(workers.len(), 4);
+
+    let session = "127.0.0.1:5000".parse().unwrap();
+
+    // The same flow always goes to the same worker
+    assert_eq!(
+        workers.worker_of(session, &dest(53)),
+        workers.worker_of(session, &dest(53))
+    );
+
+    let mut used = [false; 4];
+    for port in 1000..1100 {
+        used[workers.worker_of(session, &dest(port))] = true;
+    }
+    assert!(used.iter().all(|used| *used));
+}
+
+#[tokio::test]
+async fn test_worker_sends_datagram() {
+    let workers = UdpWorkers::new(2);
+
+    let receiver = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let port = receiver.local_addr().unwrap().port();
+
+    let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+    let guard = Arc::new(UdpGuard::new(None));
+    let session = socket.local_addr().unwrap();
+
+    workers
+        .send(session, &socket, &guard, dest(port), b"ping".to_vec())
+        .await
+        .unwrap();
+
+    let mut buf = [0u8; 16];
+    let (size, source) = receiver.recv_from(&mut buf).await.unwrap();
+    assert_eq!(&buf[..size], b"ping");
+    assert_eq!(source, session);
+
+    // The destination is registered, so its replies are relayed back
+    assert!(guard.check_reply(receiver.local_addr().unwrap(), 4));
+}
@@ -0,0 +1,48 @@
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use trojan_rust::config::base::{IoUringConfig, PolicyConfig};
+use trojan_rust::proxy::policy::Policy;
+use trojan_rust::proxy::relay::relay_tcp_with_policy;
+use trojan_rust::proxy::uring::UringWorkers;
+
+async fn tcp_pair() -> (TcpStream, TcpStream) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let connect = TcpStream::connect(listener.local_addr().unwrap());
+    let (connected, accepted) = tokio::join!(connect, listener.accept());
+    (connected.unwrap(), accepted.unwrap().0)
+}
+
+#[tokio::test]
+async fn test_relay_on_io_uring() {
+    let config = IoUringConfig {
+        workers: Some(1),
+        entries: None,
+    };
+    if UringWorkers::init(&config).is_none() {
+        // The kernel doesn't support io_uring, the relays splice instead
+        return;
+    }
+
+    let (mut client, inbound) = tcp_pair().await;
+    let (outbound, mut server) = tcp_pair().await;
+    let config = PolicyConfig {
+        max_download: Some(8),
+        ..Default::default()
+    };
+    let policy = Policy::new(&config, &PolicyConfig::default()).unwrap();
+    let relay = tokio::spawn(async move {
+        relay_tcp_with_policy(inbound, b"GET ".to_vec(), outbound, &policy).await
+    });
+
+    client.write_all(b"/ HTTP/1.1\r\n").await.unwrap();
+    let mut request = [0u8; 16];
+    server.read_exact(&mut request).await.unwrap();
+    assert_eq!(&request, b"GET / HTTP/1.1\r\n");
+
+    // The connection is closed once the server sent the cap
+    server.write_all(b"HTTP/1.1 200 OK\r\n").await.unwrap();
+    let mut response = Vec::new();
+    client.read_to_end(&mut response).await.unwrap();
+    assert_eq!(response, b"HTTP/1.1");
+    relay.await.unwrap().unwrap();
+}
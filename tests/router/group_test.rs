@@ -0,0 +1,180 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use trojan_rust::config::base::{OutboundConfig, OutboundGroupConfig};
+use trojan_rust::metrics;
+use trojan_rust::proxy::deadline::Deadline;
+use trojan_rust::proxy::drain::SessionDrain;
+use trojan_rust::proxy::tcp::handler::TcpHandler;
+use trojan_rust::router::group::{OutboundGroup, TestUrl};
+
+fn group(config: &str) -> OutboundGroup {
+    let config: OutboundGroupConfig = serde_json::from_str(config).unwrap();
+    OutboundGroup::new(&config).unwrap()
+}
+
+#[test]
+fn test_failover_hysteresis() {
+    let group = group(
+        r#"{ "tag": "auto", "type": "FAILOVER", "members": ["a", "b", "c"], "fall": 2, "rise": 2 }"#,
+    );
+    assert_eq!(group.selected(), "a");
+
+    // A single failure isn't enough to switch
+    group.record(0, false);
+    assert_eq!(group.selected(), "a");
+    group.record(0, false);
+    assert_eq!(group.selected(), "b");
+
+    // Neither is a single success to switch back
+    group.record(0, true);
+    assert_eq!(group.selected(), "b");
+    group.record(0, false);
+    group.record(0, true);
+    assert_eq!(group.selected(), "b");
+    group.record(0, true);
+    assert_eq!(group.selected(), "a");
+
+    // Stays on the last member left if all of them are down
+    for index in 0..3 {
+        group.record(index, false);
+        group.record(index, false);
+    }
+    assert_eq!(group.selected(), "c");
+}
+
+#[test]
+fn test_group_metric_labels_escaped() {
+    let group = group(
+        r#"{ "tag": "group\\\"test", "type": "FAILOVER", "members": ["a\"b", "c"], "fall": 1 }"#,
+    );
+    group.record(0, false);
+
+    let snapshot = metrics::snapshot();
+    assert_eq!(
+        snapshot[r#"outbound_healthy{group="group\\\"test",outbound="a\"b"}"#],
+        0
+    );
+    assert_eq!(
+        snapshot[r#"outbound_group_switches_total{group="group\\\"test"}"#],
+        1
+    );
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_failover_health_checks() {
+    let outbound = |config: &str| {
+        let config: OutboundConfig = serde_json::from_str(config).unwrap();
+        Arc::new(TcpHandler::new(&config).unwrap())
+    };
+
+    // Nothing listens on port 1, so the primary fails the probe right away
+    let mut handlers = HashMap::new();
+    handlers.insert(
+        "primary".to_string(),
+        outbound(r#"{ "mode": "TCP", "protocol": "TROJAN", "address": "127.0.0.1", "port": 1 }"#),
+    );
+    handlers.insert(
+        "direct".to_string(),
+        outbound(r#"{ "mode": "DIRECT", "protocol": "DIRECT" }"#),
+    );
+    let handlers: &'static HashMap<String, Arc<TcpHandler>> = Box::leak(Box::new(handlers));
+
+    let group: &'static OutboundGroup = Box::leak(Box::new(group(
+        r#"{ "tag": "auto", "type": "FAILOVER", "members": ["primary", "direct"], "fall": 1 }"#,
+    )));
+    tokio::spawn(group.run_health_checks(handlers));
+
+    // Past the timeout of the first probes and before the next round
+    tokio::time::sleep(Duration::from_secs(10)).await;
+    assert_eq!(group.selected(), "direct");
+}
+
+#[test]
+fn test_url_test_tolerance() {
+    let group =
+        group(r#"{ "tag": "auto", "type": "URL_TEST", "members": ["a", "b"], "tolerance": 50 }"#);
+    let ms = |ms| Some(Duration::from_millis(ms));
+
+    group.record_latency(0, ms(100));
+    group.record_latency(1, ms(30));
+    assert_eq!(group.selected(), "b");
+
+    // Not faster by more than the tolerance
+    group.record_latency(0, ms(60));
+    group.record_latency(1, ms(80));
+    assert_eq!(group.selected(), "b");
+
+    group.record_latency(1, ms(120));
+    assert_eq!(group.selected(), "a");
+}
+
+#[tokio::test]
+async fn test_url_test_fetch() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut buf = vec![0u8; 1024];
+        let size = stream.read(&mut buf).await.unwrap();
+        assert!(buf[..size].starts_with(b"HEAD /generate_204 HTTP/1.1\r\n"));
+        stream
+            .write_all(b"HTTP/1.1 204 No Content\r\n\r\n")
+            .await
+            .unwrap();
+    });
+
+    let config: OutboundConfig =
+        serde_json::from_str(r#"{ "mode": "DIRECT", "protocol": "DIRECT" }"#).unwrap();
+    let handler = TcpHandler::new(&config).unwrap();
+
+    let url = TestUrl::parse(&format!("http://127.0.0.1:{}/generate_204", port)).unwrap();
+    url.fetch(&handler, Deadline::after(Duration::from_secs(5)))
+        .await
+        .unwrap();
+
+    assert!(TestUrl::parse("https://example.com/").is_err());
+}
+
+#[test]
+fn test_select_group() {
+    let group = group(r#"{ "tag": "node", "type": "SELECT", "members": ["us", "eu"] }"#);
+    assert_eq!(group.selected(), "us");
+
+    assert!(group.select("eu"));
+    assert_eq!(group.selected(), "eu");
+
+    assert!(!group.select("asia"));
+    assert_eq!(group.selected(), "eu");
+
+    // Probe results don't change the selection
+    group.record(1, false);
+    group.record(1, false);
+    group.record(1, false);
+    assert_eq!(group.selected(), "eu");
+}
+
+#[test]
+fn test_draining_member() {
+    let group = group(r#"{ "tag": "auto", "type": "FAILOVER", "members": ["a", "b"] }"#);
+    assert!(group.set_draining("a", true));
+    assert_eq!(group.selected(), "b");
+    assert_eq!(group.draining().collect::<Vec<_>>(), ["a"]);
+
+    // Health checks don't bring a draining member back
+    group.record(0, true);
+    assert_eq!(group.selected(), "b");
+    assert!(group.set_draining("a", false));
+    assert_eq!(group.selected(), "a");
+    assert!(!group.set_draining("c", true));
+
+    // The sessions of the outbound are counted until they finish
+    let drain = SessionDrain::new("a");
+    let session = drain.start();
+    drain.set_draining(true);
+    assert_eq!(drain.active(), 1);
+    drop(session);
+    assert_eq!(drain.active(), 0);
+}
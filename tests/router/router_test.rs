@@ -0,0 +1,275 @@
+use bytes::Bytes;
+use trojan_rust::config::base::{Config, DomainResolution};
+use trojan_rust::protocol::common::addr::IpAddress;
+use trojan_rust::protocol::common::atype::Atype;
+use trojan_rust::protocol::common::command::Command;
+use trojan_rust::protocol::common::request::{InboundRequest, TransportProtocol};
+use trojan_rust::proxy::base::SupportedProtocols;
+use trojan_rust::router::{RouteContext, Router};
+
+fn config() -> Config {
+    serde_json::from_str(
+        r#"{
+            "inbound": {
+                "tag": "socks",
+                "mode": "TCP",
+                "protocol": "SOCKS",
+                "address": "127.0.0.1",
+                "port": 1080
+            },
+            "outbound": {
+                "tag": "proxy",
+                "mode": "TCP",
+                "protocol": "TROJAN",
+                "address": "127.0.0.1",
+                "port": 443,
+                "secret": "123123"
+            },
+            "outbounds": [
+                { "tag": "direct", "mode": "DIRECT", "protocol": "DIRECT" }
+            ],
+            "router": {
+                "rules": [
+                    { "domain": ["example.com"], "outbound": "direct" },
+                    { "domain": ["geo.test"], "resolve": "LOCAL", "outbound": "proxy" },
+                    { "ip_cidr": ["10.0.0.0/8"], "port": [80, 443], "outbound": "direct" },
+                    { "inbound_tag": ["lan"], "transport": "UDP", "outbound": "direct" }
+                ]
+            }
+        }"#,
+    )
+    .unwrap()
+}
+
+fn request(addr: IpAddress, port: u16, transport: TransportProtocol) -> InboundRequest {
+    InboundRequest::new(
+        Atype::DomainName,
+        addr,
+        Command::Connect,
+        port,
+        transport,
+        SupportedProtocols::SOCKS,
+    )
+}
+
+fn select(router: &Router, request: &InboundRequest, inbound_tag: Option<&str>) -> String {
+    router
+        .select(&RouteContext {
+            request,
+            inbound_tag,
+            sniffed_domain: None,
+        })
+        .to_string()
+}
+
+#[test]
+fn test_router_domain_rules() {
+    let router = Router::new(&config()).unwrap();
+    let domain = |name: &'static str| IpAddress::from_bytes(Bytes::from(name));
+
+    let tcp = TransportProtocol::TCP;
+    assert_eq!(
+        select(&router, &request(domain("example.com"), 443, tcp), None),
+        "direct"
+    );
+    assert_eq!(
+        select(&router, &request(domain("www.Example.com"), 443, tcp), None),
+        "direct"
+    );
+    assert_eq!(
+        select(&router, &request(domain("notexample.com"), 443, tcp), None),
+        "proxy"
+    );
+}
+
+#[test]
+fn test_router_ip_and_port_rules() {
+    let router = Router::new(&config()).unwrap();
+    let ip = |addr: [u8; 4]| IpAddress::from_u32(u32::from_be_bytes(addr));
+
+    let tcp = TransportProtocol::TCP;
+    assert_eq!(
+        select(&router, &request(ip([10, 1, 2, 3]), 443, tcp), None),
+        "direct"
+    );
+    assert_eq!(
+        select(&router, &request(ip([10, 1, 2, 3]), 22, tcp), None),
+        "proxy"
+    );
+    assert_eq!(
+        select(&router, &request(ip([8, 8, 8, 8]), 443, tcp), None),
+        "proxy"
+    );
+
+    let udp = TransportProtocol::UDP;
+    assert_eq!(
+        select(&router, &request(ip([8, 8, 8, 8]), 53, udp), Some("lan")),
+        "direct"
+    );
+    assert_eq!(
+        select(&router, &request(ip([8, 8, 8, 8]), 53, udp), Some("socks")),
+        "proxy"
+    );
+}
+
+#[test]
+fn test_router_geoip_private() {
+    let mut config = config();
+    config.router.as_mut().unwrap().rules =
+        serde_json::from_str(r#"[{ "ip_cidr": ["geoip:private"], "outbound": "direct" }]"#)
+            .unwrap();
+    let router = Router::new(&config).unwrap();
+    let tcp = TransportProtocol::TCP;
+
+    let lan = IpAddress::from_u32(u32::from_be_bytes([192, 168, 1, 1]));
+    assert_eq!(select(&router, &request(lan, 22, tcp), None), "direct");
+
+    let loopback = IpAddress::from_u128(1);
+    assert_eq!(select(&router, &request(loopback, 22, tcp), None), "direct");
+
+    let public = IpAddress::from_u32(u32::from_be_bytes([1, 1, 1, 1]));
+    assert_eq!(select(&router, &request(public, 22, tcp), None), "proxy");
+}
+
+#[test]
+fn test_router_domain_types() {
+    let mut config = config();
+    config.router.as_mut().unwrap().rules = serde_json::from_str(
+        r#"[
+            { "domain": ["full:example.org", "keyword:ads", "regexp:^cdn[0-9]+\\."], "outbound": "direct" }
+        ]"#,
+    )
+    .unwrap();
+    let router = Router::new(&config).unwrap();
+    let domain = |name: &'static str| IpAddress::from_bytes(Bytes::from(name));
+    let tcp = TransportProtocol::TCP;
+
+    for (name, outbound) in [
+        ("example.org", "direct"),
+        ("www.example.org", "proxy"),
+        ("myads.example.net", "direct"),
+        ("cdn42.example.net", "direct"),
+        ("www.cdn42.example.net", "proxy"),
+    ] {
+        assert_eq!(
+            select(&router, &request(domain(name), 443, tcp), None),
+            outbound,
+            "{}",
+            name
+        );
+    }
+}
+
+#[test]
+fn test_router_geosite() {
+    use prost::Message;
+    use trojan_rust::router::geosite::proto::{domain, Domain, GeoSite, GeoSiteList};
+
+    let entry = |r#type: domain::Type, value: &str, attribute: Option<&str>| Domain {
+        r#type: r#type as i32,
+        value: value.to_string(),
+        attribute: attribute
+            .map(|key| domain::Attribute {
+                key: key.to_string(),
+                typed_value: Some(domain::attribute::TypedValue::BoolValue(true)),
+            })
+            .into_iter()
+            .collect(),
+    };
+    let list = GeoSiteList {
+        entry: vec![GeoSite {
+            country_code: "GOOGLE".to_string(),
+            domain: vec![
+                entry(domain::Type::Domain, "google.com", None),
+                entry(domain::Type::Full, "google.cn", Some("cn")),
+                entry(domain::Type::Plain, "youtube", None),
+            ],
+        }],
+    };
+
+    let path = std::env::temp_dir().join(format!("trojan-geosite-{}.dat", std::process::id()));
+    std::fs::write(&path, list.encode_to_vec()).unwrap();
+
+    let mut config = config();
+    let router_config = config.router.as_mut().unwrap();
+    router_config.geosite_database = Some(path.to_str().unwrap().to_string());
+    router_config.rules = serde_json::from_str(
+        r#"[
+            { "domain": ["geosite:google@cn"], "port": [80], "outbound": "direct" },
+            { "domain": ["geosite:google"], "port": [443], "outbound": "direct" }
+        ]"#,
+    )
+    .unwrap();
+    let router = Router::new(&config).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    let domain = |name: &'static str| IpAddress::from_bytes(Bytes::from(name));
+    let tcp = TransportProtocol::TCP;
+
+    for (name, port, outbound) in [
+        ("mail.google.com", 443, "direct"),
+        ("www.youtube.com", 443, "direct"),
+        ("www.google.cn", 443, "proxy"),
+        ("google.cn", 80, "direct"),
+        ("google.com", 80, "proxy"),
+        ("example.com", 443, "proxy"),
+    ] {
+        assert_eq!(
+            select(&router, &request(domain(name), port, tcp), None),
+            outbound,
+            "{}",
+            name
+        );
+    }
+}
+
+#[test]
+fn test_router_port_ranges() {
+    let mut config = config();
+    config.router.as_mut().unwrap().rules =
+        serde_json::from_str(r#"[{ "port": [25, "465", "8000-8999"], "outbound": "direct" }]"#)
+            .unwrap();
+    let router = Router::new(&config).unwrap();
+    let addr = IpAddress::from_u32(u32::from_be_bytes([1, 1, 1, 1]));
+    let tcp = TransportProtocol::TCP;
+
+    for (port, outbound) in [
+        (25, "direct"),
+        (465, "direct"),
+        (8000, "direct"),
+        (8999, "direct"),
+        (7999, "proxy"),
+        (9000, "proxy"),
+        (443, "proxy"),
+    ] {
+        assert_eq!(
+            select(&router, &request(addr.clone(), port, tcp), None),
+            outbound,
+            "{}",
+            port
+        );
+    }
+}
+
+#[test]
+fn test_router_domain_resolution() {
+    let router = Router::new(&config()).unwrap();
+    let domain = |name: &'static str| IpAddress::from_bytes(Bytes::from(name));
+    let resolution = |request: &InboundRequest| {
+        router
+            .route_with_resolution(&RouteContext {
+                request,
+                inbound_tag: None,
+                sniffed_domain: None,
+            })
+            .1
+    };
+
+    let tcp = TransportProtocol::TCP;
+    assert_eq!(
+        resolution(&request(domain("www.geo.test"), 443, tcp)),
+        Some(DomainResolution::LOCAL)
+    );
+    assert_eq!(resolution(&request(domain("example.com"), 443, tcp)), None);
+    assert_eq!(resolution(&request(domain("other.test"), 443, tcp)), None);
+}
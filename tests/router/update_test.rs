@@ -0,0 +1,177 @@
+use bytes::Bytes;
+use prost::Message;
+use std::io::ErrorKind;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use trojan_rust::config::base::Config;
+use trojan_rust::protocol::common::addr::IpAddress;
+use trojan_rust::protocol::common::atype::Atype;
+use trojan_rust::protocol::common::command::Command;
+use trojan_rust::protocol::common::request::{InboundRequest, TransportProtocol};
+use trojan_rust::proxy::base::SupportedProtocols;
+use trojan_rust::router::geosite::proto::{domain, Domain, GeoSite, GeoSiteList};
+use trojan_rust::router::update::{download, MAX_DOWNLOAD_SIZE};
+use trojan_rust::router::{RouteContext, Router};
+
+/// Encode a geosite.dat file with a single category holding the domains.
+fn geosite(category: &str, domains: &[&str]) -> Vec<u8> {
+    GeoSiteList {
+        entry: vec![GeoSite {
+            country_code: category.to_string(),
+            domain: domains
+                .iter()
+                .map(|value| Domain {
+                    r#type: domain::Type::Domain as i32,
+                    value: value.to_string(),
+                    attribute: Vec::new(),
+                })
+                .collect(),
+        }],
+    }
+    .encode_to_vec()
+}
+
+/// Serve the body to every HTTP request, returns the url of the file.
+async fn serve(body: Vec<u8>) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/geosite.dat", listener.local_addr().unwrap());
+
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0u8; 1024];
+            while !request.ends_with(b"\r\n\r\n") {
+                let n = stream.read(&mut buf).await.unwrap();
+                if n == 0 {
+                    break;
+                }
+                request.extend_from_slice(&buf[..n]);
+            }
+
+            let header = format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                body.len()
+            );
+            stream.write_all(header.as_bytes()).await.unwrap();
+            stream.write_all(&body).await.unwrap();
+        }
+    });
+
+    url
+}
+
+fn config(path: &str, url: &str) -> Config {
+    serde_json::from_value(serde_json::json!({
+        "inbound": {
+            "mode": "TCP",
+            "protocol": "SOCKS",
+            "address": "127.0.0.1",
+            "port": 1080
+        },
+        "outbound": { "tag": "proxy", "mode": "DIRECT", "protocol": "DIRECT" },
+        "outbounds": [{ "tag": "block", "mode": "BLOCK", "protocol": "DIRECT" }],
+        "router": {
+            "rules": [{ "domain": ["geosite:ads"], "outbound": "block" }],
+            "geosite_database": path,
+            "geosite_url": url
+        }
+    }))
+    .unwrap()
+}
+
+fn select(router: &Router, name: &'static str) -> String {
+    let request = InboundRequest::new(
+        Atype::DomainName,
+        IpAddress::from_bytes(Bytes::from(name)),
+        Command::Connect,
+        443,
+        TransportProtocol::TCP,
+        SupportedProtocols::SOCKS,
+    );
+    router.select(&RouteContext {
+        request: &request,
+        inbound_tag: None,
+        sniffed_domain: None,
+    })
+}
+
+fn database_path(name: &str) -> String {
+    std::env::temp_dir()
+        .join(format!("trojan-{}-{}.dat", name, std::process::id()))
+        .to_str()
+        .unwrap()
+        .to_string()
+}
+
+#[tokio::test]
+async fn test_update_swaps_rules() {
+    let path = database_path("update");
+    std::fs::write(&path, geosite("ads", &["ads.example.com"])).unwrap();
+
+    let updated = geosite("ads", &["tracker.example.net"]);
+    let url = serve(updated.clone()).await;
+    let router = Router::new(&config(&path, &url)).unwrap();
+
+    assert_eq!(select(&router, "ads.example.com"), "block");
+    assert_eq!(select(&router, "tracker.example.net"), "proxy");
+
+    router.update_databases().await.unwrap();
+
+    assert_eq!(select(&router, "ads.example.com"), "proxy");
+    assert_eq!(select(&router, "tracker.example.net"), "block");
+    assert_eq!(std::fs::read(&path).unwrap(), updated);
+
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn test_failed_update_keeps_rules() {
+    let path = database_path("failed-update");
+    let original = geosite("ads", &["ads.example.com"]);
+    std::fs::write(&path, &original).unwrap();
+
+    // The new database lacks the category used by the rule
+    let url = serve(geosite("google", &["google.com"])).await;
+    let router = Router::new(&config(&path, &url)).unwrap();
+
+    assert!(router.update_databases().await.is_err());
+
+    assert_eq!(select(&router, "ads.example.com"), "block");
+    assert_eq!(std::fs::read(&path).unwrap(), original);
+
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn test_download_size_limit() {
+    // The server announces more than the limit, nothing is read
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/geosite.dat", listener.local_addr().unwrap());
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let header = format!(
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n",
+            MAX_DOWNLOAD_SIZE + 1
+        );
+        stream.write_all(header.as_bytes()).await.unwrap();
+        std::future::pending::<()>().await;
+    });
+
+    let err = download(&url).await.unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidData);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_download_timeout() {
+    // The server never answers
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/geosite.dat", listener.local_addr().unwrap());
+    tokio::spawn(async move {
+        let _stream = listener.accept().await.unwrap();
+        std::future::pending::<()>().await;
+    });
+
+    let err = download(&url).await.unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::TimedOut);
+}
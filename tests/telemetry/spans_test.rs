@@ -0,0 +1,112 @@
+use futures::future::{self, BoxFuture};
+use opentelemetry::sdk::export::trace::{ExportResult, SpanData, SpanExporter};
+use opentelemetry::sdk::trace::{config, TracerProvider};
+use opentelemetry::trace::{SpanId, SpanKind, Status, TracerProvider as _};
+use opentelemetry::{Key, Value};
+use std::io::{Error, ErrorKind};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::time::Duration;
+use tracing::field::Empty;
+use tracing::{info_span, Instrument};
+use trojan_rust::proxy::deadline::Deadline;
+use trojan_rust::telemetry::{sampler, subscriber};
+
+/// Exporter handing the finished spans over to the test.
+#[derive(Debug)]
+struct ChannelExporter(Sender<SpanData>);
+
+impl SpanExporter for ChannelExporter {
+    fn export(&mut self, batch: Vec<SpanData>) -> BoxFuture<'static, ExportResult> {
+        for span in batch {
+            let _ = self.0.send(span);
+        }
+        Box::pin(future::ready(Ok(())))
+    }
+}
+
+/// Provider exporting each span as it ends, the traces sampled at the rate.
+fn provider(sample_rate: f64) -> (TracerProvider, Receiver<SpanData>) {
+    let (sender, receiver) = mpsc::channel();
+    let provider = TracerProvider::builder()
+        .with_simple_exporter(ChannelExporter(sender))
+        .with_config(config().with_sampler(sampler(sample_rate)))
+        .build();
+    (provider, receiver)
+}
+
+/// Spans exported until none came for a while.
+fn exported(receiver: &Receiver<SpanData>) -> Vec<SpanData> {
+    let mut spans = Vec::new();
+    while let Ok(span) = receiver.recv_timeout(Duration::from_millis(200)) {
+        spans.push(span);
+    }
+    spans
+}
+
+#[tokio::test]
+async fn test_stage_spans_of_connection() {
+    let (provider, receiver) = provider(1.0);
+    let _subscriber = tracing::subscriber::set_default(subscriber(provider.tracer("test")));
+
+    let deadline = Deadline::after(Duration::from_secs(5));
+    let span = info_span!(
+        target: "trojan_rust::test",
+        "connection",
+        otel.kind = "server",
+        source = "127.0.0.1:5000",
+        uploaded = Empty
+    );
+    async {
+        deadline.run("dns", async { Ok(()) }).await.unwrap();
+        deadline
+            .run("connect", async {
+                Err::<(), _>(Error::new(ErrorKind::ConnectionRefused, "refused"))
+            })
+            .await
+            .unwrap_err();
+        tracing::Span::current().record("uploaded", 42);
+    }
+    .instrument(span)
+    .await;
+
+    let spans = exported(&receiver);
+    let names: Vec<_> = spans.iter().map(|span| span.name.as_ref()).collect();
+    assert_eq!(names, ["dns", "connect", "connection"]);
+
+    let (dns, connect, connection) = (&spans[0], &spans[1], &spans[2]);
+    assert_eq!(connection.parent_span_id, SpanId::INVALID);
+    assert_eq!(connection.span_kind, SpanKind::Server);
+    assert_eq!(
+        connection.attributes.get(&Key::new("source")),
+        Some(&Value::from("127.0.0.1:5000"))
+    );
+    assert_eq!(
+        connection.attributes.get(&Key::new("uploaded")),
+        Some(&Value::I64(42))
+    );
+    for stage in [dns, connect] {
+        assert_eq!(
+            stage.span_context.trace_id(),
+            connection.span_context.trace_id()
+        );
+        assert_eq!(stage.parent_span_id, connection.span_context.span_id());
+    }
+    assert_eq!(dns.status, Status::Unset);
+    assert_eq!(connect.status, Status::error("refused"));
+}
+
+#[test]
+fn test_traces_sampled() {
+    for (sample_rate, count) in [(1.0, 8), (0.0, 0)] {
+        let (provider, receiver) = provider(sample_rate);
+        tracing::subscriber::with_default(subscriber(provider.tracer("test")), || {
+            for _ in 0..4 {
+                let _span = info_span!(target: "trojan_rust::test", "connection").entered();
+                let _stage = info_span!(target: "trojan_rust::test", "stage").entered();
+            }
+            // Spans of other crates aren't traced
+            let _span = info_span!(target: "hyper", "connection").entered();
+        });
+        assert_eq!(exported(&receiver).len(), count);
+    }
+}
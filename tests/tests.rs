@@ -1,5 +1,121 @@
 extern crate trojan_rust;
 
+#[cfg(feature = "server")]
+mod admin {
+    mod proxy_port_test;
+}
+
+mod auth {
+    #[cfg(feature = "server")]
+    mod cache_test;
+    mod chain_test;
+    mod secret_test;
+}
+
+mod config {
+    #[cfg(feature = "server")]
+    mod certificate_test;
+    #[cfg(feature = "client")]
+    mod fingerprint_test;
+    mod parser_test;
+    #[cfg(target_os = "linux")]
+    mod runtime_test;
+    #[cfg(feature = "server")]
+    mod ticketer_test;
+    mod tls_test;
+    mod transports_test;
+}
+
+mod dns {
+    mod discovery_test;
+    #[cfg(feature = "client")]
+    mod fake_test;
+    #[cfg(feature = "client")]
+    mod hijack_test;
+    mod resolver_test;
+}
+
+mod events {
+    mod events_test;
+}
+
+mod health {
+    mod endpoint_test;
+}
+
+mod logging {
+    mod json_test;
+}
+
+mod metrics {
+    mod access_test;
+    mod export_test;
+    mod rotation_test;
+    #[cfg(feature = "sqlite")]
+    mod usage_test;
+}
+
+#[cfg(feature = "profiling")]
+mod profiling {
+    mod session_test;
+}
+
+mod protocol {
+    mod socks5_test;
+    mod tls_test;
+    mod trojan_test;
+}
+
 mod proxy {
     mod acceptor_test;
-}
\ No newline at end of file
+    mod block_test;
+    mod buffer_test;
+    mod chain_test;
+    mod connections_test;
+    mod context_test;
+    mod deadline_test;
+    mod destination_test;
+    mod fallback_test;
+    mod filter_test;
+    #[cfg(all(target_os = "linux", feature = "server"))]
+    mod ktls_test;
+    mod limiter_test;
+    mod listener_test;
+    mod piped_test;
+    mod policy_test;
+    mod pool_test;
+    mod quic_datagram_test;
+    #[cfg(feature = "server")]
+    pub mod quic_server_test;
+    mod reaper_test;
+    mod relay_test;
+    mod reset_test;
+    mod servers_test;
+    mod sim;
+    mod sniff_test;
+    mod socket_test;
+    mod throttle_test;
+    mod udp_batch_test;
+    mod udp_bind_test;
+    mod udp_guard_test;
+    mod udp_over_tcp_test;
+    mod udp_sessions_test;
+    mod udp_worker_test;
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    mod uring_test;
+}
+
+mod router {
+    mod group_test;
+    mod router_test;
+    mod update_test;
+}
+
+#[cfg(feature = "otel")]
+mod telemetry {
+    mod spans_test;
+}
+
+mod transport {
+    mod websocket_test;
+}
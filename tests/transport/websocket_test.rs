@@ -0,0 +1,62 @@
+use futures::SinkExt;
+use tokio::io::AsyncReadExt;
+use tokio_tungstenite::tungstenite::Message;
+use trojan_rust::config::base::InboundWebSocketConfig;
+use trojan_rust::protocol::common::stream::PrefixedStream;
+use trojan_rust::transport::websocket::{accept, read_upgrade_request};
+
+fn config() -> InboundWebSocketConfig {
+    InboundWebSocketConfig {
+        path: "/ws".to_string(),
+        host: Some("example.com".to_string()),
+    }
+}
+
+async fn is_upgrade(head: &str) -> bool {
+    let mut buf = head.as_bytes().to_vec();
+    read_upgrade_request(&mut tokio::io::empty(), &mut buf, &config())
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn test_upgrade_request_validation() {
+    let upgrade = |path: &str, host: &str| {
+        format!(
+            "GET {} HTTP/1.1\r\nHost: {}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\r\n",
+            path, host
+        )
+    };
+
+    assert!(is_upgrade(&upgrade("/ws", "example.com")).await);
+    assert!(is_upgrade(&upgrade("/ws?ed=2048", "EXAMPLE.com:443")).await);
+    assert!(!is_upgrade(&upgrade("/", "example.com")).await);
+    assert!(!is_upgrade(&upgrade("/ws", "example.org")).await);
+    assert!(!is_upgrade("GET /ws HTTP/1.1\r\nHost: example.com\r\n\r\n").await);
+}
+
+#[tokio::test]
+async fn test_websocket_byte_stream() {
+    let (client, mut server) = tokio::io::duplex(4096);
+
+    tokio::spawn(async move {
+        let (mut ws, _) = tokio_tungstenite::client_async("ws://example.com/ws", client)
+            .await
+            .unwrap();
+        ws.send(Message::Binary(b"hello ".to_vec())).await.unwrap();
+        ws.send(Message::Binary(b"world".to_vec())).await.unwrap();
+        ws.close(None).await.unwrap();
+    });
+
+    // The inbound has already consumed the beginning of the request while looking for trojan
+    let mut buf = vec![0u8; 16];
+    server.read_exact(&mut buf).await.unwrap();
+    assert!(read_upgrade_request(&mut server, &mut buf, &config())
+        .await
+        .unwrap());
+
+    let mut stream = accept(PrefixedStream::new(buf, server)).await.unwrap();
+    let mut data = Vec::new();
+    stream.read_to_end(&mut data).await.unwrap();
+    assert_eq!(data, b"hello world");
+}